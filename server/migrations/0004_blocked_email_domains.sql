@@ -0,0 +1,14 @@
+-- throwaway email domains rejected at registration
+CREATE TABLE blocked_email_domains (
+    domain VARCHAR(255) PRIMARY KEY,
+    reason TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+INSERT INTO blocked_email_domains (domain, reason) VALUES
+    ('mailinator.com', 'disposable'),
+    ('guerrillamail.com', 'disposable'),
+    ('10minutemail.com', 'disposable'),
+    ('temp-mail.org', 'disposable'),
+    ('yopmail.com', 'disposable'),
+    ('trashmail.com', 'disposable');
@@ -0,0 +1,7 @@
+-- fraud scoring: risky orders wait in review for an admin
+ALTER TYPE order_status ADD VALUE IF NOT EXISTS 'review';
+
+ALTER TABLE orders
+    ADD COLUMN risk_score INTEGER NOT NULL DEFAULT 0,
+    ADD COLUMN risk_reasons TEXT[] NOT NULL DEFAULT '{}',
+    ADD COLUMN shipping_country VARCHAR(2);
@@ -0,0 +1,8 @@
+-- cross-sells pinned by admins, shown in position order on product detail
+CREATE TABLE related_products (
+    product_id UUID NOT NULL REFERENCES products(product_id) ON DELETE CASCADE,
+    related_product_id UUID NOT NULL REFERENCES products(product_id) ON DELETE CASCADE,
+    position INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (product_id, related_product_id),
+    CHECK (product_id <> related_product_id)
+);
@@ -0,0 +1,2 @@
+-- idle carts are found by updated_at, which now moves on every cart change
+CREATE INDEX carts_updated_at_idx ON carts (updated_at);
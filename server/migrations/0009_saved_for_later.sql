@@ -0,0 +1,2 @@
+-- items parked in the cart's save-for-later list are left out of checkout
+ALTER TABLE cart_items ADD COLUMN saved_for_later BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,12 @@
+-- users can keep several named carts, checkout uses the active one
+ALTER TABLE carts
+    ADD COLUMN name VARCHAR(100) NOT NULL DEFAULT 'Cart',
+    ADD COLUMN is_active BOOLEAN NOT NULL DEFAULT TRUE;
+
+-- keep only the oldest existing cart active per user
+UPDATE carts SET is_active = FALSE
+WHERE cart_id NOT IN (
+    SELECT DISTINCT ON (user_id) cart_id FROM carts ORDER BY user_id, created_at
+);
+
+CREATE UNIQUE INDEX carts_one_active_per_user ON carts (user_id) WHERE is_active;
@@ -0,0 +1,8 @@
+-- gift wrap and message for the whole order or single lines
+ALTER TABLE orders
+    ADD COLUMN gift_wrap BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN gift_message TEXT;
+
+ALTER TABLE order_details
+    ADD COLUMN gift_wrap BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN gift_message TEXT;
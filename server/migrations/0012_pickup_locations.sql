@@ -0,0 +1,13 @@
+-- click-and-collect: orders picked up at a store instead of shipped
+CREATE TABLE pickup_locations (
+    location_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    name VARCHAR(255) NOT NULL,
+    address TEXT NOT NULL,
+    opening_hours TEXT,
+    is_active BOOLEAN NOT NULL DEFAULT TRUE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+ALTER TABLE orders ADD COLUMN pickup_location_id UUID REFERENCES pickup_locations(location_id);
+
+ALTER TYPE order_status ADD VALUE IF NOT EXISTS 'readyforpickup';
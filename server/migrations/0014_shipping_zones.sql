@@ -0,0 +1,25 @@
+-- shipping zones: which methods ship where and at what rate, matched on
+-- country and an optional postcode range (compared as text, so keep both
+-- ends the same length as the country's postcodes)
+CREATE TYPE shipping_method AS ENUM ('standard', 'express', 'pickup');
+
+CREATE TABLE shipping_zones (
+    zone_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    name VARCHAR(100) NOT NULL,
+    country VARCHAR(2) NOT NULL,
+    postcode_from VARCHAR(20),
+    postcode_to VARCHAR(20),
+    priority INTEGER NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX shipping_zones_country_idx ON shipping_zones (country);
+
+CREATE TABLE shipping_rates (
+    zone_id UUID NOT NULL REFERENCES shipping_zones(zone_id) ON DELETE CASCADE,
+    method shipping_method NOT NULL CHECK (method <> 'pickup'),
+    rate DECIMAL(10, 2) NOT NULL CHECK (rate >= 0),
+    PRIMARY KEY (zone_id, method)
+);
+
+ALTER TABLE orders ADD COLUMN shipping_postcode VARCHAR(20);
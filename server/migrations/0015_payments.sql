@@ -0,0 +1,28 @@
+-- payment-first checkout: orders wait in pendingpayment until the payment
+-- succeeds, unpaid ones are cancelled once payment_expires_at passes
+ALTER TYPE order_status ADD VALUE IF NOT EXISTS 'pendingpayment';
+ALTER TYPE order_status ADD VALUE IF NOT EXISTS 'cancelled';
+
+CREATE TYPE payment_status AS ENUM ('pending', 'succeeded', 'failed', 'cancelled');
+
+CREATE TABLE payments (
+    payment_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    order_id UUID NOT NULL REFERENCES orders(order_id) ON DELETE CASCADE,
+    provider VARCHAR(20) NOT NULL,
+    provider_ref VARCHAR(255) NOT NULL,
+    amount DECIMAL(10, 2) NOT NULL,
+    currency VARCHAR(3) NOT NULL,
+    status payment_status NOT NULL DEFAULT 'pending',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    UNIQUE (provider, provider_ref)
+);
+
+CREATE INDEX payments_order_idx ON payments (order_id);
+
+-- fraud flagged orders go to review instead of confirmed once paid
+ALTER TABLE orders
+    ADD COLUMN hold_for_review BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN payment_expires_at TIMESTAMPTZ;
+
+CREATE INDEX orders_payment_expires_at_idx ON orders (payment_expires_at);
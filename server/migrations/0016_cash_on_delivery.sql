@@ -0,0 +1,9 @@
+-- cash on delivery: the order confirms without a charge, the courier's
+-- collection is recorded on it for reconciliation
+ALTER TABLE orders
+    ADD COLUMN cash_on_delivery BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN cod_collected_at TIMESTAMPTZ,
+    ADD COLUMN cod_collected_amount DECIMAL(10, 2);
+
+CREATE INDEX orders_cod_outstanding_idx ON orders (created_at)
+    WHERE cash_on_delivery AND cod_collected_at IS NULL;
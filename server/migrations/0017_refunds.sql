@@ -0,0 +1,19 @@
+-- refunds issued through the payment provider, partial ones add up until the
+-- payment is fully refunded
+ALTER TYPE order_status ADD VALUE IF NOT EXISTS 'refunded';
+ALTER TYPE order_status ADD VALUE IF NOT EXISTS 'partiallyrefunded';
+
+CREATE TABLE refunds (
+    refund_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    order_id UUID NOT NULL REFERENCES orders(order_id) ON DELETE CASCADE,
+    payment_id UUID NOT NULL REFERENCES payments(payment_id) ON DELETE CASCADE,
+    amount DECIMAL(10, 2) NOT NULL CHECK (amount > 0),
+    -- the provider's refund id, none for cash on delivery paid back by hand
+    provider_ref VARCHAR(255),
+    reason TEXT,
+    restocked BOOLEAN NOT NULL DEFAULT FALSE,
+    created_by UUID REFERENCES users(user_id) ON DELETE SET NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX refunds_order_idx ON refunds (order_id);
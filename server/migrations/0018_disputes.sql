@@ -0,0 +1,21 @@
+-- chargebacks and disputes reported by payment provider webhooks
+CREATE TYPE dispute_status AS ENUM ('needs_response', 'under_review', 'won', 'lost', 'closed');
+
+CREATE TABLE disputes (
+    dispute_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    order_id UUID NOT NULL REFERENCES orders(order_id) ON DELETE CASCADE,
+    payment_id UUID REFERENCES payments(payment_id) ON DELETE SET NULL,
+    provider VARCHAR(20) NOT NULL,
+    provider_ref VARCHAR(255) NOT NULL,
+    amount DECIMAL(10, 2) NOT NULL,
+    currency VARCHAR(3) NOT NULL,
+    reason TEXT,
+    status dispute_status NOT NULL,
+    evidence_due_by TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    UNIQUE (provider, provider_ref)
+);
+
+CREATE INDEX disputes_open_idx ON disputes (evidence_due_by)
+    WHERE status IN ('needs_response', 'under_review');
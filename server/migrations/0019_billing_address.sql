@@ -0,0 +1,4 @@
+-- billing address on orders, existing orders were billed to where they shipped
+ALTER TABLE orders ADD COLUMN billing_address TEXT;
+UPDATE orders SET billing_address = shipping_address;
+ALTER TABLE orders ALTER COLUMN billing_address SET NOT NULL;
@@ -0,0 +1,11 @@
+-- EU VAT numbers for business customers, cross-border B2B orders are
+-- reverse charged and keep the validation result as evidence
+ALTER TABLE users
+    ADD COLUMN vat_number VARCHAR(20),
+    ADD COLUMN vat_company_name TEXT,
+    ADD COLUMN vat_checked_at TIMESTAMPTZ;
+
+ALTER TABLE orders
+    ADD COLUMN vat_number VARCHAR(20),
+    ADD COLUMN reverse_charge BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN vat_evidence JSONB;
@@ -0,0 +1,28 @@
+-- B2B quotes: a customer's cart sent for pricing, answered by an admin with
+-- prices and an expiry, and ordered at those prices once accepted
+CREATE TYPE quote_status AS ENUM ('requested', 'quoted', 'accepted', 'declined', 'ordered');
+
+CREATE TABLE quotes (
+    quote_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+    status quote_status NOT NULL DEFAULT 'requested',
+    note TEXT,
+    admin_note TEXT,
+    expires_at TIMESTAMPTZ,
+    order_id UUID REFERENCES orders(order_id) ON DELETE SET NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX quotes_user_idx ON quotes (user_id, created_at DESC);
+CREATE INDEX quotes_status_idx ON quotes (status, created_at);
+
+CREATE TABLE quote_items (
+    quote_id UUID NOT NULL REFERENCES quotes(quote_id) ON DELETE CASCADE,
+    product_id UUID NOT NULL REFERENCES products(product_id) ON DELETE CASCADE,
+    quantity INTEGER NOT NULL CHECK (quantity > 0),
+    -- list price when the quote was requested
+    list_price DECIMAL(10, 2) NOT NULL,
+    quoted_price DECIMAL(10, 2) CHECK (quoted_price >= 0),
+    PRIMARY KEY (quote_id, product_id)
+);
@@ -0,0 +1,19 @@
+-- quantity price breaks, a line of at least min_quantity units pays the tier
+-- price per unit, below the lowest tier the product price applies
+CREATE TABLE price_tiers (
+    product_id UUID NOT NULL REFERENCES products(product_id) ON DELETE CASCADE,
+    min_quantity INTEGER NOT NULL CHECK (min_quantity > 1),
+    price DECIMAL(10, 2) NOT NULL CHECK (price >= 0),
+    PRIMARY KEY (product_id, min_quantity)
+);
+
+-- per unit price of a product bought in the given quantity, shared by the cart,
+-- the checkout and quotes so they always agree
+CREATE FUNCTION unit_price(product UUID, quantity INTEGER) RETURNS DECIMAL(10, 2) AS $$
+    SELECT COALESCE(
+        (SELECT t.price FROM price_tiers t
+        WHERE t.product_id = product AND t.min_quantity <= quantity
+        ORDER BY t.min_quantity DESC LIMIT 1),
+        (SELECT p.price FROM products p WHERE p.product_id = product)
+    )
+$$ LANGUAGE sql STABLE;
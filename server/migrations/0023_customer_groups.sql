@@ -0,0 +1,37 @@
+-- customer groups, assigned by admins, every customer starts in retail
+CREATE TYPE customer_group AS ENUM ('retail', 'wholesale', 'vip');
+
+ALTER TABLE users ADD COLUMN customer_group customer_group NOT NULL DEFAULT 'retail';
+
+-- percentage taken off every price for a group, groups without a row pay full price
+CREATE TABLE customer_group_discounts (
+    customer_group customer_group PRIMARY KEY,
+    discount_percent DECIMAL(5, 2) NOT NULL CHECK (discount_percent > 0 AND discount_percent <= 100)
+);
+
+-- fixed per-group product prices, these replace both the tiers and the group discount
+CREATE TABLE group_prices (
+    product_id UUID NOT NULL REFERENCES products(product_id) ON DELETE CASCADE,
+    customer_group customer_group NOT NULL,
+    price DECIMAL(10, 2) NOT NULL CHECK (price >= 0),
+    PRIMARY KEY (product_id, customer_group)
+);
+
+-- what a buyer pays per unit, the group price when there is one, otherwise the
+-- tier or list price less the group discount
+CREATE FUNCTION unit_price(product UUID, quantity INTEGER, buyer UUID) RETURNS DECIMAL(10, 2) AS $$
+    SELECT COALESCE(
+        (SELECT gp.price FROM group_prices gp
+        JOIN users u ON u.customer_group = gp.customer_group
+        WHERE u.user_id = buyer AND gp.product_id = product),
+        ROUND(
+            unit_price(product, quantity) * (100 - COALESCE(
+                (SELECT d.discount_percent FROM customer_group_discounts d
+                JOIN users u ON u.customer_group = d.customer_group
+                WHERE u.user_id = buyer),
+                0
+            )) / 100,
+            2
+        )
+    )
+$$ LANGUAGE sql STABLE;
@@ -0,0 +1,22 @@
+-- customers asking for wholesale prices, approval moves them into the wholesale group
+CREATE TYPE application_status AS ENUM ('pending', 'approved', 'rejected');
+
+CREATE TABLE wholesale_applications (
+    application_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+    company_name VARCHAR(255) NOT NULL,
+    company_address TEXT NOT NULL,
+    vat_number VARCHAR(20),
+    website VARCHAR(255),
+    message TEXT,
+    status application_status NOT NULL DEFAULT 'pending',
+    admin_note TEXT,
+    reviewed_by UUID REFERENCES users(user_id) ON DELETE SET NULL,
+    reviewed_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- one open application per customer
+CREATE UNIQUE INDEX wholesale_applications_pending_idx
+    ON wholesale_applications (user_id) WHERE status = 'pending';
+CREATE INDEX wholesale_applications_status_idx ON wholesale_applications (status, created_at);
@@ -0,0 +1,36 @@
+-- bundles sell several products together at one price, in the cart and on the
+-- order they are the component lines tagged with the bundle they came from
+CREATE TABLE bundles (
+    bundle_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    name VARCHAR(255) NOT NULL,
+    description TEXT,
+    price DECIMAL(10, 2) NOT NULL CHECK (price >= 0),
+    is_available BOOLEAN NOT NULL DEFAULT TRUE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE bundle_items (
+    bundle_id UUID NOT NULL REFERENCES bundles(bundle_id) ON DELETE CASCADE,
+    product_id UUID NOT NULL REFERENCES products(product_id) ON DELETE CASCADE,
+    quantity INTEGER NOT NULL CHECK (quantity > 0),
+    PRIMARY KEY (bundle_id, product_id)
+);
+
+ALTER TABLE cart_items ADD COLUMN bundle_id UUID REFERENCES bundles(bundle_id) ON DELETE CASCADE;
+ALTER TABLE order_details ADD COLUMN bundle_id UUID REFERENCES bundles(bundle_id) ON DELETE SET NULL;
+
+-- per unit price of a component when bought in the bundle, the bundle price split
+-- over the components by their list prices, a bundle of free products costs nothing
+CREATE FUNCTION bundle_unit_price(bundle UUID, product UUID) RETURNS DECIMAL(10, 2) AS $$
+    SELECT COALESCE(ROUND(
+        b.price * p.price / NULLIF(
+            (SELECT SUM(cp.price * bi.quantity) FROM bundle_items bi
+            JOIN products cp ON cp.product_id = bi.product_id
+            WHERE bi.bundle_id = bundle),
+            0
+        ),
+        2
+    ), 0)
+    FROM bundles b, products p
+    WHERE b.bundle_id = bundle AND p.product_id = product
+$$ LANGUAGE sql STABLE;
@@ -0,0 +1,21 @@
+-- kits are products built from other products when sold, they hold no stock of
+-- their own and selling one takes its components out of stock
+CREATE TABLE kit_components (
+    kit_id UUID NOT NULL REFERENCES products(product_id) ON DELETE CASCADE,
+    component_id UUID NOT NULL REFERENCES products(product_id) ON DELETE RESTRICT,
+    quantity INTEGER NOT NULL CHECK (quantity > 0),
+    PRIMARY KEY (kit_id, component_id),
+    CHECK (kit_id <> component_id)
+);
+
+CREATE INDEX kit_components_component_idx ON kit_components (component_id);
+
+-- sellable stock of a product, for a kit the number of kits its components cover
+CREATE FUNCTION product_stock(product UUID) RETURNS INTEGER AS $$
+    SELECT COALESCE(
+        (SELECT MIN(c.stock_quantity / k.quantity) FROM kit_components k
+        JOIN products c ON c.product_id = k.component_id
+        WHERE k.kit_id = product),
+        (SELECT p.stock_quantity FROM products p WHERE p.product_id = product)
+    )
+$$ LANGUAGE sql STABLE;
@@ -0,0 +1,53 @@
+-- products sold by weight or volume, quantities become decimals and carts only
+-- take multiples of the product's quantity step (0.5 kg, 0.25 l, ...)
+CREATE TYPE product_unit AS ENUM ('piece', 'kg', 'liter');
+
+ALTER TABLE products
+    ADD COLUMN unit product_unit NOT NULL DEFAULT 'piece',
+    ADD COLUMN quantity_step DECIMAL(10, 3) NOT NULL DEFAULT 1 CHECK (quantity_step > 0),
+    ALTER COLUMN stock_quantity TYPE DECIMAL(12, 3);
+
+ALTER TABLE cart_items ALTER COLUMN quantity TYPE DECIMAL(10, 3);
+ALTER TABLE order_details ALTER COLUMN quantity TYPE DECIMAL(10, 3);
+ALTER TABLE quote_items ALTER COLUMN quantity TYPE DECIMAL(10, 3);
+
+-- the pricing and stock functions take and return decimal quantities now
+DROP FUNCTION unit_price(UUID, INTEGER, UUID);
+DROP FUNCTION unit_price(UUID, INTEGER);
+DROP FUNCTION product_stock(UUID);
+
+CREATE FUNCTION unit_price(product UUID, quantity DECIMAL) RETURNS DECIMAL(10, 2) AS $$
+    SELECT COALESCE(
+        (SELECT t.price FROM price_tiers t
+        WHERE t.product_id = product AND t.min_quantity <= quantity
+        ORDER BY t.min_quantity DESC LIMIT 1),
+        (SELECT p.price FROM products p WHERE p.product_id = product)
+    )
+$$ LANGUAGE sql STABLE;
+
+CREATE FUNCTION unit_price(product UUID, quantity DECIMAL, buyer UUID) RETURNS DECIMAL(10, 2) AS $$
+    SELECT COALESCE(
+        (SELECT gp.price FROM group_prices gp
+        JOIN users u ON u.customer_group = gp.customer_group
+        WHERE u.user_id = buyer AND gp.product_id = product),
+        ROUND(
+            unit_price(product, quantity) * (100 - COALESCE(
+                (SELECT d.discount_percent FROM customer_group_discounts d
+                JOIN users u ON u.customer_group = d.customer_group
+                WHERE u.user_id = buyer),
+                0
+            )) / 100,
+            2
+        )
+    )
+$$ LANGUAGE sql STABLE;
+
+-- kits still come in whole pieces
+CREATE FUNCTION product_stock(product UUID) RETURNS DECIMAL(12, 3) AS $$
+    SELECT COALESCE(
+        (SELECT MIN(FLOOR(c.stock_quantity / k.quantity)) FROM kit_components k
+        JOIN products c ON c.product_id = k.component_id
+        WHERE k.kit_id = product),
+        (SELECT p.stock_quantity FROM products p WHERE p.product_id = product)
+    )
+$$ LANGUAGE sql STABLE;
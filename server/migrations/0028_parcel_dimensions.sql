@@ -0,0 +1,13 @@
+-- what a product weighs and measures packed, summed per cart for shipping.
+-- Left empty a product ships weightless, kg products weigh their quantity
+ALTER TABLE products
+    ADD COLUMN weight_kg DECIMAL(10, 3) CHECK (weight_kg >= 0),
+    ADD COLUMN length_cm DECIMAL(8, 1) CHECK (length_cm > 0),
+    ADD COLUMN width_cm DECIMAL(8, 1) CHECK (width_cm > 0),
+    ADD COLUMN height_cm DECIMAL(8, 1) CHECK (height_cm > 0);
+
+-- weight based rates: the zone rate plus per_kg for every billable kg,
+-- methods stop being offered once the parcel is over max_weight_kg
+ALTER TABLE shipping_rates
+    ADD COLUMN per_kg DECIMAL(10, 2) NOT NULL DEFAULT 0 CHECK (per_kg >= 0),
+    ADD COLUMN max_weight_kg DECIMAL(10, 3) CHECK (max_weight_kg > 0);
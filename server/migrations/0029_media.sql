@@ -0,0 +1,20 @@
+-- product and review images, stored as object keys of the media bucket and
+-- turned into (signed) URLs when they are served
+CREATE TABLE product_images (
+    image_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    product_id UUID NOT NULL REFERENCES products(product_id) ON DELETE CASCADE,
+    object_key VARCHAR(500) NOT NULL,
+    position INTEGER NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX product_images_product_idx ON product_images (product_id, position);
+
+CREATE TABLE review_images (
+    image_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    review_id UUID NOT NULL REFERENCES reviews(review_id) ON DELETE CASCADE,
+    object_key VARCHAR(500) NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX review_images_review_idx ON review_images (review_id);
@@ -0,0 +1,10 @@
+-- smaller copies of product images made by a background job after upload,
+-- null keys mean that size is the original (or the job hasn't run yet)
+ALTER TABLE product_images
+    ADD COLUMN thumbnail_key VARCHAR(500),
+    ADD COLUMN medium_key VARCHAR(500),
+    ADD COLUMN resized_at TIMESTAMPTZ,
+    -- why the job gave up on an image, it isn't retried
+    ADD COLUMN resize_error TEXT;
+
+CREATE INDEX product_images_pending_idx ON product_images (created_at) WHERE resized_at IS NULL;
@@ -0,0 +1,34 @@
+-- domain events, written in the transaction of the change they describe and
+-- published to the message broker by the outbox relay
+CREATE TABLE outbox_events (
+    event_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    -- order.created, stock.changed, user.registered
+    event_type VARCHAR(100) NOT NULL,
+    -- the order, product or user the event is about, the broker message key
+    aggregate_id UUID NOT NULL,
+    payload JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    published_at TIMESTAMPTZ
+);
+
+CREATE INDEX outbox_events_pending_idx ON outbox_events (created_at) WHERE published_at IS NULL;
+
+-- stock moves in many places (checkout, cancellations, refunds, admin edits),
+-- the trigger records every one of them
+CREATE FUNCTION record_stock_changed() RETURNS TRIGGER AS $$
+BEGIN
+    INSERT INTO outbox_events (event_type, aggregate_id, payload)
+    VALUES ('stock.changed', NEW.product_id, jsonb_build_object(
+        'product_id', NEW.product_id,
+        'stock_quantity', NEW.stock_quantity,
+        'previous_stock_quantity', OLD.stock_quantity
+    ));
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER products_stock_changed
+    AFTER UPDATE OF stock_quantity ON products
+    FOR EACH ROW
+    WHEN (OLD.stock_quantity IS DISTINCT FROM NEW.stock_quantity)
+    EXECUTE FUNCTION record_stock_changed();
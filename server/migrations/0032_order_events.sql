@@ -0,0 +1,91 @@
+-- the lifecycle of an order as an append-only stream of events. The status on
+-- the orders row is projected from it: events that move the order carry the
+-- status it moves to and are applied to the row as they are appended
+ALTER TABLE orders ADD COLUMN last_event_sequence INT NOT NULL DEFAULT 0;
+
+CREATE TABLE order_events (
+    event_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    order_id UUID NOT NULL REFERENCES orders(order_id) ON DELETE CASCADE,
+    -- 1, 2, 3... per order, assigned on insert
+    sequence INT NOT NULL,
+    event_type VARCHAR(100) NOT NULL,
+    -- the status the order moved to, none for events that leave it as it is
+    status order_status,
+    data JSONB NOT NULL DEFAULT '{}',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    UNIQUE (order_id, sequence)
+);
+
+-- number the event and apply it to the order. Updating the order row first
+-- locks it, so concurrent events of one order queue up and get their own
+-- sequence
+CREATE FUNCTION project_order_event() RETURNS TRIGGER AS $$
+BEGIN
+    UPDATE orders SET
+        last_event_sequence = last_event_sequence + 1,
+        status = COALESCE(NEW.status, status),
+        -- only orders waiting for their payment expire
+        payment_expires_at = CASE
+            WHEN COALESCE(NEW.status, status) = 'pendingpayment' THEN payment_expires_at
+        END
+    WHERE order_id = NEW.order_id
+    RETURNING last_event_sequence INTO NEW.sequence;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER order_events_project
+    BEFORE INSERT ON order_events
+    FOR EACH ROW EXECUTE FUNCTION project_order_event();
+
+-- events are never changed or taken back, only removed along with their order
+CREATE FUNCTION reject_order_event_change() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'DELETE' AND pg_trigger_depth() > 1 THEN
+        RETURN OLD;
+    END IF;
+    RAISE EXCEPTION 'order events are append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER order_events_append_only
+    BEFORE UPDATE OR DELETE ON order_events
+    FOR EACH ROW EXECUTE FUNCTION reject_order_event_change();
+
+-- the status only moves through events, so the stream always explains it
+CREATE FUNCTION reject_unrecorded_status_change() RETURNS TRIGGER AS $$
+BEGIN
+    IF NEW.status IS DISTINCT FROM OLD.status
+        AND NEW.last_event_sequence = OLD.last_event_sequence THEN
+        RAISE EXCEPTION 'order status changes must be recorded as order events';
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER orders_status_from_events
+    BEFORE UPDATE OF status ON orders
+    FOR EACH ROW EXECUTE FUNCTION reject_unrecorded_status_change();
+
+-- the history recorded so far becomes the start of each stream, followed by
+-- the status every order has now, which the history didn't always show
+ALTER TABLE order_events DISABLE TRIGGER order_events_project;
+
+INSERT INTO order_events (order_id, sequence, event_type, data, created_at)
+SELECT order_id,
+    ROW_NUMBER() OVER (PARTITION BY order_id ORDER BY created_at, history_id),
+    event, details, created_at
+FROM order_history;
+
+INSERT INTO order_events (order_id, sequence, event_type, status)
+SELECT o.order_id, COALESCE(MAX(e.sequence), 0) + 1, 'order.migrated', o.status
+FROM orders o LEFT JOIN order_events e ON e.order_id = o.order_id
+GROUP BY o.order_id, o.status;
+
+UPDATE orders SET last_event_sequence = (
+    SELECT MAX(sequence) FROM order_events e WHERE e.order_id = orders.order_id
+);
+
+ALTER TABLE order_events ENABLE TRIGGER order_events_project;
+
+DROP TABLE order_history;
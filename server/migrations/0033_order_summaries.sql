@@ -0,0 +1,88 @@
+-- the admin order list as it is shown, kept up to date by triggers on the
+-- tables it is made of so a page is one indexed read
+CREATE TABLE order_summaries (
+    order_id UUID PRIMARY KEY REFERENCES orders(order_id) ON DELETE CASCADE,
+    user_id UUID NOT NULL,
+    customer_name VARCHAR(201) NOT NULL,
+    customer_email VARCHAR(255) NOT NULL,
+    status order_status NOT NULL,
+    item_count INT NOT NULL,
+    total_quantity DECIMAL(12, 3) NOT NULL,
+    total_amount DECIMAL(10, 2) NOT NULL,
+    shipping_country VARCHAR(2),
+    order_date TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX order_summaries_created_idx ON order_summaries (created_at DESC, order_id);
+
+CREATE FUNCTION refresh_order_summary(summary_order_id UUID) RETURNS VOID AS $$
+BEGIN
+    INSERT INTO order_summaries (
+        order_id, user_id, customer_name, customer_email, status, item_count,
+        total_quantity, total_amount, shipping_country, order_date, created_at
+    )
+    SELECT o.order_id, o.user_id, u.first_name || ' ' || u.last_name, u.email, o.status,
+        (SELECT COUNT(*) FROM order_details od WHERE od.order_id = o.order_id),
+        (SELECT COALESCE(SUM(od.quantity), 0) FROM order_details od WHERE od.order_id = o.order_id),
+        o.total_amount, o.shipping_country, o.order_date, o.created_at
+    FROM orders o JOIN users u ON u.user_id = o.user_id
+    WHERE o.order_id = summary_order_id
+    ON CONFLICT (order_id) DO UPDATE SET
+        user_id = EXCLUDED.user_id,
+        customer_name = EXCLUDED.customer_name,
+        customer_email = EXCLUDED.customer_email,
+        status = EXCLUDED.status,
+        item_count = EXCLUDED.item_count,
+        total_quantity = EXCLUDED.total_quantity,
+        total_amount = EXCLUDED.total_amount,
+        shipping_country = EXCLUDED.shipping_country,
+        order_date = EXCLUDED.order_date,
+        created_at = EXCLUDED.created_at;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE FUNCTION refresh_order_summary_of_order() RETURNS TRIGGER AS $$
+BEGIN
+    PERFORM refresh_order_summary(NEW.order_id);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER orders_summary
+    AFTER INSERT OR UPDATE OF user_id, status, total_amount, shipping_country, order_date
+    ON orders
+    FOR EACH ROW EXECUTE FUNCTION refresh_order_summary_of_order();
+
+CREATE FUNCTION refresh_order_summary_of_line() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP <> 'INSERT' AND OLD.order_id IS NOT NULL THEN
+        PERFORM refresh_order_summary(OLD.order_id);
+    END IF;
+    IF TG_OP <> 'DELETE' AND NEW.order_id IS NOT NULL
+        AND NEW.order_id IS DISTINCT FROM OLD.order_id THEN
+        PERFORM refresh_order_summary(NEW.order_id);
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER order_details_summary
+    AFTER INSERT OR UPDATE OR DELETE ON order_details
+    FOR EACH ROW EXECUTE FUNCTION refresh_order_summary_of_line();
+
+CREATE FUNCTION refresh_order_summaries_of_customer() RETURNS TRIGGER AS $$
+BEGIN
+    UPDATE order_summaries SET
+        customer_name = NEW.first_name || ' ' || NEW.last_name,
+        customer_email = NEW.email
+    WHERE user_id = NEW.user_id;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER users_order_summaries
+    AFTER UPDATE OF first_name, last_name, email ON users
+    FOR EACH ROW EXECUTE FUNCTION refresh_order_summaries_of_customer();
+
+SELECT refresh_order_summary(order_id) FROM orders;
@@ -0,0 +1,24 @@
+-- new orders and failed payments are announced to the admin dashboards of
+-- every instance over this NOTIFY channel once their transaction commits
+CREATE FUNCTION announce_admin_event() RETURNS TRIGGER AS $$
+BEGIN
+    PERFORM pg_notify('admin_events', json_build_object(
+        'type', NEW.event_type,
+        'order_id', NEW.order_id,
+        'status', s.status,
+        'customer_name', s.customer_name,
+        'item_count', s.item_count,
+        'total_amount', s.total_amount,
+        'details', NEW.data,
+        'occurred_at', NEW.created_at
+    )::text)
+    FROM order_summaries s WHERE s.order_id = NEW.order_id;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER order_events_admin_feed
+    AFTER INSERT ON order_events
+    FOR EACH ROW
+    WHEN (NEW.event_type IN ('order.created', 'payment.failed'))
+    EXECUTE FUNCTION announce_admin_event();
@@ -0,0 +1,81 @@
+-- push notifications to the customers' phones through FCM (android) and APNs (ios)
+CREATE TYPE push_platform AS ENUM ('android', 'ios');
+
+CREATE TABLE push_devices (
+    device_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+    platform push_platform NOT NULL,
+    -- the FCM registration token or APNs device token, a phone has one at a time
+    token TEXT NOT NULL UNIQUE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    last_seen_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX push_devices_user_idx ON push_devices (user_id);
+
+-- what a customer wants pushed, checked when a message goes out
+ALTER TABLE users
+    ADD COLUMN push_order_updates BOOLEAN NOT NULL DEFAULT TRUE,
+    ADD COLUMN push_back_in_stock BOOLEAN NOT NULL DEFAULT TRUE;
+
+-- messages waiting for the push dispatcher, only queued for customers with a device
+CREATE TABLE push_messages (
+    message_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+    -- order.status or stock.back
+    kind VARCHAR(50) NOT NULL,
+    data JSONB NOT NULL DEFAULT '{}',
+    -- pending, sent, skipped (opted out or no device left) or failed
+    status VARCHAR(20) NOT NULL DEFAULT 'pending',
+    attempts INT NOT NULL DEFAULT 0,
+    last_error TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    sent_at TIMESTAMPTZ
+);
+
+CREATE INDEX push_messages_pending_idx ON push_messages (created_at) WHERE status = 'pending';
+
+-- the customer hears when their order moves, placing it is their own doing
+CREATE FUNCTION queue_order_status_push() RETURNS TRIGGER AS $$
+BEGIN
+    INSERT INTO push_messages (user_id, kind, data)
+    SELECT o.user_id, 'order.status', jsonb_build_object(
+        'order_id', o.order_id,
+        'status', NEW.status
+    )
+    FROM orders o
+    WHERE o.order_id = NEW.order_id
+    AND EXISTS (SELECT 1 FROM push_devices d WHERE d.user_id = o.user_id);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER order_events_push
+    AFTER INSERT ON order_events
+    FOR EACH ROW
+    WHEN (NEW.status IS NOT NULL
+        AND NEW.event_type NOT IN ('order.created', 'order.seeded', 'order.migrated'))
+    EXECUTE FUNCTION queue_order_status_push();
+
+-- customers with a product in a cart or saved for later hear when it is back
+CREATE FUNCTION queue_back_in_stock_push() RETURNS TRIGGER AS $$
+BEGIN
+    INSERT INTO push_messages (user_id, kind, data)
+    SELECT DISTINCT c.user_id, 'stock.back', jsonb_build_object(
+        'product_id', NEW.product_id,
+        'name', NEW.name
+    )
+    FROM cart_items ci
+    JOIN carts c ON c.cart_id = ci.cart_id
+    WHERE ci.product_id = NEW.product_id
+    AND c.user_id IS NOT NULL
+    AND EXISTS (SELECT 1 FROM push_devices d WHERE d.user_id = c.user_id);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER products_back_in_stock_push
+    AFTER UPDATE OF stock_quantity ON products
+    FOR EACH ROW
+    WHEN (OLD.stock_quantity <= 0 AND NEW.stock_quantity > 0)
+    EXECUTE FUNCTION queue_back_in_stock_push();
@@ -0,0 +1,37 @@
+-- text messages to the customers' phones: delivery updates and login codes
+ALTER TABLE users
+    ADD COLUMN sms_delivery_updates BOOLEAN NOT NULL DEFAULT TRUE,
+    ADD COLUMN sms_two_factor BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- every text, delivery updates wait here for the dispatcher and codes are
+-- logged once sent, the rate limit per phone number counts both
+CREATE TABLE sms_messages (
+    message_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+    phone VARCHAR(20) NOT NULL,
+    -- delivery or code
+    kind VARCHAR(50) NOT NULL,
+    -- none for codes, they are only ever on the phone
+    body TEXT,
+    -- pending, sent, skipped (opted out, country off or rate limited) or failed
+    status VARCHAR(20) NOT NULL DEFAULT 'pending',
+    attempts INT NOT NULL DEFAULT 0,
+    last_error TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    sent_at TIMESTAMPTZ
+);
+
+CREATE INDEX sms_messages_pending_idx ON sms_messages (created_at) WHERE status = 'pending';
+CREATE INDEX sms_messages_sent_idx ON sms_messages (phone, sent_at) WHERE status = 'sent';
+
+-- a code texted to log in or to turn two-factor login on, kept hashed
+CREATE TABLE sms_challenges (
+    challenge_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+    -- login or enroll
+    purpose VARCHAR(20) NOT NULL,
+    code_hash TEXT NOT NULL,
+    attempts INT NOT NULL DEFAULT 0,
+    expires_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,44 @@
+-- addresses mail is no longer sent to: hard bounces and spam complaints
+-- reported by the mail provider
+CREATE TABLE email_suppressions (
+    email VARCHAR(255) PRIMARY KEY,
+    -- bounce or complaint
+    reason VARCHAR(20) NOT NULL,
+    detail TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- mail waiting for the email dispatcher, addressed when it goes out so a
+-- changed address is used
+CREATE TABLE email_messages (
+    message_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+    -- order.created
+    kind VARCHAR(50) NOT NULL,
+    data JSONB NOT NULL DEFAULT '{}',
+    -- pending, sent, skipped (undeliverable or no provider) or failed
+    status VARCHAR(20) NOT NULL DEFAULT 'pending',
+    attempts INT NOT NULL DEFAULT 0,
+    last_error TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    sent_at TIMESTAMPTZ
+);
+
+CREATE INDEX email_messages_pending_idx ON email_messages (created_at) WHERE status = 'pending';
+
+-- the customer gets a confirmation of every order they place
+CREATE FUNCTION queue_order_confirmation_email() RETURNS TRIGGER AS $$
+BEGIN
+    INSERT INTO email_messages (user_id, kind, data)
+    SELECT o.user_id, 'order.created', NEW.data || jsonb_build_object('order_id', o.order_id)
+    FROM orders o
+    WHERE o.order_id = NEW.order_id;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER order_events_confirmation_email
+    AFTER INSERT ON order_events
+    FOR EACH ROW
+    WHEN (NEW.event_type = 'order.created')
+    EXECUTE FUNCTION queue_order_confirmation_email();
@@ -0,0 +1,39 @@
+-- newsletter subscribers with double opt-in: an address is only mailed once
+-- its owner confirmed it through the link sent to it
+CREATE TABLE newsletter_subscribers (
+    subscriber_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    -- lowercased
+    email VARCHAR(255) NOT NULL UNIQUE,
+    -- pending, confirmed or unsubscribed
+    status VARCHAR(20) NOT NULL DEFAULT 'pending',
+    -- the link in the confirmation mail, hashed, until it is used
+    confirm_token_hash TEXT,
+    confirm_expires_at TIMESTAMPTZ,
+    -- goes in every newsletter so any of them unsubscribes
+    unsubscribe_token TEXT NOT NULL UNIQUE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    confirmed_at TIMESTAMPTZ,
+    unsubscribed_at TIMESTAMPTZ
+);
+
+CREATE INDEX newsletter_subscribers_confirm_idx ON newsletter_subscribers (confirm_token_hash);
+
+-- who asked for what and from where, kept after unsubscribing as the record
+-- of the consent the mail was sent under
+CREATE TABLE newsletter_consents (
+    consent_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    subscriber_id UUID NOT NULL REFERENCES newsletter_subscribers(subscriber_id) ON DELETE CASCADE,
+    -- subscribe, confirm or unsubscribe
+    action VARCHAR(20) NOT NULL,
+    ip_address VARCHAR(45),
+    user_agent TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX newsletter_consents_subscriber_idx ON newsletter_consents (subscriber_id, created_at);
+
+-- mail to addresses that have no account, like newsletter confirmations
+ALTER TABLE email_messages
+    ALTER COLUMN user_id DROP NOT NULL,
+    ADD COLUMN recipient VARCHAR(255),
+    ADD CONSTRAINT email_messages_addressed CHECK (user_id IS NOT NULL OR recipient IS NOT NULL);
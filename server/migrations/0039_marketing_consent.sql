@@ -0,0 +1,19 @@
+-- whether a customer agreed to marketing, as they last said it. Until they do
+-- they only hear about their own orders
+ALTER TABLE users ADD COLUMN marketing_opt_in BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- every time a customer gave or took back their consent, with where and under
+-- which version of the privacy policy
+CREATE TABLE marketing_consents (
+    consent_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+    granted BOOLEAN NOT NULL,
+    -- registration, checkout or profile
+    source VARCHAR(20) NOT NULL,
+    policy_version VARCHAR(50) NOT NULL,
+    ip_address VARCHAR(45),
+    user_agent TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX marketing_consents_user_idx ON marketing_consents (user_id, created_at DESC);
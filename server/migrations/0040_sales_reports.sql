@@ -0,0 +1,11 @@
+-- the scheduled sales reports that went out, so a restart or a second
+-- server doesn't mail the same period twice
+CREATE TABLE sales_report_runs (
+    -- daily or weekly
+    schedule VARCHAR(10) NOT NULL,
+    period_start TIMESTAMPTZ NOT NULL,
+    period_end TIMESTAMPTZ NOT NULL,
+    recipients INT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (schedule, period_start)
+);
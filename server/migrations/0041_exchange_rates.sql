@@ -0,0 +1,13 @@
+-- exchange rates as the provider gave them, one row per currency per fetch.
+-- The latest fetch is the one in use, older ones are pruned after 90 days
+CREATE TABLE exchange_rates (
+    base VARCHAR(3) NOT NULL,
+    currency VARCHAR(3) NOT NULL,
+    -- units of currency one unit of base buys
+    rate DECIMAL(20, 10) NOT NULL CHECK (rate > 0),
+    provider VARCHAR(30) NOT NULL,
+    fetched_at TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (base, currency, fetched_at)
+);
+
+CREATE INDEX exchange_rates_fetched_idx ON exchange_rates (base, fetched_at DESC);
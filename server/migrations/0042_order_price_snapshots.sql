@@ -0,0 +1,36 @@
+-- what each line was sold for, taken from the checkout pricing so editing a
+-- product's price or the tax rate later never changes an order
+ALTER TABLE order_details
+    ADD COLUMN tax_rate DECIMAL(7, 4) NOT NULL DEFAULT 0,
+    ADD COLUMN discount_amount DECIMAL(10, 2) NOT NULL DEFAULT 0,
+    ADD COLUMN line_total DECIMAL(12, 2);
+
+UPDATE order_details SET line_total = ROUND(price_per_unit * quantity, 2);
+ALTER TABLE order_details ALTER COLUMN line_total SET NOT NULL;
+
+-- the breakdown of total_amount, empty for orders placed before it was kept
+ALTER TABLE orders
+    ADD COLUMN subtotal_amount DECIMAL(10, 2),
+    ADD COLUMN discount_amount DECIMAL(10, 2),
+    ADD COLUMN tax_amount DECIMAL(10, 2),
+    ADD COLUMN shipping_amount DECIMAL(10, 2),
+    ADD COLUMN gift_wrap_amount DECIMAL(10, 2);
+
+-- the snapshot is written once with the line
+CREATE FUNCTION freeze_order_line_prices() RETURNS TRIGGER AS $$
+BEGIN
+    IF NEW.price_per_unit IS DISTINCT FROM OLD.price_per_unit
+        OR NEW.quantity IS DISTINCT FROM OLD.quantity
+        OR NEW.tax_rate IS DISTINCT FROM OLD.tax_rate
+        OR NEW.discount_amount IS DISTINCT FROM OLD.discount_amount
+        OR NEW.line_total IS DISTINCT FROM OLD.line_total THEN
+        RAISE EXCEPTION 'order line % was priced at checkout and cannot be repriced',
+            OLD.order_detail_id;
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER order_details_frozen_prices
+    BEFORE UPDATE ON order_details
+    FOR EACH ROW EXECUTE FUNCTION freeze_order_line_prices();
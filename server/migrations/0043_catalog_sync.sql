@@ -0,0 +1,33 @@
+-- when a product last changed, so warehouse systems can sync only what did
+ALTER TABLE products ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW();
+UPDATE products SET updated_at = COALESCE(created_at, NOW());
+CREATE INDEX products_updated_at_idx ON products (updated_at);
+
+CREATE FUNCTION touch_product() RETURNS TRIGGER AS $$
+BEGIN
+    NEW.updated_at = NOW();
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER products_touch
+    BEFORE UPDATE ON products
+    FOR EACH ROW EXECUTE FUNCTION touch_product();
+
+-- deleted products, a delta tells the warehouse to drop them
+CREATE TABLE product_deletions (
+    product_id UUID PRIMARY KEY,
+    deleted_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE FUNCTION record_product_deletion() RETURNS TRIGGER AS $$
+BEGIN
+    INSERT INTO product_deletions (product_id) VALUES (OLD.product_id)
+    ON CONFLICT (product_id) DO UPDATE SET deleted_at = NOW();
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER products_deletion
+    AFTER DELETE ON products
+    FOR EACH ROW EXECUTE FUNCTION record_product_deletion();
@@ -0,0 +1,2 @@
+-- deactivated accounts keep their orders and history but can't log in
+ALTER TABLE users ADD COLUMN is_active BOOLEAN NOT NULL DEFAULT TRUE;
@@ -0,0 +1,6 @@
+-- the search box suggests names and categories as the customer types, matched
+-- anywhere in the text so the trigram indexes keep it quick
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+CREATE INDEX products_name_trgm_idx ON products USING gin (lower(name) gin_trgm_ops);
+CREATE INDEX products_category_trgm_idx ON products USING gin (lower(category) gin_trgm_ops);
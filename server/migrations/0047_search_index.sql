@@ -0,0 +1,40 @@
+-- product changes go through the outbox to the search engine's indexer as
+-- well as the broker, the indexer keeps its own place in it
+ALTER TABLE outbox_events ADD COLUMN indexed_at TIMESTAMPTZ;
+CREATE INDEX outbox_events_unindexed_idx ON outbox_events (created_at)
+    WHERE indexed_at IS NULL AND event_type LIKE 'product.%';
+
+-- creates, deletes and edits to what is searched on, however they are made
+CREATE FUNCTION record_product_changed() RETURNS TRIGGER AS $$
+DECLARE
+    changed_id UUID;
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        changed_id := OLD.product_id;
+    ELSE
+        changed_id := NEW.product_id;
+    END IF;
+    INSERT INTO outbox_events (event_type, aggregate_id, payload)
+    VALUES (
+        CASE TG_OP
+            WHEN 'INSERT' THEN 'product.created'
+            WHEN 'UPDATE' THEN 'product.updated'
+            ELSE 'product.deleted'
+        END,
+        changed_id,
+        jsonb_build_object('product_id', changed_id)
+    );
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER products_changed
+    AFTER INSERT OR DELETE ON products
+    FOR EACH ROW EXECUTE FUNCTION record_product_changed();
+
+CREATE TRIGGER products_search_fields_changed
+    AFTER UPDATE ON products
+    FOR EACH ROW
+    WHEN ((OLD.name, OLD.description, OLD.category, OLD.is_available)
+        IS DISTINCT FROM (NEW.name, NEW.description, NEW.category, NEW.is_available))
+    EXECUTE FUNCTION record_product_changed();
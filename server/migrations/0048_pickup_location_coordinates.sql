@@ -0,0 +1,14 @@
+-- where a pickup location is, so customers can be offered the ones near them.
+-- Distances are great-circle, through earthdistance
+CREATE EXTENSION IF NOT EXISTS cube;
+CREATE EXTENSION IF NOT EXISTS earthdistance;
+
+ALTER TABLE pickup_locations
+    ADD COLUMN latitude DOUBLE PRECISION CHECK (latitude BETWEEN -90 AND 90),
+    ADD COLUMN longitude DOUBLE PRECISION CHECK (longitude BETWEEN -180 AND 180),
+    ADD CONSTRAINT pickup_locations_coordinates_check
+        CHECK ((latitude IS NULL) = (longitude IS NULL));
+
+CREATE INDEX pickup_locations_earth_idx ON pickup_locations
+    USING gist (ll_to_earth(latitude, longitude))
+    WHERE latitude IS NOT NULL;
@@ -0,0 +1,60 @@
+-- one deployment can serve several shops. Products, carts and orders belong
+-- to one, requests are served by the store of their host or X-Store-Id
+CREATE TABLE stores (
+    store_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    name VARCHAR(255) NOT NULL,
+    -- the host the shop is served on, shop.example.com
+    host VARCHAR(255) UNIQUE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- what there was before stores, and what hosts without a store of their own get
+INSERT INTO stores (store_id, name) VALUES ('00000000-0000-0000-0000-000000000001', 'Default');
+
+ALTER TABLE products ADD COLUMN store_id UUID NOT NULL
+    DEFAULT '00000000-0000-0000-0000-000000000001' REFERENCES stores(store_id);
+ALTER TABLE carts ADD COLUMN store_id UUID NOT NULL
+    DEFAULT '00000000-0000-0000-0000-000000000001' REFERENCES stores(store_id);
+ALTER TABLE orders ADD COLUMN store_id UUID NOT NULL
+    DEFAULT '00000000-0000-0000-0000-000000000001' REFERENCES stores(store_id);
+CREATE INDEX products_store_idx ON products (store_id, name);
+CREATE INDEX orders_store_idx ON orders (store_id, created_at);
+
+-- customers shop in every store, with an active cart in each
+DROP INDEX carts_one_active_per_user;
+CREATE UNIQUE INDEX carts_one_active_per_user ON carts (user_id, store_id) WHERE is_active;
+
+-- the store an admin manages, admins without one manage every store
+ALTER TABLE users ADD COLUMN store_id UUID REFERENCES stores(store_id);
+
+-- the admin order list is per store
+ALTER TABLE order_summaries ADD COLUMN store_id UUID NOT NULL
+    DEFAULT '00000000-0000-0000-0000-000000000001';
+CREATE INDEX order_summaries_store_idx ON order_summaries (store_id, created_at DESC, order_id);
+
+CREATE OR REPLACE FUNCTION refresh_order_summary(summary_order_id UUID) RETURNS VOID AS $$
+BEGIN
+    INSERT INTO order_summaries (
+        order_id, user_id, customer_name, customer_email, status, item_count,
+        total_quantity, total_amount, shipping_country, order_date, created_at, store_id
+    )
+    SELECT o.order_id, o.user_id, u.first_name || ' ' || u.last_name, u.email, o.status,
+        (SELECT COUNT(*) FROM order_details od WHERE od.order_id = o.order_id),
+        (SELECT COALESCE(SUM(od.quantity), 0) FROM order_details od WHERE od.order_id = o.order_id),
+        o.total_amount, o.shipping_country, o.order_date, o.created_at, o.store_id
+    FROM orders o JOIN users u ON u.user_id = o.user_id
+    WHERE o.order_id = summary_order_id
+    ON CONFLICT (order_id) DO UPDATE SET
+        user_id = EXCLUDED.user_id,
+        customer_name = EXCLUDED.customer_name,
+        customer_email = EXCLUDED.customer_email,
+        status = EXCLUDED.status,
+        item_count = EXCLUDED.item_count,
+        total_quantity = EXCLUDED.total_quantity,
+        total_amount = EXCLUDED.total_amount,
+        shipping_country = EXCLUDED.shipping_country,
+        order_date = EXCLUDED.order_date,
+        created_at = EXCLUDED.created_at,
+        store_id = EXCLUDED.store_id;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,4 @@
+-- staff below admin: support handles orders and refunds, the warehouse ships
+-- them. users.store_id scopes them to a store like it does admins
+ALTER TYPE user_role ADD VALUE 'support';
+ALTER TYPE user_role ADD VALUE 'warehousestaff';
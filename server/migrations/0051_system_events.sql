@@ -0,0 +1,14 @@
+-- what went wrong without anyone doing it, for the staff activity feed:
+-- webhooks that failed and that the sender will retry
+CREATE TABLE system_events (
+    event_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    event_type VARCHAR(100) NOT NULL,
+    details JSONB NOT NULL DEFAULT '{}',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX system_events_created_at_idx ON system_events (created_at DESC);
+CREATE INDEX audit_log_actor_idx ON audit_log (actor_id, created_at DESC);
+-- the order events the feed shows, staff changes and failed payments
+CREATE INDEX order_events_activity_idx ON order_events (created_at DESC)
+    WHERE data ? 'admin_id' OR event_type = 'payment.failed';
@@ -0,0 +1,32 @@
+-- products kept for some customer groups, like wholesale-only SKUs. A product
+-- without rows is for everyone, one with rows only for the groups listed
+CREATE TABLE product_visibility (
+    product_id UUID NOT NULL REFERENCES products(product_id) ON DELETE CASCADE,
+    customer_group customer_group NOT NULL,
+    PRIMARY KEY (product_id, customer_group)
+);
+
+-- the same for every product of a category in a store
+CREATE TABLE category_visibility (
+    store_id UUID NOT NULL REFERENCES stores(store_id) ON DELETE CASCADE,
+    category VARCHAR(100) NOT NULL,
+    customer_group customer_group NOT NULL,
+    PRIMARY KEY (store_id, category, customer_group)
+);
+
+-- whether a group may see and buy the product, a product restricted itself
+-- and through its category has to be open to the group both ways. A null
+-- group is staff, who see everything
+CREATE FUNCTION visible_to(product UUID, viewer customer_group) RETURNS BOOLEAN AS $$
+    SELECT viewer IS NULL OR (
+        (NOT EXISTS (SELECT 1 FROM product_visibility v WHERE v.product_id = p.product_id)
+            OR EXISTS (SELECT 1 FROM product_visibility v
+                       WHERE v.product_id = p.product_id AND v.customer_group = viewer))
+        AND (NOT EXISTS (SELECT 1 FROM category_visibility c
+                         WHERE c.store_id = p.store_id AND c.category = p.category)
+            OR EXISTS (SELECT 1 FROM category_visibility c
+                       WHERE c.store_id = p.store_id AND c.category = p.category
+                           AND c.customer_group = viewer))
+    )
+    FROM products p WHERE p.product_id = product
+$$ LANGUAGE sql STABLE;
@@ -0,0 +1,7 @@
+-- the age a customer has to be to buy the product, null for anyone
+ALTER TABLE products ADD COLUMN age_restriction SMALLINT CHECK (age_restriction > 0);
+
+-- customers give their date of birth, staff verify it against an ID. Age
+-- restricted products need both
+ALTER TABLE users ADD COLUMN date_of_birth DATE;
+ALTER TABLE users ADD COLUMN age_verified BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,15 @@
+-- every time a customer accepted a version of the terms of service or the
+-- privacy policy, with where from. Kept for as long as the account is, a
+-- customer who hasn't accepted the current versions is asked to first
+CREATE TABLE policy_acceptances (
+    acceptance_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+    -- terms or privacy
+    policy VARCHAR(20) NOT NULL,
+    version VARCHAR(50) NOT NULL,
+    ip_address VARCHAR(45),
+    user_agent TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX policy_acceptances_user_idx ON policy_acceptances (user_id, policy, created_at DESC);
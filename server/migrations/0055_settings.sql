@@ -0,0 +1,9 @@
+-- switches admins flip at runtime, without a deploy, one row per setting.
+-- The value's shape is up to the setting, maintenance holds whether the store
+-- is down, what customers are told and when to try again
+CREATE TABLE settings (
+    key VARCHAR(100) PRIMARY KEY,
+    value JSONB NOT NULL,
+    updated_by UUID REFERENCES users(user_id) ON DELETE SET NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
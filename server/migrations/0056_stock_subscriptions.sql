@@ -0,0 +1,43 @@
+-- customers waiting for a product that is out of stock. They hear when it is
+-- back, in their inbox and by mail, and the subscription is done with
+CREATE TABLE stock_subscriptions (
+    user_id UUID NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+    product_id UUID NOT NULL REFERENCES products(product_id) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_id, product_id)
+);
+
+CREATE INDEX stock_subscriptions_product_idx ON stock_subscriptions (product_id);
+
+-- whatever restocked the product, an admin's edit, a bulk update, a refund or a
+-- cancelled order putting items back, tells its subscribers, and those of the
+-- kits it goes into once the kit can be put together again. They asked for
+-- it, so it isn't marketing
+CREATE FUNCTION notify_stock_subscribers() RETURNS TRIGGER AS $$
+BEGIN
+    WITH restocked AS (
+        SELECT p.product_id, p.name FROM products p
+        WHERE (p.product_id = NEW.product_id
+            OR p.product_id IN (SELECT k.kit_id FROM kit_components k
+                                WHERE k.component_id = NEW.product_id))
+            AND product_stock(p.product_id) > 0
+    ), notified AS (
+        DELETE FROM stock_subscriptions s USING restocked r
+        WHERE s.product_id = r.product_id
+        RETURNING s.user_id, r.product_id, r.name
+    ), inbox AS (
+        INSERT INTO notifications (user_id, message)
+        SELECT user_id, name || ' is back in stock' FROM notified
+    )
+    INSERT INTO email_messages (user_id, kind, data)
+    SELECT user_id, 'stock.restocked', jsonb_build_object('product_id', product_id, 'name', name)
+    FROM notified;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER products_stock_subscriptions
+    AFTER UPDATE OF stock_quantity ON products
+    FOR EACH ROW
+    WHEN (NEW.stock_quantity > OLD.stock_quantity AND NEW.stock_quantity > 0)
+    EXECUTE FUNCTION notify_stock_subscribers();
@@ -0,0 +1,3 @@
+-- how much of a limited edition product one customer may buy over all their
+-- orders, none for no limit. Cancelled and refunded orders don't count
+ALTER TABLE products ADD COLUMN purchase_limit DECIMAL(10, 3) CHECK (purchase_limit > 0);
@@ -0,0 +1,13 @@
+-- questions customers ask on a product page, reportable like reviews
+ALTER TYPE report_target ADD VALUE 'question';
+
+CREATE TABLE product_questions (
+    question_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    product_id UUID NOT NULL REFERENCES products(product_id) ON DELETE CASCADE,
+    user_id UUID NOT NULL REFERENCES users(user_id),
+    body TEXT NOT NULL,
+    is_hidden BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX product_questions_product_idx ON product_questions (product_id, created_at);
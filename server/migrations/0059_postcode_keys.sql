@@ -0,0 +1,11 @@
+-- postcode ranges compared as text put "10000" before "9000". Zones match on
+-- a key with the spaces taken out and every run of digits padded to ten, so
+-- numbers compare as numbers and letters still compare as text
+CREATE FUNCTION postcode_key(postcode TEXT) RETURNS TEXT AS $$
+    SELECT regexp_replace(
+        regexp_replace(upper(replace(postcode, ' ', '')), '(\d+)', '000000000\1', 'g'),
+        '0*(\d{10})',
+        '\1',
+        'g'
+    )
+$$ LANGUAGE SQL IMMUTABLE;
@@ -0,0 +1,28 @@
+-- rounding each component's share to the cent could leave the lines a cent or
+-- two off the bundle price, the component bought in the fewest units (the
+-- dearest of those) now takes what is left over so the lines add up to it.
+-- Only a component bought once can always absorb it exactly
+CREATE OR REPLACE FUNCTION bundle_unit_price(bundle UUID, product UUID) RETURNS DECIMAL(10, 2) AS $$
+    WITH shares AS (
+        SELECT bi.product_id, bi.quantity, b.price as bundle_price,
+            COALESCE(ROUND(
+                b.price * p.price / NULLIF(SUM(p.price * bi.quantity) OVER (), 0),
+                2
+            ), 0) as share,
+            ROW_NUMBER() OVER (ORDER BY bi.quantity, p.price DESC, bi.product_id) as rank
+        FROM bundle_items bi
+        JOIN bundles b ON b.bundle_id = bi.bundle_id
+        JOIN products p ON p.product_id = bi.product_id
+        WHERE bi.bundle_id = bundle
+    )
+    SELECT CASE WHEN s.rank = 1
+        THEN ROUND(
+            (s.bundle_price - (
+                SELECT COALESCE(SUM(o.share * o.quantity), 0) FROM shares o WHERE o.rank <> 1
+            )) / s.quantity,
+            2
+        )
+        ELSE s.share END
+    FROM shares s
+    WHERE s.product_id = product
+$$ LANGUAGE sql STABLE;
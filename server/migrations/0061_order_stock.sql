@@ -0,0 +1,17 @@
+-- what each order took out of stock, a kit's components as they were when it
+-- was sold, so releasing the stock gives back exactly that even after the kit
+-- is redefined. Orders placed before are filled in from the kits as they are now
+CREATE TABLE order_stock (
+    order_id UUID NOT NULL REFERENCES orders(order_id) ON DELETE CASCADE,
+    product_id UUID NOT NULL REFERENCES products(product_id),
+    quantity DECIMAL(12, 3) NOT NULL CHECK (quantity > 0),
+    PRIMARY KEY (order_id, product_id)
+);
+
+INSERT INTO order_stock (order_id, product_id, quantity)
+SELECT od.order_id, COALESCE(k.component_id, od.product_id),
+    SUM(od.quantity * COALESCE(k.quantity, 1))
+FROM order_details od
+LEFT JOIN kit_components k ON k.kit_id = od.product_id
+WHERE od.order_id IS NOT NULL AND od.product_id IS NOT NULL
+GROUP BY 1, 2;
@@ -0,0 +1,9 @@
+-- the file a digital product delivers, kept in storage under downloads/ and
+-- handed to customers who bought the product
+CREATE TABLE product_downloads (
+    product_id UUID PRIMARY KEY REFERENCES products(product_id) ON DELETE CASCADE,
+    object_key TEXT NOT NULL,
+    file_name VARCHAR(255) NOT NULL,
+    content_type VARCHAR(100) NOT NULL,
+    uploaded_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,9 @@
+-- the relay claims an aggregate's pending events for a while and publishes
+-- them after committing the claim, so no transaction waits on the broker and
+-- no other relay publishes a later event of the same aggregate meanwhile
+ALTER TABLE outbox_events ADD COLUMN claimed_until TIMESTAMPTZ;
+
+-- consumers only need to know who signed up, they look the rest up
+UPDATE outbox_events
+SET payload = jsonb_build_object('user_id', payload->'user_id')
+WHERE event_type = 'user.registered';
@@ -0,0 +1,3 @@
+-- the dispatcher claims a batch of messages for a while and sends them after
+-- committing the claim, so no transaction waits on FCM or APNs
+ALTER TABLE push_messages ADD COLUMN claimed_until TIMESTAMPTZ;
@@ -0,0 +1,17 @@
+-- versions come from the clock rather than the transaction's start, so each
+-- write in a transaction gets its own and a long transaction committing late
+-- can't stamp a version older than one already handed out. Never earlier than
+-- the version before, so an ETag always changes with the row
+CREATE OR REPLACE FUNCTION touch_product() RETURNS TRIGGER AS $$
+BEGIN
+    NEW.updated_at = GREATEST(clock_timestamp(), OLD.updated_at + INTERVAL '1 microsecond');
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION touch_updated_at() RETURNS TRIGGER AS $$
+BEGIN
+    NEW.updated_at = GREATEST(clock_timestamp(), OLD.updated_at + INTERVAL '1 microsecond');
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
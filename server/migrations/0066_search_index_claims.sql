@@ -0,0 +1,3 @@
+-- the indexer claims a product's unindexed events like the relay claims
+-- unpublished ones, so the search engine is waited on outside a transaction
+ALTER TABLE outbox_events ADD COLUMN index_claimed_until TIMESTAMPTZ;
@@ -0,0 +1,27 @@
+-- bundles, quotes and the shipping set up belong to a store like products
+-- do, what there was before goes to the default store
+ALTER TABLE bundles ADD COLUMN store_id UUID NOT NULL
+    DEFAULT '00000000-0000-0000-0000-000000000001' REFERENCES stores(store_id);
+ALTER TABLE quotes ADD COLUMN store_id UUID NOT NULL
+    DEFAULT '00000000-0000-0000-0000-000000000001' REFERENCES stores(store_id);
+ALTER TABLE shipping_zones ADD COLUMN store_id UUID NOT NULL
+    DEFAULT '00000000-0000-0000-0000-000000000001' REFERENCES stores(store_id);
+ALTER TABLE pickup_locations ADD COLUMN store_id UUID NOT NULL
+    DEFAULT '00000000-0000-0000-0000-000000000001' REFERENCES stores(store_id);
+
+DROP INDEX quotes_status_idx;
+CREATE INDEX quotes_status_idx ON quotes (store_id, status, created_at);
+DROP INDEX shipping_zones_country_idx;
+CREATE INDEX shipping_zones_country_idx ON shipping_zones (store_id, country);
+
+-- a store's catalog sync only tells its warehouse about its own deletions
+ALTER TABLE product_deletions ADD COLUMN store_id UUID NOT NULL
+    DEFAULT '00000000-0000-0000-0000-000000000001';
+
+CREATE OR REPLACE FUNCTION record_product_deletion() RETURNS TRIGGER AS $$
+BEGIN
+    INSERT INTO product_deletions (product_id, store_id) VALUES (OLD.product_id, OLD.store_id)
+    ON CONFLICT (product_id) DO UPDATE SET deleted_at = NOW(), store_id = OLD.store_id;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
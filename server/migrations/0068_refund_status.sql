@@ -0,0 +1,9 @@
+-- a refund is recorded as pending before the provider is called and marked
+-- succeeded once it paid the money back, one left pending is sent again with
+-- the same idempotency key
+CREATE TYPE refund_status AS ENUM ('pending', 'succeeded');
+
+ALTER TABLE refunds ADD COLUMN status refund_status NOT NULL DEFAULT 'succeeded';
+ALTER TABLE refunds ALTER COLUMN status SET DEFAULT 'pending';
+
+CREATE INDEX refunds_pending_idx ON refunds (payment_id) WHERE status = 'pending';
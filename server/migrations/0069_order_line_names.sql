@@ -0,0 +1,7 @@
+-- each line keeps the name the product was sold under, so the order still
+-- reads the same after the product is renamed or gone. Lines whose product
+-- was already gone have none
+ALTER TABLE order_details ADD COLUMN product_name VARCHAR(255);
+
+UPDATE order_details od SET product_name = p.name
+FROM products p WHERE p.product_id = od.product_id;
@@ -0,0 +1,60 @@
+use std::time::Duration;
+
+use sqlx::{postgres::PgListener, PgPool};
+use tokio::sync::broadcast;
+
+// new orders and failed payments, as the database announces them
+const CHANNEL: &str = "admin_events";
+
+// sent when events may have been missed, dashboards reload what they show
+pub const RESYNC: &str = r#"{"type":"resync"}"#;
+
+// the admin events of every instance, for the websockets of this one
+#[derive(Clone)]
+pub struct AdminFeed {
+    sender: broadcast::Sender<String>,
+}
+
+impl Default for AdminFeed {
+    fn default() -> Self {
+        AdminFeed {
+            sender: broadcast::channel(256).0,
+        }
+    }
+}
+
+impl AdminFeed {
+    pub fn subscribe(&self) -> broadcast::Receiver<String> {
+        self.sender.subscribe()
+    }
+
+    // nobody listening is fine, the event is only for who is connected now
+    fn publish(&self, event: String) {
+        let _ = self.sender.send(event);
+    }
+}
+
+// forwards the announced events to the feed, telling the dashboards to resync
+// when the connection to the database was lost in between
+pub fn spawn_listener(pool: PgPool, feed: AdminFeed) {
+    actix_web::rt::spawn(async move {
+        loop {
+            if let Err(err) = listen(&pool, &feed).await {
+                println!("admin feed listener failed: {err:?}");
+            }
+            feed.publish(RESYNC.to_string());
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        }
+    });
+}
+
+async fn listen(pool: &PgPool, feed: &AdminFeed) -> Result<(), sqlx::Error> {
+    let mut listener = PgListener::connect_with(pool).await?;
+    listener.listen(CHANNEL).await?;
+    loop {
+        match listener.try_recv().await? {
+            Some(notification) => feed.publish(notification.payload().to_string()),
+            None => feed.publish(RESYNC.to_string()),
+        }
+    }
+}
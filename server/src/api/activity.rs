@@ -0,0 +1,134 @@
+use crate::{
+    api::users::TokenClaims,
+    envelope::{paginated, Page, PageQuery, Pagination},
+    rate_limit::database_error,
+    AppState,
+};
+use actix_web::{
+    get,
+    web::{self, ReqData},
+    HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+// ?actor= is the staff member whose actions to show, ?type= an event type
+// like user.update or webhook.failed, or staff or system for all of a kind
+#[derive(Deserialize)]
+pub struct ActivityFilter {
+    actor: Option<Uuid>,
+    #[serde(rename = "type")]
+    event_type: Option<String>,
+}
+
+// one thing that happened, done by staff or by the system
+#[derive(Serialize)]
+pub struct Activity {
+    // staff or system
+    kind: String,
+    event_type: String,
+    actor_id: Option<Uuid>,
+    actor_name: Option<String>,
+    entity_type: Option<String>,
+    entity_id: Option<Uuid>,
+    details: Value,
+    occurred_at: DateTime<Utc>,
+}
+
+impl Activity {
+    // staff actions are the audit log and the order changes staff made, system
+    // events are failed payments and the failed webhooks that get retried.
+    // Newest first
+    async fn feed(
+        pool: &PgPool,
+        filter: &ActivityFilter,
+        page: &PageQuery,
+    ) -> Result<Page<Activity>, sqlx::Error> {
+        let items = sqlx::query_as!(
+            Activity,
+            r#"
+            WITH activity AS (
+                SELECT a.audit_id as id, 'staff' as kind, a.action as event_type, a.actor_id,
+                       a.entity_type, a.entity_id, a.details, a.created_at as occurred_at
+                FROM audit_log a
+                UNION ALL
+                SELECT e.event_id, CASE WHEN e.data ? 'admin_id' THEN 'staff' ELSE 'system' END,
+                       e.event_type, (e.data->>'admin_id')::uuid, 'order', e.order_id, e.data,
+                       e.created_at
+                FROM order_events e
+                WHERE e.data ? 'admin_id' OR e.event_type = 'payment.failed'
+                UNION ALL
+                SELECT s.event_id, 'system', s.event_type, NULL, NULL, NULL, s.details,
+                       s.created_at
+                FROM system_events s
+            )
+            SELECT activity.kind as "kind!", activity.event_type as "event_type!",
+                   activity.actor_id, u.first_name || ' ' || u.last_name as actor_name,
+                   activity.entity_type, activity.entity_id, activity.details as "details!",
+                   activity.occurred_at as "occurred_at!"
+            FROM activity
+            LEFT JOIN users u ON u.user_id = activity.actor_id
+            WHERE ($1::uuid IS NULL OR activity.actor_id = $1)
+                AND ($2::text IS NULL OR activity.event_type = $2 OR activity.kind = $2)
+            ORDER BY activity.occurred_at DESC, activity.id
+            LIMIT $3 OFFSET $4
+            "#,
+            filter.actor,
+            filter.event_type,
+            page.per_page(),
+            page.offset()
+        )
+        .fetch_all(pool)
+        .await?;
+        let total = sqlx::query_scalar!(
+            r#"
+            WITH activity AS (
+                SELECT 'staff' as kind, action as event_type, actor_id FROM audit_log
+                UNION ALL
+                SELECT CASE WHEN data ? 'admin_id' THEN 'staff' ELSE 'system' END, event_type,
+                       (data->>'admin_id')::uuid
+                FROM order_events
+                WHERE data ? 'admin_id' OR event_type = 'payment.failed'
+                UNION ALL
+                SELECT 'system', event_type, NULL FROM system_events
+            )
+            SELECT COUNT(*) as "count!" FROM activity
+            WHERE ($1::uuid IS NULL OR actor_id = $1)
+                AND ($2::text IS NULL OR event_type = $2 OR kind = $2)
+            "#,
+            filter.actor,
+            filter.event_type
+        )
+        .fetch_one(pool)
+        .await?;
+        Ok(Page { items, total })
+    }
+}
+
+// admin only, for admins of every store
+// get request for what staff did and what went wrong lately, newest first, so
+// whoever takes over the next shift can catch up
+#[get("api/admin/activity")]
+pub async fn get_activity(
+    state: web::Data<AppState>,
+    query: web::Query<PageQuery>,
+    filter: web::Query<ActivityFilter>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_platform_admin() {
+                match Activity::feed(&state.db, &filter, &query).await {
+                    Ok(page) => paginated(page.items, Pagination::new(&query, page.total)),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("only admins of every store can follow activity")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
@@ -0,0 +1,75 @@
+use std::time::Duration;
+
+use actix_web::{get, http::header, web, HttpRequest, HttpResponse};
+use actix_ws::{Message, MessageStream, Session};
+use serde::Deserialize;
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+
+use crate::{admin_feed::RESYNC, api::users::verify_token, rate_limit::database_error, AppState};
+
+#[derive(Deserialize)]
+struct FeedQuery {
+    // browsers can't set headers on a websocket
+    access_token: Option<String>,
+}
+
+// admin only
+// websocket pushing a message as orders are placed and payments fail, so
+// dashboards don't have to poll the order list. The token comes as a bearer
+// token or, from browsers, as the access_token query parameter
+#[get("api/admin/feed")]
+pub async fn admin_order_feed(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    query: web::Query<FeedQuery>,
+    body: web::Payload,
+) -> Result<HttpResponse, actix_web::Error> {
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or(query.access_token.as_deref());
+    let Some(token) = token else {
+        return Ok(HttpResponse::Unauthorized().json("unable to verify indentity"));
+    };
+    match verify_token(&state, token).await {
+        // the feed has the orders of every store
+        Ok(Some((user, _))) if user.is_platform_admin() => {}
+        Ok(Some(_)) => {
+            return Ok(HttpResponse::Forbidden().json("customer not allowed to follow orders"))
+        }
+        Ok(None) => return Ok(HttpResponse::Unauthorized().json("unable to verify indentity")),
+        Err(err) => return Ok(database_error(err)),
+    }
+
+    let (res, session, messages) = actix_ws::handle(&req, body)?;
+    actix_web::rt::spawn(forward(state.admin_feed.subscribe(), session, messages));
+    Ok(res)
+}
+
+// send the events to one dashboard until it goes away
+async fn forward(mut events: Receiver<String>, mut session: Session, mut messages: MessageStream) {
+    // keeps proxies from closing a quiet connection
+    let mut heartbeat = tokio::time::interval(Duration::from_secs(30));
+    loop {
+        let sent = tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => session.text(event).await,
+                // a slow client missed events, it reloads instead
+                Err(RecvError::Lagged(_)) => session.text(RESYNC).await,
+                Err(RecvError::Closed) => break,
+            },
+            message = messages.recv() => match message {
+                Some(Ok(Message::Ping(bytes))) => session.pong(&bytes).await,
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => Ok(()),
+            },
+            _ = heartbeat.tick() => session.ping(b"").await,
+        };
+        if sent.is_err() {
+            return;
+        }
+    }
+    let _ = session.close(None).await;
+}
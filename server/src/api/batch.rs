@@ -0,0 +1,138 @@
+use crate::{
+    api::{
+        carts::{BatchStep, CartOperation},
+        stores::Store,
+        users::TokenClaims,
+    },
+    rate_limit::database_error,
+    AppState,
+};
+use actix_web::{
+    post,
+    web::{self, Json, ReqData},
+    HttpResponse, Responder,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+const MAX_BATCH_REQUESTS: usize = 20;
+
+// one request of a batch, as it would be sent on its own
+#[derive(Deserialize)]
+pub struct BatchRequest {
+    method: String,
+    path: String,
+    #[serde(default)]
+    body: Value,
+}
+
+#[derive(Deserialize)]
+pub struct BatchBody {
+    requests: Vec<BatchRequest>,
+    // keep all of the changes or none of them
+    #[serde(default)]
+    atomic: bool,
+}
+
+// the status and body the request would have been answered with on its own,
+// 424 for the ones an atomic batch didn't get to
+#[derive(Serialize)]
+struct BatchResult {
+    status: u16,
+    body: Value,
+}
+
+fn batch_result(operation: &CartOperation, result: Result<BatchStep, sqlx::Error>) -> BatchResult {
+    let (status, body) = match result {
+        Ok(BatchStep::Done(answer)) => match operation {
+            CartOperation::AddItem { .. } | CartOperation::AddBundle { .. } => (201, json!(answer)),
+            _ => (200, json!(answer)),
+        },
+        Ok(BatchStep::Rejected(violations)) => (422, json!(violations)),
+        Err(sqlx::Error::RowNotFound) => (
+            404,
+            json!(match operation {
+                CartOperation::AddItem { .. } => "Product not found",
+                CartOperation::AddBundle { .. } => "Bundle not found",
+                CartOperation::RemoveBundle { .. } => "Bundle is not in the cart",
+                CartOperation::SaveForLater { .. } => "Cart item not found",
+                CartOperation::MoveToCart { .. } => "Saved item not found",
+            }),
+        ),
+        Err(sqlx::Error::Protocol(msg)) => (409, json!(msg)),
+        Err(e) => (500, json!(e.to_string())),
+    };
+    BatchResult { status, body }
+}
+
+// post request to make several changes to the active cart in one round trip,
+// run in order with a result for each. The requests are the cart routes'
+// own, e.g. {"method": "POST", "path": "/api/cart-items", "body": {...}}.
+// With "atomic": true the first failure undoes the whole batch
+#[post("api/batch")]
+pub async fn batch(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    body: Json<BatchBody>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            let body = body.into_inner();
+            if body.requests.is_empty() || body.requests.len() > MAX_BATCH_REQUESTS {
+                return HttpResponse::BadRequest()
+                    .json(format!("A batch takes 1 to {MAX_BATCH_REQUESTS} requests"));
+            }
+            // every request is checked before any of them runs
+            let mut operations = Vec::with_capacity(body.requests.len());
+            for (index, request) in body.requests.into_iter().enumerate() {
+                match CartOperation::from_request(&request.method, &request.path, request.body) {
+                    Ok(operation) => operations.push(operation),
+                    Err(msg) => {
+                        return HttpResponse::BadRequest().json(format!("request {index}: {msg}"))
+                    }
+                }
+            }
+
+            match state.carts.active_cart(store.store_id, user.user_id).await {
+                Ok(cart) => match state
+                    .carts
+                    .batch(
+                        &state.limits,
+                        &state.pricing,
+                        cart.cart_id,
+                        user.catalogue_group(),
+                        &operations,
+                        body.atomic,
+                    )
+                    .await
+                {
+                    Ok(outcome) => {
+                        let mut results: Vec<BatchResult> = operations
+                            .iter()
+                            .zip(outcome.results)
+                            .map(|(operation, result)| batch_result(operation, result))
+                            .collect();
+                        while results.len() < operations.len() {
+                            results.push(BatchResult {
+                                status: 424,
+                                body: json!("Not run, an earlier request in the batch failed"),
+                            });
+                        }
+                        match state.carts.view(&state.pricing, cart.cart_id).await {
+                            Ok(cart_view) => HttpResponse::Ok().json(json!({
+                                "committed": outcome.committed,
+                                "results": results,
+                                "cart": cart_view,
+                            })),
+                            Err(e) => database_error(e),
+                        }
+                    }
+                    Err(e) => database_error(e),
+                },
+                Err(e) => database_error(e),
+            }
+        }
+        None => HttpResponse::Unauthorized().json("Please log in"),
+    }
+}
@@ -0,0 +1,164 @@
+use crate::{api::users::TokenClaims, rate_limit::database_error, AppState};
+use actix_web::{
+    delete, get, post,
+    web::{self, Json, ReqData},
+    HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+
+#[derive(Serialize, FromRow)]
+pub struct BlockedDomain {
+    domain: String,
+    reason: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+struct BlockedDomainBody {
+    domain: String,
+    reason: Option<String>,
+}
+
+impl BlockedDomain {
+    // check the email domain and its parent domains against the blocklist,
+    // so "mx.mailinator.com" is caught by "mailinator.com"
+    pub async fn is_blocked(pool: &PgPool, email: &str) -> Result<bool, sqlx::Error> {
+        let domain = match email.rsplit_once('@') {
+            Some((_, domain)) => domain.trim().to_lowercase(),
+            None => return Ok(false),
+        };
+
+        let blocked = sqlx::query!(
+            "SELECT domain FROM blocked_email_domains
+            WHERE $1 = domain OR right($1, length(domain) + 1) = '.' || domain LIMIT 1",
+            domain
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(blocked.is_some())
+    }
+
+    async fn get_all(pool: &PgPool) -> Result<Vec<BlockedDomain>, sqlx::Error> {
+        sqlx::query_as!(
+            BlockedDomain,
+            "SELECT * FROM blocked_email_domains ORDER BY domain"
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    // a host name of two labels or more, each of letters, digits and inner hyphens
+    fn valid_domain(domain: &str) -> bool {
+        domain.len() <= 253
+            && domain.contains('.')
+            && domain.split('.').all(|label| {
+                !label.is_empty()
+                    && label.len() <= 63
+                    && !label.starts_with('-')
+                    && !label.ends_with('-')
+                    && label
+                        .chars()
+                        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+            })
+    }
+
+    async fn add(pool: &PgPool, body: BlockedDomainBody) -> Result<BlockedDomain, sqlx::Error> {
+        let domain = body.domain.trim().trim_start_matches('@').to_lowercase();
+        if !BlockedDomain::valid_domain(&domain) {
+            return Err(sqlx::Error::Protocol("Invalid domain".into()));
+        }
+
+        sqlx::query_as!(
+            BlockedDomain,
+            "INSERT INTO blocked_email_domains (domain, reason) VALUES ($1, $2)
+            ON CONFLICT (domain) DO UPDATE SET reason = $2 RETURNING *",
+            domain,
+            body.reason
+        )
+        .fetch_one(pool)
+        .await
+    }
+
+    async fn remove(pool: &PgPool, domain: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            "DELETE FROM blocked_email_domains WHERE domain = $1",
+            domain.to_lowercase()
+        )
+        .execute(pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+// admin only
+// get request to list blocked email domains
+#[get("api/admin/blocked-domains")]
+pub async fn get_blocked_domains(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match BlockedDomain::get_all(&state.db).await {
+                    Ok(domains) => HttpResponse::Ok().json(domains),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("customer not allowed to see blocked domains")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// post request to block an email domain
+#[post("api/admin/blocked-domains")]
+pub async fn add_blocked_domain(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+    body: Json<BlockedDomainBody>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match BlockedDomain::add(&state.db, body.into_inner()).await {
+                    Ok(domain) => HttpResponse::Created().json(domain),
+                    Err(sqlx::Error::Protocol(msg)) => HttpResponse::BadRequest().json(msg),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("customer not allowed to block domains")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// delete request to unblock an email domain
+#[delete("api/admin/blocked-domains/{domain}")]
+pub async fn remove_blocked_domain(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+    domain: web::Path<String>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match BlockedDomain::remove(&state.db, &domain).await {
+                    Ok(true) => HttpResponse::Ok().json("domain unblocked"),
+                    Ok(false) => HttpResponse::NotFound().json("domain is not blocked"),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("customer not allowed to unblock domains")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
@@ -0,0 +1,326 @@
+use crate::{
+    api::{stores::Store, users::TokenClaims},
+    rate_limit::database_error,
+    AppState,
+};
+use actix_web::{
+    get, post, put,
+    web::{self, Json, ReqData},
+    HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Decimal, FromRow, PgPool};
+use uuid::Uuid;
+
+#[derive(Serialize, FromRow)]
+struct Bundle {
+    bundle_id: Uuid,
+    name: String,
+    description: Option<String>,
+    price: Decimal,
+    is_available: bool,
+    // how many bundles the component stock covers
+    stock_quantity: i32,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, FromRow)]
+struct BundleItem {
+    product_id: Uuid,
+    quantity: i32,
+}
+
+// bundle with its components and what they would cost on their own
+#[derive(Serialize)]
+struct BundleDetail {
+    #[serde(flatten)]
+    bundle: Bundle,
+    items: Vec<BundleItem>,
+    list_price: Decimal,
+}
+
+#[derive(Deserialize)]
+struct NewBundleBody {
+    name: String,
+    description: Option<String>,
+    price: Decimal,
+    items: Vec<BundleItem>,
+}
+
+// components can't change once created, orders point at the bundle for returns
+#[derive(Deserialize)]
+struct BundleBody {
+    name: String,
+    description: Option<String>,
+    price: Decimal,
+    is_available: bool,
+}
+
+impl Bundle {
+    async fn get_all(
+        pool: &PgPool,
+        store_id: Uuid,
+        available_only: bool,
+    ) -> Result<Vec<Bundle>, sqlx::Error> {
+        sqlx::query_as!(
+            Bundle,
+            r#"SELECT b.bundle_id, b.name, b.description, b.price, b.is_available,
+                COALESCE(MIN(FLOOR(product_stock(p.product_id) / bi.quantity)), 0)::int
+                    as "stock_quantity!",
+                b.created_at
+            FROM bundles b
+            LEFT JOIN bundle_items bi ON bi.bundle_id = b.bundle_id
+            LEFT JOIN products p ON p.product_id = bi.product_id
+            WHERE b.store_id = $2 AND (b.is_available OR NOT $1)
+            GROUP BY b.bundle_id
+            ORDER BY b.name"#,
+            available_only,
+            store_id
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    async fn get_detail(
+        pool: &PgPool,
+        store_id: Uuid,
+        bundle_id: Uuid,
+    ) -> Result<BundleDetail, sqlx::Error> {
+        let bundle = sqlx::query_as!(
+            Bundle,
+            r#"SELECT b.bundle_id, b.name, b.description, b.price, b.is_available,
+                COALESCE(MIN(FLOOR(product_stock(p.product_id) / bi.quantity)), 0)::int
+                    as "stock_quantity!",
+                b.created_at
+            FROM bundles b
+            LEFT JOIN bundle_items bi ON bi.bundle_id = b.bundle_id
+            LEFT JOIN products p ON p.product_id = bi.product_id
+            WHERE b.bundle_id = $1 AND b.store_id = $2
+            GROUP BY b.bundle_id"#,
+            bundle_id,
+            store_id
+        )
+        .fetch_optional(pool)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)?;
+
+        let items = sqlx::query_as!(
+            BundleItem,
+            "SELECT product_id, quantity FROM bundle_items WHERE bundle_id = $1",
+            bundle_id
+        )
+        .fetch_all(pool)
+        .await?;
+
+        let list_price = sqlx::query!(
+            r#"SELECT COALESCE(SUM(p.price * bi.quantity), 0) as "list_price!"
+            FROM bundle_items bi JOIN products p ON p.product_id = bi.product_id
+            WHERE bi.bundle_id = $1"#,
+            bundle_id
+        )
+        .fetch_one(pool)
+        .await?
+        .list_price;
+
+        Ok(BundleDetail {
+            bundle,
+            items,
+            list_price,
+        })
+    }
+
+    // a bundle of the store's own products
+    async fn create(
+        pool: &PgPool,
+        store_id: Uuid,
+        body: NewBundleBody,
+    ) -> Result<BundleDetail, sqlx::Error> {
+        if body.price < Decimal::ZERO {
+            return Err(sqlx::Error::Protocol(
+                "Bundle price can't be negative".into(),
+            ));
+        }
+        if body.items.len() < 2 {
+            return Err(sqlx::Error::Protocol(
+                "A bundle needs at least two products".into(),
+            ));
+        }
+        if body.items.iter().any(|item| item.quantity <= 0) {
+            return Err(sqlx::Error::Protocol(
+                "Component quantities must be positive".into(),
+            ));
+        }
+
+        // components from another store are as good as missing
+        let mut product_ids: Vec<Uuid> = body.items.iter().map(|item| item.product_id).collect();
+        product_ids.sort();
+        product_ids.dedup();
+        let in_store = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) as "count!" FROM products
+            WHERE product_id = ANY($1) AND store_id = $2"#,
+            &product_ids,
+            store_id
+        )
+        .fetch_one(pool)
+        .await?;
+        if in_store < product_ids.len() as i64 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+
+        let mut tx = pool.begin().await?;
+
+        let bundle_id = sqlx::query!(
+            "INSERT INTO bundles (name, description, price, store_id) VALUES ($1, $2, $3, $4)
+            RETURNING bundle_id",
+            body.name,
+            body.description,
+            body.price,
+            store_id
+        )
+        .fetch_one(&mut *tx)
+        .await?
+        .bundle_id;
+
+        for item in &body.items {
+            sqlx::query!(
+                "INSERT INTO bundle_items (bundle_id, product_id, quantity) VALUES ($1, $2, $3)",
+                bundle_id,
+                item.product_id,
+                item.quantity
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        Bundle::get_detail(pool, store_id, bundle_id).await
+    }
+
+    async fn update(
+        pool: &PgPool,
+        store_id: Uuid,
+        bundle_id: Uuid,
+        body: BundleBody,
+    ) -> Result<BundleDetail, sqlx::Error> {
+        if body.price < Decimal::ZERO {
+            return Err(sqlx::Error::Protocol(
+                "Bundle price can't be negative".into(),
+            ));
+        }
+
+        let result = sqlx::query!(
+            "UPDATE bundles SET name = $1, description = $2, price = $3, is_available = $4
+            WHERE bundle_id = $5 AND store_id = $6",
+            body.name,
+            body.description,
+            body.price,
+            body.is_available,
+            bundle_id,
+            store_id
+        )
+        .execute(pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        Bundle::get_detail(pool, store_id, bundle_id).await
+    }
+}
+
+// get request for the bundles on sale
+#[get("api/bundles")]
+pub async fn get_bundles(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => match Bundle::get_all(&state.db, store.store_id, !user.is_admin()).await {
+            Ok(bundles) => HttpResponse::Ok().json(bundles),
+            Err(err) => database_error(err),
+        },
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// get request for a bundle and its components
+#[get("api/bundles/{id}")]
+pub async fn get_bundle(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    bundle_id: web::Path<Uuid>,
+) -> impl Responder {
+    match req_user {
+        Some(_) => match Bundle::get_detail(&state.db, store.store_id, *bundle_id).await {
+            Ok(bundle) => HttpResponse::Ok().json(bundle),
+            Err(sqlx::Error::RowNotFound) => HttpResponse::NotFound().json("bundle was not found"),
+            Err(err) => database_error(err),
+        },
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// post request to create a bundle from existing products
+#[post("api/admin/bundles")]
+pub async fn create_bundle(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    body: Json<NewBundleBody>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match Bundle::create(&state.db, store.store_id, body.into_inner()).await {
+                    Ok(bundle) => HttpResponse::Created().json(bundle),
+                    Err(sqlx::Error::Protocol(msg)) => HttpResponse::BadRequest().json(msg),
+                    Err(sqlx::Error::RowNotFound) => {
+                        HttpResponse::NotFound().json("product was not found")
+                    }
+                    Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+                        HttpResponse::BadRequest().json("Each product can appear once per bundle")
+                    }
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("costumer cant create product")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// put request to rename, reprice or take a bundle off sale
+#[put("api/admin/bundles/{id}")]
+pub async fn update_bundle(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    bundle_id: web::Path<Uuid>,
+    body: Json<BundleBody>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match Bundle::update(&state.db, store.store_id, *bundle_id, body.into_inner()).await
+                {
+                    Ok(bundle) => HttpResponse::Ok().json(bundle),
+                    Err(sqlx::Error::Protocol(msg)) => HttpResponse::BadRequest().json(msg),
+                    Err(sqlx::Error::RowNotFound) => {
+                        HttpResponse::NotFound().json("bundle was not found")
+                    }
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("costumer cant edit product")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
@@ -0,0 +1,147 @@
+use crate::{
+    api::users::TokenClaims,
+    rate_limit::database_error,
+    vat::{parse_vat_number, ReverseCharge},
+    AppState,
+};
+use actix_web::{
+    get, put,
+    web::{self, Json, ReqData},
+    HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+// a customer's VAT registration, only validated numbers are stored
+#[derive(Serialize, FromRow)]
+pub struct VatProfile {
+    vat_number: Option<String>,
+    vat_company_name: Option<String>,
+    vat_checked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+struct VatBody {
+    // null removes the number
+    vat_number: Option<String>,
+}
+
+impl VatProfile {
+    async fn get(pool: &PgPool, user_id: Uuid) -> Result<VatProfile, sqlx::Error> {
+        sqlx::query_as!(
+            VatProfile,
+            "SELECT vat_number, vat_company_name, vat_checked_at FROM users WHERE user_id = $1",
+            user_id
+        )
+        .fetch_one(pool)
+        .await
+    }
+
+    // the stored number when its orders are reverse charged
+    pub async fn reverse_charge_number(
+        pool: &PgPool,
+        reverse_charge: Option<&ReverseCharge>,
+        user_id: Uuid,
+    ) -> Result<Option<String>, sqlx::Error> {
+        let Some(reverse_charge) = reverse_charge else {
+            return Ok(None);
+        };
+        let profile = VatProfile::get(pool, user_id).await?;
+        Ok(profile
+            .vat_number
+            .filter(|number| reverse_charge.applies(&number[..2])))
+    }
+
+    // check the number with the validator before storing it
+    async fn set(
+        pool: &PgPool,
+        reverse_charge: &ReverseCharge,
+        user_id: Uuid,
+        vat_number: Option<String>,
+    ) -> Result<VatProfile, sqlx::Error> {
+        let Some(vat_number) = vat_number else {
+            return sqlx::query_as!(
+                VatProfile,
+                "UPDATE users SET vat_number = NULL, vat_company_name = NULL, vat_checked_at = NULL
+                WHERE user_id = $1
+                RETURNING vat_number, vat_company_name, vat_checked_at",
+                user_id
+            )
+            .fetch_one(pool)
+            .await;
+        };
+
+        let (country, number) = parse_vat_number(&vat_number)
+            .ok_or_else(|| sqlx::Error::Protocol("VAT number is not an EU VAT number".into()))?;
+        let check = reverse_charge
+            .validate(&format!("{country}{number}"))
+            .await
+            .map_err(|err| sqlx::Error::Protocol(format!("VAT validation unavailable: {err}")))?;
+        if !check.valid {
+            return Err(sqlx::Error::Protocol("VAT number is not valid".into()));
+        }
+
+        sqlx::query_as!(
+            VatProfile,
+            "UPDATE users SET vat_number = $1, vat_company_name = $2, vat_checked_at = $3
+            WHERE user_id = $4
+            RETURNING vat_number, vat_company_name, vat_checked_at",
+            check.vat_number,
+            check.name,
+            check.checked_at,
+            user_id
+        )
+        .fetch_one(pool)
+        .await
+    }
+}
+
+// get request for the current user's VAT number
+#[get("api/users/me/vat")]
+pub async fn get_vat_profile(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => match VatProfile::get(&state.db, user.user_id).await {
+            Ok(profile) => HttpResponse::Ok().json(profile),
+            Err(err) => database_error(err),
+        },
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// put request to set or remove the current user's VAT number
+#[put("api/users/me/vat")]
+pub async fn set_vat_profile(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+    body: Json<VatBody>,
+) -> impl Responder {
+    let Some(reverse_charge) = state.reverse_charge.as_ref() else {
+        return HttpResponse::ServiceUnavailable().json("VAT numbers are not supported");
+    };
+
+    match req_user {
+        Some(user) => {
+            match VatProfile::set(
+                &state.db,
+                reverse_charge,
+                user.user_id,
+                body.into_inner().vat_number,
+            )
+            .await
+            {
+                Ok(profile) => HttpResponse::Ok().json(profile),
+                Err(sqlx::Error::Protocol(msg)) if msg.contains("unavailable") => {
+                    HttpResponse::ServiceUnavailable().json(msg)
+                }
+                Err(sqlx::Error::Protocol(msg)) => HttpResponse::UnprocessableEntity().json(msg),
+                Err(err) => database_error(err),
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
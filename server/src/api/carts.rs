@@ -1,20 +1,71 @@
-use crate::{api::users::TokenClaims, AppState};
+use crate::{
+    api::{
+        customer_groups::CustomerGroup, products::ProductUnit, stores::Store, users::TokenClaims,
+    },
+    limits::{self, LimitViolation, OrderLimits},
+    money::Money,
+    pricing::Pricing,
+    query_stats::QueryStats,
+    rate_limit::database_error,
+    AppState,
+};
 use actix_web::{
-    body, delete, get, post, put,
+    delete, get, post, put,
     web::{self, Json, ReqData},
-    HttpMessage, HttpResponse, Responder,
+    HttpResponse, Responder,
 };
+use async_trait::async_trait;
 use chrono::{DateTime, Utc};
-use serde::{de::Error, Deserialize, Serialize};
-use sqlx::{types::Decimal, FromRow, PgPool};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Decimal, Connection, FromRow, PgConnection, PgPool};
+use std::sync::Arc;
 use uuid::Uuid;
 
-#[derive(Serialize, Deserialize, FromRow)]
+// a carts row, sent to clients as CartResponse
+#[derive(FromRow)]
 pub struct Cart {
     pub cart_id: Uuid,
     pub user_id: Option<Uuid>,
     pub created_at: Option<DateTime<Utc>>,
     pub updated_at: Option<DateTime<Utc>>,
+    pub name: String,
+    pub is_active: bool,
+    pub store_id: Uuid,
+}
+
+#[derive(Serialize)]
+pub struct CartResponse {
+    cart_id: Uuid,
+    user_id: Option<Uuid>,
+    created_at: Option<DateTime<Utc>>,
+    updated_at: Option<DateTime<Utc>>,
+    name: String,
+    is_active: bool,
+}
+
+impl From<Cart> for CartResponse {
+    fn from(cart: Cart) -> Self {
+        CartResponse {
+            cart_id: cart.cart_id,
+            user_id: cart.user_id,
+            created_at: cart.created_at,
+            updated_at: cart.updated_at,
+            name: cart.name,
+            is_active: cart.is_active,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct NewCartBody {
+    name: String,
+    #[serde(default)]
+    activate: bool,
+}
+
+#[derive(Deserialize)]
+struct RenameCartBody {
+    name: String,
 }
 
 #[derive(Serialize, Deserialize, FromRow)]
@@ -22,39 +73,201 @@ struct CartItem {
     cart_item_id: Option<Uuid>,
     cart_id: Option<Uuid>,
     product_id: Option<Uuid>,
-    quantity: Option<i32>,
+    quantity: Option<Decimal>,
     added_at: Option<DateTime<Utc>>,
+    saved_for_later: bool,
+    bundle_id: Option<Uuid>,
 }
 
 #[derive(Serialize, Deserialize, FromRow)]
 struct CartItemBody {
     product_id: Uuid,
-    quantity: i32,
+    // a multiple of the product's quantity step, 1.5 for one and a half kg
+    quantity: Decimal,
 }
 
-#[derive(Serialize, Deserialize, FromRow)]
-struct CartBody {
-    user_id: Option<Uuid>,
+#[derive(Deserialize)]
+struct CartBundleBody {
+    bundle_id: Uuid,
+    quantity: i32,
 }
 
 #[derive(Serialize, FromRow)]
-struct CartItemWithProduct {
+pub struct CartItemWithProduct {
     cart_item_id: Option<Uuid>,
     cart_id: Option<Uuid>,
     product_id: Option<Uuid>,
-    quantity: Option<i32>,
+    quantity: Option<Decimal>,
     added_at: Option<DateTime<Utc>>,
+    saved_for_later: bool,
+    // lines added as part of a bundle are priced and removed with it
+    bundle_id: Option<Uuid>,
     product_name: String,
+    // price per unit, the line costs price times quantity
     product_price: Decimal,
+    unit: ProductUnit,
+}
+
+// the active cart and the save-for-later list
+#[derive(Serialize)]
+pub struct CartView {
+    items: Vec<CartItemWithProduct>,
+    saved_items: Vec<CartItemWithProduct>,
+    subtotal: Money,
+    // how much more to add for free shipping, null when there is no threshold
+    amount_to_free_shipping: Option<Money>,
+}
+
+// a product often ordered together with what is already in the cart
+#[derive(Serialize, FromRow)]
+pub struct CartSuggestion {
+    product_id: Uuid,
+    name: String,
+    price: Decimal,
+    times_bought_together: i64,
+}
+
+#[derive(Deserialize)]
+struct SuggestionQuery {
+    limit: Option<i64>,
+}
+
+pub enum CartItemOutcome {
+    Added,
+    Rejected(Vec<LimitViolation>),
+}
+
+// one change to the active cart, as one of the cart routes would make it
+pub enum CartOperation {
+    AddItem { product_id: Uuid, quantity: Decimal },
+    AddBundle { bundle_id: Uuid, quantity: i32 },
+    RemoveBundle { bundle_id: Uuid },
+    SaveForLater { cart_item_id: Uuid },
+    MoveToCart { cart_item_id: Uuid },
+}
+
+impl CartOperation {
+    // the operation a request to one of the cart routes stands for, with the
+    // same body that route takes
+    pub fn from_request(
+        method: &str,
+        path: &str,
+        body: serde_json::Value,
+    ) -> Result<CartOperation, String> {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        let id = |id: &str| Uuid::parse_str(id).map_err(|_| format!("{id} is not a valid id"));
+        let body_error = |err: serde_json::Error| format!("invalid body: {err}");
+        match (method.to_uppercase().as_str(), segments.as_slice()) {
+            ("POST", ["api", "cart-items"]) => {
+                let body: CartItemBody = serde_json::from_value(body).map_err(body_error)?;
+                Ok(CartOperation::AddItem {
+                    product_id: body.product_id,
+                    quantity: body.quantity,
+                })
+            }
+            ("POST", ["api", "cart-bundles"]) => {
+                let body: CartBundleBody = serde_json::from_value(body).map_err(body_error)?;
+                Ok(CartOperation::AddBundle {
+                    bundle_id: body.bundle_id,
+                    quantity: body.quantity,
+                })
+            }
+            ("DELETE", ["api", "cart-bundles", bundle_id]) => Ok(CartOperation::RemoveBundle {
+                bundle_id: id(bundle_id)?,
+            }),
+            ("POST", ["api", "cart-items", cart_item_id, "save-for-later"]) => {
+                Ok(CartOperation::SaveForLater {
+                    cart_item_id: id(cart_item_id)?,
+                })
+            }
+            ("POST", ["api", "cart-items", cart_item_id, "move-to-cart"]) => {
+                Ok(CartOperation::MoveToCart {
+                    cart_item_id: id(cart_item_id)?,
+                })
+            }
+            _ => Err(format!("{method} {path} can't be batched")),
+        }
+    }
+}
+
+// a line of a reordered order that didn't go back into the cart as ordered,
+// added is how much of it did, nothing when it was left out
+#[derive(Serialize)]
+pub struct ReorderShortfall {
+    product_id: Option<Uuid>,
+    bundle_id: Option<Uuid>,
+    name: Option<String>,
+    ordered: Decimal,
+    added: Decimal,
+    code: &'static str,
+    message: String,
+}
+
+// the cart after a reorder and what of the order isn't in it
+#[derive(Serialize)]
+struct ReorderReport {
+    items: Vec<CartItemWithProduct>,
+    not_added: Vec<ReorderShortfall>,
+}
+
+// why an add to the cart was refused, none when it went through. Anything but
+// the product or the cart being in the way is an error of its own
+fn refusal(
+    result: Result<CartItemOutcome, sqlx::Error>,
+) -> Result<Option<(&'static str, String)>, sqlx::Error> {
+    match result {
+        Ok(CartItemOutcome::Added) => Ok(None),
+        Ok(CartItemOutcome::Rejected(violations)) => Ok(Some((
+            violations
+                .first()
+                .map_or("rejected", |violation| violation.code),
+            violations
+                .into_iter()
+                .map(|violation| violation.message)
+                .collect::<Vec<_>>()
+                .join(", "),
+        ))),
+        Err(sqlx::Error::RowNotFound) => Ok(Some(("discontinued", "no longer sold".into()))),
+        Err(sqlx::Error::Protocol(msg)) => Ok(Some(("unavailable", msg))),
+        Err(err) => Err(err),
+    }
+}
+
+// what a cart route answers a change with, the cart's items after adding a
+// product and the whole cart after anything else
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum CartAnswer {
+    Items(Vec<CartItemWithProduct>),
+    View(CartView),
+}
+
+// one operation of a batch that ran: the cart as it stood right after it, or
+// why the cart's limits refused it
+pub enum BatchStep {
+    Done(CartAnswer),
+    Rejected(Vec<LimitViolation>),
+}
+
+// what came of a batch, one result per operation that ran. Operations after
+// the first failure of an atomic batch don't run, and nothing is kept
+pub struct BatchOutcome {
+    pub committed: bool,
+    pub results: Vec<Result<BatchStep, sqlx::Error>>,
 }
 
 impl Cart {
-    async fn get_or_create_cart(pool: &PgPool, user_id: Uuid) -> Result<Cart, sqlx::Error> {
+    async fn get_or_create_cart(
+        pool: &PgPool,
+        store_id: Uuid,
+        user_id: Uuid,
+    ) -> Result<Cart, sqlx::Error> {
         // First try to get existing active cart
         if let Some(cart) = sqlx::query_as!(
             Cart,
-            "SELECT * FROM carts WHERE user_id = $1 LIMIT 1",
-            user_id
+            "SELECT * FROM carts WHERE user_id = $1 AND store_id = $2 AND is_active",
+            user_id,
+            store_id
         )
         .fetch_optional(pool)
         .await?
@@ -64,83 +277,1218 @@ impl Cart {
             // Create new cart if none exists
             sqlx::query_as!(
                 Cart,
-                "INSERT INTO carts (user_id) VALUES ($1) RETURNING *",
-                user_id
+                "INSERT INTO carts (user_id, store_id) VALUES ($1, $2) RETURNING *",
+                user_id,
+                store_id
             )
             .fetch_one(pool)
             .await
         }
     }
 
-    async fn get_cart_with_items(
+    // every cart of the user in the store, the active one first
+    async fn get_user_carts(
         pool: &PgPool,
+        store_id: Uuid,
+        user_id: Uuid,
+    ) -> Result<Vec<Cart>, sqlx::Error> {
+        sqlx::query_as!(
+            Cart,
+            "SELECT * FROM carts WHERE user_id = $1 AND store_id = $2
+            ORDER BY is_active DESC, updated_at DESC",
+            user_id,
+            store_id
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    fn validate_name(name: &str) -> Result<String, sqlx::Error> {
+        let name = name.trim();
+        if name.is_empty() || name.chars().count() > 100 {
+            return Err(sqlx::Error::Protocol(
+                "Cart name must be 1 to 100 characters".into(),
+            ));
+        }
+        Ok(name.to_string())
+    }
+
+    async fn create_cart(
+        pool: &PgPool,
+        store_id: Uuid,
+        user_id: Uuid,
+        body: NewCartBody,
+    ) -> Result<Cart, sqlx::Error> {
+        let name = Cart::validate_name(&body.name)?;
+        let mut tx = pool.begin().await?;
+
+        if body.activate {
+            sqlx::query!(
+                "UPDATE carts SET is_active = FALSE
+                WHERE user_id = $1 AND store_id = $2 AND is_active",
+                user_id,
+                store_id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        let cart = sqlx::query_as!(
+            Cart,
+            "INSERT INTO carts (user_id, name, is_active, store_id) VALUES ($1, $2, $3, $4)
+            RETURNING *",
+            user_id,
+            name,
+            body.activate,
+            store_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(cart)
+    }
+
+    async fn rename_cart(
+        pool: &PgPool,
+        store_id: Uuid,
+        user_id: Uuid,
         cart_id: Uuid,
+        name: &str,
+    ) -> Result<Cart, sqlx::Error> {
+        let name = Cart::validate_name(name)?;
+        sqlx::query_as!(
+            Cart,
+            "UPDATE carts SET name = $1, updated_at = NOW()
+            WHERE cart_id = $2 AND user_id = $3 AND store_id = $4 RETURNING *",
+            name,
+            cart_id,
+            user_id,
+            store_id
+        )
+        .fetch_optional(pool)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    // make another of the user's carts in the store the one checkout uses
+    async fn activate_cart(
+        pool: &PgPool,
+        store_id: Uuid,
+        user_id: Uuid,
+        cart_id: Uuid,
+    ) -> Result<Cart, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        sqlx::query!(
+            "UPDATE carts SET is_active = FALSE WHERE user_id = $1 AND store_id = $2 AND is_active",
+            user_id,
+            store_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        let cart = sqlx::query_as!(
+            Cart,
+            "UPDATE carts SET is_active = TRUE, updated_at = NOW()
+            WHERE cart_id = $1 AND user_id = $2 AND store_id = $3 RETURNING *",
+            cart_id,
+            user_id,
+            store_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)?;
+
+        tx.commit().await?;
+
+        Ok(cart)
+    }
+
+    async fn get_cart_with_items(
+        conn: &mut PgConnection,
+        cart_id: Uuid,
+        saved_for_later: bool,
     ) -> Result<Vec<CartItemWithProduct>, sqlx::Error> {
         sqlx::query_as!(
             CartItemWithProduct,
-            "
+            r#"
             SELECT 
             cart_items.*,
             products.name as product_name,
-            products.price as product_price 
+            CASE WHEN cart_items.bundle_id IS NULL
+                THEN unit_price(cart_items.product_id, cart_items.quantity, carts.user_id)
+                ELSE bundle_unit_price(cart_items.bundle_id, cart_items.product_id)
+            END as "product_price!",
+            products.unit as "unit: ProductUnit"
             FROM cart_items 
+            JOIN carts ON cart_items.cart_id = carts.cart_id
             JOIN products ON cart_items.product_id = products.product_id
-            WHERE cart_items.cart_id = $1",
+            WHERE cart_items.cart_id = $1 AND cart_items.saved_for_later = $2"#,
+            cart_id,
+            saved_for_later
+        )
+        .fetch_all(conn)
+        .await
+    }
+
+    async fn get_cart_view(
+        conn: &mut PgConnection,
+        pricing: &Pricing,
+        cart_id: Uuid,
+    ) -> Result<CartView, sqlx::Error> {
+        let items = Cart::get_cart_with_items(&mut *conn, cart_id, false).await?;
+        let subtotal = pricing.subtotal(
+            items
+                .iter()
+                .map(|item| (item.product_price, item.quantity.unwrap_or_default())),
+        );
+
+        Ok(CartView {
+            saved_items: Cart::get_cart_with_items(conn, cart_id, true).await?,
+            items,
+            subtotal,
+            amount_to_free_shipping: pricing.amount_to_free_shipping(subtotal),
+        })
+    }
+
+    // park an active item in the save-for-later list, bundle lines can't be split off
+    async fn save_for_later(
+        conn: &mut PgConnection,
+        cart_id: Uuid,
+        cart_item_id: Uuid,
+    ) -> Result<(), sqlx::Error> {
+        let result = sqlx::query!(
+            "UPDATE cart_items SET saved_for_later = TRUE
+            WHERE cart_item_id = $1 AND cart_id = $2 AND NOT saved_for_later
+                AND bundle_id IS NULL",
+            cart_item_id,
+            cart_id
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+
+        sqlx::query!(
+            "UPDATE carts SET updated_at = NOW() WHERE cart_id = $1",
             cart_id
         )
+        .execute(&mut *conn)
+        .await?;
+        Ok(())
+    }
+
+    // put a saved item back, going through add_cart_item so limits and stock
+    // are checked and it merges with the same product already in the cart
+    async fn move_to_cart(
+        conn: &mut PgConnection,
+        limits: &OrderLimits,
+        cart_id: Uuid,
+        group: Option<CustomerGroup>,
+        cart_item_id: Uuid,
+    ) -> Result<CartItemOutcome, sqlx::Error> {
+        let saved = sqlx::query!(
+            r#"SELECT product_id as "product_id!", quantity FROM cart_items
+            WHERE cart_item_id = $1 AND cart_id = $2 AND saved_for_later"#,
+            cart_item_id,
+            cart_id
+        )
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)?;
+
+        let outcome = Cart::add_cart_item(
+            &mut *conn,
+            limits,
+            cart_id,
+            group,
+            saved.product_id,
+            saved.quantity,
+        )
+        .await?;
+        if let CartItemOutcome::Added = outcome {
+            sqlx::query!(
+                "DELETE FROM cart_items WHERE cart_item_id = $1",
+                cart_item_id
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+
+        Ok(outcome)
+    }
+
+    // products that share past orders with the cart contents, most frequent
+    // first, leaving out the ones kept from the group. Items saved for later
+    // are not in the cart, they neither bring nor hold back suggestions
+    async fn get_suggestions(
+        pool: &PgPool,
+        cart_id: Uuid,
+        group: Option<CustomerGroup>,
+        limit: i64,
+    ) -> Result<Vec<CartSuggestion>, sqlx::Error> {
+        sqlx::query_as!(
+            CartSuggestion,
+            r#"
+            SELECT
+            products.product_id,
+            products.name,
+            unit_price(products.product_id, 1, (SELECT user_id FROM carts WHERE cart_id = $1))
+                as "price!",
+            COUNT(DISTINCT order_details.order_id) as "times_bought_together!"
+            FROM order_details
+            JOIN products ON order_details.product_id = products.product_id
+            WHERE order_details.order_id IN (
+                SELECT order_id FROM order_details
+                WHERE product_id IN (
+                    SELECT product_id FROM cart_items
+                    WHERE cart_id = $1 AND NOT saved_for_later
+                )
+            )
+            AND order_details.product_id NOT IN (
+                SELECT product_id FROM cart_items
+                WHERE cart_id = $1 AND product_id IS NOT NULL AND NOT saved_for_later
+            )
+            AND products.is_available IS NOT FALSE
+            AND product_stock(products.product_id) > 0
+            AND visible_to(products.product_id, $3)
+            GROUP BY products.product_id
+            ORDER BY 4 DESC, products.name
+            LIMIT $2"#,
+            cart_id,
+            limit,
+            group as Option<CustomerGroup>
+        )
         .fetch_all(pool)
         .await
     }
 
     async fn add_cart_item(
-        pool: &PgPool,
+        conn: &mut PgConnection,
+        limits: &OrderLimits,
         cart_id: Uuid,
+        group: Option<CustomerGroup>,
         product_id: Uuid,
-        quantity: i32,
-    ) -> Result<CartItem, sqlx::Error> {
-        if let Some(cart_item) = sqlx::query_as!(
+        quantity: Decimal,
+    ) -> Result<CartItemOutcome, sqlx::Error> {
+        // only adds go through here, a zero or negative quantity is never valid
+        if quantity <= Decimal::ZERO {
+            return Ok(CartItemOutcome::Rejected(
+                limits.check_cart_item(product_id, quantity, 0),
+            ));
+        }
+
+        // Check the product can be bought in this quantity, products of
+        // another store than the cart's or kept from the group are not found
+        let product = sqlx::query!(
+            r#"SELECT product_stock(product_id) as "stock_quantity!", is_available,
+                unit as "unit: ProductUnit", quantity_step, age_restriction, purchase_limit,
+                (SELECT user_id FROM carts WHERE cart_id = $2) as buyer
+            FROM products
+            WHERE product_id = $1
+                AND store_id = (SELECT store_id FROM carts WHERE cart_id = $2)
+                AND visible_to(product_id, $3)"#,
+            product_id,
+            cart_id,
+            group as Option<CustomerGroup>
+        )
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)?;
+        if product.is_available == Some(false) {
+            return Err(sqlx::Error::Protocol("Product is not available".into()));
+        }
+        if !(quantity % product.quantity_step).is_zero() {
+            return Ok(CartItemOutcome::Rejected(vec![LimitViolation {
+                code: "quantity_step",
+                message: format!(
+                    "quantity must be a multiple of {} {}",
+                    product.quantity_step.normalize(),
+                    product.unit
+                ),
+                product_id: Some(product_id),
+            }]));
+        }
+        if product.age_restriction.is_some() {
+            let violations =
+                limits::check_ages(&mut *conn, product.buyer.unwrap_or_default(), &[product_id])
+                    .await?;
+            if !violations.is_empty() {
+                return Ok(CartItemOutcome::Rejected(violations));
+            }
+        }
+
+        let existing = sqlx::query_as!(
             CartItem,
-            "SELECT * FROM cart_items WHERE cart_id = $1 AND product_id = $2",
+            "SELECT * FROM cart_items
+            WHERE cart_id = $1 AND product_id = $2 AND NOT saved_for_later AND bundle_id IS NULL",
             cart_id,
             product_id
         )
-        .fetch_optional(pool)
+        .fetch_optional(&mut *conn)
+        .await?;
+        // bundles in the cart take from the same stock
+        let bundled = sqlx::query!(
+            r#"SELECT COALESCE(SUM(quantity), 0) as "quantity!" FROM cart_items
+            WHERE cart_id = $1 AND product_id = $2 AND NOT saved_for_later
+                AND bundle_id IS NOT NULL"#,
+            cart_id,
+            product_id
+        )
+        .fetch_one(&mut *conn)
         .await?
-        {
-            sqlx::query_as!(
-                CartItem,
-                "UPDATE cart_items SET quantity = $1 WHERE cart_item_id = $2 RETURNING *",
-                cart_item.quantity.unwrap_or(0) + quantity,
+        .quantity;
+
+        // Check the cart as it would be after the add
+        let items = sqlx::query!(
+            r#"SELECT COUNT(*) as "count!" FROM cart_items
+            WHERE cart_id = $1 AND NOT saved_for_later"#,
+            cart_id
+        )
+        .fetch_one(&mut *conn)
+        .await?
+        .count;
+        let (new_quantity, new_items) = match &existing {
+            Some(cart_item) => (cart_item.quantity.unwrap_or_default() + quantity, items),
+            None => (quantity, items + 1),
+        };
+        if new_quantity + bundled > product.stock_quantity {
+            return Err(sqlx::Error::Protocol(format!(
+                "Not enough stock: {} available, {} requested",
+                product.stock_quantity,
+                new_quantity + bundled
+            )));
+        }
+        let mut violations = limits.check_cart_item(product_id, new_quantity, new_items);
+        if product.purchase_limit.is_some() {
+            violations.extend(
+                limits::check_purchase_limits(
+                    &mut *conn,
+                    product.buyer.unwrap_or_default(),
+                    &[(product_id, new_quantity + bundled)],
+                )
+                .await?,
+            );
+        }
+        if !violations.is_empty() {
+            return Ok(CartItemOutcome::Rejected(violations));
+        }
+
+        if let Some(cart_item) = existing {
+            sqlx::query!(
+                "UPDATE cart_items SET quantity = $1 WHERE cart_item_id = $2",
+                new_quantity,
                 cart_item.cart_item_id
             )
-            .fetch_one(pool)
-            .await
+            .execute(&mut *conn)
+            .await?;
         } else {
-            sqlx::query_as!(
-                CartItem,
-                "INSERT INTO cart_items (cart_id, product_id, quantity) VALUES ($1, $2, $3) RETURNING *",
+            sqlx::query!(
+                "INSERT INTO cart_items (cart_item_id, cart_id, product_id, quantity)
+                VALUES ($1, $2, $3, $4)",
+                Uuid::now_v7(),
                 cart_id,
                 product_id,
                 quantity
             )
-            .fetch_one(pool)
-            .await
+            .execute(&mut *conn)
+            .await?;
+        }
+
+        // Mark the cart as active so the cleanup job keeps it
+        sqlx::query!(
+            "UPDATE carts SET updated_at = NOW() WHERE cart_id = $1",
+            cart_id
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        Ok(CartItemOutcome::Added)
+    }
+
+    // add a bundle as its component lines, every component needs stock for
+    // everything the cart already holds of it plus the bundle's share
+    async fn add_bundle(
+        conn: &mut PgConnection,
+        limits: &OrderLimits,
+        cart_id: Uuid,
+        group: Option<CustomerGroup>,
+        bundle_id: Uuid,
+        quantity: i32,
+    ) -> Result<CartItemOutcome, sqlx::Error> {
+        if quantity <= 0 {
+            return Ok(CartItemOutcome::Rejected(vec![LimitViolation {
+                code: "invalid_quantity",
+                message: "quantity must be positive".into(),
+                product_id: None,
+            }]));
+        }
+
+        // a bundle of another store than the cart's, or with a product kept
+        // from the group, is not found
+        let bundle = sqlx::query!(
+            "SELECT is_available FROM bundles
+            WHERE bundle_id = $1 AND store_id = (SELECT store_id FROM carts WHERE cart_id = $2)
+                AND NOT EXISTS (
+                    SELECT 1 FROM bundle_items
+                    WHERE bundle_id = $1 AND NOT visible_to(product_id, $3)
+                )",
+            bundle_id,
+            cart_id,
+            group as Option<CustomerGroup>
+        )
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)?;
+        if !bundle.is_available {
+            return Err(sqlx::Error::Protocol("Bundle is not available".into()));
+        }
+
+        let components = sqlx::query!(
+            r#"SELECT bi.product_id, bi.quantity, product_stock(p.product_id) as "stock_quantity!",
+                p.is_available,
+                COALESCE(SUM(ci.quantity), 0) as "in_cart!",
+                COALESCE(SUM(ci.quantity) FILTER (WHERE ci.bundle_id = $2), 0) as "in_bundle!",
+                BOOL_OR(ci.bundle_id = $2) IS NOT TRUE as "new_line!"
+            FROM bundle_items bi
+            JOIN products p ON p.product_id = bi.product_id
+            LEFT JOIN cart_items ci ON ci.cart_id = $1 AND ci.product_id = bi.product_id
+                AND NOT ci.saved_for_later
+            WHERE bi.bundle_id = $2
+            GROUP BY bi.product_id, bi.quantity, p.product_id, p.is_available"#,
+            cart_id,
+            bundle_id
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+
+        let items = sqlx::query!(
+            r#"SELECT COUNT(*) as "count!" FROM cart_items
+            WHERE cart_id = $1 AND NOT saved_for_later"#,
+            cart_id
+        )
+        .fetch_one(&mut *conn)
+        .await?
+        .count;
+        let new_items = items
+            + components
+                .iter()
+                .filter(|component| component.new_line)
+                .count() as i64;
+
+        let mut violations = Vec::new();
+        // what the cart would hold of each component after the add
+        let mut lines = Vec::with_capacity(components.len());
+        for (index, component) in components.iter().enumerate() {
+            if component.is_available == Some(false) {
+                return Err(sqlx::Error::Protocol(format!(
+                    "Product {} in the bundle is not available",
+                    component.product_id
+                )));
+            }
+            let needed = Decimal::from(component.quantity * quantity);
+            if component.in_cart + needed > component.stock_quantity {
+                return Err(sqlx::Error::Protocol(format!(
+                    "Not enough stock for product {} in the bundle: {} available, {} requested",
+                    component.product_id,
+                    component.stock_quantity,
+                    component.in_cart + needed
+                )));
+            }
+            lines.push((component.product_id, component.in_cart + needed));
+            // the cart size only needs checking once
+            let items = if index == 0 { new_items } else { 0 };
+            violations.extend(limits.check_cart_item(
+                component.product_id,
+                component.in_bundle + needed,
+                items,
+            ));
+        }
+        let buyer = sqlx::query_scalar!("SELECT user_id FROM carts WHERE cart_id = $1", cart_id)
+            .fetch_one(&mut *conn)
+            .await?;
+        let product_ids: Vec<Uuid> = components
+            .iter()
+            .map(|component| component.product_id)
+            .collect();
+        violations
+            .extend(limits::check_ages(&mut *conn, buyer.unwrap_or_default(), &product_ids).await?);
+        violations.extend(
+            limits::check_purchase_limits(&mut *conn, buyer.unwrap_or_default(), &lines).await?,
+        );
+        if !violations.is_empty() {
+            return Ok(CartItemOutcome::Rejected(violations));
+        }
+
+        let mut tx = conn.begin().await?;
+
+        for component in &components {
+            let needed = Decimal::from(component.quantity * quantity);
+            if component.new_line {
+                sqlx::query!(
+                    "INSERT INTO cart_items (cart_item_id, cart_id, product_id, quantity, bundle_id)
+                    VALUES ($1, $2, $3, $4, $5)",
+                    Uuid::now_v7(),
+                    cart_id,
+                    component.product_id,
+                    needed,
+                    bundle_id
+                )
+                .execute(&mut *tx)
+                .await?;
+            } else {
+                sqlx::query!(
+                    "UPDATE cart_items SET quantity = quantity + $1
+                    WHERE cart_id = $2 AND product_id = $3 AND bundle_id = $4
+                        AND NOT saved_for_later",
+                    needed,
+                    cart_id,
+                    component.product_id,
+                    bundle_id
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
         }
+
+        sqlx::query!(
+            "UPDATE carts SET updated_at = NOW() WHERE cart_id = $1",
+            cart_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(CartItemOutcome::Added)
+    }
+
+    async fn apply(
+        conn: &mut PgConnection,
+        limits: &OrderLimits,
+        cart_id: Uuid,
+        group: Option<CustomerGroup>,
+        operation: &CartOperation,
+    ) -> Result<CartItemOutcome, sqlx::Error> {
+        match *operation {
+            CartOperation::AddItem {
+                product_id,
+                quantity,
+            } => Cart::add_cart_item(conn, limits, cart_id, group, product_id, quantity).await,
+            CartOperation::AddBundle {
+                bundle_id,
+                quantity,
+            } => Cart::add_bundle(conn, limits, cart_id, group, bundle_id, quantity).await,
+            CartOperation::RemoveBundle { bundle_id } => {
+                Cart::remove_bundle(conn, cart_id, bundle_id)
+                    .await
+                    .map(|_| CartItemOutcome::Added)
+            }
+            CartOperation::SaveForLater { cart_item_id } => {
+                Cart::save_for_later(conn, cart_id, cart_item_id)
+                    .await
+                    .map(|_| CartItemOutcome::Added)
+            }
+            CartOperation::MoveToCart { cart_item_id } => {
+                Cart::move_to_cart(conn, limits, cart_id, group, cart_item_id).await
+            }
+        }
+    }
+
+    // run the operations in order, each in a savepoint so a failed one leaves
+    // nothing behind. An atomic batch stops at the first failure and rolls
+    // everything back
+    async fn apply_batch(
+        pool: &PgPool,
+        limits: &OrderLimits,
+        pricing: &Pricing,
+        cart_id: Uuid,
+        group: Option<CustomerGroup>,
+        operations: &[CartOperation],
+        atomic: bool,
+    ) -> Result<BatchOutcome, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        let mut results = Vec::with_capacity(operations.len());
+        for operation in operations {
+            let mut savepoint = tx.begin().await?;
+            let result = match Cart::apply(&mut savepoint, limits, cart_id, group, operation).await
+            {
+                // answered the way the operation's own route would
+                Ok(CartItemOutcome::Added) => match operation {
+                    CartOperation::AddItem { .. } => {
+                        Cart::get_cart_with_items(&mut savepoint, cart_id, false)
+                            .await
+                            .map(|items| BatchStep::Done(CartAnswer::Items(items)))
+                    }
+                    _ => Cart::get_cart_view(&mut savepoint, pricing, cart_id)
+                        .await
+                        .map(|view| BatchStep::Done(CartAnswer::View(view))),
+                },
+                Ok(CartItemOutcome::Rejected(violations)) => Ok(BatchStep::Rejected(violations)),
+                Err(err) => Err(err),
+            };
+            let failed = !matches!(result, Ok(BatchStep::Done(_)));
+            if failed {
+                savepoint.rollback().await?;
+            } else {
+                savepoint.commit().await?;
+            }
+            results.push(result);
+
+            if failed && atomic {
+                tx.rollback().await?;
+                return Ok(BatchOutcome {
+                    committed: false,
+                    results,
+                });
+            }
+        }
+        tx.commit().await?;
+
+        Ok(BatchOutcome {
+            committed: true,
+            results,
+        })
+    }
+
+    // put what the cart owner bought in one of their orders back into the
+    // cart. Each line goes in on its own, capped at what is in stock beside
+    // what the cart already holds, and is left out when the product or bundle
+    // is no longer sold or the cart's limits refuse it
+    async fn reorder(
+        pool: &PgPool,
+        limits: &OrderLimits,
+        cart_id: Uuid,
+        group: Option<CustomerGroup>,
+        order_id: Uuid,
+    ) -> Result<Vec<ReorderShortfall>, sqlx::Error> {
+        sqlx::query!(
+            "SELECT o.order_id FROM orders o
+            JOIN carts c ON c.user_id = o.user_id AND c.store_id = o.store_id
+            WHERE o.order_id = $1 AND c.cart_id = $2",
+            order_id,
+            cart_id
+        )
+        .fetch_optional(pool)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)?;
+
+        let lines = sqlx::query!(
+            r#"SELECT od.product_id, p.name as "name?", SUM(od.quantity) as "ordered!",
+                p.product_id IS NOT NULL AND p.store_id = o.store_id
+                    AND p.is_available IS NOT FALSE
+                    AND visible_to(p.product_id, $3) as "sold!",
+                product_stock(p.product_id) as "stock?", p.quantity_step as "quantity_step?",
+                (SELECT COALESCE(SUM(ci.quantity), 0) FROM cart_items ci
+                WHERE ci.cart_id = $2 AND ci.product_id = od.product_id
+                    AND NOT ci.saved_for_later) as "in_cart!"
+            FROM order_details od
+            JOIN orders o ON o.order_id = od.order_id
+            LEFT JOIN products p ON p.product_id = od.product_id
+            WHERE od.order_id = $1 AND od.bundle_id IS NULL
+            GROUP BY od.product_id, p.product_id, o.store_id
+            ORDER BY p.name NULLS LAST"#,
+            order_id,
+            cart_id,
+            group as Option<CustomerGroup>
+        )
+        .fetch_all(pool)
+        .await?;
+        // an order holds a bundle as its component lines, how many were
+        // bought is read back from any of them. Components are fixed once a
+        // bundle is made, it is still sold while available and none of them
+        // is kept from the group
+        let bundles = sqlx::query!(
+            r#"SELECT b.bundle_id, b.name,
+                b.is_available AND NOT EXISTS (
+                    SELECT 1 FROM bundle_items c
+                    WHERE c.bundle_id = b.bundle_id AND NOT visible_to(c.product_id, $2)
+                ) as "sold!",
+                FLOOR(MIN(od.quantity / bi.quantity))::INT as ordered
+            FROM order_details od
+            JOIN bundles b ON b.bundle_id = od.bundle_id
+            JOIN bundle_items bi
+                ON bi.bundle_id = od.bundle_id AND bi.product_id = od.product_id
+            WHERE od.order_id = $1
+            GROUP BY b.bundle_id
+            ORDER BY b.name"#,
+            order_id,
+            group as Option<CustomerGroup>
+        )
+        .fetch_all(pool)
+        .await?;
+
+        let mut tx = pool.begin().await?;
+        let mut shortfalls = Vec::new();
+        for line in lines {
+            let name = line.name.clone().unwrap_or_else(|| "A product".into());
+            let shortfall =
+                |added: Decimal, code: &'static str, message: String| ReorderShortfall {
+                    product_id: line.product_id,
+                    bundle_id: None,
+                    name: line.name.clone(),
+                    ordered: line.ordered,
+                    added,
+                    code,
+                    message,
+                };
+            let (Some(product_id), true) = (line.product_id, line.sold) else {
+                shortfalls.push(shortfall(
+                    Decimal::ZERO,
+                    "discontinued",
+                    format!("{name} is no longer sold"),
+                ));
+                continue;
+            };
+            let available = line.stock.unwrap_or_default() - line.in_cart;
+            let step = line.quantity_step.unwrap_or(Decimal::ONE);
+            let quantity = if line.ordered > available {
+                (available / step).floor() * step
+            } else {
+                line.ordered
+            };
+            if quantity <= Decimal::ZERO {
+                shortfalls.push(shortfall(
+                    Decimal::ZERO,
+                    "out_of_stock",
+                    format!("{name} is out of stock"),
+                ));
+                continue;
+            }
+
+            let mut savepoint = tx.begin().await?;
+            let result =
+                Cart::add_cart_item(&mut savepoint, limits, cart_id, group, product_id, quantity)
+                    .await;
+            match refusal(result)? {
+                None => {
+                    savepoint.commit().await?;
+                    if quantity < line.ordered {
+                        shortfalls.push(shortfall(
+                            quantity,
+                            "limited_stock",
+                            format!("only {} of {name} is in stock", quantity.normalize()),
+                        ));
+                    }
+                }
+                Some((code, message)) => {
+                    savepoint.rollback().await?;
+                    shortfalls.push(shortfall(Decimal::ZERO, code, message));
+                }
+            }
+        }
+
+        for bundle in bundles {
+            let shortfall = |added: i32, code: &'static str, message: String| ReorderShortfall {
+                product_id: None,
+                bundle_id: Some(bundle.bundle_id),
+                name: Some(bundle.name.clone()),
+                ordered: Decimal::from(bundle.ordered.unwrap_or_default()),
+                added: Decimal::from(added),
+                code,
+                message,
+            };
+            let Some(ordered) = bundle.ordered.filter(|_| bundle.sold) else {
+                shortfalls.push(shortfall(
+                    0,
+                    "discontinued",
+                    format!("{} is no longer sold", bundle.name),
+                ));
+                continue;
+            };
+            // as many as the stock left after the lines added before it allows
+            let in_stock = sqlx::query_scalar!(
+                r#"SELECT FLOOR(MIN(
+                    (product_stock(c.product_id) - COALESCE((SELECT SUM(ci.quantity)
+                        FROM cart_items ci
+                        WHERE ci.cart_id = $2 AND ci.product_id = c.product_id
+                            AND NOT ci.saved_for_later), 0)) / c.quantity
+                ))::INT FROM bundle_items c WHERE c.bundle_id = $1"#,
+                bundle.bundle_id,
+                cart_id
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+            let quantity = ordered.min(in_stock.unwrap_or_default());
+            if quantity <= 0 {
+                shortfalls.push(shortfall(
+                    0,
+                    "out_of_stock",
+                    format!("{} is out of stock", bundle.name),
+                ));
+                continue;
+            }
+
+            let mut savepoint = tx.begin().await?;
+            let result = Cart::add_bundle(
+                &mut savepoint,
+                limits,
+                cart_id,
+                group,
+                bundle.bundle_id,
+                quantity,
+            )
+            .await;
+            match refusal(result)? {
+                None => {
+                    savepoint.commit().await?;
+                    if quantity < ordered {
+                        shortfalls.push(shortfall(
+                            quantity,
+                            "limited_stock",
+                            format!("only {quantity} of {} is in stock", bundle.name),
+                        ));
+                    }
+                }
+                Some((code, message)) => {
+                    savepoint.rollback().await?;
+                    shortfalls.push(shortfall(0, code, message));
+                }
+            }
+        }
+        tx.commit().await?;
+
+        Ok(shortfalls)
+    }
+
+    // take every line of a bundle out of the cart
+    async fn remove_bundle(
+        conn: &mut PgConnection,
+        cart_id: Uuid,
+        bundle_id: Uuid,
+    ) -> Result<(), sqlx::Error> {
+        let result = sqlx::query!(
+            "DELETE FROM cart_items WHERE cart_id = $1 AND bundle_id = $2",
+            cart_id,
+            bundle_id
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+
+        sqlx::query!(
+            "UPDATE carts SET updated_at = NOW() WHERE cart_id = $1",
+            cart_id
+        )
+        .execute(&mut *conn)
+        .await?;
+        Ok(())
+    }
+}
+
+// data access for carts, handlers reach it through AppState::carts so they
+// can be tested without a database
+#[async_trait]
+pub trait CartRepo: Send + Sync {
+    // the user's active cart in the store, created on first use. A customer
+    // has carts of their own in every store
+    async fn active_cart(&self, store_id: Uuid, user_id: Uuid) -> Result<Cart, sqlx::Error>;
+    async fn user_carts(&self, store_id: Uuid, user_id: Uuid) -> Result<Vec<Cart>, sqlx::Error>;
+    async fn create(
+        &self,
+        store_id: Uuid,
+        user_id: Uuid,
+        body: NewCartBody,
+    ) -> Result<Cart, sqlx::Error>;
+    async fn rename(
+        &self,
+        store_id: Uuid,
+        user_id: Uuid,
+        cart_id: Uuid,
+        name: &str,
+    ) -> Result<Cart, sqlx::Error>;
+    async fn activate(
+        &self,
+        store_id: Uuid,
+        user_id: Uuid,
+        cart_id: Uuid,
+    ) -> Result<Cart, sqlx::Error>;
+    async fn items(
+        &self,
+        cart_id: Uuid,
+        saved_for_later: bool,
+    ) -> Result<Vec<CartItemWithProduct>, sqlx::Error>;
+    async fn view(&self, pricing: &Pricing, cart_id: Uuid) -> Result<CartView, sqlx::Error>;
+    async fn add_item(
+        &self,
+        limits: &OrderLimits,
+        cart_id: Uuid,
+        group: Option<CustomerGroup>,
+        product_id: Uuid,
+        quantity: Decimal,
+    ) -> Result<CartItemOutcome, sqlx::Error>;
+    async fn add_bundle(
+        &self,
+        limits: &OrderLimits,
+        cart_id: Uuid,
+        group: Option<CustomerGroup>,
+        bundle_id: Uuid,
+        quantity: i32,
+    ) -> Result<CartItemOutcome, sqlx::Error>;
+    async fn remove_bundle(&self, cart_id: Uuid, bundle_id: Uuid) -> Result<(), sqlx::Error>;
+    async fn reorder(
+        &self,
+        limits: &OrderLimits,
+        cart_id: Uuid,
+        group: Option<CustomerGroup>,
+        order_id: Uuid,
+    ) -> Result<Vec<ReorderShortfall>, sqlx::Error>;
+    async fn suggestions(
+        &self,
+        cart_id: Uuid,
+        group: Option<CustomerGroup>,
+        limit: i64,
+    ) -> Result<Vec<CartSuggestion>, sqlx::Error>;
+    async fn save_for_later(&self, cart_id: Uuid, cart_item_id: Uuid) -> Result<(), sqlx::Error>;
+    async fn move_to_cart(
+        &self,
+        limits: &OrderLimits,
+        cart_id: Uuid,
+        group: Option<CustomerGroup>,
+        cart_item_id: Uuid,
+    ) -> Result<CartItemOutcome, sqlx::Error>;
+    async fn batch(
+        &self,
+        limits: &OrderLimits,
+        pricing: &Pricing,
+        cart_id: Uuid,
+        group: Option<CustomerGroup>,
+        operations: &[CartOperation],
+        atomic: bool,
+    ) -> Result<BatchOutcome, sqlx::Error>;
+}
+
+// every call is timed under "carts.<method>"
+pub struct PgCartRepo {
+    pool: PgPool,
+    timings: Arc<QueryStats>,
+}
+
+impl PgCartRepo {
+    pub fn new(pool: PgPool, timings: Arc<QueryStats>) -> Self {
+        PgCartRepo { pool, timings }
+    }
+}
+
+#[async_trait]
+impl CartRepo for PgCartRepo {
+    async fn active_cart(&self, store_id: Uuid, user_id: Uuid) -> Result<Cart, sqlx::Error> {
+        self.timings
+            .time(
+                "carts.active_cart",
+                Cart::get_or_create_cart(&self.pool, store_id, user_id),
+            )
+            .await
+    }
+
+    async fn user_carts(&self, store_id: Uuid, user_id: Uuid) -> Result<Vec<Cart>, sqlx::Error> {
+        self.timings
+            .time(
+                "carts.user_carts",
+                Cart::get_user_carts(&self.pool, store_id, user_id),
+            )
+            .await
+    }
+
+    async fn create(
+        &self,
+        store_id: Uuid,
+        user_id: Uuid,
+        body: NewCartBody,
+    ) -> Result<Cart, sqlx::Error> {
+        self.timings
+            .time(
+                "carts.create",
+                Cart::create_cart(&self.pool, store_id, user_id, body),
+            )
+            .await
+    }
+
+    async fn rename(
+        &self,
+        store_id: Uuid,
+        user_id: Uuid,
+        cart_id: Uuid,
+        name: &str,
+    ) -> Result<Cart, sqlx::Error> {
+        self.timings
+            .time(
+                "carts.rename",
+                Cart::rename_cart(&self.pool, store_id, user_id, cart_id, name),
+            )
+            .await
+    }
+
+    async fn activate(
+        &self,
+        store_id: Uuid,
+        user_id: Uuid,
+        cart_id: Uuid,
+    ) -> Result<Cart, sqlx::Error> {
+        self.timings
+            .time(
+                "carts.activate",
+                Cart::activate_cart(&self.pool, store_id, user_id, cart_id),
+            )
+            .await
+    }
+
+    async fn items(
+        &self,
+        cart_id: Uuid,
+        saved_for_later: bool,
+    ) -> Result<Vec<CartItemWithProduct>, sqlx::Error> {
+        self.timings
+            .time("carts.items", async {
+                let mut conn = self.pool.acquire().await?;
+                Cart::get_cart_with_items(&mut conn, cart_id, saved_for_later).await
+            })
+            .await
+    }
+
+    async fn view(&self, pricing: &Pricing, cart_id: Uuid) -> Result<CartView, sqlx::Error> {
+        self.timings
+            .time("carts.view", async {
+                let mut conn = self.pool.acquire().await?;
+                Cart::get_cart_view(&mut conn, pricing, cart_id).await
+            })
+            .await
+    }
+
+    async fn add_item(
+        &self,
+        limits: &OrderLimits,
+        cart_id: Uuid,
+        group: Option<CustomerGroup>,
+        product_id: Uuid,
+        quantity: Decimal,
+    ) -> Result<CartItemOutcome, sqlx::Error> {
+        self.timings
+            .time("carts.add_item", async {
+                let mut conn = self.pool.acquire().await?;
+                Cart::add_cart_item(&mut conn, limits, cart_id, group, product_id, quantity).await
+            })
+            .await
+    }
+
+    async fn add_bundle(
+        &self,
+        limits: &OrderLimits,
+        cart_id: Uuid,
+        group: Option<CustomerGroup>,
+        bundle_id: Uuid,
+        quantity: i32,
+    ) -> Result<CartItemOutcome, sqlx::Error> {
+        self.timings
+            .time("carts.add_bundle", async {
+                let mut conn = self.pool.acquire().await?;
+                Cart::add_bundle(&mut conn, limits, cart_id, group, bundle_id, quantity).await
+            })
+            .await
+    }
+
+    async fn remove_bundle(&self, cart_id: Uuid, bundle_id: Uuid) -> Result<(), sqlx::Error> {
+        self.timings
+            .time("carts.remove_bundle", async {
+                let mut conn = self.pool.acquire().await?;
+                Cart::remove_bundle(&mut conn, cart_id, bundle_id).await
+            })
+            .await
+    }
+
+    async fn reorder(
+        &self,
+        limits: &OrderLimits,
+        cart_id: Uuid,
+        group: Option<CustomerGroup>,
+        order_id: Uuid,
+    ) -> Result<Vec<ReorderShortfall>, sqlx::Error> {
+        self.timings
+            .time(
+                "carts.reorder",
+                Cart::reorder(&self.pool, limits, cart_id, group, order_id),
+            )
+            .await
+    }
+
+    async fn suggestions(
+        &self,
+        cart_id: Uuid,
+        group: Option<CustomerGroup>,
+        limit: i64,
+    ) -> Result<Vec<CartSuggestion>, sqlx::Error> {
+        self.timings
+            .time(
+                "carts.suggestions",
+                Cart::get_suggestions(&self.pool, cart_id, group, limit),
+            )
+            .await
+    }
+
+    async fn save_for_later(&self, cart_id: Uuid, cart_item_id: Uuid) -> Result<(), sqlx::Error> {
+        self.timings
+            .time("carts.save_for_later", async {
+                let mut conn = self.pool.acquire().await?;
+                Cart::save_for_later(&mut conn, cart_id, cart_item_id).await
+            })
+            .await
+    }
+
+    async fn move_to_cart(
+        &self,
+        limits: &OrderLimits,
+        cart_id: Uuid,
+        group: Option<CustomerGroup>,
+        cart_item_id: Uuid,
+    ) -> Result<CartItemOutcome, sqlx::Error> {
+        self.timings
+            .time("carts.move_to_cart", async {
+                let mut conn = self.pool.acquire().await?;
+                Cart::move_to_cart(&mut conn, limits, cart_id, group, cart_item_id).await
+            })
+            .await
+    }
+
+    async fn batch(
+        &self,
+        limits: &OrderLimits,
+        pricing: &Pricing,
+        cart_id: Uuid,
+        group: Option<CustomerGroup>,
+        operations: &[CartOperation],
+        atomic: bool,
+    ) -> Result<BatchOutcome, sqlx::Error> {
+        self.timings
+            .time(
+                "carts.batch",
+                Cart::apply_batch(
+                    &self.pool, limits, pricing, cart_id, group, operations, atomic,
+                ),
+            )
+            .await
     }
 }
 
 #[get("api/carts")]
 pub async fn get_cart(
     state: web::Data<AppState>,
+    store: ReqData<Store>,
     req_user: Option<ReqData<TokenClaims>>,
 ) -> impl Responder {
     match req_user {
-        Some(user) => match Cart::get_or_create_cart(&state.db, user.user_id).await {
-            Ok(cart) => match Cart::get_cart_with_items(&state.db, cart.cart_id).await {
-                Ok(cart_with_items) => HttpResponse::Ok().json(cart_with_items),
-                Err(e) => HttpResponse::InternalServerError().json(e.to_string()),
+        Some(user) => match state.carts.active_cart(store.store_id, user.user_id).await {
+            Ok(cart) => match state.carts.view(&state.pricing, cart.cart_id).await {
+                Ok(cart_view) => HttpResponse::Ok().json(cart_view),
+                Err(e) => database_error(e),
             },
-            Err(e) => HttpResponse::InternalServerError().json(e.to_string()),
+            Err(e) => database_error(e),
         },
         None => HttpResponse::Unauthorized().json("Please log in"),
     }
@@ -149,36 +1497,340 @@ pub async fn get_cart(
 #[post("api/cart-items")]
 pub async fn add_cart_item(
     state: web::Data<AppState>,
+    store: ReqData<Store>,
     body: Json<CartItemBody>,
     req_user: Option<ReqData<TokenClaims>>,
 ) -> impl Responder {
     match req_user {
         Some(user) => {
             // Get or create cart
-            match Cart::get_or_create_cart(&state.db, user.user_id).await {
+            match state.carts.active_cart(store.store_id, user.user_id).await {
                 Ok(cart) => {
                     // Add item to cart
-                    match Cart::add_cart_item(
-                        &state.db,
-                        cart.cart_id, // No need for Some()
-                        body.product_id,
-                        body.quantity,
-                    )
-                    .await
+                    match state
+                        .carts
+                        .add_item(
+                            &state.limits,
+                            cart.cart_id, // No need for Some()
+                            user.catalogue_group(),
+                            body.product_id,
+                            body.quantity,
+                        )
+                        .await
                     {
-                        Ok(_) => {
+                        Ok(CartItemOutcome::Rejected(violations)) => {
+                            HttpResponse::UnprocessableEntity().json(violations)
+                        }
+                        Ok(CartItemOutcome::Added) => {
                             // Get updated cart items
-                            match Cart::get_cart_with_items(&state.db, cart.cart_id).await {
+                            match state.carts.items(cart.cart_id, false).await {
                                 Ok(cart_items) => HttpResponse::Created().json(cart_items),
-                                Err(e) => HttpResponse::InternalServerError().json(e.to_string()),
+                                Err(e) => database_error(e),
                             }
                         }
-                        Err(e) => HttpResponse::InternalServerError().json(e.to_string()),
+                        Err(sqlx::Error::RowNotFound) => {
+                            HttpResponse::NotFound().json("Product not found")
+                        }
+                        Err(sqlx::Error::Protocol(msg)) => HttpResponse::Conflict().json(msg),
+                        Err(e) => database_error(e),
                     }
                 }
-                Err(e) => HttpResponse::InternalServerError().json(e.to_string()),
+                Err(e) => database_error(e),
             }
         }
         None => HttpResponse::Unauthorized().json("Please log in"),
     }
 }
+
+// post request to put what was bought in one of the customer's orders back into
+// the active cart, as much of it as is in stock. The cart comes back with what
+// couldn't be added as ordered and why
+#[post("api/orders/{id}/reorder")]
+pub async fn reorder(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    order_id: web::Path<Uuid>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => match state.carts.active_cart(store.store_id, user.user_id).await {
+            Ok(cart) => match state
+                .carts
+                .reorder(
+                    &state.limits,
+                    cart.cart_id,
+                    user.catalogue_group(),
+                    *order_id,
+                )
+                .await
+            {
+                Ok(not_added) => match state.carts.items(cart.cart_id, false).await {
+                    Ok(items) => HttpResponse::Ok().json(ReorderReport { items, not_added }),
+                    Err(e) => database_error(e),
+                },
+                Err(sqlx::Error::RowNotFound) => HttpResponse::NotFound().json("Order not found"),
+                Err(e) => database_error(e),
+            },
+            Err(e) => database_error(e),
+        },
+        None => HttpResponse::Unauthorized().json("Please log in"),
+    }
+}
+
+// post request to add a bundle to the active cart
+#[post("api/cart-bundles")]
+pub async fn add_cart_bundle(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    body: Json<CartBundleBody>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => match state.carts.active_cart(store.store_id, user.user_id).await {
+            Ok(cart) => {
+                match state
+                    .carts
+                    .add_bundle(
+                        &state.limits,
+                        cart.cart_id,
+                        user.catalogue_group(),
+                        body.bundle_id,
+                        body.quantity,
+                    )
+                    .await
+                {
+                    Ok(CartItemOutcome::Added) => {
+                        match state.carts.view(&state.pricing, cart.cart_id).await {
+                            Ok(cart_view) => HttpResponse::Created().json(cart_view),
+                            Err(e) => database_error(e),
+                        }
+                    }
+                    Ok(CartItemOutcome::Rejected(violations)) => {
+                        HttpResponse::UnprocessableEntity().json(violations)
+                    }
+                    Err(sqlx::Error::RowNotFound) => {
+                        HttpResponse::NotFound().json("Bundle not found")
+                    }
+                    Err(sqlx::Error::Protocol(msg)) => HttpResponse::Conflict().json(msg),
+                    Err(e) => database_error(e),
+                }
+            }
+            Err(e) => database_error(e),
+        },
+        None => HttpResponse::Unauthorized().json("Please log in"),
+    }
+}
+
+// delete request to take a bundle out of the active cart
+#[delete("api/cart-bundles/{bundle_id}")]
+pub async fn remove_cart_bundle(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    bundle_id: web::Path<Uuid>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => match state.carts.active_cart(store.store_id, user.user_id).await {
+            Ok(cart) => match state.carts.remove_bundle(cart.cart_id, *bundle_id).await {
+                Ok(()) => match state.carts.view(&state.pricing, cart.cart_id).await {
+                    Ok(cart_view) => HttpResponse::Ok().json(cart_view),
+                    Err(e) => database_error(e),
+                },
+                Err(sqlx::Error::RowNotFound) => {
+                    HttpResponse::NotFound().json("Bundle is not in the cart")
+                }
+                Err(e) => database_error(e),
+            },
+            Err(e) => database_error(e),
+        },
+        None => HttpResponse::Unauthorized().json("Please log in"),
+    }
+}
+
+// get request for products to suggest before checkout, ?limit= defaults to 10
+#[get("api/carts/suggestions")]
+pub async fn get_cart_suggestions(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    query: web::Query<SuggestionQuery>,
+) -> impl Responder {
+    let limit = query.limit.unwrap_or(10).clamp(1, 50);
+
+    match req_user {
+        Some(user) => match state.carts.active_cart(store.store_id, user.user_id).await {
+            Ok(cart) => match state
+                .carts
+                .suggestions(cart.cart_id, user.catalogue_group(), limit)
+                .await
+            {
+                Ok(suggestions) => HttpResponse::Ok().json(suggestions),
+                Err(e) => database_error(e),
+            },
+            Err(e) => database_error(e),
+        },
+        None => HttpResponse::Unauthorized().json("Please log in"),
+    }
+}
+
+// post request to move a cart item to the save-for-later list
+#[post("api/cart-items/{id}/save-for-later")]
+pub async fn save_for_later(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    cart_item_id: web::Path<Uuid>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => match state.carts.active_cart(store.store_id, user.user_id).await {
+            Ok(cart) => match state
+                .carts
+                .save_for_later(cart.cart_id, *cart_item_id)
+                .await
+            {
+                Ok(()) => match state.carts.view(&state.pricing, cart.cart_id).await {
+                    Ok(cart_view) => HttpResponse::Ok().json(cart_view),
+                    Err(e) => database_error(e),
+                },
+                Err(sqlx::Error::RowNotFound) => {
+                    HttpResponse::NotFound().json("Cart item not found")
+                }
+                Err(e) => database_error(e),
+            },
+            Err(e) => database_error(e),
+        },
+        None => HttpResponse::Unauthorized().json("Please log in"),
+    }
+}
+
+// post request to move a saved item back into the cart
+#[post("api/cart-items/{id}/move-to-cart")]
+pub async fn move_to_cart(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    cart_item_id: web::Path<Uuid>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => match state.carts.active_cart(store.store_id, user.user_id).await {
+            Ok(cart) => {
+                match state
+                    .carts
+                    .move_to_cart(
+                        &state.limits,
+                        cart.cart_id,
+                        user.catalogue_group(),
+                        *cart_item_id,
+                    )
+                    .await
+                {
+                    Ok(CartItemOutcome::Added) => {
+                        match state.carts.view(&state.pricing, cart.cart_id).await {
+                            Ok(cart_view) => HttpResponse::Ok().json(cart_view),
+                            Err(e) => database_error(e),
+                        }
+                    }
+                    Ok(CartItemOutcome::Rejected(violations)) => {
+                        HttpResponse::UnprocessableEntity().json(violations)
+                    }
+                    Err(sqlx::Error::RowNotFound) => {
+                        HttpResponse::NotFound().json("Saved item not found")
+                    }
+                    Err(sqlx::Error::Protocol(msg)) => HttpResponse::Conflict().json(msg),
+                    Err(e) => database_error(e),
+                }
+            }
+            Err(e) => database_error(e),
+        },
+        None => HttpResponse::Unauthorized().json("Please log in"),
+    }
+}
+
+// get request for all carts of the current user
+#[get("api/carts/all")]
+pub async fn get_user_carts(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => match state.carts.user_carts(store.store_id, user.user_id).await {
+            Ok(carts) => HttpResponse::Ok().json(
+                carts
+                    .into_iter()
+                    .map(CartResponse::from)
+                    .collect::<Vec<_>>(),
+            ),
+            Err(e) => database_error(e),
+        },
+        None => HttpResponse::Unauthorized().json("Please log in"),
+    }
+}
+
+// post request to create a named cart, "activate": true switches to it
+#[post("api/carts")]
+pub async fn create_cart(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    body: Json<NewCartBody>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => match state
+            .carts
+            .create(store.store_id, user.user_id, body.into_inner())
+            .await
+        {
+            Ok(cart) => HttpResponse::Created().json(CartResponse::from(cart)),
+            Err(sqlx::Error::Protocol(msg)) => HttpResponse::BadRequest().json(msg),
+            Err(e) => database_error(e),
+        },
+        None => HttpResponse::Unauthorized().json("Please log in"),
+    }
+}
+
+// put request to rename a cart
+#[put("api/carts/{id}")]
+pub async fn rename_cart(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    cart_id: web::Path<Uuid>,
+    body: Json<RenameCartBody>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => match state
+            .carts
+            .rename(store.store_id, user.user_id, *cart_id, &body.name)
+            .await
+        {
+            Ok(cart) => HttpResponse::Ok().json(CartResponse::from(cart)),
+            Err(sqlx::Error::RowNotFound) => HttpResponse::NotFound().json("Cart not found"),
+            Err(sqlx::Error::Protocol(msg)) => HttpResponse::BadRequest().json(msg),
+            Err(e) => database_error(e),
+        },
+        None => HttpResponse::Unauthorized().json("Please log in"),
+    }
+}
+
+// post request to switch the active cart
+#[post("api/carts/{id}/activate")]
+pub async fn activate_cart(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    cart_id: web::Path<Uuid>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => match state
+            .carts
+            .activate(store.store_id, user.user_id, *cart_id)
+            .await
+        {
+            Ok(cart) => HttpResponse::Ok().json(CartResponse::from(cart)),
+            Err(sqlx::Error::RowNotFound) => HttpResponse::NotFound().json("Cart not found"),
+            Err(e) => database_error(e),
+        },
+        None => HttpResponse::Unauthorized().json("Please log in"),
+    }
+}
@@ -0,0 +1,193 @@
+use crate::{
+    api::{stores::Store, users::TokenClaims},
+    rate_limit::database_error,
+    AppState,
+};
+use actix_web::{
+    get,
+    http::header::{self, Header},
+    web::{self, ReqData},
+    HttpRequest, HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+// the schema warehouse systems generate their decoders from
+const SCHEMA: &str = include_str!("../../proto/catalog.proto");
+
+const PROTOBUF: &str = "application/x-protobuf";
+
+// a product as proto/catalog.proto has it, sent as JSON with the same fields
+#[derive(Clone, PartialEq, Message, Serialize)]
+pub struct SyncedProduct {
+    #[prost(string, tag = "1")]
+    product_id: String,
+    #[prost(string, tag = "2")]
+    name: String,
+    #[prost(string, optional, tag = "3")]
+    description: Option<String>,
+    #[prost(string, optional, tag = "4")]
+    category: Option<String>,
+    #[prost(string, tag = "5")]
+    price: String,
+    #[prost(string, tag = "6")]
+    stock_quantity: String,
+    #[prost(string, tag = "7")]
+    unit: String,
+    #[prost(string, tag = "8")]
+    quantity_step: String,
+    #[prost(bool, tag = "9")]
+    is_available: bool,
+    #[prost(int64, tag = "10")]
+    updated_at: i64,
+}
+
+#[derive(Clone, PartialEq, Message, Serialize)]
+pub struct CatalogSync {
+    #[prost(int64, tag = "1")]
+    synced_at: i64,
+    #[prost(bool, tag = "2")]
+    full: bool,
+    #[prost(message, repeated, tag = "3")]
+    products: Vec<SyncedProduct>,
+    #[prost(string, repeated, tag = "4")]
+    deleted_product_ids: Vec<String>,
+}
+
+// ?since= the synced_at of the previous sync, everything without it
+#[derive(Deserialize)]
+pub struct SyncQuery {
+    since: Option<i64>,
+}
+
+impl CatalogSync {
+    // the store's products changed after `since`, kits when one of their
+    // components changed, and the ones deleted since. Deltas reach a minute
+    // further back than asked so changes committed late aren't missed, they
+    // are upserts
+    pub async fn since(
+        pool: &PgPool,
+        store_id: Uuid,
+        since: Option<DateTime<Utc>>,
+    ) -> Result<CatalogSync, sqlx::Error> {
+        // one snapshot for the products, the deletions and the time they're as of
+        let mut tx = pool.begin().await?;
+        sqlx::query!("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ")
+            .execute(&mut *tx)
+            .await?;
+        let synced_at = sqlx::query_scalar!(r#"SELECT NOW() as "now!""#)
+            .fetch_one(&mut *tx)
+            .await?;
+
+        let products = sqlx::query!(
+            r#"SELECT p.product_id, p.name, p.description, p.category, p.price,
+                product_stock(p.product_id) as "stock_quantity!", p.unit::text as "unit!",
+                p.quantity_step, COALESCE(p.is_available, TRUE) as "is_available!", p.updated_at
+            FROM products p
+            WHERE p.store_id = $2 AND ($1::timestamptz IS NULL
+                OR p.updated_at > $1 - INTERVAL '1 minute'
+                OR EXISTS (
+                    SELECT 1 FROM kit_components k
+                    JOIN products c ON c.product_id = k.component_id
+                    WHERE k.kit_id = p.product_id AND c.updated_at > $1 - INTERVAL '1 minute'
+                ))
+            ORDER BY p.product_id"#,
+            since,
+            store_id
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let deleted_product_ids = match since {
+            Some(since) => sqlx::query_scalar!(
+                "SELECT product_id FROM product_deletions
+                WHERE deleted_at > $1::timestamptz - INTERVAL '1 minute' AND store_id = $2
+                ORDER BY product_id",
+                since,
+                store_id
+            )
+            .fetch_all(&mut *tx)
+            .await?
+            .into_iter()
+            .map(|id| id.to_string())
+            .collect(),
+            None => Vec::new(),
+        };
+        tx.commit().await?;
+
+        Ok(CatalogSync {
+            synced_at: synced_at.timestamp_millis(),
+            full: since.is_none(),
+            products: products
+                .into_iter()
+                .map(|product| SyncedProduct {
+                    product_id: product.product_id.to_string(),
+                    name: product.name,
+                    description: product.description,
+                    category: product.category,
+                    price: product.price.to_string(),
+                    stock_quantity: product.stock_quantity.normalize().to_string(),
+                    unit: product.unit,
+                    quantity_step: product.quantity_step.normalize().to_string(),
+                    is_available: product.is_available,
+                    updated_at: product.updated_at.timestamp_millis(),
+                })
+                .collect(),
+            deleted_product_ids,
+        })
+    }
+}
+
+fn wants_protobuf(req: &HttpRequest) -> bool {
+    header::Accept::parse(req).is_ok_and(|accept| {
+        matches!(
+            accept.preference().essence_str(),
+            PROTOBUF | "application/protobuf"
+        )
+    })
+}
+
+// admin only
+// get request for the catalogue with stock for warehouse systems, all of it or
+// what changed ?since= the last sync. Accept: application/x-protobuf gets it
+// encoded as proto/catalog.proto describes
+#[get("api/admin/catalog/sync")]
+pub async fn sync_catalog(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    query: web::Query<SyncQuery>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if !user.is_admin() {
+                return HttpResponse::Forbidden().json("customer not allowed to sync the catalog");
+            }
+            let since = match query.since.map(DateTime::from_timestamp_millis) {
+                Some(None) => return HttpResponse::BadRequest().json("since is not a valid time"),
+                Some(since) => since,
+                None => None,
+            };
+            match CatalogSync::since(&state.db, store.store_id, since).await {
+                Ok(sync) if wants_protobuf(&req) => HttpResponse::Ok()
+                    .content_type(PROTOBUF)
+                    .body(sync.encode_to_vec()),
+                Ok(sync) => HttpResponse::Ok().json(sync),
+                Err(err) => database_error(err),
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// get request for the protobuf schema of the catalog sync
+#[get("api/catalog/sync.proto")]
+pub async fn get_catalog_schema() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; charset=utf-8")
+        .body(SCHEMA)
+}
@@ -0,0 +1,111 @@
+use crate::{
+    api::{stores::Store, users::TokenClaims},
+    geoip::{self, RequestContext},
+    rate_limit::database_error,
+    AppState,
+};
+use actix_web::{
+    get,
+    web::{self, ReqData},
+    HttpRequest, HttpResponse, Responder,
+};
+use serde::Serialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+// what the checkout form starts with for a signed in customer, the address of
+// their last shipped order in this store and their contact details
+#[derive(Serialize)]
+struct CheckoutDefaults {
+    #[serde(flatten)]
+    context: RequestContext,
+    shipping_address: Option<String>,
+    billing_address: Option<String>,
+    shipping_postcode: Option<String>,
+    first_name: String,
+    last_name: String,
+    email: String,
+    phone: Option<String>,
+}
+
+impl CheckoutDefaults {
+    async fn load(
+        pool: &PgPool,
+        user_id: Uuid,
+        store_id: Uuid,
+        mut context: RequestContext,
+    ) -> Result<CheckoutDefaults, sqlx::Error> {
+        let contact = sqlx::query!(
+            "SELECT first_name, last_name, email, phone FROM users WHERE user_id = $1",
+            user_id
+        )
+        .fetch_one(pool)
+        .await?;
+
+        // pickup orders carry the location's address, not the customer's
+        let last_order = sqlx::query!(
+            "SELECT shipping_address, billing_address, shipping_country, shipping_postcode
+            FROM orders
+            WHERE user_id = $1 AND store_id = $2 AND pickup_location_id IS NULL
+            ORDER BY created_at DESC LIMIT 1",
+            user_id,
+            store_id
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        // the country they shipped to before beats the one their address is in
+        if let Some(country) = last_order
+            .as_ref()
+            .and_then(|order| order.shipping_country.clone())
+        {
+            if let Some(currency) = geoip::currency_for_country(&country) {
+                context.currency = currency.to_owned();
+            }
+            context.country = country;
+            context.detected = true;
+        }
+
+        Ok(CheckoutDefaults {
+            context,
+            shipping_address: last_order
+                .as_ref()
+                .map(|order| order.shipping_address.clone()),
+            billing_address: last_order
+                .as_ref()
+                .map(|order| order.billing_address.clone()),
+            shipping_postcode: last_order.and_then(|order| order.shipping_postcode),
+            first_name: contact.first_name,
+            last_name: contact.last_name,
+            email: contact.email,
+            phone: contact.phone,
+        })
+    }
+}
+
+// get request for the default country and currency of the caller,
+// used by the storefront before login
+#[get("api/context")]
+pub async fn get_context(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(geoip::resolve(state.geoip.as_ref(), &req))
+}
+
+// get request for what to pre-fill the checkout form with
+#[get("api/checkout/defaults")]
+pub async fn get_checkout_defaults(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            let context = geoip::resolve(state.geoip.as_ref(), &req);
+            match CheckoutDefaults::load(&state.db, user.user_id, store.store_id, context).await {
+                Ok(defaults) => HttpResponse::Ok().json(defaults),
+                Err(err) => database_error(err),
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
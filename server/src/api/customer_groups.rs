@@ -0,0 +1,559 @@
+use crate::{
+    api::{stores::Store, users::TokenClaims},
+    rate_limit::database_error,
+    AppState,
+};
+use actix_web::{
+    get, put,
+    web::{self, Json, ReqData},
+    HttpResponse, Responder,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Decimal, FromRow, PgExecutor, PgPool};
+use uuid::Uuid;
+
+#[derive(Debug, Default, Serialize, Deserialize, sqlx::Type, Clone, Copy, PartialEq, Eq, Hash)]
+#[sqlx(type_name = "customer_group", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum CustomerGroup {
+    #[default]
+    Retail,
+    Wholesale,
+    Vip,
+}
+
+// a group and the discount its members get, null when they pay full price
+#[derive(Serialize, FromRow)]
+struct GroupDiscount {
+    customer_group: CustomerGroup,
+    discount_percent: Option<Decimal>,
+}
+
+#[derive(Deserialize)]
+struct DiscountBody {
+    // null removes the discount
+    discount_percent: Option<Decimal>,
+}
+
+#[derive(Deserialize)]
+struct AssignBody {
+    customer_group: CustomerGroup,
+}
+
+// fixed price of a product for one group
+#[derive(Serialize, Deserialize, FromRow)]
+struct GroupPrice {
+    customer_group: CustomerGroup,
+    price: Decimal,
+}
+
+// the groups a product or a category is kept for, empty when it is for everyone
+#[derive(Serialize, Deserialize)]
+struct Visibility {
+    customer_groups: Vec<CustomerGroup>,
+}
+
+impl GroupDiscount {
+    // every group, with or without a discount
+    async fn get_all(pool: &PgPool) -> Result<Vec<GroupDiscount>, sqlx::Error> {
+        sqlx::query_as!(
+            GroupDiscount,
+            r#"SELECT g.customer_group as "customer_group!: CustomerGroup", d.discount_percent
+            FROM UNNEST(enum_range(NULL::customer_group)) AS g(customer_group)
+            LEFT JOIN customer_group_discounts d ON d.customer_group = g.customer_group
+            ORDER BY g.customer_group"#
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    async fn set(
+        pool: &PgPool,
+        customer_group: CustomerGroup,
+        discount_percent: Option<Decimal>,
+    ) -> Result<GroupDiscount, sqlx::Error> {
+        match discount_percent {
+            Some(percent) => {
+                if percent <= Decimal::ZERO || percent > Decimal::ONE_HUNDRED {
+                    return Err(sqlx::Error::Protocol(
+                        "Discount must be above 0 and at most 100 percent".into(),
+                    ));
+                }
+                sqlx::query!(
+                    "INSERT INTO customer_group_discounts (customer_group, discount_percent)
+                    VALUES ($1, $2)
+                    ON CONFLICT (customer_group) DO UPDATE SET discount_percent = $2",
+                    customer_group as CustomerGroup,
+                    percent
+                )
+                .execute(pool)
+                .await?;
+            }
+            None => {
+                sqlx::query!(
+                    "DELETE FROM customer_group_discounts WHERE customer_group = $1",
+                    customer_group as CustomerGroup
+                )
+                .execute(pool)
+                .await?;
+            }
+        }
+
+        Ok(GroupDiscount {
+            customer_group,
+            discount_percent,
+        })
+    }
+}
+
+impl CustomerGroup {
+    // move a customer into a group, their prices change from the next request on
+    pub async fn assign<'c>(
+        executor: impl PgExecutor<'c>,
+        user_id: Uuid,
+        customer_group: CustomerGroup,
+    ) -> Result<(), sqlx::Error> {
+        let result = sqlx::query!(
+            "UPDATE users SET customer_group = $1 WHERE user_id = $2",
+            customer_group as CustomerGroup,
+            user_id
+        )
+        .execute(executor)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        Ok(())
+    }
+}
+
+impl GroupPrice {
+    async fn get_for_product(
+        pool: &PgPool,
+        product_id: Uuid,
+    ) -> Result<Vec<GroupPrice>, sqlx::Error> {
+        sqlx::query_as!(
+            GroupPrice,
+            r#"SELECT customer_group as "customer_group: CustomerGroup", price
+            FROM group_prices WHERE product_id = $1 ORDER BY customer_group"#,
+            product_id
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    // replace the fixed group prices of a product, groups left out go back to
+    // the list price less their discount
+    async fn set_for_product(
+        pool: &PgPool,
+        product_id: Uuid,
+        prices: &[GroupPrice],
+    ) -> Result<Vec<GroupPrice>, sqlx::Error> {
+        if prices.iter().any(|price| price.price < Decimal::ZERO) {
+            return Err(sqlx::Error::Protocol(
+                "Group prices can't be negative".into(),
+            ));
+        }
+
+        let mut tx = pool.begin().await?;
+
+        sqlx::query!("DELETE FROM group_prices WHERE product_id = $1", product_id)
+            .execute(&mut *tx)
+            .await?;
+
+        for price in prices {
+            sqlx::query!(
+                "INSERT INTO group_prices (product_id, customer_group, price) VALUES ($1, $2, $3)",
+                product_id,
+                price.customer_group as CustomerGroup,
+                price.price
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        GroupPrice::get_for_product(pool, product_id).await
+    }
+}
+
+impl Visibility {
+    async fn get_for_product(
+        pool: &PgPool,
+        store_id: Uuid,
+        product_id: Uuid,
+    ) -> Result<Visibility, sqlx::Error> {
+        sqlx::query!(
+            "SELECT product_id FROM products WHERE product_id = $1 AND store_id = $2",
+            product_id,
+            store_id
+        )
+        .fetch_optional(pool)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)?;
+
+        let customer_groups = sqlx::query_scalar!(
+            r#"SELECT customer_group as "customer_group: CustomerGroup" FROM product_visibility
+            WHERE product_id = $1 ORDER BY customer_group"#,
+            product_id
+        )
+        .fetch_all(pool)
+        .await?;
+        Ok(Visibility { customer_groups })
+    }
+
+    // replace the groups a product is kept for, none opens it to everyone
+    async fn set_for_product(
+        pool: &PgPool,
+        store_id: Uuid,
+        product_id: Uuid,
+        customer_groups: &[CustomerGroup],
+    ) -> Result<Visibility, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        sqlx::query!(
+            "SELECT product_id FROM products WHERE product_id = $1 AND store_id = $2 FOR UPDATE",
+            product_id,
+            store_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)?;
+
+        sqlx::query!(
+            "DELETE FROM product_visibility WHERE product_id = $1",
+            product_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        for customer_group in customer_groups {
+            sqlx::query!(
+                "INSERT INTO product_visibility (product_id, customer_group) VALUES ($1, $2)
+                ON CONFLICT DO NOTHING",
+                product_id,
+                *customer_group as CustomerGroup
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        Visibility::get_for_product(pool, store_id, product_id).await
+    }
+
+    async fn get_for_category(
+        pool: &PgPool,
+        store_id: Uuid,
+        category: &str,
+    ) -> Result<Visibility, sqlx::Error> {
+        let customer_groups = sqlx::query_scalar!(
+            r#"SELECT customer_group as "customer_group: CustomerGroup" FROM category_visibility
+            WHERE store_id = $1 AND category = $2 ORDER BY customer_group"#,
+            store_id,
+            category
+        )
+        .fetch_all(pool)
+        .await?;
+        Ok(Visibility { customer_groups })
+    }
+
+    // replace the groups every product of the category is kept for, products
+    // added to it later are kept for them too
+    async fn set_for_category(
+        pool: &PgPool,
+        store_id: Uuid,
+        category: &str,
+        customer_groups: &[CustomerGroup],
+    ) -> Result<Visibility, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        sqlx::query!(
+            "DELETE FROM category_visibility WHERE store_id = $1 AND category = $2",
+            store_id,
+            category
+        )
+        .execute(&mut *tx)
+        .await?;
+        for customer_group in customer_groups {
+            sqlx::query!(
+                "INSERT INTO category_visibility (store_id, category, customer_group)
+                VALUES ($1, $2, $3)
+                ON CONFLICT DO NOTHING",
+                store_id,
+                category,
+                *customer_group as CustomerGroup
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        Visibility::get_for_category(pool, store_id, category).await
+    }
+}
+
+// admin only
+// get request for the customer groups and their discounts
+#[get("api/admin/customer-groups")]
+pub async fn get_customer_groups(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match GroupDiscount::get_all(&state.db).await {
+                    Ok(groups) => HttpResponse::Ok().json(groups),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("customer not allowed to view customer groups")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// put request to set or remove the discount of a customer group
+#[put("api/admin/customer-groups/{group}")]
+pub async fn set_group_discount(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+    customer_group: web::Path<CustomerGroup>,
+    body: Json<DiscountBody>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match GroupDiscount::set(&state.db, *customer_group, body.discount_percent).await {
+                    Ok(group) => {
+                        // listings show group prices
+                        state.product_listings.invalidate().await;
+                        HttpResponse::Ok().json(group)
+                    }
+                    Err(sqlx::Error::Protocol(msg)) => HttpResponse::BadRequest().json(msg),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("customer not allowed to edit customer groups")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// put request to move a customer into a group
+#[put("api/admin/users/{id}/customer-group")]
+pub async fn assign_customer_group(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+    user_id: web::Path<Uuid>,
+    body: Json<AssignBody>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match CustomerGroup::assign(&state.db, *user_id, body.customer_group).await {
+                    Ok(()) => HttpResponse::Ok().json(body.customer_group),
+                    Err(sqlx::Error::RowNotFound) => {
+                        HttpResponse::NotFound().json("user was not found")
+                    }
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("customer not allowed to edit customer groups")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// get request for the fixed group prices of a product
+#[get("api/admin/products/{id}/group-prices")]
+pub async fn get_group_prices(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+    product_id: web::Path<Uuid>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match GroupPrice::get_for_product(&state.db, *product_id).await {
+                    Ok(prices) => HttpResponse::Ok().json(prices),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("costumer cant edit product")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// put request to set the fixed group prices of a product
+#[put("api/admin/products/{id}/group-prices")]
+pub async fn set_group_prices(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+    product_id: web::Path<Uuid>,
+    body: Json<Vec<GroupPrice>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match GroupPrice::set_for_product(&state.db, *product_id, &body).await {
+                    Ok(prices) => {
+                        state.product_listings.invalidate().await;
+                        HttpResponse::Ok().json(prices)
+                    }
+                    Err(sqlx::Error::Protocol(msg)) => HttpResponse::BadRequest().json(msg),
+                    Err(sqlx::Error::Database(err)) if err.is_foreign_key_violation() => {
+                        HttpResponse::NotFound().json("product was not found")
+                    }
+                    Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+                        HttpResponse::BadRequest().json("One price per customer group")
+                    }
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("costumer cant edit product")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// get request for the customer groups a product is kept for
+#[get("api/admin/products/{id}/visibility")]
+pub async fn get_product_visibility(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    product_id: web::Path<Uuid>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match Visibility::get_for_product(&state.db, store.store_id, *product_id).await {
+                    Ok(visibility) => HttpResponse::Ok().json(visibility),
+                    Err(sqlx::Error::RowNotFound) => {
+                        HttpResponse::NotFound().json("product was not found")
+                    }
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("costumer cant edit product")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// put request to keep a product for some customer groups, like wholesale-only
+// SKUs. Other groups don't find it in listings, search or their cart and can't
+// check it out, an empty list opens it to everyone
+#[put("api/admin/products/{id}/visibility")]
+pub async fn set_product_visibility(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    product_id: web::Path<Uuid>,
+    body: Json<Visibility>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match Visibility::set_for_product(
+                    &state.db,
+                    store.store_id,
+                    *product_id,
+                    &body.customer_groups,
+                )
+                .await
+                {
+                    Ok(visibility) => {
+                        state.product_listings.invalidate().await;
+                        HttpResponse::Ok().json(visibility)
+                    }
+                    Err(sqlx::Error::RowNotFound) => {
+                        HttpResponse::NotFound().json("product was not found")
+                    }
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("costumer cant edit product")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// get request for the customer groups a category is kept for
+#[get("api/admin/categories/{category}/visibility")]
+pub async fn get_category_visibility(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    category: web::Path<String>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match Visibility::get_for_category(&state.db, store.store_id, &category).await {
+                    Ok(visibility) => HttpResponse::Ok().json(visibility),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("costumer cant edit product")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// put request to keep every product of a category for some customer groups,
+// an empty list opens the category to everyone
+#[put("api/admin/categories/{category}/visibility")]
+pub async fn set_category_visibility(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    category: web::Path<String>,
+    body: Json<Visibility>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match Visibility::set_for_category(
+                    &state.db,
+                    store.store_id,
+                    &category,
+                    &body.customer_groups,
+                )
+                .await
+                {
+                    Ok(visibility) => {
+                        state.product_listings.invalidate().await;
+                        HttpResponse::Ok().json(visibility)
+                    }
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("costumer cant edit product")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
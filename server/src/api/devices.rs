@@ -0,0 +1,190 @@
+use crate::{api::users::TokenClaims, push::Platform, rate_limit::database_error, AppState};
+use actix_web::{
+    delete, get, post, put,
+    web::{self, Json, ReqData},
+    HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+// a phone that gets the user's push notifications
+#[derive(Serialize, FromRow)]
+pub struct Device {
+    device_id: Uuid,
+    platform: Platform,
+    created_at: DateTime<Utc>,
+    last_seen_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+struct RegisterDeviceBody {
+    platform: Platform,
+    // the FCM registration token or the APNs device token
+    token: String,
+}
+
+// which push notifications the user wants, all of them until they say otherwise
+#[derive(Serialize, Deserialize, FromRow)]
+struct PushPreferences {
+    order_updates: bool,
+    back_in_stock: bool,
+}
+
+impl Device {
+    // a token moves to whoever registers it last, the app logged in as them
+    async fn register(
+        pool: &PgPool,
+        user_id: Uuid,
+        platform: Platform,
+        token: &str,
+    ) -> Result<Device, sqlx::Error> {
+        sqlx::query_as!(
+            Device,
+            r#"INSERT INTO push_devices (user_id, platform, token) VALUES ($1, $2, $3)
+            ON CONFLICT (token) DO UPDATE
+            SET user_id = EXCLUDED.user_id, platform = EXCLUDED.platform, last_seen_at = NOW()
+            RETURNING device_id, platform as "platform: Platform", created_at, last_seen_at"#,
+            user_id,
+            platform as Platform,
+            token
+        )
+        .fetch_one(pool)
+        .await
+    }
+
+    async fn get_user_devices(pool: &PgPool, user_id: Uuid) -> Result<Vec<Device>, sqlx::Error> {
+        sqlx::query_as!(
+            Device,
+            r#"SELECT device_id, platform as "platform: Platform", created_at, last_seen_at
+            FROM push_devices WHERE user_id = $1 ORDER BY last_seen_at DESC"#,
+            user_id
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    async fn remove(pool: &PgPool, device_id: Uuid, user_id: Uuid) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            "DELETE FROM push_devices WHERE device_id = $1 AND user_id = $2",
+            device_id,
+            user_id
+        )
+        .execute(pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+impl PushPreferences {
+    async fn get(pool: &PgPool, user_id: Uuid) -> Result<PushPreferences, sqlx::Error> {
+        sqlx::query_as!(
+            PushPreferences,
+            "SELECT push_order_updates as order_updates, push_back_in_stock as back_in_stock
+            FROM users WHERE user_id = $1",
+            user_id
+        )
+        .fetch_one(pool)
+        .await
+    }
+
+    async fn set(&self, pool: &PgPool, user_id: Uuid) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "UPDATE users SET push_order_updates = $2, push_back_in_stock = $3
+            WHERE user_id = $1",
+            user_id,
+            self.order_updates,
+            self.back_in_stock
+        )
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+}
+
+// post request to register the phone the app runs on for push notifications
+#[post("api/users/me/devices")]
+pub async fn register_device(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+    body: Json<RegisterDeviceBody>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            let token = body.token.trim();
+            if token.is_empty() || token.len() > 4096 {
+                return HttpResponse::BadRequest().json("token is not a device token");
+            }
+            match Device::register(&state.db, user.user_id, body.platform, token).await {
+                Ok(device) => HttpResponse::Created().json(device),
+                Err(err) => database_error(err),
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// get request for the phones getting the current user's push notifications
+#[get("api/users/me/devices")]
+pub async fn get_devices(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => match Device::get_user_devices(&state.db, user.user_id).await {
+            Ok(devices) => HttpResponse::Ok().json(devices),
+            Err(err) => database_error(err),
+        },
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// delete request to stop pushing to a phone, on logout or from the settings
+#[delete("api/users/me/devices/{id}")]
+pub async fn remove_device(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+    device_id: web::Path<Uuid>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => match Device::remove(&state.db, *device_id, user.user_id).await {
+            Ok(true) => HttpResponse::Ok().json("device removed"),
+            Ok(false) => HttpResponse::NotFound().json("device not found"),
+            Err(err) => database_error(err),
+        },
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// get request for the push notifications the current user wants
+#[get("api/users/me/push-preferences")]
+pub async fn get_push_preferences(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => match PushPreferences::get(&state.db, user.user_id).await {
+            Ok(preferences) => HttpResponse::Ok().json(preferences),
+            Err(err) => database_error(err),
+        },
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// put request to opt in or out of order updates and back in stock pushes,
+// messages already queued follow the new choice
+#[put("api/users/me/push-preferences")]
+pub async fn set_push_preferences(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+    body: Json<PushPreferences>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => match body.set(&state.db, user.user_id).await {
+            Ok(()) => HttpResponse::Ok().json(body.into_inner()),
+            Err(err) => database_error(err),
+        },
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
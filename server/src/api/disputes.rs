@@ -0,0 +1,174 @@
+use crate::{
+    api::{
+        orders::record_event,
+        stores::Store,
+        users::{Permission, TokenClaims},
+    },
+    payments::{DisputeEvent, DisputeStatus},
+    rate_limit::database_error,
+    AppState,
+};
+use actix_web::{
+    get,
+    web::{self, ReqData},
+    HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{types::Decimal, FromRow, PgPool};
+use uuid::Uuid;
+
+#[derive(Serialize, FromRow)]
+pub struct Dispute {
+    dispute_id: Uuid,
+    order_id: Uuid,
+    payment_id: Option<Uuid>,
+    provider: String,
+    provider_ref: String,
+    amount: Decimal,
+    currency: String,
+    reason: Option<String>,
+    status: DisputeStatus,
+    evidence_due_by: Option<DateTime<Utc>>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+struct DisputeQuery {
+    status: Option<DisputeStatus>,
+}
+
+impl Dispute {
+    // store or update a dispute from a provider webhook, disputes on charges
+    // we don't know are skipped
+    pub async fn record(
+        pool: &PgPool,
+        provider: &str,
+        event: DisputeEvent,
+    ) -> Result<Option<Dispute>, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        let Some(payment) = sqlx::query!(
+            "SELECT payment_id, order_id FROM payments
+            WHERE provider = $1 AND (provider_ref = $2 OR order_id = $3)
+            ORDER BY created_at DESC LIMIT 1",
+            provider,
+            event.charge_ref,
+            event.order_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Ok(None);
+        };
+
+        let existing = sqlx::query!(
+            r#"SELECT status as "status: DisputeStatus" FROM disputes
+            WHERE provider = $1 AND provider_ref = $2"#,
+            provider,
+            event.provider_ref
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let dispute = sqlx::query_as!(
+            Dispute,
+            r#"INSERT INTO disputes (
+                order_id, payment_id, provider, provider_ref, amount, currency, reason,
+                status, evidence_due_by
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            ON CONFLICT (provider, provider_ref) DO UPDATE SET
+                amount = $5, reason = $7, status = $8, evidence_due_by = $9, updated_at = NOW()
+            RETURNING dispute_id, order_id, payment_id, provider, provider_ref, amount, currency,
+                reason, status as "status: DisputeStatus", evidence_due_by, created_at, updated_at"#,
+            payment.order_id,
+            payment.payment_id,
+            provider,
+            event.provider_ref,
+            event.amount,
+            event.currency,
+            event.reason,
+            event.status as DisputeStatus,
+            event.evidence_due_by
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        // history only moves when the status does, providers resend disputes often
+        let event = match existing {
+            None => Some("dispute.opened"),
+            Some(existing) if existing.status != dispute.status => Some("dispute.updated"),
+            Some(_) => None,
+        };
+        if let Some(event) = event {
+            record_event(
+                &mut *tx,
+                dispute.order_id,
+                event,
+                json!({
+                    "dispute_id": dispute.dispute_id,
+                    "status": dispute.status,
+                    "amount": dispute.amount,
+                    "evidence_due_by": dispute.evidence_due_by,
+                }),
+            )
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(Some(dispute))
+    }
+
+    // disputes on the store's orders with the given status, the open ones
+    // soonest due first by default
+    async fn get_queue(
+        pool: &PgPool,
+        store_id: Uuid,
+        status: Option<DisputeStatus>,
+    ) -> Result<Vec<Dispute>, sqlx::Error> {
+        sqlx::query_as!(
+            Dispute,
+            r#"SELECT d.dispute_id, d.order_id, d.payment_id, d.provider, d.provider_ref,
+                d.amount, d.currency, d.reason, d.status as "status: DisputeStatus",
+                d.evidence_due_by, d.created_at, d.updated_at
+            FROM disputes d
+            JOIN orders o ON o.order_id = d.order_id
+            WHERE o.store_id = $2 AND CASE WHEN $1::dispute_status IS NULL
+                THEN d.status IN ('needs_response', 'under_review')
+                ELSE d.status = $1 END
+            ORDER BY d.evidence_due_by ASC NULLS LAST, d.created_at"#,
+            status as Option<DisputeStatus>,
+            store_id
+        )
+        .fetch_all(pool)
+        .await
+    }
+}
+
+// admin and support
+// get request for the dispute queue, open disputes unless ?status= asks for others
+#[get("api/admin/disputes")]
+pub async fn get_disputes(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    query: web::Query<DisputeQuery>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.can(Permission::ViewOrders) {
+                match Dispute::get_queue(&state.db, store.store_id, query.status).await {
+                    Ok(disputes) => HttpResponse::Ok().json(disputes),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("customer not allowed to view disputes")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
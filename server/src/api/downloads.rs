@@ -0,0 +1,218 @@
+use crate::{
+    api::{stores::Store, users::TokenClaims},
+    rate_limit::database_error,
+    storage::Storage,
+    AppState,
+};
+use actix_web::{
+    get,
+    http::header,
+    put,
+    web::{self, ReqData},
+    HttpRequest, HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+#[derive(Serialize, FromRow)]
+struct Download {
+    product_id: Uuid,
+    #[serde(skip)]
+    object_key: String,
+    file_name: String,
+    content_type: String,
+    uploaded_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+struct DownloadQuery {
+    file_name: String,
+}
+
+impl Download {
+    // store the product's file, replacing the one it had
+    async fn upload(
+        pool: &PgPool,
+        storage: &dyn Storage,
+        store_id: Uuid,
+        product_id: Uuid,
+        file_name: &str,
+        content_type: &str,
+        bytes: &[u8],
+    ) -> Result<Download, sqlx::Error> {
+        let file_name = file_name.trim();
+        if file_name.is_empty() || file_name.contains(['/', '\\', '"']) {
+            return Err(sqlx::Error::Protocol("File name is not valid".into()));
+        }
+        if bytes.is_empty() {
+            return Err(sqlx::Error::Protocol("File is empty".into()));
+        }
+
+        let previous = sqlx::query!(
+            r#"SELECT d.object_key as "object_key?" FROM products p
+            LEFT JOIN product_downloads d ON d.product_id = p.product_id
+            WHERE p.product_id = $1 AND p.store_id = $2"#,
+            product_id,
+            store_id
+        )
+        .fetch_optional(pool)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)?;
+
+        let object_key = format!("downloads/{product_id}/{}", Uuid::new_v4());
+        storage
+            .put(&object_key, bytes.to_vec(), content_type)
+            .await?;
+
+        let download = sqlx::query_as!(
+            Download,
+            "INSERT INTO product_downloads (product_id, object_key, file_name, content_type)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (product_id) DO UPDATE SET object_key = EXCLUDED.object_key,
+                file_name = EXCLUDED.file_name, content_type = EXCLUDED.content_type,
+                uploaded_at = NOW()
+            RETURNING *",
+            product_id,
+            object_key,
+            file_name,
+            content_type
+        )
+        .fetch_one(pool)
+        .await?;
+
+        if let Some(key) = previous.object_key {
+            storage.delete(&key).await?;
+        }
+        Ok(download)
+    }
+
+    // the product's file for a customer with a paid order of it in the store
+    async fn for_buyer(
+        pool: &PgPool,
+        storage: &dyn Storage,
+        store_id: Uuid,
+        product_id: Uuid,
+        user_id: Uuid,
+    ) -> Result<(Download, Vec<u8>), sqlx::Error> {
+        let download = sqlx::query_as!(
+            Download,
+            "SELECT d.* FROM product_downloads d
+            JOIN products p ON p.product_id = d.product_id
+            WHERE d.product_id = $1 AND p.store_id = $2",
+            product_id,
+            store_id
+        )
+        .fetch_optional(pool)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)?;
+
+        let bought = sqlx::query_scalar!(
+            r#"SELECT EXISTS (
+                SELECT 1 FROM order_details od
+                JOIN orders o ON o.order_id = od.order_id
+                WHERE od.product_id = $1 AND o.user_id = $2 AND o.store_id = $3
+                AND o.status IN ('confirmed', 'shipped', 'readyforpickup', 'partiallyrefunded')
+            ) as "bought!""#,
+            product_id,
+            user_id,
+            store_id
+        )
+        .fetch_one(pool)
+        .await?;
+        if !bought {
+            return Err(sqlx::Error::Protocol(
+                "Buy the product to download it".into(),
+            ));
+        }
+
+        let bytes = storage.get(&download.object_key).await?;
+        Ok((download, bytes))
+    }
+}
+
+// admin only
+// put request with the raw file as body to set what a digital product
+// delivers, ?file_name= is what customers save it as
+#[put("api/admin/products/{id}/download")]
+pub async fn upload_product_download(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    product_id: web::Path<Uuid>,
+    query: web::Query<DownloadQuery>,
+    payload: web::Payload,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                let bytes = match payload.to_bytes_limited(state.media.max_upload_bytes).await {
+                    Ok(Ok(bytes)) => bytes,
+                    Ok(Err(_)) => return HttpResponse::PayloadTooLarge().json("file is too large"),
+                    Err(err) => return HttpResponse::BadRequest().json(err.to_string()),
+                };
+                let content_type = req
+                    .headers()
+                    .get(header::CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok())
+                    .unwrap_or("application/octet-stream");
+                match Download::upload(
+                    &state.db,
+                    state.storage.as_ref(),
+                    store.store_id,
+                    *product_id,
+                    &query.file_name,
+                    content_type,
+                    &bytes,
+                )
+                .await
+                {
+                    Ok(download) => HttpResponse::Ok().json(download),
+                    Err(sqlx::Error::Protocol(msg)) => HttpResponse::BadRequest().json(msg),
+                    Err(sqlx::Error::RowNotFound) => {
+                        HttpResponse::NotFound().json("product was not found")
+                    }
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("costumer cant edit product")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// get request for the file of a digital product the customer bought
+#[get("api/products/{id}/download")]
+pub async fn download_product(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    product_id: web::Path<Uuid>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => match Download::for_buyer(
+            &state.db,
+            state.storage.as_ref(),
+            store.store_id,
+            *product_id,
+            user.user_id,
+        )
+        .await
+        {
+            Ok((download, bytes)) => HttpResponse::Ok()
+                .content_type(download.content_type)
+                .insert_header((
+                    header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"{}\"", download.file_name),
+                ))
+                .body(bytes),
+            Err(sqlx::Error::RowNotFound) => HttpResponse::NotFound().json("download not found"),
+            Err(sqlx::Error::Protocol(msg)) => HttpResponse::Forbidden().json(msg),
+            Err(err) => database_error(err),
+        },
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
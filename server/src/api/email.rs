@@ -0,0 +1,272 @@
+use crate::{api::users::TokenClaims, rate_limit::database_error, AppState};
+use actix_web::{
+    delete, get, post,
+    web::{self, Bytes, ReqData},
+    HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{FromRow, PgPool};
+use url::Url;
+
+// an address mail is no longer sent to
+#[derive(Serialize, FromRow)]
+pub struct Suppression {
+    email: String,
+    reason: String,
+    detail: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum ReportKind {
+    Bounce,
+    Complaint,
+}
+
+// a hard bounce or complaint about one address, the body of the generic
+// webhook: {"type": "bounce", "email": "...", "detail": "..."}
+#[derive(Deserialize)]
+struct DeliveryReport {
+    #[serde(rename = "type")]
+    kind: ReportKind,
+    email: String,
+    detail: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct WebhookQuery {
+    token: Option<String>,
+}
+
+// what SNS posts for the SES bounce and complaint topics
+#[derive(Deserialize)]
+struct SnsEnvelope {
+    #[serde(rename = "Type")]
+    kind: String,
+    #[serde(rename = "Message")]
+    message: String,
+    #[serde(rename = "SubscribeURL")]
+    subscribe_url: Option<String>,
+}
+
+impl ReportKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            ReportKind::Bounce => "bounce",
+            ReportKind::Complaint => "complaint",
+        }
+    }
+}
+
+impl Suppression {
+    // mark the addresses undeliverable, the first report of an address stays.
+    // Returns how many were new
+    async fn record(pool: &PgPool, reports: &[DeliveryReport]) -> Result<u64, sqlx::Error> {
+        let mut added = 0;
+        for report in reports {
+            added += sqlx::query!(
+                "INSERT INTO email_suppressions (email, reason, detail)
+                VALUES (lower($1), $2, $3) ON CONFLICT (email) DO NOTHING",
+                report.email.trim(),
+                report.kind.as_str(),
+                report.detail
+            )
+            .execute(pool)
+            .await?
+            .rows_affected();
+        }
+        Ok(added)
+    }
+
+    async fn all(pool: &PgPool) -> Result<Vec<Suppression>, sqlx::Error> {
+        sqlx::query_as!(
+            Suppression,
+            "SELECT email, reason, detail, created_at FROM email_suppressions
+            ORDER BY created_at DESC"
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    async fn remove(pool: &PgPool, email: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            "DELETE FROM email_suppressions WHERE email = lower($1)",
+            email
+        )
+        .execute(pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+// the addresses an SES notification reports undeliverable. Soft bounces
+// (mailbox full, out of office) are left alone, the next mail may arrive
+fn ses_reports(notification: &Value) -> Vec<DeliveryReport> {
+    let kind = notification["notificationType"]
+        .as_str()
+        .or(notification["eventType"].as_str())
+        .unwrap_or_default();
+    let (kind, recipients, detail_field) = match kind {
+        "Bounce" if notification["bounce"]["bounceType"] == "Permanent" => (
+            ReportKind::Bounce,
+            &notification["bounce"]["bouncedRecipients"],
+            "diagnosticCode",
+        ),
+        "Complaint" => (
+            ReportKind::Complaint,
+            &notification["complaint"]["complainedRecipients"],
+            "complaintFeedbackType",
+        ),
+        _ => return Vec::new(),
+    };
+    // bounces say why per recipient, complaints once for all of them
+    let shared_detail = notification["complaint"][detail_field].as_str();
+    recipients
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|recipient| {
+            Some(DeliveryReport {
+                kind,
+                email: recipient["emailAddress"].as_str()?.to_string(),
+                detail: recipient[detail_field]
+                    .as_str()
+                    .or(shared_detail)
+                    .map(str::to_owned),
+            })
+        })
+        .collect()
+}
+
+// the SubscribeURL of a confirmation when it points at SNS in the region the
+// topic lives in, SNS_REGION or else SES_REGION
+fn sns_subscribe_url(subscribe_url: &str) -> Option<Url> {
+    let region = std::env::var("SNS_REGION")
+        .or_else(|_| std::env::var("SES_REGION"))
+        .ok()?;
+    let url = Url::parse(subscribe_url).ok()?;
+    (url.scheme() == "https"
+        && url.username().is_empty()
+        && url.password().is_none()
+        && url.port().is_none()
+        && url.host_str() == Some(format!("sns.{region}.amazonaws.com").as_str()))
+    .then_some(url)
+}
+
+// post request from the mail provider about bounced or complained about
+// mail, authenticated with ?token=EMAIL_WEBHOOK_SECRET. "ses" takes the SNS
+// notifications of SES, "generic" one DeliveryReport for any other relay
+#[post("api/webhooks/email/{provider}")]
+pub async fn email_webhook(
+    state: web::Data<AppState>,
+    provider: web::Path<String>,
+    query: web::Query<WebhookQuery>,
+    body: Bytes,
+) -> impl Responder {
+    let Some(secret) = &state.email_webhook_secret else {
+        return HttpResponse::NotFound().json("email webhooks are not configured");
+    };
+    let token = query.token.as_deref().unwrap_or_default();
+    if token.len() != secret.len() || !openssl::memcmp::eq(token.as_bytes(), secret.as_bytes()) {
+        return HttpResponse::Unauthorized().json("invalid token");
+    }
+
+    let reports = match provider.to_lowercase().as_str() {
+        "ses" => {
+            let envelope: SnsEnvelope = match serde_json::from_slice(&body) {
+                Ok(envelope) => envelope,
+                Err(err) => return HttpResponse::BadRequest().json(err.to_string()),
+            };
+            match envelope.kind.as_str() {
+                // SNS waits for the subscription to be confirmed before posting
+                "SubscriptionConfirmation" => {
+                    let Some(url) = envelope
+                        .subscribe_url
+                        .as_deref()
+                        .and_then(sns_subscribe_url)
+                    else {
+                        return HttpResponse::BadRequest().json("not an SNS subscription");
+                    };
+                    // the confirmation is fetched from SNS itself, never from where it sends us
+                    let client = reqwest::Client::builder()
+                        .redirect(reqwest::redirect::Policy::none())
+                        .build()
+                        .expect("failed to build the HTTP client");
+                    return match client
+                        .get(url)
+                        .send()
+                        .await
+                        .and_then(|res| res.error_for_status())
+                    {
+                        Ok(_) => HttpResponse::Ok().json("subscription confirmed"),
+                        Err(err) => HttpResponse::BadGateway().json(err.to_string()),
+                    };
+                }
+                "Notification" => match serde_json::from_str(&envelope.message) {
+                    Ok(notification) => ses_reports(&notification),
+                    Err(err) => return HttpResponse::BadRequest().json(err.to_string()),
+                },
+                _ => return HttpResponse::Ok().json("ignored"),
+            }
+        }
+        "generic" => match serde_json::from_slice(&body) {
+            Ok(report) => vec![report],
+            Err(err) => return HttpResponse::BadRequest().json(err.to_string()),
+        },
+        _ => return HttpResponse::NotFound().json("unknown email provider"),
+    };
+
+    match Suppression::record(&state.db, &reports).await {
+        Ok(added) => HttpResponse::Ok().json(format!("{added} addresses marked undeliverable")),
+        Err(err) => database_error(err),
+    }
+}
+
+// admin only
+// get request for the addresses mail is no longer sent to
+#[get("api/admin/email-suppressions")]
+pub async fn get_email_suppressions(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match Suppression::all(&state.db).await {
+                    Ok(suppressions) => HttpResponse::Ok().json(suppressions),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("customer not allowed to see email suppressions")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// delete request to send mail to an address again, once its owner fixed it
+#[delete("api/admin/email-suppressions/{email}")]
+pub async fn remove_email_suppression(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+    email: web::Path<String>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match Suppression::remove(&state.db, &email).await {
+                    Ok(true) => HttpResponse::Ok().json("address is deliverable again"),
+                    Ok(false) => HttpResponse::NotFound().json("address is not suppressed"),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("customer not allowed to change email suppressions")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
@@ -0,0 +1,15 @@
+use crate::{rate_limit::database_error, AppState};
+use actix_web::{get, web, HttpResponse, Responder};
+
+// get request for the exchange rates against the shop currency, for showing
+// prices in the visitor's currency. Stale rates are still served, flagged so
+#[get("api/exchange-rates")]
+pub async fn get_exchange_rates(state: web::Data<AppState>) -> impl Responder {
+    match &state.exchange_rates {
+        Some(rates) => match rates.latest(&state.db).await {
+            Ok(table) => HttpResponse::Ok().json(table),
+            Err(err) => database_error(err),
+        },
+        None => HttpResponse::ServiceUnavailable().json("exchange rates are not configured"),
+    }
+}
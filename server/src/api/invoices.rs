@@ -0,0 +1,200 @@
+use crate::{
+    api::{
+        orders::OrderStatus,
+        stores::Store,
+        users::{Permission, TokenClaims},
+    },
+    rate_limit::database_error,
+    AppState,
+};
+use actix_web::{
+    get,
+    web::{self, ReqData},
+    HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{types::Decimal, FromRow, PgPool};
+use uuid::Uuid;
+
+// an order's invoice, billed to the billing address and built from the prices
+// frozen at checkout, so it reads the same however often it is downloaded
+#[derive(Serialize)]
+struct Invoice {
+    invoice_number: String,
+    order_id: Uuid,
+    issued_at: DateTime<Utc>,
+    seller: String,
+    customer_name: String,
+    customer_email: String,
+    billing_address: String,
+    shipping_address: String,
+    vat_number: Option<String>,
+    reverse_charge: bool,
+    currency: String,
+    lines: Vec<InvoiceLine>,
+    subtotal_amount: Option<Decimal>,
+    discount_amount: Option<Decimal>,
+    tax_amount: Option<Decimal>,
+    shipping_amount: Option<Decimal>,
+    gift_wrap_amount: Option<Decimal>,
+    total_amount: Decimal,
+}
+
+#[derive(Serialize, FromRow)]
+struct InvoiceLine {
+    product_id: Uuid,
+    description: String,
+    quantity: Decimal,
+    unit_price: Decimal,
+    tax_rate: Decimal,
+    discount_amount: Decimal,
+    line_total: Decimal,
+}
+
+impl Invoice {
+    // the invoice of an order in the store, the customer's own when a user is
+    // given. Orders waiting for or cancelled before payment have none yet
+    async fn for_order(
+        pool: &PgPool,
+        store_id: Uuid,
+        order_id: Uuid,
+        user_id: Option<Uuid>,
+        default_currency: &str,
+    ) -> Result<Invoice, sqlx::Error> {
+        let order = sqlx::query!(
+            r#"SELECT o.order_id, o.order_date, o.status as "status!: OrderStatus",
+                o.billing_address, o.shipping_address, o.vat_number, o.reverse_charge,
+                o.subtotal_amount, o.discount_amount, o.tax_amount, o.shipping_amount,
+                o.gift_wrap_amount, o.total_amount, s.name as seller,
+                u.first_name, u.last_name, u.email,
+                (SELECT currency FROM payments p WHERE p.order_id = o.order_id
+                    ORDER BY p.created_at DESC LIMIT 1) as currency
+            FROM orders o
+            JOIN stores s ON s.store_id = o.store_id
+            JOIN users u ON u.user_id = o.user_id
+            WHERE o.order_id = $1 AND o.store_id = $2
+            AND ($3::uuid IS NULL OR o.user_id = $3)"#,
+            order_id,
+            store_id,
+            user_id
+        )
+        .fetch_optional(pool)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)?;
+
+        if matches!(
+            order.status,
+            OrderStatus::PendingPayment | OrderStatus::Cancelled
+        ) {
+            return Err(sqlx::Error::Protocol(
+                "Order has no invoice until it is paid".into(),
+            ));
+        }
+
+        let lines = sqlx::query_as!(
+            InvoiceLine,
+            "SELECT od.product_id as \"product_id!\", p.name as description, od.quantity,
+                od.price_per_unit as unit_price, od.tax_rate, od.discount_amount, od.line_total
+            FROM order_details od
+            JOIN products p ON p.product_id = od.product_id
+            WHERE od.order_id = $1
+            ORDER BY p.name",
+            order_id
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(Invoice {
+            // one invoice per order, numbered after it
+            invoice_number: format!(
+                "INV-{}-{}",
+                order.order_date.format("%Y%m%d"),
+                order.order_id.simple().to_string()[..8].to_uppercase()
+            ),
+            order_id: order.order_id,
+            issued_at: order.order_date,
+            seller: order.seller,
+            customer_name: format!("{} {}", order.first_name, order.last_name),
+            customer_email: order.email,
+            billing_address: order.billing_address,
+            shipping_address: order.shipping_address,
+            vat_number: order.vat_number,
+            reverse_charge: order.reverse_charge,
+            currency: order
+                .currency
+                .unwrap_or_else(|| default_currency.to_string())
+                .to_uppercase(),
+            lines,
+            subtotal_amount: order.subtotal_amount,
+            discount_amount: order.discount_amount,
+            tax_amount: order.tax_amount,
+            shipping_amount: order.shipping_amount,
+            gift_wrap_amount: order.gift_wrap_amount,
+            total_amount: order.total_amount,
+        })
+    }
+}
+
+// get request for the invoice of one of the customer's orders
+#[get("api/orders/{id}/invoice")]
+pub async fn get_invoice(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    order_id: web::Path<Uuid>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => match Invoice::for_order(
+            &state.db,
+            store.store_id,
+            *order_id,
+            Some(user.user_id),
+            state.payments.currency().code(),
+        )
+        .await
+        {
+            Ok(invoice) => HttpResponse::Ok().json(invoice),
+            Err(sqlx::Error::RowNotFound) => HttpResponse::NotFound().json("order not found"),
+            Err(sqlx::Error::Protocol(msg)) => HttpResponse::Conflict().json(msg),
+            Err(err) => database_error(err),
+        },
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin and support
+// get request for the invoice of any order in the store
+#[get("api/admin/orders/{id}/invoice")]
+pub async fn get_admin_invoice(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    order_id: web::Path<Uuid>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.can(Permission::ViewOrders) {
+                match Invoice::for_order(
+                    &state.db,
+                    store.store_id,
+                    *order_id,
+                    None,
+                    state.payments.currency().code(),
+                )
+                .await
+                {
+                    Ok(invoice) => HttpResponse::Ok().json(invoice),
+                    Err(sqlx::Error::RowNotFound) => {
+                        HttpResponse::NotFound().json("order not found")
+                    }
+                    Err(sqlx::Error::Protocol(msg)) => HttpResponse::Conflict().json(msg),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("customer not allowed to see other invoices")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
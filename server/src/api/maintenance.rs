@@ -0,0 +1,296 @@
+use std::time::Duration;
+
+use crate::{
+    api::users::{verify_token, TokenClaims},
+    cache::{Cached, Invalidate},
+    problem,
+    rate_limit::database_error,
+    AppState,
+};
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    get,
+    http::header,
+    middleware::Next,
+    put,
+    web::{self, Json, ReqData},
+    HttpResponse, Responder,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+const SETTING: &str = "maintenance";
+
+// what customers are told when there is no message of the admin's
+const DEFAULT_MESSAGE: &str = "The store is down for maintenance, please try again shortly";
+
+// whether the store is down for maintenance. While it is only admins get
+// through, everyone else is answered with 503 and when to try again
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Maintenance {
+    enabled: bool,
+    message: Option<String>,
+    // the Retry-After customers are sent
+    retry_after_secs: u32,
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Maintenance {
+            enabled: false,
+            message: None,
+            retry_after_secs: 300,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct MaintenanceBody {
+    enabled: bool,
+    message: Option<String>,
+    retry_after_secs: Option<u32>,
+}
+
+// the maintenance mode as admins see it, forced when MAINTENANCE_MODE keeps
+// it on whatever the setting says
+#[derive(Serialize)]
+struct MaintenanceResponse {
+    #[serde(flatten)]
+    maintenance: Maintenance,
+    forced: bool,
+}
+
+// what a request turned away during maintenance answers with
+#[derive(Serialize)]
+struct MaintenanceNotice {
+    code: &'static str,
+    message: String,
+    retry_after_secs: u32,
+}
+
+// the maintenance setting, read on every request so it is cached for
+// MAINTENANCE_CACHE_TTL_SECS (default 5, 0 turns caching off) and dropped on
+// every instance when an admin changes it. MAINTENANCE_MODE=true turns it on
+// from the start, for deploys whose migrations the running instances mustn't
+// serve requests during, and keeps it on until the variable is unset
+pub struct MaintenanceSwitch {
+    forced: bool,
+    setting: Cached<(), Maintenance>,
+}
+
+impl MaintenanceSwitch {
+    pub fn from_env(pool: PgPool) -> Self {
+        let ttl = std::env::var("MAINTENANCE_CACHE_TTL_SECS")
+            .ok()
+            .map(|value| {
+                value
+                    .parse()
+                    .expect("MAINTENANCE_CACHE_TTL_SECS must be a number")
+            })
+            .unwrap_or(5);
+        MaintenanceSwitch {
+            forced: std::env::var("MAINTENANCE_MODE").is_ok_and(|value| value == "true"),
+            setting: Cached::new(SETTING, Duration::from_secs(ttl), 1).shared(pool),
+        }
+    }
+
+    // off when it was never set
+    async fn current(&self, pool: &PgPool) -> Result<Maintenance, sqlx::Error> {
+        let mut maintenance = self
+            .setting
+            .get_or_load((), Maintenance::load(pool))
+            .await?;
+        maintenance.enabled |= self.forced;
+        Ok(maintenance)
+    }
+}
+
+impl Invalidate for MaintenanceSwitch {
+    fn name(&self) -> &'static str {
+        self.setting.name()
+    }
+
+    fn clear(&self) {
+        self.setting.clear()
+    }
+}
+
+impl Maintenance {
+    async fn load(pool: &PgPool) -> Result<Maintenance, sqlx::Error> {
+        let value = sqlx::query_scalar!("SELECT value FROM settings WHERE key = $1", SETTING)
+            .fetch_optional(pool)
+            .await?;
+        match value {
+            Some(value) => {
+                serde_json::from_value(value).map_err(|err| sqlx::Error::Decode(Box::new(err)))
+            }
+            None => Ok(Maintenance::default()),
+        }
+    }
+
+    async fn save(
+        pool: &PgPool,
+        maintenance: &Maintenance,
+        admin_id: Uuid,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "INSERT INTO settings (key, value, updated_by) VALUES ($1, $2, $3)
+            ON CONFLICT (key) DO UPDATE
+                SET value = EXCLUDED.value, updated_by = EXCLUDED.updated_by, updated_at = NOW()",
+            SETTING,
+            json!(maintenance),
+            admin_id
+        )
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+}
+
+// the routes that work during maintenance for everyone: health checks and
+// metrics for the load balancer and monitoring, the key set and logging in
+// so admins can get a token
+fn always_open(path: &str) -> bool {
+    matches!(
+        path,
+        "/health" | "/metrics" | "/.well-known/jwks.json" | "/api/auth" | "/api/auth/sms"
+    )
+}
+
+// whether the request carries an admin's token, admins keep working during
+// maintenance to finish it
+async fn from_admin(state: &AppState, req: &ServiceRequest) -> bool {
+    let Some(token) = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    // nobody is let through as an admin while their session can't be checked
+    verify_token(state, token)
+        .await
+        .is_ok_and(|found| found.is_some_and(|(claims, _)| claims.is_admin()))
+}
+
+// middleware that turns away every request but admins' and the always open
+// routes while the store is down for maintenance. A setting that can't be read
+// leaves the store open, the requests fail on their own if the database is down
+pub async fn gate(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let state = req
+        .app_data::<web::Data<AppState>>()
+        .cloned()
+        .expect("app state must be registered");
+    let maintenance = match state.maintenance.current(&state.db).await {
+        Ok(maintenance) => maintenance,
+        Err(err) => {
+            println!("failed to read the maintenance setting: {err:?}");
+            Maintenance::default()
+        }
+    };
+    if !maintenance.enabled || always_open(req.path()) || from_admin(&state, &req).await {
+        return Ok(next.call(req).await?.map_into_left_body());
+    }
+
+    let mut res = HttpResponse::ServiceUnavailable()
+        .insert_header((header::RETRY_AFTER, maintenance.retry_after_secs))
+        .json(vec![MaintenanceNotice {
+            code: "maintenance",
+            message: maintenance
+                .message
+                .unwrap_or_else(|| DEFAULT_MESSAGE.to_string()),
+            retry_after_secs: maintenance.retry_after_secs,
+        }]);
+    res.extensions_mut().insert(problem::Expected);
+    Ok(req.into_response(res).map_into_right_body())
+}
+
+// get request for load balancers, 200 while the database answers, also during
+// maintenance so the instance isn't taken out of rotation
+#[get("/health")]
+pub async fn health(state: web::Data<AppState>) -> impl Responder {
+    match sqlx::query!("SELECT 1 as ok").fetch_one(&state.db).await {
+        Ok(_) => {
+            let maintenance = state
+                .maintenance
+                .current(&state.db)
+                .await
+                .is_ok_and(|maintenance| maintenance.enabled);
+            HttpResponse::Ok().json(json!({ "status": "ok", "maintenance": maintenance }))
+        }
+        Err(err) => {
+            println!("health check failed: {err:?}");
+            HttpResponse::ServiceUnavailable().json("the database can't be reached")
+        }
+    }
+}
+
+// admin only, for admins of every store
+// get request for whether the store is down for maintenance
+#[get("api/admin/maintenance")]
+pub async fn get_maintenance(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_platform_admin() {
+                match state.maintenance.current(&state.db).await {
+                    Ok(maintenance) => HttpResponse::Ok().json(MaintenanceResponse {
+                        maintenance,
+                        forced: state.maintenance.forced,
+                    }),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("only admins of every store can see maintenance")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only, for admins of every store
+// put request to take the store down for maintenance or bring it back, on
+// every instance within moments
+#[put("api/admin/maintenance")]
+pub async fn set_maintenance(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+    body: Json<MaintenanceBody>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_platform_admin() {
+                let body = body.into_inner();
+                let maintenance = Maintenance {
+                    enabled: body.enabled,
+                    message: body.message.filter(|message| !message.trim().is_empty()),
+                    retry_after_secs: body
+                        .retry_after_secs
+                        .unwrap_or(Maintenance::default().retry_after_secs),
+                };
+                match Maintenance::save(&state.db, &maintenance, user.user_id).await {
+                    Ok(()) => {
+                        state.maintenance.setting.invalidate().await;
+                        HttpResponse::Ok().json(MaintenanceResponse {
+                            maintenance,
+                            forced: state.maintenance.forced,
+                        })
+                    }
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("only admins of every store can switch maintenance")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
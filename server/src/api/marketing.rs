@@ -0,0 +1,118 @@
+use crate::{api::users::TokenClaims, client_ip::client_ip, rate_limit::database_error, AppState};
+use actix_web::{
+    put,
+    web::{self, Json, ReqData},
+    HttpRequest, HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+// notifications that advertise rather than tell the customer about their own
+// orders, only sent to customers who agreed to marketing
+pub fn is_marketing(kind: &str) -> bool {
+    matches!(kind, "stock.back")
+}
+
+// the consent a customer gave last
+#[derive(Serialize, FromRow)]
+pub struct MarketingConsent {
+    granted: bool,
+    source: String,
+    policy_version: String,
+    recorded_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+struct ConsentBody {
+    granted: bool,
+}
+
+impl MarketingConsent {
+    // record a consent given or taken back, it applies from now on
+    pub async fn record(
+        pool: &PgPool,
+        user_id: Uuid,
+        granted: bool,
+        source: &str,
+        policy_version: &str,
+        req: &HttpRequest,
+    ) -> Result<MarketingConsent, sqlx::Error> {
+        let ip_address = client_ip(req).map(|ip| ip.to_string());
+        let user_agent = req
+            .headers()
+            .get("User-Agent")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned);
+
+        let mut tx = pool.begin().await?;
+        let consent = sqlx::query_as!(
+            MarketingConsent,
+            "INSERT INTO marketing_consents
+                (user_id, granted, source, policy_version, ip_address, user_agent)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING granted, source, policy_version, created_at as recorded_at",
+            user_id,
+            granted,
+            source,
+            policy_version,
+            ip_address,
+            user_agent
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "UPDATE users SET marketing_opt_in = $2 WHERE user_id = $1",
+            user_id,
+            granted
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(consent)
+    }
+
+    // none when the customer never said
+    pub async fn current(
+        pool: &PgPool,
+        user_id: Uuid,
+    ) -> Result<Option<MarketingConsent>, sqlx::Error> {
+        sqlx::query_as!(
+            MarketingConsent,
+            "SELECT granted, source, policy_version, created_at as recorded_at
+            FROM marketing_consents WHERE user_id = $1
+            ORDER BY created_at DESC LIMIT 1",
+            user_id
+        )
+        .fetch_optional(pool)
+        .await
+    }
+}
+
+// put request to give or take back consent to marketing from the profile
+#[put("api/users/me/marketing-consent")]
+pub async fn set_marketing_consent(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+    body: Json<ConsentBody>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => match MarketingConsent::record(
+            &state.db,
+            user.user_id,
+            body.granted,
+            "profile",
+            &state.marketing_policy_version,
+            &req,
+        )
+        .await
+        {
+            Ok(consent) => HttpResponse::Ok().json(consent),
+            Err(err) => database_error(err),
+        },
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
@@ -0,0 +1,20 @@
+use actix_web::{get, web, HttpResponse, Responder};
+
+use crate::AppState;
+
+// get request for the query timings in the Prometheus text format, and the
+// age of the exchange rates when they are refreshed. Tags and counts only,
+// keep it off the public internet at the proxy all the same
+#[get("/metrics")]
+pub async fn get_metrics(state: web::Data<AppState>) -> impl Responder {
+    let mut body = state.query_stats.render();
+    if let Some(rates) = &state.exchange_rates {
+        match rates.render(&state.db).await {
+            Ok(rates) => body.push_str(&rates),
+            Err(err) => println!("exchange rate metrics failed: {err:?}"),
+        }
+    }
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body)
+}
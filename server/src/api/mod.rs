@@ -1,4 +1,50 @@
+pub mod activity;
+pub mod admin_feed;
+pub mod batch;
+pub mod blocklist;
+pub mod bundles;
+pub mod business;
 pub mod carts;
+pub mod catalog;
+pub mod context;
+pub mod customer_groups;
+pub mod devices;
+pub mod disputes;
+pub mod downloads;
+pub mod email;
+pub mod exchange_rates;
+pub mod invoices;
+pub mod maintenance;
+pub mod marketing;
+pub mod metrics;
+pub mod newsletter;
+pub mod notifications;
 pub mod orders;
+pub mod payments;
+pub mod pickup_locations;
+pub mod policies;
 pub mod products;
+pub mod questions;
+pub mod quotes;
+pub mod refunds;
+pub mod reports;
+pub mod reviews;
+pub mod sales;
+pub mod sessions;
+pub mod shipments;
+pub mod shipping_zones;
+pub mod sms;
+pub mod stock_alerts;
+pub mod stores;
 pub mod users;
+pub mod wholesale;
+
+// for patch bodies, tells a null apart from a field that isn't there, which
+// serde's default leaves as None
+pub fn nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: serde::Deserialize<'de>,
+{
+    <Option<T> as serde::Deserialize>::deserialize(deserializer).map(Some)
+}
@@ -0,0 +1,344 @@
+use crate::{
+    api::users::TokenClaims,
+    captcha,
+    client_ip::client_ip,
+    csv,
+    rate_limit::database_error,
+    storage::{hex, Storage},
+    AppState,
+};
+use actix_web::{
+    get, post,
+    web::{self, Json, ReqData},
+    HttpRequest, HttpResponse, Responder,
+};
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+// confirmation links work for two days
+const CONFIRM_TTL_HOURS: i32 = 48;
+
+#[derive(Deserialize)]
+struct SubscribeBody {
+    email: String,
+}
+
+#[derive(Deserialize)]
+struct TokenBody {
+    token: String,
+}
+
+// where a consent was given from, kept with it
+struct ConsentSource {
+    ip_address: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl ConsentSource {
+    fn of(req: &HttpRequest) -> Self {
+        ConsentSource {
+            ip_address: client_ip(req).map(|ip| ip.to_string()),
+            user_agent: req
+                .headers()
+                .get("User-Agent")
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned),
+        }
+    }
+}
+
+// two v4 uuids, 244 random bits
+fn new_token() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+fn hash_token(token: &str) -> String {
+    hex(&Sha256::digest(token.trim().as_bytes()))
+}
+
+pub struct Subscriber;
+
+impl Subscriber {
+    async fn record_consent(
+        tx: &mut Transaction<'_, Postgres>,
+        subscriber_id: Uuid,
+        action: &str,
+        source: &ConsentSource,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "INSERT INTO newsletter_consents (subscriber_id, action, ip_address, user_agent)
+            VALUES ($1, $2, $3, $4)",
+            subscriber_id,
+            action,
+            source.ip_address,
+            source.user_agent
+        )
+        .execute(&mut **tx)
+        .await?;
+        Ok(())
+    }
+
+    // start the double opt-in: a new confirmation link is mailed to the
+    // address unless it is confirmed already
+    async fn subscribe(
+        pool: &PgPool,
+        email: &str,
+        shop_url: &str,
+        source: &ConsentSource,
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        let confirmed = sqlx::query_scalar!(
+            "SELECT status = 'confirmed' as \"confirmed!\" FROM newsletter_subscribers
+            WHERE email = $1 FOR UPDATE",
+            email
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if confirmed == Some(true) {
+            return Ok(());
+        }
+
+        let token = new_token();
+        let subscriber_id = sqlx::query_scalar!(
+            "INSERT INTO newsletter_subscribers
+                (email, confirm_token_hash, confirm_expires_at, unsubscribe_token)
+            VALUES ($1, $2, NOW() + make_interval(hours => $3), $4)
+            ON CONFLICT (email) DO UPDATE SET
+                status = 'pending',
+                confirm_token_hash = EXCLUDED.confirm_token_hash,
+                confirm_expires_at = EXCLUDED.confirm_expires_at,
+                confirmed_at = NULL,
+                unsubscribed_at = NULL
+            RETURNING subscriber_id",
+            email,
+            hash_token(&token),
+            CONFIRM_TTL_HOURS,
+            new_token()
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            "INSERT INTO email_messages (recipient, kind, data)
+            VALUES ($1, 'newsletter.confirm', $2)",
+            email,
+            json!({
+                "confirm_url": format!(
+                    "{}/newsletter/confirm?token={token}",
+                    shop_url.trim_end_matches('/')
+                ),
+            })
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        Subscriber::record_consent(&mut tx, subscriber_id, "subscribe", source).await?;
+
+        tx.commit().await
+    }
+
+    // false when the link is unknown, used or expired
+    async fn confirm(
+        pool: &PgPool,
+        token: &str,
+        source: &ConsentSource,
+    ) -> Result<bool, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        let subscriber_id = sqlx::query_scalar!(
+            "UPDATE newsletter_subscribers SET status = 'confirmed', confirmed_at = NOW(),
+                confirm_token_hash = NULL, confirm_expires_at = NULL
+            WHERE confirm_token_hash = $1 AND confirm_expires_at > NOW()
+            RETURNING subscriber_id",
+            hash_token(token)
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(subscriber_id) = subscriber_id else {
+            return Ok(false);
+        };
+
+        Subscriber::record_consent(&mut tx, subscriber_id, "confirm", source).await?;
+
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    // false for unknown tokens, unsubscribing twice is fine
+    async fn unsubscribe(
+        pool: &PgPool,
+        token: &str,
+        source: &ConsentSource,
+    ) -> Result<bool, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        let subscriber = sqlx::query!(
+            "SELECT subscriber_id, status FROM newsletter_subscribers
+            WHERE unsubscribe_token = $1 FOR UPDATE",
+            token.trim()
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(subscriber) = subscriber else {
+            return Ok(false);
+        };
+
+        if subscriber.status != "unsubscribed" {
+            sqlx::query!(
+                "UPDATE newsletter_subscribers SET status = 'unsubscribed', unsubscribed_at = NOW(),
+                    confirm_token_hash = NULL, confirm_expires_at = NULL
+                WHERE subscriber_id = $1",
+                subscriber.subscriber_id
+            )
+            .execute(&mut *tx)
+            .await?;
+            Subscriber::record_consent(&mut tx, subscriber.subscriber_id, "unsubscribe", source)
+                .await?;
+        }
+
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    // the confirmed subscribers as CSV, with the consent each was confirmed
+    // under and the link that unsubscribes them. A copy of every export is kept
+    // in storage under exports/, a record of which addresses left the shop when
+    async fn export(
+        pool: &PgPool,
+        storage: &dyn Storage,
+        shop_url: &str,
+    ) -> Result<String, sqlx::Error> {
+        let subscribers = sqlx::query!(
+            r#"SELECT s.email, s.created_at, s.confirmed_at as "confirmed_at!",
+                s.unsubscribe_token, c.ip_address as "ip_address?", c.user_agent as "user_agent?"
+            FROM newsletter_subscribers s
+            LEFT JOIN LATERAL (
+                SELECT ip_address, user_agent FROM newsletter_consents
+                WHERE subscriber_id = s.subscriber_id AND action = 'confirm'
+                ORDER BY created_at DESC LIMIT 1
+            ) c ON TRUE
+            WHERE s.status = 'confirmed'
+            ORDER BY s.confirmed_at"#
+        )
+        .fetch_all(pool)
+        .await?;
+
+        let mut body = String::from(
+            "email,subscribed_at,confirmed_at,confirmed_from_ip,confirmed_from_user_agent,unsubscribe_url\n",
+        );
+        for subscriber in subscribers {
+            let fields = [
+                subscriber.email,
+                subscriber.created_at.to_rfc3339(),
+                subscriber.confirmed_at.to_rfc3339(),
+                subscriber.ip_address.unwrap_or_default(),
+                subscriber.user_agent.unwrap_or_default(),
+                format!(
+                    "{}/newsletter/unsubscribe?token={}",
+                    shop_url.trim_end_matches('/'),
+                    subscriber.unsubscribe_token
+                ),
+            ];
+            body.push_str(&csv::row(&fields));
+        }
+
+        let key = format!(
+            "exports/newsletter-subscribers-{}.csv",
+            Utc::now().format("%Y%m%dT%H%M%S%.3fZ")
+        );
+        storage
+            .put(&key, body.clone().into_bytes(), "text/csv; charset=utf-8")
+            .await?;
+        Ok(body)
+    }
+}
+
+// post request to get the newsletter, answered the same whatever the address
+// so it tells nothing about who subscribed
+#[post("api/newsletter/subscribe")]
+pub async fn subscribe_newsletter(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: Json<SubscribeBody>,
+) -> impl Responder {
+    // verify captcha when enabled
+    if let Err(response) = captcha::check_request(state.captcha.as_ref(), &req).await {
+        return response;
+    }
+
+    let email = body.email.trim().to_lowercase();
+    let valid = email.len() <= 255
+        && email
+            .split_once('@')
+            .is_some_and(|(local, domain)| !local.is_empty() && domain.contains('.'));
+    if !valid {
+        return HttpResponse::UnprocessableEntity().json("not an email address");
+    }
+
+    match Subscriber::subscribe(&state.db, &email, &state.shop_url, &ConsentSource::of(&req)).await
+    {
+        Ok(()) => HttpResponse::Accepted().json("check your inbox to confirm the subscription"),
+        Err(err) => database_error(err),
+    }
+}
+
+// post request with the token of the confirmation link
+#[post("api/newsletter/confirm")]
+pub async fn confirm_newsletter(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: Json<TokenBody>,
+) -> impl Responder {
+    match Subscriber::confirm(&state.db, &body.token, &ConsentSource::of(&req)).await {
+        Ok(true) => HttpResponse::Ok().json("subscription confirmed"),
+        Ok(false) => HttpResponse::NotFound().json("the link is invalid or expired"),
+        Err(err) => database_error(err),
+    }
+}
+
+// post request with the token of the unsubscribe link in every newsletter
+#[post("api/newsletter/unsubscribe")]
+pub async fn unsubscribe_newsletter(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: Json<TokenBody>,
+) -> impl Responder {
+    match Subscriber::unsubscribe(&state.db, &body.token, &ConsentSource::of(&req)).await {
+        Ok(true) => HttpResponse::Ok().json("unsubscribed"),
+        Ok(false) => HttpResponse::NotFound().json("the link is invalid"),
+        Err(err) => database_error(err),
+    }
+}
+
+// admin only
+// get request for the confirmed subscribers as a CSV file
+#[get("api/admin/newsletter/subscribers.csv")]
+pub async fn export_newsletter_subscribers(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match Subscriber::export(&state.db, state.storage.as_ref(), &state.shop_url).await {
+                    Ok(csv) => HttpResponse::Ok()
+                        .content_type("text/csv; charset=utf-8")
+                        .insert_header((
+                            "Content-Disposition",
+                            "attachment; filename=\"newsletter-subscribers.csv\"",
+                        ))
+                        .body(csv),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("customer not allowed to export subscribers")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
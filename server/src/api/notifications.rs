@@ -0,0 +1,100 @@
+use crate::{api::users::TokenClaims, rate_limit::database_error, AppState};
+use actix_web::{
+    get, put,
+    web::{self, ReqData},
+    HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{FromRow, PgExecutor, PgPool};
+use uuid::Uuid;
+
+#[derive(Serialize, FromRow)]
+pub struct Notification {
+    notification_id: Uuid,
+    message: String,
+    created_at: DateTime<Utc>,
+    read_at: Option<DateTime<Utc>>,
+}
+
+impl Notification {
+    // queue a message for the user's notification inbox
+    pub async fn create<'c>(
+        executor: impl PgExecutor<'c>,
+        user_id: Uuid,
+        message: &str,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "INSERT INTO notifications (user_id, message) VALUES ($1, $2)",
+            user_id,
+            message
+        )
+        .execute(executor)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_user_notifications(
+        pool: &PgPool,
+        user_id: Uuid,
+    ) -> Result<Vec<Notification>, sqlx::Error> {
+        sqlx::query_as!(
+            Notification,
+            "SELECT notification_id, message, created_at, read_at
+            FROM notifications WHERE user_id = $1 ORDER BY created_at DESC LIMIT 100",
+            user_id
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    async fn mark_read(
+        pool: &PgPool,
+        notification_id: Uuid,
+        user_id: Uuid,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            "UPDATE notifications SET read_at = COALESCE(read_at, NOW())
+            WHERE notification_id = $1 AND user_id = $2",
+            notification_id,
+            user_id
+        )
+        .execute(pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+// get request for the current user's notifications, newest first
+#[get("api/users/me/notifications")]
+pub async fn get_notifications(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => match Notification::get_user_notifications(&state.db, user.user_id).await {
+            Ok(notifications) => HttpResponse::Ok().json(notifications),
+            Err(err) => database_error(err),
+        },
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// put request to mark a notification as read
+#[put("api/users/me/notifications/{id}/read")]
+pub async fn mark_notification_read(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+    notification_id: web::Path<Uuid>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            match Notification::mark_read(&state.db, *notification_id, user.user_id).await {
+                Ok(true) => HttpResponse::Ok().json("notification marked as read"),
+                Ok(false) => HttpResponse::NotFound().json("notification not found"),
+                Err(err) => database_error(err),
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
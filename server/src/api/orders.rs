@@ -1,12 +1,44 @@
-use crate::{api::users::TokenClaims, AppState};
+use crate::{
+    api::{
+        business::VatProfile,
+        customer_groups::CustomerGroup,
+        marketing::MarketingConsent,
+        pickup_locations::PickupLocation,
+        quotes::Quote,
+        shipping_zones::ShippingZone,
+        stores::Store,
+        users::{Permission, TokenClaims},
+    },
+    csv,
+    envelope::{paginated, Page, PageQuery, Pagination},
+    fraud::{FraudChecker, FraudContext},
+    geoip,
+    limits::{self, LimitViolation, OrderLimits},
+    money::Money,
+    outbox,
+    payments::{CashOnDelivery, Charge, ChargeRequest, PaymentMethod, Payments},
+    precondition::{self, Precondition},
+    pricing::{CartLine, OrderTotals, Pricing, ShippingMethod},
+    query_stats::QueryStats,
+    rate_limit::database_error,
+    vat::ReverseCharge,
+    AppState,
+};
 use actix_web::{
-    delete, get, post, put,
-    web::{self, Json, ReqData},
-    HttpMessage, HttpResponse, Responder,
+    get, post, put,
+    web::{self, Bytes, Json, ReqData},
+    HttpRequest, HttpResponse, Responder,
 };
+use async_trait::async_trait;
 use chrono::{DateTime, Utc};
-use serde::{de::Error, Deserialize, Serialize};
-use sqlx::{types::Decimal, FromRow, PgPool};
+use futures_util::stream::{self, BoxStream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{types::Decimal, FromRow, PgConnection, PgExecutor, PgPool};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use uuid::Uuid;
 
 use super::carts::Cart;
@@ -17,173 +49,1760 @@ pub enum OrderStatus {
     Pending,
     Confirmed,
     Shipped,
+    Review,
+    ReadyForPickup,
+    PendingPayment,
+    Cancelled,
+    Refunded,
+    PartiallyRefunded,
 }
 
-#[derive(Serialize, Deserialize, sqlx::FromRow)]
-struct Order {
+impl OrderStatus {
+    // the status an admin asked for by name, none for names we don't know
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "Pending" => Some(OrderStatus::Pending),
+            "Confirmed" => Some(OrderStatus::Confirmed),
+            "Shipped" => Some(OrderStatus::Shipped),
+            "Review" => Some(OrderStatus::Review),
+            "ReadyForPickup" => Some(OrderStatus::ReadyForPickup),
+            "PendingPayment" => Some(OrderStatus::PendingPayment),
+            "Cancelled" => Some(OrderStatus::Cancelled),
+            "Refunded" => Some(OrderStatus::Refunded),
+            "PartiallyRefunded" => Some(OrderStatus::PartiallyRefunded),
+            _ => None,
+        }
+    }
+
+    // admins set any status, the warehouse only moves orders on to shipped or
+    // ready for pickup
+    fn may_be_set_by(&self, user: &TokenClaims) -> bool {
+        user.is_admin()
+            || (user.can(Permission::ShipOrders)
+                && matches!(self, OrderStatus::Shipped | OrderStatus::ReadyForPickup))
+    }
+
+    // whether the user may move an order from its current status to this one.
+    // The warehouse ships confirmed orders and ones ready for pickup, and
+    // readies confirmed ones for pickup. A cancelled order stays cancelled,
+    // its stock may have gone back already and nothing would take it out again
+    fn may_follow(&self, from: &OrderStatus, user: &TokenClaims) -> bool {
+        if matches!(from, OrderStatus::Cancelled) {
+            return false;
+        }
+        user.is_admin()
+            || (user.can(Permission::ShipOrders)
+                && matches!(
+                    (from, self),
+                    (OrderStatus::Confirmed, OrderStatus::Shipped)
+                        | (OrderStatus::Confirmed, OrderStatus::ReadyForPickup)
+                        | (OrderStatus::ReadyForPickup, OrderStatus::Shipped)
+                ))
+    }
+}
+
+// the error of a status change the user may not make from the order's status
+const STATUS_REFUSED: &str = "not allowed to change the order from its current status";
+
+// an orders row, OrderResponse is what goes out
+#[derive(sqlx::FromRow)]
+pub struct Order {
     order_id: Uuid,
     user_id: Uuid,
     order_date: DateTime<Utc>,
     status: OrderStatus,
     shipping_address: String,
+    billing_address: String,
     created_at: DateTime<Utc>,
     total_amount: Decimal,
 }
 
+#[derive(Serialize)]
+pub struct OrderResponse {
+    order_id: Uuid,
+    user_id: Uuid,
+    order_date: DateTime<Utc>,
+    status: OrderStatus,
+    shipping_address: String,
+    billing_address: String,
+    created_at: DateTime<Utc>,
+    total_amount: Decimal,
+}
+
+impl From<Order> for OrderResponse {
+    fn from(order: Order) -> Self {
+        OrderResponse {
+            order_id: order.order_id,
+            user_id: order.user_id,
+            order_date: order.order_date,
+            status: order.status,
+            shipping_address: order.shipping_address,
+            billing_address: order.billing_address,
+            created_at: order.created_at,
+            total_amount: order.total_amount,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, sqlx::FromRow)]
 struct UpdateBody {
     order_status: String,
     order_id: Uuid,
 }
 
+#[derive(Deserialize)]
+struct BulkUpdateBody {
+    order_ids: Vec<Uuid>,
+    status: OrderStatus,
+}
+
+// outcome of one order in a bulk update
+#[derive(Serialize)]
+pub struct BulkUpdateResult {
+    order_id: Uuid,
+    success: bool,
+    error: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, sqlx::FromRow)]
-struct OrderBody {
+pub struct OrderBody {
     shipping_address: String,
+    // the shipping address when left out
+    billing_address: Option<String>,
+    shipping_country: Option<String>,
+    shipping_postcode: Option<String>,
+    #[serde(default)]
+    shipping_method: ShippingMethod,
+    #[serde(default)]
+    gift: GiftOptions,
+    // required when shipping_method is pickup
+    pickup_location_id: Option<Uuid>,
+    // stripe, paypal or cod, the configured default when left out
+    payment_provider: Option<String>,
+    // order an accepted quote at its prices instead of the cart
+    quote_id: Option<Uuid>,
+    // the answer to the marketing checkbox, none when it wasn't shown
+    marketing_consent: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct PreviewBody {
+    shipping_country: Option<String>,
+    shipping_postcode: Option<String>,
+    #[serde(default)]
+    shipping_method: ShippingMethod,
+    #[serde(default)]
+    gift: GiftOptions,
+}
+
+// gift wrap and message for the order, lines can be wrapped on their own
+#[derive(Serialize, Deserialize, Default)]
+struct GiftOptions {
+    #[serde(default)]
+    wrap: bool,
+    message: Option<String>,
+    #[serde(default)]
+    lines: Vec<GiftLine>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct GiftLine {
+    product_id: Uuid,
+    #[serde(default)]
+    wrap: bool,
+    message: Option<String>,
+}
+
+// a row of the admin order list, read from order_summaries which triggers
+// keep up to date with the order, its lines and its customer
+#[derive(Serialize, FromRow)]
+pub struct OrderSummary {
+    order_id: Uuid,
+    user_id: Uuid,
+    customer_name: String,
+    customer_email: String,
+    status: OrderStatus,
+    item_count: i32,
+    total_quantity: Decimal,
+    total_amount: Decimal,
+    shipping_country: Option<String>,
+    order_date: DateTime<Utc>,
+    created_at: DateTime<Utc>,
+}
+
+// narrows the admin order listing and the export, by status and by when the
+// order was placed, from inclusive and to exclusive
+#[derive(Deserialize, Clone, Default)]
+pub struct OrderFilter {
+    status: Option<OrderStatus>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+}
+
+// orders go out this many at a time, with their lines
+const EXPORT_CHUNK: i64 = 500;
+
+// where the export got to, it continues after the last order sent
+struct ExportCursor {
+    pool: PgPool,
+    timings: Arc<QueryStats>,
+    store_id: Uuid,
+    filter: OrderFilter,
+    after: Option<(DateTime<Utc>, Uuid)>,
+    started: bool,
+    finished: bool,
+}
+
+// order with what the warehouse needs to pack it
+#[derive(Serialize)]
+pub struct AdminOrderDetail {
+    #[serde(flatten)]
+    order: OrderResponse,
+    shipping_country: Option<String>,
+    pickup_location_id: Option<Uuid>,
+    gift_wrap: bool,
+    gift_message: Option<String>,
+    vat_number: Option<String>,
+    reverse_charge: bool,
+    // the VAT validation the reverse charge was based on
+    vat_evidence: Option<serde_json::Value>,
+    // how total_amount breaks down, none for orders placed before it was kept
+    subtotal_amount: Option<Decimal>,
+    discount_amount: Option<Decimal>,
+    tax_amount: Option<Decimal>,
+    shipping_amount: Option<Decimal>,
+    gift_wrap_amount: Option<Decimal>,
+    // its ETag and Last-Modified come from this
+    updated_at: DateTime<Utc>,
+    items: Vec<AdminOrderLine>,
+    // everything that happened to the order, oldest first
+    history: Vec<OrderEvent>,
+}
+
+#[derive(Serialize, FromRow)]
+struct OrderEvent {
+    sequence: i32,
+    event: String,
+    // the status the order moved to
+    status: Option<OrderStatus>,
+    details: serde_json::Value,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, FromRow)]
+struct AdminOrderLine {
+    product_id: Option<Uuid>,
+    // as it was sold, null for a line of a product deleted before names were kept
+    product_name: Option<String>,
+    quantity: Decimal,
+    price_per_unit: Decimal,
+    tax_rate: Decimal,
+    discount_amount: Decimal,
+    // price_per_unit times quantity less the discount, as it was charged
+    line_total: Decimal,
+    // the bundle the line was sold in, returns go back through it
+    bundle_id: Option<Uuid>,
+    gift_wrap: bool,
+    gift_message: Option<String>,
+}
+
+// orders whose stored amounts no longer add up from their lines
+#[derive(Serialize)]
+pub struct PriceAudit {
+    checked: i64,
+    // placed before the breakdown was kept, there is nothing to check them against
+    unverifiable: i64,
+    mismatches: Vec<PriceMismatch>,
+}
+
+#[derive(Serialize, FromRow)]
+pub struct PriceMismatch {
+    order_id: Uuid,
+    total_amount: Decimal,
+    // what the stored breakdown adds up to
+    expected_total: Decimal,
+    // the amounts that disagree: line_total, subtotal, discount, tax or total
+    problems: Vec<String>,
+}
+
+// what checkout would charge for the current cart
+#[derive(Serialize)]
+pub struct CheckoutPreview {
+    items: Vec<PreviewLine>,
+    #[serde(flatten)]
+    totals: OrderTotals,
+    // limits that would make checkout fail as the cart is now
+    violations: Vec<LimitViolation>,
+    // the weight shipping was priced on
+    billable_weight_kg: Decimal,
+}
+
+#[derive(Serialize)]
+struct PreviewLine {
+    product_id: Option<Uuid>,
+    quantity: Decimal,
+    price_per_unit: Decimal,
+    bundle_id: Option<Uuid>,
+}
+
+// order held for review with the rules that flagged it
+#[derive(Serialize, sqlx::FromRow)]
+pub struct OrderReview {
+    order_id: Uuid,
+    user_id: Uuid,
+    total_amount: Decimal,
+    shipping_country: Option<String>,
+    risk_score: i32,
+    risk_reasons: Vec<String>,
+    created_at: DateTime<Utc>,
+}
+
+impl GiftOptions {
+    fn validate(&self) -> Result<(), sqlx::Error> {
+        let messages = std::iter::once(&self.message).chain(self.lines.iter().map(|l| &l.message));
+        for message in messages.flatten() {
+            if message.chars().count() > 500 {
+                return Err(sqlx::Error::Protocol(
+                    "Gift message must be at most 500 characters".into(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn line(&self, product_id: Option<Uuid>) -> Option<&GiftLine> {
+        self.lines
+            .iter()
+            .find(|line| Some(line.product_id) == product_id)
+    }
+
+    // wrapped order plus wrapped lines that are in the cart, each is charged the fee
+    fn wraps(&self, lines: &[CartLine]) -> usize {
+        let wrapped_lines = lines
+            .iter()
+            .filter(|line| self.line(line.product_id).is_some_and(|gift| gift.wrap))
+            .count();
+        usize::from(self.wrap) + wrapped_lines
+    }
+}
+
+// what the client needs to complete the payment
+#[derive(Serialize)]
+struct CheckoutPayment {
+    payment_id: Uuid,
+    provider: &'static str,
+    client_secret: Option<String>,
+    approval_url: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct CheckoutResponse {
+    order: OrderResponse,
+    payment: CheckoutPayment,
+}
+
+pub enum CheckoutOutcome {
+    Placed(CheckoutResponse),
+    Rejected(Vec<LimitViolation>),
+}
+
+// append an event that leaves the order's status as it is to its stream
+pub async fn record_event<'c>(
+    executor: impl PgExecutor<'c>,
+    order_id: Uuid,
+    event: &str,
+    details: serde_json::Value,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO order_events (order_id, event_type, data) VALUES ($1, $2, $3)",
+        order_id,
+        event,
+        details
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+// append an event that moves the order to a status, the orders row follows
+// from the event and can't be changed without one
+pub async fn record_status_event<'c>(
+    executor: impl PgExecutor<'c>,
+    order_id: Uuid,
+    event: &str,
+    status: OrderStatus,
+    details: serde_json::Value,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO order_events (order_id, event_type, status, data) VALUES ($1, $2, $3, $4)",
+        order_id,
+        event,
+        status as OrderStatus,
+        details
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+// put what the order took out of stock back, kits give back the components
+// they were sold with. What was released is taken off the order, so releasing
+// it again gives nothing back twice
+pub async fn release_stock(conn: &mut PgConnection, order_id: Uuid) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "WITH released AS (
+            DELETE FROM order_stock WHERE order_id = $1 RETURNING product_id, quantity
+        )
+        UPDATE products SET stock_quantity = products.stock_quantity + released.quantity
+        FROM released
+        WHERE products.product_id = released.product_id",
+        order_id
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+// take a line's quantity out of stock for the order, a kit takes it out of each of
+// its components. What was taken is kept on the order for release_stock.
+// False when anything is short, the caller's transaction then undoes the rest
+async fn reserve_stock(
+    conn: &mut PgConnection,
+    order_id: Uuid,
+    product_id: Uuid,
+    quantity: Decimal,
+) -> Result<bool, sqlx::Error> {
+    let components = sqlx::query!(
+        r#"SELECT COUNT(*) as "count!" FROM kit_components WHERE kit_id = $1"#,
+        product_id
+    )
+    .fetch_one(&mut *conn)
+    .await?
+    .count;
+
+    let reserved = if components == 0 {
+        sqlx::query!(
+            "UPDATE products SET stock_quantity = stock_quantity - $1
+            WHERE product_id = $2 AND stock_quantity >= $1",
+            quantity,
+            product_id
+        )
+        .execute(&mut *conn)
+        .await?
+        .rows_affected()
+    } else {
+        sqlx::query!(
+            "UPDATE products SET stock_quantity = products.stock_quantity - k.quantity * $1::DECIMAL
+            FROM kit_components k
+            WHERE k.kit_id = $2 AND products.product_id = k.component_id
+                AND products.stock_quantity >= k.quantity * $1::DECIMAL",
+            quantity,
+            product_id
+        )
+        .execute(&mut *conn)
+        .await?
+        .rows_affected()
+    };
+    if reserved != components.max(1) as u64 {
+        return Ok(false);
+    }
+
+    sqlx::query!(
+        "INSERT INTO order_stock (order_id, product_id, quantity)
+        SELECT $1, COALESCE(k.component_id, p.product_id), $3::DECIMAL * COALESCE(k.quantity, 1)
+        FROM products p
+        LEFT JOIN kit_components k ON k.kit_id = p.product_id
+        WHERE p.product_id = $2
+        ON CONFLICT (order_id, product_id)
+        DO UPDATE SET quantity = order_stock.quantity + EXCLUDED.quantity",
+        order_id,
+        product_id,
+        quantity
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(true)
 }
 
 impl Order {
     // Retrieve all orders from current_user
-    async fn get_all_user_orders(pool: &PgPool, user_id: Uuid) -> Result<Vec<Order>, sqlx::Error> {
-        sqlx::query_as!(
+    async fn get_all_user_orders(
+        pool: &PgPool,
+        store_id: Uuid,
+        user_id: Uuid,
+        page: &PageQuery,
+    ) -> Result<Page<Order>, sqlx::Error> {
+        let items = sqlx::query_as!(
             Order,
-            r#"SELECT order_id, user_id, order_date, status as "status!: OrderStatus", shipping_address, created_at, total_amount FROM orders WHERE user_id = $1 ORDER BY created_at DESC"#
-        , user_id)
+            r#"SELECT order_id, user_id, order_date, status as "status!: OrderStatus", shipping_address, billing_address, created_at, total_amount FROM orders WHERE user_id = $1 AND store_id = $4 ORDER BY created_at DESC, order_id LIMIT $2 OFFSET $3"#
+        , user_id, page.per_page(), page.offset(), store_id)
         .fetch_all(pool)
-        .await
+        .await?;
+        let total = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) as "count!" FROM orders WHERE user_id = $1 AND store_id = $2"#,
+            user_id,
+            store_id
+        )
+        .fetch_one(pool)
+        .await?;
+        Ok(Page { items, total })
     }
 
     // admin
-    // Retrieve all orders from the database
-    async fn get_all_orders(pool: &PgPool) -> Result<Vec<Order>, sqlx::Error> {
-        sqlx::query_as!(
-                Order,
-                r#"SELECT order_id, user_id, order_date, status as "status!: OrderStatus", shipping_address, created_at, total_amount FROM orders ORDER BY created_at DESC"#)
-            .fetch_all(pool)
-            .await
+    // Retrieve all orders from the database, newest first
+    async fn get_all_orders(
+        pool: &PgPool,
+        store_id: Uuid,
+        filter: &OrderFilter,
+        page: &PageQuery,
+    ) -> Result<Page<OrderSummary>, sqlx::Error> {
+        let items = sqlx::query_as!(
+            OrderSummary,
+            r#"SELECT order_id, user_id, customer_name, customer_email,
+                status as "status: OrderStatus", item_count, total_quantity, total_amount,
+                shipping_country, order_date, created_at
+            FROM order_summaries
+            WHERE store_id = $6
+                AND ($1::order_status IS NULL OR status = $1)
+                AND ($2::timestamptz IS NULL OR created_at >= $2)
+                AND ($3::timestamptz IS NULL OR created_at < $3)
+            ORDER BY created_at DESC, order_id LIMIT $4 OFFSET $5"#,
+            filter.status.clone() as Option<OrderStatus>,
+            filter.from,
+            filter.to,
+            page.per_page(),
+            page.offset(),
+            store_id
+        )
+        .fetch_all(pool)
+        .await?;
+        let total = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) as "count!" FROM order_summaries
+            WHERE store_id = $4
+                AND ($1::order_status IS NULL OR status = $1)
+                AND ($2::timestamptz IS NULL OR created_at >= $2)
+                AND ($3::timestamptz IS NULL OR created_at < $3)"#,
+            filter.status.clone() as Option<OrderStatus>,
+            filter.from,
+            filter.to,
+            store_id
+        )
+        .fetch_one(pool)
+        .await?;
+        Ok(Page { items, total })
+    }
+
+    // admin
+    // the next chunk of the export as CSV, one line per order line so the
+    // order columns repeat on each. Orders without lines get one line of their
+    // own. None once every order was sent
+    async fn export_chunk(cursor: &mut ExportCursor) -> Result<Option<String>, sqlx::Error> {
+        if cursor.finished {
+            return Ok(None);
+        }
+        let mut chunk = String::new();
+        if !cursor.started {
+            cursor.started = true;
+            chunk.push_str(&csv::row(&[
+                "order_id",
+                "created_at",
+                "status",
+                "customer_name",
+                "customer_email",
+                "shipping_country",
+                "vat_number",
+                "reverse_charge",
+                "order_total",
+                "product_id",
+                "product_name",
+                "quantity",
+                "unit_price",
+                "line_total",
+            ]));
+        }
+
+        let (after_created, after_order) = cursor.after.unzip();
+        let lines = cursor
+            .timings
+            .time(
+                "orders.export",
+                sqlx::query!(
+                    r#"WITH chunk AS (
+                        SELECT order_id FROM order_summaries
+                        WHERE ($1::order_status IS NULL OR status = $1)
+                            AND ($2::timestamptz IS NULL OR created_at >= $2)
+                            AND ($3::timestamptz IS NULL OR created_at < $3)
+                            AND ($4::timestamptz IS NULL OR (created_at, order_id) > ($4, $5::uuid))
+                            AND store_id = $7
+                        ORDER BY created_at, order_id LIMIT $6
+                    )
+                    SELECT s.order_id, s.created_at, s.status::text as "status!", s.customer_name,
+                        s.customer_email, s.shipping_country, o.vat_number, o.reverse_charge,
+                        s.total_amount, od.product_id as "product_id?",
+                        COALESCE(od.product_name, p.name) as "product_name?",
+                        od.quantity as "quantity?", od.price_per_unit as "price_per_unit?"
+                    FROM chunk
+                    JOIN order_summaries s ON s.order_id = chunk.order_id
+                    JOIN orders o ON o.order_id = chunk.order_id
+                    LEFT JOIN order_details od ON od.order_id = chunk.order_id
+                    LEFT JOIN products p ON p.product_id = od.product_id
+                    ORDER BY s.created_at, s.order_id, od.order_detail_id"#,
+                    cursor.filter.status.clone() as Option<OrderStatus>,
+                    cursor.filter.from,
+                    cursor.filter.to,
+                    after_created,
+                    after_order,
+                    EXPORT_CHUNK,
+                    cursor.store_id
+                )
+                .fetch_all(&cursor.pool),
+            )
+            .await?;
+
+        let mut orders = 0;
+        for line in &lines {
+            if cursor.after != Some((line.created_at, line.order_id)) {
+                cursor.after = Some((line.created_at, line.order_id));
+                orders += 1;
+            }
+            let line_total = match (line.quantity, line.price_per_unit) {
+                (Some(quantity), Some(price)) => (quantity * price).round_dp(2).to_string(),
+                _ => String::new(),
+            };
+            chunk.push_str(&csv::row(&[
+                line.order_id.to_string(),
+                line.created_at.to_rfc3339(),
+                line.status.clone(),
+                line.customer_name.clone(),
+                line.customer_email.clone(),
+                line.shipping_country.clone().unwrap_or_default(),
+                line.vat_number.clone().unwrap_or_default(),
+                line.reverse_charge.to_string(),
+                line.total_amount.to_string(),
+                line.product_id.map(|id| id.to_string()).unwrap_or_default(),
+                line.product_name.clone().unwrap_or_default(),
+                line.quantity
+                    .map(|q| q.normalize().to_string())
+                    .unwrap_or_default(),
+                line.price_per_unit
+                    .map(|p| p.to_string())
+                    .unwrap_or_default(),
+                line_total,
+            ]));
+        }
+        if orders < EXPORT_CHUNK {
+            cursor.finished = true;
+        }
+
+        // the last chunk was full and nothing came after it
+        if chunk.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(chunk))
     }
 
     // admin
     // update order status
     async fn update_order_status(
         pool: &PgPool,
+        store_id: Uuid,
         order_id: Uuid,
-        order_status: String,
+        order_status: OrderStatus,
+        user: &TokenClaims,
+        precondition: &Precondition,
     ) -> Result<(), sqlx::Error> {
-        let order = sqlx::query!("SELECT order_id FROM orders WHERE order_id = $1", order_id)
-            .fetch_optional(pool)
-            .await?;
-        if order.is_none() {
-            return Err(sqlx::Error::RowNotFound);
+        // locked so the version and status checked are the ones the status is
+        // set on
+        let mut tx = pool.begin().await?;
+        let order = sqlx::query!(
+            r#"SELECT status as "status!: OrderStatus", pickup_location_id, updated_at
+            FROM orders
+            WHERE order_id = $1 AND store_id = $2 FOR UPDATE"#,
+            order_id,
+            store_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)?;
+        precondition.check(order.updated_at)?;
+
+        if !order_status.may_follow(&order.status, user) {
+            return Err(sqlx::Error::Protocol(STATUS_REFUSED.into()));
         }
 
-        let order_status = match order_status.as_str() {
-            "Pending" => OrderStatus::Pending,
-            "Confirmed" => OrderStatus::Confirmed,
-            "Shipped" => OrderStatus::Shipped,
-            _ => OrderStatus::Pending,
-        };
+        if matches!(order_status, OrderStatus::ReadyForPickup) && order.pickup_location_id.is_none()
+        {
+            return Err(sqlx::Error::Protocol(
+                "Only pickup orders can be ready for pickup".into(),
+            ));
+        }
+
+        record_status_event(
+            &mut *tx,
+            order_id,
+            "order.status_changed",
+            order_status,
+            json!({ "admin_id": user.user_id }),
+        )
+        .await?;
+        tx.commit().await
+    }
+
+    // admin
+    // set the same status on many orders, the ones it may be set on change in
+    // one transaction and the others report why they didn't
+    async fn bulk_update_status(
+        pool: &PgPool,
+        store_id: Uuid,
+        order_ids: &[Uuid],
+        status: OrderStatus,
+        user: &TokenClaims,
+    ) -> Result<Vec<BulkUpdateResult>, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        let orders = sqlx::query!(
+            r#"SELECT order_id, status as "status!: OrderStatus", pickup_location_id
+            FROM orders
+            WHERE order_id = ANY($1) AND store_id = $2
+            ORDER BY order_id FOR UPDATE"#,
+            order_ids,
+            store_id
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let mut changed: Vec<Uuid> = Vec::new();
+        let results = order_ids
+            .iter()
+            .map(|order_id| {
+                let order = orders.iter().find(|order| order.order_id == *order_id);
+                // ready for pickup only applies to pickup orders
+                let error = match order {
+                    None => Some("order not found"),
+                    Some(order) if !status.may_follow(&order.status, user) => Some(STATUS_REFUSED),
+                    Some(order)
+                        if matches!(status, OrderStatus::ReadyForPickup)
+                            && order.pickup_location_id.is_none() =>
+                    {
+                        Some("not a pickup order")
+                    }
+                    Some(_) => {
+                        if !changed.contains(order_id) {
+                            changed.push(*order_id);
+                        }
+                        None
+                    }
+                };
+                BulkUpdateResult {
+                    order_id: *order_id,
+                    success: error.is_none(),
+                    error: error.map(str::to_string),
+                }
+            })
+            .collect();
 
         sqlx::query!(
-            "UPDATE orders SET status = $1 WHERE order_id = $2",
-            order_status as OrderStatus,
+            "INSERT INTO order_events (order_id, event_type, status, data)
+            SELECT order_id, 'order.status_changed', $1, jsonb_build_object('admin_id', $3::uuid)
+            FROM UNNEST($2::uuid[]) AS changed(order_id)",
+            status as OrderStatus,
+            &changed,
+            user.user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(results)
+    }
+
+    // admin
+    // orders held by the fraud check, riskiest first
+    async fn get_review_orders(
+        pool: &PgPool,
+        store_id: Uuid,
+    ) -> Result<Vec<OrderReview>, sqlx::Error> {
+        sqlx::query_as!(
+            OrderReview,
+            "SELECT order_id, user_id, total_amount, shipping_country, risk_score, risk_reasons, created_at
+            FROM orders WHERE status = 'review' AND store_id = $1
+            ORDER BY risk_score DESC, created_at",
+            store_id
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    // admin
+    // one order with its lines and gift instructions
+    async fn get_admin_order(
+        pool: &PgPool,
+        store_id: Uuid,
+        order_id: Uuid,
+    ) -> Result<AdminOrderDetail, sqlx::Error> {
+        let row = sqlx::query!(
+            r#"SELECT order_id, user_id, order_date, status as "status!: OrderStatus",
+                shipping_address, billing_address, created_at, total_amount, shipping_country,
+                pickup_location_id, gift_wrap, gift_message, vat_number, reverse_charge,
+                vat_evidence, subtotal_amount, discount_amount, tax_amount, shipping_amount,
+                gift_wrap_amount, updated_at
+            FROM orders WHERE order_id = $1 AND store_id = $2"#,
+            order_id,
+            store_id
+        )
+        .fetch_optional(pool)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)?;
+
+        let items = sqlx::query_as!(
+            AdminOrderLine,
+            "SELECT od.product_id, COALESCE(od.product_name, p.name) as product_name,
+                od.quantity, od.price_per_unit, od.tax_rate, od.discount_amount, od.line_total,
+                od.bundle_id, od.gift_wrap, od.gift_message
+            FROM order_details od
+            LEFT JOIN products p ON od.product_id = p.product_id
+            WHERE od.order_id = $1",
             order_id
         )
-        .execute(pool)
+        .fetch_all(pool)
         .await?;
 
-        Ok(())
+        let history = sqlx::query_as!(
+            OrderEvent,
+            r#"SELECT sequence, event_type as event, status as "status: OrderStatus",
+                data as details, created_at
+            FROM order_events WHERE order_id = $1 ORDER BY sequence"#,
+            order_id
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(AdminOrderDetail {
+            order: OrderResponse {
+                order_id: row.order_id,
+                user_id: row.user_id,
+                order_date: row.order_date,
+                status: row.status,
+                shipping_address: row.shipping_address,
+                billing_address: row.billing_address,
+                created_at: row.created_at,
+                total_amount: row.total_amount,
+            },
+            shipping_country: row.shipping_country,
+            pickup_location_id: row.pickup_location_id,
+            gift_wrap: row.gift_wrap,
+            gift_message: row.gift_message,
+            vat_number: row.vat_number,
+            reverse_charge: row.reverse_charge,
+            vat_evidence: row.vat_evidence,
+            subtotal_amount: row.subtotal_amount,
+            discount_amount: row.discount_amount,
+            tax_amount: row.tax_amount,
+            shipping_amount: row.shipping_amount,
+            gift_wrap_amount: row.gift_wrap_amount,
+            updated_at: row.updated_at,
+            items,
+            history,
+        })
     }
 
-    // Create order
-    async fn create_order(
+    // admin
+    // recompute every matching order from its price snapshots, each line from
+    // its unit price and quantity and the order from its lines. Nothing is read
+    // from the products so later price edits can't show up here
+    async fn price_audit(
         pool: &PgPool,
-        shipping_address: String, // Fixed spelling
+        store_id: Uuid,
+        filter: &OrderFilter,
+    ) -> Result<PriceAudit, sqlx::Error> {
+        let mismatches = sqlx::query_as!(
+            PriceMismatch,
+            r#"WITH lines AS (
+                SELECT order_id,
+                    BOOL_AND(line_total = ROUND(price_per_unit * quantity, 2) - discount_amount)
+                        as lines_ok,
+                    SUM(line_total) as lines_total,
+                    SUM(discount_amount) as discount,
+                    ROUND(SUM(line_total * tax_rate), 2) as tax
+                FROM order_details GROUP BY order_id
+            ), audited AS (
+                SELECT o.order_id, o.total_amount,
+                    o.subtotal_amount - o.discount_amount + o.tax_amount + o.shipping_amount
+                        + o.gift_wrap_amount as expected_total,
+                    ARRAY_REMOVE(ARRAY[
+                        CASE WHEN NOT l.lines_ok THEN 'line_total' END,
+                        CASE WHEN l.lines_total IS DISTINCT FROM o.subtotal_amount - o.discount_amount
+                            THEN 'subtotal' END,
+                        CASE WHEN l.discount IS DISTINCT FROM o.discount_amount THEN 'discount' END,
+                        CASE WHEN l.tax IS DISTINCT FROM o.tax_amount THEN 'tax' END,
+                        CASE WHEN o.subtotal_amount - o.discount_amount + o.tax_amount
+                            + o.shipping_amount + o.gift_wrap_amount <> o.total_amount
+                            THEN 'total' END
+                    ], NULL) as problems
+                FROM orders o
+                LEFT JOIN lines l ON l.order_id = o.order_id
+                WHERE o.subtotal_amount IS NOT NULL AND o.store_id = $4
+                    AND ($1::order_status IS NULL OR o.status = $1)
+                    AND ($2::timestamptz IS NULL OR o.created_at >= $2)
+                    AND ($3::timestamptz IS NULL OR o.created_at < $3)
+            )
+            SELECT order_id, total_amount, expected_total as "expected_total!",
+                problems as "problems!"
+            FROM audited WHERE CARDINALITY(problems) > 0
+            ORDER BY order_id"#,
+            filter.status.clone() as Option<OrderStatus>,
+            filter.from,
+            filter.to,
+            store_id
+        )
+        .fetch_all(pool)
+        .await?;
+        let counts = sqlx::query!(
+            r#"SELECT COUNT(*) FILTER (WHERE subtotal_amount IS NOT NULL) as "checked!",
+                COUNT(*) FILTER (WHERE subtotal_amount IS NULL) as "unverifiable!"
+            FROM orders
+            WHERE store_id = $4
+                AND ($1::order_status IS NULL OR status = $1)
+                AND ($2::timestamptz IS NULL OR created_at >= $2)
+                AND ($3::timestamptz IS NULL OR created_at < $3)"#,
+            filter.status.clone() as Option<OrderStatus>,
+            filter.from,
+            filter.to,
+            store_id
+        )
+        .fetch_one(pool)
+        .await?;
+        Ok(PriceAudit {
+            checked: counts.checked,
+            unverifiable: counts.unverifiable,
+            mismatches,
+        })
+    }
+
+    // the user's cart in the store and its lines at current prices, errors
+    // when there is nothing to order, also when no cart was ever started
+    async fn cart_lines(
+        conn: &mut PgConnection,
+        store_id: Uuid,
         user_id: Uuid,
-    ) -> Result<Order, sqlx::Error> {
-        let mut tx = pool.begin().await?;
+        group: Option<CustomerGroup>,
+    ) -> Result<(Uuid, Vec<CartLine>), sqlx::Error> {
+        let cart = sqlx::query_as!(
+            Cart,
+            "SELECT * FROM carts WHERE user_id = $1 AND store_id = $2 AND is_active",
+            user_id,
+            store_id
+        )
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or_else(|| sqlx::Error::Protocol("Cart is empty".into()))?;
+
+        let lines = sqlx::query_as!(
+            CartLine,
+            r#"SELECT ci.product_id, ci.quantity,
+                CASE WHEN ci.bundle_id IS NULL THEN unit_price(ci.product_id, ci.quantity, $2)
+                    ELSE bundle_unit_price(ci.bundle_id, ci.product_id) END as "price!",
+                ci.bundle_id
+            FROM cart_items ci
+            WHERE cart_id = $1 AND NOT ci.saved_for_later AND ci.product_id IS NOT NULL"#,
+            cart.cart_id,
+            user_id
+        )
+        .fetch_all(&mut *conn)
+        .await?;
 
-        // Check if cart exists and has items
-        let cart = sqlx::query_as!(Cart, "SELECT * FROM carts WHERE user_id = $1", user_id)
-            .fetch_optional(&mut *tx)
+        if lines.is_empty() {
+            return Err(sqlx::Error::Protocol("Cart is empty".into()));
+        }
+
+        // products can be kept from the group after they went in the cart
+        let hidden = sqlx::query_scalar!(
+            "SELECT p.name FROM cart_items ci
+            JOIN products p ON p.product_id = ci.product_id
+            WHERE ci.cart_id = $1 AND NOT ci.saved_for_later AND NOT visible_to(p.product_id, $2)
+            ORDER BY p.name LIMIT 1",
+            cart.cart_id,
+            group as Option<CustomerGroup>
+        )
+        .fetch_optional(&mut *conn)
+        .await?;
+        if let Some(name) = hidden {
+            return Err(sqlx::Error::Protocol(format!(
+                "{name} is not sold to your customer group"
+            )));
+        }
+
+        Ok((cart.cart_id, lines))
+    }
+
+    // price the cart the same way create_order does, without placing anything
+    #[allow(clippy::too_many_arguments)]
+    async fn preview(
+        pool: &PgPool,
+        limits: &OrderLimits,
+        pricing: &Pricing,
+        reverse_charge: Option<&ReverseCharge>,
+        body: PreviewBody,
+        store_id: Uuid,
+        user_id: Uuid,
+        group: Option<CustomerGroup>,
+    ) -> Result<CheckoutPreview, sqlx::Error> {
+        let mut conn = pool.acquire().await?;
+        let (_, lines) = Order::cart_lines(&mut conn, store_id, user_id, group).await?;
+        body.gift.validate()?;
+        let parcel = ShippingZone::parcel_for(&mut *conn, &lines).await?;
+        let shipping = ShippingZone::rate_for(
+            &mut conn,
+            store_id,
+            pricing,
+            &parcel,
+            body.shipping_method,
+            body.shipping_country.as_deref(),
+            body.shipping_postcode.as_deref(),
+        )
+        .await?;
+        let mut totals = pricing.totals(&lines, shipping, body.gift.wraps(&lines));
+        // the stored validation is enough for a preview, checkout checks again
+        if VatProfile::reverse_charge_number(pool, reverse_charge, user_id)
+            .await?
+            .is_some()
+        {
+            totals = totals.reverse_charged();
+        }
+
+        let mut violations = limits.check_order(&lines, totals.subtotal.amount());
+        let product_ids: Vec<Uuid> = lines.iter().filter_map(|line| line.product_id).collect();
+        violations.extend(limits::check_ages(&mut *conn, user_id, &product_ids).await?);
+        violations.extend(
+            limits::check_purchase_limits(&mut *conn, user_id, &limits::quantities(&lines)).await?,
+        );
+
+        Ok(CheckoutPreview {
+            violations,
+            billable_weight_kg: pricing.billable_weight(&parcel),
+            totals,
+            items: lines
+                .into_iter()
+                .map(|line| PreviewLine {
+                    product_id: line.product_id,
+                    quantity: line.quantity,
+                    price_per_unit: line.price,
+                    bundle_id: line.bundle_id,
+                })
+                .collect(),
+        })
+    }
+
+    // put back an order whose charge couldn't be opened: its stock is released,
+    // its lines go back in the cart or its quote is open again, and the order
+    // is cancelled
+    async fn abandon(
+        pool: &PgPool,
+        order_id: Uuid,
+        cart_id: Option<Uuid>,
+        error: &str,
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        release_stock(&mut tx, order_id).await?;
+        if let Some(cart_id) = cart_id {
+            sqlx::query!(
+                "INSERT INTO cart_items (cart_id, product_id, quantity, bundle_id)
+                SELECT $2, product_id, quantity, bundle_id FROM order_details
+                WHERE order_id = $1",
+                order_id,
+                cart_id
+            )
+            .execute(&mut *tx)
             .await?;
+            sqlx::query!(
+                "UPDATE carts SET updated_at = NOW() WHERE cart_id = $1",
+                cart_id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        Quote::reopen(&mut tx, order_id).await?;
+        record_status_event(
+            &mut *tx,
+            order_id,
+            "order.charge_failed",
+            OrderStatus::Cancelled,
+            json!({ "error": error }),
+        )
+        .await?;
+        tx.commit().await
+    }
 
-        let cart = cart.ok_or(sqlx::Error::RowNotFound)?;
+    // Create order, it waits in PendingPayment with its stock reserved until
+    // the payment succeeds or payment_expires_at passes. Cash on delivery
+    // orders skip the wait and confirm straight away
+    #[allow(clippy::too_many_arguments)]
+    async fn create_order(
+        pool: &PgPool,
+        payments: &Payments,
+        fraud: &FraudChecker,
+        limits: &OrderLimits,
+        pricing: &Pricing,
+        reverse_charge: Option<&ReverseCharge>,
+        body: OrderBody,
+        ip_country: Option<String>,
+        store_id: Uuid,
+        user_id: Uuid,
+        group: Option<CustomerGroup>,
+        charge_time: &mut Duration,
+    ) -> Result<CheckoutOutcome, sqlx::Error> {
+        let method = payments
+            .select(body.payment_provider.as_deref())
+            .ok_or_else(|| sqlx::Error::Protocol("Payment provider is not available".into()))?;
 
-        // Get cart items
-        let cart_items = sqlx::query!(
-            r#"SELECT ci.*, p.price 
-            FROM cart_items ci 
-            JOIN products p ON ci.product_id = p.product_id 
-            WHERE cart_id = $1"#,
-            cart.cart_id
+        // Pickup orders go to an active location, its address is the order address
+        let (shipping_address, pickup_location_id) = match body.shipping_method {
+            ShippingMethod::Pickup => {
+                let location_id = body
+                    .pickup_location_id
+                    .ok_or_else(|| sqlx::Error::Protocol("Pickup location is required".into()))?;
+                let address = PickupLocation::active_address(pool, store_id, location_id)
+                    .await?
+                    .ok_or_else(|| {
+                        sqlx::Error::Protocol("Pickup location is not available".into())
+                    })?;
+                (address, Some(location_id))
+            }
+            _ => (body.shipping_address.clone(), None),
+        };
+        // for pickup too, the customer's own address rather than the store's
+        let billing_address = body
+            .billing_address
+            .clone()
+            .filter(|address| !address.trim().is_empty())
+            .unwrap_or_else(|| body.shipping_address.clone());
+
+        // Reverse charge needs a number that is valid today, the check is kept as evidence.
+        // When the validator can't be reached the order is charged VAT as usual
+        let vat_number = VatProfile::reverse_charge_number(pool, reverse_charge, user_id).await?;
+        let mut vat_evidence = None;
+        let mut reverse_charged = false;
+        if let (Some(reverse_charge), Some(number)) = (reverse_charge, &vat_number) {
+            vat_evidence = Some(match reverse_charge.validate(number).await {
+                Ok(check) => {
+                    reverse_charged = check.valid;
+                    json!(check)
+                }
+                Err(err) => json!({ "vat_number": number, "error": err.to_string() }),
+            });
+        }
+
+        let mut tx = pool.begin().await?;
+        // one checkout per buyer at a time, purchase limits count the orders
+        // placed before this one
+        limits::lock_buyer(&mut *tx, user_id).await?;
+
+        let (cart_id, cart_items) = match body.quote_id {
+            Some(quote_id) => (
+                None,
+                Quote::order_lines(&mut tx, store_id, user_id, quote_id).await?,
+            ),
+            None => {
+                let (cart_id, lines) = Order::cart_lines(&mut tx, store_id, user_id, group).await?;
+                (Some(cart_id), lines)
+            }
+        };
+        body.gift.validate()?;
+
+        // The method has to ship to the address, its zone prices the parcel
+        let parcel = ShippingZone::parcel_for(&mut *tx, &cart_items).await?;
+        let shipping = ShippingZone::rate_for(
+            &mut tx,
+            store_id,
+            pricing,
+            &parcel,
+            body.shipping_method,
+            body.shipping_country.as_deref(),
+            body.shipping_postcode.as_deref(),
         )
-        .fetch_all(&mut *tx)
         .await?;
+        let mut totals = pricing.totals(&cart_items, shipping, body.gift.wraps(&cart_items));
+        if reverse_charged {
+            totals = totals.reverse_charged();
+        }
+        let total_amount = totals.total.amount();
+        let shipping_country = body.shipping_country.map(|c| c.trim().to_uppercase());
 
-        if cart_items.is_empty() {
-            return Err(sqlx::Error::Protocol("Cart is empty".into()));
+        if let PaymentMethod::CashOnDelivery(cod) = &method {
+            cod.check(total_amount, shipping_country.as_deref())
+                .map_err(sqlx::Error::Protocol)?;
         }
 
-        // Calculate total
-        let total_amount: Decimal = cart_items
+        // Enforce order constraints, the age of whoever buys age restricted
+        // products and how much of a limited edition they bought before
+        let mut violations = limits.check_order(&cart_items, totals.subtotal.amount());
+        let product_ids: Vec<Uuid> = cart_items
             .iter()
-            .map(|item| item.price * Decimal::from(item.quantity))
-            .sum();
+            .filter_map(|line| line.product_id)
+            .collect();
+        violations.extend(limits::check_ages(&mut *tx, user_id, &product_ids).await?);
+        violations.extend(
+            limits::check_purchase_limits(&mut *tx, user_id, &limits::quantities(&cart_items))
+                .await?,
+        );
+        if !violations.is_empty() {
+            return Ok(CheckoutOutcome::Rejected(violations));
+        }
+
+        let history = sqlx::query!(
+            r#"SELECT COUNT(*) as "previous_orders!",
+                COUNT(*) FILTER (WHERE created_at > NOW() - INTERVAL '1 hour') as "orders_last_hour!",
+                COALESCE(SUM(total_amount) FILTER (WHERE created_at > NOW() - INTERVAL '1 day'), 0)
+                    as "value_last_day!"
+            FROM orders WHERE user_id = $1"#,
+            user_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        // Enforce per-user velocity limits
+        if let Some(max) = limits.max_orders_per_hour {
+            if history.orders_last_hour >= max {
+                return Err(sqlx::Error::Protocol(format!(
+                    "Order limit exceeded: at most {max} orders per hour"
+                )));
+            }
+        }
+        if let Some(max) = limits.max_value_per_day {
+            if history.value_last_day + total_amount > max {
+                return Err(sqlx::Error::Protocol(format!(
+                    "Order limit exceeded: at most {max} in orders per day"
+                )));
+            }
+        }
 
-        // Create order
+        // Score the order, risky ones wait for an admin once paid instead of being confirmed
+        let assessment = fraud.assess(&FraudContext {
+            order_total: total_amount,
+            previous_orders: history.previous_orders,
+            orders_last_hour: history.orders_last_hour,
+            ip_country,
+            shipping_country: shipping_country.clone(),
+        });
+
+        // nothing to wait for when the courier collects the money
+        let (status, expires_in) = match method {
+            PaymentMethod::Online(_) => (
+                OrderStatus::PendingPayment,
+                Some(payments.timeout_minutes()),
+            ),
+            PaymentMethod::CashOnDelivery(_) if assessment.needs_review => {
+                (OrderStatus::Review, None)
+            }
+            PaymentMethod::CashOnDelivery(_) => (OrderStatus::Confirmed, None),
+        };
+
+        // Create order, ids are time ordered so new orders land at the end of the
+        // index like they do in created_at
         let order = sqlx::query_as!(
             Order,
             r#"INSERT INTO orders (
+                order_id,
                 user_id, 
                 total_amount, 
                 status, 
                 shipping_address,
-                order_date
+                order_date,
+                shipping_country,
+                risk_score,
+                risk_reasons,
+                gift_wrap,
+                gift_message,
+                pickup_location_id,
+                shipping_postcode,
+                hold_for_review,
+                payment_expires_at,
+                cash_on_delivery,
+                billing_address,
+                vat_number,
+                reverse_charge,
+                vat_evidence,
+                subtotal_amount,
+                discount_amount,
+                tax_amount,
+                shipping_amount,
+                gift_wrap_amount,
+                store_id
+            )
+            VALUES (
+                $24, $1, $2, $3, $4, NOW(), $5, $6, $7, $8, $9, $10, $11, $12,
+                NOW() + make_interval(mins => $13), $14, $15, $16, $17, $18,
+                $19, $20, $21, $22, $23, $25
             )
-            VALUES ($1, $2, $3, $4, NOW())
             RETURNING 
                 order_id, 
                 user_id, 
                 order_date, 
                 status as "status!: OrderStatus",
                 shipping_address,
+                billing_address,
                 created_at,
                 total_amount"#,
             user_id,
             total_amount,
-            OrderStatus::Pending as OrderStatus,
-            shipping_address
+            status as OrderStatus,
+            shipping_address,
+            shipping_country,
+            assessment.score,
+            &assessment.reasons,
+            body.gift.wrap,
+            body.gift.message,
+            pickup_location_id,
+            body.shipping_postcode.map(|p| p.trim().to_uppercase()),
+            assessment.needs_review,
+            expires_in,
+            matches!(method, PaymentMethod::CashOnDelivery(_)),
+            billing_address,
+            vat_number,
+            reverse_charged,
+            vat_evidence,
+            totals.subtotal.amount(),
+            totals.discount.amount(),
+            totals.tax.amount(),
+            totals.shipping.amount(),
+            totals.gift_wrap.amount(),
+            Uuid::now_v7(),
+            store_id
         )
         .fetch_one(&mut *tx)
         .await?;
 
-        // Create order items
+        // Create order items, reserving their stock. Each keeps the price and tax
+        // rate it was sold at, later price edits leave the order as it was
         for item in cart_items {
+            let reserved = reserve_stock(
+                &mut tx,
+                order.order_id,
+                item.product_id.unwrap_or_default(),
+                item.quantity,
+            )
+            .await?;
+            if !reserved {
+                return Err(sqlx::Error::Protocol(format!(
+                    "Not enough stock for product {}",
+                    item.product_id.unwrap_or_default()
+                )));
+            }
+
+            let gift = body.gift.line(item.product_id);
             sqlx::query!(
                 "INSERT INTO order_details (
+                    order_detail_id,
                     order_id, 
                     product_id, 
                     quantity, 
-                    price_per_unit
+                    price_per_unit,
+                    gift_wrap,
+                    gift_message,
+                    bundle_id,
+                    tax_rate,
+                    line_total,
+                    product_name
                 )
-                VALUES ($1, $2, $3, $4)",
+                VALUES (
+                    $10, $1, $2, $3, $4, $5, $6, $7, $8, $9,
+                    (SELECT name FROM products WHERE product_id = $2)
+                )",
                 order.order_id,
                 item.product_id,
                 item.quantity,
-                item.price
+                item.price,
+                gift.is_some_and(|gift| gift.wrap),
+                gift.and_then(|gift| gift.message.clone()),
+                item.bundle_id,
+                totals.tax_rate,
+                pricing.line_total(item.price, item.quantity).amount(),
+                Uuid::now_v7()
             )
             .execute(&mut *tx)
             .await?;
         }
 
-        // Clear cart
-        sqlx::query!("DELETE FROM cart_items WHERE cart_id = $1", cart.cart_id)
+        // Clear cart, or close the quote the order came from
+        if let Some(cart_id) = cart_id {
+            sqlx::query!(
+                "DELETE FROM cart_items WHERE cart_id = $1 AND NOT saved_for_later",
+                cart_id
+            )
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query!(
+                "UPDATE carts SET updated_at = NOW() WHERE cart_id = $1",
+                cart_id
+            )
             .execute(&mut *tx)
             .await?;
+        }
+        if let Some(quote_id) = body.quote_id {
+            Quote::mark_ordered(&mut tx, quote_id, order.order_id).await?;
+        }
+
+        record_status_event(
+            &mut *tx,
+            order.order_id,
+            "order.created",
+            order.status.clone(),
+            json!({ "total_amount": order.total_amount }),
+        )
+        .await?;
+        outbox::record(
+            &mut *tx,
+            "order.created",
+            order.order_id,
+            json!({
+                "order_id": order.order_id,
+                "user_id": order.user_id,
+                "status": order.status,
+                "total_amount": order.total_amount,
+                "currency": payments.currency(),
+                "created_at": order.created_at,
+            }),
+        )
+        .await?;
 
         tx.commit().await?;
 
-        Ok(order)
+        // Open the payment once the order and its stock are committed, so a
+        // slow provider holds no locks. When it fails the order is put back.
+        // Cash on delivery gets a pending payment row that is settled once collected
+        let (provider, charge) = match method {
+            PaymentMethod::Online(provider) => {
+                let started = Instant::now();
+                let charge = provider
+                    .create_charge(&ChargeRequest {
+                        amount: Money::new(order.total_amount, payments.currency()),
+                        order_id: order.order_id,
+                        billing_address: &order.billing_address,
+                        shipping_address: &order.shipping_address,
+                        shipping_country: shipping_country.as_deref(),
+                    })
+                    .await;
+                *charge_time = started.elapsed();
+                match charge {
+                    Ok(charge) => (provider.name(), charge),
+                    Err(err) => {
+                        let error = format!("Payment provider error: {err}");
+                        Order::abandon(pool, order.order_id, cart_id, &error).await?;
+                        return Err(sqlx::Error::Protocol(error));
+                    }
+                }
+            }
+            PaymentMethod::CashOnDelivery(_) => (
+                CashOnDelivery::NAME,
+                Charge {
+                    provider_ref: order.order_id.to_string(),
+                    client_secret: None,
+                    approval_url: None,
+                },
+            ),
+        };
+        let currency = payments.currency();
+        let payment = sqlx::query!(
+            "INSERT INTO payments (order_id, provider, provider_ref, amount, currency)
+            VALUES ($1, $2, $3, $4, $5) RETURNING payment_id",
+            order.order_id,
+            provider,
+            charge.provider_ref,
+            order.total_amount,
+            currency.code()
+        )
+        .fetch_one(pool)
+        .await?;
+
+        Ok(CheckoutOutcome::Placed(CheckoutResponse {
+            order: order.into(),
+            payment: CheckoutPayment {
+                payment_id: payment.payment_id,
+                provider,
+                client_secret: charge.client_secret,
+                approval_url: charge.approval_url,
+            },
+        }))
+    }
+}
+
+// data access for orders and checkout, handlers use AppState::orders so a fake
+// can stand in for Postgres in tests
+#[async_trait]
+pub trait OrderRepo: Send + Sync {
+    async fn for_user(
+        &self,
+        store_id: Uuid,
+        user_id: Uuid,
+        page: &PageQuery,
+    ) -> Result<Page<Order>, sqlx::Error>;
+    async fn all(
+        &self,
+        store_id: Uuid,
+        filter: &OrderFilter,
+        page: &PageQuery,
+    ) -> Result<Page<OrderSummary>, sqlx::Error>;
+    // the matching orders as CSV, fetched a chunk at a time as it is read
+    fn export(
+        &self,
+        store_id: Uuid,
+        filter: OrderFilter,
+    ) -> BoxStream<'static, Result<Bytes, sqlx::Error>>;
+    async fn update_status(
+        &self,
+        store_id: Uuid,
+        order_id: Uuid,
+        order_status: OrderStatus,
+        user: &TokenClaims,
+        precondition: &Precondition,
+    ) -> Result<(), sqlx::Error>;
+    async fn bulk_update_status(
+        &self,
+        store_id: Uuid,
+        order_ids: &[Uuid],
+        status: OrderStatus,
+        user: &TokenClaims,
+    ) -> Result<Vec<BulkUpdateResult>, sqlx::Error>;
+    async fn review_orders(&self, store_id: Uuid) -> Result<Vec<OrderReview>, sqlx::Error>;
+    async fn admin_order(
+        &self,
+        store_id: Uuid,
+        order_id: Uuid,
+    ) -> Result<AdminOrderDetail, sqlx::Error>;
+    async fn price_audit(
+        &self,
+        store_id: Uuid,
+        filter: &OrderFilter,
+    ) -> Result<PriceAudit, sqlx::Error>;
+    #[allow(clippy::too_many_arguments)]
+    async fn preview(
+        &self,
+        limits: &OrderLimits,
+        pricing: &Pricing,
+        reverse_charge: Option<&ReverseCharge>,
+        body: PreviewBody,
+        store_id: Uuid,
+        user_id: Uuid,
+        group: Option<CustomerGroup>,
+    ) -> Result<CheckoutPreview, sqlx::Error>;
+    #[allow(clippy::too_many_arguments)]
+    async fn create(
+        &self,
+        payments: &Payments,
+        fraud: &FraudChecker,
+        limits: &OrderLimits,
+        pricing: &Pricing,
+        reverse_charge: Option<&ReverseCharge>,
+        body: OrderBody,
+        ip_country: Option<String>,
+        store_id: Uuid,
+        user_id: Uuid,
+        group: Option<CustomerGroup>,
+    ) -> Result<CheckoutOutcome, sqlx::Error>;
+}
+
+// every call is timed under "orders.<method>"
+pub struct PgOrderRepo {
+    pool: PgPool,
+    timings: Arc<QueryStats>,
+}
+
+impl PgOrderRepo {
+    pub fn new(pool: PgPool, timings: Arc<QueryStats>) -> Self {
+        PgOrderRepo { pool, timings }
+    }
+}
+
+#[async_trait]
+impl OrderRepo for PgOrderRepo {
+    async fn for_user(
+        &self,
+        store_id: Uuid,
+        user_id: Uuid,
+        page: &PageQuery,
+    ) -> Result<Page<Order>, sqlx::Error> {
+        self.timings
+            .time(
+                "orders.for_user",
+                Order::get_all_user_orders(&self.pool, store_id, user_id, page),
+            )
+            .await
+    }
+
+    async fn all(
+        &self,
+        store_id: Uuid,
+        filter: &OrderFilter,
+        page: &PageQuery,
+    ) -> Result<Page<OrderSummary>, sqlx::Error> {
+        self.timings
+            .time(
+                "orders.all",
+                Order::get_all_orders(&self.pool, store_id, filter, page),
+            )
+            .await
+    }
+
+    fn export(
+        &self,
+        store_id: Uuid,
+        filter: OrderFilter,
+    ) -> BoxStream<'static, Result<Bytes, sqlx::Error>> {
+        let cursor = ExportCursor {
+            pool: self.pool.clone(),
+            timings: self.timings.clone(),
+            store_id,
+            filter,
+            after: None,
+            started: false,
+            finished: false,
+        };
+        stream::try_unfold(cursor, |mut cursor| async move {
+            let chunk = Order::export_chunk(&mut cursor).await?;
+            Ok(chunk.map(|chunk| (Bytes::from(chunk), cursor)))
+        })
+        .boxed()
+    }
+
+    async fn update_status(
+        &self,
+        store_id: Uuid,
+        order_id: Uuid,
+        order_status: OrderStatus,
+        user: &TokenClaims,
+        precondition: &Precondition,
+    ) -> Result<(), sqlx::Error> {
+        self.timings
+            .time(
+                "orders.update_status",
+                Order::update_order_status(
+                    &self.pool,
+                    store_id,
+                    order_id,
+                    order_status,
+                    user,
+                    precondition,
+                ),
+            )
+            .await
+    }
+
+    async fn bulk_update_status(
+        &self,
+        store_id: Uuid,
+        order_ids: &[Uuid],
+        status: OrderStatus,
+        user: &TokenClaims,
+    ) -> Result<Vec<BulkUpdateResult>, sqlx::Error> {
+        self.timings
+            .time(
+                "orders.bulk_update_status",
+                Order::bulk_update_status(&self.pool, store_id, order_ids, status, user),
+            )
+            .await
+    }
+
+    async fn review_orders(&self, store_id: Uuid) -> Result<Vec<OrderReview>, sqlx::Error> {
+        self.timings
+            .time(
+                "orders.review_orders",
+                Order::get_review_orders(&self.pool, store_id),
+            )
+            .await
+    }
+
+    async fn admin_order(
+        &self,
+        store_id: Uuid,
+        order_id: Uuid,
+    ) -> Result<AdminOrderDetail, sqlx::Error> {
+        self.timings
+            .time(
+                "orders.admin_order",
+                Order::get_admin_order(&self.pool, store_id, order_id),
+            )
+            .await
+    }
+
+    async fn price_audit(
+        &self,
+        store_id: Uuid,
+        filter: &OrderFilter,
+    ) -> Result<PriceAudit, sqlx::Error> {
+        self.timings
+            .time(
+                "orders.price_audit",
+                Order::price_audit(&self.pool, store_id, filter),
+            )
+            .await
+    }
+
+    async fn preview(
+        &self,
+        limits: &OrderLimits,
+        pricing: &Pricing,
+        reverse_charge: Option<&ReverseCharge>,
+        body: PreviewBody,
+        store_id: Uuid,
+        user_id: Uuid,
+        group: Option<CustomerGroup>,
+    ) -> Result<CheckoutPreview, sqlx::Error> {
+        self.timings
+            .time(
+                "orders.preview",
+                Order::preview(
+                    &self.pool,
+                    limits,
+                    pricing,
+                    reverse_charge,
+                    body,
+                    store_id,
+                    user_id,
+                    group,
+                ),
+            )
+            .await
+    }
+
+    async fn create(
+        &self,
+        payments: &Payments,
+        fraud: &FraudChecker,
+        limits: &OrderLimits,
+        pricing: &Pricing,
+        reverse_charge: Option<&ReverseCharge>,
+        body: OrderBody,
+        ip_country: Option<String>,
+        store_id: Uuid,
+        user_id: Uuid,
+        group: Option<CustomerGroup>,
+    ) -> Result<CheckoutOutcome, sqlx::Error> {
+        // the payment provider's answer is timed on its own, orders.create is
+        // what is left, the database work
+        let started = Instant::now();
+        let mut charge_time = Duration::ZERO;
+        let outcome = Order::create_order(
+            &self.pool,
+            payments,
+            fraud,
+            limits,
+            pricing,
+            reverse_charge,
+            body,
+            ip_country,
+            store_id,
+            user_id,
+            group,
+            &mut charge_time,
+        )
+        .await;
+        self.timings.record(
+            "orders.create",
+            started.elapsed().saturating_sub(charge_time),
+        );
+        if !charge_time.is_zero() {
+            self.timings.record("payments.create_charge", charge_time);
+        }
+        outcome
     }
 }
 
@@ -191,35 +1810,111 @@ impl Order {
 #[get("api/orders")]
 pub async fn get_all_user_orders(
     state: web::Data<AppState>,
+    store: ReqData<Store>,
+    query: web::Query<PageQuery>,
     req_user: Option<ReqData<TokenClaims>>,
 ) -> impl Responder {
     match req_user {
-        Some(user) => match Order::get_all_user_orders(&state.db, user.user_id).await {
-            Ok(products) => HttpResponse::Ok().json(products),
-            Err(err) => HttpResponse::InternalServerError().json(format!("{err:?}")),
+        Some(user) => match state
+            .orders
+            .for_user(store.store_id, user.user_id, &query)
+            .await
+        {
+            Ok(page) => paginated(
+                page.items.into_iter().map(OrderResponse::from).collect(),
+                Pagination::new(&query, page.total),
+            ),
+            Err(err) => database_error(err),
         },
         None => HttpResponse::Unauthorized().json("unable to verify indentity"),
     }
 }
 
-// post request to create order and order details
-#[post("api/orders")]
-pub async fn create_order(
+// post request to place the order for the active cart, returns the order
+// in PendingPayment with the client secret or approval url to pay it with
+#[post("api/checkout")]
+pub async fn checkout(
+    req: HttpRequest,
     state: web::Data<AppState>,
+    store: ReqData<Store>,
     req_user: Option<ReqData<TokenClaims>>,
     body: Json<OrderBody>,
 ) -> impl Responder {
+    // country the customer is ordering from, for the fraud check
+    let ip_country = geoip::resolve(state.geoip.as_ref(), &req);
+    let ip_country = ip_country.detected.then_some(ip_country.country);
+
+    if !state.payments.is_configured() {
+        return HttpResponse::ServiceUnavailable().json("payments are not configured");
+    }
+
+    let marketing_consent = body.marketing_consent;
     match req_user {
         Some(user) => {
-            match Order::create_order(&state.db, body.shipping_address.clone(), user.user_id).await
+            match state
+                .orders
+                .create(
+                    &state.payments,
+                    &state.fraud,
+                    &state.limits,
+                    &state.pricing,
+                    state.reverse_charge.as_ref(),
+                    body.into_inner(),
+                    ip_country,
+                    store.store_id,
+                    user.user_id,
+                    user.catalogue_group(),
+                )
+                .await
             {
-                Ok(order) => HttpResponse::Created().json(order),
+                Ok(CheckoutOutcome::Placed(checkout)) => {
+                    if let Some(granted) = marketing_consent {
+                        if let Err(err) = MarketingConsent::record(
+                            &state.db,
+                            user.user_id,
+                            granted,
+                            "checkout",
+                            &state.marketing_policy_version,
+                            &req,
+                        )
+                        .await
+                        {
+                            println!("failed to record marketing consent: {err:?}");
+                        }
+                    }
+                    HttpResponse::Created().json(checkout)
+                }
+                Ok(CheckoutOutcome::Rejected(violations)) => {
+                    HttpResponse::UnprocessableEntity().json(violations)
+                }
                 Err(err) => match err {
                     sqlx::Error::RowNotFound => HttpResponse::NotFound().json("Cart not found"),
                     sqlx::Error::Protocol(msg) if msg.contains("Cart is empty") => {
                         HttpResponse::BadRequest().json("Cart is empty")
                     }
-                    _ => HttpResponse::InternalServerError().json(format!("{err:?}")),
+                    sqlx::Error::Protocol(msg) if msg.contains("Order limit exceeded") => {
+                        HttpResponse::TooManyRequests().json(msg)
+                    }
+                    sqlx::Error::Protocol(msg)
+                        if msg.contains("Not enough stock")
+                            || msg.contains("not sold to your customer group") =>
+                    {
+                        HttpResponse::Conflict().json(msg)
+                    }
+                    sqlx::Error::Protocol(msg) if msg.contains("Payment provider error") => {
+                        HttpResponse::BadGateway().json(msg)
+                    }
+                    sqlx::Error::Protocol(msg)
+                        if msg.contains("Gift message")
+                            || msg.contains("Pickup location")
+                            || msg.contains("Shipping method")
+                            || msg.contains("Payment provider is not available")
+                            || msg.contains("Cash on delivery")
+                            || msg.contains("Quote is not accepted") =>
+                    {
+                        HttpResponse::BadRequest().json(msg)
+                    }
+                    _ => database_error(err),
                 },
             }
         }
@@ -227,19 +1922,113 @@ pub async fn create_order(
     }
 }
 
-// admin only
-// get request to get all orders
+// post request for the totals checkout would charge, no order is created
+#[post("api/checkout/preview")]
+pub async fn preview_checkout(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    body: Json<PreviewBody>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            match state
+                .orders
+                .preview(
+                    &state.limits,
+                    &state.pricing,
+                    state.reverse_charge.as_ref(),
+                    body.into_inner(),
+                    store.store_id,
+                    user.user_id,
+                    user.catalogue_group(),
+                )
+                .await
+            {
+                Ok(preview) => HttpResponse::Ok().json(preview),
+                Err(sqlx::Error::RowNotFound) => HttpResponse::NotFound().json("Cart not found"),
+                Err(sqlx::Error::Protocol(msg)) => HttpResponse::BadRequest().json(msg),
+                Err(err) => database_error(err),
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unauthorized"),
+    }
+}
+
+// admin and support
+// get request to get all orders, Accept: text/csv gets every matching order
+// as the export has them
 #[get("api/admin/orders")]
 pub async fn get_all_orders(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    query: web::Query<PageQuery>,
+    filter: web::Query<OrderFilter>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.can(Permission::ViewOrders) {
+                if csv::requested(&req) {
+                    return csv::attachment(
+                        "orders.csv",
+                        state.orders.export(store.store_id, filter.into_inner()),
+                    );
+                }
+                match state.orders.all(store.store_id, &filter, &query).await {
+                    Ok(page) => paginated(page.items, Pagination::new(&query, page.total)),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Unauthorized().json("customer not allowed to see all orders")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin and support
+// get request for the orders as CSV for accounting, with the same filters as
+// the listing. The file is streamed so any number of orders fits
+#[get("api/admin/orders/export")]
+pub async fn export_orders(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    filter: web::Query<OrderFilter>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.can(Permission::ViewOrders) {
+                csv::attachment(
+                    "orders.csv",
+                    state.orders.export(store.store_id, filter.into_inner()),
+                )
+            } else {
+                HttpResponse::Unauthorized().json("customer not allowed to see all orders")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// get request to check that the matching orders still add up from the prices
+// they were sold at, takes the same filters as the listing
+#[get("api/admin/orders/price-audit")]
+pub async fn audit_order_prices(
     state: web::Data<AppState>,
+    store: ReqData<Store>,
+    filter: web::Query<OrderFilter>,
     req_user: Option<ReqData<TokenClaims>>,
 ) -> impl Responder {
     match req_user {
         Some(user) => {
             if user.is_admin() {
-                match Order::get_all_orders(&state.db).await {
-                    Ok(products) => HttpResponse::Ok().json(products),
-                    Err(err) => HttpResponse::InternalServerError().json(format!("{err:?}")),
+                match state.orders.price_audit(store.store_id, &filter).await {
+                    Ok(audit) => HttpResponse::Ok().json(audit),
+                    Err(err) => database_error(err),
                 }
             } else {
                 HttpResponse::Unauthorized().json("customer not allowed to see all orders")
@@ -249,25 +2038,99 @@ pub async fn get_all_orders(
     }
 }
 
-// put request to update the order status
+// admin, and warehouse staff shipping or readying confirmed orders
+// put request to update the order status, If-Match or If-Unmodified-Since
+// make it conditional
 #[put("api/admin/order")]
 pub async fn update_order_status(
+    req: HttpRequest,
     state: web::Data<AppState>,
+    store: ReqData<Store>,
     req_user: Option<ReqData<TokenClaims>>,
     body: Json<UpdateBody>,
 ) -> impl Responder {
     match req_user {
         Some(user) => {
-            if user.is_admin() {
-                match Order::update_order_status(
-                    &state.db,
-                    body.order_id,
-                    body.order_status.clone(),
-                )
-                .await
+            let Some(order_status) = OrderStatus::from_name(&body.order_status) else {
+                return HttpResponse::BadRequest()
+                    .json(format!("unknown order status {}", body.order_status));
+            };
+            if order_status.may_be_set_by(&user) {
+                match state
+                    .orders
+                    .update_status(
+                        store.store_id,
+                        body.order_id,
+                        order_status,
+                        &user,
+                        &Precondition::read(&req),
+                    )
+                    .await
                 {
                     Ok(_) => HttpResponse::Ok().json("updated order successfully"),
-                    Err(err) => HttpResponse::InternalServerError().json(format!("{err:?}")),
+                    Err(sqlx::Error::RowNotFound) => {
+                        HttpResponse::NotFound().json("order not found")
+                    }
+                    Err(sqlx::Error::Protocol(msg)) if msg == precondition::FAILED => {
+                        HttpResponse::PreconditionFailed().json(msg)
+                    }
+                    Err(sqlx::Error::Protocol(msg)) if msg == STATUS_REFUSED => {
+                        HttpResponse::Forbidden().json(msg)
+                    }
+                    Err(sqlx::Error::Protocol(msg)) => HttpResponse::BadRequest().json(msg),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("customer not allowed to update orders")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin and support
+// get request for orders held by the fraud check, approve them by
+// updating their status to Confirmed
+#[get("api/admin/orders/review")]
+pub async fn get_review_orders(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.can(Permission::ViewOrders) {
+                match state.orders.review_orders(store.store_id).await {
+                    Ok(orders) => HttpResponse::Ok().json(orders),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Unauthorized().json("customer not allowed to review orders")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin, support and the warehouse packing it
+// get request for one order with its lines and gift instructions
+#[get("api/admin/orders/{id}")]
+pub async fn get_admin_order(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    order_id: web::Path<Uuid>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.can(Permission::ViewOrders) || user.can(Permission::ShipOrders) {
+                match state.orders.admin_order(store.store_id, *order_id).await {
+                    Ok(order) => precondition::versioned(&mut HttpResponse::Ok(), order.updated_at)
+                        .json(order),
+                    Err(sqlx::Error::RowNotFound) => {
+                        HttpResponse::NotFound().json("order not found")
+                    }
+                    Err(err) => database_error(err),
                 }
             } else {
                 HttpResponse::Unauthorized().json("customer not allowed to see all orders")
@@ -276,3 +2139,34 @@ pub async fn update_order_status(
         None => HttpResponse::Unauthorized().json("unable to verify indentity"),
     }
 }
+
+// admin, and warehouse staff shipping or readying confirmed orders
+// put request to update the status of a batch of orders
+#[put("api/admin/orders/status")]
+pub async fn bulk_update_order_status(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    body: Json<BulkUpdateBody>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if body.status.may_be_set_by(&user) {
+                if body.order_ids.is_empty() {
+                    return HttpResponse::BadRequest().json("order_ids must not be empty");
+                }
+                match state
+                    .orders
+                    .bulk_update_status(store.store_id, &body.order_ids, body.status.clone(), &user)
+                    .await
+                {
+                    Ok(results) => HttpResponse::Ok().json(results),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("customer not allowed to update orders")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
@@ -0,0 +1,536 @@
+use crate::{
+    api::{
+        disputes::Dispute,
+        orders::{record_event, record_status_event, OrderStatus},
+        refunds::complete_refund,
+        stores::Store,
+        users::{Permission, TokenClaims},
+    },
+    payments::{CashOnDelivery, ChargeStatus, PaymentError, PaymentProvider, WebhookEvent},
+    rate_limit::database_error,
+    system_events, AppState,
+};
+use actix_web::{
+    get, post,
+    web::{self, Bytes, Json, ReqData},
+    HttpRequest, HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{types::Decimal, PgPool};
+use uuid::Uuid;
+
+// what became of the order when its payment came in
+#[derive(Serialize)]
+struct PaymentResult {
+    order_id: Uuid,
+    paid: bool,
+    #[serde(skip)]
+    payment_id: Uuid,
+}
+
+// cash on delivery order as reconciliation needs it
+#[derive(Serialize)]
+struct CodOrder {
+    order_id: Uuid,
+    user_id: Uuid,
+    total_amount: Decimal,
+    created_at: DateTime<Utc>,
+    cod_collected_at: Option<DateTime<Utc>>,
+    cod_collected_amount: Option<Decimal>,
+}
+
+#[derive(Deserialize)]
+struct CodQuery {
+    collected: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct CollectedBody {
+    // what the courier handed over, the order total when left out
+    amount: Option<Decimal>,
+}
+
+pub struct Payment;
+
+impl Payment {
+    // record a successful payment, the order moves on to confirmed, or review
+    // when fraud checks held it. Providers repeat themselves, a success already
+    // recorded changes nothing. Returns none for payments we don't know
+    async fn mark_paid(
+        pool: &PgPool,
+        provider: &str,
+        provider_ref: &str,
+    ) -> Result<Option<PaymentResult>, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        let Some(payment) = sqlx::query!(
+            "UPDATE payments SET status = 'succeeded', updated_at = NOW()
+            WHERE provider = $1 AND provider_ref = $2 AND status <> 'succeeded'
+            RETURNING payment_id, order_id, amount, currency",
+            provider,
+            provider_ref
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            let recorded = sqlx::query!(
+                r#"SELECT p.payment_id, p.order_id, o.status as "status!: OrderStatus"
+                FROM payments p JOIN orders o ON o.order_id = p.order_id
+                WHERE p.provider = $1 AND p.provider_ref = $2"#,
+                provider,
+                provider_ref
+            )
+            .fetch_optional(&mut *tx)
+            .await?;
+            return Ok(recorded.map(|payment| PaymentResult {
+                order_id: payment.order_id,
+                paid: !matches!(payment.status, OrderStatus::Cancelled),
+                payment_id: payment.payment_id,
+            }));
+        };
+
+        let order = sqlx::query!(
+            r#"SELECT status as "status!: OrderStatus", hold_for_review FROM orders
+            WHERE order_id = $1 FOR UPDATE"#,
+            payment.order_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        // a payment landing after the order expired gets a pending refund, one
+        // for an order already moved on is only noted for reconciliation
+        let details = json!({ "payment_id": payment.payment_id, "provider": provider });
+        let paid = match order.status {
+            OrderStatus::PendingPayment => {
+                let status = if order.hold_for_review {
+                    OrderStatus::Review
+                } else {
+                    OrderStatus::Confirmed
+                };
+                record_status_event(
+                    &mut *tx,
+                    payment.order_id,
+                    "payment.succeeded",
+                    status,
+                    details,
+                )
+                .await?;
+                true
+            }
+            OrderStatus::Cancelled => {
+                record_event(
+                    &mut *tx,
+                    payment.order_id,
+                    "payment.succeeded_after_cancel",
+                    details,
+                )
+                .await?;
+                sqlx::query!(
+                    "INSERT INTO refunds (order_id, payment_id, amount, reason)
+                    VALUES ($1, $2, $3, 'Paid after the order was cancelled')",
+                    payment.order_id,
+                    payment.payment_id,
+                    payment.amount
+                )
+                .execute(&mut *tx)
+                .await?;
+                false
+            }
+            _ => {
+                record_event(&mut *tx, payment.order_id, "payment.succeeded", details).await?;
+                true
+            }
+        };
+
+        tx.commit().await?;
+
+        Ok(Some(PaymentResult {
+            order_id: payment.order_id,
+            paid,
+            payment_id: payment.payment_id,
+        }))
+    }
+
+    // a failed attempt leaves the order waiting, the customer can retry until it expires
+    async fn mark_failed(
+        pool: &PgPool,
+        provider: &str,
+        provider_ref: &str,
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        let payment = sqlx::query!(
+            "UPDATE payments SET status = 'failed', updated_at = NOW()
+            WHERE provider = $1 AND provider_ref = $2 AND status = 'pending'
+            RETURNING payment_id, order_id",
+            provider,
+            provider_ref
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        if let Some(payment) = payment {
+            record_event(
+                &mut *tx,
+                payment.order_id,
+                "payment.failed",
+                json!({ "payment_id": payment.payment_id, "provider": provider }),
+            )
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    // the latest charge of a customer's order in the store
+    async fn latest_charge(
+        pool: &PgPool,
+        store_id: Uuid,
+        order_id: Uuid,
+        user_id: Uuid,
+    ) -> Result<(String, String), sqlx::Error> {
+        let payment = sqlx::query!(
+            "SELECT p.provider, p.provider_ref FROM payments p
+            JOIN orders o ON o.order_id = p.order_id
+            WHERE p.order_id = $1 AND o.user_id = $2 AND o.store_id = $3
+            ORDER BY p.created_at DESC LIMIT 1",
+            order_id,
+            user_id,
+            store_id
+        )
+        .fetch_one(pool)
+        .await?;
+        Ok((payment.provider, payment.provider_ref))
+    }
+}
+
+impl CodOrder {
+    // the store's cash on delivery orders
+    async fn get_all(
+        pool: &PgPool,
+        store_id: Uuid,
+        collected: Option<bool>,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as!(
+            CodOrder,
+            "SELECT order_id, user_id, total_amount, created_at, cod_collected_at,
+                cod_collected_amount
+            FROM orders
+            WHERE cash_on_delivery AND store_id = $2
+                AND ($1::bool IS NULL OR (cod_collected_at IS NOT NULL) = $1)
+            ORDER BY created_at",
+            collected,
+            store_id
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    // record the money the courier collected, settling the order's cod payment
+    async fn mark_collected(
+        pool: &PgPool,
+        store_id: Uuid,
+        order_id: Uuid,
+        amount: Option<Decimal>,
+        admin_id: Uuid,
+    ) -> Result<Self, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        let order = sqlx::query!(
+            r#"SELECT total_amount, cod_collected_at, status as "status!: OrderStatus"
+            FROM orders
+            WHERE order_id = $1 AND store_id = $2 AND cash_on_delivery FOR UPDATE"#,
+            order_id,
+            store_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        if order.cod_collected_at.is_some() {
+            return Err(sqlx::Error::Protocol(
+                "Payment was already collected".into(),
+            ));
+        }
+        // cash is only owed for an order on its way or waiting to be picked up,
+        // never for a cancelled or expired one
+        if !matches!(
+            order.status,
+            OrderStatus::Confirmed | OrderStatus::Shipped | OrderStatus::ReadyForPickup
+        ) {
+            return Err(sqlx::Error::Protocol(
+                "Payment is not owed for this order".into(),
+            ));
+        }
+
+        let amount = amount.unwrap_or(order.total_amount);
+        if amount < Decimal::ZERO {
+            return Err(sqlx::Error::Protocol(
+                "Collected amount can't be negative".into(),
+            ));
+        }
+
+        let collected = sqlx::query_as!(
+            CodOrder,
+            "UPDATE orders SET cod_collected_at = NOW(), cod_collected_amount = $2
+            WHERE order_id = $1
+            RETURNING order_id, user_id, total_amount, created_at, cod_collected_at,
+                cod_collected_amount",
+            order_id,
+            amount
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            "UPDATE payments SET status = 'succeeded', updated_at = NOW()
+            WHERE order_id = $1 AND provider = $2",
+            order_id,
+            CashOnDelivery::NAME
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        // a short or over collection shows up in the history for reconciliation
+        record_event(
+            &mut *tx,
+            order_id,
+            "cod.collected",
+            json!({
+                "amount": amount,
+                "difference": amount - order.total_amount,
+                "admin_id": admin_id,
+            }),
+        )
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(collected)
+    }
+}
+
+// where a charge stands after asking its provider
+enum Settlement {
+    Paid(PaymentResult),
+    Pending,
+    Failed,
+    Unknown,
+}
+
+// re-read the charge with its provider and record the outcome, money that
+// arrives for an order cancelled in the meantime goes straight back
+async fn settle(
+    pool: &PgPool,
+    provider: &dyn PaymentProvider,
+    provider_ref: &str,
+) -> Result<Settlement, String> {
+    let status = provider
+        .confirm_charge(provider_ref)
+        .await
+        .map_err(|err| format!("Payment provider error: {err}"))?;
+
+    match status {
+        ChargeStatus::Succeeded => {
+            let Some(result) = Payment::mark_paid(pool, provider.name(), provider_ref)
+                .await
+                .map_err(|err| format!("{err:?}"))?
+            else {
+                return Ok(Settlement::Unknown);
+            };
+
+            // money for a cancelled order goes back, a refund the provider
+            // doesn't take now is sent again by the refund retry job
+            if !result.paid {
+                let pending = sqlx::query_scalar!(
+                    "SELECT refund_id FROM refunds WHERE payment_id = $1 AND status = 'pending'",
+                    result.payment_id
+                )
+                .fetch_all(pool)
+                .await
+                .map_err(|err| format!("{err:?}"))?;
+                for refund_id in pending {
+                    if let Err(err) = complete_refund(pool, Some(provider), refund_id).await {
+                        record_event(
+                            pool,
+                            result.order_id,
+                            "payment.refund_failed",
+                            json!({ "refund_id": refund_id, "error": format!("{err}") }),
+                        )
+                        .await
+                        .map_err(|err| format!("{err:?}"))?;
+                    }
+                }
+            }
+            Ok(Settlement::Paid(result))
+        }
+        ChargeStatus::Failed => {
+            Payment::mark_failed(pool, provider.name(), provider_ref)
+                .await
+                .map_err(|err| format!("{err:?}"))?;
+            Ok(Settlement::Failed)
+        }
+        ChargeStatus::Pending => Ok(Settlement::Pending),
+    }
+}
+
+// post request for the client to report a finished payment, checked with
+// the provider so it works without waiting for the webhook
+#[post("api/checkout/{order_id}/confirm")]
+pub async fn confirm_payment(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    order_id: web::Path<Uuid>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            let (provider, provider_ref) =
+                match Payment::latest_charge(&state.db, store.store_id, *order_id, user.user_id)
+                    .await
+                {
+                    Ok(charge) => charge,
+                    Err(sqlx::Error::RowNotFound) => {
+                        return HttpResponse::NotFound().json("Order not found")
+                    }
+                    Err(err) => return database_error(err),
+                };
+            if provider == CashOnDelivery::NAME {
+                return HttpResponse::Conflict().json("Order is paid on delivery");
+            }
+            let Some(provider) = state.payments.get(&provider) else {
+                return HttpResponse::ServiceUnavailable()
+                    .json("payment provider is not configured");
+            };
+
+            match settle(&state.db, provider.as_ref(), &provider_ref).await {
+                Ok(Settlement::Paid(result)) => HttpResponse::Ok().json(result),
+                Ok(Settlement::Pending) => HttpResponse::Accepted().json("Payment is processing"),
+                Ok(Settlement::Failed) => {
+                    HttpResponse::PaymentRequired().json("Payment has not succeeded")
+                }
+                Ok(Settlement::Unknown) => HttpResponse::NotFound().json("Payment not found"),
+                Err(msg) if msg.contains("Payment provider error") => {
+                    HttpResponse::BadGateway().json(msg)
+                }
+                Err(msg) => HttpResponse::InternalServerError().json(msg),
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// post request from a payment provider about one of its charges, each
+// provider checks its own signature
+#[post("api/webhooks/payments/{provider}")]
+pub async fn payment_webhook(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    provider: web::Path<String>,
+    body: Bytes,
+) -> impl Responder {
+    let Some(provider) = state.payments.get(&provider.to_lowercase()) else {
+        return HttpResponse::NotFound().json("unknown payment provider");
+    };
+
+    let event = match provider.parse_webhook(req.headers(), &body).await {
+        Ok(Some(event)) => event,
+        // acknowledge events we don't act on so the provider stops retrying them
+        Ok(None) => return HttpResponse::Ok().json("ignored"),
+        Err(PaymentError::InvalidSignature) => {
+            return HttpResponse::Unauthorized().json("invalid signature")
+        }
+        Err(PaymentError::Malformed(msg)) => return HttpResponse::BadRequest().json(msg),
+        Err(err) => return HttpResponse::BadGateway().json(err.to_string()),
+    };
+
+    let result = match event {
+        WebhookEvent::Charge(provider_ref) => settle(&state.db, provider.as_ref(), &provider_ref)
+            .await
+            .map(|_| ()),
+        WebhookEvent::Dispute(dispute) => Dispute::record(&state.db, provider.name(), dispute)
+            .await
+            .map(|_| ())
+            .map_err(|err| format!("{err:?}")),
+    };
+
+    match result {
+        Ok(()) => HttpResponse::Ok().json("ok"),
+        // the provider sends it again, staff see that it is retrying
+        Err(msg) => {
+            let details =
+                json!({ "source": format!("payments/{}", provider.name()), "error": &msg });
+            if let Err(err) = system_events::record(&state.db, "webhook.failed", details).await {
+                println!("failed to record the failed webhook: {err:?}");
+            }
+            HttpResponse::InternalServerError().json(msg)
+        }
+    }
+}
+
+// admin and support
+// get request for cash on delivery orders, ?collected=false for the outstanding ones
+#[get("api/admin/orders/cod")]
+pub async fn get_cod_orders(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    query: web::Query<CodQuery>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.can(Permission::ViewOrders) {
+                match CodOrder::get_all(&state.db, store.store_id, query.collected).await {
+                    Ok(orders) => HttpResponse::Ok().json(orders),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("customer not allowed to reconcile payments")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// post request to record the cash collected on delivery of an order
+#[post("api/admin/orders/{id}/cod-collected")]
+pub async fn mark_cod_collected(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    order_id: web::Path<Uuid>,
+    body: Json<CollectedBody>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match CodOrder::mark_collected(
+                    &state.db,
+                    store.store_id,
+                    *order_id,
+                    body.amount,
+                    user.user_id,
+                )
+                .await
+                {
+                    Ok(order) => HttpResponse::Ok().json(order),
+                    Err(sqlx::Error::RowNotFound) => {
+                        HttpResponse::NotFound().json("Cash on delivery order not found")
+                    }
+                    Err(sqlx::Error::Protocol(msg))
+                        if msg.contains("already collected") || msg.contains("not owed") =>
+                    {
+                        HttpResponse::Conflict().json(msg)
+                    }
+                    Err(sqlx::Error::Protocol(msg)) => HttpResponse::BadRequest().json(msg),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("customer not allowed to reconcile payments")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
@@ -0,0 +1,390 @@
+use crate::{
+    api::{stores::Store, users::TokenClaims},
+    rate_limit::database_error,
+    AppState,
+};
+use actix_web::{
+    delete, get, post, put,
+    web::{self, Json, ReqData},
+    HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+#[derive(Serialize, FromRow)]
+pub struct PickupLocation {
+    location_id: Uuid,
+    name: String,
+    address: String,
+    opening_hours: Option<String>,
+    is_active: bool,
+    created_at: DateTime<Utc>,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    store_id: Uuid,
+}
+
+// an active location near the customer, with how far away it is
+#[derive(Serialize)]
+pub struct NearbyPickupLocation {
+    #[serde(flatten)]
+    location: PickupLocation,
+    distance_km: f64,
+}
+
+// ?lat=52.37&lng=4.89&radius=10, the radius in km
+#[derive(Deserialize)]
+pub struct NearbyQuery {
+    lat: f64,
+    lng: f64,
+    radius: Option<f64>,
+}
+
+const DEFAULT_RADIUS_KM: f64 = 25.0;
+const MAX_RADIUS_KM: f64 = 500.0;
+
+#[derive(Deserialize)]
+struct PickupLocationBody {
+    name: String,
+    address: String,
+    opening_hours: Option<String>,
+    #[serde(default = "default_active")]
+    is_active: bool,
+    // without them the location isn't offered by distance
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+}
+
+fn default_active() -> bool {
+    true
+}
+
+impl PickupLocationBody {
+    fn validate(&self) -> Result<(), sqlx::Error> {
+        if self.name.trim().is_empty() || self.address.trim().is_empty() {
+            return Err(sqlx::Error::Protocol(
+                "Name and address are required".into(),
+            ));
+        }
+        match (self.latitude, self.longitude) {
+            (None, None) => {}
+            (Some(latitude), Some(longitude))
+                if (-90.0..=90.0).contains(&latitude) && (-180.0..=180.0).contains(&longitude) => {}
+            _ => {
+                return Err(sqlx::Error::Protocol(
+                    "Latitude and longitude go together, within -90 to 90 and -180 to 180".into(),
+                ))
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PickupLocation {
+    // address of an active location of the store, checkout stores it as the
+    // order address
+    pub async fn active_address(
+        pool: &PgPool,
+        store_id: Uuid,
+        location_id: Uuid,
+    ) -> Result<Option<String>, sqlx::Error> {
+        let location = sqlx::query!(
+            "SELECT name, address FROM pickup_locations
+            WHERE location_id = $1 AND store_id = $2 AND is_active",
+            location_id,
+            store_id
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(location.map(|location| format!("{}, {}", location.name, location.address)))
+    }
+
+    async fn get_all(
+        pool: &PgPool,
+        store_id: Uuid,
+        active_only: bool,
+    ) -> Result<Vec<PickupLocation>, sqlx::Error> {
+        sqlx::query_as!(
+            PickupLocation,
+            "SELECT * FROM pickup_locations WHERE store_id = $2 AND (is_active OR NOT $1)
+            ORDER BY name",
+            active_only,
+            store_id
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    async fn create(
+        pool: &PgPool,
+        store_id: Uuid,
+        body: PickupLocationBody,
+    ) -> Result<PickupLocation, sqlx::Error> {
+        body.validate()?;
+        sqlx::query_as!(
+            PickupLocation,
+            "INSERT INTO pickup_locations
+                (name, address, opening_hours, is_active, latitude, longitude, store_id)
+            VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING *",
+            body.name.trim(),
+            body.address.trim(),
+            body.opening_hours,
+            body.is_active,
+            body.latitude,
+            body.longitude,
+            store_id
+        )
+        .fetch_one(pool)
+        .await
+    }
+
+    async fn update(
+        pool: &PgPool,
+        store_id: Uuid,
+        location_id: Uuid,
+        body: PickupLocationBody,
+    ) -> Result<PickupLocation, sqlx::Error> {
+        body.validate()?;
+        sqlx::query_as!(
+            PickupLocation,
+            "UPDATE pickup_locations SET name = $1, address = $2, opening_hours = $3, is_active = $4,
+                latitude = $5, longitude = $6
+            WHERE location_id = $7 AND store_id = $8 RETURNING *",
+            body.name.trim(),
+            body.address.trim(),
+            body.opening_hours,
+            body.is_active,
+            body.latitude,
+            body.longitude,
+            location_id,
+            store_id
+        )
+        .fetch_optional(pool)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    // active locations within the radius, nearest first. The box check lets
+    // the index narrow them down before the exact distance is worked out
+    async fn nearby(
+        pool: &PgPool,
+        store_id: Uuid,
+        latitude: f64,
+        longitude: f64,
+        radius_km: f64,
+    ) -> Result<Vec<NearbyPickupLocation>, sqlx::Error> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT location_id, name, address, opening_hours, is_active, created_at,
+                   latitude, longitude,
+                   earth_distance(ll_to_earth($1, $2), ll_to_earth(latitude, longitude)) / 1000
+                       as "distance_km!",
+                   store_id
+            FROM pickup_locations
+            WHERE store_id = $4 AND is_active AND latitude IS NOT NULL
+            AND earth_box(ll_to_earth($1, $2), $3::float8 * 1000) @> ll_to_earth(latitude, longitude)
+            AND earth_distance(ll_to_earth($1, $2), ll_to_earth(latitude, longitude)) <= $3 * 1000
+            ORDER BY 9, name
+            "#,
+            latitude,
+            longitude,
+            radius_km,
+            store_id
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| NearbyPickupLocation {
+                location: PickupLocation {
+                    location_id: row.location_id,
+                    name: row.name,
+                    address: row.address,
+                    opening_hours: row.opening_hours,
+                    is_active: row.is_active,
+                    created_at: row.created_at,
+                    latitude: row.latitude,
+                    longitude: row.longitude,
+                    store_id: row.store_id,
+                },
+                distance_km: row.distance_km,
+            })
+            .collect())
+    }
+
+    async fn delete(pool: &PgPool, store_id: Uuid, location_id: Uuid) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            "DELETE FROM pickup_locations WHERE location_id = $1 AND store_id = $2",
+            location_id,
+            store_id
+        )
+        .execute(pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+// get request for the locations customers can pick up from
+#[get("api/pickup-locations")]
+pub async fn get_pickup_locations(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(_) => match PickupLocation::get_all(&state.db, store.store_id, true).await {
+            Ok(locations) => HttpResponse::Ok().json(locations),
+            Err(err) => database_error(err),
+        },
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// get request for the pickup locations near the customer, nearest first, for
+// choosing where to collect an order. ?radius= is in km, 25 unless asked
+#[get("api/pickup-locations/nearby")]
+pub async fn get_nearby_pickup_locations(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    query: web::Query<NearbyQuery>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(_) => {
+            if !(-90.0..=90.0).contains(&query.lat) || !(-180.0..=180.0).contains(&query.lng) {
+                return HttpResponse::BadRequest()
+                    .json("lat must be within -90 to 90 and lng within -180 to 180");
+            }
+            let radius = query.radius.unwrap_or(DEFAULT_RADIUS_KM);
+            if !(radius > 0.0 && radius <= MAX_RADIUS_KM) {
+                return HttpResponse::BadRequest().json(format!(
+                    "radius must be more than 0 and at most {MAX_RADIUS_KM} km"
+                ));
+            }
+            match PickupLocation::nearby(&state.db, store.store_id, query.lat, query.lng, radius)
+                .await
+            {
+                Ok(locations) => HttpResponse::Ok().json(locations),
+                Err(err) => database_error(err),
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// get request for all pickup locations, inactive ones included
+#[get("api/admin/pickup-locations")]
+pub async fn get_all_pickup_locations(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match PickupLocation::get_all(&state.db, store.store_id, false).await {
+                    Ok(locations) => HttpResponse::Ok().json(locations),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("customer not allowed to manage pickup locations")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// post request to add a pickup location
+#[post("api/admin/pickup-locations")]
+pub async fn create_pickup_location(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    body: Json<PickupLocationBody>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match PickupLocation::create(&state.db, store.store_id, body.into_inner()).await {
+                    Ok(location) => HttpResponse::Created().json(location),
+                    Err(sqlx::Error::Protocol(msg)) => HttpResponse::BadRequest().json(msg),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("customer not allowed to manage pickup locations")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// put request to update a pickup location, set is_active false to stop offering it
+#[put("api/admin/pickup-locations/{id}")]
+pub async fn update_pickup_location(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    location_id: web::Path<Uuid>,
+    body: Json<PickupLocationBody>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match PickupLocation::update(
+                    &state.db,
+                    store.store_id,
+                    *location_id,
+                    body.into_inner(),
+                )
+                .await
+                {
+                    Ok(location) => HttpResponse::Ok().json(location),
+                    Err(sqlx::Error::RowNotFound) => {
+                        HttpResponse::NotFound().json("pickup location not found")
+                    }
+                    Err(sqlx::Error::Protocol(msg)) => HttpResponse::BadRequest().json(msg),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("customer not allowed to manage pickup locations")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// delete request to remove a pickup location no order uses
+#[delete("api/admin/pickup-locations/{id}")]
+pub async fn delete_pickup_location(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    location_id: web::Path<Uuid>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match PickupLocation::delete(&state.db, store.store_id, *location_id).await {
+                    Ok(true) => HttpResponse::Ok().json("pickup location deleted"),
+                    Ok(false) => HttpResponse::NotFound().json("pickup location not found"),
+                    Err(sqlx::Error::Database(err)) if err.is_foreign_key_violation() => {
+                        HttpResponse::Conflict()
+                            .json("pickup location has orders, deactivate it instead")
+                    }
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("customer not allowed to manage pickup locations")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
@@ -0,0 +1,275 @@
+use crate::{api::users::TokenClaims, client_ip::client_ip, rate_limit::database_error, AppState};
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    get,
+    middleware::Next,
+    put,
+    web::{self, Json, ReqData},
+    HttpMessage, HttpRequest, HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+// the policies a customer agrees to by having an account
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Policy {
+    Terms,
+    Privacy,
+}
+
+impl Policy {
+    const ALL: [Policy; 2] = [Policy::Terms, Policy::Privacy];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Policy::Terms => "terms",
+            Policy::Privacy => "privacy",
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            Policy::Terms => "terms of service",
+            Policy::Privacy => "privacy policy",
+        }
+    }
+}
+
+// the versions of the policies in force. Publishing a new version means
+// bumping TERMS_VERSION or PRIVACY_POLICY_VERSION, every customer is asked to
+// accept it again on their next request. The privacy policy is the one
+// marketing consents are given under until it has a version of its own
+#[derive(Clone)]
+pub struct PolicyVersions {
+    terms: String,
+    privacy: String,
+}
+
+impl PolicyVersions {
+    pub fn from_env() -> Self {
+        PolicyVersions {
+            terms: std::env::var("TERMS_VERSION").unwrap_or_else(|_| "1".into()),
+            privacy: std::env::var("PRIVACY_POLICY_VERSION")
+                .or_else(|_| std::env::var("MARKETING_POLICY_VERSION"))
+                .unwrap_or_else(|_| "1".into()),
+        }
+    }
+
+    fn current(&self, policy: Policy) -> &str {
+        match policy {
+            Policy::Terms => &self.terms,
+            Policy::Privacy => &self.privacy,
+        }
+    }
+
+    // the policies whose current version wasn't the last one accepted
+    pub fn pending(&self, terms: Option<&str>, privacy: Option<&str>) -> Vec<Policy> {
+        Policy::ALL
+            .into_iter()
+            .zip([terms, privacy])
+            .filter(|(policy, accepted)| *accepted != Some(self.current(*policy)))
+            .map(|(policy, _)| policy)
+            .collect()
+    }
+}
+
+// a version of a policy the customer accepted
+#[derive(Serialize)]
+pub struct PolicyAcceptance {
+    policy: String,
+    version: String,
+    accepted_at: DateTime<Utc>,
+}
+
+// where the customer stands with a policy, the version they accepted last
+// and when, none when they never did
+#[derive(Serialize)]
+struct PolicyStatus {
+    policy: Policy,
+    current_version: String,
+    accepted_version: Option<String>,
+    accepted_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+struct AcceptanceBody {
+    // the version the customer was shown, accepting an older one is refused
+    version: String,
+}
+
+// a policy not accepted in its current version, as middleware answers with it
+#[derive(Serialize)]
+struct PendingPolicy {
+    code: &'static str,
+    message: String,
+    policy: Policy,
+    version: String,
+}
+
+impl PolicyAcceptance {
+    // record that the customer accepted the current version of each policy
+    pub async fn record(
+        pool: &PgPool,
+        user_id: Uuid,
+        policies: &[Policy],
+        versions: &PolicyVersions,
+        req: &HttpRequest,
+    ) -> Result<Vec<PolicyAcceptance>, sqlx::Error> {
+        let ip_address = client_ip(req).map(|ip| ip.to_string());
+        let user_agent = req
+            .headers()
+            .get("User-Agent")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned);
+
+        let mut tx = pool.begin().await?;
+        let mut acceptances = Vec::with_capacity(policies.len());
+        for policy in policies {
+            let acceptance = sqlx::query_as!(
+                PolicyAcceptance,
+                "INSERT INTO policy_acceptances (user_id, policy, version, ip_address, user_agent)
+                VALUES ($1, $2, $3, $4, $5)
+                RETURNING policy, version, created_at as accepted_at",
+                user_id,
+                policy.as_str(),
+                versions.current(*policy),
+                ip_address,
+                user_agent
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+            acceptances.push(acceptance);
+        }
+        tx.commit().await?;
+
+        Ok(acceptances)
+    }
+
+    // the last acceptance of each policy
+    async fn latest(pool: &PgPool, user_id: Uuid) -> Result<Vec<PolicyAcceptance>, sqlx::Error> {
+        sqlx::query_as!(
+            PolicyAcceptance,
+            "SELECT DISTINCT ON (policy) policy, version, created_at as accepted_at
+            FROM policy_acceptances WHERE user_id = $1
+            ORDER BY policy, created_at DESC",
+            user_id
+        )
+        .fetch_all(pool)
+        .await
+    }
+}
+
+// the routes a customer can still use before accepting, to read and accept
+// the policies and to see who they are logged in as
+fn allowed_before_acceptance(path: &str) -> bool {
+    path == "/api/user_info" || path.starts_with("/api/users/me/policies")
+}
+
+// middleware behind the bearer token, answers 451 with the policies still
+// to accept until the customer has accepted their current versions
+pub async fn require_acceptance(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let pending = req
+        .extensions()
+        .get::<TokenClaims>()
+        .map(|claims| claims.pending_policies().to_vec())
+        .unwrap_or_default();
+    if pending.is_empty() || allowed_before_acceptance(req.path()) {
+        return Ok(next.call(req).await?.map_into_left_body());
+    }
+
+    let state = req
+        .app_data::<web::Data<AppState>>()
+        .cloned()
+        .expect("app state must be registered");
+    let pending: Vec<PendingPolicy> = pending
+        .into_iter()
+        .map(|policy| PendingPolicy {
+            code: "policy_not_accepted",
+            message: format!(
+                "version {} of the {} has to be accepted first",
+                state.policies.current(policy),
+                policy.title()
+            ),
+            policy,
+            version: state.policies.current(policy).to_string(),
+        })
+        .collect();
+    let res = HttpResponse::UnavailableForLegalReasons().json(pending);
+    Ok(req.into_response(res).map_into_right_body())
+}
+
+// get request for the current version of each policy and the one the user
+// accepted last
+#[get("api/users/me/policies")]
+pub async fn get_policies(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => match PolicyAcceptance::latest(&state.db, user.user_id).await {
+            Ok(acceptances) => HttpResponse::Ok().json(
+                Policy::ALL
+                    .into_iter()
+                    .map(|policy| {
+                        let accepted = acceptances
+                            .iter()
+                            .find(|acceptance| acceptance.policy == policy.as_str());
+                        PolicyStatus {
+                            policy,
+                            current_version: state.policies.current(policy).to_string(),
+                            accepted_version: accepted.map(|a| a.version.clone()),
+                            accepted_at: accepted.map(|a| a.accepted_at),
+                        }
+                    })
+                    .collect::<Vec<_>>(),
+            ),
+            Err(err) => database_error(err),
+        },
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// put request to accept the current version of the terms or the privacy
+// policy, 409 when the version shown to the customer is no longer current
+#[put("api/users/me/policies/{policy}")]
+pub async fn accept_policy(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<Policy>,
+    req_user: Option<ReqData<TokenClaims>>,
+    body: Json<AcceptanceBody>,
+) -> impl Responder {
+    let policy = path.into_inner();
+    match req_user {
+        Some(user) => {
+            let current = state.policies.current(policy);
+            if body.version != current {
+                return HttpResponse::Conflict().json(format!(
+                    "version {current} of the {} is current, not {}",
+                    policy.title(),
+                    body.version
+                ));
+            }
+            match PolicyAcceptance::record(
+                &state.db,
+                user.user_id,
+                &[policy],
+                &state.policies,
+                &req,
+            )
+            .await
+            {
+                Ok(mut acceptances) => HttpResponse::Ok().json(acceptances.remove(0)),
+                Err(err) => database_error(err),
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
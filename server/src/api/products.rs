@@ -1,141 +1,2507 @@
-use crate::{api::users::TokenClaims, AppState};
+use crate::{
+    api::{customer_groups::CustomerGroup, stores::Store, users::TokenClaims},
+    cache::Cached,
+    csv,
+    envelope::{paginated, Page, PageQuery, Pagination},
+    images::{sized_key, upload_format, SIZES},
+    media::{valid_object_key, MediaUrls},
+    precondition::{self, Precondition},
+    query_stats::QueryStats,
+    rate_limit::database_error,
+    storage::Storage,
+    AppState,
+};
 use actix_web::{
-    delete, get, post, put,
-    web::{self, Json, ReqData},
-    HttpMessage, HttpResponse, Responder,
+    delete, get,
+    http::header,
+    patch, post, put,
+    web::{self, Bytes, Json, ReqData},
+    HttpRequest, HttpResponse, Responder,
 };
+use async_trait::async_trait;
 use chrono::{DateTime, Utc};
-use serde::{de::Error, Deserialize, Serialize};
-use sqlx::{types::Decimal, FromRow, PgPool};
+use futures_util::stream::{self, BoxStream, StreamExt};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Decimal, FromRow, PgConnection, PgPool, Postgres, QueryBuilder};
+use std::{fmt, sync::Arc, time::Duration};
 use uuid::Uuid;
 
-#[derive(Serialize, Deserialize, FromRow)]
-struct Product {
-    name: String,
-    description: Option<String>,
-    price: Decimal,
-    stock_quantity: i32,
-    category: Option<String>,
-    is_available: Option<bool>,
-    created_at: Option<DateTime<Utc>>,
-    product_id: Uuid,
-}
+// what a product is sold by, its price is per unit
+#[derive(Debug, Serialize, Deserialize, sqlx::Type, Clone, Copy, Default, PartialEq)]
+#[sqlx(type_name = "product_unit", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ProductUnit {
+    #[default]
+    Piece,
+    Kg,
+    Liter,
+}
+
+impl ProductUnit {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ProductUnit::Piece => "piece",
+            ProductUnit::Kg => "kg",
+            ProductUnit::Liter => "liter",
+        }
+    }
+}
+
+impl fmt::Display for ProductUnit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// a products row, responses go out as ProductResponse
+#[derive(FromRow)]
+pub struct Product {
+    name: String,
+    description: Option<String>,
+    price: Decimal,
+    stock_quantity: Decimal,
+    category: Option<String>,
+    is_available: Option<bool>,
+    created_at: Option<DateTime<Utc>>,
+    product_id: Uuid,
+    unit: ProductUnit,
+    // carts take multiples of this, 0.5 for half kilos
+    quantity_step: Decimal,
+    // packed size of one unit, shipping is priced on it
+    weight_kg: Option<Decimal>,
+    length_cm: Option<Decimal>,
+    width_cm: Option<Decimal>,
+    height_cm: Option<Decimal>,
+    updated_at: DateTime<Utc>,
+    // the age a customer has to be to buy it, none for anyone
+    age_restriction: Option<i16>,
+    // how much one customer may buy over all their orders, none for no limit
+    purchase_limit: Option<Decimal>,
+}
+
+// what the API shows of a product
+#[derive(Serialize, Clone)]
+pub struct ProductResponse {
+    product_id: Uuid,
+    name: String,
+    description: Option<String>,
+    price: Decimal,
+    stock_quantity: Decimal,
+    category: Option<String>,
+    is_available: Option<bool>,
+    created_at: Option<DateTime<Utc>>,
+    unit: ProductUnit,
+    quantity_step: Decimal,
+    weight_kg: Option<Decimal>,
+    length_cm: Option<Decimal>,
+    width_cm: Option<Decimal>,
+    height_cm: Option<Decimal>,
+    // its ETag and Last-Modified come from this
+    updated_at: DateTime<Utc>,
+    age_restriction: Option<i16>,
+    purchase_limit: Option<Decimal>,
+}
+
+impl From<Product> for ProductResponse {
+    fn from(product: Product) -> Self {
+        ProductResponse {
+            product_id: product.product_id,
+            name: product.name,
+            description: product.description,
+            price: product.price,
+            stock_quantity: product.stock_quantity,
+            category: product.category,
+            is_available: product.is_available,
+            created_at: product.created_at,
+            unit: product.unit,
+            quantity_step: product.quantity_step,
+            weight_kg: product.weight_kg,
+            length_cm: product.length_cm,
+            width_cm: product.width_cm,
+            height_cm: product.height_cm,
+            updated_at: product.updated_at,
+            age_restriction: product.age_restriction,
+            purchase_limit: product.purchase_limit,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ProductBody {
+    name: String,
+    description: Option<String>,
+    price: Decimal,
+    stock_quantity: Decimal,
+    // left out keeps what the product has, pieces in steps of 1 for new products
+    unit: Option<ProductUnit>,
+    quantity_step: Option<Decimal>,
+    weight_kg: Option<Decimal>,
+    length_cm: Option<Decimal>,
+    width_cm: Option<Decimal>,
+    height_cm: Option<Decimal>,
+    age_restriction: Option<i16>,
+    purchase_limit: Option<Decimal>,
+}
+
+impl ProductBody {
+    fn validate(&self) -> Result<(), sqlx::Error> {
+        if self.quantity_step.is_some_and(|step| step <= Decimal::ZERO) {
+            return Err(sqlx::Error::Protocol(
+                "Quantity step must be positive".into(),
+            ));
+        }
+        if self.weight_kg.is_some_and(|weight| weight < Decimal::ZERO)
+            || [self.length_cm, self.width_cm, self.height_cm]
+                .into_iter()
+                .flatten()
+                .any(|size| size <= Decimal::ZERO)
+        {
+            return Err(sqlx::Error::Protocol(
+                "Weight can't be negative and dimensions must be positive".into(),
+            ));
+        }
+        if self.age_restriction.is_some_and(|age| age <= 0) {
+            return Err(sqlx::Error::Protocol(
+                "Age restriction must be positive".into(),
+            ));
+        }
+        if self
+            .purchase_limit
+            .is_some_and(|limit| limit <= Decimal::ZERO)
+        {
+            return Err(sqlx::Error::Protocol(
+                "Purchase limit must be positive".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+// a partial edit, fields left out keep their value and the nullable ones
+// are cleared with null
+#[derive(Deserialize)]
+pub struct ProductPatch {
+    name: Option<String>,
+    #[serde(default, deserialize_with = "crate::api::nullable")]
+    description: Option<Option<String>>,
+    #[serde(default, deserialize_with = "crate::api::nullable")]
+    category: Option<Option<String>>,
+    price: Option<Decimal>,
+    stock_quantity: Option<Decimal>,
+    is_available: Option<bool>,
+    unit: Option<ProductUnit>,
+    quantity_step: Option<Decimal>,
+    #[serde(default, deserialize_with = "crate::api::nullable")]
+    weight_kg: Option<Option<Decimal>>,
+    #[serde(default, deserialize_with = "crate::api::nullable")]
+    length_cm: Option<Option<Decimal>>,
+    #[serde(default, deserialize_with = "crate::api::nullable")]
+    width_cm: Option<Option<Decimal>>,
+    #[serde(default, deserialize_with = "crate::api::nullable")]
+    height_cm: Option<Option<Decimal>>,
+    // null sells it to anyone again
+    #[serde(default, deserialize_with = "crate::api::nullable")]
+    age_restriction: Option<Option<i16>>,
+    // null lifts the limit
+    #[serde(default, deserialize_with = "crate::api::nullable")]
+    purchase_limit: Option<Option<Decimal>>,
+}
+
+impl ProductPatch {
+    fn validate(&self) -> Result<(), sqlx::Error> {
+        let invalid = |msg: &str| Err(sqlx::Error::Protocol(msg.into()));
+        if self
+            .name
+            .as_ref()
+            .is_some_and(|name| name.trim().is_empty())
+        {
+            return invalid("Name can't be empty");
+        }
+        if self.price.is_some_and(|price| price < Decimal::ZERO) {
+            return invalid("Price must not be negative");
+        }
+        if self
+            .stock_quantity
+            .is_some_and(|stock| stock < Decimal::ZERO)
+        {
+            return invalid("Stock must not be negative");
+        }
+        if self.quantity_step.is_some_and(|step| step <= Decimal::ZERO) {
+            return invalid("Quantity step must be positive");
+        }
+        if self
+            .weight_kg
+            .flatten()
+            .is_some_and(|weight| weight < Decimal::ZERO)
+            || [self.length_cm, self.width_cm, self.height_cm]
+                .into_iter()
+                .flatten()
+                .flatten()
+                .any(|size| size <= Decimal::ZERO)
+        {
+            return invalid("Weight can't be negative and dimensions must be positive");
+        }
+        if self.age_restriction.flatten().is_some_and(|age| age <= 0) {
+            return invalid("Age restriction must be positive");
+        }
+        if self
+            .purchase_limit
+            .flatten()
+            .is_some_and(|limit| limit <= Decimal::ZERO)
+        {
+            return invalid("Purchase limit must be positive");
+        }
+        Ok(())
+    }
+}
+
+// compact product shown in the related list
+#[derive(Serialize, FromRow)]
+pub struct RelatedProduct {
+    product_id: Uuid,
+    name: String,
+    price: Decimal,
+    is_available: Option<bool>,
+}
+
+// quantity price break, lines of at least min_quantity units pay this price per unit
+#[derive(Serialize, Deserialize, FromRow)]
+pub struct PriceTier {
+    min_quantity: i32,
+    price: Decimal,
+}
+
+// one product of a kit's bill of materials
+#[derive(Serialize, Deserialize, FromRow)]
+pub struct KitComponent {
+    product_id: Uuid,
+    quantity: i32,
+}
+
+// URLs of an image per size, list views should use the thumbnail.
+// Until the resize job has run every size is the original
+#[derive(Serialize, Clone)]
+struct ImageSizes {
+    thumbnail: String,
+    medium: String,
+    full: String,
+}
+
+impl ImageSizes {
+    fn new(
+        media: &MediaUrls,
+        object_key: &str,
+        thumbnail_key: Option<&str>,
+        medium_key: Option<&str>,
+    ) -> Self {
+        ImageSizes {
+            thumbnail: media.url(thumbnail_key.or(medium_key).unwrap_or(object_key)),
+            medium: media.url(medium_key.unwrap_or(object_key)),
+            full: media.url(object_key),
+        }
+    }
+}
+
+// a product photo, urls expire when media URLs are signed
+#[derive(Serialize)]
+pub struct ProductImage {
+    image_id: Uuid,
+    position: i32,
+    sizes: ImageSizes,
+}
+
+// a product in the catalogue list with its first image
+#[derive(Serialize, Clone)]
+pub struct ProductListItem {
+    #[serde(flatten)]
+    product: ProductResponse,
+    image: Option<ImageSizes>,
+}
+
+// ?category=Toys narrows the catalogue list to one category
+#[derive(Deserialize)]
+pub struct ListingFilter {
+    category: Option<String>,
+}
+
+// published products are in the catalogue, unpublished ones only in the
+// admin list until they are made available
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum PublishStatus {
+    Published,
+    Unpublished,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ProductSort {
+    Name,
+    Price,
+    Stock,
+    CreatedAt,
+    #[default]
+    UpdatedAt,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+// the admin list with everything the catalogue leaves out, narrowed by
+// ?status=, ?category= and stock between ?min_stock= and ?max_stock=
+// (max_stock=0 is what sold out), ordered by ?sort= name, price, stock,
+// created_at or updated_at (the default) and ?order=asc or desc. Stores are
+// the sellers here, admins of every store pick another seller's products with
+// ?store_id=
+#[derive(Deserialize)]
+pub struct AdminProductFilter {
+    status: Option<PublishStatus>,
+    category: Option<String>,
+    min_stock: Option<Decimal>,
+    max_stock: Option<Decimal>,
+    store_id: Option<Uuid>,
+    #[serde(default)]
+    sort: ProductSort,
+    #[serde(default)]
+    order: SortOrder,
+}
+
+// ?q=cof&limit=5 for the search box's suggestions
+#[derive(Deserialize)]
+pub struct SuggestQuery {
+    q: String,
+    limit: Option<i64>,
+}
+
+// ?q=travel mug for a product search
+#[derive(Deserialize)]
+pub struct SearchFilter {
+    q: String,
+}
+
+// a completion for what has been typed so far, products carry their id so the
+// storefront can go straight to them
+#[derive(Serialize, Debug)]
+pub struct Suggestion {
+    text: String,
+    kind: String,
+    product_id: Option<Uuid>,
+}
+
+// products go out this many at a time when the list is asked for as CSV
+const EXPORT_CHUNK: i64 = 500;
+
+// where a CSV listing got to, it continues after the last product sent
+struct ExportCursor {
+    pool: PgPool,
+    timings: Arc<QueryStats>,
+    store_id: Uuid,
+    user_id: Uuid,
+    group: Option<CustomerGroup>,
+    category: Option<String>,
+    after: Option<(String, Uuid)>,
+    started: bool,
+    finished: bool,
+}
+
+// a cached page of the catalogue list
+#[derive(Hash, PartialEq, Eq)]
+pub struct ListingKey {
+    store_id: Uuid,
+    group: CustomerGroup,
+    // the group whose products the listing shows, none for staff
+    visible_to: Option<CustomerGroup>,
+    category: Option<String>,
+    page: i64,
+    per_page: i64,
+}
+
+pub type ProductListings = Cached<ListingKey, Page<ProductListItem>>;
+
+impl ProductListings {
+    // PRODUCT_CACHE_TTL_SECS (default 30, 0 turns caching off) is how long a
+    // listing may be served without asking the database, stock counts included.
+    // Writes on any instance drop the listings of all of them
+    pub fn from_env(pool: PgPool) -> Self {
+        let ttl = std::env::var("PRODUCT_CACHE_TTL_SECS")
+            .ok()
+            .map(|value| {
+                value
+                    .parse()
+                    .expect("PRODUCT_CACHE_TTL_SECS must be a number")
+            })
+            .unwrap_or(30);
+        Cached::new("product_listings", Duration::from_secs(ttl), 10_000).shared(pool)
+    }
+}
+
+#[derive(Deserialize)]
+struct ImageUploadQuery {
+    #[serde(default)]
+    position: i32,
+}
+
+#[derive(Deserialize)]
+pub struct ProductImageBody {
+    // key of an object already in the media bucket
+    object_key: String,
+    #[serde(default)]
+    position: i32,
+}
+
+// product detail with its images, pinned cross-sells, quantity price breaks and,
+// for kits, the components its stock comes from
+#[derive(Serialize)]
+pub struct ProductDetail {
+    #[serde(flatten)]
+    product: ProductResponse,
+    images: Vec<ProductImage>,
+    price_tiers: Vec<PriceTier>,
+    related: Vec<RelatedProduct>,
+    components: Vec<KitComponent>,
+}
+
+#[derive(Deserialize)]
+struct RelatedBody {
+    product_ids: Vec<Uuid>,
+}
+
+// one row of a bulk update, omitted fields are left unchanged
+#[derive(Deserialize)]
+pub struct BulkProductChange {
+    product_id: Uuid,
+    price: Option<Decimal>,
+    is_available: Option<bool>,
+    stock_delta: Option<Decimal>,
+}
+
+#[derive(Serialize)]
+pub struct BulkRowError {
+    row: usize,
+    product_id: Uuid,
+    error: String,
+}
+
+// beyond what the price and stock columns hold, DECIMAL(10, 2) and
+// DECIMAL(12, 3), once rounded to their scale
+fn price_out_of_range(price: Decimal) -> bool {
+    price.round_dp(2) >= Decimal::new(100_000_000, 0)
+}
+
+fn stock_out_of_range(stock: Decimal) -> bool {
+    stock.round_dp(3) >= Decimal::new(1_000_000_000, 0)
+}
+
+pub enum BulkOutcome {
+    Applied(Vec<Product>),
+    Invalid(Vec<BulkRowError>),
+}
+
+// text to match as it is in a LIKE pattern
+fn like_escaped(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+impl Product {
+    // names and categories of available products containing the text, the
+    // ones that start with it first, then the closest matches
+    async fn suggest(
+        pool: &PgPool,
+        store_id: Uuid,
+        group: Option<CustomerGroup>,
+        q: &str,
+        limit: i64,
+    ) -> Result<Vec<Suggestion>, sqlx::Error> {
+        let q = q.to_lowercase();
+        let escaped = like_escaped(&q);
+        sqlx::query_as!(
+            Suggestion,
+            r#"
+            SELECT text as "text!", kind as "kind!", product_id
+            FROM (
+                SELECT name as text, 'product'::text as kind, product_id,
+                       lower(name) LIKE $2 as prefix, word_similarity($1, lower(name)) as score
+                FROM products
+                WHERE store_id = $5 AND is_available IS NOT FALSE AND lower(name) LIKE $3
+                    AND visible_to(product_id, $6)
+                UNION ALL
+                SELECT category, 'category'::text, NULL::uuid,
+                       lower(category) LIKE $2, word_similarity($1, lower(category))
+                FROM products
+                WHERE store_id = $5 AND is_available IS NOT FALSE AND lower(category) LIKE $3
+                    AND visible_to(product_id, $6)
+                GROUP BY category
+            ) suggestions
+            ORDER BY prefix DESC, score DESC, text
+            LIMIT $4
+            "#,
+            q,
+            format!("{escaped}%"),
+            format!("%{escaped}%"),
+            limit,
+            store_id,
+            group as Option<CustomerGroup>
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    // available products named or categorised with the text, priced for the
+    // viewer's customer group. What search falls back to without a search
+    // engine, matched like suggestions are
+    async fn search_products(
+        pool: &PgPool,
+        store_id: Uuid,
+        user_id: Uuid,
+        group: Option<CustomerGroup>,
+        q: &str,
+        page: &PageQuery,
+    ) -> Result<Page<Product>, sqlx::Error> {
+        let q = q.to_lowercase();
+        let escaped = like_escaped(&q);
+        let items = sqlx::query_as!(
+            Product,
+            r#"
+            SELECT name, description, unit_price(product_id, 1, $1) as "price!",
+                   product_stock(product_id) as "stock_quantity!",
+                   category, is_available, created_at, product_id,
+                   unit as "unit: ProductUnit", quantity_step, weight_kg, length_cm, width_cm,
+                   height_cm, updated_at, age_restriction, purchase_limit
+            FROM products
+            WHERE store_id = $7 AND is_available IS NOT FALSE
+                AND (lower(name) LIKE $2 OR lower(category) LIKE $2)
+                AND visible_to(product_id, $8)
+            ORDER BY lower(name) LIKE $3 DESC, word_similarity($4, lower(name)) DESC,
+                     name, product_id
+            LIMIT $5 OFFSET $6
+            "#,
+            user_id,
+            format!("%{escaped}%"),
+            format!("{escaped}%"),
+            q,
+            page.per_page(),
+            page.offset(),
+            store_id,
+            group as Option<CustomerGroup>
+        )
+        .fetch_all(pool)
+        .await?;
+        let total = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) as "count!" FROM products
+            WHERE store_id = $2 AND is_available IS NOT FALSE
+                AND (lower(name) LIKE $1 OR lower(category) LIKE $1)
+                AND visible_to(product_id, $3)"#,
+            format!("%{escaped}%"),
+            store_id,
+            group as Option<CustomerGroup>
+        )
+        .fetch_one(pool)
+        .await?;
+        Ok(Page { items, total })
+    }
+
+    // the products a search engine found, in the order it ranked them. Ones
+    // deleted since they were indexed, in another store or kept from the
+    // viewer's group are left out
+    // which of the products are available in the store to the viewer's
+    // customer group, in the order given
+    async fn visible_ids(
+        pool: &PgPool,
+        store_id: Uuid,
+        group: Option<CustomerGroup>,
+        product_ids: &[Uuid],
+    ) -> Result<Vec<Uuid>, sqlx::Error> {
+        sqlx::query_scalar!(
+            "SELECT product_id FROM products
+            WHERE product_id = ANY($1) AND store_id = $2 AND is_available IS NOT FALSE
+                AND visible_to(product_id, $3)
+            ORDER BY array_position($1, product_id)",
+            product_ids,
+            store_id,
+            group as Option<CustomerGroup>
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    async fn get_by_ids(
+        pool: &PgPool,
+        store_id: Uuid,
+        user_id: Uuid,
+        group: Option<CustomerGroup>,
+        product_ids: &[Uuid],
+    ) -> Result<Vec<Product>, sqlx::Error> {
+        sqlx::query_as!(
+            Product,
+            r#"
+            SELECT name, description, unit_price(product_id, 1, $1) as "price!",
+                   product_stock(product_id) as "stock_quantity!",
+                   category, is_available, created_at, product_id,
+                   unit as "unit: ProductUnit", quantity_step, weight_kg, length_cm, width_cm,
+                   height_cm, updated_at, age_restriction, purchase_limit
+            FROM products
+            WHERE product_id = ANY($2) AND store_id = $3 AND visible_to(product_id, $4)
+            ORDER BY array_position($2, product_id)
+            "#,
+            user_id,
+            product_ids,
+            store_id,
+            group as Option<CustomerGroup>
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    // impl to get all products from db the viewer's customer group may see,
+    // priced for it. Unpublished ones are left out unless an admin, who has no
+    // group, is looking
+    async fn get_products(
+        pool: &PgPool,
+        store_id: Uuid,
+        user_id: Uuid,
+        group: Option<CustomerGroup>,
+        category: Option<&str>,
+        page: &PageQuery,
+    ) -> Result<Page<Product>, sqlx::Error> {
+        let items = sqlx::query_as!(
+            Product,
+            r#"
+            SELECT name, description, unit_price(product_id, 1, $1) as "price!",
+                   product_stock(product_id) as "stock_quantity!",
+                   category, is_available, created_at, product_id,
+                   unit as "unit: ProductUnit", quantity_step, weight_kg, length_cm, width_cm,
+                   height_cm, updated_at, age_restriction, purchase_limit
+            FROM products
+            WHERE store_id = $5 AND ($4::text IS NULL OR category = $4)
+                AND visible_to(product_id, $6)
+                AND ($6::customer_group IS NULL OR is_available IS NOT FALSE)
+            ORDER BY name, product_id
+            LIMIT $2 OFFSET $3;
+            "#,
+            user_id,
+            page.per_page(),
+            page.offset(),
+            category,
+            store_id,
+            group as Option<CustomerGroup>
+        )
+        .fetch_all(pool)
+        .await?;
+        let total = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) as "count!" FROM products
+            WHERE store_id = $2 AND ($1::text IS NULL OR category = $1)
+                AND visible_to(product_id, $3)
+                AND ($3::customer_group IS NULL OR is_available IS NOT FALSE)"#,
+            category,
+            store_id,
+            group as Option<CustomerGroup>
+        )
+        .fetch_one(pool)
+        .await?;
+        Ok(Page { items, total })
+    }
+
+    // the group whose prices the user sees, retail for anyone unknown
+    async fn pricing_group(pool: &PgPool, user_id: Uuid) -> Result<CustomerGroup, sqlx::Error> {
+        let group = sqlx::query_scalar!(
+            r#"SELECT customer_group as "customer_group: CustomerGroup" FROM users
+            WHERE user_id = $1"#,
+            user_id
+        )
+        .fetch_optional(pool)
+        .await?;
+        Ok(group.unwrap_or(CustomerGroup::Retail))
+    }
+
+    // whether none of the products belong to another store. Ones that don't
+    // exist are left to the query that needs them
+    async fn in_store(
+        pool: &PgPool,
+        store_id: Uuid,
+        product_ids: &[Uuid],
+    ) -> Result<bool, sqlx::Error> {
+        sqlx::query_scalar!(
+            r#"SELECT NOT EXISTS (
+                SELECT 1 FROM products WHERE product_id = ANY($1) AND store_id <> $2
+            ) as "in_store!""#,
+            product_ids,
+            store_id
+        )
+        .fetch_one(pool)
+        .await
+    }
+
+    // get single product detail, priced for the viewer's customer group. None
+    // when the product is kept from that group
+    async fn get_product_by_id(
+        pool: &PgPool,
+        product_id: Uuid,
+        user_id: Uuid,
+        group: Option<CustomerGroup>,
+    ) -> Result<Option<Product>, sqlx::Error> {
+        sqlx::query_as!(
+            Product,
+            r#"
+        SELECT name, description, unit_price(product_id, 1, $2) as "price!",
+               product_stock(product_id) as "stock_quantity!",
+               category, is_available, created_at, product_id,
+               unit as "unit: ProductUnit", quantity_step, weight_kg, length_cm, width_cm,
+               height_cm, updated_at, age_restriction, purchase_limit
+        FROM products WHERE product_id = $1 AND visible_to(product_id, $3);
+        "#,
+            product_id,
+            user_id,
+            group as Option<CustomerGroup>
+        )
+        .fetch_optional(pool)
+        .await
+    }
+
+    // a product with everything its page shows
+    async fn get_detail(
+        pool: &PgPool,
+        media: &MediaUrls,
+        product_id: Uuid,
+        user_id: Uuid,
+        group: Option<CustomerGroup>,
+    ) -> Result<Option<ProductDetail>, sqlx::Error> {
+        let Some(product) = Product::get_product_by_id(pool, product_id, user_id, group).await?
+        else {
+            return Ok(None);
+        };
+        Ok(Some(ProductDetail {
+            product: product.into(),
+            images: Product::get_images(pool, media, product_id).await?,
+            price_tiers: Product::get_price_tiers_for(pool, product_id, user_id).await?,
+            related: Product::get_related(pool, product_id, user_id, group).await?,
+            components: Product::get_components(pool, product_id).await?,
+        }))
+    }
+
+    // create product
+    async fn create_product(
+        pool: &PgPool,
+        store_id: Uuid,
+        new_product: ProductBody,
+    ) -> Result<Product, sqlx::Error> {
+        new_product.validate()?;
+        sqlx::query_as!(Product, r#"INSERT INTO products (name, description, price, stock_quantity, unit, quantity_step, weight_kg, length_cm, width_cm, height_cm, store_id, age_restriction, purchase_limit) VALUES ($1, $2, $3, $4, $5, COALESCE($6::DECIMAL, 1), $7, $8, $9, $10, $11, $12, $13)
+            RETURNING name, description, price, stock_quantity, category, is_available, created_at,
+                product_id, unit as "unit: ProductUnit", quantity_step, weight_kg, length_cm,
+                width_cm, height_cm, updated_at, age_restriction, purchase_limit"#,
+        new_product.name, new_product.description, new_product.price, new_product.stock_quantity,
+        new_product.unit.unwrap_or_default() as ProductUnit, new_product.quantity_step,
+        new_product.weight_kg, new_product.length_cm, new_product.width_cm, new_product.height_cm,
+        store_id, new_product.age_restriction, new_product.purchase_limit
+    )
+        .fetch_one(pool)
+        .await
+    }
+
+    // delete product
+    async fn delete_product(
+        pool: &PgPool,
+        product_id: Uuid,
+        precondition: &Precondition,
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        if Product::lock_version(&mut tx, product_id, precondition).await? {
+            sqlx::query!("DELETE FROM products WHERE product_id = $1", product_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await
+    }
+
+    // lock the product until the transaction ends and check the client's
+    // precondition against the version it has, false when there is no such
+    // product
+    async fn lock_version(
+        conn: &mut PgConnection,
+        product_id: Uuid,
+        precondition: &Precondition,
+    ) -> Result<bool, sqlx::Error> {
+        let updated_at = sqlx::query_scalar!(
+            "SELECT updated_at FROM products WHERE product_id = $1 FOR UPDATE",
+            product_id
+        )
+        .fetch_optional(conn)
+        .await?;
+        match updated_at {
+            Some(updated_at) => precondition.check(updated_at).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    // edit product
+    async fn edit_product_by_id(
+        pool: &PgPool,
+        product_id: Uuid,
+        new_product: ProductBody,
+        precondition: &Precondition,
+    ) -> Result<Option<Product>, sqlx::Error> {
+        new_product.validate()?;
+        let mut tx = pool.begin().await?;
+        if !Product::lock_version(&mut tx, product_id, precondition).await? {
+            return Ok(None);
+        }
+        let product = sqlx::query_as!(
+            Product,
+            r#"UPDATE products 
+            SET name = $1, description = $2,
+            price = $3, stock_quantity = $4,
+            unit = COALESCE($5, unit), quantity_step = COALESCE($6, quantity_step),
+            weight_kg = COALESCE($7, weight_kg), length_cm = COALESCE($8, length_cm),
+            width_cm = COALESCE($9, width_cm), height_cm = COALESCE($10, height_cm),
+            age_restriction = COALESCE($12, age_restriction),
+            purchase_limit = COALESCE($13, purchase_limit)
+            WHERE product_id = $11
+            RETURNING name, description, price, stock_quantity, category, is_available, created_at,
+                product_id, unit as "unit: ProductUnit", quantity_step, weight_kg, length_cm,
+                width_cm, height_cm, updated_at, age_restriction, purchase_limit
+            "#,
+            new_product.name,
+            new_product.description,
+            new_product.price,
+            new_product.stock_quantity,
+            new_product.unit as Option<ProductUnit>,
+            new_product.quantity_step,
+            new_product.weight_kg,
+            new_product.length_cm,
+            new_product.width_cm,
+            new_product.height_cm,
+            product_id,
+            new_product.age_restriction,
+            new_product.purchase_limit
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(Some(product))
+    }
+
+    // update only the columns the patch has, None when there is no such product
+    async fn patch_product(
+        pool: &PgPool,
+        product_id: Uuid,
+        patch: ProductPatch,
+        precondition: &Precondition,
+    ) -> Result<Option<Product>, sqlx::Error> {
+        patch.validate()?;
+        let mut tx = pool.begin().await?;
+        if !Product::lock_version(&mut tx, product_id, precondition).await? {
+            return Ok(None);
+        }
+        let mut query = QueryBuilder::<Postgres>::new("UPDATE products SET ");
+        let mut columns = query.separated(", ");
+        if let Some(name) = patch.name {
+            columns.push("name = ").push_bind_unseparated(name);
+        }
+        if let Some(description) = patch.description {
+            columns
+                .push("description = ")
+                .push_bind_unseparated(description);
+        }
+        if let Some(category) = patch.category {
+            columns.push("category = ").push_bind_unseparated(category);
+        }
+        if let Some(price) = patch.price {
+            columns.push("price = ").push_bind_unseparated(price);
+        }
+        if let Some(stock_quantity) = patch.stock_quantity {
+            columns
+                .push("stock_quantity = ")
+                .push_bind_unseparated(stock_quantity);
+        }
+        if let Some(is_available) = patch.is_available {
+            columns
+                .push("is_available = ")
+                .push_bind_unseparated(is_available);
+        }
+        if let Some(unit) = patch.unit {
+            columns.push("unit = ").push_bind_unseparated(unit);
+        }
+        if let Some(quantity_step) = patch.quantity_step {
+            columns
+                .push("quantity_step = ")
+                .push_bind_unseparated(quantity_step);
+        }
+        if let Some(age_restriction) = patch.age_restriction {
+            columns
+                .push("age_restriction = ")
+                .push_bind_unseparated(age_restriction);
+        }
+        if let Some(purchase_limit) = patch.purchase_limit {
+            columns
+                .push("purchase_limit = ")
+                .push_bind_unseparated(purchase_limit);
+        }
+        for (column, value) in [
+            ("weight_kg = ", patch.weight_kg),
+            ("length_cm = ", patch.length_cm),
+            ("width_cm = ", patch.width_cm),
+            ("height_cm = ", patch.height_cm),
+        ] {
+            if let Some(value) = value {
+                columns.push(column).push_bind_unseparated(value);
+            }
+        }
+        // an empty patch still answers with the product
+        columns.push("product_id = product_id");
+
+        let product = query
+            .push(" WHERE product_id = ")
+            .push_bind(product_id)
+            .push(
+                " RETURNING name, description, price, stock_quantity, category, is_available,
+                created_at, product_id, unit, quantity_step, weight_kg, length_cm, width_cm,
+                height_cm, updated_at, age_restriction, purchase_limit",
+            )
+            .build_query_as::<Product>()
+            .fetch_one(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(Some(product))
+    }
+
+    // pinned cross-sells of a product the viewer may see, in display order and
+    // priced for them
+    async fn get_related(
+        pool: &PgPool,
+        product_id: Uuid,
+        user_id: Uuid,
+        group: Option<CustomerGroup>,
+    ) -> Result<Vec<RelatedProduct>, sqlx::Error> {
+        sqlx::query_as!(
+            RelatedProduct,
+            r#"SELECT p.product_id, p.name, unit_price(p.product_id, 1, $2) as "price!",
+                p.is_available
+            FROM related_products r
+            JOIN products p ON p.product_id = r.related_product_id
+            WHERE r.product_id = $1 AND visible_to(p.product_id, $3)
+            ORDER BY r.position"#,
+            product_id,
+            user_id,
+            group as Option<CustomerGroup>
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    // replace the pinned cross-sells, keeping the order they were given in
+    async fn set_related(
+        pool: &PgPool,
+        product_id: Uuid,
+        related_ids: &[Uuid],
+    ) -> Result<Vec<RelatedProduct>, sqlx::Error> {
+        if related_ids.contains(&product_id) {
+            return Err(sqlx::Error::Protocol(
+                "A product cannot be related to itself".into(),
+            ));
+        }
+
+        let mut tx = pool.begin().await?;
+
+        sqlx::query!(
+            "DELETE FROM related_products WHERE product_id = $1",
+            product_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            "INSERT INTO related_products (product_id, related_product_id, position)
+            SELECT $1, related.id, related.position
+            FROM UNNEST($2::uuid[]) WITH ORDINALITY AS related(id, position)
+            ON CONFLICT DO NOTHING",
+            product_id,
+            related_ids
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        // the nil id matches no customer, admins get list prices back and
+        // every product
+        Product::get_related(pool, product_id, Uuid::nil(), None).await
+    }
+
+    // price breaks of a product, smallest quantity first
+    async fn get_price_tiers(
+        pool: &PgPool,
+        product_id: Uuid,
+    ) -> Result<Vec<PriceTier>, sqlx::Error> {
+        sqlx::query_as!(
+            PriceTier,
+            "SELECT min_quantity, price FROM price_tiers
+            WHERE product_id = $1 ORDER BY min_quantity",
+            product_id
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    // the price breaks as the viewer pays them, none when their group has a fixed price
+    async fn get_price_tiers_for(
+        pool: &PgPool,
+        product_id: Uuid,
+        user_id: Uuid,
+    ) -> Result<Vec<PriceTier>, sqlx::Error> {
+        sqlx::query_as!(
+            PriceTier,
+            r#"SELECT min_quantity, unit_price(product_id, min_quantity, $2) as "price!"
+            FROM price_tiers t
+            WHERE product_id = $1 AND NOT EXISTS (
+                SELECT 1 FROM group_prices gp
+                JOIN users u ON u.customer_group = gp.customer_group
+                WHERE gp.product_id = t.product_id AND u.user_id = $2
+            )
+            ORDER BY min_quantity"#,
+            product_id,
+            user_id
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    // replace the price breaks, an empty list goes back to the flat price
+    async fn set_price_tiers(
+        pool: &PgPool,
+        product_id: Uuid,
+        tiers: &[PriceTier],
+    ) -> Result<Vec<PriceTier>, sqlx::Error> {
+        if tiers.iter().any(|tier| tier.min_quantity < 2) {
+            return Err(sqlx::Error::Protocol(
+                "Tiers start at a quantity of 2, the product price covers single units".into(),
+            ));
+        }
+        if tiers.iter().any(|tier| tier.price < Decimal::ZERO) {
+            return Err(sqlx::Error::Protocol(
+                "Tier prices can't be negative".into(),
+            ));
+        }
+
+        let mut tx = pool.begin().await?;
+
+        sqlx::query!("DELETE FROM price_tiers WHERE product_id = $1", product_id)
+            .execute(&mut *tx)
+            .await?;
+
+        let (quantities, prices): (Vec<i32>, Vec<Decimal>) = tiers
+            .iter()
+            .map(|tier| (tier.min_quantity, tier.price))
+            .unzip();
+        sqlx::query!(
+            "INSERT INTO price_tiers (product_id, min_quantity, price)
+            SELECT $1, tier.min_quantity, tier.price
+            FROM UNNEST($2::int[], $3::numeric[]) AS tier(min_quantity, price)",
+            product_id,
+            &quantities,
+            &prices
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Product::get_price_tiers(pool, product_id).await
+    }
+
+    // the next chunk of the catalogue as CSV, the products the listing has in
+    // its order and priced for the viewer. None once every product was sent
+    async fn export_chunk(cursor: &mut ExportCursor) -> Result<Option<String>, sqlx::Error> {
+        if cursor.finished {
+            return Ok(None);
+        }
+        let mut chunk = String::new();
+        if !cursor.started {
+            cursor.started = true;
+            chunk.push_str(&csv::row(&[
+                "product_id",
+                "name",
+                "description",
+                "category",
+                "price",
+                "stock_quantity",
+                "unit",
+                "quantity_step",
+                "is_available",
+                "weight_kg",
+                "length_cm",
+                "width_cm",
+                "height_cm",
+                "created_at",
+            ]));
+        }
+
+        let (after_name, after_product) = cursor.after.clone().unzip();
+        let products = cursor
+            .timings
+            .time(
+                "products.export",
+                sqlx::query_as!(
+                    Product,
+                    r#"SELECT name, description, unit_price(product_id, 1, $1) as "price!",
+                        product_stock(product_id) as "stock_quantity!",
+                        category, is_available, created_at, product_id,
+                        unit as "unit: ProductUnit", quantity_step, weight_kg, length_cm, width_cm,
+                        height_cm, updated_at, age_restriction, purchase_limit
+                    FROM products
+                    WHERE ($2::text IS NULL OR category = $2)
+                        AND ($3::text IS NULL OR (name, product_id) > ($3, $4::uuid))
+                        AND store_id = $6 AND visible_to(product_id, $7)
+                        AND ($7::customer_group IS NULL OR is_available IS NOT FALSE)
+                    ORDER BY name, product_id
+                    LIMIT $5"#,
+                    cursor.user_id,
+                    cursor.category,
+                    after_name,
+                    after_product,
+                    EXPORT_CHUNK,
+                    cursor.store_id,
+                    cursor.group as Option<CustomerGroup>
+                )
+                .fetch_all(&cursor.pool),
+            )
+            .await?;
+
+        let optional = |value: Option<Decimal>| value.map(|v| v.to_string()).unwrap_or_default();
+        for product in &products {
+            chunk.push_str(&csv::row(&[
+                product.product_id.to_string(),
+                product.name.clone(),
+                product.description.clone().unwrap_or_default(),
+                product.category.clone().unwrap_or_default(),
+                product.price.to_string(),
+                product.stock_quantity.normalize().to_string(),
+                product.unit.as_str().to_string(),
+                product.quantity_step.normalize().to_string(),
+                product
+                    .is_available
+                    .map(|available| available.to_string())
+                    .unwrap_or_default(),
+                optional(product.weight_kg),
+                optional(product.length_cm),
+                optional(product.width_cm),
+                optional(product.height_cm),
+                product
+                    .created_at
+                    .map(|created| created.to_rfc3339())
+                    .unwrap_or_default(),
+            ]));
+        }
+        match products.last() {
+            Some(last) if products.len() as i64 == EXPORT_CHUNK => {
+                cursor.after = Some((last.name.clone(), last.product_id));
+            }
+            _ => cursor.finished = true,
+        }
+
+        // the last chunk was full and nothing came after it
+        if chunk.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(chunk))
+    }
+
+    // the store's products as admins manage them, unpublished ones and the
+    // ones kept for some customer groups included, at their base price
+    async fn get_admin_list(
+        pool: &PgPool,
+        store_id: Uuid,
+        filter: &AdminProductFilter,
+        page: &PageQuery,
+    ) -> Result<Page<Product>, sqlx::Error> {
+        fn narrow(query: &mut QueryBuilder<Postgres>, store_id: Uuid, filter: &AdminProductFilter) {
+            query.push(" WHERE store_id = ").push_bind(store_id);
+            match filter.status {
+                Some(PublishStatus::Published) => {
+                    query.push(" AND is_available IS NOT FALSE");
+                }
+                Some(PublishStatus::Unpublished) => {
+                    query.push(" AND is_available IS FALSE");
+                }
+                None => {}
+            }
+            if let Some(category) = &filter.category {
+                query.push(" AND category = ").push_bind(category.clone());
+            }
+            if let Some(min_stock) = filter.min_stock {
+                query
+                    .push(" AND product_stock(product_id) >= ")
+                    .push_bind(min_stock);
+            }
+            if let Some(max_stock) = filter.max_stock {
+                query
+                    .push(" AND product_stock(product_id) <= ")
+                    .push_bind(max_stock);
+            }
+        }
+
+        let mut query = QueryBuilder::<Postgres>::new(
+            "SELECT name, description, price, product_stock(product_id) as stock_quantity,
+                category, is_available, created_at, product_id, unit, quantity_step, weight_kg,
+                length_cm, width_cm, height_cm, updated_at, age_restriction, purchase_limit
+            FROM products",
+        );
+        narrow(&mut query, store_id, filter);
+        let column = match filter.sort {
+            ProductSort::Name => "lower(name)",
+            ProductSort::Price => "price",
+            ProductSort::Stock => "product_stock(product_id)",
+            ProductSort::CreatedAt => "created_at",
+            ProductSort::UpdatedAt => "updated_at",
+        };
+        let order = match filter.order {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        };
+        let items = query
+            .push(format!(
+                " ORDER BY {column} {order} NULLS LAST, product_id LIMIT "
+            ))
+            .push_bind(page.per_page())
+            .push(" OFFSET ")
+            .push_bind(page.offset())
+            .build_query_as::<Product>()
+            .fetch_all(pool)
+            .await?;
+
+        let mut count = QueryBuilder::<Postgres>::new("SELECT COUNT(*) FROM products");
+        narrow(&mut count, store_id, filter);
+        let total = count.build_query_scalar().fetch_one(pool).await?;
+        Ok(Page { items, total })
+    }
+
+    // the catalogue with each product's first image
+    async fn get_list(
+        pool: &PgPool,
+        media: &MediaUrls,
+        store_id: Uuid,
+        user_id: Uuid,
+        group: Option<CustomerGroup>,
+        category: Option<&str>,
+        page: &PageQuery,
+    ) -> Result<Page<ProductListItem>, sqlx::Error> {
+        let Page {
+            items: products,
+            total,
+        } = Product::get_products(pool, store_id, user_id, group, category, page).await?;
+        let items = Product::with_images(pool, media, products).await?;
+        Ok(Page { items, total })
+    }
+
+    // list items for products, each with its first image
+    async fn with_images(
+        pool: &PgPool,
+        media: &MediaUrls,
+        products: Vec<Product>,
+    ) -> Result<Vec<ProductListItem>, sqlx::Error> {
+        let product_ids: Vec<Uuid> = products.iter().map(|p| p.product_id).collect();
+        let images = sqlx::query!(
+            "SELECT DISTINCT ON (product_id) product_id, object_key, thumbnail_key, medium_key
+            FROM product_images WHERE product_id = ANY($1)
+            ORDER BY product_id, position, created_at",
+            &product_ids
+        )
+        .fetch_all(pool)
+        .await?;
+
+        let items = products
+            .into_iter()
+            .map(|product| ProductListItem {
+                image: images
+                    .iter()
+                    .find(|image| image.product_id == product.product_id)
+                    .map(|image| {
+                        ImageSizes::new(
+                            media,
+                            &image.object_key,
+                            image.thumbnail_key.as_deref(),
+                            image.medium_key.as_deref(),
+                        )
+                    }),
+                product: product.into(),
+            })
+            .collect();
+        Ok(items)
+    }
+
+    async fn get_images(
+        pool: &PgPool,
+        media: &MediaUrls,
+        product_id: Uuid,
+    ) -> Result<Vec<ProductImage>, sqlx::Error> {
+        let rows = sqlx::query!(
+            "SELECT image_id, position, object_key, thumbnail_key, medium_key FROM product_images
+            WHERE product_id = $1 ORDER BY position, created_at",
+            product_id
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| ProductImage {
+                image_id: row.image_id,
+                position: row.position,
+                sizes: ImageSizes::new(
+                    media,
+                    &row.object_key,
+                    row.thumbnail_key.as_deref(),
+                    row.medium_key.as_deref(),
+                ),
+            })
+            .collect())
+    }
+
+    // store an uploaded image, the resize job makes the smaller sizes from it
+    async fn upload_image(
+        pool: &PgPool,
+        media: &MediaUrls,
+        storage: &dyn Storage,
+        product_id: Uuid,
+        position: i32,
+        bytes: &[u8],
+    ) -> Result<ProductImage, sqlx::Error> {
+        let format = upload_format(bytes)
+            .ok_or_else(|| sqlx::Error::Protocol("Upload a JPEG, PNG or WebP image".into()))?;
+        let exists = sqlx::query!(
+            r#"SELECT EXISTS(SELECT 1 FROM products WHERE product_id = $1) as "exists!""#,
+            product_id
+        )
+        .fetch_one(pool)
+        .await?;
+        if !exists.exists {
+            return Err(sqlx::Error::RowNotFound);
+        }
+
+        let image_id = Uuid::new_v4();
+        let object_key = format!(
+            "products/{product_id}/{image_id}.{}",
+            format.extensions_str()[0]
+        );
+        storage
+            .put(&object_key, bytes.to_vec(), format.to_mime_type())
+            .await?;
+
+        sqlx::query!(
+            "INSERT INTO product_images (image_id, product_id, object_key, position)
+            VALUES ($1, $2, $3, $4)",
+            image_id,
+            product_id,
+            object_key,
+            position
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(ProductImage {
+            image_id,
+            position,
+            sizes: ImageSizes::new(media, &object_key, None, None),
+        })
+    }
+
+    async fn add_image(
+        pool: &PgPool,
+        media: &MediaUrls,
+        product_id: Uuid,
+        body: ProductImageBody,
+    ) -> Result<ProductImage, sqlx::Error> {
+        if !valid_object_key(&body.object_key) {
+            return Err(sqlx::Error::Protocol("Object key is not valid".into()));
+        }
+
+        let image_id = sqlx::query!(
+            "INSERT INTO product_images (product_id, object_key, position) VALUES ($1, $2, $3)
+            RETURNING image_id",
+            product_id,
+            body.object_key,
+            body.position
+        )
+        .fetch_one(pool)
+        .await?
+        .image_id;
+
+        Ok(ProductImage {
+            image_id,
+            position: body.position,
+            sizes: ImageSizes::new(media, &body.object_key, None, None),
+        })
+    }
+
+    // uploaded images are deleted from storage with their sizes once no copy of
+    // the product shows them, objects added by key were put there by someone
+    // else and stay
+    async fn remove_image(
+        pool: &PgPool,
+        storage: &dyn Storage,
+        product_id: Uuid,
+        image_id: Uuid,
+    ) -> Result<bool, sqlx::Error> {
+        let Some(image) = sqlx::query!(
+            "DELETE FROM product_images WHERE image_id = $1 AND product_id = $2
+            RETURNING object_key",
+            image_id,
+            product_id
+        )
+        .fetch_optional(pool)
+        .await?
+        else {
+            return Ok(false);
+        };
+
+        let shared = sqlx::query!(
+            r#"SELECT EXISTS(SELECT 1 FROM product_images WHERE object_key = $1) as "exists!""#,
+            image.object_key
+        )
+        .fetch_one(pool)
+        .await?
+        .exists;
+        // a copy shows the objects uploaded for the product it was made from
+        let uploaded = image
+            .object_key
+            .strip_prefix("products/")
+            .and_then(|rest| rest.split_once('/'))
+            .is_some_and(|(owner, _)| Uuid::parse_str(owner).is_ok());
+        if uploaded && !shared {
+            let sizes = SIZES
+                .iter()
+                .map(|(size, _)| sized_key(&image.object_key, size));
+            for key in std::iter::once(image.object_key.clone()).chain(sizes) {
+                // the row is gone either way, a leftover file only costs space
+                if let Err(err) = storage.delete(&key).await {
+                    println!("deleting {key} failed: {err}");
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    async fn get_components(
+        pool: &PgPool,
+        product_id: Uuid,
+    ) -> Result<Vec<KitComponent>, sqlx::Error> {
+        sqlx::query_as!(
+            KitComponent,
+            "SELECT component_id as product_id, quantity FROM kit_components WHERE kit_id = $1",
+            product_id
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    // replace a kit's bill of materials, an empty list makes it a plain product again.
+    // Kits can't be nested, a kit is never a component and a component never a kit
+    async fn set_components(
+        pool: &PgPool,
+        product_id: Uuid,
+        components: &[KitComponent],
+    ) -> Result<Vec<KitComponent>, sqlx::Error> {
+        if components.iter().any(|component| component.quantity <= 0) {
+            return Err(sqlx::Error::Protocol(
+                "Component quantities must be positive".into(),
+            ));
+        }
+        if components
+            .iter()
+            .any(|component| component.product_id == product_id)
+        {
+            return Err(sqlx::Error::Protocol("A kit cannot contain itself".into()));
+        }
+
+        let mut tx = pool.begin().await?;
+
+        let component_ids: Vec<Uuid> = components
+            .iter()
+            .map(|component| component.product_id)
+            .collect();
+        let nested = sqlx::query!(
+            r#"SELECT EXISTS (SELECT 1 FROM kit_components WHERE kit_id = ANY($1))
+                OR ($2 AND EXISTS (SELECT 1 FROM kit_components WHERE component_id = $3))
+                as "nested!""#,
+            &component_ids,
+            !components.is_empty(),
+            product_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        if nested.nested {
+            return Err(sqlx::Error::Protocol(
+                "Kits cannot be nested in other kits".into(),
+            ));
+        }
+
+        sqlx::query!("DELETE FROM kit_components WHERE kit_id = $1", product_id)
+            .execute(&mut *tx)
+            .await?;
+
+        for component in components {
+            sqlx::query!(
+                "INSERT INTO kit_components (kit_id, component_id, quantity) VALUES ($1, $2, $3)",
+                product_id,
+                component.product_id,
+                component.quantity
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        Product::get_components(pool, product_id).await
+    }
+
+    // clone a product as an unavailable draft with no stock of its own, in the
+    // same store, with the images, price breaks, group prices and kit
+    // components it is sold with
+    async fn duplicate_product(
+        pool: &PgPool,
+        store_id: Uuid,
+        product_id: Uuid,
+    ) -> Result<Option<Product>, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        let Some(copy) = sqlx::query_as!(
+            Product,
+            r#"INSERT INTO products (
+                name, description, price, stock_quantity, category, is_available, unit,
+                quantity_step, weight_kg, length_cm, width_cm, height_cm, store_id, age_restriction,
+                purchase_limit
+            )
+            SELECT name || ' (copy)', description, price, 0, category, FALSE, unit, quantity_step,
+                weight_kg, length_cm, width_cm, height_cm, store_id, age_restriction, purchase_limit
+            FROM products WHERE product_id = $1 AND store_id = $2
+            RETURNING name, description, price, stock_quantity, category, is_available, created_at,
+                product_id, unit as "unit: ProductUnit", quantity_step, weight_kg, length_cm,
+                width_cm, height_cm, updated_at, age_restriction, purchase_limit"#,
+            product_id,
+            store_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Ok(None);
+        };
+
+        // a copy of a product kept for some groups is kept for them too
+        sqlx::query!(
+            "INSERT INTO product_visibility (product_id, customer_group)
+            SELECT $2, customer_group FROM product_visibility WHERE product_id = $1",
+            product_id,
+            copy.product_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        // the images share their objects, remove_image leaves them while another
+        // product shows them
+        sqlx::query!(
+            "INSERT INTO product_images (
+                product_id, object_key, position, thumbnail_key, medium_key, resized_at,
+                resize_error
+            )
+            SELECT $2, object_key, position, thumbnail_key, medium_key, resized_at, resize_error
+            FROM product_images WHERE product_id = $1",
+            product_id,
+            copy.product_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            "INSERT INTO price_tiers (product_id, min_quantity, price)
+            SELECT $2, min_quantity, price FROM price_tiers WHERE product_id = $1",
+            product_id,
+            copy.product_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            "INSERT INTO group_prices (product_id, customer_group, price)
+            SELECT $2, customer_group, price FROM group_prices WHERE product_id = $1",
+            product_id,
+            copy.product_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        // a kit's stock comes from its components
+        sqlx::query!(
+            "INSERT INTO kit_components (kit_id, component_id, quantity)
+            SELECT $2, component_id, quantity FROM kit_components WHERE kit_id = $1",
+            product_id,
+            copy.product_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(Some(copy))
+    }
+
+    // apply every change or none of them, reporting validation errors per row
+    async fn bulk_update(
+        pool: &PgPool,
+        store_id: Uuid,
+        changes: Vec<BulkProductChange>,
+    ) -> Result<BulkOutcome, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        let mut errors = Vec::new();
+        let mut products = Vec::new();
+
+        for (row, change) in changes.into_iter().enumerate() {
+            let error = |error: &str| BulkRowError {
+                row,
+                product_id: change.product_id,
+                error: error.to_string(),
+            };
+
+            if change.price.is_some_and(|price| price < Decimal::ZERO) {
+                errors.push(error("price must not be negative"));
+                continue;
+            }
+            if change.price.is_some_and(price_out_of_range) {
+                errors.push(error("price is too large"));
+                continue;
+            }
+
+            let current = sqlx::query!(
+                r#"SELECT stock_quantity,
+                    EXISTS (SELECT 1 FROM kit_components WHERE kit_id = $1) as "is_kit!"
+                FROM products WHERE product_id = $1 AND store_id = $2 FOR UPDATE"#,
+                change.product_id,
+                store_id
+            )
+            .fetch_optional(&mut *tx)
+            .await?;
+
+            let Some(current) = current else {
+                errors.push(error("product not found"));
+                continue;
+            };
+
+            if current.is_kit && change.stock_delta.is_some_and(|delta| !delta.is_zero()) {
+                errors.push(error("kit stock comes from its components"));
+                continue;
+            }
+
+            let stock_delta = change.stock_delta.unwrap_or_default();
+            let Some(stock) = current.stock_quantity.checked_add(stock_delta) else {
+                errors.push(error("stock would be too large"));
+                continue;
+            };
+            if stock < Decimal::ZERO {
+                errors.push(error("stock would go below zero"));
+                continue;
+            }
+            if stock_out_of_range(stock) {
+                errors.push(error("stock would be too large"));
+                continue;
+            }
+
+            let product = sqlx::query_as!(
+                Product,
+                r#"UPDATE products
+                SET price = COALESCE($1, price),
+                is_available = COALESCE($2, is_available),
+                stock_quantity = stock_quantity + $3
+                WHERE product_id = $4
+                RETURNING name, description, price, stock_quantity, category, is_available,
+                    created_at, product_id, unit as "unit: ProductUnit", quantity_step, weight_kg, length_cm,
+                width_cm, height_cm, updated_at, age_restriction, purchase_limit"#,
+                change.price,
+                change.is_available,
+                stock_delta,
+                change.product_id
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+            products.push(product);
+        }
+
+        if !errors.is_empty() {
+            tx.rollback().await?;
+            return Ok(BulkOutcome::Invalid(errors));
+        }
+
+        tx.commit().await?;
+        Ok(BulkOutcome::Applied(products))
+    }
+}
+
+// data access for the catalogue, handlers go through AppState::products so
+// they can run against a fake store in tests. Calls are for one store, the
+// products of other stores are not found. Reads take the customer group the
+// caller browses as, products kept from it are left out too and none shows
+// staff everything
+#[async_trait]
+pub trait ProductRepo: Send + Sync {
+    async fn admin_list(
+        &self,
+        store_id: Uuid,
+        media: &MediaUrls,
+        filter: &AdminProductFilter,
+        page: &PageQuery,
+    ) -> Result<Page<ProductListItem>, sqlx::Error>;
+    async fn list(
+        &self,
+        store_id: Uuid,
+        media: &MediaUrls,
+        user_id: Uuid,
+        group: Option<CustomerGroup>,
+        category: Option<&str>,
+        page: &PageQuery,
+    ) -> Result<Page<ProductListItem>, sqlx::Error>;
+    async fn suggest(
+        &self,
+        store_id: Uuid,
+        group: Option<CustomerGroup>,
+        q: &str,
+        limit: i64,
+    ) -> Result<Vec<Suggestion>, sqlx::Error>;
+    // the database's own search, for when there is no search engine
+    async fn search(
+        &self,
+        store_id: Uuid,
+        media: &MediaUrls,
+        user_id: Uuid,
+        group: Option<CustomerGroup>,
+        q: &str,
+        page: &PageQuery,
+    ) -> Result<Page<ProductListItem>, sqlx::Error>;
+    // a page of what a search engine found, out of the hits the viewer may see
+    async fn list_hits(
+        &self,
+        store_id: Uuid,
+        media: &MediaUrls,
+        user_id: Uuid,
+        group: Option<CustomerGroup>,
+        product_ids: &[Uuid],
+        page: &PageQuery,
+    ) -> Result<Page<ProductListItem>, sqlx::Error>;
+    // the whole catalogue list as CSV, fetched a chunk at a time as it is read
+    fn export(
+        &self,
+        store_id: Uuid,
+        user_id: Uuid,
+        group: Option<CustomerGroup>,
+        category: Option<String>,
+    ) -> BoxStream<'static, Result<Bytes, sqlx::Error>>;
+    async fn get(
+        &self,
+        store_id: Uuid,
+        media: &MediaUrls,
+        product_id: Uuid,
+        user_id: Uuid,
+        group: Option<CustomerGroup>,
+    ) -> Result<Option<ProductDetail>, sqlx::Error>;
+    async fn create(&self, store_id: Uuid, body: ProductBody) -> Result<Product, sqlx::Error>;
+    async fn update(
+        &self,
+        store_id: Uuid,
+        product_id: Uuid,
+        body: ProductBody,
+        precondition: &Precondition,
+    ) -> Result<Option<Product>, sqlx::Error>;
+    async fn patch(
+        &self,
+        store_id: Uuid,
+        product_id: Uuid,
+        patch: ProductPatch,
+        precondition: &Precondition,
+    ) -> Result<Option<Product>, sqlx::Error>;
+    async fn delete(
+        &self,
+        store_id: Uuid,
+        product_id: Uuid,
+        precondition: &Precondition,
+    ) -> Result<(), sqlx::Error>;
+    async fn duplicate(
+        &self,
+        store_id: Uuid,
+        product_id: Uuid,
+    ) -> Result<Option<Product>, sqlx::Error>;
+    async fn bulk_update(
+        &self,
+        store_id: Uuid,
+        changes: Vec<BulkProductChange>,
+    ) -> Result<BulkOutcome, sqlx::Error>;
+    async fn set_related(
+        &self,
+        store_id: Uuid,
+        product_id: Uuid,
+        related_ids: &[Uuid],
+    ) -> Result<Vec<RelatedProduct>, sqlx::Error>;
+    async fn set_price_tiers(
+        &self,
+        store_id: Uuid,
+        product_id: Uuid,
+        tiers: &[PriceTier],
+    ) -> Result<Vec<PriceTier>, sqlx::Error>;
+    async fn set_components(
+        &self,
+        store_id: Uuid,
+        product_id: Uuid,
+        components: &[KitComponent],
+    ) -> Result<Vec<KitComponent>, sqlx::Error>;
+    async fn add_image(
+        &self,
+        store_id: Uuid,
+        media: &MediaUrls,
+        product_id: Uuid,
+        body: ProductImageBody,
+    ) -> Result<ProductImage, sqlx::Error>;
+    async fn upload_image(
+        &self,
+        store_id: Uuid,
+        media: &MediaUrls,
+        storage: &dyn Storage,
+        product_id: Uuid,
+        position: i32,
+        bytes: &[u8],
+    ) -> Result<ProductImage, sqlx::Error>;
+    async fn remove_image(
+        &self,
+        store_id: Uuid,
+        storage: &dyn Storage,
+        product_id: Uuid,
+        image_id: Uuid,
+    ) -> Result<bool, sqlx::Error>;
+}
+
+// every call is timed under "products.<method>", listings are served from
+// the cache and dropped from it by every write
+pub struct PgProductRepo {
+    pool: PgPool,
+    timings: Arc<QueryStats>,
+    listings: Arc<ProductListings>,
+}
+
+impl PgProductRepo {
+    pub fn new(pool: PgPool, timings: Arc<QueryStats>, listings: Arc<ProductListings>) -> Self {
+        PgProductRepo {
+            pool,
+            timings,
+            listings,
+        }
+    }
+
+    // a write went through, cached listings may show the product as it was
+    async fn changed<T>(&self, result: Result<T, sqlx::Error>) -> Result<T, sqlx::Error> {
+        if result.is_ok() {
+            self.listings.invalidate().await;
+        }
+        result
+    }
+}
+
+#[async_trait]
+impl ProductRepo for PgProductRepo {
+    // never cached, admins see their changes straight away
+    async fn admin_list(
+        &self,
+        store_id: Uuid,
+        media: &MediaUrls,
+        filter: &AdminProductFilter,
+        page: &PageQuery,
+    ) -> Result<Page<ProductListItem>, sqlx::Error> {
+        self.timings
+            .time("products.admin_list", async {
+                let Page { items, total } =
+                    Product::get_admin_list(&self.pool, store_id, filter, page).await?;
+                let items = Product::with_images(&self.pool, media, items).await?;
+                Ok(Page { items, total })
+            })
+            .await
+    }
+
+    async fn list(
+        &self,
+        store_id: Uuid,
+        media: &MediaUrls,
+        user_id: Uuid,
+        group: Option<CustomerGroup>,
+        category: Option<&str>,
+        page: &PageQuery,
+    ) -> Result<Page<ProductListItem>, sqlx::Error> {
+        self.timings
+            .time("products.list", async {
+                // prices differ per customer group, the rest of a listing doesn't
+                let key = ListingKey {
+                    store_id,
+                    group: Product::pricing_group(&self.pool, user_id).await?,
+                    visible_to: group,
+                    category: category.map(str::to_string),
+                    page: page.page(),
+                    per_page: page.per_page(),
+                };
+                self.listings
+                    .get_or_load(
+                        key,
+                        Product::get_list(
+                            &self.pool, media, store_id, user_id, group, category, page,
+                        ),
+                    )
+                    .await
+            })
+            .await
+    }
+
+    async fn suggest(
+        &self,
+        store_id: Uuid,
+        group: Option<CustomerGroup>,
+        q: &str,
+        limit: i64,
+    ) -> Result<Vec<Suggestion>, sqlx::Error> {
+        self.timings
+            .time(
+                "products.suggest",
+                Product::suggest(&self.pool, store_id, group, q, limit),
+            )
+            .await
+    }
+
+    async fn search(
+        &self,
+        store_id: Uuid,
+        media: &MediaUrls,
+        user_id: Uuid,
+        group: Option<CustomerGroup>,
+        q: &str,
+        page: &PageQuery,
+    ) -> Result<Page<ProductListItem>, sqlx::Error> {
+        self.timings
+            .time("products.search", async {
+                let Page { items, total } =
+                    Product::search_products(&self.pool, store_id, user_id, group, q, page).await?;
+                let items = Product::with_images(&self.pool, media, items).await?;
+                Ok(Page { items, total })
+            })
+            .await
+    }
+
+    async fn list_hits(
+        &self,
+        store_id: Uuid,
+        media: &MediaUrls,
+        user_id: Uuid,
+        group: Option<CustomerGroup>,
+        product_ids: &[Uuid],
+        page: &PageQuery,
+    ) -> Result<Page<ProductListItem>, sqlx::Error> {
+        self.timings
+            .time("products.list_hits", async {
+                // the engine doesn't know who may see what, pages and the
+                // total are of the hits left once that is known
+                let visible =
+                    Product::visible_ids(&self.pool, store_id, group, product_ids).await?;
+                let total = visible.len() as i64;
+                let shown: Vec<Uuid> = visible
+                    .into_iter()
+                    .skip(page.offset() as usize)
+                    .take(page.per_page() as usize)
+                    .collect();
+                let products =
+                    Product::get_by_ids(&self.pool, store_id, user_id, group, &shown).await?;
+                let items = Product::with_images(&self.pool, media, products).await?;
+                Ok(Page { items, total })
+            })
+            .await
+    }
+
+    // straight from the database, the cache holds pages
+    fn export(
+        &self,
+        store_id: Uuid,
+        user_id: Uuid,
+        group: Option<CustomerGroup>,
+        category: Option<String>,
+    ) -> BoxStream<'static, Result<Bytes, sqlx::Error>> {
+        let cursor = ExportCursor {
+            pool: self.pool.clone(),
+            timings: self.timings.clone(),
+            store_id,
+            user_id,
+            group,
+            category,
+            after: None,
+            started: false,
+            finished: false,
+        };
+        stream::try_unfold(cursor, |mut cursor| async move {
+            let chunk = Product::export_chunk(&mut cursor).await?;
+            Ok(chunk.map(|chunk| (Bytes::from(chunk), cursor)))
+        })
+        .boxed()
+    }
+
+    async fn get(
+        &self,
+        store_id: Uuid,
+        media: &MediaUrls,
+        product_id: Uuid,
+        user_id: Uuid,
+        group: Option<CustomerGroup>,
+    ) -> Result<Option<ProductDetail>, sqlx::Error> {
+        self.timings
+            .time("products.get", async {
+                if !Product::in_store(&self.pool, store_id, &[product_id]).await? {
+                    return Ok(None);
+                }
+                Product::get_detail(&self.pool, media, product_id, user_id, group).await
+            })
+            .await
+    }
+
+    async fn create(&self, store_id: Uuid, body: ProductBody) -> Result<Product, sqlx::Error> {
+        let result = self
+            .timings
+            .time(
+                "products.create",
+                Product::create_product(&self.pool, store_id, body),
+            )
+            .await;
+        self.changed(result).await
+    }
+
+    async fn update(
+        &self,
+        store_id: Uuid,
+        product_id: Uuid,
+        body: ProductBody,
+        precondition: &Precondition,
+    ) -> Result<Option<Product>, sqlx::Error> {
+        let result = self
+            .timings
+            .time("products.update", async {
+                if !Product::in_store(&self.pool, store_id, &[product_id]).await? {
+                    return Ok(None);
+                }
+                Product::edit_product_by_id(&self.pool, product_id, body, precondition).await
+            })
+            .await;
+        self.changed(result).await
+    }
+
+    async fn patch(
+        &self,
+        store_id: Uuid,
+        product_id: Uuid,
+        patch: ProductPatch,
+        precondition: &Precondition,
+    ) -> Result<Option<Product>, sqlx::Error> {
+        let result = self
+            .timings
+            .time("products.patch", async {
+                if !Product::in_store(&self.pool, store_id, &[product_id]).await? {
+                    return Ok(None);
+                }
+                Product::patch_product(&self.pool, product_id, patch, precondition).await
+            })
+            .await;
+        self.changed(result).await
+    }
+
+    // deleting another store's product is a no-op, like deleting a missing one
+    async fn delete(
+        &self,
+        store_id: Uuid,
+        product_id: Uuid,
+        precondition: &Precondition,
+    ) -> Result<(), sqlx::Error> {
+        let result = self
+            .timings
+            .time("products.delete", async {
+                if !Product::in_store(&self.pool, store_id, &[product_id]).await? {
+                    return Ok(());
+                }
+                Product::delete_product(&self.pool, product_id, precondition).await
+            })
+            .await;
+        self.changed(result).await
+    }
+
+    async fn duplicate(
+        &self,
+        store_id: Uuid,
+        product_id: Uuid,
+    ) -> Result<Option<Product>, sqlx::Error> {
+        let result = self
+            .timings
+            .time(
+                "products.duplicate",
+                Product::duplicate_product(&self.pool, store_id, product_id),
+            )
+            .await;
+        self.changed(result).await
+    }
+
+    async fn bulk_update(
+        &self,
+        store_id: Uuid,
+        changes: Vec<BulkProductChange>,
+    ) -> Result<BulkOutcome, sqlx::Error> {
+        let result = self
+            .timings
+            .time(
+                "products.bulk_update",
+                Product::bulk_update(&self.pool, store_id, changes),
+            )
+            .await;
+        self.changed(result).await
+    }
 
-#[derive(Serialize, Deserialize, FromRow)]
-struct ProductBody {
-    name: String,
-    description: Option<String>,
-    price: Decimal,
-    stock_quantity: i32,
-}
+    async fn set_related(
+        &self,
+        store_id: Uuid,
+        product_id: Uuid,
+        related_ids: &[Uuid],
+    ) -> Result<Vec<RelatedProduct>, sqlx::Error> {
+        self.timings
+            .time("products.set_related", async {
+                if !Product::in_store(&self.pool, store_id, &[product_id]).await? {
+                    return Err(sqlx::Error::RowNotFound);
+                }
+                if !Product::in_store(&self.pool, store_id, related_ids).await? {
+                    return Err(sqlx::Error::Protocol(
+                        "Related products must be in the same store".into(),
+                    ));
+                }
+                Product::set_related(&self.pool, product_id, related_ids).await
+            })
+            .await
+    }
 
-impl Product {
-    // impl to get all products from db
-    async fn get_products(pool: &PgPool) -> Result<Vec<Product>, sqlx::Error> {
-        sqlx::query_as!(
-            Product,
-            "
-            SELECT name, description, price, stock_quantity, category, 
-                   is_available, created_at, product_id 
-            FROM products;
-            "
-        )
-        .fetch_all(pool)
-        .await
+    async fn set_price_tiers(
+        &self,
+        store_id: Uuid,
+        product_id: Uuid,
+        tiers: &[PriceTier],
+    ) -> Result<Vec<PriceTier>, sqlx::Error> {
+        let result = self
+            .timings
+            .time("products.set_price_tiers", async {
+                if !Product::in_store(&self.pool, store_id, &[product_id]).await? {
+                    return Err(sqlx::Error::RowNotFound);
+                }
+                Product::set_price_tiers(&self.pool, product_id, tiers).await
+            })
+            .await;
+        self.changed(result).await
     }
 
-    // get single product detail
-    async fn get_product_by_id(
-        pool: &PgPool,
+    async fn set_components(
+        &self,
+        store_id: Uuid,
         product_id: Uuid,
-    ) -> Result<Option<Product>, sqlx::Error> {
-        sqlx::query_as!(
-            Product,
-            "
-        SELECT name, description, price, stock_quantity, category, 
-               is_available, created_at, product_id 
-        FROM products WHERE product_id = $1;
-        ",
-            product_id
-        )
-        .fetch_optional(pool)
-        .await
+        components: &[KitComponent],
+    ) -> Result<Vec<KitComponent>, sqlx::Error> {
+        let result = self
+            .timings
+            .time("products.set_components", async {
+                if !Product::in_store(&self.pool, store_id, &[product_id]).await? {
+                    return Err(sqlx::Error::RowNotFound);
+                }
+                let component_ids: Vec<Uuid> = components
+                    .iter()
+                    .map(|component| component.product_id)
+                    .collect();
+                if !Product::in_store(&self.pool, store_id, &component_ids).await? {
+                    return Err(sqlx::Error::Protocol(
+                        "Kit components must be in the same store".into(),
+                    ));
+                }
+                Product::set_components(&self.pool, product_id, components).await
+            })
+            .await;
+        self.changed(result).await
     }
 
-    // create product
-    async fn create_product(
-        pool: &PgPool,
-        body: Json<ProductBody>,
-    ) -> Result<Product, sqlx::Error> {
-        let new_product = body.into_inner();
-        sqlx::query_as!(Product, "INSERT INTO products (name, description, price, stock_quantity) VALUES ($1, $2, $3, $4) RETURNING *",
-        new_product.name, new_product.description, new_product.price, new_product.stock_quantity
-    )
-        .fetch_one(pool)
-        .await
+    async fn add_image(
+        &self,
+        store_id: Uuid,
+        media: &MediaUrls,
+        product_id: Uuid,
+        body: ProductImageBody,
+    ) -> Result<ProductImage, sqlx::Error> {
+        let result = self
+            .timings
+            .time("products.add_image", async {
+                if !Product::in_store(&self.pool, store_id, &[product_id]).await? {
+                    return Err(sqlx::Error::RowNotFound);
+                }
+                Product::add_image(&self.pool, media, product_id, body).await
+            })
+            .await;
+        self.changed(result).await
     }
 
-    // delete product
-    async fn delete_product(pool: &PgPool, product_id: Uuid) -> Result<(), sqlx::Error> {
-        sqlx::query!("DELETE FROM products WHERE product_id = $1", product_id)
-            .execute(pool)
-            .await?;
-        Ok(())
+    async fn upload_image(
+        &self,
+        store_id: Uuid,
+        media: &MediaUrls,
+        storage: &dyn Storage,
+        product_id: Uuid,
+        position: i32,
+        bytes: &[u8],
+    ) -> Result<ProductImage, sqlx::Error> {
+        let result = self
+            .timings
+            .time("products.upload_image", async {
+                if !Product::in_store(&self.pool, store_id, &[product_id]).await? {
+                    return Err(sqlx::Error::RowNotFound);
+                }
+                Product::upload_image(&self.pool, media, storage, product_id, position, bytes).await
+            })
+            .await;
+        self.changed(result).await
     }
 
-    // edit product
-    async fn edit_product_by_id(
-        pool: &PgPool,
+    async fn remove_image(
+        &self,
+        store_id: Uuid,
+        storage: &dyn Storage,
         product_id: Uuid,
-        body: Json<ProductBody>,
-    ) -> Result<Option<Product>, sqlx::Error> {
-        let new_product = body.into_inner();
-        sqlx::query_as!(
-            Product,
-            "UPDATE products 
-            SET name = $1, description = $2,
-            price = $3, stock_quantity = $4
-            WHERE product_id = $5 RETURNING *
-            ",
-            new_product.name,
-            new_product.description,
-            new_product.price,
-            new_product.stock_quantity,
-            product_id
-        )
-        .fetch_optional(pool)
-        .await
+        image_id: Uuid,
+    ) -> Result<bool, sqlx::Error> {
+        let result = self
+            .timings
+            .time("products.remove_image", async {
+                if !Product::in_store(&self.pool, store_id, &[product_id]).await? {
+                    return Ok(false);
+                }
+                Product::remove_image(&self.pool, storage, product_id, image_id).await
+            })
+            .await;
+        self.changed(result).await
     }
 }
 
-// get request to get all the products
+// get request to get all the products, Accept: text/csv gets all of them at
+// once instead of a page
 #[get("api/products")]
 pub async fn get_products(
+    req: HttpRequest,
     state: web::Data<AppState>,
+    store: ReqData<Store>,
+    query: web::Query<PageQuery>,
+    filter: web::Query<ListingFilter>,
     req_user: Option<ReqData<TokenClaims>>,
 ) -> impl Responder {
     match req_user {
-        Some(_) => match Product::get_products(&state.db).await {
-            Ok(products) => HttpResponse::Ok().json(products),
-            Err(err) => HttpResponse::InternalServerError().json(format!("{err:?}")),
+        Some(user) if csv::requested(&req) => csv::attachment(
+            "products.csv",
+            state.products.export(
+                store.store_id,
+                user.user_id,
+                user.catalogue_group(),
+                filter.into_inner().category,
+            ),
+        ),
+        Some(user) => match state
+            .products
+            .list(
+                store.store_id,
+                &state.media,
+                user.user_id,
+                user.catalogue_group(),
+                filter.category.as_deref(),
+                &query,
+            )
+            .await
+        {
+            Ok(page) => paginated(page.items, Pagination::new(&query, page.total)),
+            Err(err) => database_error(err),
         },
         None => HttpResponse::Unauthorized().json("unable to verify indentity"),
     }
 }
 
+// admin only
+// get request for the products as admins manage them, unpublished ones and
+// the ones kept for some customer groups included, filtered, sorted and paged
+#[get("api/admin/products")]
+pub async fn get_admin_products(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    query: web::Query<PageQuery>,
+    filter: web::Query<AdminProductFilter>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if !user.is_admin() {
+                return HttpResponse::Forbidden().json("costumer cant manage products");
+            }
+            let store_id = match filter.store_id {
+                Some(store_id) if store_id != store.store_id && !user.is_platform_admin() => {
+                    return HttpResponse::Forbidden()
+                        .json("only admins of every store can see another store's products");
+                }
+                Some(store_id) => store_id,
+                None => store.store_id,
+            };
+            match state
+                .products
+                .admin_list(store_id, &state.media, &filter, &query)
+                .await
+            {
+                Ok(page) => paginated(page.items, Pagination::new(&query, page.total)),
+                Err(err) => database_error(err),
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// how many of a search engine's best matches are paged through, as many as
+// meilisearch counts by default
+const SEARCH_HITS: i64 = 1000;
+
+// get request to search the catalogue, ?q= is what was searched for and the
+// results are paged like the catalogue list. A configured search engine ranks
+// them, tolerating typos; without one, or while it is failing, the database
+// matches names and categories
+#[get("api/products/search")]
+pub async fn search_products(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    query: web::Query<PageQuery>,
+    filter: web::Query<SearchFilter>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            let q = filter.q.trim();
+            if q.is_empty() {
+                return HttpResponse::BadRequest().json("q must not be empty");
+            }
+            let found = match &state.search {
+                Some(engine) => match engine.search(store.store_id, q, 0, SEARCH_HITS).await {
+                    Ok(hits) => {
+                        state
+                            .products
+                            .list_hits(
+                                store.store_id,
+                                &state.media,
+                                user.user_id,
+                                user.catalogue_group(),
+                                &hits.product_ids,
+                                &query,
+                            )
+                            .await
+                    }
+                    Err(err) => {
+                        println!("search on {} failed: {err}", engine.name());
+                        state
+                            .products
+                            .search(
+                                store.store_id,
+                                &state.media,
+                                user.user_id,
+                                user.catalogue_group(),
+                                q,
+                                &query,
+                            )
+                            .await
+                    }
+                },
+                None => {
+                    state
+                        .products
+                        .search(
+                            store.store_id,
+                            &state.media,
+                            user.user_id,
+                            user.catalogue_group(),
+                            q,
+                            &query,
+                        )
+                        .await
+                }
+            };
+            match found {
+                Ok(page) => paginated(page.items, Pagination::new(&query, page.total)),
+                Err(err) => database_error(err),
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// get request for what to suggest as the search box is typed in,
+// ?q= is the text so far and ?limit= how many to return, 8 unless asked
+#[get("api/products/suggest")]
+pub async fn suggest_products(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    query: web::Query<SuggestQuery>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            let q = query.q.trim();
+            if q.is_empty() {
+                return HttpResponse::BadRequest().json("q must not be empty");
+            }
+            let limit = query.limit.unwrap_or(8).clamp(1, 20);
+            match state
+                .products
+                .suggest(store.store_id, user.catalogue_group(), q, limit)
+                .await
+            {
+                // the same keystrokes come round again as the customer types
+                // and deletes, a short private cache saves the round trip
+                Ok(suggestions) => HttpResponse::Ok()
+                    .insert_header((header::CACHE_CONTROL, "private, max-age=60"))
+                    .json(suggestions),
+                Err(err) => database_error(err),
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
 // get request to get a product by id
 #[get("api/product/{id}")]
 pub async fn get_product_by_id(
     state: web::Data<AppState>,
+    store: ReqData<Store>,
     product_id: web::Path<Uuid>,
     req_user: Option<ReqData<TokenClaims>>,
 ) -> impl Responder {
     match req_user {
-        Some(_) => match Product::get_product_by_id(&state.db, *product_id).await {
-            Ok(Some(product)) => HttpResponse::Ok().json(product),
-            Ok(None) => HttpResponse::Ok().json("product was not found"),
-            Err(err) => HttpResponse::InternalServerError().json(format!("{err:?}")),
-        },
+        Some(user) => {
+            match state
+                .products
+                .get(
+                    store.store_id,
+                    &state.media,
+                    *product_id,
+                    user.user_id,
+                    user.catalogue_group(),
+                )
+                .await
+            {
+                Ok(Some(product)) => {
+                    precondition::versioned(&mut HttpResponse::Ok(), product.product.updated_at)
+                        .json(product)
+                }
+                Ok(None) => HttpResponse::Ok().json("product was not found"),
+                Err(err) => database_error(err),
+            }
+        }
         None => HttpResponse::Unauthorized().json("unable to verify indentity"),
     }
 }
@@ -144,15 +2510,21 @@ pub async fn get_product_by_id(
 #[post("api/product")]
 pub async fn create_product(
     state: web::Data<AppState>,
+    store: ReqData<Store>,
     req_user: Option<ReqData<TokenClaims>>,
     body: Json<ProductBody>,
 ) -> impl Responder {
     match req_user {
         Some(user) => {
             if user.is_admin() {
-                match Product::create_product(&state.db, body).await {
-                    Ok(product) => HttpResponse::Ok().json(product),
-                    Err(err) => HttpResponse::InternalServerError().json(format!("{err:?}")),
+                match state
+                    .products
+                    .create(store.store_id, body.into_inner())
+                    .await
+                {
+                    Ok(product) => HttpResponse::Ok().json(ProductResponse::from(product)),
+                    Err(sqlx::Error::Protocol(msg)) => HttpResponse::BadRequest().json(msg),
+                    Err(err) => database_error(err),
                 }
             } else {
                 HttpResponse::Forbidden().json("costumer cant create product")
@@ -162,19 +2534,30 @@ pub async fn create_product(
     }
 }
 
-// delete request to delete product by id
+// delete request to delete product by id, If-Match or If-Unmodified-Since
+// make it conditional
 #[delete("api/product/{id}")]
 pub async fn delete_product_id(
+    req: HttpRequest,
     state: web::Data<AppState>,
+    store: ReqData<Store>,
     req_user: Option<ReqData<TokenClaims>>,
     product_id: web::Path<Uuid>,
 ) -> impl Responder {
     match req_user {
         Some(user) => {
             if user.is_admin() {
-                match Product::delete_product(&state.db, *product_id).await {
+                let precondition = Precondition::read(&req);
+                match state
+                    .products
+                    .delete(store.store_id, *product_id, &precondition)
+                    .await
+                {
                     Ok(_) => HttpResponse::Ok().json("product deleted sucessfully"),
-                    Err(err) => HttpResponse::InternalServerError().json(format!("{err:?}")),
+                    Err(sqlx::Error::Protocol(msg)) if msg == precondition::FAILED => {
+                        HttpResponse::PreconditionFailed().json(msg)
+                    }
+                    Err(err) => database_error(err),
                 }
             } else {
                 HttpResponse::Forbidden().json("costumer cant delete product")
@@ -184,10 +2567,12 @@ pub async fn delete_product_id(
     }
 }
 
-// update product by id
+// update product by id, If-Match or If-Unmodified-Since make it conditional
 #[put("api/product/{id}")]
 pub async fn update_product_by_id(
+    req: HttpRequest,
     state: web::Data<AppState>,
+    store: ReqData<Store>,
     req_user: Option<ReqData<TokenClaims>>,
     product_id: web::Path<Uuid>,
     body: Json<ProductBody>,
@@ -195,10 +2580,363 @@ pub async fn update_product_by_id(
     match req_user {
         Some(user) => {
             if user.is_admin() {
-                match Product::edit_product_by_id(&state.db, *product_id, body).await {
-                    Ok(Some(product)) => HttpResponse::Ok().json(product),
+                let precondition = Precondition::read(&req);
+                match state
+                    .products
+                    .update(
+                        store.store_id,
+                        *product_id,
+                        body.into_inner(),
+                        &precondition,
+                    )
+                    .await
+                {
+                    Ok(Some(product)) => {
+                        precondition::versioned(&mut HttpResponse::Ok(), product.updated_at)
+                            .json(ProductResponse::from(product))
+                    }
                     Ok(None) => HttpResponse::Ok().json("invalid product_id"),
-                    Err(err) => HttpResponse::InternalServerError().json(format!("{err:?}")),
+                    Err(sqlx::Error::Protocol(msg)) if msg == precondition::FAILED => {
+                        HttpResponse::PreconditionFailed().json(msg)
+                    }
+                    Err(sqlx::Error::Protocol(msg)) => HttpResponse::BadRequest().json(msg),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("costumer cant edit product")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// patch product by id, only the fields sent are changed. If-Match or
+// If-Unmodified-Since make it conditional
+#[patch("api/product/{id}")]
+pub async fn patch_product_by_id(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    product_id: web::Path<Uuid>,
+    body: Json<ProductPatch>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                let precondition = Precondition::read(&req);
+                match state
+                    .products
+                    .patch(
+                        store.store_id,
+                        *product_id,
+                        body.into_inner(),
+                        &precondition,
+                    )
+                    .await
+                {
+                    Ok(Some(product)) => {
+                        precondition::versioned(&mut HttpResponse::Ok(), product.updated_at)
+                            .json(ProductResponse::from(product))
+                    }
+                    Ok(None) => HttpResponse::NotFound().json("invalid product_id"),
+                    Err(sqlx::Error::Protocol(msg)) if msg == precondition::FAILED => {
+                        HttpResponse::PreconditionFailed().json(msg)
+                    }
+                    Err(sqlx::Error::Protocol(msg)) => HttpResponse::BadRequest().json(msg),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("costumer cant edit product")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// patch request to reprice, restock or toggle availability of many products at once
+#[patch("api/admin/products/bulk")]
+pub async fn bulk_update_products(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    body: Json<Vec<BulkProductChange>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match state
+                    .products
+                    .bulk_update(store.store_id, body.into_inner())
+                    .await
+                {
+                    Ok(BulkOutcome::Applied(products)) => HttpResponse::Ok().json(
+                        products
+                            .into_iter()
+                            .map(ProductResponse::from)
+                            .collect::<Vec<_>>(),
+                    ),
+                    Ok(BulkOutcome::Invalid(errors)) => {
+                        HttpResponse::UnprocessableEntity().json(errors)
+                    }
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("costumer cant edit product")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// post request to copy a product into a new draft
+#[post("api/admin/products/{id}/duplicate")]
+pub async fn duplicate_product(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    product_id: web::Path<Uuid>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match state.products.duplicate(store.store_id, *product_id).await {
+                    Ok(Some(product)) => {
+                        HttpResponse::Created().json(ProductResponse::from(product))
+                    }
+                    Ok(None) => HttpResponse::NotFound().json("product was not found"),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("costumer cant create product")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// put request to pin the related products shown on a product
+#[put("api/admin/products/{id}/related")]
+pub async fn set_related_products(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    product_id: web::Path<Uuid>,
+    body: Json<RelatedBody>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match state
+                    .products
+                    .set_related(store.store_id, *product_id, &body.product_ids)
+                    .await
+                {
+                    Ok(related) => HttpResponse::Ok().json(related),
+                    Err(sqlx::Error::Protocol(msg)) => HttpResponse::BadRequest().json(msg),
+                    Err(sqlx::Error::RowNotFound) => {
+                        HttpResponse::NotFound().json("product was not found")
+                    }
+                    Err(sqlx::Error::Database(err)) if err.is_foreign_key_violation() => {
+                        HttpResponse::NotFound().json("product was not found")
+                    }
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("costumer cant edit product")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// put request to set the quantity price breaks of a product
+#[put("api/admin/products/{id}/price-tiers")]
+pub async fn set_price_tiers(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    product_id: web::Path<Uuid>,
+    body: Json<Vec<PriceTier>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match state
+                    .products
+                    .set_price_tiers(store.store_id, *product_id, &body)
+                    .await
+                {
+                    Ok(tiers) => HttpResponse::Ok().json(tiers),
+                    Err(sqlx::Error::Protocol(msg)) => HttpResponse::BadRequest().json(msg),
+                    Err(sqlx::Error::RowNotFound) => {
+                        HttpResponse::NotFound().json("product was not found")
+                    }
+                    Err(sqlx::Error::Database(err)) if err.is_foreign_key_violation() => {
+                        HttpResponse::NotFound().json("product was not found")
+                    }
+                    Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+                        HttpResponse::BadRequest().json("Tier quantities must be unique")
+                    }
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("costumer cant edit product")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// put request to set the components a kit is assembled from
+#[put("api/admin/products/{id}/components")]
+pub async fn set_kit_components(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    product_id: web::Path<Uuid>,
+    body: Json<Vec<KitComponent>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match state
+                    .products
+                    .set_components(store.store_id, *product_id, &body)
+                    .await
+                {
+                    Ok(components) => HttpResponse::Ok().json(components),
+                    Err(sqlx::Error::Protocol(msg)) => HttpResponse::BadRequest().json(msg),
+                    Err(sqlx::Error::RowNotFound) => {
+                        HttpResponse::NotFound().json("product was not found")
+                    }
+                    Err(sqlx::Error::Database(err)) if err.is_foreign_key_violation() => {
+                        HttpResponse::NotFound().json("product was not found")
+                    }
+                    Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+                        HttpResponse::BadRequest().json("Each component can appear once per kit")
+                    }
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("costumer cant edit product")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// post request to add an image from the media bucket to a product
+#[post("api/admin/products/{id}/images")]
+pub async fn add_product_image(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    product_id: web::Path<Uuid>,
+    body: Json<ProductImageBody>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match state
+                    .products
+                    .add_image(store.store_id, &state.media, *product_id, body.into_inner())
+                    .await
+                {
+                    Ok(image) => HttpResponse::Created().json(image),
+                    Err(sqlx::Error::Protocol(msg)) => HttpResponse::BadRequest().json(msg),
+                    Err(sqlx::Error::RowNotFound) => {
+                        HttpResponse::NotFound().json("product was not found")
+                    }
+                    Err(sqlx::Error::Database(err)) if err.is_foreign_key_violation() => {
+                        HttpResponse::NotFound().json("product was not found")
+                    }
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("costumer cant edit product")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// post request with the raw image as body to upload a product image,
+// ?position= orders it among the others
+#[post("api/admin/products/{id}/images/upload")]
+pub async fn upload_product_image(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    product_id: web::Path<Uuid>,
+    query: web::Query<ImageUploadQuery>,
+    payload: web::Payload,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                let bytes = match payload.to_bytes_limited(state.media.max_upload_bytes).await {
+                    Ok(Ok(bytes)) => bytes,
+                    Ok(Err(_)) => {
+                        return HttpResponse::PayloadTooLarge().json("image is too large")
+                    }
+                    Err(err) => return HttpResponse::BadRequest().json(err.to_string()),
+                };
+                match state
+                    .products
+                    .upload_image(
+                        store.store_id,
+                        &state.media,
+                        state.storage.as_ref(),
+                        *product_id,
+                        query.position,
+                        &bytes,
+                    )
+                    .await
+                {
+                    Ok(image) => HttpResponse::Created().json(image),
+                    Err(sqlx::Error::Protocol(msg)) => HttpResponse::BadRequest().json(msg),
+                    Err(sqlx::Error::RowNotFound) => {
+                        HttpResponse::NotFound().json("product was not found")
+                    }
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("costumer cant edit product")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// delete request to take an image off a product
+#[delete("api/admin/products/{id}/images/{image_id}")]
+pub async fn remove_product_image(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    path: web::Path<(Uuid, Uuid)>,
+) -> impl Responder {
+    let (product_id, image_id) = path.into_inner();
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match state
+                    .products
+                    .remove_image(store.store_id, state.storage.as_ref(), product_id, image_id)
+                    .await
+                {
+                    Ok(true) => HttpResponse::Ok().json("image removed"),
+                    Ok(false) => HttpResponse::NotFound().json("image not found"),
+                    Err(err) => database_error(err),
                 }
             } else {
                 HttpResponse::Forbidden().json("costumer cant edit product")
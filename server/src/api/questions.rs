@@ -0,0 +1,132 @@
+use crate::{
+    api::{stores::Store, users::TokenClaims},
+    rate_limit::database_error,
+    AppState,
+};
+use actix_web::{
+    get, post,
+    web::{self, Json, ReqData},
+    HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize, FromRow)]
+struct Question {
+    question_id: Uuid,
+    product_id: Uuid,
+    user_id: Uuid,
+    body: String,
+    is_hidden: bool,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+struct QuestionBody {
+    body: String,
+}
+
+impl Question {
+    // questions on a product in the store, oldest first, the ones hidden by
+    // moderation left out
+    async fn get_product_questions(
+        pool: &PgPool,
+        store_id: Uuid,
+        product_id: Uuid,
+    ) -> Result<Vec<Question>, sqlx::Error> {
+        sqlx::query_as!(
+            Question,
+            "SELECT * FROM product_questions WHERE product_id = $1 AND NOT is_hidden
+            AND product_id IN (SELECT product_id FROM products WHERE store_id = $2)
+            ORDER BY created_at",
+            product_id,
+            store_id
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    // ask a question about a product in the store
+    async fn create_question(
+        pool: &PgPool,
+        store_id: Uuid,
+        product_id: Uuid,
+        user_id: Uuid,
+        body: QuestionBody,
+    ) -> Result<Question, sqlx::Error> {
+        if body.body.trim().is_empty() {
+            return Err(sqlx::Error::Protocol("Question is required".into()));
+        }
+
+        sqlx::query!(
+            "SELECT product_id FROM products WHERE product_id = $1 AND store_id = $2",
+            product_id,
+            store_id
+        )
+        .fetch_optional(pool)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)?;
+
+        sqlx::query_as!(
+            Question,
+            "INSERT INTO product_questions (product_id, user_id, body) VALUES ($1, $2, $3)
+            RETURNING *",
+            product_id,
+            user_id,
+            body.body
+        )
+        .fetch_one(pool)
+        .await
+    }
+}
+
+// get request to list the questions asked about a product
+#[get("api/product/{id}/questions")]
+pub async fn get_product_questions(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    product_id: web::Path<Uuid>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(_) => {
+            match Question::get_product_questions(&state.db, store.store_id, *product_id).await {
+                Ok(questions) => HttpResponse::Ok().json(questions),
+                Err(err) => database_error(err),
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// post request to ask a question about a product
+#[post("api/product/{id}/questions")]
+pub async fn create_question(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    product_id: web::Path<Uuid>,
+    req_user: Option<ReqData<TokenClaims>>,
+    body: Json<QuestionBody>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            match Question::create_question(
+                &state.db,
+                store.store_id,
+                *product_id,
+                user.user_id,
+                body.into_inner(),
+            )
+            .await
+            {
+                Ok(question) => HttpResponse::Created().json(question),
+                Err(sqlx::Error::RowNotFound) => HttpResponse::NotFound().json("product not found"),
+                Err(sqlx::Error::Protocol(msg)) => HttpResponse::BadRequest().json(msg),
+                Err(err) => database_error(err),
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
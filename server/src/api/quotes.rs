@@ -0,0 +1,561 @@
+use crate::{
+    api::{customer_groups::CustomerGroup, stores::Store, users::TokenClaims},
+    pricing::CartLine,
+    rate_limit::database_error,
+    AppState,
+};
+use actix_web::{
+    get, post, put,
+    web::{self, Json, ReqData},
+    HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Decimal, FromRow, PgConnection, PgPool};
+use uuid::Uuid;
+
+#[derive(Debug, Serialize, Deserialize, sqlx::Type, Clone, Copy, PartialEq)]
+#[sqlx(type_name = "quote_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum QuoteStatus {
+    Requested,
+    Quoted,
+    Accepted,
+    Declined,
+    Ordered,
+}
+
+#[derive(Serialize, FromRow)]
+pub struct Quote {
+    quote_id: Uuid,
+    user_id: Uuid,
+    status: QuoteStatus,
+    note: Option<String>,
+    admin_note: Option<String>,
+    expires_at: Option<DateTime<Utc>>,
+    order_id: Option<Uuid>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, FromRow)]
+struct QuoteItem {
+    product_id: Uuid,
+    product_name: String,
+    quantity: Decimal,
+    list_price: Decimal,
+    quoted_price: Option<Decimal>,
+}
+
+#[derive(Serialize)]
+struct QuoteDetail {
+    #[serde(flatten)]
+    quote: Quote,
+    items: Vec<QuoteItem>,
+}
+
+#[derive(Deserialize)]
+struct QuoteRequestBody {
+    note: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct QuotePrice {
+    product_id: Uuid,
+    price: Decimal,
+}
+
+#[derive(Deserialize)]
+struct RespondBody {
+    prices: Vec<QuotePrice>,
+    expires_at: DateTime<Utc>,
+    admin_note: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct QuoteQuery {
+    status: Option<QuoteStatus>,
+}
+
+impl Quote {
+    async fn with_items(
+        pool: &PgPool,
+        quotes: Vec<Quote>,
+    ) -> Result<Vec<QuoteDetail>, sqlx::Error> {
+        let mut result = Vec::with_capacity(quotes.len());
+        for quote in quotes {
+            let items = sqlx::query_as!(
+                QuoteItem,
+                "SELECT qi.product_id, p.name as product_name, qi.quantity, qi.list_price,
+                    qi.quoted_price
+                FROM quote_items qi
+                JOIN products p ON qi.product_id = p.product_id
+                WHERE qi.quote_id = $1 ORDER BY p.name",
+                quote.quote_id
+            )
+            .fetch_all(pool)
+            .await?;
+            result.push(QuoteDetail { quote, items });
+        }
+        Ok(result)
+    }
+
+    // send the store's active cart for pricing, the cart itself is left as
+    // it is. Only business customers, the ones with a VAT number, can ask
+    // for quotes
+    async fn request(
+        pool: &PgPool,
+        store_id: Uuid,
+        user_id: Uuid,
+        group: Option<CustomerGroup>,
+        note: Option<String>,
+    ) -> Result<QuoteDetail, sqlx::Error> {
+        let business = sqlx::query!(
+            r#"SELECT vat_number IS NOT NULL as "business!" FROM users WHERE user_id = $1"#,
+            user_id
+        )
+        .fetch_one(pool)
+        .await?;
+        if !business.business {
+            return Err(sqlx::Error::Protocol(
+                "Quotes are for business customers, add a VAT number first".into(),
+            ));
+        }
+
+        // bundles already have their own price
+        let bundled = sqlx::query!(
+            r#"SELECT EXISTS (
+                SELECT 1 FROM carts c JOIN cart_items ci ON ci.cart_id = c.cart_id
+                WHERE c.user_id = $1 AND c.store_id = $2 AND c.is_active
+                    AND NOT ci.saved_for_later AND ci.bundle_id IS NOT NULL
+            ) as "bundled!""#,
+            user_id,
+            store_id
+        )
+        .fetch_one(pool)
+        .await?;
+        if bundled.bundled {
+            return Err(sqlx::Error::Protocol(
+                "Bundles can't be quoted, remove them from the cart first".into(),
+            ));
+        }
+
+        // products can be kept from the group after they went in the cart
+        let hidden = sqlx::query_scalar!(
+            "SELECT p.name FROM carts c
+            JOIN cart_items ci ON ci.cart_id = c.cart_id
+            JOIN products p ON p.product_id = ci.product_id
+            WHERE c.user_id = $1 AND c.store_id = $2 AND c.is_active
+                AND NOT ci.saved_for_later AND NOT visible_to(p.product_id, $3)
+            ORDER BY p.name LIMIT 1",
+            user_id,
+            store_id,
+            group as Option<CustomerGroup>
+        )
+        .fetch_optional(pool)
+        .await?;
+        if let Some(name) = hidden {
+            return Err(sqlx::Error::Protocol(format!(
+                "{name} is not sold to your customer group"
+            )));
+        }
+
+        let mut tx = pool.begin().await?;
+
+        let quote = sqlx::query_as!(
+            Quote,
+            r#"INSERT INTO quotes (user_id, note, store_id) VALUES ($1, $2, $3)
+            RETURNING quote_id, user_id, status as "status: QuoteStatus", note, admin_note,
+                expires_at, order_id, created_at, updated_at"#,
+            user_id,
+            note,
+            store_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let items = sqlx::query!(
+            "INSERT INTO quote_items (quote_id, product_id, quantity, list_price)
+            SELECT $1, ci.product_id, ci.quantity, unit_price(ci.product_id, ci.quantity, $2)
+            FROM carts c
+            JOIN cart_items ci ON ci.cart_id = c.cart_id
+            WHERE c.user_id = $2 AND c.store_id = $3 AND c.is_active AND NOT ci.saved_for_later
+                AND ci.product_id IS NOT NULL",
+            quote.quote_id,
+            user_id,
+            store_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        if items.rows_affected() == 0 {
+            return Err(sqlx::Error::Protocol("Cart is empty".into()));
+        }
+
+        tx.commit().await?;
+
+        Ok(Quote::with_items(pool, vec![quote]).await?.remove(0))
+    }
+
+    async fn get_for_user(
+        pool: &PgPool,
+        store_id: Uuid,
+        user_id: Uuid,
+    ) -> Result<Vec<QuoteDetail>, sqlx::Error> {
+        let quotes = sqlx::query_as!(
+            Quote,
+            r#"SELECT quote_id, user_id, status as "status: QuoteStatus", note, admin_note,
+                expires_at, order_id, created_at, updated_at
+            FROM quotes WHERE user_id = $1 AND store_id = $2 ORDER BY created_at DESC"#,
+            user_id,
+            store_id
+        )
+        .fetch_all(pool)
+        .await?;
+        Quote::with_items(pool, quotes).await
+    }
+
+    async fn get_all(
+        pool: &PgPool,
+        store_id: Uuid,
+        status: Option<QuoteStatus>,
+    ) -> Result<Vec<QuoteDetail>, sqlx::Error> {
+        let quotes = sqlx::query_as!(
+            Quote,
+            r#"SELECT quote_id, user_id, status as "status: QuoteStatus", note, admin_note,
+                expires_at, order_id, created_at, updated_at
+            FROM quotes WHERE store_id = $2 AND ($1::quote_status IS NULL OR status = $1)
+            ORDER BY created_at"#,
+            status as Option<QuoteStatus>,
+            store_id
+        )
+        .fetch_all(pool)
+        .await?;
+        Quote::with_items(pool, quotes).await
+    }
+
+    // price every line of a requested quote, answering again replaces the prices
+    async fn respond(
+        pool: &PgPool,
+        store_id: Uuid,
+        quote_id: Uuid,
+        body: RespondBody,
+    ) -> Result<QuoteDetail, sqlx::Error> {
+        if body.expires_at <= Utc::now() {
+            return Err(sqlx::Error::Protocol("Expiry must be in the future".into()));
+        }
+        if body.prices.iter().any(|price| price.price < Decimal::ZERO) {
+            return Err(sqlx::Error::Protocol(
+                "Quoted prices can't be negative".into(),
+            ));
+        }
+
+        let mut tx = pool.begin().await?;
+
+        let quote = sqlx::query!(
+            r#"SELECT status as "status: QuoteStatus" FROM quotes
+            WHERE quote_id = $1 AND store_id = $2 FOR UPDATE"#,
+            quote_id,
+            store_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        if !matches!(quote.status, QuoteStatus::Requested | QuoteStatus::Quoted) {
+            return Err(sqlx::Error::Protocol(
+                "Quote can no longer be changed".into(),
+            ));
+        }
+
+        for price in &body.prices {
+            sqlx::query!(
+                "UPDATE quote_items SET quoted_price = $1 WHERE quote_id = $2 AND product_id = $3",
+                price.price,
+                quote_id,
+                price.product_id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        let unpriced = sqlx::query!(
+            r#"SELECT COUNT(*) as "count!" FROM quote_items
+            WHERE quote_id = $1 AND quoted_price IS NULL"#,
+            quote_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        if unpriced.count > 0 {
+            return Err(sqlx::Error::Protocol(
+                "Every quote line needs a price".into(),
+            ));
+        }
+
+        let quote = sqlx::query_as!(
+            Quote,
+            r#"UPDATE quotes SET status = 'quoted', expires_at = $1, admin_note = $2,
+                updated_at = NOW()
+            WHERE quote_id = $3
+            RETURNING quote_id, user_id, status as "status: QuoteStatus", note, admin_note,
+                expires_at, order_id, created_at, updated_at"#,
+            body.expires_at,
+            body.admin_note,
+            quote_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(Quote::with_items(pool, vec![quote]).await?.remove(0))
+    }
+
+    // the customer's answer to a quoted quote
+    async fn decide(
+        pool: &PgPool,
+        store_id: Uuid,
+        user_id: Uuid,
+        quote_id: Uuid,
+        status: QuoteStatus,
+    ) -> Result<Quote, sqlx::Error> {
+        sqlx::query_as!(
+            Quote,
+            r#"UPDATE quotes SET status = $1, updated_at = NOW()
+            WHERE quote_id = $2 AND user_id = $3 AND store_id = $4 AND status = 'quoted'
+                AND expires_at > NOW()
+            RETURNING quote_id, user_id, status as "status: QuoteStatus", note, admin_note,
+                expires_at, order_id, created_at, updated_at"#,
+            status as QuoteStatus,
+            quote_id,
+            user_id,
+            store_id
+        )
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| sqlx::Error::Protocol("Quote is not open or has expired".into()))
+    }
+
+    // lines of an accepted quote in the store at the quoted prices, locked
+    // until the order is placed
+    pub async fn order_lines(
+        conn: &mut PgConnection,
+        store_id: Uuid,
+        user_id: Uuid,
+        quote_id: Uuid,
+    ) -> Result<Vec<CartLine>, sqlx::Error> {
+        sqlx::query!(
+            "SELECT quote_id FROM quotes
+            WHERE quote_id = $1 AND user_id = $2 AND store_id = $3 AND status = 'accepted'
+                AND expires_at > NOW()
+            FOR UPDATE",
+            quote_id,
+            user_id,
+            store_id
+        )
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or_else(|| sqlx::Error::Protocol("Quote is not accepted or has expired".into()))?;
+
+        sqlx::query_as!(
+            CartLine,
+            r#"SELECT product_id as "product_id?", quantity, quoted_price as "price!",
+                NULL::uuid as bundle_id
+            FROM quote_items WHERE quote_id = $1"#,
+            quote_id
+        )
+        .fetch_all(&mut *conn)
+        .await
+    }
+
+    pub async fn mark_ordered(
+        conn: &mut PgConnection,
+        quote_id: Uuid,
+        order_id: Uuid,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "UPDATE quotes SET status = 'ordered', order_id = $1, updated_at = NOW()
+            WHERE quote_id = $2",
+            order_id,
+            quote_id
+        )
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+
+    // an accepted quote ordered by an order that was cancelled before it was
+    // paid can be ordered again
+    pub async fn reopen(conn: &mut PgConnection, order_id: Uuid) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "UPDATE quotes SET status = 'accepted', order_id = NULL, updated_at = NOW()
+            WHERE order_id = $1 AND status = 'ordered'",
+            order_id
+        )
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+}
+
+fn quote_error(err: sqlx::Error) -> HttpResponse {
+    match err {
+        sqlx::Error::RowNotFound => HttpResponse::NotFound().json("Quote not found"),
+        sqlx::Error::Protocol(msg)
+            if msg.contains("no longer")
+                || msg.contains("not open")
+                || msg.contains("not sold to") =>
+        {
+            HttpResponse::Conflict().json(msg)
+        }
+        sqlx::Error::Protocol(msg) if msg.contains("business customers") => {
+            HttpResponse::Forbidden().json(msg)
+        }
+        sqlx::Error::Protocol(msg) => HttpResponse::BadRequest().json(msg),
+        err => database_error(err),
+    }
+}
+
+// post request to send the active cart as a quote request
+#[post("api/quotes")]
+pub async fn request_quote(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    body: Json<QuoteRequestBody>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            match Quote::request(
+                &state.db,
+                store.store_id,
+                user.user_id,
+                user.catalogue_group(),
+                body.into_inner().note,
+            )
+            .await
+            {
+                Ok(quote) => HttpResponse::Created().json(quote),
+                Err(err) => quote_error(err),
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// get request for the current user's quotes
+#[get("api/quotes")]
+pub async fn get_quotes(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => match Quote::get_for_user(&state.db, store.store_id, user.user_id).await {
+            Ok(quotes) => HttpResponse::Ok().json(quotes),
+            Err(err) => database_error(err),
+        },
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// post request to accept a quote, checkout with its quote_id then orders it
+#[post("api/quotes/{id}/accept")]
+pub async fn accept_quote(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    quote_id: web::Path<Uuid>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            match Quote::decide(
+                &state.db,
+                store.store_id,
+                user.user_id,
+                *quote_id,
+                QuoteStatus::Accepted,
+            )
+            .await
+            {
+                Ok(quote) => HttpResponse::Ok().json(quote),
+                Err(err) => quote_error(err),
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// post request to decline a quote
+#[post("api/quotes/{id}/decline")]
+pub async fn decline_quote(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    quote_id: web::Path<Uuid>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            match Quote::decide(
+                &state.db,
+                store.store_id,
+                user.user_id,
+                *quote_id,
+                QuoteStatus::Declined,
+            )
+            .await
+            {
+                Ok(quote) => HttpResponse::Ok().json(quote),
+                Err(err) => quote_error(err),
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// get request for quotes, ?status=requested for the ones waiting on prices
+#[get("api/admin/quotes")]
+pub async fn get_all_quotes(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    query: web::Query<QuoteQuery>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match Quote::get_all(&state.db, store.store_id, query.status).await {
+                    Ok(quotes) => HttpResponse::Ok().json(quotes),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("customer not allowed to manage quotes")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// put request to answer a quote with prices and an expiry
+#[put("api/admin/quotes/{id}")]
+pub async fn respond_to_quote(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    quote_id: web::Path<Uuid>,
+    body: Json<RespondBody>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match Quote::respond(&state.db, store.store_id, *quote_id, body.into_inner()).await
+                {
+                    Ok(quote) => HttpResponse::Ok().json(quote),
+                    Err(err) => quote_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("customer not allowed to manage quotes")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
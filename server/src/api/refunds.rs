@@ -0,0 +1,370 @@
+use crate::{
+    api::{
+        orders::{record_event, record_status_event, release_stock, OrderStatus},
+        stores::Store,
+        users::{Permission, TokenClaims},
+    },
+    money::{Currency, Money},
+    payments::{CashOnDelivery, PaymentProvider, Payments},
+    rate_limit::database_error,
+    AppState,
+};
+use actix_web::{
+    post,
+    web::{self, Json, ReqData},
+    HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{types::Decimal, FromRow, PgPool};
+use uuid::Uuid;
+
+#[derive(Debug, Serialize, Deserialize, sqlx::Type, Clone, Copy, PartialEq)]
+#[sqlx(type_name = "refund_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum RefundStatus {
+    Pending,
+    Succeeded,
+}
+
+#[derive(Serialize, FromRow)]
+struct Refund {
+    refund_id: Uuid,
+    order_id: Uuid,
+    payment_id: Uuid,
+    amount: Decimal,
+    provider_ref: Option<String>,
+    reason: Option<String>,
+    restocked: bool,
+    status: RefundStatus,
+    created_by: Option<Uuid>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+struct RefundBody {
+    // what is left to refund when left out
+    amount: Option<Decimal>,
+    #[serde(default)]
+    restock: bool,
+    reason: Option<String>,
+}
+
+#[derive(Serialize)]
+struct RefundResponse {
+    refund: Refund,
+    status: OrderStatus,
+    refunded_total: Decimal,
+}
+
+impl Refund {
+    // refund a paid order of the store in full or in part through the
+    // provider that took the payment, restocking puts all of the order's
+    // items back once. The refund is recorded as pending before the provider
+    // is called with its id as the idempotency key, so money paid back is
+    // never lost track of. A refund left pending by a failure is sent again
+    // and finished before a new one is taken
+    async fn create(
+        pool: &PgPool,
+        payments: &Payments,
+        store_id: Uuid,
+        order_id: Uuid,
+        body: RefundBody,
+        admin_id: Uuid,
+    ) -> Result<RefundResponse, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        sqlx::query!(
+            "SELECT order_id FROM orders WHERE order_id = $1 AND store_id = $2 FOR UPDATE",
+            order_id,
+            store_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let payment = sqlx::query!(
+            "SELECT payment_id, provider, provider_ref, amount, currency FROM payments
+            WHERE order_id = $1 AND status = 'succeeded'
+            ORDER BY created_at DESC LIMIT 1",
+            order_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or_else(|| sqlx::Error::Protocol("Order has no settled payment to refund".into()))?;
+
+        let currency = Currency::parse(&payment.currency).ok_or_else(|| {
+            sqlx::Error::Protocol(format!("Unknown payment currency {}", payment.currency))
+        })?;
+
+        let pending = sqlx::query_as!(
+            Refund,
+            r#"SELECT refund_id, order_id, payment_id, amount, provider_ref, reason, restocked,
+                status as "status: RefundStatus", created_by, created_at
+            FROM refunds WHERE payment_id = $1 AND status = 'pending'"#,
+            payment.payment_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let refund = match pending {
+            Some(refund) => refund,
+            None => {
+                let previous = sqlx::query!(
+                    r#"SELECT COALESCE(SUM(amount), 0) as "refunded!",
+                        BOOL_OR(restocked) as restocked
+                    FROM refunds WHERE payment_id = $1"#,
+                    payment.payment_id
+                )
+                .fetch_one(&mut *tx)
+                .await?;
+
+                let remaining = Money::new(payment.amount - previous.refunded, currency);
+                let amount = body
+                    .amount
+                    .map_or(remaining, |amount| Money::new(amount, currency));
+                if amount.amount() <= Decimal::ZERO || amount.amount() > remaining.amount() {
+                    return Err(sqlx::Error::Protocol(format!(
+                        "Refund amount must be more than 0 and at most {remaining}"
+                    )));
+                }
+                if body.restock && previous.restocked.unwrap_or(false) {
+                    return Err(sqlx::Error::Protocol(
+                        "Order items were already restocked".into(),
+                    ));
+                }
+
+                sqlx::query_as!(
+                    Refund,
+                    r#"INSERT INTO refunds (
+                        order_id, payment_id, amount, reason, restocked, created_by
+                    )
+                    VALUES ($1, $2, $3, $4, $5, $6)
+                    RETURNING refund_id, order_id, payment_id, amount, provider_ref, reason,
+                        restocked, status as "status: RefundStatus", created_by, created_at"#,
+                    order_id,
+                    payment.payment_id,
+                    amount.amount(),
+                    body.reason,
+                    body.restock,
+                    admin_id
+                )
+                .fetch_one(&mut *tx)
+                .await?
+            }
+        };
+
+        tx.commit().await?;
+
+        // cash on delivery is paid back by hand, there is nobody to call
+        let provider = if payment.provider == CashOnDelivery::NAME {
+            None
+        } else {
+            let provider = payments.get(&payment.provider).ok_or_else(|| {
+                sqlx::Error::Protocol(format!(
+                    "Payment provider error: {} is not configured",
+                    payment.provider
+                ))
+            })?;
+            Some(provider.as_ref())
+        };
+        Refund::complete(pool, provider, refund.refund_id).await
+    }
+
+    // send a pending refund to the provider that took its payment, none for
+    // cash on delivery, with the refund id as the idempotency key and finish
+    // it. It stays pending when the provider fails
+    async fn complete(
+        pool: &PgPool,
+        provider: Option<&dyn PaymentProvider>,
+        refund_id: Uuid,
+    ) -> Result<RefundResponse, sqlx::Error> {
+        let provider_ref = match provider {
+            Some(provider) => {
+                let payment = sqlx::query!(
+                    "SELECT p.provider_ref, p.currency, r.amount FROM refunds r
+                    JOIN payments p ON p.payment_id = r.payment_id
+                    WHERE r.refund_id = $1",
+                    refund_id
+                )
+                .fetch_one(pool)
+                .await?;
+                let currency = Currency::parse(&payment.currency).ok_or_else(|| {
+                    sqlx::Error::Protocol(format!("Unknown payment currency {}", payment.currency))
+                })?;
+                let refund_ref = provider
+                    .refund(
+                        &payment.provider_ref,
+                        Money::new(payment.amount, currency),
+                        &refund_id.to_string(),
+                    )
+                    .await
+                    .map_err(|err| {
+                        sqlx::Error::Protocol(format!("Payment provider error: {err}"))
+                    })?;
+                Some(refund_ref)
+            }
+            None => None,
+        };
+
+        Refund::finish(pool, refund_id, provider_ref).await
+    }
+
+    // mark a pending refund the provider paid back as succeeded, put the
+    // order's items back when it restocks and move the order on. A cancelled
+    // order stays cancelled with the refund noted on it, a refund finished in
+    // the meantime is only read back
+    async fn finish(
+        pool: &PgPool,
+        refund_id: Uuid,
+        provider_ref: Option<String>,
+    ) -> Result<RefundResponse, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        let order = sqlx::query!(
+            r#"SELECT o.status as "status!: OrderStatus", p.amount as paid FROM refunds r
+            JOIN orders o ON o.order_id = r.order_id
+            JOIN payments p ON p.payment_id = r.payment_id
+            WHERE r.refund_id = $1
+            FOR UPDATE OF o"#,
+            refund_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let finished = sqlx::query_as!(
+            Refund,
+            r#"UPDATE refunds SET status = 'succeeded', provider_ref = $2
+            WHERE refund_id = $1 AND status = 'pending'
+            RETURNING refund_id, order_id, payment_id, amount, provider_ref, reason,
+                restocked, status as "status: RefundStatus", created_by, created_at"#,
+            refund_id,
+            provider_ref
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        let just_finished = finished.is_some();
+        let refund = match finished {
+            Some(refund) => refund,
+            None => {
+                sqlx::query_as!(
+                    Refund,
+                    r#"SELECT refund_id, order_id, payment_id, amount, provider_ref, reason,
+                        restocked, status as "status: RefundStatus", created_by, created_at
+                    FROM refunds WHERE refund_id = $1"#,
+                    refund_id
+                )
+                .fetch_one(&mut *tx)
+                .await?
+            }
+        };
+
+        let refunded_total = sqlx::query_scalar!(
+            r#"SELECT COALESCE(SUM(amount), 0) as "refunded!" FROM refunds
+            WHERE payment_id = $1 AND status = 'succeeded'"#,
+            refund.payment_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        let status = match order.status {
+            OrderStatus::Cancelled => OrderStatus::Cancelled,
+            _ if refunded_total >= order.paid => OrderStatus::Refunded,
+            _ => OrderStatus::PartiallyRefunded,
+        };
+
+        if just_finished {
+            if refund.restocked {
+                release_stock(&mut tx, refund.order_id).await?;
+            }
+            if matches!(status, OrderStatus::Cancelled) {
+                record_event(
+                    &mut *tx,
+                    refund.order_id,
+                    "payment.refunded",
+                    json!({ "refund_id": refund.refund_id, "refund_ref": refund.provider_ref }),
+                )
+                .await?;
+            } else {
+                record_status_event(
+                    &mut *tx,
+                    refund.order_id,
+                    "order.refunded",
+                    status.clone(),
+                    json!({
+                        "refund_id": refund.refund_id,
+                        "amount": refund.amount,
+                        "restocked": refund.restocked,
+                        "admin_id": refund.created_by,
+                    }),
+                )
+                .await?;
+            }
+        }
+
+        tx.commit().await?;
+
+        Ok(RefundResponse {
+            refund,
+            status,
+            refunded_total,
+        })
+    }
+}
+
+// send a pending refund again and finish it, for a payment that came in after
+// its order was cancelled and for the refund retry job
+pub async fn complete_refund(
+    pool: &PgPool,
+    provider: Option<&dyn PaymentProvider>,
+    refund_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    Refund::complete(pool, provider, refund_id)
+        .await
+        .map(|_| ())
+}
+
+// admin and support
+// post request to refund an order, in full or for the given amount
+#[post("api/admin/orders/{id}/refund")]
+pub async fn refund_order(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    order_id: web::Path<Uuid>,
+    body: Json<RefundBody>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.can(Permission::IssueRefunds) {
+                match Refund::create(
+                    &state.db,
+                    &state.payments,
+                    store.store_id,
+                    *order_id,
+                    body.into_inner(),
+                    user.user_id,
+                )
+                .await
+                {
+                    Ok(refund) => HttpResponse::Created().json(refund),
+                    Err(sqlx::Error::RowNotFound) => {
+                        HttpResponse::NotFound().json("Order not found")
+                    }
+                    Err(sqlx::Error::Protocol(msg)) if msg.contains("Payment provider error") => {
+                        HttpResponse::BadGateway().json(msg)
+                    }
+                    Err(sqlx::Error::Protocol(msg))
+                        if msg.contains("no settled payment") || msg.contains("already") =>
+                    {
+                        HttpResponse::Conflict().json(msg)
+                    }
+                    Err(sqlx::Error::Protocol(msg)) => HttpResponse::BadRequest().json(msg),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("customer not allowed to refund orders")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
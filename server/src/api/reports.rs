@@ -0,0 +1,340 @@
+use crate::{api::users::TokenClaims, audit, rate_limit::database_error, AppState};
+use actix_web::{
+    get, post, put,
+    web::{self, Json, ReqData},
+    HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{FromRow, PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+#[derive(Debug, Serialize, Deserialize, sqlx::Type, Clone, Copy)]
+#[sqlx(type_name = "report_target", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ReportTarget {
+    Review,
+    Question,
+    Seller,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::Type, Clone, Copy)]
+#[sqlx(type_name = "report_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ReportStatus {
+    Open,
+    Dismissed,
+    Actioned,
+}
+
+#[derive(Serialize, FromRow)]
+struct Report {
+    report_id: Uuid,
+    reporter_id: Uuid,
+    target_type: ReportTarget,
+    target_id: Uuid,
+    reason: String,
+    status: ReportStatus,
+    created_at: DateTime<Utc>,
+    resolved_by: Option<Uuid>,
+    resolved_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+struct ReportBody {
+    target_type: ReportTarget,
+    target_id: Uuid,
+    reason: String,
+}
+
+// what an admin does with a report
+#[derive(Deserialize, Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum ReportAction {
+    Dismiss,
+    HideContent,
+    WarnUser,
+}
+
+#[derive(Deserialize)]
+struct ResolveBody {
+    action: ReportAction,
+    note: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ReportQuery {
+    status: Option<ReportStatus>,
+}
+
+impl Report {
+    // file a report after checking the reported content exists
+    async fn create_report(
+        pool: &PgPool,
+        reporter_id: Uuid,
+        body: ReportBody,
+    ) -> Result<Report, sqlx::Error> {
+        if body.reason.trim().is_empty() {
+            return Err(sqlx::Error::Protocol("Reason is required".into()));
+        }
+
+        let exists = match body.target_type {
+            ReportTarget::Review => sqlx::query!(
+                "SELECT review_id FROM reviews WHERE review_id = $1",
+                body.target_id
+            )
+            .fetch_optional(pool)
+            .await?
+            .is_some(),
+            ReportTarget::Question => sqlx::query!(
+                "SELECT question_id FROM product_questions WHERE question_id = $1",
+                body.target_id
+            )
+            .fetch_optional(pool)
+            .await?
+            .is_some(),
+            ReportTarget::Seller => sqlx::query!(
+                "SELECT user_id FROM users WHERE user_id = $1",
+                body.target_id
+            )
+            .fetch_optional(pool)
+            .await?
+            .is_some(),
+        };
+        if !exists {
+            return Err(sqlx::Error::RowNotFound);
+        }
+
+        sqlx::query_as!(
+            Report,
+            r#"INSERT INTO reports (reporter_id, target_type, target_id, reason)
+            VALUES ($1, $2, $3, $4)
+            RETURNING report_id, reporter_id, target_type as "target_type!: ReportTarget",
+                target_id, reason, status as "status!: ReportStatus", created_at,
+                resolved_by, resolved_at"#,
+            reporter_id,
+            body.target_type as ReportTarget,
+            body.target_id,
+            body.reason
+        )
+        .fetch_one(pool)
+        .await
+    }
+
+    // admin
+    // reports queue, oldest first so nothing waits forever
+    async fn get_reports(pool: &PgPool, status: ReportStatus) -> Result<Vec<Report>, sqlx::Error> {
+        sqlx::query_as!(
+            Report,
+            r#"SELECT report_id, reporter_id, target_type as "target_type!: ReportTarget",
+                target_id, reason, status as "status!: ReportStatus", created_at,
+                resolved_by, resolved_at
+            FROM reports WHERE status = $1 ORDER BY created_at"#,
+            status as ReportStatus
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    // only reviews and questions can be hidden, sellers are handled with warnings
+    async fn hide_content(
+        tx: &mut Transaction<'_, Postgres>,
+        target_type: ReportTarget,
+        target_id: Uuid,
+    ) -> Result<(), sqlx::Error> {
+        match target_type {
+            ReportTarget::Review => {
+                sqlx::query!(
+                    "UPDATE reviews SET is_hidden = TRUE WHERE review_id = $1",
+                    target_id
+                )
+                .execute(&mut **tx)
+                .await?;
+                Ok(())
+            }
+            ReportTarget::Question => {
+                sqlx::query!(
+                    "UPDATE product_questions SET is_hidden = TRUE WHERE question_id = $1",
+                    target_id
+                )
+                .execute(&mut **tx)
+                .await?;
+                Ok(())
+            }
+            ReportTarget::Seller => Err(sqlx::Error::Protocol("Sellers can only be warned".into())),
+        }
+    }
+
+    // admin
+    // act on a report, the action and the resolution are audit logged together
+    async fn resolve_report(
+        pool: &PgPool,
+        report_id: Uuid,
+        admin_id: Uuid,
+        body: ResolveBody,
+    ) -> Result<Report, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        let report = sqlx::query!(
+            r#"SELECT target_type as "target_type!: ReportTarget", target_id
+            FROM reports WHERE report_id = $1 AND status = 'open' FOR UPDATE"#,
+            report_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)?;
+
+        let status = match body.action {
+            ReportAction::Dismiss => ReportStatus::Dismissed,
+            ReportAction::HideContent => {
+                Report::hide_content(&mut tx, report.target_type, report.target_id).await?;
+                ReportStatus::Actioned
+            }
+            ReportAction::WarnUser => {
+                let user_id = match report.target_type {
+                    ReportTarget::Review => {
+                        sqlx::query!(
+                            "SELECT user_id FROM reviews WHERE review_id = $1",
+                            report.target_id
+                        )
+                        .fetch_one(&mut *tx)
+                        .await?
+                        .user_id
+                    }
+                    ReportTarget::Question => {
+                        sqlx::query!(
+                            "SELECT user_id FROM product_questions WHERE question_id = $1",
+                            report.target_id
+                        )
+                        .fetch_one(&mut *tx)
+                        .await?
+                        .user_id
+                    }
+                    ReportTarget::Seller => report.target_id,
+                };
+                let reason = body
+                    .note
+                    .clone()
+                    .unwrap_or_else(|| "content reported".into());
+                sqlx::query!(
+                    "INSERT INTO user_warnings (user_id, reason, issued_by) VALUES ($1, $2, $3)",
+                    user_id,
+                    reason,
+                    admin_id
+                )
+                .execute(&mut *tx)
+                .await?;
+                ReportStatus::Actioned
+            }
+        };
+
+        let resolved = sqlx::query_as!(
+            Report,
+            r#"UPDATE reports SET status = $1, resolved_by = $2, resolved_at = NOW()
+            WHERE report_id = $3
+            RETURNING report_id, reporter_id, target_type as "target_type!: ReportTarget",
+                target_id, reason, status as "status!: ReportStatus", created_at,
+                resolved_by, resolved_at"#,
+            status as ReportStatus,
+            admin_id,
+            report_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        audit::record(
+            &mut *tx,
+            admin_id,
+            "report.resolve",
+            "report",
+            report_id,
+            json!({
+                "action": body.action,
+                "target_type": report.target_type,
+                "target_id": report.target_id,
+                "note": body.note,
+            }),
+        )
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(resolved)
+    }
+}
+
+// post request to report a review, question or seller
+#[post("api/reports")]
+pub async fn create_report(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+    body: Json<ReportBody>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => match Report::create_report(&state.db, user.user_id, body.into_inner()).await
+        {
+            Ok(report) => HttpResponse::Created().json(report),
+            Err(sqlx::Error::RowNotFound) => {
+                HttpResponse::NotFound().json("reported content not found")
+            }
+            Err(sqlx::Error::Protocol(msg)) => HttpResponse::BadRequest().json(msg),
+            Err(err) => database_error(err),
+        },
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// get request for the reports queue, ?status=open|dismissed|actioned
+#[get("api/admin/reports")]
+pub async fn get_reports(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+    query: web::Query<ReportQuery>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                let status = query.status.unwrap_or(ReportStatus::Open);
+                match Report::get_reports(&state.db, status).await {
+                    Ok(reports) => HttpResponse::Ok().json(reports),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("customer not allowed to see reports")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// put request to dismiss a report, hide the content or warn its author
+#[put("api/admin/reports/{id}")]
+pub async fn resolve_report(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+    report_id: web::Path<Uuid>,
+    body: Json<ResolveBody>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match Report::resolve_report(&state.db, *report_id, user.user_id, body.into_inner())
+                    .await
+                {
+                    Ok(report) => HttpResponse::Ok().json(report),
+                    Err(sqlx::Error::RowNotFound) => {
+                        HttpResponse::NotFound().json("open report not found")
+                    }
+                    Err(sqlx::Error::Protocol(msg)) => HttpResponse::BadRequest().json(msg),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("customer not allowed to resolve reports")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
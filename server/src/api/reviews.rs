@@ -0,0 +1,445 @@
+use crate::{
+    api::{stores::Store, users::TokenClaims},
+    images::upload_format,
+    media::MediaUrls,
+    rate_limit::database_error,
+    storage::Storage,
+    AppState,
+};
+use actix_web::{
+    get, post,
+    web::{self, Json, ReqData},
+    HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+#[derive(Debug, Serialize, Deserialize, sqlx::Type, Clone, Copy, PartialEq)]
+#[sqlx(type_name = "review_vote", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ReviewVote {
+    Helpful,
+    Unhelpful,
+}
+
+#[derive(Serialize, Deserialize, FromRow)]
+struct Review {
+    review_id: Uuid,
+    product_id: Uuid,
+    user_id: Uuid,
+    rating: i32,
+    body: Option<String>,
+    helpful_count: i32,
+    unhelpful_count: i32,
+    created_at: DateTime<Utc>,
+    is_hidden: bool,
+}
+
+// a review as listed, with URLs of the customer's photos
+#[derive(Serialize)]
+struct ReviewWithImages {
+    #[serde(flatten)]
+    review: Review,
+    images: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ReviewBody {
+    rating: i32,
+    body: Option<String>,
+}
+
+// photos a customer can attach to one review
+const MAX_REVIEW_IMAGES: i64 = 4;
+
+#[derive(Deserialize)]
+struct VoteBody {
+    vote: ReviewVote,
+}
+
+// sort options for review listing
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+enum ReviewSort {
+    #[default]
+    Newest,
+    Helpful,
+}
+
+#[derive(Deserialize)]
+struct ReviewQuery {
+    sort: Option<ReviewSort>,
+}
+
+impl Review {
+    // get reviews of a product in the store, newest first or most helpful first
+    async fn get_product_reviews(
+        pool: &PgPool,
+        store_id: Uuid,
+        product_id: Uuid,
+        sort: ReviewSort,
+    ) -> Result<Vec<Review>, sqlx::Error> {
+        match sort {
+            ReviewSort::Newest => {
+                sqlx::query_as!(
+                    Review,
+                    "SELECT * FROM reviews WHERE product_id = $1 AND NOT is_hidden
+                    AND product_id IN (SELECT product_id FROM products WHERE store_id = $2)
+                    ORDER BY created_at DESC",
+                    product_id,
+                    store_id
+                )
+                .fetch_all(pool)
+                .await
+            }
+            ReviewSort::Helpful => {
+                sqlx::query_as!(
+                    Review,
+                    "SELECT * FROM reviews WHERE product_id = $1 AND NOT is_hidden
+                    AND product_id IN (SELECT product_id FROM products WHERE store_id = $2)
+                    ORDER BY helpful_count - unhelpful_count DESC, created_at DESC",
+                    product_id,
+                    store_id
+                )
+                .fetch_all(pool)
+                .await
+            }
+        }
+    }
+
+    // attach the photos of each review, in upload order
+    async fn with_images(
+        pool: &PgPool,
+        media: &MediaUrls,
+        reviews: Vec<Review>,
+    ) -> Result<Vec<ReviewWithImages>, sqlx::Error> {
+        let review_ids: Vec<Uuid> = reviews.iter().map(|review| review.review_id).collect();
+        let images = sqlx::query!(
+            "SELECT review_id, object_key FROM review_images
+            WHERE review_id = ANY($1) ORDER BY created_at",
+            &review_ids
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(reviews
+            .into_iter()
+            .map(|review| ReviewWithImages {
+                images: images
+                    .iter()
+                    .filter(|image| image.review_id == review.review_id)
+                    .map(|image| media.url(&image.object_key))
+                    .collect(),
+                review,
+            })
+            .collect())
+    }
+
+    // create review of a product in the store, one per user per product
+    async fn create_review(
+        pool: &PgPool,
+        store_id: Uuid,
+        product_id: Uuid,
+        user_id: Uuid,
+        body: ReviewBody,
+    ) -> Result<Review, sqlx::Error> {
+        if !(1..=5).contains(&body.rating) {
+            return Err(sqlx::Error::Protocol(
+                "Rating must be between 1 and 5".into(),
+            ));
+        }
+
+        sqlx::query_as!(
+            Review,
+            "INSERT INTO reviews (product_id, user_id, rating, body)
+            SELECT product_id, $2, $3, $4 FROM products WHERE product_id = $1 AND store_id = $5
+            RETURNING *",
+            product_id,
+            user_id,
+            body.rating,
+            body.body,
+            store_id
+        )
+        .fetch_optional(pool)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    // store a photo for the author's own review, returns its URL
+    async fn upload_image(
+        pool: &PgPool,
+        media: &MediaUrls,
+        storage: &dyn Storage,
+        review_id: Uuid,
+        user_id: Uuid,
+        bytes: &[u8],
+    ) -> Result<String, sqlx::Error> {
+        let format = upload_format(bytes)
+            .ok_or_else(|| sqlx::Error::Protocol("Upload a JPEG, PNG or WebP image".into()))?;
+
+        // the review row is locked while counting so two uploads at once can't
+        // both take the last free slot
+        let mut tx = pool.begin().await?;
+        let review = sqlx::query!(
+            "SELECT user_id FROM reviews WHERE review_id = $1 FOR UPDATE",
+            review_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)?;
+
+        if review.user_id != user_id {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        let images = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) as "count!" FROM review_images WHERE review_id = $1"#,
+            review_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        if images >= MAX_REVIEW_IMAGES {
+            return Err(sqlx::Error::Protocol(format!(
+                "A review can have at most {MAX_REVIEW_IMAGES} photos"
+            )));
+        }
+
+        let image_id = Uuid::new_v4();
+        let object_key = format!(
+            "reviews/{review_id}/{image_id}.{}",
+            format.extensions_str()[0]
+        );
+        sqlx::query!(
+            "INSERT INTO review_images (image_id, review_id, object_key) VALUES ($1, $2, $3)",
+            image_id,
+            review_id,
+            object_key
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        // the upload happens outside the lock, a failed one gives the slot back
+        if let Err(err) = storage
+            .put(&object_key, bytes.to_vec(), format.to_mime_type())
+            .await
+        {
+            sqlx::query!("DELETE FROM review_images WHERE image_id = $1", image_id)
+                .execute(pool)
+                .await?;
+            return Err(err.into());
+        }
+
+        Ok(media.url(&object_key))
+    }
+
+    // cast or change a vote, keeping the denormalized counts in sync
+    async fn vote(
+        pool: &PgPool,
+        store_id: Uuid,
+        review_id: Uuid,
+        user_id: Uuid,
+        vote: ReviewVote,
+    ) -> Result<Review, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        let review = sqlx::query!(
+            "SELECT user_id FROM reviews WHERE review_id = $1 AND NOT is_hidden
+            AND product_id IN (SELECT product_id FROM products WHERE store_id = $2)
+            FOR UPDATE",
+            review_id,
+            store_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)?;
+
+        if review.user_id == user_id {
+            return Err(sqlx::Error::Protocol(
+                "Cannot vote on your own review".into(),
+            ));
+        }
+
+        let previous = sqlx::query!(
+            r#"SELECT vote as "vote!: ReviewVote" FROM review_votes
+            WHERE review_id = $1 AND user_id = $2"#,
+            review_id,
+            user_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .map(|row| row.vote);
+
+        if previous != Some(vote) {
+            sqlx::query!(
+                "INSERT INTO review_votes (review_id, user_id, vote) VALUES ($1, $2, $3)
+                ON CONFLICT (review_id, user_id) DO UPDATE SET vote = $3, created_at = NOW()",
+                review_id,
+                user_id,
+                vote as ReviewVote
+            )
+            .execute(&mut *tx)
+            .await?;
+
+            // +1 for the new vote, -1 for the vote it replaces
+            let (helpful_delta, unhelpful_delta) = match (previous, vote) {
+                (None, ReviewVote::Helpful) => (1, 0),
+                (None, ReviewVote::Unhelpful) => (0, 1),
+                (Some(ReviewVote::Unhelpful), ReviewVote::Helpful) => (1, -1),
+                (Some(ReviewVote::Helpful), ReviewVote::Unhelpful) => (-1, 1),
+                _ => (0, 0),
+            };
+
+            sqlx::query!(
+                "UPDATE reviews SET helpful_count = helpful_count + $1,
+                unhelpful_count = unhelpful_count + $2 WHERE review_id = $3",
+                helpful_delta,
+                unhelpful_delta,
+                review_id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        let review = sqlx::query_as!(
+            Review,
+            "SELECT * FROM reviews WHERE review_id = $1",
+            review_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(review)
+    }
+}
+
+// get request to list reviews of a product, ?sort=newest|helpful
+#[get("api/product/{id}/reviews")]
+pub async fn get_product_reviews(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    product_id: web::Path<Uuid>,
+    query: web::Query<ReviewQuery>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    let sort = query.into_inner().sort.unwrap_or_default();
+    match req_user {
+        Some(_) => {
+            let reviews =
+                match Review::get_product_reviews(&state.db, store.store_id, *product_id, sort)
+                    .await
+                {
+                    Ok(reviews) => reviews,
+                    Err(err) => return database_error(err),
+                };
+            match Review::with_images(&state.db, &state.media, reviews).await {
+                Ok(reviews) => HttpResponse::Ok().json(reviews),
+                Err(err) => database_error(err),
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// post request to review a product
+#[post("api/product/{id}/reviews")]
+pub async fn create_review(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    product_id: web::Path<Uuid>,
+    req_user: Option<ReqData<TokenClaims>>,
+    body: Json<ReviewBody>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            match Review::create_review(
+                &state.db,
+                store.store_id,
+                *product_id,
+                user.user_id,
+                body.into_inner(),
+            )
+            .await
+            {
+                Ok(review) => HttpResponse::Created().json(review),
+                Err(sqlx::Error::RowNotFound) => HttpResponse::NotFound().json("product not found"),
+                Err(sqlx::Error::Protocol(msg)) => HttpResponse::BadRequest().json(msg),
+                Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+                    HttpResponse::Conflict().json("product already reviewed")
+                }
+                // deleted while the review went in
+                Err(sqlx::Error::Database(err)) if err.is_foreign_key_violation() => {
+                    HttpResponse::NotFound().json("product not found")
+                }
+                Err(err) => database_error(err),
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// post request to vote a review helpful or unhelpful
+#[post("api/reviews/{id}/vote")]
+pub async fn vote_review(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    review_id: web::Path<Uuid>,
+    req_user: Option<ReqData<TokenClaims>>,
+    body: Json<VoteBody>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => match Review::vote(
+            &state.db,
+            store.store_id,
+            *review_id,
+            user.user_id,
+            body.vote,
+        )
+        .await
+        {
+            Ok(review) => HttpResponse::Ok().json(review),
+            Err(sqlx::Error::RowNotFound) => HttpResponse::NotFound().json("review not found"),
+            Err(sqlx::Error::Protocol(msg)) => HttpResponse::BadRequest().json(msg),
+            Err(err) => database_error(err),
+        },
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// post request with the raw image as body to add a photo to your own review
+#[post("api/reviews/{id}/images")]
+pub async fn upload_review_image(
+    state: web::Data<AppState>,
+    review_id: web::Path<Uuid>,
+    req_user: Option<ReqData<TokenClaims>>,
+    payload: web::Payload,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            let bytes = match payload.to_bytes_limited(state.media.max_upload_bytes).await {
+                Ok(Ok(bytes)) => bytes,
+                Ok(Err(_)) => return HttpResponse::PayloadTooLarge().json("image is too large"),
+                Err(err) => return HttpResponse::BadRequest().json(err.to_string()),
+            };
+            match Review::upload_image(
+                &state.db,
+                &state.media,
+                state.storage.as_ref(),
+                *review_id,
+                user.user_id,
+                &bytes,
+            )
+            .await
+            {
+                Ok(url) => HttpResponse::Created().json(url),
+                Err(sqlx::Error::RowNotFound) => HttpResponse::NotFound().json("review not found"),
+                Err(sqlx::Error::Protocol(msg)) => HttpResponse::BadRequest().json(msg),
+                Err(err) => database_error(err),
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
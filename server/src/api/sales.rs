@@ -0,0 +1,268 @@
+use crate::{
+    api::{stores::Store, users::TokenClaims},
+    rate_limit::database_error,
+    AppState,
+};
+use actix_web::{
+    get,
+    web::{self, ReqData},
+    HttpResponse, Responder,
+};
+use chrono::{DateTime, Datelike, Duration, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{types::Decimal, FromRow, PgPool};
+use uuid::Uuid;
+
+// how many products the summary ranks
+const TOP_PRODUCTS: i64 = 5;
+
+// what sold in a period. Orders count from when they were placed, unpaid and
+// cancelled ones are left out, refunds count from when they were made
+#[derive(Serialize)]
+pub struct SalesSummary {
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    orders: i64,
+    revenue: Decimal,
+    average_order: Decimal,
+    units: Decimal,
+    refunded: Decimal,
+    top_products: Vec<ProductSales>,
+}
+
+#[derive(Serialize, FromRow)]
+pub struct ProductSales {
+    product_id: Uuid,
+    name: String,
+    quantity: Decimal,
+    revenue: Decimal,
+}
+
+#[derive(Deserialize)]
+struct SalesQuery {
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+}
+
+impl SalesSummary {
+    // from inclusive, to exclusive, of one store or every store without one
+    pub async fn between(
+        pool: &PgPool,
+        store_id: Option<Uuid>,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<SalesSummary, sqlx::Error> {
+        let totals = sqlx::query!(
+            r#"SELECT COUNT(*) as "orders!", COALESCE(SUM(total_amount), 0) as "revenue!",
+                COALESCE(SUM(total_quantity), 0) as "units!"
+            FROM order_summaries
+            WHERE created_at >= $1 AND created_at < $2
+                AND status NOT IN ('pendingpayment', 'cancelled')
+                AND ($3::uuid IS NULL OR store_id = $3)"#,
+            from,
+            to,
+            store_id
+        )
+        .fetch_one(pool)
+        .await?;
+
+        let refunded = sqlx::query_scalar!(
+            r#"SELECT COALESCE(SUM(r.amount), 0) as "refunded!" FROM refunds r
+            JOIN orders o ON o.order_id = r.order_id
+            WHERE r.created_at >= $1 AND r.created_at < $2 AND r.status = 'succeeded'
+                AND ($3::uuid IS NULL OR o.store_id = $3)"#,
+            from,
+            to,
+            store_id
+        )
+        .fetch_one(pool)
+        .await?;
+
+        let mut top_products = sqlx::query_as!(
+            ProductSales,
+            r#"SELECT p.product_id, p.name, SUM(od.quantity) as "quantity!",
+                ROUND(SUM(od.quantity * od.price_per_unit), 2) as "revenue!"
+            FROM order_details od
+            JOIN orders o ON o.order_id = od.order_id
+            JOIN products p ON p.product_id = od.product_id
+            WHERE o.created_at >= $1 AND o.created_at < $2
+                AND o.status NOT IN ('pendingpayment', 'cancelled')
+                AND ($4::uuid IS NULL OR o.store_id = $4)
+            GROUP BY p.product_id, p.name
+            ORDER BY 4 DESC, p.name
+            LIMIT $3"#,
+            from,
+            to,
+            TOP_PRODUCTS,
+            store_id
+        )
+        .fetch_all(pool)
+        .await?;
+
+        for product in &mut top_products {
+            product.quantity = product.quantity.normalize();
+        }
+
+        let average_order = if totals.orders > 0 {
+            (totals.revenue / Decimal::from(totals.orders)).round_dp(2)
+        } else {
+            Decimal::ZERO
+        };
+
+        Ok(SalesSummary {
+            from,
+            to,
+            orders: totals.orders,
+            revenue: totals.revenue,
+            average_order,
+            units: totals.units.normalize(),
+            refunded,
+            top_products,
+        })
+    }
+}
+
+// which summaries are mailed and to whom. SALES_REPORT_RECIPIENTS is a comma
+// separated list of addresses, SALES_REPORT_SCHEDULE daily, weekly or both
+// (default weekly) and SALES_REPORT_HOUR the hour in UTC they go out after
+// (default 6). Days start at midnight UTC and weeks on Monday. The mailed
+// summary covers every store
+pub struct ReportSchedule {
+    recipients: Vec<String>,
+    daily: bool,
+    weekly: bool,
+    hour: u32,
+}
+
+impl ReportSchedule {
+    pub fn from_env() -> Option<Self> {
+        let recipients: Vec<String> = std::env::var("SALES_REPORT_RECIPIENTS")
+            .ok()?
+            .split(',')
+            .map(|address| address.trim().to_lowercase())
+            .filter(|address| !address.is_empty())
+            .collect();
+        if recipients.is_empty() {
+            return None;
+        }
+
+        let schedule =
+            std::env::var("SALES_REPORT_SCHEDULE").unwrap_or_else(|_| "weekly".to_string());
+        let schedules: Vec<&str> = schedule.split(',').map(str::trim).collect();
+        for name in &schedules {
+            if !matches!(*name, "daily" | "weekly") {
+                panic!("SALES_REPORT_SCHEDULE must be daily, weekly or both, not {name}");
+            }
+        }
+        let hour = std::env::var("SALES_REPORT_HOUR")
+            .ok()
+            .map(|hour| {
+                hour.parse()
+                    .ok()
+                    .filter(|hour| *hour < 24)
+                    .expect("SALES_REPORT_HOUR must be an hour from 0 to 23")
+            })
+            .unwrap_or(6);
+
+        Some(ReportSchedule {
+            recipients,
+            daily: schedules.contains(&"daily"),
+            weekly: schedules.contains(&"weekly"),
+            hour,
+        })
+    }
+
+    // the periods that ended and are due by now, as (schedule, from, to)
+    pub fn due(&self, now: DateTime<Utc>) -> Vec<(&'static str, DateTime<Utc>, DateTime<Utc>)> {
+        let today = Utc
+            .with_ymd_and_hms(now.year(), now.month(), now.day(), 0, 0, 0)
+            .unwrap();
+        let send_after = Duration::hours(self.hour.into());
+
+        let mut due = Vec::new();
+        if self.daily && now >= today + send_after {
+            due.push(("daily", today - Duration::days(1), today));
+        }
+        let monday = today - Duration::days(now.weekday().num_days_from_monday().into());
+        if self.weekly && now >= monday + send_after {
+            due.push(("weekly", monday - Duration::weeks(1), monday));
+        }
+        due
+    }
+
+    // queue the mail for a period unless it was sent already, false then
+    pub async fn queue(
+        &self,
+        pool: &PgPool,
+        schedule: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<bool, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        let claimed = sqlx::query!(
+            "INSERT INTO sales_report_runs (schedule, period_start, period_end, recipients)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT DO NOTHING",
+            schedule,
+            from,
+            to,
+            self.recipients.len() as i32
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+            == 1;
+        if !claimed {
+            return Ok(false);
+        }
+
+        let summary = SalesSummary::between(pool, None, from, to).await?;
+        let mut data = serde_json::to_value(&summary).unwrap_or_default();
+        data["schedule"] = json!(schedule);
+        for recipient in &self.recipients {
+            sqlx::query!(
+                "INSERT INTO email_messages (recipient, kind, data)
+                VALUES ($1, 'report.sales', $2)",
+                recipient,
+                data
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(true)
+    }
+}
+
+// admin only
+// get request for the store's sales summary between from and to, the last
+// 7 days when left out
+#[get("api/admin/reports/sales")]
+pub async fn get_sales_summary(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    query: web::Query<SalesQuery>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                let to = query.to.unwrap_or_else(Utc::now);
+                let from = query.from.unwrap_or(to - Duration::days(7));
+                if from >= to {
+                    return HttpResponse::BadRequest().json("from must be before to");
+                }
+                match SalesSummary::between(&state.db, Some(store.store_id), from, to).await {
+                    Ok(summary) => HttpResponse::Ok().json(summary),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("customer not allowed to see sales")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
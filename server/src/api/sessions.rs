@@ -0,0 +1,170 @@
+use crate::{
+    api::{customer_groups::CustomerGroup, users::TokenClaims},
+    client_ip::client_ip,
+    rate_limit::database_error,
+    AppState,
+};
+use actix_web::{
+    delete, get,
+    web::{self, ReqData},
+    HttpRequest, HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+#[derive(Serialize, FromRow)]
+pub struct Session {
+    session_id: Uuid,
+    user_agent: Option<String>,
+    ip_address: Option<String>,
+    created_at: DateTime<Utc>,
+    last_used_at: DateTime<Utc>,
+}
+
+// a session still in use, with what the token can't carry: the store its
+// user is an admin of, the customer group they are in and the policy
+// versions they accepted may change while the session lasts
+pub struct LiveSession {
+    pub admin_store: Option<Uuid>,
+    pub customer_group: CustomerGroup,
+    pub accepted_terms: Option<String>,
+    pub accepted_privacy: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SessionResponse {
+    #[serde(flatten)]
+    session: Session,
+    current: bool,
+}
+
+impl Session {
+    // record a new login for the device making the request
+    pub async fn create(
+        pool: &PgPool,
+        user_id: Uuid,
+        req: &HttpRequest,
+    ) -> Result<Uuid, sqlx::Error> {
+        let user_agent = req
+            .headers()
+            .get("User-Agent")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned);
+        let ip_address = client_ip(req).map(|ip| ip.to_string());
+
+        let session = sqlx::query!(
+            "INSERT INTO sessions (user_id, user_agent, ip_address) VALUES ($1, $2, $3)
+            RETURNING session_id",
+            user_id,
+            user_agent,
+            ip_address
+        )
+        .fetch_one(pool)
+        .await?;
+
+        Ok(session.session_id)
+    }
+
+    // mark the session used, none when it was revoked or doesn't belong to the user.
+    // last_used_at only moves once a minute, requests in between just read it
+    pub async fn touch(
+        pool: &PgPool,
+        session_id: Uuid,
+        user_id: Uuid,
+    ) -> Result<Option<LiveSession>, sqlx::Error> {
+        sqlx::query_as!(
+            LiveSession,
+            r#"WITH live AS (
+                SELECT s.session_id, s.last_used_at, u.user_id, u.store_id, u.customer_group
+                FROM sessions s JOIN users u ON u.user_id = s.user_id
+                WHERE s.session_id = $1 AND s.user_id = $2 AND s.revoked_at IS NULL
+            ), touched AS (
+                UPDATE sessions SET last_used_at = NOW()
+                FROM live
+                WHERE sessions.session_id = live.session_id
+                    AND live.last_used_at < NOW() - INTERVAL '1 minute'
+            )
+            SELECT live.store_id as admin_store,
+                live.customer_group as "customer_group!: CustomerGroup",
+                (SELECT version FROM policy_acceptances a
+                 WHERE a.user_id = live.user_id AND a.policy = 'terms'
+                 ORDER BY a.created_at DESC LIMIT 1) as accepted_terms,
+                (SELECT version FROM policy_acceptances a
+                 WHERE a.user_id = live.user_id AND a.policy = 'privacy'
+                 ORDER BY a.created_at DESC LIMIT 1) as accepted_privacy
+            FROM live"#,
+            session_id,
+            user_id
+        )
+        .fetch_optional(pool)
+        .await
+    }
+
+    async fn get_user_sessions(pool: &PgPool, user_id: Uuid) -> Result<Vec<Session>, sqlx::Error> {
+        sqlx::query_as!(
+            Session,
+            "SELECT session_id, user_agent, ip_address, created_at, last_used_at
+            FROM sessions WHERE user_id = $1 AND revoked_at IS NULL
+            ORDER BY last_used_at DESC",
+            user_id
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    async fn revoke(pool: &PgPool, session_id: Uuid, user_id: Uuid) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            "UPDATE sessions SET revoked_at = NOW()
+            WHERE session_id = $1 AND user_id = $2 AND revoked_at IS NULL",
+            session_id,
+            user_id
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+// get request to list the current user's active sessions
+#[get("api/users/me/sessions")]
+pub async fn get_sessions(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => match Session::get_user_sessions(&state.db, user.user_id).await {
+            Ok(sessions) => {
+                let sessions: Vec<SessionResponse> = sessions
+                    .into_iter()
+                    .map(|session| SessionResponse {
+                        current: session.session_id == user.session_id,
+                        session,
+                    })
+                    .collect();
+                HttpResponse::Ok().json(sessions)
+            }
+            Err(err) => database_error(err),
+        },
+        None => HttpResponse::Unauthorized().json("please log in first"),
+    }
+}
+
+// delete request to revoke one of the current user's sessions
+#[delete("api/users/me/sessions/{id}")]
+pub async fn revoke_session(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+    session_id: web::Path<Uuid>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => match Session::revoke(&state.db, *session_id, user.user_id).await {
+            Ok(true) => HttpResponse::Ok().json("session revoked"),
+            Ok(false) => HttpResponse::NotFound().json("session not found"),
+            Err(err) => database_error(err),
+        },
+        None => HttpResponse::Unauthorized().json("please log in first"),
+    }
+}
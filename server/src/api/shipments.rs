@@ -0,0 +1,287 @@
+use crate::{
+    api::{
+        notifications::Notification,
+        orders::{record_event, record_status_event, OrderStatus},
+        stores::Store,
+        users::{Permission, TokenClaims},
+    },
+    carriers::{ShipmentStatus, SIGNATURE_HEADER},
+    rate_limit::database_error,
+    system_events, AppState,
+};
+use actix_web::{
+    post,
+    web::{self, Bytes, Json, ReqData},
+    HttpRequest, HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+#[derive(Serialize, FromRow)]
+struct Shipment {
+    shipment_id: Uuid,
+    order_id: Uuid,
+    carrier: String,
+    tracking_number: String,
+    status: ShipmentStatus,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+struct ShipmentBody {
+    carrier: String,
+    tracking_number: String,
+}
+
+// tracking callback as carriers post it to the webhook
+#[derive(Deserialize)]
+struct TrackingEvent {
+    tracking_number: String,
+    status: String,
+    description: Option<String>,
+    occurred_at: Option<DateTime<Utc>>,
+}
+
+impl Shipment {
+    // admin
+    // attach a carrier tracking number to an order of the store
+    async fn create(
+        pool: &PgPool,
+        store_id: Uuid,
+        order_id: Uuid,
+        body: ShipmentBody,
+    ) -> Result<Shipment, sqlx::Error> {
+        let carrier = body.carrier.trim().to_lowercase();
+        let tracking_number = body.tracking_number.trim();
+        if carrier.is_empty() || tracking_number.is_empty() {
+            return Err(sqlx::Error::Protocol(
+                "Carrier and tracking number are required".into(),
+            ));
+        }
+
+        let mut tx = pool.begin().await?;
+
+        sqlx::query!(
+            "SELECT order_id FROM orders WHERE order_id = $1 AND store_id = $2",
+            order_id,
+            store_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)?;
+
+        let shipment = sqlx::query_as!(
+            Shipment,
+            r#"INSERT INTO shipments (order_id, carrier, tracking_number)
+            VALUES ($1, $2, $3)
+            RETURNING shipment_id, order_id, carrier, tracking_number,
+                status as "status!: ShipmentStatus", created_at, updated_at"#,
+            order_id,
+            carrier,
+            tracking_number
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        record_event(
+            &mut *tx,
+            order_id,
+            "shipment.created",
+            json!({ "carrier": shipment.carrier, "tracking_number": shipment.tracking_number }),
+        )
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(shipment)
+    }
+
+    // apply a carrier callback: update the shipment unless it is already further
+    // along, mark the order shipped once it moves, log it to the order history and tell the customer, by text too
+    // when texts are sent
+    async fn apply_tracking(
+        pool: &PgPool,
+        carrier: &str,
+        event: TrackingEvent,
+        status: ShipmentStatus,
+        texts: bool,
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        let shipment = sqlx::query!(
+            r#"SELECT s.shipment_id, s.order_id, s.status as "status!: ShipmentStatus", o.user_id,
+                o.status as "order_status!: OrderStatus"
+            FROM shipments s JOIN orders o ON o.order_id = s.order_id
+            WHERE s.carrier = $1 AND s.tracking_number = $2
+            FOR UPDATE OF s, o"#,
+            carrier,
+            event.tracking_number
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)?;
+
+        // carriers resend callbacks, only act on changes
+        if shipment.status == status {
+            return Ok(());
+        }
+        if !shipment.status.can_move_to(status) {
+            return Err(sqlx::Error::Protocol(format!(
+                "Shipment is already past {}",
+                event.status
+            )));
+        }
+
+        sqlx::query!(
+            "UPDATE shipments SET status = $1, updated_at = NOW() WHERE shipment_id = $2",
+            status as ShipmentStatus,
+            shipment.shipment_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        let moved = matches!(
+            status,
+            ShipmentStatus::InTransit | ShipmentStatus::OutForDelivery | ShipmentStatus::Delivered
+        );
+        let details = json!({
+            "carrier": carrier,
+            "tracking_number": event.tracking_number,
+            "status": status,
+            "description": event.description,
+            "occurred_at": event.occurred_at,
+        });
+        if moved
+            && matches!(
+                shipment.order_status,
+                OrderStatus::Pending | OrderStatus::Confirmed
+            )
+        {
+            record_status_event(
+                &mut *tx,
+                shipment.order_id,
+                "shipment.status",
+                OrderStatus::Shipped,
+                details,
+            )
+            .await?;
+        } else {
+            record_event(&mut *tx, shipment.order_id, "shipment.status", details).await?;
+        }
+
+        if let Some(message) = status.notification() {
+            Notification::create(&mut *tx, shipment.user_id, message).await?;
+
+            // the last steps of the delivery are worth a text, for the
+            // dispatcher to send
+            if texts
+                && matches!(
+                    status,
+                    ShipmentStatus::OutForDelivery
+                        | ShipmentStatus::Delivered
+                        | ShipmentStatus::Exception
+                )
+            {
+                sqlx::query!(
+                    "INSERT INTO sms_messages (user_id, phone, kind, body)
+                    SELECT user_id, phone, 'delivery', $2 FROM users
+                    WHERE user_id = $1 AND phone IS NOT NULL AND sms_delivery_updates",
+                    shipment.user_id,
+                    message
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+}
+
+// admin and warehouse staff
+// post request to add a carrier tracking number to an order
+#[post("api/admin/orders/{id}/shipments")]
+pub async fn create_shipment(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    order_id: web::Path<Uuid>,
+    body: Json<ShipmentBody>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.can(Permission::ShipOrders) {
+                match Shipment::create(&state.db, store.store_id, *order_id, body.into_inner())
+                    .await
+                {
+                    Ok(shipment) => HttpResponse::Created().json(shipment),
+                    Err(sqlx::Error::RowNotFound) => {
+                        HttpResponse::NotFound().json("order not found")
+                    }
+                    Err(sqlx::Error::Protocol(msg)) => HttpResponse::BadRequest().json(msg),
+                    Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+                        HttpResponse::Conflict().json("tracking number already in use")
+                    }
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("customer not allowed to add shipments")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// post request from a carrier with a tracking update, signed with the
+// carrier's webhook secret in the X-Webhook-Signature header
+#[post("api/webhooks/carrier/{carrier}")]
+pub async fn carrier_webhook(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    carrier: web::Path<String>,
+    body: Bytes,
+) -> impl Responder {
+    let carrier = carrier.to_lowercase();
+    if !state.carriers.is_configured(&carrier) {
+        return HttpResponse::NotFound().json("unknown carrier");
+    }
+
+    let signature = req
+        .headers()
+        .get(SIGNATURE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    if !state.carriers.verify(&carrier, &body, signature) {
+        return HttpResponse::Unauthorized().json("invalid signature");
+    }
+
+    let event: TrackingEvent = match serde_json::from_slice(&body) {
+        Ok(event) => event,
+        Err(err) => return HttpResponse::BadRequest().json(err.to_string()),
+    };
+    let Some(status) = ShipmentStatus::from_carrier(&event.status) else {
+        return HttpResponse::UnprocessableEntity()
+            .json(format!("unknown tracking status: {}", event.status));
+    };
+
+    match Shipment::apply_tracking(&state.db, &carrier, event, status, state.sms.is_some()).await {
+        Ok(()) => HttpResponse::Ok().json("tracking update applied"),
+        Err(sqlx::Error::RowNotFound) => HttpResponse::NotFound().json("shipment not found"),
+        Err(sqlx::Error::Protocol(msg)) => HttpResponse::Conflict().json(msg),
+        // the carrier sends it again, staff see that it is retrying
+        Err(err) => {
+            let details =
+                json!({ "source": format!("carriers/{carrier}"), "error": format!("{err:?}") });
+            if let Err(err) = system_events::record(&state.db, "webhook.failed", details).await {
+                println!("failed to record the failed webhook: {err:?}");
+            }
+            database_error(err)
+        }
+    }
+}
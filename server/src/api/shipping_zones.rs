@@ -0,0 +1,436 @@
+use crate::{
+    api::{stores::Store, users::TokenClaims},
+    pricing::{CartLine, Parcel, Pricing, ShippingMethod},
+    rate_limit::database_error,
+    AppState,
+};
+use actix_web::{
+    delete, get, post,
+    web::{self, Json, ReqData},
+    HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Decimal, FromRow, PgConnection, PgExecutor, PgPool};
+use uuid::Uuid;
+
+#[derive(Serialize, FromRow)]
+pub struct ShippingZone {
+    zone_id: Uuid,
+    name: String,
+    country: String,
+    postcode_from: Option<String>,
+    postcode_to: Option<String>,
+    priority: i32,
+    created_at: DateTime<Utc>,
+    store_id: Uuid,
+}
+
+// a method offered for an address, priced for the parcel
+#[derive(Serialize, Deserialize, FromRow)]
+pub struct ShippingOption {
+    method: ShippingMethod,
+    rate: Decimal,
+}
+
+// how a zone prices a method: rate plus per_kg for every billable kg
+#[derive(Serialize, Deserialize, FromRow)]
+struct ShippingRate {
+    method: ShippingMethod,
+    rate: Decimal,
+    #[serde(default)]
+    per_kg: Decimal,
+    // heavier parcels can't go with this method
+    max_weight_kg: Option<Decimal>,
+}
+
+#[derive(Serialize)]
+struct ZoneWithRates {
+    #[serde(flatten)]
+    zone: ShippingZone,
+    rates: Vec<ShippingRate>,
+}
+
+#[derive(Deserialize)]
+struct ZoneBody {
+    name: String,
+    country: String,
+    postcode_from: Option<String>,
+    postcode_to: Option<String>,
+    #[serde(default)]
+    priority: i32,
+    rates: Vec<ShippingRate>,
+}
+
+#[derive(Deserialize)]
+struct AddressQuery {
+    country: Option<String>,
+    postcode: Option<String>,
+}
+
+impl ShippingZone {
+    // what a set of lines weighs and measures, kits use their own measurements
+    pub async fn parcel_for<'c>(
+        executor: impl PgExecutor<'c>,
+        lines: &[CartLine],
+    ) -> Result<Parcel, sqlx::Error> {
+        let product_ids: Vec<Uuid> = lines.iter().filter_map(|line| line.product_id).collect();
+        let quantities: Vec<Decimal> = lines
+            .iter()
+            .filter(|line| line.product_id.is_some())
+            .map(|line| line.quantity)
+            .collect();
+
+        sqlx::query_as!(
+            Parcel,
+            r#"SELECT
+                COALESCE(SUM(l.quantity * COALESCE(
+                    p.weight_kg, CASE WHEN p.unit = 'kg' THEN 1 ELSE 0 END
+                )), 0) as "weight_kg!",
+                COALESCE(SUM(l.quantity * p.length_cm * p.width_cm * p.height_cm), 0)
+                    as "volume_cm3!"
+            FROM UNNEST($1::uuid[], $2::numeric[]) AS l(product_id, quantity)
+            JOIN products p ON p.product_id = l.product_id"#,
+            &product_ids,
+            &quantities
+        )
+        .fetch_one(executor)
+        .await
+    }
+
+    // the parcel for what is in the user's active cart in the store, empty
+    // without one
+    async fn cart_parcel(
+        pool: &PgPool,
+        store_id: Uuid,
+        user_id: Uuid,
+    ) -> Result<Parcel, sqlx::Error> {
+        sqlx::query_as!(
+            Parcel,
+            r#"SELECT
+                COALESCE(SUM(ci.quantity * COALESCE(
+                    p.weight_kg, CASE WHEN p.unit = 'kg' THEN 1 ELSE 0 END
+                )), 0) as "weight_kg!",
+                COALESCE(SUM(ci.quantity * p.length_cm * p.width_cm * p.height_cm), 0)
+                    as "volume_cm3!"
+            FROM cart_items ci
+            JOIN carts c ON c.cart_id = ci.cart_id
+            JOIN products p ON p.product_id = ci.product_id
+            WHERE c.user_id = $1 AND c.store_id = $2 AND c.is_active
+            AND NOT ci.saved_for_later"#,
+            user_id,
+            store_id
+        )
+        .fetch_one(pool)
+        .await
+    }
+
+    // methods and rates for an address: the store's best matching zone priced
+    // for the parcel's billable weight, or the flat rates while it has no
+    // zones. Pickup is offered on top of either
+    pub async fn options_for(
+        conn: &mut PgConnection,
+        store_id: Uuid,
+        pricing: &Pricing,
+        parcel: &Parcel,
+        country: Option<&str>,
+        postcode: Option<&str>,
+    ) -> Result<Vec<ShippingOption>, sqlx::Error> {
+        let zones = sqlx::query!(
+            r#"SELECT EXISTS(SELECT 1 FROM shipping_zones WHERE store_id = $1) as "exists!""#,
+            store_id
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+
+        let mut options = if zones.exists {
+            let country = country.map(|c| c.trim().to_uppercase());
+            let postcode = postcode.map(|p| p.trim().to_uppercase());
+            sqlx::query_as!(
+                ShippingOption,
+                r#"SELECT method as "method!: ShippingMethod", ROUND(rate + per_kg * $3, 2) as "rate!"
+                FROM shipping_rates
+                WHERE zone_id = (
+                    SELECT zone_id FROM shipping_zones
+                    WHERE store_id = $4 AND country = $1
+                    AND (postcode_from IS NULL OR postcode_key($2) >= postcode_key(postcode_from))
+                    AND (postcode_to IS NULL OR postcode_key($2) <= postcode_key(postcode_to))
+                    ORDER BY priority DESC, postcode_from IS NOT NULL DESC
+                    LIMIT 1
+                )
+                AND (max_weight_kg IS NULL OR max_weight_kg >= $3)
+                ORDER BY 2"#,
+                country,
+                postcode,
+                pricing.billable_weight(parcel),
+                store_id
+            )
+            .fetch_all(&mut *conn)
+            .await?
+        } else {
+            [ShippingMethod::Standard, ShippingMethod::Express]
+                .into_iter()
+                .map(|method| ShippingOption {
+                    method,
+                    rate: pricing.flat_shipping(method),
+                })
+                .collect()
+        };
+
+        let pickup = sqlx::query!(
+            r#"SELECT EXISTS(
+                SELECT 1 FROM pickup_locations WHERE store_id = $1 AND is_active
+            ) as "exists!""#,
+            store_id
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+        if pickup.exists {
+            options.push(ShippingOption {
+                method: ShippingMethod::Pickup,
+                rate: Decimal::ZERO,
+            });
+        }
+
+        Ok(options)
+    }
+
+    // shipping rate for the chosen method, errors when it doesn't ship to the address
+    pub async fn rate_for(
+        conn: &mut PgConnection,
+        store_id: Uuid,
+        pricing: &Pricing,
+        parcel: &Parcel,
+        method: ShippingMethod,
+        country: Option<&str>,
+        postcode: Option<&str>,
+    ) -> Result<Decimal, sqlx::Error> {
+        ShippingZone::options_for(conn, store_id, pricing, parcel, country, postcode)
+            .await?
+            .into_iter()
+            .find(|option| option.method == method)
+            .map(|option| option.rate)
+            .ok_or_else(|| {
+                sqlx::Error::Protocol("Shipping method is not available for this address".into())
+            })
+    }
+
+    async fn get_all(pool: &PgPool, store_id: Uuid) -> Result<Vec<ZoneWithRates>, sqlx::Error> {
+        let zones = sqlx::query_as!(
+            ShippingZone,
+            "SELECT * FROM shipping_zones WHERE store_id = $1
+            ORDER BY country, priority DESC, name",
+            store_id
+        )
+        .fetch_all(pool)
+        .await?;
+
+        let mut result = Vec::with_capacity(zones.len());
+        for zone in zones {
+            let rates = sqlx::query_as!(
+                ShippingRate,
+                r#"SELECT method as "method!: ShippingMethod", rate, per_kg, max_weight_kg
+                FROM shipping_rates
+                WHERE zone_id = $1 ORDER BY rate"#,
+                zone.zone_id
+            )
+            .fetch_all(pool)
+            .await?;
+            result.push(ZoneWithRates { zone, rates });
+        }
+
+        Ok(result)
+    }
+
+    async fn create(
+        pool: &PgPool,
+        store_id: Uuid,
+        body: ZoneBody,
+    ) -> Result<ZoneWithRates, sqlx::Error> {
+        let country = body.country.trim().to_uppercase();
+        if body.name.trim().is_empty() || country.len() != 2 {
+            return Err(sqlx::Error::Protocol(
+                "Zone needs a name and a 2 letter country code".into(),
+            ));
+        }
+        if body.rates.is_empty()
+            || body
+                .rates
+                .iter()
+                .any(|r| r.method == ShippingMethod::Pickup || r.rate < Decimal::ZERO)
+        {
+            return Err(sqlx::Error::Protocol(
+                "Zone needs standard or express rates that are not negative".into(),
+            ));
+        }
+        if body.rates.iter().any(|r| {
+            r.per_kg < Decimal::ZERO || r.max_weight_kg.is_some_and(|max| max <= Decimal::ZERO)
+        }) {
+            return Err(sqlx::Error::Protocol(
+                "Per kg rates can't be negative and weight limits must be positive".into(),
+            ));
+        }
+
+        let mut tx = pool.begin().await?;
+
+        let zone = sqlx::query_as!(
+            ShippingZone,
+            "INSERT INTO shipping_zones
+                (name, country, postcode_from, postcode_to, priority, store_id)
+            VALUES ($1, $2, $3, $4, $5, $6) RETURNING *",
+            body.name.trim(),
+            country,
+            body.postcode_from.map(|p| p.trim().to_uppercase()),
+            body.postcode_to.map(|p| p.trim().to_uppercase()),
+            body.priority,
+            store_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        for rate in &body.rates {
+            sqlx::query!(
+                "INSERT INTO shipping_rates (zone_id, method, rate, per_kg, max_weight_kg)
+                VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT (zone_id, method)
+                DO UPDATE SET rate = $3, per_kg = $4, max_weight_kg = $5",
+                zone.zone_id,
+                rate.method as ShippingMethod,
+                rate.rate,
+                rate.per_kg,
+                rate.max_weight_kg
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(ZoneWithRates {
+            zone,
+            rates: body.rates,
+        })
+    }
+
+    async fn delete(pool: &PgPool, store_id: Uuid, zone_id: Uuid) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            "DELETE FROM shipping_zones WHERE zone_id = $1 AND store_id = $2",
+            zone_id,
+            store_id
+        )
+        .execute(pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+// get request for the shipping methods available to ?country=&postcode=,
+// priced for what is in the cart
+#[get("api/checkout/shipping-options")]
+pub async fn get_shipping_options(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    query: web::Query<AddressQuery>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            let parcel =
+                match ShippingZone::cart_parcel(&state.db, store.store_id, user.user_id).await {
+                    Ok(parcel) => parcel,
+                    Err(err) => return database_error(err),
+                };
+            let mut conn = match state.db.acquire().await {
+                Ok(conn) => conn,
+                Err(err) => return database_error(err),
+            };
+            match ShippingZone::options_for(
+                &mut conn,
+                store.store_id,
+                &state.pricing,
+                &parcel,
+                query.country.as_deref(),
+                query.postcode.as_deref(),
+            )
+            .await
+            {
+                Ok(options) => HttpResponse::Ok().json(options),
+                Err(err) => database_error(err),
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// get request for all shipping zones with their rates
+#[get("api/admin/shipping-zones")]
+pub async fn get_shipping_zones(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match ShippingZone::get_all(&state.db, store.store_id).await {
+                    Ok(zones) => HttpResponse::Ok().json(zones),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("customer not allowed to manage shipping zones")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// post request to add a shipping zone with its rates
+#[post("api/admin/shipping-zones")]
+pub async fn create_shipping_zone(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    body: Json<ZoneBody>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match ShippingZone::create(&state.db, store.store_id, body.into_inner()).await {
+                    Ok(zone) => HttpResponse::Created().json(zone),
+                    Err(sqlx::Error::Protocol(msg)) => HttpResponse::BadRequest().json(msg),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("customer not allowed to manage shipping zones")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// delete request to remove a shipping zone and its rates
+#[delete("api/admin/shipping-zones/{id}")]
+pub async fn delete_shipping_zone(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    zone_id: web::Path<Uuid>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match ShippingZone::delete(&state.db, store.store_id, *zone_id).await {
+                    Ok(true) => HttpResponse::Ok().json("shipping zone deleted"),
+                    Ok(false) => HttpResponse::NotFound().json("shipping zone not found"),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("customer not allowed to manage shipping zones")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
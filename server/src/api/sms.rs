@@ -0,0 +1,302 @@
+use crate::{
+    api::users::{sign_in, TokenClaims, UserRole},
+    rate_limit::database_error,
+    sms::SmsError,
+    AppState,
+};
+use actix_web::{
+    delete, get, post, put,
+    web::{self, Json, ReqData},
+    HttpRequest, HttpResponse, Responder,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+// codes are good for 10 minutes and 5 guesses
+const CODE_TTL_MINUTES: i32 = 10;
+const CODE_ATTEMPTS: i32 = 5;
+
+#[derive(Serialize, Deserialize, FromRow)]
+struct SmsSettings {
+    delivery_updates: bool,
+    two_factor: bool,
+}
+
+#[derive(Deserialize)]
+struct SmsSettingsBody {
+    delivery_updates: bool,
+}
+
+#[derive(Deserialize)]
+struct CodeBody {
+    challenge_id: Uuid,
+    code: String,
+}
+
+// a code texted to the user, what it unlocks is its purpose
+struct SmsChallenge;
+
+impl SmsChallenge {
+    fn hash(challenge_id: Uuid, code: &str) -> String {
+        format!(
+            "{:x}",
+            Sha256::digest(format!("{challenge_id}:{}", code.trim()))
+        )
+    }
+
+    // text a new code to the user's phone, the id it is verified under
+    async fn create(
+        state: &AppState,
+        user_id: Uuid,
+        phone: Option<&str>,
+        purpose: &str,
+    ) -> Result<Uuid, SmsError> {
+        let sms = state.sms.as_ref().ok_or(SmsError::CountryDisabled)?;
+        let phone = phone.ok_or(SmsError::CountryDisabled)?;
+
+        // v4 uuids are random, six digits of one make the code
+        let challenge_id = Uuid::new_v4();
+        let code = format!("{:06}", Uuid::new_v4().as_u128() % 1_000_000);
+        sqlx::query!(
+            "INSERT INTO sms_challenges (challenge_id, user_id, purpose, code_hash, expires_at)
+            VALUES ($1, $2, $3, $4, NOW() + make_interval(mins => $5))",
+            challenge_id,
+            user_id,
+            purpose,
+            SmsChallenge::hash(challenge_id, &code),
+            CODE_TTL_MINUTES
+        )
+        .execute(&state.db)
+        .await?;
+
+        sms.send_code(&state.db, user_id, phone, &code).await?;
+        Ok(challenge_id)
+    }
+
+    // the user the code was sent to when it is right, every guess uses up an
+    // attempt and a right one the code
+    async fn verify(
+        pool: &PgPool,
+        challenge_id: Uuid,
+        purpose: &str,
+        code: &str,
+    ) -> Result<Option<Uuid>, sqlx::Error> {
+        let open = sqlx::query!(
+            "UPDATE sms_challenges SET attempts = attempts + 1
+            WHERE challenge_id = $1 AND purpose = $2 AND expires_at > NOW() AND attempts < $3
+            RETURNING challenge_id",
+            challenge_id,
+            purpose,
+            CODE_ATTEMPTS
+        )
+        .fetch_optional(pool)
+        .await?;
+        if open.is_none() {
+            return Ok(None);
+        }
+
+        let used = sqlx::query!(
+            "DELETE FROM sms_challenges WHERE challenge_id = $1 AND code_hash = $2
+            RETURNING user_id",
+            challenge_id,
+            SmsChallenge::hash(challenge_id, code)
+        )
+        .fetch_optional(pool)
+        .await?;
+        Ok(used.map(|challenge| challenge.user_id))
+    }
+}
+
+impl SmsSettings {
+    async fn get(pool: &PgPool, user_id: Uuid) -> Result<SmsSettings, sqlx::Error> {
+        sqlx::query_as!(
+            SmsSettings,
+            "SELECT sms_delivery_updates as delivery_updates, sms_two_factor as two_factor
+            FROM users WHERE user_id = $1",
+            user_id
+        )
+        .fetch_one(pool)
+        .await
+    }
+}
+
+fn sms_error_response(err: SmsError) -> HttpResponse {
+    match err {
+        SmsError::CountryDisabled => HttpResponse::UnprocessableEntity()
+            .json("texts can't be sent to the phone number of this account"),
+        SmsError::RateLimited => HttpResponse::TooManyRequests().json(err.to_string()),
+        SmsError::Database(err) => database_error(err),
+        err => HttpResponse::BadGateway().json(err.to_string()),
+    }
+}
+
+// the answer to a right password when two-factor login is on: the id of the
+// code texted to the user, to verify with POST api/auth/sms
+pub async fn start_login(state: &AppState, user_id: Uuid, phone: Option<&str>) -> HttpResponse {
+    if state.sms.is_none() {
+        return HttpResponse::ServiceUnavailable().json("texts are not available to log in with");
+    }
+    match SmsChallenge::create(state, user_id, phone, "login").await {
+        Ok(challenge_id) => HttpResponse::Accepted().json(json!({ "challenge_id": challenge_id })),
+        Err(err) => sms_error_response(err),
+    }
+}
+
+// post request with the texted code to finish logging in, answers with the token
+#[post("api/auth/sms")]
+pub async fn verify_login_code(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: Json<CodeBody>,
+) -> impl Responder {
+    match SmsChallenge::verify(&state.db, body.challenge_id, "login", &body.code).await {
+        Ok(Some(user_id)) => {
+            // the account may have been deactivated since the code was texted
+            let user = sqlx::query!(
+                r#"SELECT role as "role!: UserRole", is_active FROM users WHERE user_id = $1"#,
+                user_id
+            )
+            .fetch_one(&state.db)
+            .await;
+            match user {
+                Ok(user) if !user.is_active => {
+                    HttpResponse::Forbidden().json("this account has been deactivated")
+                }
+                Ok(user) => sign_in(&state, &req, user_id, user.role).await,
+                Err(err) => database_error(err),
+            }
+        }
+        Ok(None) => HttpResponse::Unauthorized().json("the code is wrong or expired"),
+        Err(err) => database_error(err),
+    }
+}
+
+// get request for the texts the current user gets
+#[get("api/users/me/sms")]
+pub async fn get_sms_settings(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => match SmsSettings::get(&state.db, user.user_id).await {
+            Ok(settings) => HttpResponse::Ok().json(settings),
+            Err(err) => database_error(err),
+        },
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// put request to opt in or out of delivery texts
+#[put("api/users/me/sms")]
+pub async fn set_sms_settings(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+    body: Json<SmsSettingsBody>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            let updated = sqlx::query_as!(
+                SmsSettings,
+                "UPDATE users SET sms_delivery_updates = $2 WHERE user_id = $1
+                RETURNING sms_delivery_updates as delivery_updates, sms_two_factor as two_factor",
+                user.user_id,
+                body.delivery_updates
+            )
+            .fetch_one(&state.db)
+            .await;
+            match updated {
+                Ok(settings) => HttpResponse::Ok().json(settings),
+                Err(err) => database_error(err),
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// post request to text a code to the account's phone, two-factor login is
+// turned on once it comes back through PUT, so nobody locks themselves out
+// with a number they can't read
+#[post("api/users/me/two-factor")]
+pub async fn start_two_factor(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if state.sms.is_none() {
+                return HttpResponse::ServiceUnavailable().json("texts are not available");
+            }
+            let phone = match sqlx::query_scalar!(
+                "SELECT phone FROM users WHERE user_id = $1",
+                user.user_id
+            )
+            .fetch_one(&state.db)
+            .await
+            {
+                Ok(phone) => phone,
+                Err(err) => return database_error(err),
+            };
+            match SmsChallenge::create(&state, user.user_id, phone.as_deref(), "enroll").await {
+                Ok(challenge_id) => {
+                    HttpResponse::Accepted().json(json!({ "challenge_id": challenge_id }))
+                }
+                Err(err) => sms_error_response(err),
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// put request with the code texted by POST to turn two-factor login on
+#[put("api/users/me/two-factor")]
+pub async fn enable_two_factor(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+    body: Json<CodeBody>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            match SmsChallenge::verify(&state.db, body.challenge_id, "enroll", &body.code).await {
+                Ok(Some(user_id)) if user_id == user.user_id => {
+                    match sqlx::query!(
+                        "UPDATE users SET sms_two_factor = TRUE WHERE user_id = $1",
+                        user_id
+                    )
+                    .execute(&state.db)
+                    .await
+                    {
+                        Ok(_) => HttpResponse::Ok().json("two-factor login enabled"),
+                        Err(err) => database_error(err),
+                    }
+                }
+                Ok(_) => HttpResponse::UnprocessableEntity().json("the code is wrong or expired"),
+                Err(err) => database_error(err),
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// delete request to log in with the password alone again
+#[delete("api/users/me/two-factor")]
+pub async fn disable_two_factor(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => match sqlx::query!(
+            "UPDATE users SET sms_two_factor = FALSE WHERE user_id = $1",
+            user.user_id
+        )
+        .execute(&state.db)
+        .await
+        {
+            Ok(_) => HttpResponse::Ok().json("two-factor login disabled"),
+            Err(err) => database_error(err),
+        },
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
@@ -0,0 +1,190 @@
+use crate::{
+    api::{customer_groups::CustomerGroup, stores::Store, users::TokenClaims},
+    envelope::{paginated, Page, PageQuery, Pagination},
+    rate_limit::database_error,
+    AppState,
+};
+use actix_web::{
+    delete, get, post,
+    web::{self, ReqData},
+    HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{types::Decimal, PgPool};
+use uuid::Uuid;
+
+// a customer waiting for a product to be back in stock
+#[derive(Serialize)]
+pub struct StockSubscription {
+    product_id: Uuid,
+    subscribed_at: DateTime<Utc>,
+}
+
+// an out of stock product and how many customers are waiting for it
+#[derive(Serialize)]
+pub struct StockDemand {
+    product_id: Uuid,
+    name: String,
+    stock_quantity: Decimal,
+    subscribers: i64,
+    // since when the longest waiting customer has been
+    waiting_since: DateTime<Utc>,
+}
+
+impl StockSubscription {
+    // only products the customer can see and that are out of stock take
+    // subscriptions, subscribing twice keeps the first one
+    async fn subscribe(
+        pool: &PgPool,
+        store_id: Uuid,
+        user: &TokenClaims,
+        product_id: Uuid,
+    ) -> Result<StockSubscription, sqlx::Error> {
+        let product = sqlx::query!(
+            r#"SELECT product_stock(product_id) as "stock!"
+            FROM products
+            WHERE product_id = $1 AND store_id = $2 AND visible_to(product_id, $3)"#,
+            product_id,
+            store_id,
+            user.catalogue_group() as Option<CustomerGroup>
+        )
+        .fetch_optional(pool)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)?;
+        if product.stock > Decimal::ZERO {
+            return Err(sqlx::Error::Protocol("product is in stock".into()));
+        }
+
+        sqlx::query_as!(
+            StockSubscription,
+            "INSERT INTO stock_subscriptions (user_id, product_id) VALUES ($1, $2)
+            ON CONFLICT (user_id, product_id) DO UPDATE SET created_at = stock_subscriptions.created_at
+            RETURNING product_id, created_at as subscribed_at",
+            user.user_id,
+            product_id
+        )
+        .fetch_one(pool)
+        .await
+    }
+
+    async fn unsubscribe(
+        pool: &PgPool,
+        user_id: Uuid,
+        product_id: Uuid,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            "DELETE FROM stock_subscriptions WHERE user_id = $1 AND product_id = $2",
+            user_id,
+            product_id
+        )
+        .execute(pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+impl StockDemand {
+    // the store's products customers are waiting for, most wanted first
+    async fn for_store(
+        pool: &PgPool,
+        store_id: Uuid,
+        page: &PageQuery,
+    ) -> Result<Page<StockDemand>, sqlx::Error> {
+        let items = sqlx::query_as!(
+            StockDemand,
+            r#"SELECT p.product_id, p.name, product_stock(p.product_id) as "stock_quantity!",
+                   COUNT(*) as "subscribers!", MIN(s.created_at) as "waiting_since!"
+            FROM stock_subscriptions s
+            JOIN products p ON p.product_id = s.product_id
+            WHERE p.store_id = $1
+            GROUP BY p.product_id
+            ORDER BY COUNT(*) DESC, MIN(s.created_at), p.product_id
+            LIMIT $2 OFFSET $3"#,
+            store_id,
+            page.per_page(),
+            page.offset()
+        )
+        .fetch_all(pool)
+        .await?;
+        let total = sqlx::query_scalar!(
+            r#"SELECT COUNT(DISTINCT s.product_id) as "count!"
+            FROM stock_subscriptions s
+            JOIN products p ON p.product_id = s.product_id
+            WHERE p.store_id = $1"#,
+            store_id
+        )
+        .fetch_one(pool)
+        .await?;
+        Ok(Page { items, total })
+    }
+}
+
+// post request to hear when an out of stock product is back, in the inbox and
+// by mail. The subscription ends once it is
+#[post("api/products/{id}/notify-me")]
+pub async fn subscribe_to_stock(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    product_id: web::Path<Uuid>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            match StockSubscription::subscribe(&state.db, store.store_id, &user, *product_id).await
+            {
+                Ok(subscription) => HttpResponse::Created().json(subscription),
+                Err(sqlx::Error::RowNotFound) => {
+                    HttpResponse::NotFound().json("product was not found")
+                }
+                Err(sqlx::Error::Protocol(msg)) => HttpResponse::Conflict().json(msg),
+                Err(err) => database_error(err),
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// delete request to stop waiting for a product
+#[delete("api/products/{id}/notify-me")]
+pub async fn unsubscribe_from_stock(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+    product_id: web::Path<Uuid>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            match StockSubscription::unsubscribe(&state.db, user.user_id, *product_id).await {
+                Ok(true) => HttpResponse::NoContent().finish(),
+                Ok(false) => HttpResponse::NotFound().json("subscription was not found"),
+                Err(err) => database_error(err),
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// get request for the out of stock products customers are waiting for, most
+// wanted first, to decide what to reorder
+#[get("api/admin/products/stock-demand")]
+pub async fn get_stock_demand(
+    state: web::Data<AppState>,
+    store: ReqData<Store>,
+    req_user: Option<ReqData<TokenClaims>>,
+    query: web::Query<PageQuery>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match StockDemand::for_store(&state.db, store.store_id, &query).await {
+                    Ok(page) => paginated(page.items, Pagination::new(&query, page.total)),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("costumer cant see stock demand")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
@@ -0,0 +1,278 @@
+use crate::{api::users::TokenClaims, cache::Cached, rate_limit::database_error, AppState};
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    get,
+    http::header::HeaderName,
+    middleware::Next,
+    post, put,
+    web::{self, Json, ReqData},
+    HttpMessage, HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use std::time::Duration;
+use uuid::Uuid;
+
+// the store everything from before stores belongs to, requests on a host
+// without a store of its own are served by it
+pub const DEFAULT_STORE: Uuid = Uuid::from_u128(1);
+
+// picks the store by id, for clients that share a host across stores
+pub const HEADER: HeaderName = HeaderName::from_static("x-store-id");
+
+// a shop served by this deployment, the one a request is for is in its
+// extensions
+#[derive(Serialize, FromRow, Clone)]
+pub struct Store {
+    pub store_id: Uuid,
+    name: String,
+    host: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+struct StoreBody {
+    name: String,
+    host: Option<String>,
+}
+
+impl StoreBody {
+    // hosts are matched as the Host header sends them, lowercased, port and all
+    fn validate(&self) -> Result<(String, Option<String>), sqlx::Error> {
+        let name = self.name.trim();
+        if name.is_empty() {
+            return Err(sqlx::Error::Protocol("Name is required".into()));
+        }
+        let host = self
+            .host
+            .as_deref()
+            .map(|host| host.trim().to_lowercase())
+            .filter(|host| !host.is_empty());
+        if let Some(host) = &host {
+            if host.contains("://") || host.contains('/') {
+                return Err(sqlx::Error::Protocol(
+                    "Host is a name like shop.example.com, not a url".into(),
+                ));
+            }
+        }
+        Ok((name.to_string(), host))
+    }
+}
+
+// how a request names its store
+#[derive(Hash, PartialEq, Eq, Clone)]
+pub enum StoreKey {
+    Id(Uuid),
+    Host(String),
+}
+
+// stores by id and host, every request looks its store up. STORE_CACHE_TTL_SECS
+// (default 60, 0 turns caching off) is how long a lookup is kept, changes to
+// stores drop them on every instance
+pub type StoreDirectory = Cached<StoreKey, Option<Store>>;
+
+impl StoreDirectory {
+    pub fn from_env(pool: PgPool) -> Self {
+        let ttl = std::env::var("STORE_CACHE_TTL_SECS")
+            .ok()
+            .map(|value| {
+                value
+                    .parse()
+                    .expect("STORE_CACHE_TTL_SECS must be a number")
+            })
+            .unwrap_or(60);
+        Cached::new("stores", Duration::from_secs(ttl), 1_000).shared(pool)
+    }
+}
+
+impl Store {
+    // a host without a store of its own gets the default one, an id has to exist
+    async fn find(pool: &PgPool, key: &StoreKey) -> Result<Option<Store>, sqlx::Error> {
+        match key {
+            StoreKey::Id(store_id) => {
+                sqlx::query_as!(Store, "SELECT * FROM stores WHERE store_id = $1", store_id)
+                    .fetch_optional(pool)
+                    .await
+            }
+            StoreKey::Host(host) => {
+                sqlx::query_as!(
+                    Store,
+                    "SELECT * FROM stores WHERE host = $1 OR store_id = $2
+                    ORDER BY host = $1 IS TRUE DESC LIMIT 1",
+                    host,
+                    DEFAULT_STORE
+                )
+                .fetch_optional(pool)
+                .await
+            }
+        }
+    }
+
+    async fn get_all(pool: &PgPool) -> Result<Vec<Store>, sqlx::Error> {
+        sqlx::query_as!(Store, "SELECT * FROM stores ORDER BY created_at")
+            .fetch_all(pool)
+            .await
+    }
+
+    async fn create(pool: &PgPool, body: StoreBody) -> Result<Store, sqlx::Error> {
+        let (name, host) = body.validate()?;
+        sqlx::query_as!(
+            Store,
+            "INSERT INTO stores (name, host) VALUES ($1, $2) RETURNING *",
+            name,
+            host
+        )
+        .fetch_one(pool)
+        .await
+    }
+
+    async fn update(pool: &PgPool, store_id: Uuid, body: StoreBody) -> Result<Store, sqlx::Error> {
+        let (name, host) = body.validate()?;
+        sqlx::query_as!(
+            Store,
+            "UPDATE stores SET name = $1, host = $2 WHERE store_id = $3 RETURNING *",
+            name,
+            host,
+            store_id
+        )
+        .fetch_optional(pool)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)
+    }
+}
+
+// middleware that works out which store a request is for, from X-Store-Id or
+// else the Host, and puts it in the request's extensions for the handlers
+pub async fn resolve(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let state = req
+        .app_data::<web::Data<AppState>>()
+        .cloned()
+        .expect("app state must be registered");
+    let key = match req.headers().get(&HEADER) {
+        Some(value) => match value.to_str().ok().and_then(|id| id.trim().parse().ok()) {
+            Some(store_id) => StoreKey::Id(store_id),
+            None => {
+                let res = HttpResponse::BadRequest().json("X-Store-Id must be a store id");
+                return Ok(req.into_response(res).map_into_right_body());
+            }
+        },
+        None => StoreKey::Host(req.connection_info().host().to_lowercase()),
+    };
+
+    match state
+        .stores
+        .get_or_load(key.clone(), Store::find(&state.db, &key))
+        .await
+    {
+        Ok(Some(store)) => {
+            req.extensions_mut().insert(store);
+            Ok(next.call(req).await?.map_into_left_body())
+        }
+        Ok(None) => {
+            let res = HttpResponse::NotFound().json("store not found");
+            Ok(req.into_response(res).map_into_right_body())
+        }
+        Err(err) => {
+            let res = database_error(err);
+            Ok(req.into_response(res).map_into_right_body())
+        }
+    }
+}
+
+// get request for the store the request is served by, for the storefront's
+// name and for clients checking which store they reached
+#[get("api/store")]
+pub async fn get_current_store(store: ReqData<Store>) -> impl Responder {
+    HttpResponse::Ok().json(store.into_inner())
+}
+
+// admin only, for admins of every store
+// get request for all stores
+#[get("api/admin/stores")]
+pub async fn get_stores(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_platform_admin() {
+                match Store::get_all(&state.db).await {
+                    Ok(stores) => HttpResponse::Ok().json(stores),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("only admins of every store can manage stores")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only, for admins of every store
+// post request to add a store
+#[post("api/admin/stores")]
+pub async fn create_store(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+    body: Json<StoreBody>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_platform_admin() {
+                match Store::create(&state.db, body.into_inner()).await {
+                    Ok(store) => {
+                        state.stores.invalidate().await;
+                        HttpResponse::Created().json(store)
+                    }
+                    Err(sqlx::Error::Protocol(msg)) => HttpResponse::BadRequest().json(msg),
+                    Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+                        HttpResponse::Conflict().json("another store has that host")
+                    }
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("only admins of every store can manage stores")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only, for admins of every store
+// put request to rename a store or move it to another host
+#[put("api/admin/stores/{id}")]
+pub async fn update_store(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+    store_id: web::Path<Uuid>,
+    body: Json<StoreBody>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_platform_admin() {
+                match Store::update(&state.db, *store_id, body.into_inner()).await {
+                    Ok(store) => {
+                        state.stores.invalidate().await;
+                        HttpResponse::Ok().json(store)
+                    }
+                    Err(sqlx::Error::RowNotFound) => {
+                        HttpResponse::NotFound().json("store not found")
+                    }
+                    Err(sqlx::Error::Protocol(msg)) => HttpResponse::BadRequest().json(msg),
+                    Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+                        HttpResponse::Conflict().json("another store has that host")
+                    }
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("only admins of every store can manage stores")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
@@ -1,34 +1,45 @@
 //----------------------------------------IMPORTS----------------------------------------//
-use crate::AppState;
+use crate::{
+    api::{
+        blocklist::BlockedDomain,
+        customer_groups::CustomerGroup,
+        marketing::MarketingConsent,
+        policies::{Policy, PolicyAcceptance},
+        sessions::Session,
+        sms,
+        stores::Store,
+    },
+    audit, captcha, outbox,
+    query_stats::QueryStats,
+    rate_limit::database_error,
+    AppState,
+};
 use actix_web::{
-    dev::ServiceRequest,
-    get, post,
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    error::InternalError,
+    get,
+    http::header::{HeaderName, HeaderValue},
+    middleware::Next,
+    patch, post, put,
     web::{self, Json, ReqData},
-    HttpMessage, HttpResponse, Responder,
+    HttpMessage, HttpRequest, HttpResponse, Responder,
 };
 use serde::{Deserialize, Serialize};
 use sqlx::{FromRow, PgPool};
+use std::sync::{Arc, Mutex};
 use uuid::Uuid;
 
 // for auth import
-use actix_web_httpauth::{
-    extractors::{
-        basic::BasicAuth,
-        bearer::{self, BearerAuth},
-        AuthenticationError,
-    },
-    middleware::HttpAuthentication,
+use actix_web_httpauth::extractors::{
+    basic::BasicAuth,
+    bearer::{self, BearerAuth},
+    AuthenticationError,
 };
 
 use argonautica::{Hasher, Verifier};
-use chrono::NaiveDateTime;
-use hmac::{
-    digest::{core_api::CoreWrapper, KeyInit},
-    Hmac, HmacCore,
-};
-use jwt::SignWithKey;
-use jwt::VerifyWithKey;
-use sha2::Sha256;
+use async_trait::async_trait;
+use chrono::{NaiveDate, Utc};
 //----------------------------------------IMPORTS----------------------------------------//
 
 // token struct
@@ -36,17 +47,57 @@ use sha2::Sha256;
 pub struct TokenClaims {
     pub user_id: Uuid,
     role: UserRole,
+    pub session_id: Uuid,
+    // the store the user is an admin or staff of, none for every store.
+    // Read with the session on every request, never taken from the token
+    #[serde(skip)]
+    admin_store: Option<Uuid>,
+    // read with the session too, moving a customer between groups changes
+    // what they see from their next request on
+    #[serde(skip)]
+    customer_group: CustomerGroup,
+    // the terms or privacy policy a customer has yet to accept in their
+    // current version, read with the session. Staff don't sign up, none for them
+    #[serde(skip)]
+    pending_policies: Vec<Policy>,
 }
 
-#[derive(Debug, Serialize, Deserialize, sqlx::Type, Clone)]
+#[derive(Debug, Serialize, Deserialize, sqlx::Type, Clone, PartialEq)]
 #[sqlx(type_name = "user_role", rename_all = "lowercase")]
 pub enum UserRole {
     Admin,
     Customer,
+    // staff below admin, each with the permissions their role grants
+    Support,
+    WarehouseStaff,
+}
+
+// what staff may do in the admin API. Admins may do all of it
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    // read orders, the fraud review queue, cash on delivery and disputes
+    ViewOrders,
+    IssueRefunds,
+    // move orders on to shipped or ready for pickup and book their shipments
+    ShipOrders,
+}
+
+impl UserRole {
+    pub fn grants(&self, permission: Permission) -> bool {
+        match self {
+            UserRole::Admin => true,
+            UserRole::Support => matches!(
+                permission,
+                Permission::ViewOrders | Permission::IssueRefunds
+            ),
+            UserRole::WarehouseStaff => permission == Permission::ShipOrders,
+            UserRole::Customer => false,
+        }
+    }
 }
 
-// user struct
-#[derive(Serialize, Deserialize, sqlx::FromRow)]
+// a users row, handlers answer with UserResponse
+#[derive(sqlx::FromRow, Clone)]
 pub struct User {
     user_id: Uuid,
     first_name: String,
@@ -54,34 +105,126 @@ pub struct User {
     phone: Option<String>,
     email: String,
     role: UserRole,
+    customer_group: CustomerGroup,
+    // inactive accounts can't log in
+    is_active: bool,
+    // the store an admin or staff member works in, none for every store
+    store_id: Option<Uuid>,
+    date_of_birth: Option<NaiveDate>,
+    // staff checked the date of birth against an ID
+    age_verified: bool,
 }
 
 // struct for create user body
 #[derive(Deserialize)]
-struct CreateUserBody {
+pub struct CreateUserBody {
     first_name: String,
     last_name: String,
     email: String,
     password: String,
     phone: String,
+    // the answer to the marketing checkbox, none when it wasn't shown
+    marketing_consent: Option<bool>,
+    // the terms of service and privacy policy checkbox, an account can't be
+    // made without it
+    #[serde(default)]
+    accept_terms: bool,
+}
+
+// struct for change password body
+#[derive(Deserialize)]
+struct ChangePasswordBody {
+    current_password: String,
+    new_password: String,
+}
+
+// an admin's change to an account, fields left out stay as they are and a
+// null phone clears it
+#[derive(Deserialize)]
+pub struct UserPatch {
+    role: Option<UserRole>,
+    is_active: Option<bool>,
+    #[serde(default, deserialize_with = "crate::api::nullable")]
+    phone: Option<Option<String>>,
+    // null makes an admin an admin of every store
+    #[serde(default, deserialize_with = "crate::api::nullable")]
+    store_id: Option<Option<Uuid>>,
+    // true once staff have seen an ID with the customer's date of birth
+    age_verified: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct DateOfBirthBody {
+    date_of_birth: NaiveDate,
+}
+
+impl UserPatch {
+    fn validate(&self) -> Result<(), sqlx::Error> {
+        let Some(Some(phone)) = &self.phone else {
+            return Ok(());
+        };
+        let digits: String = phone
+            .chars()
+            .filter(|c| !matches!(c, ' ' | '-' | '.' | '(' | ')'))
+            .collect();
+        let digits = digits.strip_prefix('+').unwrap_or(&digits);
+        if !(7..=15).contains(&digits.len()) || !digits.chars().all(|c| c.is_ascii_digit()) {
+            return Err(sqlx::Error::Protocol(
+                "Phone must be 7 to 15 digits, optionally starting with +".into(),
+            ));
+        }
+        Ok(())
+    }
 }
 
 // struct for user response
-#[derive(Serialize, FromRow)]
-struct UserResponse {
+#[derive(Serialize)]
+pub struct UserResponse {
     user_id: Uuid,
     first_name: String,
     last_name: String,
     email: String,
     phone: Option<String>,
+    role: UserRole,
+    customer_group: CustomerGroup,
+    is_active: bool,
+    store_id: Option<Uuid>,
+    date_of_birth: Option<NaiveDate>,
+    age_verified: bool,
+    // only on the user's own profile
+    #[serde(skip_serializing_if = "Option::is_none")]
+    marketing_consent: Option<MarketingConsent>,
 }
 
-#[derive(Serialize, FromRow)]
-struct AuthResponse {
+impl From<User> for UserResponse {
+    fn from(user: User) -> Self {
+        UserResponse {
+            user_id: user.user_id,
+            first_name: user.first_name,
+            last_name: user.last_name,
+            email: user.email,
+            phone: user.phone,
+            role: user.role,
+            customer_group: user.customer_group,
+            is_active: user.is_active,
+            store_id: user.store_id,
+            date_of_birth: user.date_of_birth,
+            age_verified: user.age_verified,
+            marketing_consent: None,
+        }
+    }
+}
+
+// what a login is checked against, holds the password hash so it has no
+// Serialize and can't end up in a response
+#[derive(FromRow)]
+pub struct Credentials {
     user_id: Uuid,
-    email: String,
     password_hash: String,
     role: UserRole,
+    phone: Option<String>,
+    sms_two_factor: bool,
+    is_active: bool,
 }
 
 // User implementation
@@ -96,7 +239,9 @@ impl User {
                 last_name, 
                 phone, 
                 email, 
-                role as "role!: UserRole"  -- Note the ! to make it non-null
+                role as "role!: UserRole",  -- Note the ! to make it non-null
+                customer_group as "customer_group: CustomerGroup", is_active, store_id,
+                date_of_birth, age_verified
             FROM users"#
         )
         .fetch_all(pool)
@@ -113,7 +258,9 @@ impl User {
                 last_name, 
                 phone, 
                 email, 
-                role as "role!: UserRole" 
+                role as "role!: UserRole",
+                customer_group as "customer_group: CustomerGroup", is_active, store_id,
+                date_of_birth, age_verified
             FROM users 
             WHERE user_id = $1"#,
             user_id
@@ -122,12 +269,8 @@ impl User {
         .await
     }
 
-    async fn create_user(
-        pool: &PgPool,
-        body: Json<CreateUserBody>,
-    ) -> Result<UserResponse, sqlx::Error> {
+    async fn create_user(pool: &PgPool, new_user: CreateUserBody) -> Result<User, sqlx::Error> {
         // check if user already exist
-        let new_user = body.into_inner();
         let existing_user =
             sqlx::query!("SELECT email FROM users WHERE email = $1", new_user.email)
                 .fetch_optional(pool)
@@ -138,28 +281,661 @@ impl User {
             return Err(sqlx::Error::Protocol("Email already exist".into()));
         }
 
+        // reject throwaway email domains
+        if BlockedDomain::is_blocked(pool, &new_user.email).await? {
+            return Err(sqlx::Error::Protocol("Email domain is not allowed".into()));
+        }
+
         // hash the password
-        let hash_secret = std::env::var("HASH_SECRET").expect("Hash secret must be set");
-        let mut hasher = Hasher::default();
-        let hashed_password = hasher
-            .with_password(new_user.password)
-            .with_secret_key(hash_secret)
-            .hash()
-            .unwrap();
+        let hashed_password = hash_password(&new_user.password);
 
-        // create new user
-        sqlx::query_as!(UserResponse, "INSERT INTO users (first_name, last_name, email, password_hash, phone) VALUES ($1, $2, $3, $4, $5) RETURNING user_id, first_name, last_name, email, phone", new_user.first_name, new_user.last_name, new_user.email, hashed_password, new_user.phone).fetch_one(pool).await
+        // create new user, announced once it is committed
+        let mut tx = pool.begin().await?;
+        let user = sqlx::query_as!(User, r#"INSERT INTO users (first_name, last_name, email, password_hash, phone) VALUES ($1, $2, $3, $4, $5) RETURNING user_id, first_name, last_name, phone, email, role as "role!: UserRole", customer_group as "customer_group: CustomerGroup", is_active, store_id, date_of_birth, age_verified"#, new_user.first_name, new_user.last_name, new_user.email, hashed_password, new_user.phone).fetch_one(&mut *tx).await?;
+        outbox::record(
+            &mut *tx,
+            "user.registered",
+            user.user_id,
+            serde_json::json!({ "user_id": user.user_id }),
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(user)
     }
 
-    async fn get_user_info(pool: &PgPool, user_id: Uuid) -> Result<UserResponse, sqlx::Error> {
+    // what a login is checked against
+    async fn get_credentials(pool: &PgPool, email: &str) -> Result<Credentials, sqlx::Error> {
         sqlx::query_as!(
-            UserResponse,
-            "SELECT user_id, first_name, last_name, email, phone FROM users WHERE user_id = $1",
+            Credentials,
+            r#"SELECT user_id, password_hash, role as "role!: UserRole", phone, sms_two_factor,
+                is_active
+            FROM users WHERE email = $1"#,
+            email
+        )
+        .fetch_one(pool)
+        .await
+    }
+
+    // store a new password hashed with the current secret
+    async fn set_password(pool: &PgPool, user_id: Uuid, password: &str) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "UPDATE users SET password_hash = $1 WHERE user_id = $2",
+            hash_password(password),
+            user_id
+        )
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    // change password after verifying the current one
+    async fn change_password(
+        pool: &PgPool,
+        user_id: Uuid,
+        current_password: &str,
+        new_password: &str,
+    ) -> Result<(), sqlx::Error> {
+        let user = sqlx::query!(
+            "SELECT password_hash FROM users WHERE user_id = $1",
             user_id
         )
         .fetch_one(pool)
+        .await?;
+
+        if let PasswordMatch::Invalid = verify_password(&user.password_hash, current_password) {
+            return Err(sqlx::Error::Protocol(
+                "Current password is incorrect".into(),
+            ));
+        }
+
+        User::set_password(pool, user_id, new_password).await
+    }
+
+    // create an admin account with this email. Returns the user and whether it
+    // was created, an existing admin is left as it is. Anyone can register an
+    // email, so an existing customer account is never made admin: its owner
+    // chose the password. There is no API for this, admins come from the
+    // command line
+    pub async fn create_admin(
+        pool: &PgPool,
+        email: &str,
+        first_name: &str,
+        last_name: &str,
+        password: Option<&str>,
+    ) -> Result<(Uuid, bool), sqlx::Error> {
+        let existing = sqlx::query!(
+            r#"SELECT user_id, role as "role!: UserRole" FROM users WHERE email = $1"#,
+            email
+        )
+        .fetch_optional(pool)
+        .await?;
+        if let Some(user) = existing {
+            return match user.role {
+                UserRole::Admin => Ok((user.user_id, false)),
+                _ => Err(sqlx::Error::Protocol(format!(
+                    "{email} is already registered, existing accounts are not made admin"
+                ))),
+            };
+        }
+
+        let password = password.ok_or_else(|| {
+            sqlx::Error::Protocol("A password is needed to create a new account".into())
+        })?;
+        let user = sqlx::query!(
+            "INSERT INTO users (first_name, last_name, email, password_hash, role)
+            VALUES ($1, $2, $3, $4, 'admin'::user_role) RETURNING user_id",
+            first_name,
+            last_name,
+            email,
+            hash_password(password)
+        )
+        .fetch_one(pool)
+        .await?;
+        Ok((user.user_id, true))
+    }
+
+    // whether the store has an admin yet
+    pub async fn has_admin(pool: &PgPool) -> Result<bool, sqlx::Error> {
+        sqlx::query_scalar!(
+            r#"SELECT EXISTS(SELECT 1 FROM users WHERE role = 'admin'::user_role) as "exists!""#
+        )
+        .fetch_one(pool)
         .await
     }
+
+    // the customer account with this email, created when there is none. Returns
+    // the user and whether it was created, an existing account is left as it is
+    pub async fn create_customer(
+        pool: &PgPool,
+        email: &str,
+        first_name: &str,
+        last_name: &str,
+        password: &str,
+    ) -> Result<(Uuid, bool), sqlx::Error> {
+        let existing = sqlx::query!("SELECT user_id FROM users WHERE email = $1", email)
+            .fetch_optional(pool)
+            .await?;
+        if let Some(user) = existing {
+            return Ok((user.user_id, false));
+        }
+
+        let user = sqlx::query!(
+            "INSERT INTO users (first_name, last_name, email, password_hash)
+            VALUES ($1, $2, $3, $4) RETURNING user_id",
+            first_name,
+            last_name,
+            email,
+            hash_password(password)
+        )
+        .fetch_one(pool)
+        .await?;
+        Ok((user.user_id, true))
+    }
+
+    // apply an admin's patch and log what it changed, None when there is no
+    // such user. Tokens carry the role, so a new role or a deactivation ends
+    // the user's sessions
+    async fn patch_user(
+        pool: &PgPool,
+        admin_id: Uuid,
+        user_id: Uuid,
+        patch: UserPatch,
+    ) -> Result<Option<User>, sqlx::Error> {
+        patch.validate()?;
+        if admin_id == user_id && (patch.role.is_some() || patch.is_active == Some(false)) {
+            return Err(sqlx::Error::Protocol(
+                "Admins can't change their own role or deactivate themselves".into(),
+            ));
+        }
+
+        let mut tx = pool.begin().await?;
+        let Some(before) = sqlx::query!(
+            r#"SELECT role as "role!: UserRole", is_active, phone, store_id, age_verified
+            FROM users WHERE user_id = $1 FOR UPDATE"#,
+            user_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Ok(None);
+        };
+
+        let user = sqlx::query_as!(
+            User,
+            r#"UPDATE users SET role = COALESCE($1, role), is_active = COALESCE($2, is_active),
+                phone = CASE WHEN $3 THEN $4 ELSE phone END,
+                store_id = CASE WHEN $5 THEN $6 ELSE store_id END,
+                age_verified = COALESCE($8, age_verified)
+            WHERE user_id = $7
+            RETURNING user_id, first_name, last_name, phone, email, role as "role!: UserRole",
+                customer_group as "customer_group: CustomerGroup", is_active, store_id,
+                date_of_birth, age_verified"#,
+            patch.role as Option<UserRole>,
+            patch.is_active,
+            patch.phone.is_some(),
+            patch.phone.flatten(),
+            patch.store_id.is_some(),
+            patch.store_id.flatten(),
+            user_id,
+            patch.age_verified
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        // only what actually changed goes in the log
+        let (mut old, mut new) = (serde_json::Map::new(), serde_json::Map::new());
+        let role_changed = before.role != user.role;
+        if role_changed {
+            old.insert("role".into(), serde_json::json!(before.role));
+            new.insert("role".into(), serde_json::json!(user.role));
+        }
+        if before.is_active != user.is_active {
+            old.insert("is_active".into(), before.is_active.into());
+            new.insert("is_active".into(), user.is_active.into());
+        }
+        if before.phone != user.phone {
+            old.insert("phone".into(), serde_json::json!(before.phone));
+            new.insert("phone".into(), serde_json::json!(user.phone));
+        }
+        if before.store_id != user.store_id {
+            old.insert("store_id".into(), serde_json::json!(before.store_id));
+            new.insert("store_id".into(), serde_json::json!(user.store_id));
+        }
+        if before.age_verified != user.age_verified {
+            old.insert("age_verified".into(), before.age_verified.into());
+            new.insert("age_verified".into(), user.age_verified.into());
+        }
+        if !new.is_empty() {
+            audit::record(
+                &mut *tx,
+                admin_id,
+                "user.update",
+                "user",
+                user_id,
+                serde_json::json!({ "before": old, "after": new }),
+            )
+            .await?;
+        }
+
+        if role_changed || (before.is_active && !user.is_active) {
+            sqlx::query!(
+                "UPDATE sessions SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL",
+                user_id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(Some(user))
+    }
+
+    // the customer's own date of birth, a changed one has to be verified again
+    async fn set_date_of_birth(
+        pool: &PgPool,
+        user_id: Uuid,
+        date_of_birth: NaiveDate,
+    ) -> Result<User, sqlx::Error> {
+        let today = Utc::now().date_naive();
+        if date_of_birth > today || today.years_since(date_of_birth).unwrap_or(0) > 130 {
+            return Err(sqlx::Error::Protocol(
+                "Date of birth must be a real past date".into(),
+            ));
+        }
+        sqlx::query_as!(
+            User,
+            r#"UPDATE users SET date_of_birth = $1,
+                age_verified = age_verified AND date_of_birth IS NOT DISTINCT FROM $1
+            WHERE user_id = $2
+            RETURNING user_id, first_name, last_name, phone, email, role as "role!: UserRole",
+                customer_group as "customer_group: CustomerGroup", is_active, store_id,
+                date_of_birth, age_verified"#,
+            date_of_birth,
+            user_id
+        )
+        .fetch_one(pool)
+        .await
+    }
+
+    async fn get_user_info(pool: &PgPool, user_id: Uuid) -> Result<User, sqlx::Error> {
+        sqlx::query_as!(
+            User,
+            r#"SELECT user_id, first_name, last_name, phone, email, role as "role!: UserRole",
+                customer_group as "customer_group: CustomerGroup", is_active, store_id,
+                date_of_birth, age_verified
+            FROM users WHERE user_id = $1"#,
+            user_id
+        )
+        .fetch_one(pool)
+        .await
+    }
+}
+
+// data access for users, handlers go through AppState::users so they can run
+// against a fake store in tests
+#[async_trait]
+pub trait UserRepo: Send + Sync {
+    async fn get_all(&self) -> Result<Vec<User>, sqlx::Error>;
+    async fn get_by_id(&self, user_id: Uuid) -> Result<Option<User>, sqlx::Error>;
+    async fn create(&self, body: CreateUserBody) -> Result<User, sqlx::Error>;
+    async fn get_credentials(&self, email: &str) -> Result<Credentials, sqlx::Error>;
+    async fn set_password(&self, user_id: Uuid, password: &str) -> Result<(), sqlx::Error>;
+    async fn change_password(
+        &self,
+        user_id: Uuid,
+        current_password: &str,
+        new_password: &str,
+    ) -> Result<(), sqlx::Error>;
+    async fn get_info(&self, user_id: Uuid) -> Result<User, sqlx::Error>;
+    async fn set_date_of_birth(
+        &self,
+        user_id: Uuid,
+        date_of_birth: NaiveDate,
+    ) -> Result<User, sqlx::Error>;
+    async fn patch(
+        &self,
+        admin_id: Uuid,
+        user_id: Uuid,
+        patch: UserPatch,
+    ) -> Result<Option<User>, sqlx::Error>;
+}
+
+// every call is timed under "users.<method>"
+pub struct PgUserRepo {
+    pool: PgPool,
+    timings: Arc<QueryStats>,
+}
+
+impl PgUserRepo {
+    pub fn new(pool: PgPool, timings: Arc<QueryStats>) -> Self {
+        PgUserRepo { pool, timings }
+    }
+}
+
+#[async_trait]
+impl UserRepo for PgUserRepo {
+    async fn get_all(&self) -> Result<Vec<User>, sqlx::Error> {
+        self.timings
+            .time("users.get_all", User::get_all(&self.pool))
+            .await
+    }
+
+    async fn get_by_id(&self, user_id: Uuid) -> Result<Option<User>, sqlx::Error> {
+        self.timings
+            .time("users.get_by_id", User::get_by_id(&self.pool, user_id))
+            .await
+    }
+
+    async fn create(&self, body: CreateUserBody) -> Result<User, sqlx::Error> {
+        self.timings
+            .time("users.create", User::create_user(&self.pool, body))
+            .await
+    }
+
+    async fn get_credentials(&self, email: &str) -> Result<Credentials, sqlx::Error> {
+        self.timings
+            .time(
+                "users.get_credentials",
+                User::get_credentials(&self.pool, email),
+            )
+            .await
+    }
+
+    async fn set_password(&self, user_id: Uuid, password: &str) -> Result<(), sqlx::Error> {
+        self.timings
+            .time(
+                "users.set_password",
+                User::set_password(&self.pool, user_id, password),
+            )
+            .await
+    }
+
+    async fn change_password(
+        &self,
+        user_id: Uuid,
+        current_password: &str,
+        new_password: &str,
+    ) -> Result<(), sqlx::Error> {
+        self.timings
+            .time(
+                "users.change_password",
+                User::change_password(&self.pool, user_id, current_password, new_password),
+            )
+            .await
+    }
+
+    async fn get_info(&self, user_id: Uuid) -> Result<User, sqlx::Error> {
+        self.timings
+            .time("users.get_info", User::get_user_info(&self.pool, user_id))
+            .await
+    }
+
+    async fn set_date_of_birth(
+        &self,
+        user_id: Uuid,
+        date_of_birth: NaiveDate,
+    ) -> Result<User, sqlx::Error> {
+        self.timings
+            .time(
+                "users.set_date_of_birth",
+                User::set_date_of_birth(&self.pool, user_id, date_of_birth),
+            )
+            .await
+    }
+
+    async fn patch(
+        &self,
+        admin_id: Uuid,
+        user_id: Uuid,
+        patch: UserPatch,
+    ) -> Result<Option<User>, sqlx::Error> {
+        self.timings
+            .time(
+                "users.patch",
+                User::patch_user(&self.pool, admin_id, user_id, patch),
+            )
+            .await
+    }
+}
+
+// users kept in memory with their password hashes, for exercising handlers
+// without a database. Registration skips the domain blocklist and the outbox,
+// patches skip the audit log
+#[derive(Default)]
+pub struct MemoryUserRepo {
+    users: Mutex<Vec<(User, String)>>,
+}
+
+impl MemoryUserRepo {
+    // run f on the user, RowNotFound when there is none
+    fn update<T>(
+        &self,
+        user_id: Uuid,
+        f: impl FnOnce(&mut User, &mut String) -> T,
+    ) -> Result<T, sqlx::Error> {
+        let mut users = self.users.lock().unwrap();
+        let (user, password_hash) = users
+            .iter_mut()
+            .find(|(user, _)| user.user_id == user_id)
+            .ok_or(sqlx::Error::RowNotFound)?;
+        Ok(f(user, password_hash))
+    }
+}
+
+#[async_trait]
+impl UserRepo for MemoryUserRepo {
+    async fn get_all(&self) -> Result<Vec<User>, sqlx::Error> {
+        let users = self.users.lock().unwrap();
+        Ok(users.iter().map(|(user, _)| user.clone()).collect())
+    }
+
+    async fn get_by_id(&self, user_id: Uuid) -> Result<Option<User>, sqlx::Error> {
+        match self.update(user_id, |user, _| user.clone()) {
+            Ok(user) => Ok(Some(user)),
+            Err(sqlx::Error::RowNotFound) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn create(&self, body: CreateUserBody) -> Result<User, sqlx::Error> {
+        let mut users = self.users.lock().unwrap();
+        if users.iter().any(|(user, _)| user.email == body.email) {
+            return Err(sqlx::Error::Protocol("Email already exist".into()));
+        }
+        let user = User {
+            user_id: Uuid::new_v4(),
+            first_name: body.first_name,
+            last_name: body.last_name,
+            phone: Some(body.phone),
+            email: body.email,
+            role: UserRole::Customer,
+            customer_group: CustomerGroup::default(),
+            is_active: true,
+            store_id: None,
+            date_of_birth: None,
+            age_verified: false,
+        };
+        users.push((user.clone(), hash_password(&body.password)));
+        Ok(user)
+    }
+
+    async fn get_credentials(&self, email: &str) -> Result<Credentials, sqlx::Error> {
+        let users = self.users.lock().unwrap();
+        users
+            .iter()
+            .find(|(user, _)| user.email == email)
+            .map(|(user, password_hash)| Credentials {
+                user_id: user.user_id,
+                password_hash: password_hash.clone(),
+                role: user.role.clone(),
+                phone: user.phone.clone(),
+                sms_two_factor: false,
+                is_active: user.is_active,
+            })
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    async fn set_password(&self, user_id: Uuid, password: &str) -> Result<(), sqlx::Error> {
+        self.update(user_id, |_, password_hash| {
+            *password_hash = hash_password(password)
+        })
+    }
+
+    async fn change_password(
+        &self,
+        user_id: Uuid,
+        current_password: &str,
+        new_password: &str,
+    ) -> Result<(), sqlx::Error> {
+        self.update(user_id, |_, password_hash| {
+            if let PasswordMatch::Invalid = verify_password(password_hash, current_password) {
+                return Err(sqlx::Error::Protocol(
+                    "Current password is incorrect".into(),
+                ));
+            }
+            *password_hash = hash_password(new_password);
+            Ok(())
+        })?
+    }
+
+    async fn get_info(&self, user_id: Uuid) -> Result<User, sqlx::Error> {
+        self.update(user_id, |user, _| user.clone())
+    }
+
+    async fn set_date_of_birth(
+        &self,
+        user_id: Uuid,
+        date_of_birth: NaiveDate,
+    ) -> Result<User, sqlx::Error> {
+        self.update(user_id, |user, _| {
+            user.age_verified = user.age_verified && user.date_of_birth == Some(date_of_birth);
+            user.date_of_birth = Some(date_of_birth);
+            user.clone()
+        })
+    }
+
+    async fn patch(
+        &self,
+        admin_id: Uuid,
+        user_id: Uuid,
+        patch: UserPatch,
+    ) -> Result<Option<User>, sqlx::Error> {
+        if admin_id == user_id && (patch.role.is_some() || patch.is_active == Some(false)) {
+            return Err(sqlx::Error::Protocol(
+                "Admins can't change their own role or deactivate themselves".into(),
+            ));
+        }
+        let patched = self.update(user_id, |user, _| {
+            if let Some(role) = patch.role {
+                user.role = role;
+            }
+            if let Some(is_active) = patch.is_active {
+                user.is_active = is_active;
+            }
+            if let Some(phone) = patch.phone {
+                user.phone = phone;
+            }
+            if let Some(store_id) = patch.store_id {
+                user.store_id = store_id;
+            }
+            if let Some(age_verified) = patch.age_verified {
+                user.age_verified = age_verified;
+            }
+            user.clone()
+        });
+        match patched {
+            Ok(user) => Ok(Some(user)),
+            Err(sqlx::Error::RowNotFound) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+// current secret first, then the comma separated previous secrets that
+// are still accepted while a rotation is in progress
+fn configured_secrets(current: &str, previous: &str) -> Vec<String> {
+    let mut secrets =
+        vec![std::env::var(current).unwrap_or_else(|_| panic!("{current} must be set"))];
+    if let Ok(list) = std::env::var(previous) {
+        secrets.extend(
+            list.split(',')
+                .map(str::trim)
+                .filter(|secret| !secret.is_empty())
+                .map(String::from),
+        );
+    }
+    secrets
+}
+
+// hash a password with the current server secret
+fn hash_password(password: &str) -> String {
+    let hash_secret = std::env::var("HASH_SECRET").expect("Hash secret must be set");
+    let mut hasher = Hasher::default();
+    hasher
+        .with_password(password)
+        .with_secret_key(hash_secret)
+        .hash()
+        .unwrap()
+}
+
+// which secret a password hash was verified with
+enum PasswordMatch {
+    Current,
+    Previous,
+    Invalid,
+}
+
+// check a password against a stored hash, trying every configured secret
+fn verify_password(password_hash: &str, password: &str) -> PasswordMatch {
+    let secrets = configured_secrets("HASH_SECRET", "HASH_SECRET_PREVIOUS");
+    for (index, hash_secret) in secrets.iter().enumerate() {
+        let mut verifier = Verifier::default();
+        let is_valid = verifier
+            .with_hash(password_hash)
+            .with_password(password)
+            .with_secret_key(hash_secret)
+            .verify()
+            .expect("failed to verify");
+
+        if is_valid {
+            return if index == 0 {
+                PasswordMatch::Current
+            } else {
+                PasswordMatch::Previous
+            };
+        }
+    }
+    PasswordMatch::Invalid
+}
+
+// token re-signed with the current key, sent back in the X-Refreshed-Token header
+#[derive(Clone)]
+struct RefreshedToken(String);
+
+// the claims of a valid token whose session isn't revoked, and whether it was
+// signed with the current key. The session can't be checked while the database
+// is down, that is an error and not a logout
+pub async fn verify_token(
+    state: &AppState,
+    token: &str,
+) -> Result<Option<(TokenClaims, bool)>, sqlx::Error> {
+    let Ok((mut claims, signed_with_current)) = state.jwt_keys.verify::<TokenClaims>(token) else {
+        return Ok(None);
+    };
+    match Session::touch(&state.db, claims.session_id, claims.user_id).await? {
+        Some(session) => {
+            claims.admin_store = session.admin_store;
+            claims.customer_group = session.customer_group;
+            if claims.is_customer() {
+                claims.pending_policies = state.policies.pending(
+                    session.accepted_terms.as_deref(),
+                    session.accepted_privacy.as_deref(),
+                );
+            }
+            Ok(Some((claims, signed_with_current)))
+        }
+        None => Ok(None),
+    }
 }
 
 // validator for bearer_middleware
@@ -168,20 +944,33 @@ pub async fn validator(
     credentials: BearerAuth,
 ) -> Result<ServiceRequest, (actix_web::Error, ServiceRequest)> {
     // Changed Error type
-    dotenv::dotenv().ok();
-    let jwt_secret: String = std::env::var("JWT_SECRET").expect("JWT SECRET must be set");
-    let key: Hmac<Sha256> =
-        <CoreWrapper<HmacCore<_>> as KeyInit>::new_from_slice(jwt_secret.as_bytes()).unwrap();
-    let token_string = credentials.token();
-
-    let claims: Result<TokenClaims, jwt::Error> = token_string.verify_with_key(&key);
-
-    match claims {
-        Ok(value) => {
+    let state = req
+        .app_data::<web::Data<AppState>>()
+        .cloned()
+        .expect("app state must be registered");
+    match verify_token(&state, credentials.token()).await {
+        Err(err) => {
+            let cause = format!("{err:?}");
+            Err((
+                InternalError::from_response(cause, database_error(err)).into(),
+                req,
+            ))
+        }
+        Ok(Some((mut value, signed_with_current))) => {
+            // re-sign tokens made with an older key
+            if !signed_with_current {
+                if let Ok(token) = state.jwt_keys.sign(value.clone()) {
+                    req.extensions_mut().insert(RefreshedToken(token));
+                }
+            }
+            let store = req.extensions().get::<Store>().map(|store| store.store_id);
+            if let Some(store_id) = store {
+                value.serve(store_id);
+            }
             req.extensions_mut().insert(value);
             Ok(req)
         }
-        Err(_) => {
+        Ok(None) => {
             let config = req
                 .app_data::<bearer::Config>()
                 .cloned()
@@ -193,14 +982,42 @@ pub async fn validator(
     }
 }
 
+// middleware that hands clients a token signed with the current key
+// when they authenticated with one signed by an older key
+pub async fn refresh_token(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let mut res = next.call(req).await?;
+    let refreshed = res.request().extensions().get::<RefreshedToken>().cloned();
+    if let Some(RefreshedToken(token)) = refreshed {
+        if let Ok(value) = HeaderValue::from_str(&token) {
+            res.headers_mut()
+                .insert(HeaderName::from_static("x-refreshed-token"), value);
+        }
+    }
+    Ok(res)
+}
+
+// get request for the public keys tokens can be verified with
+#[get("/.well-known/jwks.json")]
+pub async fn jwks(state: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(state.jwt_keys.jwks())
+}
+
 // get all user request
 #[get("/api/users")]
 pub async fn get_user(state: web::Data<AppState>) -> impl Responder {
-    match User::get_all(&state.db).await {
+    match state.users.get_all().await {
         // return response 200 and users on sucess
-        Ok(users) => HttpResponse::Ok().json(users),
+        Ok(users) => HttpResponse::Ok().json(
+            users
+                .into_iter()
+                .map(UserResponse::from)
+                .collect::<Vec<_>>(),
+        ),
         // return server error 500 on fail
-        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        Err(e) => database_error(e),
     }
 }
 
@@ -210,32 +1027,93 @@ pub async fn get_user_by_id(
     state: web::Data<AppState>,
     user_id: web::Path<Uuid>,
 ) -> impl Responder {
-    match User::get_by_id(&state.db, *user_id).await {
+    match state.users.get_by_id(*user_id).await {
         // if id found return response 200
-        Ok(Some(user)) => HttpResponse::Ok().json(user),
+        Ok(Some(user)) => HttpResponse::Ok().json(UserResponse::from(user)),
         // if id is not found return response 200
         Ok(None) => HttpResponse::NotFound().body(format!("User ID: {user_id} not found")),
         // if not found return response 404
-        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        Err(e) => database_error(e),
     }
 }
 
 // post request to create new user / register
 #[post("api/users")]
-pub async fn create_user(state: web::Data<AppState>, body: Json<CreateUserBody>) -> impl Responder {
-    match User::create_user(&state.db, body).await {
+pub async fn create_user(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: Json<CreateUserBody>,
+) -> impl Responder {
+    // verify captcha when enabled
+    if let Err(response) = captcha::check_request(state.captcha.as_ref(), &req).await {
+        return response;
+    }
+
+    // enforce the password policy
+    let violations = state.password_policy.validate(&body.password).await;
+    if !violations.is_empty() {
+        return HttpResponse::UnprocessableEntity().json(violations);
+    }
+
+    if !body.accept_terms {
+        return HttpResponse::UnprocessableEntity()
+            .json("the terms of service and privacy policy have to be accepted");
+    }
+
+    let marketing_consent = body.marketing_consent;
+    match state.users.create(body.into_inner()).await {
         // return response 200 and users on sucess
-        Ok(users) => HttpResponse::Ok().json(users),
+        Ok(user) => {
+            // a customer whose acceptance wasn't recorded is asked again on
+            // their first request
+            if let Err(err) = PolicyAcceptance::record(
+                &state.db,
+                user.user_id,
+                &[Policy::Terms, Policy::Privacy],
+                &state.policies,
+                &req,
+            )
+            .await
+            {
+                println!("failed to record policy acceptance: {err:?}");
+            }
+            // an account without a recorded consent gets no marketing, which
+            // is where a failure here leaves it
+            if let Some(granted) = marketing_consent {
+                if let Err(err) = MarketingConsent::record(
+                    &state.db,
+                    user.user_id,
+                    granted,
+                    "registration",
+                    &state.marketing_policy_version,
+                    &req,
+                )
+                .await
+                {
+                    println!("failed to record marketing consent: {err:?}");
+                }
+            }
+            HttpResponse::Ok().json(UserResponse::from(user))
+        }
+        // return 422 when the email domain is blocklisted
+        Err(sqlx::Error::Protocol(msg)) if msg.contains("Email domain is not allowed") => {
+            HttpResponse::UnprocessableEntity().json(msg)
+        }
         // return server error 500 on fail
-        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        Err(e) => database_error(e),
     }
 }
 
 #[get("api/auth")]
-pub async fn auth(state: web::Data<AppState>, credentials: BasicAuth) -> impl Responder {
-    let jwt_secret: String = std::env::var("JWT_SECRET").expect("jwt secret must be set");
-    let key: Hmac<Sha256> =
-        <CoreWrapper<HmacCore<_>> as KeyInit>::new_from_slice(jwt_secret.as_bytes()).unwrap();
+pub async fn auth(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    credentials: BasicAuth,
+) -> impl Responder {
+    // verify captcha when enabled
+    if let Err(response) = captcha::check_request(state.captcha.as_ref(), &req).await {
+        return response;
+    }
 
     let email = credentials.user_id().to_string();
     let password = credentials.password();
@@ -243,43 +1121,61 @@ pub async fn auth(state: web::Data<AppState>, credentials: BasicAuth) -> impl Re
     match password {
         None => HttpResponse::Unauthorized().json("Must provide username and password"),
         Some(pass) => {
-            match sqlx::query_as!(
-                AuthResponse,
-                r#"SELECT user_id, email, password_hash, role as "role!: UserRole"
-                FROM users WHERE email = $1"#,
-                email
-            )
-            .fetch_one(&state.db)
-            .await
-            {
-                Ok(user) => {
-                    let hash_secret =
-                        std::env::var("HASH_SECRET").expect("hash secret must be set");
-                    let mut verifier = Verifier::default();
-                    let is_valid = verifier
-                        .with_hash(user.password_hash)
-                        .with_password(pass)
-                        .with_secret_key(hash_secret)
-                        .verify()
-                        .expect("failed to verify");
-
-                    if is_valid {
-                        let claims = TokenClaims {
-                            user_id: user.user_id,
-                            role: user.role,
-                        };
-                        let token_str = claims.sign_with_key(&key).expect("failed to sign in");
-                        HttpResponse::Ok().json(token_str)
-                    } else {
+            match state.users.get_credentials(&email).await {
+                Ok(user) => match verify_password(&user.password_hash, pass) {
+                    PasswordMatch::Invalid => {
                         HttpResponse::Unauthorized().json("incorrect email or password")
                     }
-                }
-                Err(err) => HttpResponse::InternalServerError().json(format!("{:?}", err)),
+                    _ if !user.is_active => {
+                        HttpResponse::Forbidden().json("this account has been deactivated")
+                    }
+                    matched => {
+                        // rehash passwords made with a previous secret
+                        if let PasswordMatch::Previous = matched {
+                            if let Err(err) = state.users.set_password(user.user_id, pass).await {
+                                println!("failed to rehash password: {err:?}");
+                            }
+                        }
+
+                        // with two-factor login the token waits for the texted code
+                        if user.sms_two_factor {
+                            return sms::start_login(&state, user.user_id, user.phone.as_deref())
+                                .await;
+                        }
+
+                        sign_in(&state, &req, user.user_id, user.role).await
+                    }
+                },
+                Err(err) => database_error(err),
             }
         }
     }
 }
 
+// record the login as a session tied to this device and answer with its token
+pub async fn sign_in(
+    state: &AppState,
+    req: &HttpRequest,
+    user_id: Uuid,
+    role: UserRole,
+) -> HttpResponse {
+    let session_id = match Session::create(&state.db, user_id, req).await {
+        Ok(session_id) => session_id,
+        Err(err) => return database_error(err),
+    };
+
+    let claims = TokenClaims {
+        user_id,
+        role,
+        session_id,
+        admin_store: None,
+        customer_group: CustomerGroup::Retail,
+        pending_policies: Vec::new(),
+    };
+    let token_str = state.jwt_keys.sign(claims).expect("failed to sign in");
+    HttpResponse::Ok().json(token_str)
+}
+
 // get request to get current user information
 #[get("api/user_info")]
 pub async fn get_user_info(
@@ -287,21 +1183,145 @@ pub async fn get_user_info(
     req_user: Option<ReqData<TokenClaims>>,
 ) -> impl Responder {
     match req_user {
-        Some(user) => match User::get_user_info(&state.db, user.user_id).await {
-            Ok(user_info) => HttpResponse::Ok().json(user_info),
-            Err(err) => HttpResponse::InternalServerError().json(format!("err:?")),
+        Some(user) => match state.users.get_info(user.user_id).await {
+            Ok(user_info) => {
+                let mut response = UserResponse::from(user_info);
+                match MarketingConsent::current(&state.db, user.user_id).await {
+                    Ok(consent) => response.marketing_consent = consent,
+                    Err(err) => return database_error(err),
+                }
+                HttpResponse::Ok().json(response)
+            }
+            Err(err) => database_error(err),
+        },
+        None => HttpResponse::Unauthorized().json("please log in first"),
+    }
+}
+
+// put request to change the current user's password
+#[put("api/users/me/password")]
+pub async fn change_password(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+    body: Json<ChangePasswordBody>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            let violations = state.password_policy.validate(&body.new_password).await;
+            if !violations.is_empty() {
+                return HttpResponse::UnprocessableEntity().json(violations);
+            }
+
+            match state
+                .users
+                .change_password(user.user_id, &body.current_password, &body.new_password)
+                .await
+            {
+                Ok(_) => HttpResponse::Ok().json("password changed"),
+                Err(sqlx::Error::Protocol(msg)) => HttpResponse::Unauthorized().json(msg),
+                Err(err) => database_error(err),
+            }
+        }
+        None => HttpResponse::Unauthorized().json("please log in first"),
+    }
+}
+
+// put request to set the current user's date of birth, age restricted
+// products also need staff to verify it
+#[put("api/users/me/date-of-birth")]
+pub async fn set_date_of_birth(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+    body: Json<DateOfBirthBody>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => match state
+            .users
+            .set_date_of_birth(user.user_id, body.date_of_birth)
+            .await
+        {
+            Ok(user) => HttpResponse::Ok().json(UserResponse::from(user)),
+            Err(sqlx::Error::Protocol(msg)) => HttpResponse::BadRequest().json(msg),
+            Err(err) => database_error(err),
         },
         None => HttpResponse::Unauthorized().json("please log in first"),
     }
 }
 
+// admin only
+// patch request to change a user's role, active flag, phone or whether their
+// age is verified
+#[patch("api/admin/users/{id}")]
+pub async fn patch_user(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+    user_id: web::Path<Uuid>,
+    body: Json<UserPatch>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if !user.is_platform_admin() {
+                return HttpResponse::Forbidden().json("only admins of every store can edit users");
+            }
+            match state
+                .users
+                .patch(user.user_id, *user_id, body.into_inner())
+                .await
+            {
+                Ok(Some(user)) => HttpResponse::Ok().json(UserResponse::from(user)),
+                Ok(None) => HttpResponse::NotFound().json(format!("User ID: {user_id} not found")),
+                Err(sqlx::Error::Protocol(msg)) if msg.starts_with("Admins") => {
+                    HttpResponse::Conflict().json(msg)
+                }
+                Err(sqlx::Error::Protocol(msg)) => HttpResponse::BadRequest().json(msg),
+                Err(sqlx::Error::Database(err)) if err.is_foreign_key_violation() => {
+                    HttpResponse::BadRequest().json("store not found")
+                }
+                Err(err) => database_error(err),
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
 // Helper functions for role checking
 impl TokenClaims {
     pub fn is_admin(&self) -> bool {
         matches!(self.role, UserRole::Admin)
     }
 
+    // admins can do everything, staff what their role grants
+    pub fn can(&self, permission: Permission) -> bool {
+        self.role.grants(permission)
+    }
+
+    // stores, users and whatever spans stores are theirs to manage
+    pub fn is_platform_admin(&self) -> bool {
+        self.is_admin() && self.admin_store.is_none()
+    }
+
+    // the request is for this store, an admin or staff member of another one
+    // is only a customer here
+    fn serve(&mut self, store_id: Uuid) {
+        if self
+            .admin_store
+            .is_some_and(|admin_store| admin_store != store_id)
+        {
+            self.role = UserRole::Customer;
+        }
+    }
+
+    // the customer group whose products the user may see, none for admins,
+    // who see the whole catalogue to manage it
+    pub fn catalogue_group(&self) -> Option<CustomerGroup> {
+        (!self.is_admin()).then_some(self.customer_group)
+    }
+
     pub fn is_customer(&self) -> bool {
         matches!(self.role, UserRole::Customer)
     }
+
+    pub fn pending_policies(&self) -> &[Policy] {
+        &self.pending_policies
+    }
 }
@@ -0,0 +1,320 @@
+use crate::{
+    api::{customer_groups::CustomerGroup, notifications::Notification, users::TokenClaims},
+    rate_limit::database_error,
+    AppState,
+};
+use actix_web::{
+    get, post,
+    web::{self, Json, ReqData},
+    HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+#[derive(Debug, Serialize, Deserialize, sqlx::Type, Clone, Copy, PartialEq)]
+#[sqlx(type_name = "application_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ApplicationStatus {
+    Pending,
+    Approved,
+    Rejected,
+}
+
+#[derive(Serialize, FromRow)]
+struct WholesaleApplication {
+    application_id: Uuid,
+    user_id: Uuid,
+    company_name: String,
+    company_address: String,
+    vat_number: Option<String>,
+    website: Option<String>,
+    message: Option<String>,
+    status: ApplicationStatus,
+    admin_note: Option<String>,
+    reviewed_by: Option<Uuid>,
+    reviewed_at: Option<DateTime<Utc>>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+struct ApplicationBody {
+    company_name: String,
+    company_address: String,
+    vat_number: Option<String>,
+    website: Option<String>,
+    message: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ReviewBody {
+    // shown to the customer in the notification
+    admin_note: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ApplicationQuery {
+    status: Option<ApplicationStatus>,
+}
+
+impl WholesaleApplication {
+    async fn create(
+        pool: &PgPool,
+        user_id: Uuid,
+        body: ApplicationBody,
+    ) -> Result<WholesaleApplication, sqlx::Error> {
+        if body.company_name.trim().is_empty() || body.company_address.trim().is_empty() {
+            return Err(sqlx::Error::Protocol(
+                "Company name and address are required".into(),
+            ));
+        }
+
+        let group = sqlx::query!(
+            r#"SELECT customer_group as "customer_group: CustomerGroup" FROM users WHERE user_id = $1"#,
+            user_id
+        )
+        .fetch_one(pool)
+        .await?;
+        if group.customer_group == CustomerGroup::Wholesale {
+            return Err(sqlx::Error::Protocol(
+                "Account already has wholesale prices".into(),
+            ));
+        }
+
+        sqlx::query_as!(
+            WholesaleApplication,
+            r#"INSERT INTO wholesale_applications
+                (user_id, company_name, company_address, vat_number, website, message)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING application_id, user_id, company_name, company_address, vat_number, website,
+                message, status as "status: ApplicationStatus", admin_note, reviewed_by,
+                reviewed_at, created_at"#,
+            user_id,
+            body.company_name.trim(),
+            body.company_address.trim(),
+            body.vat_number,
+            body.website,
+            body.message
+        )
+        .fetch_one(pool)
+        .await
+        .map_err(|err| match err {
+            sqlx::Error::Database(err) if err.is_unique_violation() => {
+                sqlx::Error::Protocol("An application is already waiting for review".into())
+            }
+            err => err,
+        })
+    }
+
+    async fn get_for_user(
+        pool: &PgPool,
+        user_id: Uuid,
+    ) -> Result<Vec<WholesaleApplication>, sqlx::Error> {
+        sqlx::query_as!(
+            WholesaleApplication,
+            r#"SELECT application_id, user_id, company_name, company_address, vat_number, website,
+                message, status as "status: ApplicationStatus", admin_note, reviewed_by,
+                reviewed_at, created_at
+            FROM wholesale_applications WHERE user_id = $1 ORDER BY created_at DESC"#,
+            user_id
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    // the approval queue, oldest first, pending unless another status is asked for
+    async fn get_queue(
+        pool: &PgPool,
+        status: ApplicationStatus,
+    ) -> Result<Vec<WholesaleApplication>, sqlx::Error> {
+        sqlx::query_as!(
+            WholesaleApplication,
+            r#"SELECT application_id, user_id, company_name, company_address, vat_number, website,
+                message, status as "status: ApplicationStatus", admin_note, reviewed_by,
+                reviewed_at, created_at
+            FROM wholesale_applications WHERE status = $1 ORDER BY created_at"#,
+            status as ApplicationStatus
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    // approve or reject a pending application and tell the customer,
+    // approval moves them into the wholesale group
+    async fn review(
+        pool: &PgPool,
+        application_id: Uuid,
+        admin_id: Uuid,
+        status: ApplicationStatus,
+        admin_note: Option<String>,
+    ) -> Result<WholesaleApplication, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        let application = sqlx::query_as!(
+            WholesaleApplication,
+            r#"UPDATE wholesale_applications
+            SET status = $1, admin_note = $2, reviewed_by = $3, reviewed_at = NOW()
+            WHERE application_id = $4 AND status = 'pending'
+            RETURNING application_id, user_id, company_name, company_address, vat_number, website,
+                message, status as "status: ApplicationStatus", admin_note, reviewed_by,
+                reviewed_at, created_at"#,
+            status as ApplicationStatus,
+            admin_note,
+            admin_id,
+            application_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)?;
+
+        let message = match status {
+            ApplicationStatus::Approved => {
+                CustomerGroup::assign(&mut *tx, application.user_id, CustomerGroup::Wholesale)
+                    .await?;
+                "Your wholesale application was approved, wholesale prices now apply".to_string()
+            }
+            _ => match &application.admin_note {
+                Some(note) => format!("Your wholesale application was not approved: {note}"),
+                None => "Your wholesale application was not approved".to_string(),
+            },
+        };
+        Notification::create(&mut *tx, application.user_id, &message).await?;
+
+        tx.commit().await?;
+
+        Ok(application)
+    }
+}
+
+// post request to apply for a wholesale account
+#[post("api/wholesale/applications")]
+pub async fn apply_for_wholesale(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+    body: Json<ApplicationBody>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            match WholesaleApplication::create(&state.db, user.user_id, body.into_inner()).await {
+                Ok(application) => HttpResponse::Created().json(application),
+                Err(sqlx::Error::Protocol(msg)) if msg.contains("required") => {
+                    HttpResponse::BadRequest().json(msg)
+                }
+                Err(sqlx::Error::Protocol(msg)) => HttpResponse::Conflict().json(msg),
+                Err(err) => database_error(err),
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// get request for the current user's wholesale applications
+#[get("api/wholesale/applications")]
+pub async fn get_wholesale_applications(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => match WholesaleApplication::get_for_user(&state.db, user.user_id).await {
+            Ok(applications) => HttpResponse::Ok().json(applications),
+            Err(err) => database_error(err),
+        },
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// get request for the wholesale approval queue
+#[get("api/admin/wholesale/applications")]
+pub async fn get_wholesale_queue(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+    query: web::Query<ApplicationQuery>,
+) -> impl Responder {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                let status = query.status.unwrap_or(ApplicationStatus::Pending);
+                match WholesaleApplication::get_queue(&state.db, status).await {
+                    Ok(applications) => HttpResponse::Ok().json(applications),
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("customer not allowed to review applications")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+async fn review_application(
+    state: &AppState,
+    req_user: Option<ReqData<TokenClaims>>,
+    application_id: Uuid,
+    status: ApplicationStatus,
+    body: ReviewBody,
+) -> HttpResponse {
+    match req_user {
+        Some(user) => {
+            if user.is_admin() {
+                match WholesaleApplication::review(
+                    &state.db,
+                    application_id,
+                    user.user_id,
+                    status,
+                    body.admin_note,
+                )
+                .await
+                {
+                    Ok(application) => HttpResponse::Ok().json(application),
+                    Err(sqlx::Error::RowNotFound) => {
+                        HttpResponse::NotFound().json("no pending application with that id")
+                    }
+                    Err(err) => database_error(err),
+                }
+            } else {
+                HttpResponse::Forbidden().json("customer not allowed to review applications")
+            }
+        }
+        None => HttpResponse::Unauthorized().json("unable to verify indentity"),
+    }
+}
+
+// admin only
+// post request to approve an application, the customer gets wholesale prices
+#[post("api/admin/wholesale/applications/{id}/approve")]
+pub async fn approve_wholesale_application(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+    application_id: web::Path<Uuid>,
+    body: Json<ReviewBody>,
+) -> impl Responder {
+    review_application(
+        &state,
+        req_user,
+        *application_id,
+        ApplicationStatus::Approved,
+        body.into_inner(),
+    )
+    .await
+}
+
+// admin only
+// post request to reject an application
+#[post("api/admin/wholesale/applications/{id}/reject")]
+pub async fn reject_wholesale_application(
+    state: web::Data<AppState>,
+    req_user: Option<ReqData<TokenClaims>>,
+    application_id: web::Path<Uuid>,
+    body: Json<ReviewBody>,
+) -> impl Responder {
+    review_application(
+        &state,
+        req_user,
+        *application_id,
+        ApplicationStatus::Rejected,
+        body.into_inner(),
+    )
+    .await
+}
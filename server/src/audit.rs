@@ -0,0 +1,27 @@
+use serde_json::Value;
+use sqlx::PgExecutor;
+use uuid::Uuid;
+
+// append an entry to the audit log, pass the transaction so the entry
+// is only kept when the action itself is committed
+pub async fn record<'c>(
+    executor: impl PgExecutor<'c>,
+    actor_id: Uuid,
+    action: &str,
+    entity_type: &str,
+    entity_id: Uuid,
+    details: Value,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO audit_log (actor_id, action, entity_type, entity_id, details)
+        VALUES ($1, $2, $3, $4, $5)",
+        actor_id,
+        action,
+        entity_type,
+        entity_id,
+        details
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,150 @@
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use async_trait::async_trait;
+use serde_json::json;
+
+use crate::outbox::OutboxEvent;
+
+#[derive(Debug)]
+pub enum BrokerError {
+    Nats(String),
+    Http(reqwest::Error),
+    Rejected(String),
+}
+
+impl fmt::Display for BrokerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BrokerError::Nats(msg) => write!(f, "nats: {msg}"),
+            BrokerError::Http(err) => write!(f, "{err}"),
+            BrokerError::Rejected(msg) => write!(f, "broker rejected the event: {msg}"),
+        }
+    }
+}
+
+impl From<reqwest::Error> for BrokerError {
+    fn from(err: reqwest::Error) -> Self {
+        BrokerError::Http(err)
+    }
+}
+
+// where domain events go for analytics and fulfilment. Messages are the
+// event as JSON, keyed by the order, product or user it is about
+#[async_trait]
+pub trait Publisher: Send + Sync {
+    async fn publish(&self, topic: &str, event: &OutboxEvent) -> Result<(), BrokerError>;
+}
+
+// NATS subjects, connected on first use
+pub struct Nats {
+    url: String,
+    client: tokio::sync::OnceCell<async_nats::Client>,
+}
+
+#[async_trait]
+impl Publisher for Nats {
+    async fn publish(&self, topic: &str, event: &OutboxEvent) -> Result<(), BrokerError> {
+        let client = self
+            .client
+            .get_or_try_init(|| async_nats::connect(self.url.as_str()))
+            .await
+            .map_err(|err| BrokerError::Nats(err.to_string()))?;
+        let payload = serde_json::to_vec(event).expect("events serialize");
+        client
+            .publish(topic.to_string(), payload.into())
+            .await
+            .map_err(|err| BrokerError::Nats(err.to_string()))?;
+        // only count it as published once the server has it
+        client
+            .flush()
+            .await
+            .map_err(|err| BrokerError::Nats(err.to_string()))
+    }
+}
+
+// Kafka topics through a Confluent-compatible REST proxy
+pub struct KafkaRest {
+    client: reqwest::Client,
+    url: String,
+}
+
+#[async_trait]
+impl Publisher for KafkaRest {
+    async fn publish(&self, topic: &str, event: &OutboxEvent) -> Result<(), BrokerError> {
+        let res = self
+            .client
+            .post(format!("{}/topics/{topic}", self.url))
+            .header("Content-Type", "application/vnd.kafka.json.v2+json")
+            .json(&json!({
+                "records": [{ "key": event.aggregate_id, "value": event }],
+            }))
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            return Err(BrokerError::Rejected(format!(
+                "{} {}",
+                res.status(),
+                res.text().await.unwrap_or_default()
+            )));
+        }
+        Ok(())
+    }
+}
+
+// the configured broker and which topic each event type goes to.
+// EVENT_BROKER_URL picks the broker: nats://host:4222 for NATS, the http(s)
+// address of a Kafka REST proxy for Kafka. EVENT_TOPICS maps event types to
+// topics ("order.created=orders,stock.changed=inventory"), the rest go to a
+// topic named after the event type, prefixed with EVENT_TOPIC_PREFIX
+pub struct Broker {
+    publisher: Arc<dyn Publisher>,
+    topics: HashMap<String, String>,
+    prefix: String,
+}
+
+impl Broker {
+    pub fn from_env() -> Option<Self> {
+        let url = std::env::var("EVENT_BROKER_URL")
+            .ok()
+            .filter(|url| !url.is_empty())?;
+        let publisher: Arc<dyn Publisher> = if url.starts_with("nats://") {
+            Arc::new(Nats {
+                url,
+                client: tokio::sync::OnceCell::new(),
+            })
+        } else if url.starts_with("http://") || url.starts_with("https://") {
+            Arc::new(KafkaRest {
+                client: reqwest::Client::new(),
+                url: url.trim_end_matches('/').to_string(),
+            })
+        } else {
+            panic!("EVENT_BROKER_URL must be a nats:// or http(s):// url");
+        };
+
+        let topics = std::env::var("EVENT_TOPICS")
+            .unwrap_or_default()
+            .split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .map(|entry| {
+                let (event_type, topic) = entry
+                    .split_once('=')
+                    .unwrap_or_else(|| panic!("EVENT_TOPICS entry {entry} must be type=topic"));
+                (event_type.trim().to_string(), topic.trim().to_string())
+            })
+            .collect();
+
+        Some(Broker {
+            publisher,
+            topics,
+            prefix: std::env::var("EVENT_TOPIC_PREFIX").unwrap_or_default(),
+        })
+    }
+
+    pub async fn publish(&self, event: &OutboxEvent) -> Result<(), BrokerError> {
+        let topic = match self.topics.get(&event.event_type) {
+            Some(topic) => topic.clone(),
+            None => format!("{}{}", self.prefix, event.event_type),
+        };
+        self.publisher.publish(&topic, event).await
+    }
+}
@@ -0,0 +1,144 @@
+use std::{future::Future, hash::Hash, io, sync::Arc, time::Duration};
+
+use moka::future::Cache;
+use sqlx::{postgres::PgListener, PgPool};
+
+use crate::rate_limit;
+
+// invalidations go to every instance over this Postgres NOTIFY channel, the
+// payload is the name of the cache to drop
+const CHANNEL: &str = "cache_invalidation";
+
+// an in-process cache of query results for a short while. Callers asking for a
+// key that is being loaded wait for that load instead of running the query
+// again, so an expired hot entry costs one query and not one per request.
+// A zero ttl turns caching off
+pub struct Cached<K, V> {
+    name: &'static str,
+    entries: Option<Cache<K, V>>,
+    // where invalidations are announced to the other instances
+    bus: Option<PgPool>,
+}
+
+// what the invalidation listener needs of a cache
+pub trait Invalidate: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // drop the entries of this instance only
+    fn clear(&self);
+}
+
+impl<K, V> Cached<K, V>
+where
+    K: Hash + Eq + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    pub fn new(name: &'static str, ttl: Duration, capacity: u64) -> Self {
+        Cached {
+            name,
+            entries: (!ttl.is_zero()).then(|| {
+                Cache::builder()
+                    .time_to_live(ttl)
+                    .max_capacity(capacity)
+                    .build()
+            }),
+            bus: None,
+        }
+    }
+
+    // announce invalidations to the instances sharing the database
+    pub fn shared(self, pool: PgPool) -> Self {
+        Cached {
+            bus: Some(pool),
+            ..self
+        }
+    }
+
+    // the cached value, or what load returns, which is kept when it succeeded
+    pub async fn get_or_load(
+        &self,
+        key: K,
+        load: impl Future<Output = Result<V, sqlx::Error>>,
+    ) -> Result<V, sqlx::Error> {
+        let Some(entries) = &self.entries else {
+            return load.await;
+        };
+        entries.try_get_with(key, load).await.map_err(|err| {
+            // everyone waiting on the load shares its error, each gets one
+            // handlers tell apart the same way
+            Arc::try_unwrap(err).unwrap_or_else(|err| match &*err {
+                sqlx::Error::RowNotFound => sqlx::Error::RowNotFound,
+                sqlx::Error::Protocol(msg) => sqlx::Error::Protocol(msg.clone()),
+                err if rate_limit::overloaded(err) => sqlx::Error::PoolTimedOut,
+                err => sqlx::Error::Io(io::Error::other(err.to_string())),
+            })
+        })
+    }
+
+    // drop everything here and on the other instances, after a write the
+    // cached values may not show. A failed announcement leaves the other
+    // instances to their ttl, the write itself went through
+    pub async fn invalidate(&self) {
+        let Some(entries) = &self.entries else {
+            return;
+        };
+        entries.invalidate_all();
+        if let Some(pool) = &self.bus {
+            if let Err(err) = sqlx::query!("SELECT pg_notify($1, $2)", CHANNEL, self.name)
+                .execute(pool)
+                .await
+            {
+                println!(
+                    "failed to announce the invalidation of {}: {err:?}",
+                    self.name
+                );
+            }
+        }
+    }
+}
+
+impl<K, V> Invalidate for Cached<K, V>
+where
+    K: Hash + Eq + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn clear(&self) {
+        if let Some(entries) = &self.entries {
+            entries.invalidate_all();
+        }
+    }
+}
+
+// clears the caches other instances (and this one) announce invalidations of.
+// Announcements made while the connection was down are lost, so every cache
+// is cleared once it is back
+pub fn spawn_invalidation_listener(pool: PgPool, caches: Vec<Arc<dyn Invalidate>>) {
+    actix_web::rt::spawn(async move {
+        loop {
+            if let Err(err) = listen(&pool, &caches).await {
+                println!("cache invalidation listener failed: {err:?}");
+            }
+            caches.iter().for_each(|cache| cache.clear());
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        }
+    });
+}
+
+async fn listen(pool: &PgPool, caches: &[Arc<dyn Invalidate>]) -> Result<(), sqlx::Error> {
+    let mut listener = PgListener::connect_with(pool).await?;
+    listener.listen(CHANNEL).await?;
+    // none when the connection dropped, try_recv reconnects on the next call
+    loop {
+        match listener.try_recv().await? {
+            Some(notification) => caches
+                .iter()
+                .filter(|cache| cache.name() == notification.payload())
+                .for_each(|cache| cache.clear()),
+            None => caches.iter().for_each(|cache| cache.clear()),
+        }
+    }
+}
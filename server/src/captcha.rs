@@ -0,0 +1,113 @@
+use std::sync::Arc;
+
+use actix_web::{HttpRequest, HttpResponse};
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use crate::client_ip::client_ip;
+
+// header the client sends the captcha response token in
+pub const CAPTCHA_HEADER: &str = "X-Captcha-Token";
+
+#[async_trait]
+pub trait CaptchaVerifier: Send + Sync {
+    async fn verify(&self, token: &str, remote_ip: Option<&str>) -> Result<bool, reqwest::Error>;
+}
+
+#[derive(Deserialize)]
+struct SiteVerifyResponse {
+    success: bool,
+}
+
+// hCaptcha, reCAPTCHA and Turnstile all share the same siteverify protocol,
+// a form post of secret + response token answered with {"success": bool}
+pub struct SiteVerify {
+    client: reqwest::Client,
+    secret: String,
+    verify_url: &'static str,
+}
+
+impl SiteVerify {
+    pub fn hcaptcha(secret: String) -> Self {
+        Self::new(secret, "https://api.hcaptcha.com/siteverify")
+    }
+
+    pub fn recaptcha(secret: String) -> Self {
+        Self::new(secret, "https://www.google.com/recaptcha/api/siteverify")
+    }
+
+    pub fn turnstile(secret: String) -> Self {
+        Self::new(
+            secret,
+            "https://challenges.cloudflare.com/turnstile/v0/siteverify",
+        )
+    }
+
+    fn new(secret: String, verify_url: &'static str) -> Self {
+        SiteVerify {
+            client: reqwest::Client::new(),
+            secret,
+            verify_url,
+        }
+    }
+}
+
+#[async_trait]
+impl CaptchaVerifier for SiteVerify {
+    async fn verify(&self, token: &str, remote_ip: Option<&str>) -> Result<bool, reqwest::Error> {
+        let mut form = vec![("secret", self.secret.as_str()), ("response", token)];
+        if let Some(ip) = remote_ip {
+            form.push(("remoteip", ip));
+        }
+
+        let response: SiteVerifyResponse = self
+            .client
+            .post(self.verify_url)
+            .form(&form)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(response.success)
+    }
+}
+
+// build the verifier from CAPTCHA_PROVIDER and CAPTCHA_SECRET,
+// captcha checks are disabled when no provider is configured
+pub fn from_env() -> Option<Arc<dyn CaptchaVerifier>> {
+    let provider = std::env::var("CAPTCHA_PROVIDER").ok()?;
+    let secret = std::env::var("CAPTCHA_SECRET").expect("CAPTCHA_SECRET must be set");
+
+    let verifier: Arc<dyn CaptchaVerifier> = match provider.to_lowercase().as_str() {
+        "hcaptcha" => Arc::new(SiteVerify::hcaptcha(secret)),
+        "recaptcha" => Arc::new(SiteVerify::recaptcha(secret)),
+        "turnstile" => Arc::new(SiteVerify::turnstile(secret)),
+        other => panic!("unknown CAPTCHA_PROVIDER: {other}"),
+    };
+    Some(verifier)
+}
+
+// check the captcha token on a request, returns the response to send back on failure
+pub async fn check_request(
+    verifier: Option<&Arc<dyn CaptchaVerifier>>,
+    req: &HttpRequest,
+) -> Result<(), HttpResponse> {
+    let Some(verifier) = verifier else {
+        return Ok(());
+    };
+
+    let token = req
+        .headers()
+        .get(CAPTCHA_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .ok_or_else(|| HttpResponse::BadRequest().json("captcha token is required"))?;
+
+    let remote_ip = client_ip(req).map(|ip| ip.to_string());
+    match verifier.verify(token, remote_ip.as_deref()).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(HttpResponse::BadRequest().json("captcha verification failed")),
+        Err(err) => Err(HttpResponse::ServiceUnavailable().json(format!("{err:?}"))),
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+// header carrying the hex HMAC-SHA256 of the webhook body
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+
+#[derive(Debug, Serialize, Deserialize, sqlx::Type, Clone, Copy, PartialEq)]
+#[sqlx(type_name = "shipment_status", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ShipmentStatus {
+    LabelCreated,
+    InTransit,
+    OutForDelivery,
+    Delivered,
+    Exception,
+}
+
+impl ShipmentStatus {
+    // carriers word their statuses differently, map the common spellings and codes
+    pub fn from_carrier(status: &str) -> Option<Self> {
+        let status = status.trim().to_lowercase().replace([' ', '-'], "_");
+        match status.as_str() {
+            "label_created" | "pre_transit" | "manifest" | "m" | "info_received" => {
+                Some(ShipmentStatus::LabelCreated)
+            }
+            "in_transit" | "transit" | "picked_up" | "i" | "p" => Some(ShipmentStatus::InTransit),
+            "out_for_delivery" | "o" => Some(ShipmentStatus::OutForDelivery),
+            "delivered" | "d" => Some(ShipmentStatus::Delivered),
+            "exception" | "failure" | "failed_attempt" | "returned" | "x" => {
+                Some(ShipmentStatus::Exception)
+            }
+            _ => None,
+        }
+    }
+
+    // how far along the delivery the status is, none for exceptions which can
+    // come at any step
+    fn step(&self) -> Option<u8> {
+        match self {
+            ShipmentStatus::LabelCreated => Some(0),
+            ShipmentStatus::InTransit => Some(1),
+            ShipmentStatus::OutForDelivery => Some(2),
+            ShipmentStatus::Delivered => Some(3),
+            ShipmentStatus::Exception => None,
+        }
+    }
+
+    // callbacks arrive late and out of order, a shipment never goes back to an
+    // earlier step. Exceptions can be raised and resolved from anywhere
+    pub fn can_move_to(&self, next: ShipmentStatus) -> bool {
+        match (self.step(), next.step()) {
+            (Some(current), Some(next)) => next > current,
+            _ => true,
+        }
+    }
+
+    // what the customer is told, none for statuses not worth a notification
+    pub fn notification(&self) -> Option<&'static str> {
+        match self {
+            ShipmentStatus::LabelCreated => None,
+            ShipmentStatus::InTransit => Some("Your order is on its way"),
+            ShipmentStatus::OutForDelivery => Some("Your order is out for delivery"),
+            ShipmentStatus::Delivered => Some("Your order was delivered"),
+            ShipmentStatus::Exception => Some("There is a problem with the delivery of your order"),
+        }
+    }
+}
+
+// webhook secrets per carrier, CARRIER_WEBHOOK_SECRETS="ups:secret1,dhl:secret2",
+// carriers without a secret have no webhook
+#[derive(Clone, Default)]
+pub struct CarrierWebhooks {
+    secrets: HashMap<String, String>,
+}
+
+impl CarrierWebhooks {
+    pub fn from_env() -> Self {
+        let secrets = std::env::var("CARRIER_WEBHOOK_SECRETS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let (carrier, secret) = entry
+                    .split_once(':')
+                    .expect("CARRIER_WEBHOOK_SECRETS entries must be carrier:secret");
+                (carrier.to_lowercase(), secret.to_string())
+            })
+            .collect();
+        CarrierWebhooks { secrets }
+    }
+
+    pub fn is_configured(&self, carrier: &str) -> bool {
+        self.secrets.contains_key(carrier)
+    }
+
+    // constant time check of the body signature
+    pub fn verify(&self, carrier: &str, body: &[u8], signature: &str) -> bool {
+        let (Some(secret), Some(signature)) = (self.secrets.get(carrier), decode_hex(signature))
+        else {
+            return false;
+        };
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("invalid webhook secret");
+        mac.update(body);
+        mac.verify_slice(&signature).is_ok()
+    }
+}
+
+// hex signature as sent in webhook headers, an optional "sha256=" prefix is dropped
+pub fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    let value = value.trim().trim_start_matches("sha256=");
+    if !value.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -0,0 +1,156 @@
+use clap::{Parser, Subcommand};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{api::users::User, password::PasswordPolicy, search, seed};
+
+// `server` on its own serves the API, the other subcommands are for operators
+#[derive(Parser)]
+#[command(about = "RustaceanMarket API server")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run pending migrations and start the API server (the default)
+    Serve,
+    /// Run pending migrations and exit
+    Migrate,
+    /// Create an admin account, an email that is already registered is refused
+    CreateAdmin {
+        #[arg(long)]
+        email: String,
+        #[arg(long, default_value = "Admin")]
+        first_name: String,
+        #[arg(long, default_value = "User")]
+        last_name: String,
+        /// Read from the environment so it stays out of the shell history
+        #[arg(long, env = "ADMIN_PASSWORD", hide_env_values = true)]
+        password: Option<String>,
+    },
+    /// Run pending migrations and add the sample users, products, carts and orders
+    Seed,
+    /// Send every product to the search engine set with SEARCH_ENGINE, for a
+    /// new or emptied index
+    Reindex,
+}
+
+pub async fn migrate(pool: &PgPool) {
+    sqlx::migrate!("./migrations")
+        .run(pool)
+        .await
+        .expect("migration failed");
+}
+
+pub async fn create_admin(
+    pool: &PgPool,
+    email: &str,
+    first_name: &str,
+    last_name: &str,
+    password: Option<&str>,
+) -> Result<(), String> {
+    if let Some(password) = password {
+        let violations = PasswordPolicy::from_env().validate(password).await;
+        if !violations.is_empty() {
+            return Err(violations.join(", "));
+        }
+    }
+
+    match User::create_admin(pool, email, first_name, last_name, password).await {
+        Ok((user_id, true)) => {
+            println!("created admin {email} ({user_id})");
+            Ok(())
+        }
+        Ok((user_id, false)) => {
+            println!("{email} ({user_id}) is already an admin");
+            Ok(())
+        }
+        Err(sqlx::Error::Protocol(msg)) => Err(msg),
+        Err(err) => Err(format!("{err:?}")),
+    }
+}
+
+// BOOTSTRAP_ADMIN_EMAIL and ADMIN_PASSWORD create the first admin when the
+// server starts on a store without admins. The account is created here with the
+// operator's password, one registered through the API under that email is never
+// promoted. Unset them once the first admin is in, create-admin covers
+// everything after that
+pub async fn bootstrap_admin(pool: &PgPool) {
+    let Ok(email) = std::env::var("BOOTSTRAP_ADMIN_EMAIL") else {
+        return;
+    };
+    let password = std::env::var("ADMIN_PASSWORD").ok();
+    if let Err(err) = bootstrap_first_admin(pool, &email, password.as_deref()).await {
+        println!("admin bootstrap failed: {err}");
+    }
+}
+
+// create the admin unless the store has one already
+pub async fn bootstrap_first_admin(
+    pool: &PgPool,
+    email: &str,
+    password: Option<&str>,
+) -> Result<(), String> {
+    if User::has_admin(pool)
+        .await
+        .map_err(|err| format!("{err:?}"))?
+    {
+        println!("BOOTSTRAP_ADMIN_EMAIL is set but the store has an admin already");
+        return Ok(());
+    }
+    let password =
+        password.ok_or_else(|| "BOOTSTRAP_ADMIN_EMAIL needs ADMIN_PASSWORD".to_string())?;
+    create_admin(pool, email, "Admin", "User", Some(password)).await
+}
+
+// seeded accounts log in with SEED_PASSWORD, or a password made up for this
+// run and printed once when it is not set. It has every kind of character so
+// any password policy takes it
+pub async fn seed(pool: &PgPool) -> Result<(), String> {
+    let password = match std::env::var("SEED_PASSWORD") {
+        Ok(password) => {
+            let violations = PasswordPolicy::from_env().validate(&password).await;
+            if !violations.is_empty() {
+                return Err(format!("SEED_PASSWORD: {}", violations.join(", ")));
+            }
+            password
+        }
+        Err(_) => {
+            let password = format!("Seed-{}-0", Uuid::new_v4().simple());
+            println!("SEED_PASSWORD is not set, new seeded accounts log in with {password}");
+            password
+        }
+    };
+
+    let seeded = seed::seed(pool, &password)
+        .await
+        .map_err(|err| format!("{err:?}"))?;
+    println!(
+        "seeded {} users, {} products, {} carts and {} orders",
+        seeded.users, seeded.products, seeded.carts, seeded.orders
+    );
+    Ok(())
+}
+
+// the search index as a whole, products reach it on their own once the
+// indexer runs but ones from before it was set up need this
+pub async fn reindex(pool: &PgPool) -> Result<(), String> {
+    let engine = search::from_env().ok_or("SEARCH_ENGINE is not set")?;
+    let product_ids = sqlx::query_scalar!("SELECT product_id FROM products ORDER BY product_id")
+        .fetch_all(pool)
+        .await
+        .map_err(|err| format!("{err:?}"))?;
+    for chunk in product_ids.chunks(500) {
+        let documents = search::documents(pool, chunk)
+            .await
+            .map_err(|err| format!("{err:?}"))?;
+        engine
+            .upsert(&documents)
+            .await
+            .map_err(|err| err.to_string())?;
+    }
+    println!("sent {} products to {}", product_ids.len(), engine.name());
+    Ok(())
+}
@@ -0,0 +1,49 @@
+use std::{net::IpAddr, sync::OnceLock};
+
+use actix_web::HttpRequest;
+
+// proxies in front of the API, from TRUSTED_PROXIES as comma separated IPs.
+// Only their X-Forwarded-For is believed, anyone else could put any address in it
+fn trusted_proxies() -> &'static [IpAddr] {
+    static PROXIES: OnceLock<Vec<IpAddr>> = OnceLock::new();
+    PROXIES.get_or_init(|| {
+        std::env::var("TRUSTED_PROXIES")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|proxy| !proxy.is_empty())
+            .map(|proxy| {
+                proxy
+                    .parse()
+                    .unwrap_or_else(|_| panic!("TRUSTED_PROXIES entry {proxy} is not an IP"))
+            })
+            .collect()
+    })
+}
+
+// the address the request came from: the connection's peer, or when that is a
+// trusted proxy the last address before the proxies in X-Forwarded-For
+pub fn client_ip(req: &HttpRequest) -> Option<IpAddr> {
+    let peer = req.peer_addr()?.ip();
+    let proxies = trusted_proxies();
+    if !proxies.contains(&peer) {
+        return Some(peer);
+    }
+
+    let forwarded: Vec<IpAddr> = req
+        .headers()
+        .get_all("X-Forwarded-For")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|hop| hop.trim().parse().ok())
+        .collect();
+    // each proxy appends the address it got the request from, so the first
+    // untrusted one from the right was added by our own proxies
+    forwarded
+        .iter()
+        .rev()
+        .find(|hop| !proxies.contains(hop))
+        .or(forwarded.first())
+        .copied()
+        .or(Some(peer))
+}
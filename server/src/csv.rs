@@ -0,0 +1,57 @@
+use actix_web::{
+    http::header::{self, Header},
+    web::Bytes,
+    HttpRequest, HttpResponse,
+};
+use futures_util::Stream;
+
+// CSV for the exports admins open in spreadsheets and accounting tools, and
+// for listings asked for with Accept: text/csv
+
+// quoted when needed, and kept from being read as a formula by spreadsheets
+pub fn field(value: &str) -> String {
+    let value = if value.starts_with(['=', '+', '-', '@']) {
+        format!("'{value}")
+    } else {
+        value.to_string()
+    };
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+// one line, newline included
+pub fn row<S: AsRef<str>>(fields: &[S]) -> String {
+    let fields: Vec<String> = fields.iter().map(|value| field(value.as_ref())).collect();
+    format!("{}\n", fields.join(","))
+}
+
+// whether the client prefers CSV to JSON, listings answer with it then
+pub fn requested(req: &HttpRequest) -> bool {
+    header::Accept::parse(req).is_ok_and(|accept| accept.preference().essence_str() == "text/csv")
+}
+
+// a CSV download streamed as it is written, the status is sent before the
+// first row so a failing query cuts the file short
+pub fn attachment<S, E>(filename: &str, body: S) -> HttpResponse
+where
+    S: Stream<Item = Result<Bytes, E>> + 'static,
+    E: std::fmt::Debug + 'static,
+{
+    let name = filename.to_string();
+    let body = futures_util::StreamExt::map(body, move |chunk| {
+        chunk.map_err(|err| {
+            println!("{name} failed: {err:?}");
+            actix_web::error::ErrorInternalServerError("export failed")
+        })
+    });
+    HttpResponse::Ok()
+        .content_type("text/csv; charset=utf-8")
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{filename}\""),
+        ))
+        .streaming(body)
+}
@@ -0,0 +1,182 @@
+use actix_web::{
+    body::{self, BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    error::ErrorInternalServerError,
+    http::header::{self, Header, HeaderValue},
+    middleware::Next,
+    HttpRequest, HttpResponse,
+};
+use serde::{Deserialize, Serialize};
+
+// every successful JSON response goes out as { "data": ..., "meta": ... }, meta
+// only when the handler attached pagination. Handlers keep answering with the
+// plain value, `wrap` puts it in the envelope. Paginated responses also get a
+// Link header for clients that don't read the envelope, and the routes the
+// mobile app polls most answer in MessagePack when the client prefers it
+#[derive(Serialize)]
+pub struct Envelope<T> {
+    data: T,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    meta: Option<Meta>,
+}
+
+#[derive(Serialize)]
+pub struct Meta {
+    pagination: Pagination,
+}
+
+// ?page=2&per_page=20 on listing endpoints, pages start at 1
+#[derive(Deserialize)]
+pub struct PageQuery {
+    page: Option<i64>,
+    per_page: Option<i64>,
+}
+
+const DEFAULT_PER_PAGE: i64 = 50;
+const MAX_PER_PAGE: i64 = 100;
+
+impl PageQuery {
+    pub fn page(&self) -> i64 {
+        self.page.unwrap_or(1).max(1)
+    }
+
+    pub fn per_page(&self) -> i64 {
+        self.per_page
+            .unwrap_or(DEFAULT_PER_PAGE)
+            .clamp(1, MAX_PER_PAGE)
+    }
+
+    pub fn offset(&self) -> i64 {
+        (self.page() - 1) * self.per_page()
+    }
+}
+
+// one page of a listing and how many rows the whole listing has
+#[derive(Clone)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub total: i64,
+}
+
+#[derive(Serialize, Clone, Copy)]
+pub struct Pagination {
+    page: i64,
+    per_page: i64,
+    total: i64,
+    total_pages: i64,
+}
+
+impl Pagination {
+    pub fn new(query: &PageQuery, total: i64) -> Self {
+        let per_page = query.per_page();
+        Pagination {
+            page: query.page(),
+            per_page,
+            total,
+            total_pages: (total + per_page - 1) / per_page,
+        }
+    }
+}
+
+// 200 with a page of a listing, the pagination ends up in meta
+pub fn paginated<T: Serialize>(items: Vec<T>, pagination: Pagination) -> HttpResponse {
+    let mut response = HttpResponse::Ok().json(items);
+    response.extensions_mut().insert(pagination);
+    response
+}
+
+// RFC 5988 links to the first, previous, next and last page, the rest of the
+// query stays as it was
+fn links(req: &HttpRequest, pagination: &Pagination) -> String {
+    let url = req.full_url();
+    let query: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(name, _)| name != "page" && name != "per_page")
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect();
+    let link = |page: i64, rel: &str| {
+        let mut url = url.clone();
+        url.query_pairs_mut()
+            .clear()
+            .extend_pairs(&query)
+            .append_pair("page", &page.to_string())
+            .append_pair("per_page", &pagination.per_page.to_string());
+        format!("<{url}>; rel=\"{rel}\"")
+    };
+
+    let last = pagination.total_pages.max(1);
+    let mut links = vec![link(1, "first")];
+    if pagination.page > 1 {
+        links.push(link((pagination.page - 1).min(last), "prev"));
+    }
+    if pagination.page < last {
+        links.push(link(pagination.page + 1, "next"));
+    }
+    links.push(link(last, "last"));
+    links.join(", ")
+}
+
+// the catalogue list and the cart, the same envelope encoded as MessagePack for
+// Accept: application/msgpack
+const MSGPACK_ROUTES: &[&str] = &["/api/products", "/api/carts"];
+const MSGPACK: &str = "application/msgpack";
+
+fn wants_msgpack(req: &HttpRequest) -> bool {
+    header::Accept::parse(req).is_ok_and(|accept| {
+        matches!(
+            accept.preference().essence_str(),
+            MSGPACK | "application/x-msgpack"
+        )
+    })
+}
+
+pub async fn wrap(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let res = next.call(req).await?;
+    let is_json = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|value| value.as_bytes().starts_with(b"application/json"));
+    if !res.status().is_success() || !is_json {
+        return Ok(res.map_into_boxed_body());
+    }
+
+    let pagination = res.response().extensions().get::<Pagination>().copied();
+    let (req, res) = res.into_parts();
+    let (mut res, body) = res.into_parts();
+    let negotiated = req
+        .match_pattern()
+        .is_some_and(|route| MSGPACK_ROUTES.contains(&route.as_str()));
+    if negotiated {
+        res.headers_mut()
+            .insert(header::VARY, HeaderValue::from_static("Accept"));
+    }
+    if let Some(link) = pagination
+        .map(|pagination| links(&req, &pagination))
+        .and_then(|link| HeaderValue::from_str(&link).ok())
+    {
+        res.headers_mut().insert(header::LINK, link);
+    }
+    let bytes = body::to_bytes(body).await.map_err(|err| {
+        let err: Box<dyn std::error::Error> = err.into();
+        ErrorInternalServerError(err.to_string())
+    })?;
+    let data: serde_json::Value = serde_json::from_slice(&bytes)?;
+
+    let envelope = Envelope {
+        data,
+        meta: pagination.map(|pagination| Meta { pagination }),
+    };
+    if negotiated && wants_msgpack(&req) {
+        let body = rmp_serde::to_vec_named(&envelope)
+            .map_err(|err| ErrorInternalServerError(err.to_string()))?;
+        res.headers_mut()
+            .insert(header::CONTENT_TYPE, HeaderValue::from_static(MSGPACK));
+        let res = res.set_body(body);
+        return Ok(ServiceResponse::new(req, res.map_into_boxed_body()));
+    }
+    let res = res.set_body(serde_json::to_vec(&envelope)?);
+    Ok(ServiceResponse::new(req, res.map_into_boxed_body()))
+}
@@ -0,0 +1,135 @@
+use sqlx::types::Decimal;
+
+// what the rules know about an order being placed
+pub struct FraudContext {
+    pub order_total: Decimal,
+    pub previous_orders: i64,
+    pub orders_last_hour: i64,
+    pub ip_country: Option<String>,
+    pub shipping_country: Option<String>,
+}
+
+pub trait FraudRule: Send + Sync {
+    fn name(&self) -> &'static str;
+    // risk points this rule adds, 0 when it doesn't apply
+    fn score(&self, ctx: &FraudContext) -> i32;
+}
+
+// order shipped to a different country than the one the customer browses from
+struct CountryMismatch;
+
+impl FraudRule for CountryMismatch {
+    fn name(&self) -> &'static str {
+        "country_mismatch"
+    }
+
+    fn score(&self, ctx: &FraudContext) -> i32 {
+        match (&ctx.ip_country, &ctx.shipping_country) {
+            (Some(ip), Some(shipping)) if !ip.eq_ignore_ascii_case(shipping) => 30,
+            _ => 0,
+        }
+    }
+}
+
+// large first order from a new account
+struct HighValueFirstOrder {
+    threshold: Decimal,
+}
+
+impl FraudRule for HighValueFirstOrder {
+    fn name(&self) -> &'static str {
+        "high_value_first_order"
+    }
+
+    fn score(&self, ctx: &FraudContext) -> i32 {
+        if ctx.previous_orders == 0 && ctx.order_total >= self.threshold {
+            40
+        } else {
+            0
+        }
+    }
+}
+
+// many orders in a short time
+struct Velocity {
+    max_per_hour: i64,
+}
+
+impl FraudRule for Velocity {
+    fn name(&self) -> &'static str {
+        "velocity"
+    }
+
+    fn score(&self, ctx: &FraudContext) -> i32 {
+        if ctx.orders_last_hour >= self.max_per_hour {
+            40
+        } else {
+            0
+        }
+    }
+}
+
+pub struct FraudAssessment {
+    pub score: i32,
+    pub reasons: Vec<String>,
+    pub needs_review: bool,
+}
+
+pub struct FraudChecker {
+    rules: Vec<Box<dyn FraudRule>>,
+    review_threshold: i32,
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .map(|value| {
+            value
+                .parse()
+                .unwrap_or_else(|_| panic!("{name} is not valid"))
+        })
+        .unwrap_or(default)
+}
+
+impl FraudChecker {
+    pub fn new(rules: Vec<Box<dyn FraudRule>>, review_threshold: i32) -> Self {
+        FraudChecker {
+            rules,
+            review_threshold,
+        }
+    }
+
+    // built-in rules, thresholds configured with FRAUD_* env vars
+    pub fn from_env() -> Self {
+        FraudChecker::new(
+            vec![
+                Box::new(CountryMismatch),
+                Box::new(HighValueFirstOrder {
+                    threshold: env_or("FRAUD_HIGH_VALUE_THRESHOLD", Decimal::from(500)),
+                }),
+                Box::new(Velocity {
+                    max_per_hour: env_or("FRAUD_MAX_ORDERS_PER_HOUR", 3),
+                }),
+            ],
+            env_or("FRAUD_REVIEW_THRESHOLD", 50),
+        )
+    }
+
+    pub fn assess(&self, ctx: &FraudContext) -> FraudAssessment {
+        let mut score = 0;
+        let mut reasons = Vec::new();
+        for rule in &self.rules {
+            let points = rule.score(ctx);
+            if points > 0 {
+                score += points;
+                reasons.push(rule.name().to_string());
+            }
+        }
+
+        FraudAssessment {
+            score,
+            reasons,
+            needs_review: score >= self.review_threshold,
+        }
+    }
+}
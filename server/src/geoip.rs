@@ -0,0 +1,93 @@
+use std::{net::IpAddr, sync::Arc};
+
+use actix_web::HttpRequest;
+use maxminddb::{geoip2, Reader};
+use serde::Serialize;
+
+use crate::client_ip::client_ip;
+
+pub trait GeoIpLookup: Send + Sync {
+    // ISO 3166 alpha-2 country code of the address
+    fn country(&self, ip: IpAddr) -> Option<String>;
+}
+
+// MaxMind GeoLite2/GeoIP2 country or city database
+pub struct MaxMind {
+    reader: Reader<Vec<u8>>,
+}
+
+impl MaxMind {
+    pub fn open(path: &str) -> Self {
+        MaxMind {
+            reader: Reader::open_readfile(path).expect("failed to open GeoIP database"),
+        }
+    }
+}
+
+impl GeoIpLookup for MaxMind {
+    fn country(&self, ip: IpAddr) -> Option<String> {
+        let record: geoip2::Country = self.reader.lookup(ip).ok()?;
+        record
+            .country
+            .and_then(|country| country.iso_code)
+            .map(str::to_owned)
+    }
+}
+
+// GeoIP is optional, enabled by pointing GEOIP_DB_PATH at a database file
+pub fn from_env() -> Option<Arc<dyn GeoIpLookup>> {
+    let path = std::env::var("GEOIP_DB_PATH").ok()?;
+    Some(Arc::new(MaxMind::open(&path)))
+}
+
+const EURO_COUNTRIES: [&str; 21] = [
+    "AT", "BE", "BG", "HR", "CY", "EE", "FI", "FR", "DE", "GR", "IE", "IT", "LV", "LT", "LU", "MT",
+    "NL", "PT", "SK", "SI", "ES",
+];
+
+pub fn currency_for_country(country: &str) -> Option<&'static str> {
+    match country {
+        "US" => Some("USD"),
+        "GB" => Some("GBP"),
+        "AU" => Some("AUD"),
+        "CA" => Some("CAD"),
+        "NZ" => Some("NZD"),
+        "JP" => Some("JPY"),
+        "SG" => Some("SGD"),
+        "ID" => Some("IDR"),
+        "CH" => Some("CHF"),
+        country if EURO_COUNTRIES.contains(&country) => Some("EUR"),
+        _ => None,
+    }
+}
+
+// storefront defaults for a request
+#[derive(Serialize)]
+pub struct RequestContext {
+    pub country: String,
+    pub currency: String,
+    // false when the defaults came from configuration instead of the client address
+    pub detected: bool,
+}
+
+pub fn resolve(geoip: Option<&Arc<dyn GeoIpLookup>>, req: &HttpRequest) -> RequestContext {
+    let default_country = std::env::var("DEFAULT_COUNTRY").unwrap_or_else(|_| "US".into());
+    let default_currency = std::env::var("DEFAULT_CURRENCY").unwrap_or_else(|_| "USD".into());
+
+    let detected = geoip.and_then(|geoip| geoip.country(client_ip(req)?));
+
+    match detected {
+        Some(country) => RequestContext {
+            currency: currency_for_country(&country)
+                .map(str::to_owned)
+                .unwrap_or(default_currency),
+            country,
+            detected: true,
+        },
+        None => RequestContext {
+            country: default_country,
+            currency: default_currency,
+            detected: false,
+        },
+    }
+}
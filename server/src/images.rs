@@ -0,0 +1,49 @@
+use std::io::Cursor;
+
+use image::{imageops::FilterType, ImageError, ImageFormat};
+
+// the smaller copies made of every product image, longest side in pixels,
+// the original is served as the full size
+pub const SIZES: [(&str, u32); 2] = [("thumbnail", 200), ("medium", 800)];
+
+// formats we accept for upload, what the bytes are rather than what the client says
+pub fn upload_format(bytes: &[u8]) -> Option<ImageFormat> {
+    image::guess_format(bytes).ok().filter(|format| {
+        matches!(
+            format,
+            ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP
+        )
+    })
+}
+
+// products/x/photo.jpg becomes products/x/photo_thumbnail.jpg
+pub fn sized_key(key: &str, size: &str) -> String {
+    match key.rsplit_once('.') {
+        Some((stem, ext)) if !ext.contains('/') => format!("{stem}_{size}.{ext}"),
+        _ => format!("{key}_{size}"),
+    }
+}
+
+// a resized copy and its content type
+pub type Resized = (Vec<u8>, &'static str);
+
+// every size of an image in its own format, none for sizes the original is
+// already small enough for. CPU heavy, run it off the async threads
+pub fn resize_all(original: &[u8]) -> Result<Vec<(&'static str, Option<Resized>)>, ImageError> {
+    let format = image::guess_format(original)?;
+    let image = image::load_from_memory_with_format(original, format)?;
+
+    let mut sizes = Vec::with_capacity(SIZES.len());
+    for (size, max) in SIZES {
+        if image.width() <= max && image.height() <= max {
+            sizes.push((size, None));
+            continue;
+        }
+        let mut resized = Cursor::new(Vec::new());
+        image
+            .resize(max, max, FilterType::Lanczos3)
+            .write_to(&mut resized, format)?;
+        sizes.push((size, Some((resized.into_inner(), format.to_mime_type()))));
+    }
+    Ok(sizes)
+}
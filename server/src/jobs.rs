@@ -0,0 +1,839 @@
+use std::{
+    collections::HashSet,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use serde_json::json;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{
+    api::{
+        marketing,
+        orders::{record_status_event, release_stock, OrderStatus},
+        quotes::Quote,
+        refunds::complete_refund,
+        sales::ReportSchedule,
+    },
+    broker::Broker,
+    email::{self, Email, EmailSender},
+    images::{resize_all, sized_key},
+    outbox,
+    payments::{CashOnDelivery, Payments},
+    push::{self, Platform, Push, PushError},
+    query_stats::QueryStats,
+    rates::ExchangeRates,
+    search::{self, SearchEngine, SearchError},
+    sms::{Sms, SmsError},
+    storage::Storage,
+};
+
+// empties default carts nobody touched for CART_TTL_DAYS (default 30), checked
+// every CART_CLEANUP_INTERVAL_MINUTES (default 60), a TTL of 0 turns it off. Each
+// run is timed and the carts and items it removed are counted on /metrics
+pub fn spawn_cart_cleanup(pool: PgPool, stats: Arc<QueryStats>) {
+    let ttl_days: i32 = env_number("CART_TTL_DAYS", 30);
+    let interval_minutes: u64 = env_number("CART_CLEANUP_INTERVAL_MINUTES", 60);
+    if ttl_days == 0 {
+        return;
+    }
+
+    actix_web::rt::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(interval_minutes * 60));
+        loop {
+            interval.tick().await;
+
+            match stats
+                .time("jobs.cart_cleanup", cleanup_carts(&pool, ttl_days))
+                .await
+            {
+                Ok((carts, items)) => {
+                    stats.add_rows("cart_cleanup.carts", carts);
+                    stats.add_rows("cart_cleanup.cart_items", items);
+                }
+                Err(err) => println!("cart cleanup failed: {err:?}"),
+            }
+        }
+    });
+}
+
+// cancels orders still unpaid after their payment_expires_at, checked every
+// minute, their stock goes back, the open charges are cancelled and the
+// quotes they were placed from can be ordered again
+pub fn spawn_payment_expiry(pool: PgPool, payments: Payments) {
+    actix_web::rt::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+
+            let started = Instant::now();
+            match expire_unpaid_orders(&pool).await {
+                Ok((orders, charges)) => {
+                    // best effort, a charge paid after the cancel is refunded when it comes in
+                    for (provider, provider_ref) in charges {
+                        let Some(provider) = payments.get(&provider) else {
+                            continue;
+                        };
+                        if let Err(err) = provider.cancel_charge(&provider_ref).await {
+                            println!("cancelling charge {provider_ref} failed: {err}");
+                        }
+                    }
+                    if orders > 0 {
+                        println!(
+                            "payment expiry: cancelled {orders} unpaid orders in {:?}",
+                            started.elapsed()
+                        );
+                    }
+                }
+                Err(err) => println!("payment expiry failed: {err:?}"),
+            }
+        }
+    });
+}
+
+// sends the refunds still pending a minute after they were made to their
+// provider again, checked every minute, until the provider takes them
+pub fn spawn_refund_retry(pool: PgPool, payments: Payments) {
+    actix_web::rt::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+
+            match retry_refunds(&pool, &payments).await {
+                Ok(0) => {}
+                Ok(refunds) => println!("refund retry: finished {refunds} refunds"),
+                Err(err) => println!("refund retry failed: {err:?}"),
+            }
+        }
+    });
+}
+
+// makes the thumbnail and medium sizes of new product images, checked every
+// 10 seconds so uploads return straight away
+pub fn spawn_image_resizer(pool: PgPool, storage: Arc<dyn Storage>) {
+    actix_web::rt::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(10));
+        loop {
+            interval.tick().await;
+
+            let started = Instant::now();
+            match resize_pending_images(&pool, storage.as_ref()).await {
+                Ok(0) => {}
+                Ok(images) => println!(
+                    "image resizer: resized {images} images in {:?}",
+                    started.elapsed()
+                ),
+                Err(err) => println!("image resizer failed: {err:?}"),
+            }
+        }
+    });
+}
+
+// publishes the events in the outbox to the message broker every second, each
+// order, product or user's in the order they were recorded. An event the
+// broker doesn't take is tried again on the next tick, the later events of
+// its aggregate wait for it
+pub fn spawn_outbox_relay(pool: PgPool, broker: Arc<Broker>) {
+    actix_web::rt::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+
+            let started = Instant::now();
+            match relay_events(&pool, &broker).await {
+                Ok(0) => {}
+                Ok(events) => println!(
+                    "outbox relay: published {events} events in {:?}",
+                    started.elapsed()
+                ),
+                Err(err) => println!("outbox relay failed: {err:?}"),
+            }
+        }
+    });
+}
+
+// drops the outbox events every consumer has handled once they are
+// OUTBOX_KEEP_DAYS (default 7) old, checked every hour. The relay handles
+// them when there is a broker and the search indexer the product events
+// when there is a search engine
+pub fn spawn_outbox_pruner(pool: PgPool, publishing: bool, indexing: bool) {
+    let keep_days: i32 = env_number("OUTBOX_KEEP_DAYS", 7);
+
+    actix_web::rt::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(3600));
+        loop {
+            interval.tick().await;
+
+            match outbox::prune(&pool, keep_days, publishing, indexing).await {
+                Ok(0) => {}
+                Ok(events) => println!("outbox pruning: removed {events} handled events"),
+                Err(err) => println!("outbox pruning failed: {err:?}"),
+            }
+        }
+    });
+}
+
+// sends the queued push notifications every 5 seconds, skipping the ones the
+// customer opted out of since they were queued. A message failing on every
+// device is tried 5 times. Sent and skipped messages are kept 30 days
+pub fn spawn_push_dispatcher(pool: PgPool, push: Arc<Push>) {
+    actix_web::rt::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(5));
+        let mut last_prune = Instant::now();
+        loop {
+            interval.tick().await;
+
+            let started = Instant::now();
+            match dispatch_push_messages(&pool, &push).await {
+                Ok(0) => {}
+                Ok(messages) => println!(
+                    "push dispatcher: handled {messages} messages in {:?}",
+                    started.elapsed()
+                ),
+                Err(err) => println!("push dispatcher failed: {err:?}"),
+            }
+
+            if last_prune.elapsed() > Duration::from_secs(3600) {
+                last_prune = Instant::now();
+                if let Err(err) = sqlx::query!(
+                    "DELETE FROM push_messages
+                    WHERE status <> 'pending' AND created_at < NOW() - INTERVAL '30 days'"
+                )
+                .execute(&pool)
+                .await
+                {
+                    println!("push message pruning failed: {err:?}");
+                }
+            }
+        }
+    });
+}
+
+// sends the queued delivery texts every 5 seconds, the ones handled are
+// deleted after 30 days
+pub fn spawn_sms_dispatcher(pool: PgPool, sms: Arc<Sms>) {
+    actix_web::rt::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(5));
+        let mut last_prune = Instant::now();
+        loop {
+            interval.tick().await;
+
+            let started = Instant::now();
+            match dispatch_sms_messages(&pool, &sms).await {
+                Ok(0) => {}
+                Ok(messages) => println!(
+                    "sms dispatcher: handled {messages} messages in {:?}",
+                    started.elapsed()
+                ),
+                Err(err) => println!("sms dispatcher failed: {err:?}"),
+            }
+
+            if last_prune.elapsed() > Duration::from_secs(3600) {
+                last_prune = Instant::now();
+                if let Err(err) = sqlx::query!(
+                    "DELETE FROM sms_messages
+                    WHERE status <> 'pending' AND created_at < NOW() - INTERVAL '30 days'"
+                )
+                .execute(&pool)
+                .await
+                {
+                    println!("sms message pruning failed: {err:?}");
+                }
+            }
+        }
+    });
+}
+
+// sends the queued mail every 5 seconds, skipping addresses that bounced or
+// complained. Without a provider the queue is drained without sending, mail
+// is about something happening now. Handled mail is deleted after 30 days
+pub fn spawn_email_dispatcher(pool: PgPool, sender: Option<Arc<dyn EmailSender>>) {
+    actix_web::rt::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(5));
+        let mut last_prune = Instant::now();
+        loop {
+            interval.tick().await;
+
+            let started = Instant::now();
+            match dispatch_email_messages(&pool, sender.as_deref()).await {
+                Ok(0) => {}
+                Ok(messages) => println!(
+                    "email dispatcher: handled {messages} messages in {:?}",
+                    started.elapsed()
+                ),
+                Err(err) => println!("email dispatcher failed: {err:?}"),
+            }
+
+            if last_prune.elapsed() > Duration::from_secs(3600) {
+                last_prune = Instant::now();
+                if let Err(err) = sqlx::query!(
+                    "DELETE FROM email_messages
+                    WHERE status <> 'pending' AND created_at < NOW() - INTERVAL '30 days'"
+                )
+                .execute(&pool)
+                .await
+                {
+                    println!("email message pruning failed: {err:?}");
+                }
+            }
+        }
+    });
+}
+
+// mails the sales summaries of the schedule once each period is over,
+// checked every 5 minutes
+pub fn spawn_sales_reports(pool: PgPool, schedule: ReportSchedule) {
+    actix_web::rt::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(5 * 60));
+        loop {
+            interval.tick().await;
+
+            for (name, from, to) in schedule.due(Utc::now()) {
+                match schedule.queue(&pool, name, from, to).await {
+                    Ok(true) => println!("sales reports: queued the {name} report from {from}"),
+                    Ok(false) => {}
+                    Err(err) => println!("sales reports: {name} report failed: {err:?}"),
+                }
+            }
+        }
+    });
+}
+
+// fetches the exchange rates every EXCHANGE_RATES_INTERVAL_MINUTES, the
+// last known rates stay in use while the provider is down
+pub fn spawn_rate_refresh(pool: PgPool, rates: Arc<ExchangeRates>) {
+    actix_web::rt::spawn(async move {
+        let mut interval = tokio::time::interval(rates.interval);
+        loop {
+            interval.tick().await;
+
+            match rates.refresh(&pool).await {
+                Ok(count) => println!("exchange rates: stored {count} rates"),
+                Err(err) => {
+                    println!("exchange rates: refresh failed, keeping the last known rates: {err}")
+                }
+            }
+        }
+    });
+}
+
+fn env_number<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .map(|value| {
+            value
+                .parse()
+                .unwrap_or_else(|_| panic!("{name} must be a number"))
+        })
+        .unwrap_or(default)
+}
+
+// empty the default carts nobody touched for the TTL and delete them unless
+// something is saved in them, returns how many carts and items went. Items
+// saved for later and the carts a user made and named are kept however long
+// they sit
+pub async fn cleanup_carts(pool: &PgPool, ttl_days: i32) -> Result<(u64, u64), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let expired = sqlx::query!(
+        "SELECT cart_id FROM carts
+        WHERE is_active AND name = 'Cart'
+            AND COALESCE(updated_at, created_at) < NOW() - make_interval(days => $1)
+        FOR UPDATE SKIP LOCKED",
+        ttl_days
+    )
+    .fetch_all(&mut *tx)
+    .await?
+    .into_iter()
+    .map(|row| row.cart_id)
+    .collect::<Vec<_>>();
+
+    let items = sqlx::query!(
+        "DELETE FROM cart_items WHERE cart_id = ANY($1) AND NOT saved_for_later",
+        &expired
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+
+    let carts = sqlx::query!(
+        "DELETE FROM carts c WHERE cart_id = ANY($1)
+            AND NOT EXISTS (SELECT 1 FROM cart_items i WHERE i.cart_id = c.cart_id)",
+        &expired
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+
+    tx.commit().await?;
+
+    Ok((carts, items))
+}
+
+// send the pending refunds again with their own idempotency keys, returns how
+// many the provider took. One it still refuses waits for the next run
+pub async fn retry_refunds(pool: &PgPool, payments: &Payments) -> Result<usize, sqlx::Error> {
+    let pending = sqlx::query!(
+        "SELECT r.refund_id, p.provider FROM refunds r
+        JOIN payments p ON p.payment_id = r.payment_id
+        WHERE r.status = 'pending' AND r.created_at < NOW() - INTERVAL '1 minute'
+        ORDER BY r.created_at"
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut refunded = 0;
+    for refund in pending {
+        // cash on delivery is paid back by hand, only the bookkeeping is left
+        let provider = if refund.provider == CashOnDelivery::NAME {
+            None
+        } else {
+            let Some(provider) = payments.get(&refund.provider) else {
+                continue;
+            };
+            Some(provider.as_ref())
+        };
+        match complete_refund(pool, provider, refund.refund_id).await {
+            Ok(()) => refunded += 1,
+            Err(err) => println!("refund {} failed again: {err:?}", refund.refund_id),
+        }
+    }
+    Ok(refunded)
+}
+
+// cancel expired unpaid orders, returns how many went and their open charges
+pub async fn expire_unpaid_orders(
+    pool: &PgPool,
+) -> Result<(usize, Vec<(String, String)>), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let expired = sqlx::query!(
+        "SELECT order_id FROM orders
+        WHERE status = 'pendingpayment'::order_status AND payment_expires_at < NOW()
+        FOR UPDATE SKIP LOCKED"
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let mut charges = Vec::with_capacity(expired.len());
+    for order in &expired {
+        release_stock(&mut tx, order.order_id).await?;
+        Quote::reopen(&mut tx, order.order_id).await?;
+
+        let payment = sqlx::query!(
+            "UPDATE payments SET status = 'cancelled', updated_at = NOW()
+            WHERE order_id = $1 AND status IN ('pending', 'failed')
+            RETURNING provider, provider_ref",
+            order.order_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        record_status_event(
+            &mut *tx,
+            order.order_id,
+            "order.expired",
+            OrderStatus::Cancelled,
+            json!({}),
+        )
+        .await?;
+
+        if let Some(payment) = payment {
+            charges.push((payment.provider, payment.provider_ref));
+        }
+    }
+
+    tx.commit().await?;
+
+    Ok((expired.len(), charges))
+}
+
+// keeps the search engine's index in step with the products, from the
+// product events in the outbox, checked every second. A batch the engine
+// doesn't take is tried again on the next tick
+pub fn spawn_search_indexer(pool: PgPool, engine: Arc<dyn SearchEngine>) {
+    actix_web::rt::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+
+            match index_products(&pool, engine.as_ref()).await {
+                Ok(0) => {}
+                Ok(products) => println!(
+                    "search indexer: sent {products} products to {}",
+                    engine.name()
+                ),
+                Err(err) => println!("search indexing failed: {err}"),
+            }
+        }
+    });
+}
+
+// how long an indexer has to send the products it claimed before another
+// one may take them over
+const SEARCH_CLAIM_SECONDS: i32 = 60;
+
+// send the products a batch of events is about as they are now, returns how
+// many changed. The events are claimed first and marked indexed once the
+// engine took the products, nothing is held open while it is waited on
+pub async fn index_products(
+    pool: &PgPool,
+    engine: &dyn SearchEngine,
+) -> Result<usize, SearchError> {
+    let events = outbox::claim_unindexed(pool, 100, SEARCH_CLAIM_SECONDS).await?;
+    if events.is_empty() {
+        return Ok(0);
+    }
+    let event_ids: Vec<_> = events.iter().map(|event| event.event_id).collect();
+    let mut product_ids: Vec<_> = events.iter().map(|event| event.aggregate_id).collect();
+    product_ids.sort();
+    product_ids.dedup();
+
+    match send_products(pool, engine, &product_ids).await {
+        Ok(()) => {
+            outbox::mark_indexed(pool, &event_ids).await?;
+            Ok(product_ids.len())
+        }
+        Err(err) => {
+            outbox::release_unindexed(pool, &event_ids).await?;
+            Err(err)
+        }
+    }
+}
+
+async fn send_products(
+    pool: &PgPool,
+    engine: &dyn SearchEngine,
+    product_ids: &[Uuid],
+) -> Result<(), SearchError> {
+    let documents = search::documents(pool, product_ids).await?;
+    let deleted: Vec<_> = product_ids
+        .iter()
+        .copied()
+        .filter(|product_id| !documents.iter().any(|d| d.product_id == *product_id))
+        .collect();
+    if !documents.is_empty() {
+        engine.upsert(&documents).await?;
+    }
+    if !deleted.is_empty() {
+        engine.remove(&deleted).await?;
+    }
+    Ok(())
+}
+
+// how long a relay has to publish the events it claimed before another one
+// may take them over
+const OUTBOX_CLAIM_SECONDS: i32 = 60;
+
+// publish the pending events of a batch of aggregates, returns how many went out
+async fn relay_events(pool: &PgPool, broker: &Broker) -> Result<usize, sqlx::Error> {
+    let claimed = outbox::claim_pending(pool, 100, OUTBOX_CLAIM_SECONDS).await?;
+    let mut published = 0;
+    let mut failed = HashSet::new();
+    let mut unpublished = Vec::new();
+    for event in &claimed {
+        // an aggregate's later events wait for the one that failed, consumers
+        // see each order, product or user's events in order
+        if failed.contains(&event.aggregate_id) {
+            unpublished.push(event.event_id);
+            continue;
+        }
+        if let Err(err) = broker.publish(event).await {
+            println!(
+                "publishing {} {} failed: {err}",
+                event.event_type, event.event_id
+            );
+            failed.insert(event.aggregate_id);
+            unpublished.push(event.event_id);
+            continue;
+        }
+        outbox::mark_published(pool, event.event_id).await?;
+        published += 1;
+    }
+
+    if !unpublished.is_empty() {
+        outbox::release(pool, &unpublished).await?;
+    }
+
+    Ok(published)
+}
+
+// why marketing to a customer who never agreed to it was not sent
+const NO_MARKETING_CONSENT: &str = "no marketing consent";
+
+// how long the dispatcher has to send the messages it claimed before another
+// one may take them over
+const PUSH_CLAIM_MINUTES: i32 = 5;
+
+// send a batch of queued push messages to the customers' devices, returns
+// how many were handled. The batch is claimed first and the results recorded
+// once everything is sent, no transaction is open while FCM or APNs answer
+pub async fn dispatch_push_messages(pool: &PgPool, push: &Push) -> Result<usize, sqlx::Error> {
+    let claimed = sqlx::query!(
+        r#"UPDATE push_messages m
+        SET claimed_until = NOW() + make_interval(mins => $1)
+        FROM users u
+        WHERE u.user_id = m.user_id AND m.message_id IN (
+            SELECT message_id FROM push_messages
+            WHERE status = 'pending' AND (claimed_until IS NULL OR claimed_until < NOW())
+            ORDER BY created_at LIMIT 50
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING m.message_id, m.user_id, m.kind, m.data, m.attempts,
+            CASE m.kind
+                WHEN 'order.status' THEN u.push_order_updates
+                WHEN 'stock.back' THEN u.push_back_in_stock
+                ELSE FALSE
+            END as "wanted!",
+            u.marketing_opt_in"#,
+        PUSH_CLAIM_MINUTES
+    )
+    .fetch_all(pool)
+    .await?;
+
+    for message in &claimed {
+        let rendered = push::render(&message.kind, &message.data);
+        let (status, error) = match rendered {
+            Some(_) if marketing::is_marketing(&message.kind) && !message.marketing_opt_in => {
+                ("skipped", Some(NO_MARKETING_CONSENT.to_string()))
+            }
+            Some(rendered) if message.wanted => {
+                let devices = sqlx::query!(
+                    r#"SELECT device_id, platform as "platform: Platform", token
+                    FROM push_devices WHERE user_id = $1"#,
+                    message.user_id
+                )
+                .fetch_all(pool)
+                .await?;
+
+                let mut sent = false;
+                let mut error = None;
+                let mut unregistered = Vec::new();
+                for device in devices.iter().filter(|d| push.supports(d.platform)) {
+                    match push.send(device.platform, &device.token, &rendered).await {
+                        Ok(()) => sent = true,
+                        Err(PushError::Unregistered) => unregistered.push(device.device_id),
+                        Err(err) => error = Some(err.to_string()),
+                    }
+                }
+                if !unregistered.is_empty() {
+                    sqlx::query!(
+                        "DELETE FROM push_devices WHERE device_id = ANY($1)",
+                        &unregistered
+                    )
+                    .execute(pool)
+                    .await?;
+                }
+                match error {
+                    _ if sent => ("sent", None),
+                    Some(error) if message.attempts + 1 < 5 => ("pending", Some(error)),
+                    Some(error) => ("failed", Some(error)),
+                    // every device is gone
+                    None => ("skipped", None),
+                }
+            }
+            // opted out, or a kind this version doesn't know
+            _ => ("skipped", None),
+        };
+
+        sqlx::query!(
+            "UPDATE push_messages SET status = $2::text, attempts = attempts + 1, last_error = $3,
+                sent_at = CASE WHEN $2::text = 'sent' THEN NOW() END, claimed_until = NULL
+            WHERE message_id = $1",
+            message.message_id,
+            status,
+            error
+        )
+        .execute(pool)
+        .await?;
+    }
+
+    Ok(claimed.len())
+}
+
+// send a batch of queued mail, returns how many were handled
+async fn dispatch_email_messages(
+    pool: &PgPool,
+    sender: Option<&dyn EmailSender>,
+) -> Result<usize, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let pending = sqlx::query!(
+        r#"SELECT m.message_id, m.kind, m.data, m.attempts,
+            COALESCE(m.recipient, u.email) as "email!",
+            EXISTS (
+                SELECT 1 FROM email_suppressions s
+                WHERE s.email = lower(COALESCE(m.recipient, u.email))
+            ) as "undeliverable!",
+            COALESCE(u.marketing_opt_in, FALSE) as "marketing_opt_in!"
+        FROM email_messages m LEFT JOIN users u ON u.user_id = m.user_id
+        WHERE m.status = 'pending'
+        ORDER BY m.created_at LIMIT 50
+        FOR UPDATE OF m SKIP LOCKED"#
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    for message in &pending {
+        let (status, error) = match (sender, email::render(&message.kind, &message.data)) {
+            (None, _) => (
+                "skipped",
+                Some("no email provider is configured".to_string()),
+            ),
+            (_, None) => ("skipped", None),
+            _ if message.undeliverable => ("skipped", Some("address is undeliverable".to_string())),
+            _ if marketing::is_marketing(&message.kind) && !message.marketing_opt_in => {
+                ("skipped", Some(NO_MARKETING_CONSENT.to_string()))
+            }
+            (Some(sender), Some((subject, text))) => {
+                let mail = Email {
+                    to: message.email.clone(),
+                    subject,
+                    text,
+                };
+                match sender.send(&mail).await {
+                    Ok(()) => ("sent", None),
+                    Err(err) if err.is_transient() && message.attempts + 1 < 5 => {
+                        ("pending", Some(err.to_string()))
+                    }
+                    Err(err) => ("failed", Some(err.to_string())),
+                }
+            }
+        };
+
+        sqlx::query!(
+            "UPDATE email_messages SET status = $2::text, attempts = attempts + 1, last_error = $3,
+                sent_at = CASE WHEN $2::text = 'sent' THEN NOW() END
+            WHERE message_id = $1",
+            message.message_id,
+            status,
+            error
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(pending.len())
+}
+
+// send a batch of queued texts, returns how many were handled. News that
+// can't go out now is skipped rather than sent late
+async fn dispatch_sms_messages(pool: &PgPool, sms: &Sms) -> Result<usize, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let pending = sqlx::query!(
+        r#"SELECT m.message_id, m.kind, m.phone, m.body, m.attempts,
+            u.sms_delivery_updates as wanted, u.marketing_opt_in
+        FROM sms_messages m JOIN users u ON u.user_id = m.user_id
+        WHERE m.status = 'pending'
+        ORDER BY m.created_at LIMIT 50
+        FOR UPDATE OF m SKIP LOCKED"#
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    for message in &pending {
+        let to = sms.address(&message.phone);
+        let (status, error) = match (&to, &message.body) {
+            _ if marketing::is_marketing(&message.kind) && !message.marketing_opt_in => {
+                ("skipped", Some(NO_MARKETING_CONSENT.to_string()))
+            }
+            (Some(to), Some(body)) if message.wanted => {
+                if sms.within_limit(&mut *tx, to).await? {
+                    match sms.send(to, body).await {
+                        Ok(()) => ("sent", None),
+                        Err(err @ SmsError::Http(_)) if message.attempts + 1 < 5 => {
+                            ("pending", Some(err.to_string()))
+                        }
+                        Err(err) => ("failed", Some(err.to_string())),
+                    }
+                } else {
+                    ("skipped", Some(SmsError::RateLimited.to_string()))
+                }
+            }
+            (None, _) => ("skipped", Some(SmsError::CountryDisabled.to_string())),
+            // opted out
+            _ => ("skipped", None),
+        };
+
+        sqlx::query!(
+            "UPDATE sms_messages SET status = $2::text, attempts = attempts + 1, last_error = $3,
+                phone = COALESCE($4, phone),
+                sent_at = CASE WHEN $2::text = 'sent' THEN NOW() END
+            WHERE message_id = $1",
+            message.message_id,
+            status,
+            error,
+            to
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(pending.len())
+}
+
+// resize a batch of images nobody resized yet, returns how many were done.
+// Images that can't be read or decoded are marked with the error and skipped
+async fn resize_pending_images(pool: &PgPool, storage: &dyn Storage) -> Result<usize, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let pending = sqlx::query!(
+        "SELECT image_id, object_key FROM product_images WHERE resized_at IS NULL
+        ORDER BY created_at LIMIT 10
+        FOR UPDATE SKIP LOCKED"
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    for image in &pending {
+        let resized = match storage.get(&image.object_key).await {
+            Ok(original) => tokio::task::spawn_blocking(move || resize_all(&original))
+                .await
+                .map_err(|err| err.to_string())
+                .and_then(|sizes| sizes.map_err(|err| err.to_string())),
+            Err(err) => Err(err.to_string()),
+        };
+
+        let mut keys = [None, None];
+        let mut error = None;
+        match resized {
+            Ok(sizes) => {
+                for (key, (size, resized)) in keys.iter_mut().zip(sizes) {
+                    let Some((bytes, content_type)) = resized else {
+                        continue;
+                    };
+                    let sized = sized_key(&image.object_key, size);
+                    match storage.put(&sized, bytes, content_type).await {
+                        Ok(()) => *key = Some(sized),
+                        Err(err) => error = Some(err.to_string()),
+                    }
+                }
+            }
+            Err(err) => error = Some(err),
+        }
+
+        let [thumbnail_key, medium_key] = keys;
+        sqlx::query!(
+            "UPDATE product_images SET thumbnail_key = $1, medium_key = $2, resized_at = NOW(),
+                resize_error = $3
+            WHERE image_id = $4",
+            thumbnail_key,
+            medium_key,
+            error,
+            image.image_id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(pending.len())
+}
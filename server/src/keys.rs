@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use jwt::{
+    AlgorithmType, Header, PKeyWithDigest, SignWithKey, SigningAlgorithm, Token, VerifyWithKey,
+};
+use openssl::{
+    bn::{BigNum, BigNumContext, BigNumRef},
+    hash::MessageDigest,
+    pkey::{Id, PKey, Private, Public},
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+
+// a verification key, asymmetric keys only hold the private half for the current kid
+enum JwtKey {
+    Hmac(Hmac<Sha256>),
+    Asymmetric {
+        private: Option<PKeyWithDigest<Private>>,
+        public: PKeyWithDigest<Public>,
+    },
+}
+
+// keyed set of JWT signing keys, tokens carry the key id in their `kid` header
+pub struct JwtKeys {
+    current_kid: String,
+    keys: HashMap<String, JwtKey>,
+}
+
+fn hmac_key(secret: &str) -> JwtKey {
+    JwtKey::Hmac(Hmac::new_from_slice(secret.as_bytes()).expect("invalid jwt secret"))
+}
+
+fn read_pem(path: &str) -> Vec<u8> {
+    std::fs::read(path).unwrap_or_else(|err| panic!("failed to read key {path}: {err}"))
+}
+
+fn public_key(pem: &[u8]) -> PKeyWithDigest<Public> {
+    PKeyWithDigest {
+        digest: MessageDigest::sha256(),
+        key: PKey::public_key_from_pem(pem).expect("invalid public key"),
+    }
+}
+
+impl JwtKeys {
+    // JWT_ALGORITHM selects HS256 (default), RS256 or ES256
+    pub fn from_env() -> Self {
+        match std::env::var("JWT_ALGORITHM")
+            .unwrap_or_else(|_| "HS256".into())
+            .to_uppercase()
+            .as_str()
+        {
+            "HS256" => Self::hmac_from_env(),
+            "RS256" => Self::asymmetric_from_env(Id::RSA),
+            "ES256" => Self::asymmetric_from_env(Id::EC),
+            other => panic!("unsupported JWT_ALGORITHM: {other}"),
+        }
+    }
+
+    // JWT_KEYS="kid1:secret1,kid2:secret2" with JWT_CURRENT_KID naming the signing key,
+    // falls back to the single JWT_SECRET (+ JWT_SECRET_PREVIOUS) setup
+    fn hmac_from_env() -> Self {
+        let mut keys = HashMap::new();
+
+        let current_kid = match std::env::var("JWT_KEYS") {
+            Ok(list) => {
+                for entry in list.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+                    let (kid, secret) = entry
+                        .split_once(':')
+                        .expect("JWT_KEYS entries must be kid:secret");
+                    keys.insert(kid.to_string(), hmac_key(secret));
+                }
+                std::env::var("JWT_CURRENT_KID").expect("JWT_CURRENT_KID must be set")
+            }
+            Err(_) => {
+                let secret = std::env::var("JWT_SECRET").expect("JWT SECRET must be set");
+                keys.insert("default".to_string(), hmac_key(&secret));
+                if let Ok(previous) = std::env::var("JWT_SECRET_PREVIOUS") {
+                    for (index, secret) in previous
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|s| !s.is_empty())
+                        .enumerate()
+                    {
+                        keys.insert(format!("previous-{index}"), hmac_key(secret));
+                    }
+                }
+                "default".to_string()
+            }
+        };
+
+        assert!(
+            keys.contains_key(&current_kid),
+            "JWT_CURRENT_KID {current_kid} is not in JWT_KEYS"
+        );
+
+        JwtKeys { current_kid, keys }
+    }
+
+    // JWT_PRIVATE_KEY_PATH is the PEM signing key published as JWT_CURRENT_KID,
+    // JWT_PUBLIC_KEYS="kid1:/path/key1.pem,..." lists older keys still accepted
+    fn asymmetric_from_env(id: Id) -> Self {
+        let current_kid = std::env::var("JWT_CURRENT_KID").unwrap_or_else(|_| "default".into());
+        let path = std::env::var("JWT_PRIVATE_KEY_PATH").expect("JWT_PRIVATE_KEY_PATH must be set");
+        let private = PKey::private_key_from_pem(&read_pem(&path)).expect("invalid private key");
+        assert!(
+            private.id() == id,
+            "JWT_PRIVATE_KEY_PATH does not match JWT_ALGORITHM"
+        );
+
+        let mut keys = HashMap::new();
+        let public = public_key(&private.public_key_to_pem().expect("invalid private key"));
+        keys.insert(
+            current_kid.clone(),
+            JwtKey::Asymmetric {
+                private: Some(PKeyWithDigest {
+                    digest: MessageDigest::sha256(),
+                    key: private,
+                }),
+                public,
+            },
+        );
+
+        if let Ok(list) = std::env::var("JWT_PUBLIC_KEYS") {
+            for entry in list.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+                let (kid, path) = entry
+                    .split_once(':')
+                    .expect("JWT_PUBLIC_KEYS entries must be kid:path");
+                keys.insert(
+                    kid.to_string(),
+                    JwtKey::Asymmetric {
+                        private: None,
+                        public: public_key(&read_pem(path)),
+                    },
+                );
+            }
+        }
+
+        JwtKeys { current_kid, keys }
+    }
+
+    // sign claims with the current key
+    pub fn sign<T: Serialize>(&self, claims: T) -> Result<String, jwt::Error> {
+        match &self.keys[&self.current_kid] {
+            JwtKey::Hmac(key) => self.sign_with(key, claims),
+            JwtKey::Asymmetric {
+                private: Some(key), ..
+            } => self.sign_with(key, claims),
+            JwtKey::Asymmetric { private: None, .. } => {
+                unreachable!("current key has no private key")
+            }
+        }
+    }
+
+    fn sign_with<T: Serialize>(
+        &self,
+        key: &impl SigningAlgorithm,
+        claims: T,
+    ) -> Result<String, jwt::Error> {
+        let header = Header {
+            algorithm: key.algorithm_type(),
+            key_id: Some(self.current_kid.clone()),
+            ..Default::default()
+        };
+        let token = Token::new(header, claims).sign_with_key(key)?;
+        Ok(token.as_str().to_string())
+    }
+
+    // verify a token with the key named by its kid, tokens issued before kids
+    // existed are tried against every key; the flag tells whether the token
+    // was signed with the current key
+    pub fn verify<T: DeserializeOwned + Clone>(
+        &self,
+        token: &str,
+    ) -> Result<(T, bool), jwt::Error> {
+        let unverified: Token<Header, T, _> = Token::parse_unverified(token)?;
+
+        match unverified.header().key_id.as_deref() {
+            Some(kid) => {
+                let key = self
+                    .keys
+                    .get(kid)
+                    .ok_or(jwt::Error::NoKeyWithKeyId(kid.to_string()))?;
+                let claims = Self::verify_with(key, token)?;
+                Ok((claims, kid == self.current_kid))
+            }
+            None => {
+                for key in self.keys.values() {
+                    if let Ok(claims) = Self::verify_with(key, token) {
+                        return Ok((claims, false));
+                    }
+                }
+                Err(jwt::Error::InvalidSignature)
+            }
+        }
+    }
+
+    fn verify_with<T: DeserializeOwned + Clone>(
+        key: &JwtKey,
+        token: &str,
+    ) -> Result<T, jwt::Error> {
+        let verified: Token<Header, T, _> = match key {
+            JwtKey::Hmac(key) => token.verify_with_key(key)?,
+            JwtKey::Asymmetric { public, .. } => token.verify_with_key(public)?,
+        };
+        Ok(verified.claims().clone())
+    }
+
+    // public keys as a JSON Web Key Set, empty for shared-secret setups
+    pub fn jwks(&self) -> Value {
+        let keys: Vec<Value> = self
+            .keys
+            .iter()
+            .filter_map(|(kid, key)| match key {
+                JwtKey::Hmac(_) => None,
+                JwtKey::Asymmetric { public, .. } => jwk(kid, &public.key),
+            })
+            .collect();
+        json!({ "keys": keys })
+    }
+}
+
+fn b64(number: &BigNumRef) -> String {
+    URL_SAFE_NO_PAD.encode(number.to_vec())
+}
+
+// JWK representation of an RSA or P-256 public key
+fn jwk(kid: &str, key: &PKey<Public>) -> Option<Value> {
+    match key.id() {
+        Id::RSA => {
+            let rsa = key.rsa().ok()?;
+            Some(json!({
+                "kty": "RSA",
+                "use": "sig",
+                "alg": AlgorithmType::Rs256,
+                "kid": kid,
+                "n": b64(rsa.n()),
+                "e": b64(rsa.e()),
+            }))
+        }
+        Id::EC => {
+            let ec = key.ec_key().ok()?;
+            let mut ctx = BigNumContext::new().ok()?;
+            let (mut x, mut y) = (BigNum::new().ok()?, BigNum::new().ok()?);
+            ec.public_key()
+                .affine_coordinates(ec.group(), &mut x, &mut y, &mut ctx)
+                .ok()?;
+            Some(json!({
+                "kty": "EC",
+                "use": "sig",
+                "alg": AlgorithmType::Es256,
+                "crv": "P-256",
+                "kid": kid,
+                "x": URL_SAFE_NO_PAD.encode(x.to_vec_padded(32).ok()?),
+                "y": URL_SAFE_NO_PAD.encode(y.to_vec_padded(32).ok()?),
+            }))
+        }
+        _ => None,
+    }
+}
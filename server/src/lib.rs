@@ -0,0 +1,534 @@
+use actix_web::{middleware, web, App, HttpServer};
+use actix_web_httpauth::middleware::HttpAuthentication;
+use admin_feed::AdminFeed;
+use api::{
+    carts::{CartRepo, PgCartRepo},
+    maintenance::MaintenanceSwitch,
+    orders::{OrderRepo, PgOrderRepo},
+    policies::PolicyVersions,
+    products::{PgProductRepo, ProductListings, ProductRepo},
+    sales::ReportSchedule,
+    stores::StoreDirectory,
+    users::{PgUserRepo, UserRepo},
+};
+use captcha::CaptchaVerifier;
+use carriers::CarrierWebhooks;
+use fraud::FraudChecker;
+use geoip::GeoIpLookup;
+use keys::JwtKeys;
+use limits::OrderLimits;
+use media::MediaUrls;
+use password::PasswordPolicy;
+use payload::PayloadLimits;
+use payments::{PaymentProvider, Payments};
+use pricing::Pricing;
+use query_stats::QueryStats;
+use rate_limit::RateLimiter;
+use rates::ExchangeRates;
+use search::SearchEngine;
+use sentry::Sentry;
+use sms::Sms;
+use sqlx::{types::Decimal, PgPool};
+use std::sync::Arc;
+use storage::Storage;
+use vat::{ReverseCharge, VatValidator};
+mod admin_feed;
+pub mod api;
+mod audit;
+mod broker;
+mod cache;
+mod captcha;
+mod carriers;
+pub mod cli;
+mod client_ip;
+mod csv;
+mod email;
+mod envelope;
+mod fraud;
+mod geoip;
+mod images;
+pub mod jobs;
+mod keys;
+mod limits;
+mod media;
+pub mod money;
+pub mod outbox;
+mod password;
+mod payload;
+pub mod payments;
+mod paypal;
+mod precondition;
+mod pricing;
+mod problem;
+pub mod push;
+mod query_stats;
+mod rate_limit;
+mod rates;
+mod request_id;
+pub mod search;
+mod seed;
+mod sentry;
+mod sms;
+pub mod storage;
+mod stripe;
+mod system_events;
+mod telemetry;
+pub mod vat;
+
+// api user
+use api::{
+    activity::get_activity,
+    admin_feed::admin_order_feed,
+    batch::batch,
+    blocklist::{add_blocked_domain, get_blocked_domains, remove_blocked_domain},
+    bundles::{create_bundle, get_bundle, get_bundles, update_bundle},
+    business::{get_vat_profile, set_vat_profile},
+    carts::{
+        activate_cart, add_cart_bundle, add_cart_item, create_cart, get_cart, get_cart_suggestions,
+        get_user_carts, move_to_cart, remove_cart_bundle, rename_cart, reorder, save_for_later,
+    },
+    catalog::{get_catalog_schema, sync_catalog},
+    context::{get_checkout_defaults, get_context},
+    customer_groups::{
+        assign_customer_group, get_category_visibility, get_customer_groups, get_group_prices,
+        get_product_visibility, set_category_visibility, set_group_discount, set_group_prices,
+        set_product_visibility,
+    },
+    devices::{
+        get_devices, get_push_preferences, register_device, remove_device, set_push_preferences,
+    },
+    disputes::get_disputes,
+    downloads::{download_product, upload_product_download},
+    email::{email_webhook, get_email_suppressions, remove_email_suppression},
+    exchange_rates::get_exchange_rates,
+    invoices::{get_admin_invoice, get_invoice},
+    maintenance::{get_maintenance, health, set_maintenance},
+    marketing::set_marketing_consent,
+    metrics::get_metrics,
+    newsletter::{
+        confirm_newsletter, export_newsletter_subscribers, subscribe_newsletter,
+        unsubscribe_newsletter,
+    },
+    notifications::{get_notifications, mark_notification_read},
+    orders::{
+        audit_order_prices, bulk_update_order_status, checkout, export_orders, get_admin_order,
+        get_all_orders, get_all_user_orders, get_review_orders, preview_checkout,
+        update_order_status,
+    },
+    payments::{confirm_payment, get_cod_orders, mark_cod_collected, payment_webhook},
+    pickup_locations::{
+        create_pickup_location, delete_pickup_location, get_all_pickup_locations,
+        get_nearby_pickup_locations, get_pickup_locations, update_pickup_location,
+    },
+    policies::{accept_policy, get_policies},
+    products::{
+        add_product_image, bulk_update_products, create_product, delete_product_id,
+        duplicate_product, get_admin_products, get_product_by_id, get_products,
+        patch_product_by_id, remove_product_image, search_products, set_kit_components,
+        set_price_tiers, set_related_products, suggest_products, update_product_by_id,
+        upload_product_image,
+    },
+    questions::{create_question, get_product_questions},
+    quotes::{
+        accept_quote, decline_quote, get_all_quotes, get_quotes, request_quote, respond_to_quote,
+    },
+    refunds::refund_order,
+    reports::{create_report, get_reports, resolve_report},
+    reviews::{create_review, get_product_reviews, upload_review_image, vote_review},
+    sales::get_sales_summary,
+    sessions::{get_sessions, revoke_session},
+    shipments::{carrier_webhook, create_shipment},
+    shipping_zones::{
+        create_shipping_zone, delete_shipping_zone, get_shipping_options, get_shipping_zones,
+    },
+    sms::{
+        disable_two_factor, enable_two_factor, get_sms_settings, set_sms_settings,
+        start_two_factor, verify_login_code,
+    },
+    stock_alerts::{get_stock_demand, subscribe_to_stock, unsubscribe_from_stock},
+    stores::{create_store, get_current_store, get_stores, update_store},
+    users::{
+        auth, change_password, create_user, get_user, get_user_by_id, get_user_info, jwks,
+        patch_user, refresh_token, set_date_of_birth, validator,
+    },
+    wholesale::{
+        apply_for_wholesale, approve_wholesale_application, get_wholesale_applications,
+        get_wholesale_queue, reject_wholesale_application,
+    },
+};
+
+pub struct AppState {
+    db: PgPool,
+    captcha: Option<Arc<dyn CaptchaVerifier>>,
+    password_policy: PasswordPolicy,
+    jwt_keys: Arc<JwtKeys>,
+    geoip: Option<Arc<dyn GeoIpLookup>>,
+    fraud: Arc<FraudChecker>,
+    limits: OrderLimits,
+    pricing: Pricing,
+    carriers: CarrierWebhooks,
+    payments: Payments,
+    reverse_charge: Option<ReverseCharge>,
+    media: Arc<MediaUrls>,
+    storage: Arc<dyn Storage>,
+    sentry: Option<Arc<Sentry>>,
+    sms: Option<Arc<Sms>>,
+    email_webhook_secret: Option<String>,
+    shop_url: String,
+    exchange_rates: Option<Arc<ExchangeRates>>,
+    // product search goes to it when set, to the database when not
+    search: Option<Arc<dyn SearchEngine>>,
+    // the privacy policy a marketing consent is given under
+    marketing_policy_version: String,
+    // the terms of service and privacy policy customers have to accept
+    policies: PolicyVersions,
+    admin_feed: AdminFeed,
+    query_stats: Arc<QueryStats>,
+    product_listings: Arc<ProductListings>,
+    stores: Arc<StoreDirectory>,
+    maintenance: Arc<MaintenanceSwitch>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    payload_limits: Arc<PayloadLimits>,
+    users: Arc<dyn UserRepo>,
+    products: Arc<dyn ProductRepo>,
+    carts: Arc<dyn CartRepo>,
+    orders: Arc<dyn OrderRepo>,
+}
+
+impl AppState {
+    // everything the handlers share, configured from the environment
+    pub fn from_env(db: PgPool) -> Self {
+        let query_stats = Arc::new(QueryStats::from_env());
+        let product_listings = Arc::new(ProductListings::from_env(db.clone()));
+        let stores = Arc::new(StoreDirectory::from_env(db.clone()));
+        let maintenance = Arc::new(MaintenanceSwitch::from_env(db.clone()));
+        let media = Arc::new(MediaUrls::from_env());
+        AppState {
+            users: Arc::new(PgUserRepo::new(db.clone(), query_stats.clone())),
+            products: Arc::new(PgProductRepo::new(
+                db.clone(),
+                query_stats.clone(),
+                product_listings.clone(),
+            )),
+            carts: Arc::new(PgCartRepo::new(db.clone(), query_stats.clone())),
+            orders: Arc::new(PgOrderRepo::new(db.clone(), query_stats.clone())),
+            query_stats,
+            product_listings,
+            stores,
+            maintenance,
+            rate_limiter: RateLimiter::from_env().map(Arc::new),
+            payload_limits: Arc::new(PayloadLimits::from_env(media.max_upload_bytes)),
+            db,
+            captcha: captcha::from_env(),
+            password_policy: PasswordPolicy::from_env(),
+            jwt_keys: Arc::new(JwtKeys::from_env()),
+            geoip: geoip::from_env(),
+            fraud: Arc::new(FraudChecker::from_env()),
+            limits: OrderLimits::from_env(),
+            pricing: Pricing::from_env(),
+            carriers: CarrierWebhooks::from_env(),
+            payments: Payments::from_env(),
+            reverse_charge: ReverseCharge::from_env(),
+            media,
+            storage: storage::from_env(),
+            sentry: Sentry::from_env().map(Arc::new),
+            sms: Sms::from_env().map(Arc::new),
+            email_webhook_secret: std::env::var("EMAIL_WEBHOOK_SECRET")
+                .ok()
+                .filter(|secret| !secret.is_empty()),
+            shop_url: std::env::var("SHOP_URL").unwrap_or_else(|_| "http://localhost:8080".into()),
+            exchange_rates: ExchangeRates::from_env().map(Arc::new),
+            search: search::from_env(),
+            marketing_policy_version: std::env::var("MARKETING_POLICY_VERSION")
+                .unwrap_or_else(|_| "1".into()),
+            policies: PolicyVersions::from_env(),
+            admin_feed: AdminFeed::default(),
+        }
+    }
+
+    // take payments through a provider that isn't set up from the environment
+    // as well, under its name
+    pub fn with_payment_provider(mut self, provider: Arc<dyn PaymentProvider>) -> Self {
+        self.payments.add_provider(provider);
+        self
+    }
+
+    // look users up somewhere other than the database, MemoryUserRepo lets
+    // the user handlers run without one
+    pub fn with_user_repo(mut self, users: Arc<dyn UserRepo>) -> Self {
+        self.users = users;
+        self
+    }
+
+    // keep files somewhere other than the STORAGE_BACKEND
+    pub fn with_storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    // search through an engine other than the SEARCH_ENGINE
+    pub fn with_search_engine(mut self, engine: Arc<dyn SearchEngine>) -> Self {
+        self.search = Some(engine);
+        self
+    }
+
+    // reverse charge orders for a seller in the member state, checking VAT
+    // numbers with another validator than VIES
+    pub fn with_vat_validator(
+        mut self,
+        seller_country: &str,
+        validator: Arc<dyn VatValidator>,
+    ) -> Self {
+        self.reverse_charge = Some(ReverseCharge::new(seller_country.into(), validator));
+        self
+    }
+
+    // charge tax at another rate than TAX_RATE
+    pub fn with_tax_rate(mut self, tax_rate: Decimal) -> Self {
+        self.pricing = self.pricing.with_tax_rate(tax_rate);
+        self
+    }
+}
+
+// every route of the API, the ones behind a bearer token in the unnamed scope
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    let bearer_middleware = HttpAuthentication::bearer(validator);
+    // every request gets an id and a trace span, counts against its rate limit,
+    // has its body size checked and is matched to its store, failures answer
+    // as problem documents. On success the key set and
+    // provider callbacks answer in the format their caller expects, the rest of
+    // the API in the response envelope
+    // bodies are limited per route by the payload middleware before any
+    // extractor reads them
+    cfg.app_data(web::JsonConfig::default().limit(usize::MAX))
+        .app_data(web::PayloadConfig::default().limit(usize::MAX));
+    cfg.service(
+        web::scope("")
+            .wrap(middleware::from_fn(api::maintenance::gate))
+            .wrap(middleware::from_fn(api::stores::resolve))
+            .wrap(middleware::from_fn(payload::limit))
+            .wrap(middleware::from_fn(rate_limit::limit))
+            .wrap(middleware::from_fn(problem::wrap))
+            .wrap(middleware::from_fn(telemetry::trace))
+            .wrap(middleware::from_fn(request_id::assign))
+            .service(jwks)
+            .service(get_catalog_schema)
+            .service(get_metrics)
+            .service(health)
+            .service(admin_order_feed)
+            .service(carrier_webhook)
+            .service(payment_webhook)
+            .service(email_webhook)
+            .service(
+                web::scope("")
+                    .wrap(middleware::from_fn(envelope::wrap))
+                    .service(get_user)
+                    .service(get_user_by_id)
+                    .service(create_user)
+                    .service(auth)
+                    .service(verify_login_code)
+                    .service(subscribe_newsletter)
+                    .service(confirm_newsletter)
+                    .service(unsubscribe_newsletter)
+                    .service(get_context)
+                    .service(get_current_store)
+                    .service(get_exchange_rates)
+                    .service(
+                        web::scope("")
+                            .wrap(middleware::from_fn(api::policies::require_acceptance))
+                            .wrap(bearer_middleware)
+                            .wrap(middleware::from_fn(refresh_token))
+                            .service(get_user_info)
+                            .service(change_password)
+                            .service(set_date_of_birth)
+                            .service(patch_user)
+                            .service(get_sessions)
+                            .service(revoke_session)
+                            .service(get_notifications)
+                            .service(mark_notification_read)
+                            .service(register_device)
+                            .service(get_devices)
+                            .service(remove_device)
+                            .service(get_push_preferences)
+                            .service(set_push_preferences)
+                            .service(get_sms_settings)
+                            .service(set_sms_settings)
+                            .service(start_two_factor)
+                            .service(enable_two_factor)
+                            .service(disable_two_factor)
+                            .service(set_marketing_consent)
+                            .service(get_policies)
+                            .service(accept_policy)
+                            .service(get_vat_profile)
+                            .service(set_vat_profile)
+                            .service(get_products)
+                            .service(get_admin_products)
+                            .service(suggest_products)
+                            .service(search_products)
+                            .service(get_product_by_id)
+                            .service(create_product)
+                            .service(delete_product_id)
+                            .service(update_product_by_id)
+                            .service(patch_product_by_id)
+                            .service(bulk_update_products)
+                            .service(duplicate_product)
+                            .service(set_related_products)
+                            .service(set_price_tiers)
+                            .service(set_kit_components)
+                            .service(add_product_image)
+                            .service(upload_product_image)
+                            .service(upload_product_download)
+                            .service(download_product)
+                            .service(remove_product_image)
+                            .service(get_group_prices)
+                            .service(set_group_prices)
+                            .service(subscribe_to_stock)
+                            .service(unsubscribe_from_stock)
+                            .service(get_stock_demand)
+                            .service(get_product_visibility)
+                            .service(set_product_visibility)
+                            .service(get_category_visibility)
+                            .service(set_category_visibility)
+                            .service(get_customer_groups)
+                            .service(set_group_discount)
+                            .service(assign_customer_group)
+                            .service(apply_for_wholesale)
+                            .service(get_wholesale_applications)
+                            .service(get_wholesale_queue)
+                            .service(approve_wholesale_application)
+                            .service(reject_wholesale_application)
+                            .service(get_bundles)
+                            .service(get_bundle)
+                            .service(create_bundle)
+                            .service(update_bundle)
+                            .service(get_cart)
+                            .service(get_user_carts)
+                            .service(create_cart)
+                            .service(rename_cart)
+                            .service(activate_cart)
+                            .service(add_cart_item)
+                            .service(add_cart_bundle)
+                            .service(reorder)
+                            .service(remove_cart_bundle)
+                            .service(get_cart_suggestions)
+                            .service(save_for_later)
+                            .service(move_to_cart)
+                            .service(batch)
+                            .service(get_all_user_orders)
+                            .service(get_invoice)
+                            .service(request_quote)
+                            .service(get_quotes)
+                            .service(accept_quote)
+                            .service(decline_quote)
+                            .service(get_all_quotes)
+                            .service(respond_to_quote)
+                            .service(get_checkout_defaults)
+                            .service(checkout)
+                            .service(confirm_payment)
+                            .service(preview_checkout)
+                            .service(get_shipping_options)
+                            .service(get_shipping_zones)
+                            .service(create_shipping_zone)
+                            .service(delete_shipping_zone)
+                            .service(get_all_orders)
+                            .service(update_order_status)
+                            .service(get_review_orders)
+                            .service(export_orders)
+                            .service(audit_order_prices)
+                            .service(get_cod_orders)
+                            .service(mark_cod_collected)
+                            .service(refund_order)
+                            .service(get_disputes)
+                            .service(get_activity)
+                            .service(get_maintenance)
+                            .service(set_maintenance)
+                            .service(get_admin_order)
+                            .service(get_admin_invoice)
+                            .service(create_shipment)
+                            .service(bulk_update_order_status)
+                            .service(get_product_reviews)
+                            .service(create_review)
+                            .service(vote_review)
+                            .service(upload_review_image)
+                            .service(get_product_questions)
+                            .service(create_question)
+                            .service(create_report)
+                            .service(get_reports)
+                            .service(resolve_report)
+                            .service(get_pickup_locations)
+                            .service(get_nearby_pickup_locations)
+                            .service(get_all_pickup_locations)
+                            .service(create_pickup_location)
+                            .service(update_pickup_location)
+                            .service(delete_pickup_location)
+                            .service(get_blocked_domains)
+                            .service(add_blocked_domain)
+                            .service(remove_blocked_domain)
+                            .service(get_email_suppressions)
+                            .service(remove_email_suppression)
+                            .service(export_newsletter_subscribers)
+                            .service(get_sales_summary)
+                            .service(sync_catalog)
+                            .service(get_stores)
+                            .service(create_store)
+                            .service(update_store),
+                    ),
+            ),
+    );
+}
+
+// start the background jobs and serve the API until the server is stopped
+pub async fn serve(pool: PgPool, port: u16) -> Result<(), std::io::Error> {
+    let tracing = telemetry::init();
+    let state = web::Data::new(AppState::from_env(pool.clone()));
+
+    jobs::spawn_cart_cleanup(pool.clone(), state.query_stats.clone());
+    admin_feed::spawn_listener(pool.clone(), state.admin_feed.clone());
+    jobs::spawn_payment_expiry(pool.clone(), state.payments.clone());
+    jobs::spawn_refund_retry(pool.clone(), state.payments.clone());
+    jobs::spawn_image_resizer(pool.clone(), state.storage.clone());
+    let broker = broker::Broker::from_env();
+    jobs::spawn_outbox_pruner(pool.clone(), broker.is_some(), state.search.is_some());
+    if let Some(broker) = broker {
+        jobs::spawn_outbox_relay(pool.clone(), Arc::new(broker));
+    }
+    if let Some(engine) = state.search.clone() {
+        jobs::spawn_search_indexer(pool.clone(), engine);
+    }
+    if let Some(push) = push::Push::from_env() {
+        jobs::spawn_push_dispatcher(pool.clone(), Arc::new(push));
+    }
+    jobs::spawn_email_dispatcher(pool.clone(), email::from_env());
+    if let Some(sms) = state.sms.clone() {
+        jobs::spawn_sms_dispatcher(pool.clone(), sms);
+    }
+    if let Some(rates) = state.exchange_rates.clone() {
+        jobs::spawn_rate_refresh(pool.clone(), rates);
+    }
+    if let Some(schedule) = ReportSchedule::from_env() {
+        jobs::spawn_sales_reports(pool.clone(), schedule);
+    }
+    cache::spawn_invalidation_listener(
+        pool,
+        vec![
+            state.product_listings.clone(),
+            state.stores.clone(),
+            state.maintenance.clone(),
+        ],
+    );
+
+    println!("the server is running on port {port}");
+
+    let served = HttpServer::new(move || App::new().app_data(state.clone()).configure(routes))
+        .bind(("localhost", port))?
+        .workers(2)
+        .run()
+        .await;
+    // send the spans still waiting in the batch
+    if let Some(provider) = tracing {
+        if let Err(err) = provider.shutdown() {
+            println!("failed to flush traces: {err}");
+        }
+    }
+    served
+}
@@ -0,0 +1,235 @@
+use serde::Serialize;
+use sqlx::{types::Decimal, PgExecutor};
+use uuid::Uuid;
+
+use crate::pricing::CartLine;
+
+// checkout limits, configured with ORDER_* env vars and unlimited when unset
+#[derive(Clone)]
+pub struct OrderLimits {
+    pub max_orders_per_hour: Option<i64>,
+    pub max_value_per_day: Option<Decimal>,
+    pub min_order_value: Option<Decimal>,
+    pub max_quantity_per_product: Option<Decimal>,
+    // distinct products in a cart
+    pub max_items_per_cart: Option<i64>,
+}
+
+// a broken cart or order constraint, returned to the client as a list
+#[derive(Serialize)]
+pub struct LimitViolation {
+    pub code: &'static str,
+    pub message: String,
+    pub product_id: Option<Uuid>,
+}
+
+fn env_limit<T: std::str::FromStr>(name: &str) -> Option<T> {
+    std::env::var(name).ok().map(|value| {
+        value
+            .parse()
+            .unwrap_or_else(|_| panic!("{name} is not valid"))
+    })
+}
+
+impl OrderLimits {
+    pub fn from_env() -> Self {
+        OrderLimits {
+            max_orders_per_hour: env_limit("ORDER_LIMIT_PER_HOUR"),
+            max_value_per_day: env_limit("ORDER_VALUE_LIMIT_PER_DAY"),
+            min_order_value: env_limit("ORDER_MIN_VALUE"),
+            max_quantity_per_product: env_limit("ORDER_MAX_QUANTITY_PER_PRODUCT"),
+            max_items_per_cart: env_limit("CART_MAX_ITEMS"),
+        }
+    }
+
+    // quantity is what the cart would hold of the product after the change,
+    // items the number of distinct products it would hold
+    pub fn check_cart_item(
+        &self,
+        product_id: Uuid,
+        quantity: Decimal,
+        items: i64,
+    ) -> Vec<LimitViolation> {
+        let mut violations = Vec::new();
+
+        if quantity <= Decimal::ZERO {
+            violations.push(LimitViolation {
+                code: "invalid_quantity",
+                message: "quantity must be positive".into(),
+                product_id: Some(product_id),
+            });
+        }
+        if let Some(max) = self.max_quantity_per_product {
+            if quantity > max {
+                violations.push(LimitViolation {
+                    code: "max_quantity_per_product",
+                    message: format!("at most {max} of a product per order"),
+                    product_id: Some(product_id),
+                });
+            }
+        }
+        if let Some(max) = self.max_items_per_cart {
+            if items > max {
+                violations.push(LimitViolation {
+                    code: "max_items_per_cart",
+                    message: format!("at most {max} different products per cart"),
+                    product_id: None,
+                });
+            }
+        }
+
+        violations
+    }
+
+    // the whole cart at checkout, limits may have changed since items were added
+    pub fn check_order(&self, lines: &[CartLine], subtotal: Decimal) -> Vec<LimitViolation> {
+        // per product checks, the cart size is checked once below
+        let mut violations: Vec<LimitViolation> = lines
+            .iter()
+            .filter_map(|line| Some(self.check_cart_item(line.product_id?, line.quantity, 0)))
+            .flatten()
+            .collect();
+
+        if let Some(max) = self.max_items_per_cart {
+            if lines.len() as i64 > max {
+                violations.push(LimitViolation {
+                    code: "max_items_per_cart",
+                    message: format!("at most {max} different products per cart"),
+                    product_id: None,
+                });
+            }
+        }
+        if let Some(min) = self.min_order_value {
+            if subtotal < min {
+                violations.push(LimitViolation {
+                    code: "min_order_value",
+                    message: format!("orders must be at least {min}"),
+                    product_id: None,
+                });
+            }
+        }
+
+        violations
+    }
+}
+
+// the age restricted products the buyer can't have: age_unverified until staff
+// have verified their date of birth, age_restricted while they are too young
+pub async fn check_ages<'c>(
+    executor: impl PgExecutor<'c>,
+    user_id: Uuid,
+    product_ids: &[Uuid],
+) -> Result<Vec<LimitViolation>, sqlx::Error> {
+    let restricted = sqlx::query!(
+        r#"SELECT p.product_id, p.name, p.age_restriction as "age_restriction!",
+            u.age_verified as "age_verified?",
+            date_part('year', age(u.date_of_birth))::int as age
+        FROM products p
+        LEFT JOIN users u ON u.user_id = $2
+        WHERE p.product_id = ANY($1) AND p.age_restriction IS NOT NULL
+        ORDER BY p.name"#,
+        product_ids,
+        user_id
+    )
+    .fetch_all(executor)
+    .await?;
+
+    Ok(restricted
+        .into_iter()
+        .filter_map(|product| {
+            let min = product.age_restriction;
+            match (product.age_verified, product.age) {
+                (Some(true), Some(age)) if age >= i32::from(min) => None,
+                (Some(true), Some(_)) => Some(LimitViolation {
+                    code: "age_restricted",
+                    message: format!("{} is only sold to customers aged {min} or over", product.name),
+                    product_id: Some(product.product_id),
+                }),
+                _ => Some(LimitViolation {
+                    code: "age_unverified",
+                    message: format!(
+                        "{} is only sold to customers aged {min} or over, verify your date of birth first",
+                        product.name
+                    ),
+                    product_id: Some(product.product_id),
+                }),
+            }
+        })
+        .collect())
+}
+
+// the products of a cart or order and how much of each, for the checks below
+pub fn quantities(lines: &[CartLine]) -> Vec<(Uuid, Decimal)> {
+    lines
+        .iter()
+        .filter_map(|line| Some((line.product_id?, line.quantity)))
+        .collect()
+}
+
+// holds the buyer's other checkouts back until the transaction ends, so two
+// at once can't both count the same past orders and together go over a limit
+pub async fn lock_buyer<'c>(
+    executor: impl PgExecutor<'c>,
+    user_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "SELECT pg_advisory_xact_lock(hashtext('orders.buyer.' || $1::uuid::text))",
+        user_id
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+// the limited edition products the buyer would have more of than anyone may
+// buy, counting what they bought before. Checkout takes lock_buyer first. Lines are product ids and the
+// quantity this cart or order holds of each
+pub async fn check_purchase_limits<'c>(
+    executor: impl PgExecutor<'c>,
+    user_id: Uuid,
+    lines: &[(Uuid, Decimal)],
+) -> Result<Vec<LimitViolation>, sqlx::Error> {
+    let product_ids: Vec<Uuid> = lines.iter().map(|(product_id, _)| *product_id).collect();
+    let limited = sqlx::query!(
+        r#"SELECT p.product_id, p.name, p.purchase_limit as "purchase_limit!",
+            COALESCE((
+                SELECT SUM(d.quantity) FROM order_details d
+                JOIN orders o ON o.order_id = d.order_id
+                WHERE d.product_id = p.product_id AND o.user_id = $2
+                    AND o.status NOT IN ('cancelled', 'refunded')
+            ), 0) as "bought!"
+        FROM products p
+        WHERE p.product_id = ANY($1) AND p.purchase_limit IS NOT NULL
+        ORDER BY p.name"#,
+        &product_ids,
+        user_id
+    )
+    .fetch_all(executor)
+    .await?;
+
+    Ok(limited
+        .into_iter()
+        .filter_map(|product| {
+            let wanted: Decimal = lines
+                .iter()
+                .filter(|(product_id, _)| *product_id == product.product_id)
+                .map(|(_, quantity)| *quantity)
+                .sum();
+            let limit = product.purchase_limit.normalize();
+            let left = (product.purchase_limit - product.bought).max(Decimal::ZERO);
+            (product.bought + wanted > product.purchase_limit).then(|| LimitViolation {
+                code: "purchase_limit",
+                message: if product.bought.is_zero() {
+                    format!("{} is limited to {limit} per customer", product.name)
+                } else {
+                    format!(
+                        "{} is limited to {limit} per customer, you can buy {} more",
+                        product.name,
+                        left.normalize()
+                    )
+                },
+                product_id: Some(product.product_id),
+            })
+        })
+        .collect())
+}
@@ -1,29 +1,13 @@
-use actix_web::{
-    web::{self, service},
-    App, HttpServer,
-};
-use actix_web_httpauth::middleware::HttpAuthentication;
-use sqlx::{postgres::PgPoolOptions, PgPool};
-mod api;
-
-// api user
-use api::{
-    carts::{add_cart_item, get_cart},
-    orders::{create_order, get_all_orders, get_all_user_orders, update_order_status},
-    products::{
-        create_product, delete_product_id, get_product_by_id, get_products, update_product_by_id,
-    },
-    users::{auth, create_user, get_user, get_user_by_id, get_user_info, validator},
-};
-
-struct AppState {
-    db: PgPool,
-}
+use clap::Parser;
+use server::cli::{self, Cli, Command};
+use sqlx::postgres::PgPoolOptions;
 
 #[actix_web::main]
 async fn main() -> Result<(), std::io::Error> {
     let port = 8080;
     dotenv::dotenv().ok();
+    // after dotenv so arguments can come from .env too
+    let cli = Cli::parse();
 
     let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
 
@@ -33,40 +17,47 @@ async fn main() -> Result<(), std::io::Error> {
         .await
         .expect("failed to create pool");
 
-    sqlx::migrate!("./migrations")
-        .run(&pool)
-        .await
-        .expect("migration failed");
-
-    println!("the server is running on port {port}");
-
-    HttpServer::new(move || {
-        let bearer_middleware = HttpAuthentication::bearer(validator);
-        App::new()
-            .app_data(web::Data::new(AppState { db: pool.clone() }))
-            .service(get_user)
-            .service(get_user_by_id)
-            .service(create_user)
-            .service(auth)
-            .service(
-                web::scope("")
-                    .wrap(bearer_middleware)
-                    .service(get_user_info)
-                    .service(get_products)
-                    .service(get_product_by_id)
-                    .service(create_product)
-                    .service(delete_product_id)
-                    .service(update_product_by_id)
-                    .service(get_cart)
-                    .service(add_cart_item)
-                    .service(get_all_user_orders)
-                    .service(create_order)
-                    .service(get_all_orders)
-                    .service(update_order_status),
-            )
-    })
-    .bind(("localhost", port))?
-    .workers(2)
-    .run()
-    .await
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => {
+            cli::migrate(&pool).await;
+            cli::bootstrap_admin(&pool).await;
+            server::serve(pool, port).await
+        }
+        Command::Migrate => {
+            cli::migrate(&pool).await;
+            println!("migrations are up to date");
+            Ok(())
+        }
+        Command::CreateAdmin {
+            email,
+            first_name,
+            last_name,
+            password,
+        } => {
+            cli::migrate(&pool).await;
+            if let Err(err) =
+                cli::create_admin(&pool, &email, &first_name, &last_name, password.as_deref()).await
+            {
+                eprintln!("failed to create admin: {err}");
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Command::Seed => {
+            cli::migrate(&pool).await;
+            if let Err(err) = cli::seed(&pool).await {
+                eprintln!("seeding failed: {err}");
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Command::Reindex => {
+            cli::migrate(&pool).await;
+            if let Err(err) = cli::reindex(&pool).await {
+                eprintln!("reindexing failed: {err}");
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+    }
 }
@@ -0,0 +1,109 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{Duration, Utc};
+use openssl::{
+    hash::MessageDigest,
+    pkey::{PKey, Private},
+    sign::Signer,
+};
+
+// CloudFront key pair the URLs are signed with, the distribution holds the public half
+struct SigningKey {
+    key_pair_id: String,
+    key: PKey<Private>,
+}
+
+// turns object keys of the media bucket into URLs clients can load.
+// MEDIA_BASE_URL is the CDN in front of the bucket (defaults to /media for local
+// setups), with MEDIA_KEY_PAIR_ID and MEDIA_SIGNING_KEY_PATH set every URL is a
+// canned-policy signed URL that stops working after MEDIA_URL_TTL_SECONDS, so the
+// bucket itself can stay private. Uploads are capped at MEDIA_MAX_UPLOAD_BYTES
+pub struct MediaUrls {
+    base_url: String,
+    signing_key: Option<SigningKey>,
+    ttl: Duration,
+    pub max_upload_bytes: usize,
+}
+
+// object keys are generated by us or set by admins, keep them to characters
+// that need no escaping in a URL
+pub fn valid_object_key(key: &str) -> bool {
+    !key.is_empty()
+        && !key.starts_with('/')
+        && !key.contains("..")
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '/' | '-' | '_' | '.'))
+}
+
+impl MediaUrls {
+    pub fn from_env() -> Self {
+        let signing_key = match (
+            std::env::var("MEDIA_KEY_PAIR_ID"),
+            std::env::var("MEDIA_SIGNING_KEY_PATH"),
+        ) {
+            (Ok(key_pair_id), Ok(path)) => {
+                let pem = std::fs::read(&path)
+                    .unwrap_or_else(|err| panic!("failed to read key {path}: {err}"));
+                Some(SigningKey {
+                    key_pair_id,
+                    key: PKey::private_key_from_pem(&pem).expect("invalid media signing key"),
+                })
+            }
+            (Err(_), Err(_)) => None,
+            _ => panic!("MEDIA_KEY_PAIR_ID and MEDIA_SIGNING_KEY_PATH must be set together"),
+        };
+
+        MediaUrls {
+            base_url: std::env::var("MEDIA_BASE_URL")
+                .unwrap_or_else(|_| "/media".into())
+                .trim_end_matches('/')
+                .to_string(),
+            signing_key,
+            ttl: Duration::seconds(
+                std::env::var("MEDIA_URL_TTL_SECONDS")
+                    .ok()
+                    .map(|value| value.parse().expect("MEDIA_URL_TTL_SECONDS is not valid"))
+                    .unwrap_or(3600),
+            ),
+            max_upload_bytes: std::env::var("MEDIA_MAX_UPLOAD_BYTES")
+                .ok()
+                .map(|value| value.parse().expect("MEDIA_MAX_UPLOAD_BYTES is not valid"))
+                .unwrap_or(10 * 1024 * 1024),
+        }
+    }
+
+    // the URL of an object, signed when a key is configured
+    pub fn url(&self, object_key: &str) -> String {
+        let url = format!("{}/{}", self.base_url, object_key);
+        let Some(signing_key) = &self.signing_key else {
+            return url;
+        };
+
+        // expiry is rounded up to the minute so a page of images shares one
+        // expiry and CDN caches keep hitting
+        let expires = (Utc::now() + self.ttl).timestamp();
+        let expires = expires - expires % 60 + 60;
+        // CloudFront rebuilds the canned policy byte for byte, so no serializer
+        let policy = format!(
+            r#"{{"Statement":[{{"Resource":"{url}","Condition":{{"DateLessThan":{{"AWS:EpochTime":{expires}}}}}}}]}}"#
+        );
+
+        let mut signer = Signer::new(MessageDigest::sha1(), &signing_key.key)
+            .expect("failed to create media url signer");
+        signer
+            .update(policy.as_bytes())
+            .expect("failed to sign media url");
+        let signature = signer.sign_to_vec().expect("failed to sign media url");
+        // CloudFront's URL safe base64
+        let signature = STANDARD
+            .encode(signature)
+            .replace('+', "-")
+            .replace('=', "_")
+            .replace('/', "~");
+
+        format!(
+            "{url}?Expires={expires}&Signature={signature}&Key-Pair-Id={}",
+            signing_key.key_pair_id
+        )
+    }
+}
@@ -0,0 +1,143 @@
+use std::{
+    fmt,
+    ops::{Add, Sub},
+};
+
+use rust_decimal::RoundingStrategy;
+use serde::{Serialize, Serializer};
+use sqlx::types::Decimal;
+
+// an ISO 4217 currency code, kept lowercase like the payments table and the
+// providers' webhooks have it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    pub fn parse(code: &str) -> Option<Self> {
+        let code = code.trim().to_ascii_lowercase();
+        let bytes: [u8; 3] = code.as_bytes().try_into().ok()?;
+        bytes
+            .iter()
+            .all(u8::is_ascii_lowercase)
+            .then_some(Currency(bytes))
+    }
+
+    // the shop currency, PAYMENT_CURRENCY (default usd)
+    pub fn from_env() -> Self {
+        let code = std::env::var("PAYMENT_CURRENCY").unwrap_or_else(|_| "usd".into());
+        Currency::parse(&code)
+            .unwrap_or_else(|| panic!("PAYMENT_CURRENCY {code} is not a currency code"))
+    }
+
+    pub fn code(&self) -> &str {
+        std::str::from_utf8(&self.0).expect("currency codes are ascii")
+    }
+
+    // digits after the decimal point of the smallest unit, 2 unless ISO 4217
+    // says otherwise
+    pub fn minor_digits(&self) -> u32 {
+        match self.code() {
+            "bif" | "clp" | "djf" | "gnf" | "isk" | "jpy" | "kmf" | "krw" | "pyg" | "rwf"
+            | "ugx" | "uyi" | "vnd" | "vuv" | "xaf" | "xof" | "xpf" => 0,
+            "bhd" | "iqd" | "jod" | "kwd" | "lyd" | "omr" | "tnd" => 3,
+            _ => 2,
+        }
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.code())
+    }
+}
+
+// an amount in a currency, always a whole number of the currency's smallest
+// unit. Every calculation rounds its result half away from zero, so totals,
+// tax and what the providers are asked for agree to the cent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Money {
+    amount: Decimal,
+    currency: Currency,
+}
+
+impl Money {
+    pub fn new(amount: Decimal, currency: Currency) -> Self {
+        let digits = currency.minor_digits();
+        let mut amount =
+            amount.round_dp_with_strategy(digits, RoundingStrategy::MidpointAwayFromZero);
+        amount.rescale(digits);
+        Money { amount, currency }
+    }
+
+    pub fn zero(currency: Currency) -> Self {
+        Money::new(Decimal::ZERO, currency)
+    }
+
+    // from the count of smallest units providers send and take
+    pub fn from_minor_units(units: i64, currency: Currency) -> Self {
+        Money::new(Decimal::new(units, currency.minor_digits()), currency)
+    }
+
+    pub fn amount(&self) -> Decimal {
+        self.amount
+    }
+
+    pub fn currency(&self) -> Currency {
+        self.currency
+    }
+
+    pub fn minor_units(&self) -> i64 {
+        i64::try_from(self.amount.mantissa()).expect("amount out of range")
+    }
+
+    // a unit price times a quantity, or an amount times a rate
+    pub fn times(self, factor: Decimal) -> Self {
+        Money::new(self.amount * factor, self.currency)
+    }
+
+    fn same_currency(&self, other: &Money) {
+        assert_eq!(
+            self.currency, other.currency,
+            "can't mix {} and {} amounts",
+            self.currency, other.currency
+        );
+    }
+}
+
+impl Add for Money {
+    type Output = Money;
+
+    fn add(self, other: Money) -> Money {
+        self.same_currency(&other);
+        Money::new(self.amount + other.amount, self.currency)
+    }
+}
+
+impl Sub for Money {
+    type Output = Money;
+
+    fn sub(self, other: Money) -> Money {
+        self.same_currency(&other);
+        Money::new(self.amount - other.amount, self.currency)
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.amount)
+    }
+}
+
+// as the decimal string amounts always went out as, the currency is told
+// separately
+impl Serialize for Money {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Serialize::serialize(&self.amount, serializer)
+    }
+}
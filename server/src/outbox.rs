@@ -0,0 +1,184 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{PgExecutor, PgPool};
+use uuid::Uuid;
+
+// a domain event waiting for (or past) publishing, as it goes on the wire
+#[derive(Serialize)]
+pub struct OutboxEvent {
+    pub event_id: Uuid,
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub aggregate_id: Uuid,
+    pub occurred_at: DateTime<Utc>,
+    #[serde(rename = "data")]
+    pub payload: serde_json::Value,
+}
+
+// record an event in the transaction making the change, it is published once
+// that commits and never when it rolls back
+pub async fn record<'c>(
+    executor: impl PgExecutor<'c>,
+    event_type: &str,
+    aggregate_id: Uuid,
+    payload: serde_json::Value,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO outbox_events (event_type, aggregate_id, payload) VALUES ($1, $2, $3)",
+        event_type,
+        aggregate_id,
+        payload
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+// claims every unpublished event of the aggregates with the oldest ones, for
+// claim_seconds, and returns them in the order they happened. Aggregates with
+// an event claimed by another relay are left alone, so one aggregate's events
+// are only ever published by one relay at a time, in order
+pub async fn claim_pending(
+    pool: &PgPool,
+    aggregates: i64,
+    claim_seconds: i32,
+) -> Result<Vec<OutboxEvent>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    // relays claim one at a time, two could otherwise pick the same aggregates
+    sqlx::query!("SELECT pg_advisory_xact_lock(hashtext('outbox_events.claim'))")
+        .execute(&mut *tx)
+        .await?;
+    let mut events = sqlx::query_as!(
+        OutboxEvent,
+        "WITH ready AS (
+            SELECT aggregate_id FROM outbox_events
+            WHERE published_at IS NULL
+            GROUP BY aggregate_id
+            HAVING bool_and(claimed_until IS NULL OR claimed_until < NOW())
+            ORDER BY MIN(created_at) LIMIT $1
+        )
+        UPDATE outbox_events e
+        SET claimed_until = NOW() + make_interval(secs => $2)
+        FROM ready
+        WHERE e.aggregate_id = ready.aggregate_id AND e.published_at IS NULL
+        RETURNING e.event_id, e.event_type, e.aggregate_id, e.created_at as occurred_at,
+            e.payload",
+        aggregates,
+        claim_seconds as f64
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    events.sort_by_key(|event| (event.occurred_at, event.event_id));
+    Ok(events)
+}
+
+pub async fn mark_published(pool: &PgPool, event_id: Uuid) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE outbox_events SET published_at = NOW(), claimed_until = NULL
+        WHERE event_id = $1",
+        event_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+// gives claimed events back for the next relay run
+pub async fn release(pool: &PgPool, event_ids: &[Uuid]) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE outbox_events SET claimed_until = NULL WHERE event_id = ANY($1)",
+        event_ids
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+// events are kept a while for replays once every consumer there is has
+// handled them, then dropped. Without a broker nothing publishes them and
+// without a search engine nothing indexes product events, so neither is
+// waited for then
+pub async fn prune(
+    pool: &PgPool,
+    keep_days: i32,
+    publishing: bool,
+    indexing: bool,
+) -> Result<u64, sqlx::Error> {
+    Ok(sqlx::query!(
+        "DELETE FROM outbox_events
+        WHERE created_at < NOW() - make_interval(days => $1)
+            AND (NOT $2 OR published_at < NOW() - make_interval(days => $1))
+            AND (NOT $3 OR event_type NOT LIKE 'product.%'
+                OR indexed_at < NOW() - make_interval(days => $1))",
+        keep_days,
+        publishing,
+        indexing
+    )
+    .execute(pool)
+    .await?
+    .rows_affected())
+}
+
+// claims every unindexed event of the products with the oldest ones, for
+// claim_seconds, like claim_pending does for publishing. One product is only
+// ever sent by one indexer at a time, so an older copy can't overwrite a
+// newer one in the engine
+pub async fn claim_unindexed(
+    pool: &PgPool,
+    products: i64,
+    claim_seconds: i32,
+) -> Result<Vec<OutboxEvent>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query!("SELECT pg_advisory_xact_lock(hashtext('outbox_events.index_claim'))")
+        .execute(&mut *tx)
+        .await?;
+    let mut events = sqlx::query_as!(
+        OutboxEvent,
+        "WITH ready AS (
+            SELECT aggregate_id FROM outbox_events
+            WHERE indexed_at IS NULL AND event_type LIKE 'product.%'
+            GROUP BY aggregate_id
+            HAVING bool_and(index_claimed_until IS NULL OR index_claimed_until < NOW())
+            ORDER BY MIN(created_at) LIMIT $1
+        )
+        UPDATE outbox_events e
+        SET index_claimed_until = NOW() + make_interval(secs => $2)
+        FROM ready
+        WHERE e.aggregate_id = ready.aggregate_id AND e.indexed_at IS NULL
+            AND e.event_type LIKE 'product.%'
+        RETURNING e.event_id, e.event_type, e.aggregate_id, e.created_at as occurred_at,
+            e.payload",
+        products,
+        claim_seconds as f64
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    events.sort_by_key(|event| (event.occurred_at, event.event_id));
+    Ok(events)
+}
+
+pub async fn mark_indexed(pool: &PgPool, event_ids: &[Uuid]) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE outbox_events SET indexed_at = NOW(), index_claimed_until = NULL
+        WHERE event_id = ANY($1)",
+        event_ids
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+// gives claimed product events back for the next indexer run
+pub async fn release_unindexed(pool: &PgPool, event_ids: &[Uuid]) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE outbox_events SET index_claimed_until = NULL WHERE event_id = ANY($1)",
+        event_ids
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,92 @@
+use sha1::{Digest, Sha1};
+
+// password rules, configured with PASSWORD_* env vars
+#[derive(Clone)]
+pub struct PasswordPolicy {
+    min_length: usize,
+    require_uppercase: bool,
+    require_lowercase: bool,
+    require_digit: bool,
+    require_symbol: bool,
+    check_breached: bool,
+    client: reqwest::Client,
+}
+
+fn env_flag(name: &str, default: bool) -> bool {
+    match std::env::var(name) {
+        Ok(value) => matches!(value.to_lowercase().as_str(), "1" | "true" | "yes"),
+        Err(_) => default,
+    }
+}
+
+impl PasswordPolicy {
+    pub fn from_env() -> Self {
+        PasswordPolicy {
+            min_length: std::env::var("PASSWORD_MIN_LENGTH")
+                .ok()
+                .map(|value| value.parse().expect("PASSWORD_MIN_LENGTH must be a number"))
+                .unwrap_or(8),
+            require_uppercase: env_flag("PASSWORD_REQUIRE_UPPERCASE", false),
+            require_lowercase: env_flag("PASSWORD_REQUIRE_LOWERCASE", false),
+            require_digit: env_flag("PASSWORD_REQUIRE_DIGIT", true),
+            require_symbol: env_flag("PASSWORD_REQUIRE_SYMBOL", false),
+            check_breached: env_flag("PASSWORD_CHECK_BREACHED", false),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    // returns every rule the password breaks, empty when it is acceptable
+    pub async fn validate(&self, password: &str) -> Vec<String> {
+        let mut violations = Vec::new();
+
+        if password.chars().count() < self.min_length {
+            violations.push(format!(
+                "password must be at least {} characters",
+                self.min_length
+            ));
+        }
+        if self.require_uppercase && !password.chars().any(char::is_uppercase) {
+            violations.push("password must contain an uppercase letter".into());
+        }
+        if self.require_lowercase && !password.chars().any(char::is_lowercase) {
+            violations.push("password must contain a lowercase letter".into());
+        }
+        if self.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+            violations.push("password must contain a digit".into());
+        }
+        if self.require_symbol && password.chars().all(char::is_alphanumeric) {
+            violations.push("password must contain a symbol".into());
+        }
+
+        if self.check_breached && violations.is_empty() {
+            match self.is_breached(password).await {
+                Ok(true) => violations.push("password appears in a known data breach".into()),
+                Ok(false) => {}
+                // the breach check is best effort, don't block signups when it is down
+                Err(err) => println!("breached password check failed: {err:?}"),
+            }
+        }
+
+        violations
+    }
+
+    // k-anonymity lookup: only the first 5 hex chars of the sha1 leave the server
+    async fn is_breached(&self, password: &str) -> Result<bool, reqwest::Error> {
+        let hash = format!("{:X}", Sha1::digest(password.as_bytes()));
+        let (prefix, suffix) = hash.split_at(5);
+
+        let body = self
+            .client
+            .get(format!("https://api.pwnedpasswords.com/range/{prefix}"))
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        Ok(body.lines().any(|line| {
+            line.split_once(':')
+                .is_some_and(|(candidate, _count)| candidate == suffix)
+        }))
+    }
+}
@@ -0,0 +1,217 @@
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    http::{header, Method},
+    middleware::Next,
+    web::{self, BytesMut},
+    HttpMessage, HttpResponse,
+};
+use futures_util::StreamExt;
+
+use crate::AppState;
+
+// a limit for one route, any method when none is given
+struct Rule {
+    method: Option<Method>,
+    route: String,
+    bytes: usize,
+}
+
+// how large a request body may be per route, and how deep JSON bodies may
+// nest. Login and cart bodies are a few fields, bulk edits and provider
+// webhooks carry more and image uploads go up to MEDIA_MAX_UPLOAD_BYTES.
+// PAYLOAD_LIMITS adds to or overrides these like RATE_LIMITS does, as
+// "POST /api/users=8k,/api/admin/products/bulk=2m,*=64k" in bytes or with a k
+// or m suffix, "*" for every route without a limit of its own. JSON_MAX_DEPTH
+// (default 32) caps the nesting of JSON bodies
+pub struct PayloadLimits {
+    rules: Vec<Rule>,
+    default_limit: usize,
+    max_json_depth: usize,
+}
+
+const SMALL: usize = 8 * 1024;
+const DEFAULT: usize = 64 * 1024;
+const LARGE: usize = 1024 * 1024;
+
+impl PayloadLimits {
+    pub fn from_env(max_upload_bytes: usize) -> Self {
+        let mut limits = PayloadLimits {
+            rules: Vec::new(),
+            default_limit: DEFAULT,
+            max_json_depth: std::env::var("JSON_MAX_DEPTH")
+                .ok()
+                .map(|depth| depth.parse().expect("JSON_MAX_DEPTH is not valid"))
+                .unwrap_or(32),
+        };
+        for (method, route, bytes) in [
+            (Some(Method::POST), "/api/users", SMALL),
+            (Some(Method::POST), "/api/auth/sms", SMALL),
+            (Some(Method::PUT), "/api/users/me/password", SMALL),
+            (Some(Method::POST), "/api/cart-items", SMALL),
+            (Some(Method::POST), "/api/cart-bundles", SMALL),
+            (Some(Method::PATCH), "/api/admin/products/bulk", LARGE),
+            (
+                Some(Method::POST),
+                "/api/webhooks/payments/{provider}",
+                LARGE,
+            ),
+            (Some(Method::POST), "/api/webhooks/email/{provider}", LARGE),
+            (Some(Method::POST), "/api/webhooks/carrier/{carrier}", LARGE),
+            (
+                Some(Method::POST),
+                "/api/admin/products/{id}/images/upload",
+                max_upload_bytes,
+            ),
+            (
+                Some(Method::POST),
+                "/api/reviews/{id}/images",
+                max_upload_bytes,
+            ),
+        ] {
+            limits.set(method, route.to_string(), bytes);
+        }
+
+        let config = std::env::var("PAYLOAD_LIMITS").unwrap_or_default();
+        for entry in config.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (target, bytes) = entry.rsplit_once('=').unwrap_or_else(|| {
+                panic!("PAYLOAD_LIMITS entry {entry} must look like route=bytes")
+            });
+            let bytes = parse_size(bytes).unwrap_or_else(|| {
+                panic!("PAYLOAD_LIMITS entry {entry} must look like route=bytes")
+            });
+            match target.trim().split_once(' ') {
+                _ if target.trim() == "*" => limits.default_limit = bytes,
+                Some((method, route)) => {
+                    limits.set(
+                        Some(method.to_uppercase().parse().unwrap_or_else(|_| {
+                            panic!("PAYLOAD_LIMITS method {method} is not valid")
+                        })),
+                        route.trim().to_string(),
+                        bytes,
+                    )
+                }
+                None => limits.set(None, target.trim().to_string(), bytes),
+            }
+        }
+        limits
+    }
+
+    // a later rule for the same method and route replaces the earlier one
+    fn set(&mut self, method: Option<Method>, route: String, bytes: usize) {
+        self.rules
+            .retain(|rule| !(rule.method == method && rule.route == route));
+        self.rules.push(Rule {
+            method,
+            route,
+            bytes,
+        });
+    }
+
+    // rules for the method come before the ones for any method
+    fn limit_for(&self, method: &Method, route: Option<&str>) -> usize {
+        let matching = |rule: &&Rule| Some(rule.route.as_str()) == route;
+        self.rules
+            .iter()
+            .filter(matching)
+            .find(|rule| rule.method.as_ref() == Some(method))
+            .or_else(|| {
+                self.rules
+                    .iter()
+                    .filter(matching)
+                    .find(|rule| rule.method.is_none())
+            })
+            .map_or(self.default_limit, |rule| rule.bytes)
+    }
+}
+
+fn parse_size(size: &str) -> Option<usize> {
+    let size = size.trim().to_lowercase();
+    let (number, unit) = match size.strip_suffix('k') {
+        Some(number) => (number, 1024),
+        None => match size.strip_suffix('m') {
+            Some(number) => (number, 1024 * 1024),
+            None => (size.as_str(), 1),
+        },
+    };
+    number.trim().parse::<usize>().ok()?.checked_mul(unit)
+}
+
+// how deep arrays and objects nest, brackets inside strings don't count
+fn json_depth(body: &[u8]) -> usize {
+    let (mut depth, mut deepest) = (0usize, 0);
+    let (mut in_string, mut escaped) = (false, false);
+    for byte in body {
+        match byte {
+            _ if escaped => escaped = false,
+            b'\\' if in_string => escaped = true,
+            b'"' => in_string = !in_string,
+            b'[' | b'{' if !in_string => {
+                depth += 1;
+                deepest = deepest.max(depth);
+            }
+            b']' | b'}' if !in_string => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    deepest
+}
+
+fn too_large(limit: usize) -> HttpResponse {
+    HttpResponse::PayloadTooLarge().json(format!(
+        "Request body is larger than the {limit} bytes this route accepts"
+    ))
+}
+
+// reads the request body up to its route's limit before the handler does and
+// answers 413 past it, and 400 for JSON nested deeper than allowed. Requests
+// without a body, like websocket upgrades, pass straight through
+pub async fn limit(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let has_body = req.headers().contains_key(header::CONTENT_LENGTH)
+        || req.headers().contains_key(header::TRANSFER_ENCODING);
+    let limits = req
+        .app_data::<web::Data<AppState>>()
+        .map(|state| state.payload_limits.clone());
+    let Some(limits) = limits.filter(|_| has_body) else {
+        return next.call(req).await.map(|res| res.map_into_left_body());
+    };
+    let limit = limits.limit_for(req.method(), req.match_pattern().as_deref());
+
+    let declared = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<usize>().ok());
+    if declared.is_some_and(|length| length > limit) {
+        return Ok(req.into_response(too_large(limit)).map_into_right_body());
+    }
+
+    let mut payload = req.take_payload();
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > limit {
+            return Ok(req.into_response(too_large(limit)).map_into_right_body());
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    let is_json = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains("json"));
+    if is_json && json_depth(&body) > limits.max_json_depth {
+        let res = HttpResponse::BadRequest().json(format!(
+            "JSON body is nested deeper than {} levels",
+            limits.max_json_depth
+        ));
+        return Ok(req.into_response(res).map_into_right_body());
+    }
+
+    req.set_payload(Payload::from(body.freeze()));
+    next.call(req).await.map(|res| res.map_into_left_body())
+}
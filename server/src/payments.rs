@@ -0,0 +1,340 @@
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use actix_web::http::header::HeaderMap;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use opentelemetry::KeyValue;
+use serde::{Deserialize, Serialize};
+use sqlx::types::Decimal;
+use uuid::Uuid;
+
+use crate::{
+    money::{Currency, Money},
+    paypal::PayPal,
+    stripe::Stripe,
+    telemetry,
+};
+
+#[derive(Debug)]
+pub enum PaymentError {
+    Http(reqwest::Error),
+    InvalidSignature,
+    Malformed(String),
+}
+
+impl fmt::Display for PaymentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PaymentError::Http(err) => write!(f, "{err}"),
+            PaymentError::InvalidSignature => write!(f, "invalid webhook signature"),
+            PaymentError::Malformed(msg) => write!(f, "unexpected provider response: {msg}"),
+        }
+    }
+}
+
+impl From<reqwest::Error> for PaymentError {
+    fn from(err: reqwest::Error) -> Self {
+        PaymentError::Http(err)
+    }
+}
+
+// a charge opened for an order, the client finishes it with the client secret
+// (Stripe) or by sending the customer to the approval url (PayPal)
+pub struct Charge {
+    pub provider_ref: String,
+    pub client_secret: Option<String>,
+    pub approval_url: Option<String>,
+}
+
+// what a charge is opened with, the addresses go along as provider metadata
+pub struct ChargeRequest<'a> {
+    pub amount: Money,
+    pub order_id: Uuid,
+    pub billing_address: &'a str,
+    pub shipping_address: &'a str,
+    pub shipping_country: Option<&'a str>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChargeStatus {
+    Pending,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::Type, Clone, Copy, PartialEq)]
+#[sqlx(type_name = "dispute_status", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum DisputeStatus {
+    NeedsResponse,
+    UnderReview,
+    Won,
+    Lost,
+    Closed,
+}
+
+// a chargeback or dispute as the provider reported it, tied to our charge by
+// the charge ref or, when the provider only knows its capture, our order id
+pub struct DisputeEvent {
+    pub provider_ref: String,
+    pub charge_ref: Option<String>,
+    pub order_id: Option<Uuid>,
+    pub amount: Decimal,
+    pub currency: String,
+    pub reason: Option<String>,
+    pub status: DisputeStatus,
+    pub evidence_due_by: Option<DateTime<Utc>>,
+}
+
+// what a verified webhook is about
+pub enum WebhookEvent {
+    // the charge changed, its status is re-read with confirm_charge
+    Charge(String),
+    Dispute(DisputeEvent),
+}
+
+#[async_trait]
+pub trait PaymentProvider: Send + Sync {
+    // stored in payments.provider and used in the webhook url
+    fn name(&self) -> &'static str;
+
+    async fn create_charge(&self, request: &ChargeRequest<'_>) -> Result<Charge, PaymentError>;
+
+    // settle the charge as far as the customer allowed and report where it
+    // stands, providers that need a capture step do it here
+    async fn confirm_charge(&self, provider_ref: &str) -> Result<ChargeStatus, PaymentError>;
+
+    async fn cancel_charge(&self, provider_ref: &str) -> Result<(), PaymentError>;
+
+    // refund part or all of a settled charge, returns the provider's refund id.
+    // The same idempotency key gets the same refund back instead of a second one
+    async fn refund(
+        &self,
+        provider_ref: &str,
+        amount: Money,
+        idempotency_key: &str,
+    ) -> Result<String, PaymentError>;
+
+    // verify a webhook and return what it is about, none for events we don't act on
+    async fn parse_webhook(
+        &self,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> Result<Option<WebhookEvent>, PaymentError>;
+}
+
+// every call to a provider as a client span of the request making it, so a slow
+// checkout shows how long the provider took
+struct Traced<P>(P);
+
+impl<P: PaymentProvider> Traced<P> {
+    async fn call<T>(
+        &self,
+        operation: &str,
+        call: impl std::future::Future<Output = Result<T, PaymentError>>,
+    ) -> Result<T, PaymentError> {
+        let name = self.0.name();
+        telemetry::client_span(
+            format!("{name} {operation}"),
+            vec![
+                KeyValue::new("peer.service", name),
+                KeyValue::new("payment.operation", operation.to_string()),
+            ],
+            call,
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl<P: PaymentProvider> PaymentProvider for Traced<P> {
+    fn name(&self) -> &'static str {
+        self.0.name()
+    }
+
+    async fn create_charge(&self, request: &ChargeRequest<'_>) -> Result<Charge, PaymentError> {
+        self.call("create_charge", self.0.create_charge(request))
+            .await
+    }
+
+    async fn confirm_charge(&self, provider_ref: &str) -> Result<ChargeStatus, PaymentError> {
+        self.call("confirm_charge", self.0.confirm_charge(provider_ref))
+            .await
+    }
+
+    async fn cancel_charge(&self, provider_ref: &str) -> Result<(), PaymentError> {
+        self.call("cancel_charge", self.0.cancel_charge(provider_ref))
+            .await
+    }
+
+    async fn refund(
+        &self,
+        provider_ref: &str,
+        amount: Money,
+        idempotency_key: &str,
+    ) -> Result<String, PaymentError> {
+        self.call(
+            "refund",
+            self.0.refund(provider_ref, amount, idempotency_key),
+        )
+        .await
+    }
+
+    // verified locally, nothing to trace
+    async fn parse_webhook(
+        &self,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> Result<Option<WebhookEvent>, PaymentError> {
+        self.0.parse_webhook(headers, body).await
+    }
+}
+
+// cash on delivery, turned on with COD_ENABLED=true. COD_MAX_ORDER_VALUE caps
+// the order total and COD_COUNTRIES="NL,BE" limits where it ships, any country when unset
+#[derive(Clone)]
+pub struct CashOnDelivery {
+    max_order_value: Option<Decimal>,
+    countries: Vec<String>,
+}
+
+impl CashOnDelivery {
+    // provider name orders ask for it by, also stored in payments.provider
+    pub const NAME: &'static str = "cod";
+
+    fn from_env() -> Option<Self> {
+        let enabled = std::env::var("COD_ENABLED")
+            .map(|value| value.parse().expect("COD_ENABLED must be true or false"))
+            .unwrap_or(false);
+        if !enabled {
+            return None;
+        }
+
+        Some(CashOnDelivery {
+            max_order_value: std::env::var("COD_MAX_ORDER_VALUE")
+                .ok()
+                .map(|value| value.parse().expect("COD_MAX_ORDER_VALUE must be a number")),
+            countries: std::env::var("COD_COUNTRIES")
+                .unwrap_or_default()
+                .split(',')
+                .map(|country| country.trim().to_uppercase())
+                .filter(|country| !country.is_empty())
+                .collect(),
+        })
+    }
+
+    // why the order can't be paid on delivery, if it can't
+    pub fn check(&self, total: Decimal, country: Option<&str>) -> Result<(), String> {
+        if let Some(max) = self.max_order_value {
+            if total > max {
+                return Err(format!(
+                    "Cash on delivery is not available for orders over {max}"
+                ));
+            }
+        }
+        if !self.countries.is_empty()
+            && !country.is_some_and(|country| self.countries.iter().any(|c| c == country))
+        {
+            return Err("Cash on delivery is not available for this address".into());
+        }
+        Ok(())
+    }
+}
+
+// how an order is paid, online with a provider or on delivery
+pub enum PaymentMethod<'a> {
+    Online(&'a Arc<dyn PaymentProvider>),
+    CashOnDelivery(&'a CashOnDelivery),
+}
+
+// the configured providers, STRIPE_SECRET_KEY and PAYPAL_CLIENT_ID turn them on,
+// PAYMENT_PROVIDER picks the default for orders that don't choose one.
+// PAYMENT_CURRENCY (default usd) and PAYMENT_TIMEOUT_MINUTES (default 30) apply to all
+#[derive(Clone)]
+pub struct Payments {
+    providers: HashMap<&'static str, Arc<dyn PaymentProvider>>,
+    default_provider: Option<&'static str>,
+    cash_on_delivery: Option<CashOnDelivery>,
+    currency: Currency,
+    timeout_minutes: i32,
+}
+
+impl Payments {
+    pub fn from_env() -> Self {
+        let mut providers: HashMap<&'static str, Arc<dyn PaymentProvider>> = HashMap::new();
+        if let Some(stripe) = Stripe::from_env() {
+            providers.insert(stripe.name(), Arc::new(Traced(stripe)));
+        }
+        if let Some(paypal) = PayPal::from_env() {
+            providers.insert(paypal.name(), Arc::new(Traced(paypal)));
+        }
+
+        let default_provider = match std::env::var("PAYMENT_PROVIDER") {
+            Ok(name) => {
+                let name = name.to_lowercase();
+                let (&name, _) = providers
+                    .get_key_value(name.as_str())
+                    .unwrap_or_else(|| panic!("PAYMENT_PROVIDER {name} is not configured"));
+                Some(name)
+            }
+            // stripe first when both are set up
+            Err(_) => ["stripe", "paypal"]
+                .into_iter()
+                .find(|name| providers.contains_key(name)),
+        };
+
+        Payments {
+            providers,
+            default_provider,
+            cash_on_delivery: CashOnDelivery::from_env(),
+            currency: Currency::from_env(),
+            timeout_minutes: std::env::var("PAYMENT_TIMEOUT_MINUTES")
+                .ok()
+                .map(|value| {
+                    value
+                        .parse()
+                        .expect("PAYMENT_TIMEOUT_MINUTES must be a number")
+                })
+                .unwrap_or(30),
+        }
+    }
+
+    // a provider configured in code, the default when none was set up before
+    pub fn add_provider(&mut self, provider: Arc<dyn PaymentProvider>) {
+        let name = provider.name();
+        self.providers.insert(name, provider);
+        self.default_provider.get_or_insert(name);
+    }
+
+    pub fn is_configured(&self) -> bool {
+        !self.providers.is_empty() || self.cash_on_delivery.is_some()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<dyn PaymentProvider>> {
+        self.providers.get(name)
+    }
+
+    // the requested provider, or the default one when none was asked for
+    pub fn select(&self, name: Option<&str>) -> Option<PaymentMethod<'_>> {
+        match name.map(|name| name.trim().to_lowercase()) {
+            Some(name) if name == CashOnDelivery::NAME => self
+                .cash_on_delivery
+                .as_ref()
+                .map(PaymentMethod::CashOnDelivery),
+            Some(name) => self.get(&name).map(PaymentMethod::Online),
+            None => self
+                .default_provider
+                .and_then(|name| self.get(name))
+                .map(PaymentMethod::Online),
+        }
+    }
+
+    pub fn currency(&self) -> Currency {
+        self.currency
+    }
+
+    // how long an order waits for its payment before it is cancelled
+    pub fn timeout_minutes(&self) -> i32 {
+        self.timeout_minutes
+    }
+}
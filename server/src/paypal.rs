@@ -0,0 +1,381 @@
+use actix_web::http::header::HeaderMap;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    money::Money,
+    payments::{
+        Charge, ChargeRequest, ChargeStatus, DisputeEvent, DisputeStatus, PaymentError,
+        PaymentProvider, WebhookEvent,
+    },
+};
+
+// headers PayPal sends with each webhook, checked by its verify api
+const TRANSMISSION_HEADERS: [(&str, &str); 5] = [
+    ("auth_algo", "PAYPAL-AUTH-ALGO"),
+    ("cert_url", "PAYPAL-CERT-URL"),
+    ("transmission_id", "PAYPAL-TRANSMISSION-ID"),
+    ("transmission_sig", "PAYPAL-TRANSMISSION-SIG"),
+    ("transmission_time", "PAYPAL-TRANSMISSION-TIME"),
+];
+
+#[derive(Deserialize)]
+struct AccessToken {
+    access_token: String,
+}
+
+#[derive(Deserialize)]
+struct Link {
+    href: String,
+    rel: String,
+}
+
+#[derive(Deserialize)]
+struct PayPalOrder {
+    id: String,
+    status: String,
+    #[serde(default)]
+    links: Vec<Link>,
+    #[serde(default)]
+    purchase_units: Vec<PurchaseUnit>,
+}
+
+#[derive(Deserialize)]
+struct PurchaseUnit {
+    payments: Option<UnitPayments>,
+}
+
+#[derive(Deserialize)]
+struct UnitPayments {
+    #[serde(default)]
+    captures: Vec<Capture>,
+}
+
+#[derive(Deserialize)]
+struct Capture {
+    id: String,
+    status: String,
+}
+
+#[derive(Deserialize)]
+struct Refund {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct VerifyResponse {
+    verification_status: String,
+}
+
+#[derive(Deserialize)]
+struct Event {
+    event_type: String,
+    resource: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+struct Dispute {
+    dispute_id: String,
+    reason: Option<String>,
+    status: String,
+    dispute_amount: Amount,
+    seller_response_due_date: Option<DateTime<Utc>>,
+    #[serde(default)]
+    disputed_transactions: Vec<DisputedTransaction>,
+    dispute_outcome: Option<DisputeOutcome>,
+}
+
+// paypal sends amounts as decimal strings
+#[derive(Deserialize)]
+struct Amount {
+    currency_code: String,
+    value: String,
+}
+
+// custom is the custom_id the order was created with, our order id
+#[derive(Deserialize)]
+struct DisputedTransaction {
+    custom: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct DisputeOutcome {
+    outcome_code: String,
+}
+
+impl Dispute {
+    fn into_event(self) -> Result<DisputeEvent, PaymentError> {
+        let status = match (self.status.as_str(), &self.dispute_outcome) {
+            ("RESOLVED", Some(outcome)) if outcome.outcome_code == "RESOLVED_SELLER_FAVOUR" => {
+                DisputeStatus::Won
+            }
+            ("RESOLVED", Some(outcome)) if outcome.outcome_code == "RESOLVED_BUYER_FAVOUR" => {
+                DisputeStatus::Lost
+            }
+            ("RESOLVED", _) => DisputeStatus::Closed,
+            ("WAITING_FOR_SELLER_RESPONSE", _) => DisputeStatus::NeedsResponse,
+            _ => DisputeStatus::UnderReview,
+        };
+        Ok(DisputeEvent {
+            provider_ref: self.dispute_id,
+            charge_ref: None,
+            order_id: self
+                .disputed_transactions
+                .iter()
+                .find_map(|transaction| transaction.custom.as_deref()?.parse().ok()),
+            amount: self
+                .dispute_amount
+                .value
+                .parse()
+                .map_err(|_| PaymentError::Malformed("dispute amount".into()))?,
+            currency: self.dispute_amount.currency_code.to_lowercase(),
+            reason: self.reason,
+            status,
+            evidence_due_by: self.seller_response_due_date,
+        })
+    }
+}
+
+impl PayPalOrder {
+    fn capture(&self) -> Option<&Capture> {
+        self.purchase_units
+            .iter()
+            .filter_map(|unit| unit.payments.as_ref())
+            .flat_map(|payments| payments.captures.iter())
+            .next()
+    }
+}
+
+// PayPal orders api, configured with PAYPAL_CLIENT_ID, PAYPAL_CLIENT_SECRET and
+// PAYPAL_WEBHOOK_ID, PAYPAL_API_URL points it at the sandbox
+pub struct PayPal {
+    client: reqwest::Client,
+    api_url: String,
+    client_id: String,
+    client_secret: String,
+    webhook_id: String,
+}
+
+impl PayPal {
+    pub fn from_env() -> Option<Self> {
+        let client_id = std::env::var("PAYPAL_CLIENT_ID").ok()?;
+        Some(PayPal {
+            client: reqwest::Client::new(),
+            api_url: std::env::var("PAYPAL_API_URL")
+                .unwrap_or_else(|_| "https://api-m.paypal.com".into()),
+            client_id,
+            client_secret: std::env::var("PAYPAL_CLIENT_SECRET")
+                .expect("PAYPAL_CLIENT_SECRET must be set"),
+            webhook_id: std::env::var("PAYPAL_WEBHOOK_ID").expect("PAYPAL_WEBHOOK_ID must be set"),
+        })
+    }
+
+    // every call gets a fresh token, payments are rare enough not to cache it
+    async fn access_token(&self) -> Result<String, reqwest::Error> {
+        let token: AccessToken = self
+            .client
+            .post(format!("{}/v1/oauth2/token", self.api_url))
+            .basic_auth(&self.client_id, Some(&self.client_secret))
+            .form(&[("grant_type", "client_credentials")])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(token.access_token)
+    }
+
+    async fn get_order(&self, token: &str, order_id: &str) -> Result<PayPalOrder, reqwest::Error> {
+        self.client
+            .get(format!("{}/v2/checkout/orders/{order_id}", self.api_url))
+            .bearer_auth(token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    }
+}
+
+#[async_trait]
+impl PaymentProvider for PayPal {
+    fn name(&self) -> &'static str {
+        "paypal"
+    }
+
+    async fn create_charge(&self, request: &ChargeRequest<'_>) -> Result<Charge, PaymentError> {
+        let mut unit = json!({
+            "custom_id": request.order_id,
+            "amount": {
+                "currency_code": request.amount.currency().code().to_uppercase(),
+                "value": request.amount.to_string(),
+            },
+        });
+        // paypal takes the billing address from the payer's account, only the
+        // shipping address goes along and it needs a country
+        if let Some(country) = request.shipping_country {
+            unit["shipping"] = json!({
+                "address": {
+                    "address_line_1": request.shipping_address,
+                    "country_code": country,
+                },
+            });
+        }
+
+        let token = self.access_token().await?;
+        let order: PayPalOrder = self
+            .client
+            .post(format!("{}/v2/checkout/orders", self.api_url))
+            .bearer_auth(&token)
+            // the order id keeps a retried request from opening a second paypal order
+            .header("PayPal-Request-Id", request.order_id.to_string())
+            .json(&json!({
+                "intent": "CAPTURE",
+                "purchase_units": [unit],
+            }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let approval_url = order
+            .links
+            .into_iter()
+            .find(|link| link.rel == "approve" || link.rel == "payer-action")
+            .map(|link| link.href);
+        Ok(Charge {
+            provider_ref: order.id,
+            client_secret: None,
+            approval_url,
+        })
+    }
+
+    // an approved order still has to be captured before the money moves
+    async fn confirm_charge(&self, provider_ref: &str) -> Result<ChargeStatus, PaymentError> {
+        let token = self.access_token().await?;
+        let mut order = self.get_order(&token, provider_ref).await?;
+        if order.status == "APPROVED" {
+            order = self
+                .client
+                .post(format!(
+                    "{}/v2/checkout/orders/{provider_ref}/capture",
+                    self.api_url
+                ))
+                .bearer_auth(&token)
+                .header("PayPal-Request-Id", format!("capture-{provider_ref}"))
+                .header("Content-Type", "application/json")
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+        }
+
+        Ok(match (order.status.as_str(), order.capture()) {
+            ("COMPLETED", Some(capture)) if capture.status == "COMPLETED" => {
+                ChargeStatus::Succeeded
+            }
+            ("COMPLETED", Some(capture)) if capture.status == "PENDING" => ChargeStatus::Pending,
+            ("COMPLETED", _) | ("VOIDED", _) => ChargeStatus::Failed,
+            _ => ChargeStatus::Pending,
+        })
+    }
+
+    // unapproved paypal orders can't be voided, they lapse on paypal's side
+    async fn cancel_charge(&self, _provider_ref: &str) -> Result<(), PaymentError> {
+        Ok(())
+    }
+
+    async fn refund(
+        &self,
+        provider_ref: &str,
+        amount: Money,
+        idempotency_key: &str,
+    ) -> Result<String, PaymentError> {
+        let token = self.access_token().await?;
+        let order = self.get_order(&token, provider_ref).await?;
+        let capture = order
+            .capture()
+            .ok_or_else(|| PaymentError::Malformed("order has no capture to refund".into()))?;
+
+        let refund: Refund = self
+            .client
+            .post(format!(
+                "{}/v2/payments/captures/{}/refund",
+                self.api_url, capture.id
+            ))
+            .bearer_auth(&token)
+            .header("PayPal-Request-Id", idempotency_key)
+            .json(&json!({
+                "amount": {
+                    "currency_code": amount.currency().code().to_uppercase(),
+                    "value": amount.to_string(),
+                },
+            }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(refund.id)
+    }
+
+    // paypal signs with certificates, so the event is sent back to its verify api
+    async fn parse_webhook(
+        &self,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> Result<Option<WebhookEvent>, PaymentError> {
+        let event: serde_json::Value =
+            serde_json::from_slice(body).map_err(|err| PaymentError::Malformed(err.to_string()))?;
+
+        let mut request = json!({
+            "webhook_id": self.webhook_id,
+            "webhook_event": event,
+        });
+        for (field, header) in TRANSMISSION_HEADERS {
+            let value = headers
+                .get(header)
+                .and_then(|value| value.to_str().ok())
+                .ok_or(PaymentError::InvalidSignature)?;
+            request[field] = json!(value);
+        }
+
+        let token = self.access_token().await?;
+        let verified: VerifyResponse = self
+            .client
+            .post(format!(
+                "{}/v1/notifications/verify-webhook-signature",
+                self.api_url
+            ))
+            .bearer_auth(&token)
+            .json(&request)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        if verified.verification_status != "SUCCESS" {
+            return Err(PaymentError::InvalidSignature);
+        }
+
+        let event: Event = serde_json::from_value(request["webhook_event"].take())
+            .map_err(|err| PaymentError::Malformed(err.to_string()))?;
+        let order_id = match event.event_type.as_str() {
+            "CHECKOUT.ORDER.APPROVED" => event.resource["id"].as_str(),
+            "PAYMENT.CAPTURE.COMPLETED" | "PAYMENT.CAPTURE.DENIED" => {
+                event.resource["supplementary_data"]["related_ids"]["order_id"].as_str()
+            }
+            kind if kind.starts_with("CUSTOMER.DISPUTE.") => {
+                let dispute: Dispute = serde_json::from_value(event.resource)
+                    .map_err(|err| PaymentError::Malformed(err.to_string()))?;
+                return Ok(Some(WebhookEvent::Dispute(dispute.into_event()?)));
+            }
+            _ => None,
+        };
+        Ok(order_id.map(|order_id| WebhookEvent::Charge(order_id.to_owned())))
+    }
+}
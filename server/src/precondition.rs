@@ -0,0 +1,74 @@
+use actix_web::{
+    http::header::{self, EntityTag, Header, HttpDate},
+    HttpRequest, HttpResponseBuilder,
+};
+use chrono::{DateTime, Utc};
+use std::time::SystemTime;
+
+// what a write fails with when the record changed since the client read it,
+// handlers answer it with 412
+pub const FAILED: &str = "The record was changed since it was read";
+
+// the If-Match or If-Unmodified-Since a write was sent with, so admin tools
+// and scripts don't overwrite a change they haven't seen. Versions are the
+// record's updated_at: ETags carry it to the microsecond, Last-Modified to
+// the second. If-Match wins when both are sent, as RFC 9110 has it
+pub enum Precondition {
+    None,
+    // * matches whatever version there is
+    Matches(Option<Vec<EntityTag>>),
+    UnmodifiedSince(DateTime<Utc>),
+}
+
+impl Precondition {
+    // headers that don't parse are ignored, like a missing one
+    pub fn read(req: &HttpRequest) -> Self {
+        match header::IfMatch::parse(req) {
+            Ok(header::IfMatch::Any) => return Precondition::Matches(None),
+            // a missing If-Match parses as an empty list
+            Ok(header::IfMatch::Items(tags)) if !tags.is_empty() => {
+                return Precondition::Matches(Some(tags))
+            }
+            _ => {}
+        }
+        match header::IfUnmodifiedSince::parse(req) {
+            Ok(header::IfUnmodifiedSince(date)) => {
+                Precondition::UnmodifiedSince(SystemTime::from(date).into())
+            }
+            Err(_) => Precondition::None,
+        }
+    }
+
+    // whether a write may go ahead on the version the record has now, which
+    // the caller holds locked until it has written
+    pub fn check(&self, updated_at: DateTime<Utc>) -> Result<(), sqlx::Error> {
+        let holds = match self {
+            Precondition::None | Precondition::Matches(None) => true,
+            Precondition::Matches(Some(tags)) => {
+                let current = etag(updated_at);
+                tags.iter().any(|tag| tag.strong_eq(&current))
+            }
+            Precondition::UnmodifiedSince(since) => updated_at.timestamp() <= since.timestamp(),
+        };
+        if !holds {
+            return Err(sqlx::Error::Protocol(FAILED.into()));
+        }
+        Ok(())
+    }
+}
+
+pub fn etag(updated_at: DateTime<Utc>) -> EntityTag {
+    EntityTag::new_strong(updated_at.timestamp_micros().to_string())
+}
+
+// ETag and Last-Modified for a response showing this version of a record
+pub fn versioned(
+    builder: &mut HttpResponseBuilder,
+    updated_at: DateTime<Utc>,
+) -> &mut HttpResponseBuilder {
+    builder
+        .insert_header(header::ETag(etag(updated_at)))
+        .insert_header(header::LastModified(HttpDate::from(SystemTime::from(
+            updated_at,
+        ))))
+}
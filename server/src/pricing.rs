@@ -0,0 +1,179 @@
+use serde::{Deserialize, Serialize};
+use sqlx::types::Decimal;
+use uuid::Uuid;
+
+use crate::money::{Currency, Money};
+
+// cart line as priced at checkout
+pub struct CartLine {
+    pub product_id: Option<Uuid>,
+    pub quantity: Decimal,
+    pub price: Decimal,
+    // set on the component lines of a bundle
+    pub bundle_id: Option<Uuid>,
+}
+
+// what the cart weighs and takes up once packed
+#[derive(Serialize, Default)]
+pub struct Parcel {
+    pub weight_kg: Decimal,
+    pub volume_cm3: Decimal,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::Type, Clone, Copy, Default, PartialEq)]
+#[sqlx(type_name = "shipping_method", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ShippingMethod {
+    #[default]
+    Standard,
+    Express,
+    // click-and-collect, nothing to ship
+    Pickup,
+}
+
+// everything the customer pays, total is what gets stored on the order
+#[derive(Serialize)]
+pub struct OrderTotals {
+    pub subtotal: Money,
+    pub discount: Money,
+    pub tax: Money,
+    pub shipping: Money,
+    pub gift_wrap: Money,
+    pub total: Money,
+    // the rate tax was charged at, kept on the order lines
+    #[serde(skip)]
+    pub tax_rate: Decimal,
+}
+
+impl OrderTotals {
+    // reverse charged orders pay no VAT, the buyer accounts for it
+    pub fn reverse_charged(self) -> Self {
+        OrderTotals {
+            total: self.total - self.tax,
+            tax: Money::zero(self.tax.currency()),
+            tax_rate: Decimal::ZERO,
+            ..self
+        }
+    }
+}
+
+// checkout pricing in PAYMENT_CURRENCY, configured with TAX_RATE, SHIPPING_*,
+// FREE_SHIPPING_THRESHOLD and GIFT_WRAP_FEE env vars,
+// the SHIPPING_* rates apply everywhere until shipping zones are set up,
+// shared by the checkout preview and create_order so both always agree
+#[derive(Clone)]
+pub struct Pricing {
+    currency: Currency,
+    tax_rate: Decimal,
+    standard_shipping: Decimal,
+    express_shipping: Decimal,
+    gift_wrap_fee: Decimal,
+    // orders at or above this subtotal ship for free, off when unset
+    free_shipping_threshold: Option<Decimal>,
+    // cm3 per billable kg for bulky parcels, 5000 like most carriers
+    volumetric_divisor: Decimal,
+}
+
+fn env_decimal(name: &str) -> Decimal {
+    std::env::var(name)
+        .ok()
+        .map(|value| {
+            value
+                .parse()
+                .unwrap_or_else(|_| panic!("{name} is not valid"))
+        })
+        .unwrap_or_default()
+}
+
+impl Pricing {
+    pub fn from_env() -> Self {
+        Pricing {
+            currency: Currency::from_env(),
+            tax_rate: env_decimal("TAX_RATE"),
+            standard_shipping: env_decimal("SHIPPING_STANDARD_RATE"),
+            express_shipping: env_decimal("SHIPPING_EXPRESS_RATE"),
+            gift_wrap_fee: env_decimal("GIFT_WRAP_FEE"),
+            free_shipping_threshold: std::env::var("FREE_SHIPPING_THRESHOLD")
+                .is_ok()
+                .then(|| env_decimal("FREE_SHIPPING_THRESHOLD")),
+            volumetric_divisor: if std::env::var("SHIPPING_VOLUMETRIC_DIVISOR").is_ok() {
+                env_decimal("SHIPPING_VOLUMETRIC_DIVISOR")
+            } else {
+                Decimal::from(5000)
+            },
+        }
+    }
+
+    pub fn with_tax_rate(self, tax_rate: Decimal) -> Self {
+        Pricing { tax_rate, ..self }
+    }
+
+    // the weight carriers charge for, the actual weight or the volumetric
+    // weight when the parcel is light for its size
+    pub fn billable_weight(&self, parcel: &Parcel) -> Decimal {
+        if self.volumetric_divisor <= Decimal::ZERO {
+            return parcel.weight_kg;
+        }
+        parcel
+            .weight_kg
+            .max(parcel.volume_cm3 / self.volumetric_divisor)
+            .round_dp(3)
+    }
+
+    // rate used when no shipping zones are configured
+    pub fn flat_shipping(&self, method: ShippingMethod) -> Decimal {
+        match method {
+            ShippingMethod::Standard => self.standard_shipping,
+            ShippingMethod::Express => self.express_shipping,
+            ShippingMethod::Pickup => Decimal::ZERO,
+        }
+    }
+
+    // what is left to spend for free shipping, none when there is no threshold
+    pub fn amount_to_free_shipping(&self, subtotal: Money) -> Option<Money> {
+        self.free_shipping_threshold.map(|threshold| {
+            Money::new(
+                (threshold - subtotal.amount()).max(Decimal::ZERO),
+                self.currency,
+            )
+        })
+    }
+
+    // what a line costs, rounded on its own as the invoice shows it
+    pub fn line_total(&self, price: Decimal, quantity: Decimal) -> Money {
+        Money::new(price, self.currency).times(quantity)
+    }
+
+    pub fn subtotal(&self, lines: impl IntoIterator<Item = (Decimal, Decimal)>) -> Money {
+        lines
+            .into_iter()
+            .fold(Money::zero(self.currency), |subtotal, (price, quantity)| {
+                subtotal + self.line_total(price, quantity)
+            })
+    }
+
+    // shipping is the rate for the chosen method and address,
+    // gift_wraps counts the wrapped order and wrapped lines, each pays the fee
+    pub fn totals(&self, lines: &[CartLine], shipping: Decimal, gift_wraps: usize) -> OrderTotals {
+        let money = |amount| Money::new(amount, self.currency);
+        let subtotal = self.subtotal(lines.iter().map(|line| (line.price, line.quantity)));
+        // no promotions yet, kept in the breakdown so clients don't have to change later
+        let discount = money(Decimal::ZERO);
+        let tax = (subtotal - discount).times(self.tax_rate);
+        let shipping = match self.amount_to_free_shipping(subtotal - discount) {
+            Some(left) if left.amount().is_zero() => money(Decimal::ZERO),
+            _ => money(shipping),
+        };
+        let gift_wrap = money(self.gift_wrap_fee).times(Decimal::from(gift_wraps));
+
+        OrderTotals {
+            subtotal,
+            discount,
+            tax,
+            shipping,
+            gift_wrap,
+            total: subtotal - discount + tax + shipping + gift_wrap,
+            tax_rate: self.tax_rate,
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use std::panic::AssertUnwindSafe;
+
+use actix_web::{
+    body::{self, BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    error::{ErrorInternalServerError, InternalError},
+    http::{
+        header::{self, HeaderMap, HeaderValue},
+        StatusCode,
+    },
+    middleware::Next,
+    web, HttpMessage, HttpRequest, HttpResponse,
+};
+use futures_util::FutureExt;
+use serde::Serialize;
+
+use crate::{
+    api::users::TokenClaims,
+    request_id::{self, RequestId},
+    sentry::{ErrorReport, Sentry},
+    AppState,
+};
+
+// every failed request answers with an RFC 7807 problem document. Handlers keep
+// answering with a message or a list of violations, `wrap` turns that into the
+// detail or the errors member. Errors raised before a handler runs (bad JSON,
+// missing token, unknown route) are converted the same way. Server errors and
+// panics are reported with their request and answer with a generic detail
+#[derive(Serialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    title: &'static str,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    instance: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    // structured details handlers return, like the violated password rules
+    #[serde(skip_serializing_if = "Option::is_none")]
+    errors: Option<serde_json::Value>,
+}
+
+const CONTENT_TYPE: &str = "application/problem+json";
+
+// put in the extensions of a 5xx answered on purpose, like a 503 during
+// maintenance. Nothing went wrong, its body is for the client and isn't reported
+pub struct Expected;
+
+// the detail of every 5xx, the actual error is reported with the request id
+const SERVER_ERROR_DETAIL: &str =
+    "Something went wrong on our side, quote the request id when contacting support";
+
+impl Problem {
+    // the handler's body as detail when it is text, as errors when it is structured
+    fn new(status: StatusCode, instance: String, request_id: Option<String>, body: &[u8]) -> Self {
+        let (detail, errors) = match serde_json::from_slice::<serde_json::Value>(body) {
+            Ok(serde_json::Value::String(detail)) => (Some(detail), None),
+            Ok(serde_json::Value::Null) => (None, None),
+            Ok(errors) => (None, Some(errors)),
+            Err(_) if body.is_empty() => (None, None),
+            Err(_) => (Some(String::from_utf8_lossy(body).into_owned()), None),
+        };
+        Problem {
+            kind: "about:blank",
+            title: status.canonical_reason().unwrap_or("Error"),
+            status: status.as_u16(),
+            detail,
+            instance,
+            request_id,
+            errors,
+        }
+    }
+}
+
+pub async fn wrap(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let instance = req.path().to_string();
+    let method = req.method().to_string();
+    let request_id = req
+        .extensions()
+        .get::<RequestId>()
+        .map(|RequestId(id)| id.clone());
+    let sentry = req
+        .app_data::<web::Data<AppState>>()
+        .and_then(|state| state.sentry.clone());
+    let report = |status: StatusCode, message: String, request: Option<&HttpRequest>| {
+        report(
+            sentry.as_deref(),
+            ErrorReport {
+                message,
+                status: status.as_u16(),
+                method: method.clone(),
+                path: instance.clone(),
+                route: request.and_then(HttpRequest::match_pattern),
+                user_id: request.and_then(|request| {
+                    request.extensions().get::<TokenClaims>().map(|c| c.user_id)
+                }),
+                request_id: request_id.clone(),
+            },
+        )
+    };
+
+    let res = match AssertUnwindSafe(next.call(req)).catch_unwind().await {
+        Ok(res) => res,
+        Err(panic) => {
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "handler panicked".into());
+            Err(ErrorInternalServerError(format!("panic: {message}")))
+        }
+    };
+    let res = match res {
+        Ok(res) => res,
+        // errors that no handler or middleware turned into a response yet, and
+        // panics. The request id middleware never sees these responses, the
+        // header is set here
+        Err(err) => {
+            let status = err.as_response_error().status_code();
+            let detail = if status.is_server_error() {
+                report(status, err.to_string(), None);
+                SERVER_ERROR_DETAIL.to_string()
+            } else {
+                err.to_string()
+            };
+            let mut headers = HeaderMap::new();
+            if let Some(id) = request_id
+                .as_deref()
+                .and_then(|id| HeaderValue::from_str(id).ok())
+            {
+                headers.insert(request_id::HEADER, id);
+            }
+            let problem = Problem::new(
+                status,
+                instance.clone(),
+                request_id.clone(),
+                detail.as_bytes(),
+            );
+            let response = problem_response(status, problem, &headers);
+            return Err(InternalError::from_response(err, response).into());
+        }
+    };
+    let is_problem = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|value| value.as_bytes() == CONTENT_TYPE.as_bytes());
+    if res.status().as_u16() < 400 || is_problem {
+        return Ok(res.map_into_boxed_body());
+    }
+
+    let (request, res) = res.into_parts();
+    let status = res.status();
+    let headers = res.headers().clone();
+    let expected = res.extensions().contains::<Expected>();
+    let mut bytes = body::to_bytes(res.into_body()).await.unwrap_or_default();
+    // what went wrong inside (often a database error) is for us, not the client
+    if status.is_server_error() && !expected {
+        report(
+            status,
+            String::from_utf8_lossy(&bytes).into_owned(),
+            Some(&request),
+        );
+        bytes = SERVER_ERROR_DETAIL.into();
+    }
+
+    let problem = Problem::new(status, instance.clone(), request_id.clone(), &bytes);
+    Ok(ServiceResponse::new(
+        request,
+        problem_response(status, problem, &headers),
+    ))
+}
+
+// to Sentry when it is configured, the log otherwise
+fn report(sentry: Option<&Sentry>, report: ErrorReport) {
+    match sentry {
+        Some(sentry) => sentry.capture(report),
+        None => println!(
+            "[{}] {} {} failed with {}: {}",
+            report.request_id.as_deref().unwrap_or("-"),
+            report.method,
+            report.path,
+            report.status,
+            report.message
+        ),
+    }
+}
+
+fn problem_response(status: StatusCode, problem: Problem, headers: &HeaderMap) -> HttpResponse {
+    let mut res = HttpResponse::build(status).json(problem);
+    // keep what clients act on, like WWW-Authenticate on a 401
+    for (name, value) in headers.iter() {
+        if name != header::CONTENT_TYPE && name != header::CONTENT_LENGTH {
+            res.headers_mut().append(name.clone(), value.clone());
+        }
+    }
+    res.headers_mut()
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(CONTENT_TYPE));
+    res
+}
@@ -0,0 +1,382 @@
+use std::{
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use jwt::{AlgorithmType, Header, PKeyWithDigest, SignWithKey, Token};
+use openssl::{
+    hash::MessageDigest,
+    pkey::{PKey, Private},
+};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use tokio::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "push_platform", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum Platform {
+    Android,
+    Ios,
+}
+
+// what shows on the phone, the data goes to the app with it
+pub struct PushMessage {
+    pub title: String,
+    pub body: String,
+    pub data: Value,
+}
+
+#[derive(Debug)]
+pub enum PushError {
+    Http(reqwest::Error),
+    Auth(String),
+    Rejected(String),
+    // the app was removed or got a new token, the device can go
+    Unregistered,
+}
+
+impl fmt::Display for PushError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PushError::Http(err) => write!(f, "{err}"),
+            PushError::Auth(msg) => write!(f, "push authentication failed: {msg}"),
+            PushError::Rejected(msg) => write!(f, "push rejected: {msg}"),
+            PushError::Unregistered => write!(f, "device is no longer registered"),
+        }
+    }
+}
+
+impl From<reqwest::Error> for PushError {
+    fn from(err: reqwest::Error) -> Self {
+        PushError::Http(err)
+    }
+}
+
+// the text of a queued message, none for kinds this version doesn't know
+pub fn render(kind: &str, data: &Value) -> Option<PushMessage> {
+    let mut payload = data.clone();
+    payload["type"] = json!(kind);
+    match kind {
+        "order.status" => Some(PushMessage {
+            title: "Order update".to_string(),
+            body: match data["status"].as_str()? {
+                "confirmed" => "Your order is confirmed",
+                "shipped" => "Your order is on its way",
+                "readyforpickup" => "Your order is ready for pickup",
+                "cancelled" => "Your order was cancelled",
+                "refunded" => "Your order was refunded",
+                "partiallyrefunded" => "Part of your order was refunded",
+                _ => "Your order was updated",
+            }
+            .to_string(),
+            data: payload,
+        }),
+        "stock.back" => Some(PushMessage {
+            title: "Back in stock".to_string(),
+            body: format!("{} is back in stock", data["name"].as_str()?),
+            data: payload,
+        }),
+        _ => None,
+    }
+}
+
+// delivers a message to one device of the platform it serves
+#[async_trait]
+pub trait PushService: Send + Sync {
+    async fn send(&self, token: &str, message: &PushMessage) -> Result<(), PushError>;
+}
+
+// the push services configured, phones of a platform without one get nothing
+pub struct Push {
+    android: Option<Arc<dyn PushService>>,
+    ios: Option<Arc<dyn PushService>>,
+}
+
+impl Push {
+    pub fn new(android: Option<Arc<dyn PushService>>, ios: Option<Arc<dyn PushService>>) -> Self {
+        Push { android, ios }
+    }
+
+    pub fn from_env() -> Option<Self> {
+        let android = Fcm::from_env().map(|fcm| Arc::new(fcm) as Arc<dyn PushService>);
+        let ios = Apns::from_env().map(|apns| Arc::new(apns) as Arc<dyn PushService>);
+        (android.is_some() || ios.is_some()).then(|| Push::new(android, ios))
+    }
+
+    fn service(&self, platform: Platform) -> Option<&Arc<dyn PushService>> {
+        match platform {
+            Platform::Android => self.android.as_ref(),
+            Platform::Ios => self.ios.as_ref(),
+        }
+    }
+
+    pub fn supports(&self, platform: Platform) -> bool {
+        self.service(platform).is_some()
+    }
+
+    pub async fn send(
+        &self,
+        platform: Platform,
+        token: &str,
+        message: &PushMessage,
+    ) -> Result<(), PushError> {
+        match self.service(platform) {
+            Some(service) => service.send(token, message).await,
+            None => Err(PushError::Rejected(format!(
+                "no push service for {platform:?}"
+            ))),
+        }
+    }
+}
+
+fn read_key(path: &str, name: &str) -> PKeyWithDigest<Private> {
+    let pem = std::fs::read(path).unwrap_or_else(|err| panic!("failed to read {path}: {err}"));
+    PKeyWithDigest {
+        digest: MessageDigest::sha256(),
+        key: PKey::private_key_from_pem(&pem)
+            .unwrap_or_else(|err| panic!("{name} is not a PEM private key: {err}")),
+    }
+}
+
+#[derive(Deserialize)]
+struct ServiceAccount {
+    project_id: String,
+    client_email: String,
+    private_key: String,
+    token_uri: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AccessToken {
+    access_token: String,
+    expires_in: u64,
+}
+
+// Firebase Cloud Messaging (HTTP v1) for android, FCM_SERVICE_ACCOUNT is the
+// path to the JSON key of a service account allowed to send messages
+struct Fcm {
+    client: reqwest::Client,
+    project_id: String,
+    client_email: String,
+    key: PKeyWithDigest<Private>,
+    token_uri: String,
+    // the OAuth token messages are sent with, until shortly before it expires
+    access_token: Mutex<Option<(String, Instant)>>,
+}
+
+impl Fcm {
+    fn from_env() -> Option<Self> {
+        let path = std::env::var("FCM_SERVICE_ACCOUNT")
+            .ok()
+            .filter(|path| !path.is_empty())?;
+        let json =
+            std::fs::read(&path).unwrap_or_else(|err| panic!("failed to read {path}: {err}"));
+        let account: ServiceAccount = serde_json::from_slice(&json).unwrap_or_else(|err| {
+            panic!("FCM_SERVICE_ACCOUNT is not a service account key: {err}")
+        });
+        Some(Fcm {
+            client: reqwest::Client::new(),
+            project_id: account.project_id,
+            client_email: account.client_email,
+            key: PKeyWithDigest {
+                digest: MessageDigest::sha256(),
+                key: PKey::private_key_from_pem(account.private_key.as_bytes())
+                    .unwrap_or_else(|err| panic!("FCM_SERVICE_ACCOUNT key is not valid: {err}")),
+            },
+            token_uri: account
+                .token_uri
+                .unwrap_or_else(|| "https://oauth2.googleapis.com/token".to_string()),
+            access_token: Mutex::new(None),
+        })
+    }
+
+    async fn access_token(&self) -> Result<String, PushError> {
+        let mut cached = self.access_token.lock().await;
+        if let Some((token, refresh_at)) = cached.as_ref() {
+            if *refresh_at > Instant::now() {
+                return Ok(token.clone());
+            }
+        }
+
+        // the service account signs its own grant
+        let now = Utc::now().timestamp();
+        let header = Header {
+            algorithm: AlgorithmType::Rs256,
+            ..Default::default()
+        };
+        let claims = json!({
+            "iss": self.client_email,
+            "scope": "https://www.googleapis.com/auth/firebase.messaging",
+            "aud": self.token_uri,
+            "iat": now,
+            "exp": now + 3600,
+        });
+        let assertion = Token::new(header, claims)
+            .sign_with_key(&self.key)
+            .map_err(|err| PushError::Auth(err.to_string()))?;
+        let res = self
+            .client
+            .post(&self.token_uri)
+            .form(&[
+                ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                ("assertion", assertion.as_str()),
+            ])
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            return Err(PushError::Auth(res.text().await.unwrap_or_default()));
+        }
+        let granted: AccessToken = res.json().await?;
+        let refresh_at =
+            Instant::now() + Duration::from_secs(granted.expires_in.saturating_sub(60));
+        *cached = Some((granted.access_token.clone(), refresh_at));
+        Ok(granted.access_token)
+    }
+}
+
+#[async_trait]
+impl PushService for Fcm {
+    async fn send(&self, token: &str, message: &PushMessage) -> Result<(), PushError> {
+        // FCM data values are strings
+        let data: Map<String, Value> = message
+            .data
+            .as_object()
+            .into_iter()
+            .flatten()
+            .map(|(key, value)| {
+                let value = match value {
+                    Value::String(value) => value.clone(),
+                    value => value.to_string(),
+                };
+                (key.clone(), Value::String(value))
+            })
+            .collect();
+        let res = self
+            .client
+            .post(format!(
+                "https://fcm.googleapis.com/v1/projects/{}/messages:send",
+                self.project_id
+            ))
+            .bearer_auth(self.access_token().await?)
+            .json(&json!({
+                "message": {
+                    "token": token,
+                    "notification": { "title": message.title, "body": message.body },
+                    "data": data,
+                },
+            }))
+            .send()
+            .await?;
+        match res.status() {
+            status if status.is_success() => Ok(()),
+            // FCM answers UNREGISTERED with a 404
+            StatusCode::NOT_FOUND => Err(PushError::Unregistered),
+            status => Err(PushError::Rejected(format!(
+                "{status} {}",
+                res.text().await.unwrap_or_default()
+            ))),
+        }
+    }
+}
+
+// Apple Push Notification service for ios, with the token signing key (.p8)
+// of the developer account: APNS_KEY_PATH, APNS_KEY_ID, APNS_TEAM_ID and
+// APNS_TOPIC, the app's bundle id. APNS_SANDBOX=true for development builds
+struct Apns {
+    client: reqwest::Client,
+    host: &'static str,
+    key: PKeyWithDigest<Private>,
+    key_id: String,
+    team_id: String,
+    topic: String,
+    // Apple refuses provider tokens older than an hour or renewed too often
+    provider_token: Mutex<Option<(String, Instant)>>,
+}
+
+impl Apns {
+    fn from_env() -> Option<Self> {
+        let path = std::env::var("APNS_KEY_PATH")
+            .ok()
+            .filter(|path| !path.is_empty())?;
+        let required = |name: &str| {
+            std::env::var(name).unwrap_or_else(|_| panic!("{name} must be set with APNS_KEY_PATH"))
+        };
+        let sandbox = std::env::var("APNS_SANDBOX").is_ok_and(|value| value == "true");
+        Some(Apns {
+            // APNs only speaks HTTP/2
+            client: reqwest::Client::builder()
+                .http2_prior_knowledge()
+                .build()
+                .expect("failed to build the HTTP client"),
+            host: if sandbox {
+                "api.sandbox.push.apple.com"
+            } else {
+                "api.push.apple.com"
+            },
+            key: read_key(&path, "APNS_KEY_PATH"),
+            key_id: required("APNS_KEY_ID"),
+            team_id: required("APNS_TEAM_ID"),
+            topic: required("APNS_TOPIC"),
+            provider_token: Mutex::new(None),
+        })
+    }
+
+    async fn provider_token(&self) -> Result<String, PushError> {
+        let mut cached = self.provider_token.lock().await;
+        if let Some((token, refresh_at)) = cached.as_ref() {
+            if *refresh_at > Instant::now() {
+                return Ok(token.clone());
+            }
+        }
+        let header = Header {
+            algorithm: AlgorithmType::Es256,
+            key_id: Some(self.key_id.clone()),
+            ..Default::default()
+        };
+        let claims = json!({ "iss": self.team_id, "iat": Utc::now().timestamp() });
+        let token = Token::new(header, claims)
+            .sign_with_key(&self.key)
+            .map_err(|err| PushError::Auth(err.to_string()))?
+            .as_str()
+            .to_string();
+        *cached = Some((token.clone(), Instant::now() + Duration::from_secs(40 * 60)));
+        Ok(token)
+    }
+}
+
+#[async_trait]
+impl PushService for Apns {
+    async fn send(&self, token: &str, message: &PushMessage) -> Result<(), PushError> {
+        let mut payload = message.data.clone();
+        payload["aps"] = json!({
+            "alert": { "title": message.title, "body": message.body },
+            "sound": "default",
+        });
+        let res = self
+            .client
+            .post(format!("https://{}/3/device/{token}", self.host))
+            .bearer_auth(self.provider_token().await?)
+            .header("apns-topic", &self.topic)
+            .header("apns-push-type", "alert")
+            .json(&payload)
+            .send()
+            .await?;
+        match res.status() {
+            status if status.is_success() => Ok(()),
+            StatusCode::GONE => Err(PushError::Unregistered),
+            status => {
+                let reason = res.text().await.unwrap_or_default();
+                if reason.contains("BadDeviceToken") || reason.contains("Unregistered") {
+                    Err(PushError::Unregistered)
+                } else {
+                    Err(PushError::Rejected(format!("{status} {reason}")))
+                }
+            }
+        }
+    }
+}
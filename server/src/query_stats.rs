@@ -0,0 +1,127 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// upper bounds of the duration histogram, in seconds
+const BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+// how long the repositories take to answer, per tag like "products.list". A
+// call slower than SLOW_QUERY_MS (default 250) is logged with its tag and
+// duration and counted as slow. Background jobs add the rows they went
+// through. GET /metrics serves the numbers to Prometheus
+pub struct QueryStats {
+    slow_after: Duration,
+    tags: Mutex<BTreeMap<&'static str, TagStats>>,
+    rows: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+#[derive(Default)]
+struct TagStats {
+    // calls per bucket, not cumulative, the rendering adds them up
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    seconds: f64,
+    slow: u64,
+}
+
+impl QueryStats {
+    pub fn from_env() -> Self {
+        QueryStats {
+            slow_after: Duration::from_millis(
+                std::env::var("SLOW_QUERY_MS")
+                    .ok()
+                    .map(|value| value.parse().expect("SLOW_QUERY_MS must be a number"))
+                    .unwrap_or(250),
+            ),
+            tags: Mutex::new(BTreeMap::new()),
+            rows: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    // run the query and record how long it took under the tag
+    pub async fn time<T>(&self, tag: &'static str, query: impl Future<Output = T>) -> T {
+        let started = Instant::now();
+        let result = query.await;
+        self.record(tag, started.elapsed());
+        result
+    }
+
+    // count rows a background job removed or handled under the tag
+    pub fn add_rows(&self, tag: &'static str, rows: u64) {
+        *self.rows.lock().unwrap().entry(tag).or_default() += rows;
+    }
+
+    // record a duration measured by the caller, for calls that time part of
+    // their work under another tag
+    pub fn record(&self, tag: &'static str, elapsed: Duration) {
+        let slow = elapsed >= self.slow_after;
+        if slow {
+            println!(
+                "slow query {tag} took {}ms (threshold {}ms)",
+                elapsed.as_millis(),
+                self.slow_after.as_millis()
+            );
+        }
+
+        let seconds = elapsed.as_secs_f64();
+        let mut tags = self.tags.lock().unwrap();
+        let stats = tags.entry(tag).or_default();
+        stats.count += 1;
+        stats.seconds += seconds;
+        stats.slow += u64::from(slow);
+        if let Some(bucket) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            stats.buckets[bucket] += 1;
+        }
+    }
+
+    // the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let tags = self.tags.lock().unwrap();
+        let mut out = String::new();
+
+        out.push_str("# HELP db_query_duration_seconds Time repository calls took, by tag.\n");
+        out.push_str("# TYPE db_query_duration_seconds histogram\n");
+        for (tag, stats) in tags.iter() {
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(stats.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "db_query_duration_seconds_bucket{{tag=\"{tag}\",le=\"{bound}\"}} {cumulative}"
+                );
+            }
+            let _ = writeln!(
+                out,
+                "db_query_duration_seconds_bucket{{tag=\"{tag}\",le=\"+Inf\"}} {}",
+                stats.count
+            );
+            let _ = writeln!(
+                out,
+                "db_query_duration_seconds_sum{{tag=\"{tag}\"}} {}",
+                stats.seconds
+            );
+            let _ = writeln!(
+                out,
+                "db_query_duration_seconds_count{{tag=\"{tag}\"}} {}",
+                stats.count
+            );
+        }
+
+        out.push_str("# HELP db_slow_queries_total Repository calls over SLOW_QUERY_MS, by tag.\n");
+        out.push_str("# TYPE db_slow_queries_total counter\n");
+        for (tag, stats) in tags.iter() {
+            let _ = writeln!(out, "db_slow_queries_total{{tag=\"{tag}\"}} {}", stats.slow);
+        }
+
+        out.push_str("# HELP job_rows_total Rows background jobs removed or handled, by tag.\n");
+        out.push_str("# TYPE job_rows_total counter\n");
+        for (tag, rows) in self.rows.lock().unwrap().iter() {
+            let _ = writeln!(out, "job_rows_total{{tag=\"{tag}\"}} {rows}");
+        }
+        out
+    }
+}
@@ -0,0 +1,287 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::{header, Method},
+    middleware::Next,
+    web, HttpResponse,
+};
+use redis::{aio::ConnectionManager, Script};
+use tokio::sync::OnceCell;
+
+use crate::{client_ip::client_ip, AppState};
+
+// one fixed window per key: the first request starts the window, the count
+// and the time left in it come back in one round trip
+const WINDOW_SCRIPT: &str = r"
+local count = redis.call('INCR', KEYS[1])
+if count == 1 then
+    redis.call('PEXPIRE', KEYS[1], ARGV[1])
+end
+return {count, redis.call('PTTL', KEYS[1])}
+";
+
+#[derive(Clone, Copy)]
+struct Limit {
+    requests: u64,
+    window: Duration,
+}
+
+// a limit for one route, any method when none is given
+struct Rule {
+    method: Option<Method>,
+    route: String,
+    limit: Limit,
+}
+
+enum Store {
+    Redis(Box<RedisStore>),
+    Memory(Mutex<HashMap<String, (u64, Instant)>>),
+}
+
+// connected on first use, AppState is built outside a runtime in the CLI
+struct RedisStore {
+    client: redis::Client,
+    connection: OnceCell<ConnectionManager>,
+    script: Script,
+}
+
+// requests per client (by IP, see client_ip) and route per window. RATE_LIMITS lists them as
+// "POST /api/auth=5/60,/api/product/{id}=120/60,*=600/60", requests per
+// seconds for a method and route pattern, any method when it's left out and
+// "*" for every route without a limit of its own. Counters live in Redis when
+// REDIS_URL is set, so replicas share them, and in this process otherwise
+pub struct RateLimiter {
+    rules: Vec<Rule>,
+    default_limit: Option<Limit>,
+    store: Store,
+}
+
+// where a client stands after this request
+struct Usage {
+    count: u64,
+    reset_after: Duration,
+}
+
+impl RateLimiter {
+    pub fn from_env() -> Option<Self> {
+        let config = std::env::var("RATE_LIMITS")
+            .ok()
+            .filter(|config| !config.trim().is_empty())?;
+
+        let mut rules = Vec::new();
+        let mut default_limit = None;
+        for entry in config.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (target, limit) = entry.rsplit_once('=').unwrap_or_else(|| {
+                panic!("RATE_LIMITS entry {entry} must look like route=requests/seconds")
+            });
+            let limit = parse_limit(limit).unwrap_or_else(|| {
+                panic!("RATE_LIMITS entry {entry} must look like route=requests/seconds")
+            });
+            match target.trim().split_once(' ') {
+                _ if target.trim() == "*" => default_limit = Some(limit),
+                Some((method, route)) => {
+                    rules.push(Rule {
+                        method: Some(method.to_uppercase().parse().unwrap_or_else(|_| {
+                            panic!("RATE_LIMITS method {method} is not valid")
+                        })),
+                        route: route.trim().to_string(),
+                        limit,
+                    })
+                }
+                None => rules.push(Rule {
+                    method: None,
+                    route: target.trim().to_string(),
+                    limit,
+                }),
+            }
+        }
+
+        let store = match std::env::var("REDIS_URL") {
+            Ok(url) => Store::Redis(Box::new(RedisStore {
+                client: redis::Client::open(url)
+                    .unwrap_or_else(|err| panic!("REDIS_URL is not valid: {err}")),
+                connection: OnceCell::new(),
+                script: Script::new(WINDOW_SCRIPT),
+            })),
+            Err(_) => Store::Memory(Mutex::new(HashMap::new())),
+        };
+        Some(RateLimiter {
+            rules,
+            default_limit,
+            store,
+        })
+    }
+
+    // the limit of a route and the name its counters go by
+    fn limit_for(&self, method: &Method, route: &str) -> Option<(String, Limit)> {
+        match self
+            .rules
+            .iter()
+            .find(|rule| rule.route == route && rule.method.as_ref().is_none_or(|m| m == method))
+        {
+            Some(rule) => Some((
+                match &rule.method {
+                    Some(method) => format!("{method} {}", rule.route),
+                    None => rule.route.clone(),
+                },
+                rule.limit,
+            )),
+            None => self.default_limit.map(|limit| ("*".to_string(), limit)),
+        }
+    }
+
+    async fn hit(&self, key: String, limit: Limit) -> Result<Usage, redis::RedisError> {
+        match &self.store {
+            Store::Redis(redis) => {
+                let mut connection = redis
+                    .connection
+                    .get_or_try_init(|| redis.client.get_connection_manager())
+                    .await?
+                    .clone();
+                let (count, ttl_ms): (u64, i64) = redis
+                    .script
+                    .key(format!("rate_limit:{key}"))
+                    .arg(limit.window.as_millis() as u64)
+                    .invoke_async(&mut connection)
+                    .await?;
+                Ok(Usage {
+                    count,
+                    reset_after: Duration::from_millis(ttl_ms.max(0) as u64),
+                })
+            }
+            Store::Memory(windows) => {
+                let now = Instant::now();
+                let mut windows = windows.lock().unwrap();
+                // forget clients whose window is over once in a while
+                if windows.len() > 10_000 {
+                    windows.retain(|_, (_, ends)| *ends > now);
+                }
+                let (count, ends) = windows.entry(key).or_insert((0, now + limit.window));
+                if *ends <= now {
+                    *count = 0;
+                    *ends = now + limit.window;
+                }
+                *count += 1;
+                Ok(Usage {
+                    count: *count,
+                    reset_after: *ends - now,
+                })
+            }
+        }
+    }
+}
+
+fn parse_limit(limit: &str) -> Option<Limit> {
+    let (requests, seconds) = limit.trim().split_once('/')?;
+    Some(Limit {
+        requests: requests.trim().parse().ok()?,
+        window: Duration::from_secs(seconds.trim().parse().ok().filter(|s| *s > 0)?),
+    })
+}
+
+// what clients back off by, on every limited route and on every rejection
+const LIMIT_HEADER: &str = "x-ratelimit-limit";
+const REMAINING_HEADER: &str = "x-ratelimit-remaining";
+const RESET_HEADER: &str = "x-ratelimit-reset";
+
+// how long clients wait before trying again when the database is overloaded
+const OVERLOAD_RETRY_AFTER: u64 = 5;
+
+// whole seconds, never 0 so clients don't retry straight away
+fn seconds(duration: Duration) -> u64 {
+    duration.as_secs_f64().ceil().max(1.0) as u64
+}
+
+fn insert_usage(headers: &mut header::HeaderMap, limit: Limit, usage: &Usage) {
+    for (name, value) in [
+        (LIMIT_HEADER, limit.requests),
+        (REMAINING_HEADER, limit.requests.saturating_sub(usage.count)),
+        (RESET_HEADER, seconds(usage.reset_after)),
+    ] {
+        headers.insert(
+            header::HeaderName::from_static(name),
+            header::HeaderValue::from(value),
+        );
+    }
+}
+
+// too_many_connections, configuration_limit_exceeded and cannot_connect_now
+const OVERLOAD_CODES: [&str; 3] = ["53300", "53400", "57P03"];
+
+// the database couldn't take the request: no connection free in the pool,
+// Postgres out of reach or turning connections away
+pub fn overloaded(err: &sqlx::Error) -> bool {
+    match err {
+        sqlx::Error::PoolTimedOut | sqlx::Error::Io(_) => true,
+        sqlx::Error::Database(db) => db
+            .code()
+            .is_some_and(|code| OVERLOAD_CODES.contains(&code.as_ref())),
+        _ => false,
+    }
+}
+
+// how handlers answer a database error they have no answer of their own for.
+// An overloaded database is a 503 with Retry-After, clients come back later.
+// Anything else is a 500
+pub fn database_error(err: sqlx::Error) -> HttpResponse {
+    if overloaded(&err) {
+        HttpResponse::ServiceUnavailable()
+            .insert_header((header::RETRY_AFTER, OVERLOAD_RETRY_AFTER))
+            .json(format!("{err:?}"))
+    } else {
+        HttpResponse::InternalServerError().json(format!("{err:?}"))
+    }
+}
+
+// counts the request against its route's limit and tells the client where it
+// stands in the X-RateLimit-* headers. Requests over the limit answer with
+// Retry-After
+pub async fn limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let limiter = req
+        .app_data::<web::Data<AppState>>()
+        .and_then(|state| state.rate_limiter.clone());
+    // the route pattern is known before routing, so /api/product/{id} is one limit
+    let rule = limiter.as_ref().and_then(|limiter| {
+        req.match_pattern()
+            .and_then(|route| limiter.limit_for(req.method(), &route))
+    });
+
+    let mut usage = None;
+    if let (Some(limiter), Some((name, limit))) = (limiter, rule) {
+        let client = client_ip(req.request())
+            .map(|ip| ip.to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+        match limiter.hit(format!("{name}:{client}"), limit).await {
+            Ok(hit) if hit.count > limit.requests => {
+                let retry_after = seconds(hit.reset_after);
+                let mut res = HttpResponse::TooManyRequests()
+                    .insert_header((header::RETRY_AFTER, retry_after))
+                    .json(format!(
+                        "Too many requests, try again in {retry_after} seconds"
+                    ));
+                insert_usage(res.headers_mut(), limit, &hit);
+                return Ok(req.into_response(res).map_into_right_body());
+            }
+            Ok(hit) => usage = Some((limit, hit)),
+            // an unreachable Redis shouldn't take the API down with it
+            Err(err) => {
+                println!("rate limiter unavailable, letting the request through: {err}")
+            }
+        }
+    }
+
+    let mut res = next.call(req).await?;
+    if let Some((limit, usage)) = &usage {
+        insert_usage(res.headers_mut(), *limit, usage);
+    }
+    Ok(res.map_into_left_body())
+}
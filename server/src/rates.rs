@@ -0,0 +1,306 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Write},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Decimal, PgPool};
+
+#[derive(Debug)]
+pub enum RatesError {
+    Http(reqwest::Error),
+    // the provider answered with something that isn't a list of rates
+    Invalid(String),
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for RatesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RatesError::Http(err) => write!(f, "{err}"),
+            RatesError::Invalid(msg) => write!(f, "unusable rates: {msg}"),
+            RatesError::Database(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl From<reqwest::Error> for RatesError {
+    fn from(err: reqwest::Error) -> Self {
+        RatesError::Http(err)
+    }
+}
+
+impl From<sqlx::Error> for RatesError {
+    fn from(err: sqlx::Error) -> Self {
+        RatesError::Database(err)
+    }
+}
+
+#[async_trait]
+pub trait RateProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+    // what one unit of base buys in each currency the provider knows
+    async fn fetch(&self, base: &str) -> Result<BTreeMap<String, Decimal>, RatesError>;
+}
+
+// both providers answer with the rates under "rates"
+#[derive(Deserialize)]
+struct RatesResponse {
+    rates: BTreeMap<String, Decimal>,
+}
+
+// the ECB reference rates through Frankfurter, no key needed.
+// EXCHANGE_RATES_URL points it at a self-hosted instance
+pub struct Frankfurter {
+    client: reqwest::Client,
+    url: String,
+}
+
+#[async_trait]
+impl RateProvider for Frankfurter {
+    fn name(&self) -> &'static str {
+        "frankfurter"
+    }
+
+    async fn fetch(&self, base: &str) -> Result<BTreeMap<String, Decimal>, RatesError> {
+        let response: RatesResponse = self
+            .client
+            .get(format!("{}/latest", self.url.trim_end_matches('/')))
+            .query(&[("from", base)])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(response.rates)
+    }
+}
+
+// Open Exchange Rates with OPEN_EXCHANGE_RATES_APP_ID, a base other than USD
+// needs a paid plan
+pub struct OpenExchangeRates {
+    client: reqwest::Client,
+    app_id: String,
+}
+
+#[async_trait]
+impl RateProvider for OpenExchangeRates {
+    fn name(&self) -> &'static str {
+        "openexchangerates"
+    }
+
+    async fn fetch(&self, base: &str) -> Result<BTreeMap<String, Decimal>, RatesError> {
+        let response: RatesResponse = self
+            .client
+            .get("https://openexchangerates.org/api/latest.json")
+            .query(&[("app_id", self.app_id.as_str()), ("base", base)])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(response.rates)
+    }
+}
+
+// the rates in use, from the latest fetch
+#[derive(Serialize)]
+pub struct RateTable {
+    base: String,
+    fetched_at: Option<DateTime<Utc>>,
+    // older than EXCHANGE_RATES_MAX_AGE_HOURS, the provider has been failing
+    stale: bool,
+    rates: BTreeMap<String, Decimal>,
+}
+
+// exchange rates against the shop currency, refreshed in the background.
+// EXCHANGE_RATES_PROVIDER is frankfurter or openexchangerates,
+// EXCHANGE_RATES_BASE the currency they are against (default
+// PAYMENT_CURRENCY), EXCHANGE_RATES_INTERVAL_MINUTES how often they are
+// fetched (default 60) and EXCHANGE_RATES_MAX_AGE_HOURS when the last known
+// rates count as stale (default 24). While the provider is down the last
+// known rates stay in use
+pub struct ExchangeRates {
+    provider: Box<dyn RateProvider>,
+    base: String,
+    pub interval: Duration,
+    max_age: chrono::Duration,
+    failures: AtomicU64,
+}
+
+impl ExchangeRates {
+    pub fn from_env() -> Option<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .expect("failed to build the HTTP client");
+        let provider: Box<dyn RateProvider> =
+            match std::env::var("EXCHANGE_RATES_PROVIDER").ok()?.as_str() {
+                "frankfurter" => Box::new(Frankfurter {
+                    client,
+                    url: std::env::var("EXCHANGE_RATES_URL")
+                        .unwrap_or_else(|_| "https://api.frankfurter.app".into()),
+                }),
+                "openexchangerates" => Box::new(OpenExchangeRates {
+                    client,
+                    app_id: std::env::var("OPEN_EXCHANGE_RATES_APP_ID")
+                        .expect("OPEN_EXCHANGE_RATES_APP_ID must be set for openexchangerates"),
+                }),
+                other => panic!(
+                    "EXCHANGE_RATES_PROVIDER must be frankfurter or openexchangerates, not {other}"
+                ),
+            };
+        let number = |name: &str, default: i64| {
+            std::env::var(name)
+                .ok()
+                .map(|value| {
+                    value
+                        .parse()
+                        .ok()
+                        .filter(|value| *value > 0)
+                        .unwrap_or_else(|| panic!("{name} must be a positive number"))
+                })
+                .unwrap_or(default)
+        };
+
+        Some(ExchangeRates {
+            provider,
+            base: std::env::var("EXCHANGE_RATES_BASE")
+                .or_else(|_| std::env::var("PAYMENT_CURRENCY"))
+                .unwrap_or_else(|_| "usd".into())
+                .to_uppercase(),
+            interval: Duration::from_secs(
+                number("EXCHANGE_RATES_INTERVAL_MINUTES", 60) as u64 * 60,
+            ),
+            max_age: chrono::Duration::hours(number("EXCHANGE_RATES_MAX_AGE_HOURS", 24)),
+            failures: AtomicU64::new(0),
+        })
+    }
+
+    // fetch and store the current rates, returns how many currencies came.
+    // On failure nothing is stored so the last known rates stay in use
+    pub async fn refresh(&self, pool: &PgPool) -> Result<usize, RatesError> {
+        let fetched = self.fetch().await;
+        if fetched.is_err() {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+        let rates = fetched?;
+
+        let fetched_at = Utc::now();
+        let mut tx = pool.begin().await?;
+        for (currency, rate) in &rates {
+            sqlx::query!(
+                "INSERT INTO exchange_rates (base, currency, rate, provider, fetched_at)
+                VALUES ($1, $2, $3, $4, $5)",
+                self.base,
+                currency,
+                rate,
+                self.provider.name(),
+                fetched_at
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        sqlx::query!("DELETE FROM exchange_rates WHERE fetched_at < NOW() - INTERVAL '90 days'")
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(rates.len())
+    }
+
+    async fn fetch(&self) -> Result<BTreeMap<String, Decimal>, RatesError> {
+        let rates: BTreeMap<String, Decimal> = self
+            .provider
+            .fetch(&self.base)
+            .await?
+            .into_iter()
+            .map(|(currency, rate)| (currency.to_uppercase(), rate))
+            .filter(|(currency, _)| *currency != self.base)
+            .collect();
+        if rates.is_empty() {
+            return Err(RatesError::Invalid("no rates".into()));
+        }
+        if let Some((currency, _)) = rates
+            .iter()
+            .find(|(currency, rate)| currency.len() != 3 || **rate <= Decimal::ZERO)
+        {
+            return Err(RatesError::Invalid(format!("bad rate for {currency}")));
+        }
+        Ok(rates)
+    }
+
+    // the rates of the latest fetch, empty before the first one
+    pub async fn latest(&self, pool: &PgPool) -> Result<RateTable, sqlx::Error> {
+        let rows = sqlx::query!(
+            "SELECT currency, rate, fetched_at FROM exchange_rates
+            WHERE base = $1
+                AND fetched_at = (SELECT MAX(fetched_at) FROM exchange_rates WHERE base = $1)",
+            self.base
+        )
+        .fetch_all(pool)
+        .await?;
+
+        let fetched_at = rows.first().map(|row| row.fetched_at);
+        Ok(RateTable {
+            base: self.base.clone(),
+            fetched_at,
+            stale: self.is_stale(fetched_at),
+            rates: rows
+                .into_iter()
+                .map(|row| (row.currency, row.rate.normalize()))
+                .collect(),
+        })
+    }
+
+    fn is_stale(&self, fetched_at: Option<DateTime<Utc>>) -> bool {
+        fetched_at.is_none_or(|fetched_at| Utc::now() - fetched_at > self.max_age)
+    }
+
+    // the Prometheus text exposition format, how old the rates in use are
+    pub async fn render(&self, pool: &PgPool) -> Result<String, sqlx::Error> {
+        let fetched_at = sqlx::query_scalar!(
+            "SELECT MAX(fetched_at) FROM exchange_rates WHERE base = $1",
+            self.base
+        )
+        .fetch_one(pool)
+        .await?;
+        let base = &self.base;
+        let mut out = String::new();
+
+        if let Some(fetched_at) = fetched_at {
+            out.push_str(
+                "# HELP exchange_rates_age_seconds Time since the rates in use were fetched.\n",
+            );
+            out.push_str("# TYPE exchange_rates_age_seconds gauge\n");
+            let _ = writeln!(
+                out,
+                "exchange_rates_age_seconds{{base=\"{base}\"}} {}",
+                (Utc::now() - fetched_at).num_seconds()
+            );
+        }
+        out.push_str(
+            "# HELP exchange_rates_stale Whether the rates in use are older than EXCHANGE_RATES_MAX_AGE_HOURS.\n",
+        );
+        out.push_str("# TYPE exchange_rates_stale gauge\n");
+        let _ = writeln!(
+            out,
+            "exchange_rates_stale{{base=\"{base}\"}} {}",
+            u8::from(self.is_stale(fetched_at))
+        );
+        out.push_str(
+            "# HELP exchange_rates_refresh_failures_total Fetches from the provider that failed.\n",
+        );
+        out.push_str("# TYPE exchange_rates_refresh_failures_total counter\n");
+        let _ = writeln!(
+            out,
+            "exchange_rates_refresh_failures_total{{base=\"{base}\"}} {}",
+            self.failures.load(Ordering::Relaxed)
+        );
+        Ok(out)
+    }
+}
@@ -0,0 +1,413 @@
+use std::{fmt, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::PgExecutor;
+use uuid::Uuid;
+
+#[derive(Debug)]
+pub enum SearchError {
+    Http(reqwest::Error),
+    Rejected(String),
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for SearchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SearchError::Http(err) => write!(f, "{err}"),
+            SearchError::Rejected(msg) => write!(f, "search engine rejected the request: {msg}"),
+            SearchError::Database(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl From<reqwest::Error> for SearchError {
+    fn from(err: reqwest::Error) -> Self {
+        SearchError::Http(err)
+    }
+}
+
+impl From<sqlx::Error> for SearchError {
+    fn from(err: sqlx::Error) -> Self {
+        SearchError::Database(err)
+    }
+}
+
+// a product as the search engine has it. Prices differ per customer group,
+// they are read from the database once the engine has found the products
+#[derive(Serialize)]
+pub struct SearchDocument {
+    pub product_id: Uuid,
+    store_id: Uuid,
+    name: String,
+    description: Option<String>,
+    category: Option<String>,
+    is_available: bool,
+}
+
+// the products the engine found, best match first
+pub struct SearchHits {
+    pub product_ids: Vec<Uuid>,
+}
+
+// the fields a query is matched against and how much a match in each counts
+#[derive(Clone)]
+pub struct Relevance {
+    weights: Vec<(String, u32)>,
+    typo_tolerance: bool,
+}
+
+#[async_trait]
+pub trait SearchEngine: Send + Sync {
+    fn name(&self) -> &'static str;
+    // add the products, or replace what the engine has for them
+    async fn upsert(&self, documents: &[SearchDocument]) -> Result<(), SearchError>;
+    async fn remove(&self, product_ids: &[Uuid]) -> Result<(), SearchError>;
+    // the store's available products only
+    async fn search(
+        &self,
+        store_id: Uuid,
+        q: &str,
+        offset: i64,
+        limit: i64,
+    ) -> Result<SearchHits, SearchError>;
+}
+
+async fn accepted(res: reqwest::Response) -> Result<reqwest::Response, SearchError> {
+    if !res.status().is_success() {
+        return Err(SearchError::Rejected(format!(
+            "{} {}",
+            res.status(),
+            res.text().await.unwrap_or_default()
+        )));
+    }
+    Ok(res)
+}
+
+// Meilisearch, which tolerates typos on its own. The index settings are sent
+// before the first request so the weights apply from the start
+pub struct Meilisearch {
+    client: reqwest::Client,
+    url: String,
+    api_key: Option<String>,
+    index: String,
+    relevance: Relevance,
+    configured: tokio::sync::OnceCell<()>,
+}
+
+#[derive(Deserialize)]
+struct MeilisearchHit {
+    product_id: Uuid,
+}
+
+#[derive(Deserialize)]
+struct MeilisearchResults {
+    hits: Vec<MeilisearchHit>,
+}
+
+impl Meilisearch {
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        let request = self
+            .client
+            .request(method, format!("{}/indexes/{}{path}", self.url, self.index));
+        match &self.api_key {
+            Some(key) => request.bearer_auth(key),
+            None => request,
+        }
+    }
+
+    async fn configure(&self) -> Result<(), SearchError> {
+        self.configured
+            .get_or_try_init(|| async {
+                // meilisearch ranks by the order of the searchable attributes
+                let mut weights = self.relevance.weights.clone();
+                weights.sort_by_key(|(_, weight)| std::cmp::Reverse(*weight));
+                let searchable: Vec<&str> =
+                    weights.iter().map(|(field, _)| field.as_str()).collect();
+                accepted(
+                    self.request(reqwest::Method::PATCH, "/settings")
+                        .json(&json!({
+                            "searchableAttributes": searchable,
+                            "filterableAttributes": ["store_id", "is_available", "category"],
+                            "typoTolerance": { "enabled": self.relevance.typo_tolerance },
+                        }))
+                        .send()
+                        .await?,
+                )
+                .await
+                .map(|_| ())
+            })
+            .await
+            .map(|_| ())
+    }
+}
+
+#[async_trait]
+impl SearchEngine for Meilisearch {
+    fn name(&self) -> &'static str {
+        "meilisearch"
+    }
+
+    async fn upsert(&self, documents: &[SearchDocument]) -> Result<(), SearchError> {
+        self.configure().await?;
+        accepted(
+            self.request(reqwest::Method::POST, "/documents?primaryKey=product_id")
+                .json(documents)
+                .send()
+                .await?,
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn remove(&self, product_ids: &[Uuid]) -> Result<(), SearchError> {
+        accepted(
+            self.request(reqwest::Method::POST, "/documents/delete-batch")
+                .json(product_ids)
+                .send()
+                .await?,
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn search(
+        &self,
+        store_id: Uuid,
+        q: &str,
+        offset: i64,
+        limit: i64,
+    ) -> Result<SearchHits, SearchError> {
+        self.configure().await?;
+        let results: MeilisearchResults = accepted(
+            self.request(reqwest::Method::POST, "/search")
+                .json(&json!({
+                    "q": q,
+                    "offset": offset,
+                    "limit": limit,
+                    "filter": format!("store_id = '{store_id}' AND is_available = true"),
+                    "attributesToRetrieve": ["product_id"],
+                }))
+                .send()
+                .await?,
+        )
+        .await?
+        .json()
+        .await?;
+        Ok(SearchHits {
+            product_ids: results.hits.into_iter().map(|hit| hit.product_id).collect(),
+        })
+    }
+}
+
+// Elasticsearch or OpenSearch, typos are matched with fuzzy queries and the
+// weights are field boosts
+pub struct Elasticsearch {
+    client: reqwest::Client,
+    url: String,
+    api_key: Option<String>,
+    index: String,
+    relevance: Relevance,
+}
+
+impl Elasticsearch {
+    fn request(&self, path: &str) -> reqwest::RequestBuilder {
+        let request = self
+            .client
+            .post(format!("{}/{}{path}", self.url, self.index));
+        match &self.api_key {
+            Some(key) => request.header("Authorization", format!("ApiKey {key}")),
+            None => request,
+        }
+    }
+
+    // a bulk request fails item by item, the response says whether any did
+    async fn bulk(&self, lines: Vec<Value>) -> Result<(), SearchError> {
+        let mut body = String::new();
+        for line in lines {
+            body.push_str(&line.to_string());
+            body.push('\n');
+        }
+        let results: Value = accepted(
+            self.request("/_bulk")
+                .header("Content-Type", "application/x-ndjson")
+                .body(body)
+                .send()
+                .await?,
+        )
+        .await?
+        .json()
+        .await?;
+        if results["errors"].as_bool().unwrap_or(false) {
+            return Err(SearchError::Rejected(results["items"].to_string()));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SearchEngine for Elasticsearch {
+    fn name(&self) -> &'static str {
+        "elasticsearch"
+    }
+
+    async fn upsert(&self, documents: &[SearchDocument]) -> Result<(), SearchError> {
+        let mut lines = Vec::with_capacity(documents.len() * 2);
+        for document in documents {
+            lines.push(json!({ "index": { "_id": document.product_id } }));
+            lines.push(json!(document));
+        }
+        self.bulk(lines).await
+    }
+
+    async fn remove(&self, product_ids: &[Uuid]) -> Result<(), SearchError> {
+        self.bulk(
+            product_ids
+                .iter()
+                .map(|product_id| json!({ "delete": { "_id": product_id } }))
+                .collect(),
+        )
+        .await
+    }
+
+    async fn search(
+        &self,
+        store_id: Uuid,
+        q: &str,
+        offset: i64,
+        limit: i64,
+    ) -> Result<SearchHits, SearchError> {
+        let fields: Vec<String> = self
+            .relevance
+            .weights
+            .iter()
+            .map(|(field, weight)| format!("{field}^{weight}"))
+            .collect();
+        let results: Value = accepted(
+            self.request("/_search")
+                .json(&json!({
+                    "from": offset,
+                    "size": limit,
+                    "_source": false,
+                    "query": { "bool": {
+                        "must": { "multi_match": {
+                            "query": q,
+                            "fields": fields,
+                            "fuzziness": if self.relevance.typo_tolerance { "AUTO" } else { "0" },
+                        } },
+                        "filter": [
+                            { "term": { "store_id.keyword": store_id } },
+                            { "term": { "is_available": true } },
+                        ],
+                    } },
+                }))
+                .send()
+                .await?,
+        )
+        .await?
+        .json()
+        .await?;
+        let product_ids = results["hits"]["hits"]
+            .as_array()
+            .map(|hits| {
+                hits.iter()
+                    .filter_map(|hit| hit["_id"].as_str()?.parse().ok())
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok(SearchHits { product_ids })
+    }
+}
+
+// the external search engine, none unless SEARCH_ENGINE is set. It is
+// meilisearch or elasticsearch, at SEARCH_URL with SEARCH_API_KEY when the
+// engine needs one, holding the products in SEARCH_INDEX (default products).
+// SEARCH_WEIGHTS sets how much a match in each field counts (default
+// "name=3,category=2,description=1") and SEARCH_TYPO_TOLERANCE=false turns
+// off matching misspelt words
+pub fn from_env() -> Option<Arc<dyn SearchEngine>> {
+    let engine = std::env::var("SEARCH_ENGINE")
+        .ok()
+        .filter(|engine| !engine.is_empty())?;
+    let url = std::env::var("SEARCH_URL")
+        .expect("SEARCH_URL must be set with SEARCH_ENGINE")
+        .trim_end_matches('/')
+        .to_string();
+    let api_key = std::env::var("SEARCH_API_KEY")
+        .ok()
+        .filter(|key| !key.is_empty());
+    let index = std::env::var("SEARCH_INDEX").unwrap_or_else(|_| "products".into());
+
+    let weights = std::env::var("SEARCH_WEIGHTS")
+        .unwrap_or_else(|_| "name=3,category=2,description=1".into())
+        .split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| {
+            let (field, weight) = entry
+                .split_once('=')
+                .unwrap_or_else(|| panic!("SEARCH_WEIGHTS entry {entry} must be field=weight"));
+            let field = field.trim();
+            if !["name", "category", "description"].contains(&field) {
+                panic!("SEARCH_WEIGHTS field {field} must be name, category or description");
+            }
+            let weight = weight
+                .trim()
+                .parse()
+                .ok()
+                .filter(|weight| *weight > 0)
+                .unwrap_or_else(|| {
+                    panic!("SEARCH_WEIGHTS weight for {field} must be a positive number")
+                });
+            (field.to_string(), weight)
+        })
+        .collect();
+    let relevance = Relevance {
+        weights,
+        typo_tolerance: std::env::var("SEARCH_TYPO_TOLERANCE")
+            .map_or(true, |value| value != "false"),
+    };
+
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(5))
+        .build()
+        .expect("failed to build the HTTP client");
+    let engine: Arc<dyn SearchEngine> = match engine.to_lowercase().as_str() {
+        "meilisearch" => Arc::new(Meilisearch {
+            client,
+            url,
+            api_key,
+            index,
+            relevance,
+            configured: tokio::sync::OnceCell::new(),
+        }),
+        "elasticsearch" => Arc::new(Elasticsearch {
+            client,
+            url,
+            api_key,
+            index,
+            relevance,
+        }),
+        other => panic!("SEARCH_ENGINE must be meilisearch or elasticsearch, not {other}"),
+    };
+    Some(engine)
+}
+
+// the products as they are now, the ones that have been deleted are missing
+pub async fn documents<'c>(
+    executor: impl PgExecutor<'c>,
+    product_ids: &[Uuid],
+) -> Result<Vec<SearchDocument>, sqlx::Error> {
+    sqlx::query_as!(
+        SearchDocument,
+        r#"
+        SELECT product_id, store_id, name, description, category,
+               is_available IS NOT FALSE as "is_available!"
+        FROM products WHERE product_id = ANY($1)
+        "#,
+        product_ids
+    )
+    .fetch_all(executor)
+    .await
+}
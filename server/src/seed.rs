@@ -0,0 +1,356 @@
+use sqlx::{types::Decimal, PgPool};
+use uuid::Uuid;
+
+use crate::api::{
+    orders::{record_status_event, OrderStatus},
+    products::ProductUnit,
+    users::User,
+};
+
+// sample data for development and demo stores. Every row has a fixed id (or
+// email for users) and is skipped when it exists, so seeding twice changes
+// nothing and seeding an old database only adds what is missing.
+// Seeded accounts log in with the password given
+
+// (email, first name, last name, admin)
+const USERS: [(&str, &str, &str, bool); 4] = [
+    ("admin@example.com", "Ada", "Admin", true),
+    ("alice@example.com", "Alice", "Walker", false),
+    ("bob@example.com", "Bob", "Fischer", false),
+    ("carol@example.com", "Carol", "Nguyen", false),
+];
+
+struct SeedProduct {
+    id: u128,
+    name: &'static str,
+    description: &'static str,
+    category: &'static str,
+    price: &'static str,
+    stock: &'static str,
+    unit: ProductUnit,
+    quantity_step: &'static str,
+    weight_kg: Option<&'static str>,
+}
+
+const PRODUCTS: [SeedProduct; 8] = [
+    SeedProduct {
+        id: 0x5eed_0001,
+        name: "Ferris Plush",
+        description: "Soft 30 cm crab, the mascot every desk needs.",
+        category: "Toys",
+        price: "24.90",
+        stock: "120",
+        unit: ProductUnit::Piece,
+        quantity_step: "1",
+        weight_kg: Some("0.350"),
+    },
+    SeedProduct {
+        id: 0x5eed_0002,
+        name: "Borrow Checker Mug",
+        description: "Stoneware mug, 350 ml. Holds one mutable coffee at a time.",
+        category: "Kitchen",
+        price: "14.50",
+        stock: "80",
+        unit: ProductUnit::Piece,
+        quantity_step: "1",
+        weight_kg: Some("0.420"),
+    },
+    SeedProduct {
+        id: 0x5eed_0003,
+        name: "Oxidized Hoodie",
+        description: "Rust orange heavyweight hoodie, unisex fit.",
+        category: "Apparel",
+        price: "49.00",
+        stock: "45",
+        unit: ProductUnit::Piece,
+        quantity_step: "1",
+        weight_kg: Some("0.800"),
+    },
+    SeedProduct {
+        id: 0x5eed_0004,
+        name: "Mechanical Keyboard",
+        description: "Hot-swappable 75% board with tactile switches.",
+        category: "Electronics",
+        price: "129.00",
+        stock: "15",
+        unit: ProductUnit::Piece,
+        quantity_step: "1",
+        weight_kg: Some("1.100"),
+    },
+    SeedProduct {
+        id: 0x5eed_0005,
+        name: "Sticker Pack",
+        description: "Twelve vinyl stickers, waterproof and laptop safe.",
+        category: "Accessories",
+        price: "6.00",
+        stock: "500",
+        unit: ProductUnit::Piece,
+        quantity_step: "1",
+        weight_kg: Some("0.020"),
+    },
+    SeedProduct {
+        id: 0x5eed_0006,
+        name: "Single Origin Coffee Beans",
+        description: "Medium roast from Huila, Colombia, sold by weight.",
+        category: "Groceries",
+        price: "32.00",
+        stock: "25",
+        unit: ProductUnit::Kg,
+        quantity_step: "0.25",
+        weight_kg: None,
+    },
+    SeedProduct {
+        id: 0x5eed_0007,
+        name: "Cold Brew Concentrate",
+        description: "Ready to dilute 1:3, sold by the liter.",
+        category: "Groceries",
+        price: "12.00",
+        stock: "40",
+        unit: ProductUnit::Liter,
+        quantity_step: "0.5",
+        weight_kg: Some("1.050"),
+    },
+    SeedProduct {
+        id: 0x5eed_0008,
+        name: "Rust in Action (Paperback)",
+        description: "A hands-on introduction to systems programming in Rust.",
+        category: "Books",
+        price: "39.99",
+        stock: "0",
+        unit: ProductUnit::Piece,
+        quantity_step: "1",
+        weight_kg: Some("0.900"),
+    },
+];
+
+// (product, quantity) lines of a seeded cart or order
+type Lines = &'static [(u128, &'static str)];
+
+// (cart id, customer, lines)
+const CARTS: [(u128, &str, Lines); 2] = [
+    (
+        0x5eed_c001,
+        "alice@example.com",
+        &[(0x5eed_0001, "1"), (0x5eed_0005, "3")],
+    ),
+    (
+        0x5eed_c002,
+        "bob@example.com",
+        &[(0x5eed_0006, "0.75"), (0x5eed_0002, "2")],
+    ),
+];
+
+// (order id, customer, status, days ago, lines)
+const ORDERS: [(u128, &str, OrderStatus, i32, Lines); 4] = [
+    (
+        0x5eed_d001,
+        "alice@example.com",
+        OrderStatus::Shipped,
+        21,
+        &[(0x5eed_0003, "1"), (0x5eed_0005, "2")],
+    ),
+    (
+        0x5eed_d002,
+        "alice@example.com",
+        OrderStatus::Confirmed,
+        2,
+        &[(0x5eed_0004, "1")],
+    ),
+    (
+        0x5eed_d003,
+        "bob@example.com",
+        OrderStatus::Shipped,
+        9,
+        &[(0x5eed_0006, "1.5"), (0x5eed_0002, "1")],
+    ),
+    (
+        0x5eed_d004,
+        "carol@example.com",
+        OrderStatus::Cancelled,
+        5,
+        &[(0x5eed_0007, "2")],
+    ),
+];
+
+const ADDRESSES: [(&str, &str, &str); 3] = [
+    (
+        "alice@example.com",
+        "12 Harbour Road, Bristol BS1 4RB",
+        "GB",
+    ),
+    ("bob@example.com", "Kastanienallee 8, 10435 Berlin", "DE"),
+    ("carol@example.com", "45 Rue Oberkampf, 75011 Paris", "FR"),
+];
+
+fn decimal(value: &str) -> Decimal {
+    value.parse().expect("seed data holds valid decimals")
+}
+
+// a stable id per seeded row of a table, the 0x5eed prefix makes them easy to spot
+fn seed_id(id: u128) -> Uuid {
+    Uuid::from_u128(id)
+}
+
+// what the seeded rows of each table came to, new rows only
+#[derive(Default)]
+pub struct Seeded {
+    pub users: u64,
+    pub products: u64,
+    pub carts: u64,
+    pub orders: u64,
+}
+
+pub async fn seed(pool: &PgPool, password: &str) -> Result<Seeded, sqlx::Error> {
+    let mut seeded = Seeded::default();
+
+    let mut user_ids = Vec::with_capacity(USERS.len());
+    for (email, first_name, last_name, admin) in USERS {
+        let (user_id, created) = if admin {
+            User::create_admin(pool, email, first_name, last_name, Some(password)).await?
+        } else {
+            User::create_customer(pool, email, first_name, last_name, password).await?
+        };
+        seeded.users += created as u64;
+        user_ids.push((email, user_id));
+    }
+    let user_id = |email: &str| {
+        user_ids
+            .iter()
+            .find(|(seeded, _)| *seeded == email)
+            .map(|(_, user_id)| *user_id)
+            .expect("seed data only references seeded users")
+    };
+
+    let mut tx = pool.begin().await?;
+
+    for product in &PRODUCTS {
+        seeded.products += sqlx::query!(
+            "INSERT INTO products (product_id, name, description, category, price,
+                stock_quantity, unit, quantity_step, weight_kg)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            ON CONFLICT (product_id) DO NOTHING",
+            seed_id(product.id),
+            product.name,
+            product.description,
+            product.category,
+            decimal(product.price),
+            decimal(product.stock),
+            product.unit as ProductUnit,
+            decimal(product.quantity_step),
+            product.weight_kg.map(decimal)
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    }
+
+    for (cart_id, email, items) in CARTS {
+        let user_id = user_id(email);
+        // a customer who already has an active cart keeps it active
+        let created = sqlx::query!(
+            "INSERT INTO carts (cart_id, user_id, name, is_active)
+            VALUES ($1, $2, 'Cart', NOT EXISTS(SELECT 1 FROM carts WHERE user_id = $2 AND is_active))
+            ON CONFLICT (cart_id) DO NOTHING",
+            seed_id(cart_id),
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if created == 0 {
+            continue;
+        }
+        seeded.carts += 1;
+
+        for (product, quantity) in items {
+            sqlx::query!(
+                "INSERT INTO cart_items (cart_item_id, cart_id, product_id, quantity)
+                VALUES ($1, $2, $3, $4)",
+                Uuid::now_v7(),
+                seed_id(cart_id),
+                seed_id(*product),
+                decimal(quantity)
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+
+    for (order_id, email, status, days_ago, items) in ORDERS {
+        let (_, address, country) = ADDRESSES
+            .iter()
+            .find(|(customer, _, _)| *customer == email)
+            .expect("seed data has an address for every ordering customer");
+        // (product, quantity, unit price)
+        let lines: Vec<(Uuid, Decimal, Decimal)> = items
+            .iter()
+            .map(|(product, quantity)| {
+                let product = PRODUCTS
+                    .iter()
+                    .find(|seeded| seeded.id == *product)
+                    .expect("seed data only orders seeded products");
+                (
+                    seed_id(product.id),
+                    decimal(quantity),
+                    decimal(product.price),
+                )
+            })
+            .collect();
+        let total: Decimal = lines
+            .iter()
+            .map(|(_, quantity, price)| (price * quantity).round_dp(2))
+            .sum();
+
+        let created = sqlx::query!(
+            "INSERT INTO orders (order_id, user_id, status, total_amount, shipping_address,
+                billing_address, shipping_country, order_date, created_at, subtotal_amount,
+                discount_amount, tax_amount, shipping_amount, gift_wrap_amount)
+            VALUES ($1, $2, $3, $4, $5, $5, $6,
+                NOW() - make_interval(days => $7), NOW() - make_interval(days => $7),
+                $4, 0, 0, 0, 0)
+            ON CONFLICT (order_id) DO NOTHING",
+            seed_id(order_id),
+            user_id(email),
+            status.clone() as OrderStatus,
+            total,
+            address,
+            country,
+            days_ago
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if created == 0 {
+            continue;
+        }
+        seeded.orders += 1;
+
+        for (product_id, quantity, price) in lines {
+            sqlx::query!(
+                "INSERT INTO order_details (order_detail_id, order_id, product_id, quantity,
+                    price_per_unit, line_total, product_name)
+                VALUES ($1, $2, $3, $4, $5, $6, (SELECT name FROM products WHERE product_id = $3))",
+                Uuid::now_v7(),
+                seed_id(order_id),
+                product_id,
+                quantity,
+                price,
+                (price * quantity).round_dp(2)
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        record_status_event(
+            &mut *tx,
+            seed_id(order_id),
+            "order.seeded",
+            status,
+            serde_json::json!({}),
+        )
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(seeded)
+}
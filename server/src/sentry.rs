@@ -0,0 +1,102 @@
+use chrono::Utc;
+use reqwest::Url;
+use serde_json::json;
+use uuid::Uuid;
+
+// a server error worth a look, with the request it happened on
+pub struct ErrorReport {
+    pub message: String,
+    pub status: u16,
+    pub method: String,
+    pub path: String,
+    // the route pattern, /api/product/{id} rather than the concrete path
+    pub route: Option<String>,
+    pub user_id: Option<Uuid>,
+    pub request_id: Option<String>,
+}
+
+// sends 5xx responses and handler panics to Sentry when SENTRY_DSN is set.
+// SENTRY_ENVIRONMENT tags the events (production, staging, ...). Events are
+// posted in the background, a Sentry outage never slows down or fails a request
+pub struct Sentry {
+    client: reqwest::Client,
+    endpoint: String,
+    auth: String,
+    environment: Option<String>,
+}
+
+impl Sentry {
+    pub fn from_env() -> Option<Self> {
+        let dsn = std::env::var("SENTRY_DSN")
+            .ok()
+            .filter(|dsn| !dsn.is_empty())?;
+        let dsn = Url::parse(&dsn).unwrap_or_else(|err| panic!("SENTRY_DSN is not valid: {err}"));
+
+        // https://<public key>@<host>[/<path>]/<project id>
+        let key = dsn.username();
+        let (prefix, project) = dsn
+            .path()
+            .trim_end_matches('/')
+            .rsplit_once('/')
+            .filter(|(_, project)| !project.is_empty() && !key.is_empty())
+            .unwrap_or_else(|| panic!("SENTRY_DSN must name a key and a project"));
+        let host = match dsn.port() {
+            Some(port) => format!("{}:{port}", dsn.host_str().unwrap_or_default()),
+            None => dsn.host_str().unwrap_or_default().to_string(),
+        };
+
+        Some(Sentry {
+            client: reqwest::Client::new(),
+            endpoint: format!("{}://{host}{prefix}/api/{project}/envelope/", dsn.scheme()),
+            auth: format!(
+                "Sentry sentry_version=7, sentry_key={key}, sentry_client=rustacean-market/{}",
+                env!("CARGO_PKG_VERSION")
+            ),
+            environment: std::env::var("SENTRY_ENVIRONMENT").ok(),
+        })
+    }
+
+    pub fn capture(&self, report: ErrorReport) {
+        let event_id = Uuid::new_v4().simple().to_string();
+        let event = json!({
+            "event_id": event_id,
+            "timestamp": Utc::now().to_rfc3339(),
+            "platform": "other",
+            "level": "error",
+            "logger": "http",
+            "release": env!("CARGO_PKG_VERSION"),
+            "environment": self.environment,
+            "transaction": report.route.as_deref().unwrap_or(&report.path),
+            "message": { "formatted": report.message },
+            "request": { "method": report.method, "url": report.path },
+            "user": report.user_id.map(|user_id| json!({ "id": user_id })),
+            "tags": {
+                "status": report.status.to_string(),
+                "request_id": report.request_id,
+            },
+        });
+        // an envelope is a header line followed by item header and payload lines
+        let body = format!(
+            "{}\n{}\n{}\n",
+            json!({ "event_id": event_id }),
+            json!({ "type": "event" }),
+            event
+        );
+
+        let request = self
+            .client
+            .post(&self.endpoint)
+            .header("X-Sentry-Auth", &self.auth)
+            .header("Content-Type", "application/x-sentry-envelope")
+            .body(body);
+        actix_web::rt::spawn(async move {
+            match request.send().await {
+                Ok(res) if !res.status().is_success() => {
+                    println!("sentry rejected an event: {}", res.status())
+                }
+                Ok(_) => {}
+                Err(err) => println!("failed to send an event to sentry: {err:?}"),
+            }
+        });
+    }
+}
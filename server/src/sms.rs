@@ -0,0 +1,219 @@
+use std::{fmt, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use sqlx::PgExecutor;
+use uuid::Uuid;
+
+#[async_trait]
+pub trait SmsSender: Send + Sync {
+    async fn send(&self, to: &str, body: &str) -> Result<(), SmsError>;
+}
+
+#[derive(Debug)]
+pub enum SmsError {
+    Http(reqwest::Error),
+    Rejected(String),
+    Database(sqlx::Error),
+    // SMS_COUNTRIES leaves the number's country out, or it isn't international
+    CountryDisabled,
+    RateLimited,
+}
+
+impl fmt::Display for SmsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SmsError::Http(err) => write!(f, "{err}"),
+            SmsError::Rejected(msg) => write!(f, "text rejected: {msg}"),
+            SmsError::Database(err) => write!(f, "{err}"),
+            SmsError::CountryDisabled => write!(f, "texts are not sent to this phone number"),
+            SmsError::RateLimited => write!(f, "too many texts sent to this phone number"),
+        }
+    }
+}
+
+impl From<reqwest::Error> for SmsError {
+    fn from(err: reqwest::Error) -> Self {
+        SmsError::Http(err)
+    }
+}
+
+impl From<sqlx::Error> for SmsError {
+    fn from(err: sqlx::Error) -> Self {
+        SmsError::Database(err)
+    }
+}
+
+#[derive(Deserialize)]
+struct TwilioError {
+    message: String,
+}
+
+// Twilio's Messages API, TWILIO_FROM is the sending number or the id (MG...)
+// of a messaging service
+pub struct Twilio {
+    client: reqwest::Client,
+    account_sid: String,
+    auth_token: String,
+    from: String,
+}
+
+impl Twilio {
+    fn from_env() -> Self {
+        let required = |name: &str| {
+            std::env::var(name).unwrap_or_else(|_| panic!("{name} must be set for twilio"))
+        };
+        Twilio {
+            client: reqwest::Client::new(),
+            account_sid: required("TWILIO_ACCOUNT_SID"),
+            auth_token: required("TWILIO_AUTH_TOKEN"),
+            from: required("TWILIO_FROM"),
+        }
+    }
+}
+
+#[async_trait]
+impl SmsSender for Twilio {
+    async fn send(&self, to: &str, body: &str) -> Result<(), SmsError> {
+        let from = if self.from.starts_with("MG") {
+            "MessagingServiceSid"
+        } else {
+            "From"
+        };
+        let res = self
+            .client
+            .post(format!(
+                "https://api.twilio.com/2010-04-01/Accounts/{}/Messages.json",
+                self.account_sid
+            ))
+            .basic_auth(&self.account_sid, Some(&self.auth_token))
+            .form(&[("To", to), (from, self.from.as_str()), ("Body", body)])
+            .send()
+            .await?;
+        if res.status().is_success() {
+            return Ok(());
+        }
+        // 4xx are about the message or the number, trying again won't help
+        if res.status().is_client_error() {
+            let status = res.status();
+            let message = match res.json::<TwilioError>().await {
+                Ok(error) => error.message,
+                Err(_) => status.to_string(),
+            };
+            return Err(SmsError::Rejected(message));
+        }
+        Err(res.error_for_status().unwrap_err().into())
+    }
+}
+
+// prints the texts instead of sending them, for development
+pub struct Console;
+
+#[async_trait]
+impl SmsSender for Console {
+    async fn send(&self, to: &str, body: &str) -> Result<(), SmsError> {
+        println!("sms to {to}: {body}");
+        Ok(())
+    }
+}
+
+// the provider texts go out through and where they may go. SMS_PROVIDER is
+// twilio or console, SMS_COUNTRIES the calling codes texts are sent to
+// ("31,32,49", every country when unset) and SMS_RATE_LIMIT the texts one
+// number gets per seconds (default "5/3600")
+pub struct Sms {
+    sender: Arc<dyn SmsSender>,
+    countries: Vec<String>,
+    limit: i64,
+    window: Duration,
+}
+
+impl Sms {
+    pub fn from_env() -> Option<Self> {
+        let sender: Arc<dyn SmsSender> = match std::env::var("SMS_PROVIDER").ok()?.as_str() {
+            "twilio" => Arc::new(Twilio::from_env()),
+            "console" => Arc::new(Console),
+            other => panic!("unknown SMS_PROVIDER {other}, expected twilio or console"),
+        };
+        let countries = std::env::var("SMS_COUNTRIES")
+            .unwrap_or_default()
+            .split(',')
+            .map(|code| code.trim().trim_start_matches('+').to_string())
+            .filter(|code| !code.is_empty())
+            .collect();
+        let rate_limit = std::env::var("SMS_RATE_LIMIT").unwrap_or_else(|_| "5/3600".to_string());
+        let (limit, window) = rate_limit
+            .split_once('/')
+            .and_then(|(limit, seconds)| Some((limit.parse().ok()?, seconds.parse().ok()?)))
+            .unwrap_or_else(|| panic!("SMS_RATE_LIMIT must look like texts/seconds"));
+        Some(Sms {
+            sender,
+            countries,
+            limit,
+            window: Duration::from_secs(window),
+        })
+    }
+
+    // the number as providers take it (+ and digits), none when texts may
+    // not go there
+    pub fn address(&self, phone: &str) -> Option<String> {
+        let phone: String = phone
+            .chars()
+            .filter(|c| !matches!(c, ' ' | '-' | '.' | '(' | ')'))
+            .collect();
+        let digits = phone.strip_prefix('+')?;
+        if digits.len() < 8 || !digits.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        (self.countries.is_empty() || self.countries.iter().any(|code| digits.starts_with(code)))
+            .then_some(phone)
+    }
+
+    // whether the number got fewer texts than the limit in the last window
+    pub async fn within_limit<'e>(
+        &self,
+        executor: impl PgExecutor<'e>,
+        phone: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let sent = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) as "count!" FROM sms_messages
+            WHERE phone = $1 AND status = 'sent'
+            AND sent_at > NOW() - make_interval(secs => $2)"#,
+            phone,
+            self.window.as_secs_f64()
+        )
+        .fetch_one(executor)
+        .await?;
+        Ok(sent < self.limit)
+    }
+
+    pub async fn send(&self, to: &str, body: &str) -> Result<(), SmsError> {
+        self.sender.send(to, body).await
+    }
+
+    // text a code right away, logged without the code so it counts against
+    // the limit
+    pub async fn send_code(
+        &self,
+        pool: &sqlx::PgPool,
+        user_id: Uuid,
+        phone: &str,
+        code: &str,
+    ) -> Result<(), SmsError> {
+        let to = self.address(phone).ok_or(SmsError::CountryDisabled)?;
+        if !self.within_limit(pool, &to).await? {
+            return Err(SmsError::RateLimited);
+        }
+        self.send(&to, &format!("Your RustaceanMarket code is {code}"))
+            .await?;
+        sqlx::query!(
+            "INSERT INTO sms_messages (user_id, phone, kind, status, attempts, sent_at)
+            VALUES ($1, $2, 'code', 'sent', 1, NOW())",
+            user_id,
+            to
+        )
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+}
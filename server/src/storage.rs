@@ -0,0 +1,251 @@
+use std::{fmt, path::PathBuf, sync::Arc};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use reqwest::{StatusCode, Url};
+use sha2::{Digest, Sha256};
+
+#[derive(Debug)]
+pub enum StorageError {
+    NotFound,
+    Io(std::io::Error),
+    Http(reqwest::Error),
+    // the object store answered with something other than success
+    Status(StatusCode),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::NotFound => write!(f, "object not found"),
+            StorageError::Io(err) => write!(f, "{err}"),
+            StorageError::Http(err) => write!(f, "{err}"),
+            StorageError::Status(status) => write!(f, "object store answered {status}"),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<std::io::Error> for StorageError {
+    fn from(err: std::io::Error) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::NotFound => StorageError::NotFound,
+            _ => StorageError::Io(err),
+        }
+    }
+}
+
+impl From<reqwest::Error> for StorageError {
+    fn from(err: reqwest::Error) -> Self {
+        StorageError::Http(err)
+    }
+}
+
+// lets model code mix storage calls and queries under one error
+impl From<StorageError> for sqlx::Error {
+    fn from(err: StorageError) -> Self {
+        match err {
+            StorageError::NotFound => sqlx::Error::RowNotFound,
+            StorageError::Io(err) => sqlx::Error::Io(err),
+            err => sqlx::Error::Io(std::io::Error::other(err)),
+        }
+    }
+}
+
+// where files live: product images and their sizes, review photos and
+// anything else written by the server. Keys look like paths, products/x/y.jpg
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn put(&self, key: &str, bytes: Vec<u8>, content_type: &str) -> Result<(), StorageError>;
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StorageError>;
+
+    // deleting a missing object is not an error
+    async fn delete(&self, key: &str) -> Result<(), StorageError>;
+}
+
+// STORAGE_BACKEND selects local (default) or s3
+pub fn from_env() -> Arc<dyn Storage> {
+    match std::env::var("STORAGE_BACKEND")
+        .unwrap_or_else(|_| "local".into())
+        .to_lowercase()
+        .as_str()
+    {
+        "local" => Arc::new(LocalStorage::from_env()),
+        "s3" => Arc::new(S3Storage::from_env()),
+        other => panic!("unknown STORAGE_BACKEND: {other}"),
+    }
+}
+
+// files under STORAGE_ROOT, for development and single server setups.
+// MEDIA_BASE_URL has to serve its products/ and reviews/ folders. Without it
+// the old MEDIA_ROOT (default ./media) is used, so images uploaded before
+// storage was configurable stay where they are
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    fn from_env() -> Self {
+        LocalStorage {
+            root: std::env::var("STORAGE_ROOT")
+                .or_else(|_| std::env::var("MEDIA_ROOT"))
+                .unwrap_or_else(|_| "media".into())
+                .into(),
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(
+        &self,
+        key: &str,
+        bytes: Vec<u8>,
+        _content_type: &str,
+    ) -> Result<(), StorageError> {
+        let path = self.root.join(key);
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        Ok(tokio::fs::write(path, bytes).await?)
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StorageError> {
+        Ok(tokio::fs::read(self.root.join(key)).await?)
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        match tokio::fs::remove_file(self.root.join(key))
+            .await
+            .map_err(StorageError::from)
+        {
+            Err(StorageError::NotFound) => Ok(()),
+            result => result,
+        }
+    }
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+// a bucket on S3 or anything speaking its API (MinIO, R2, Spaces), requests are
+// signed with SigV4. Configured with S3_ENDPOINT, S3_BUCKET, S3_REGION
+// (default us-east-1), S3_ACCESS_KEY_ID and S3_SECRET_ACCESS_KEY, the bucket is
+// addressed path style so any endpoint works
+pub struct S3Storage {
+    client: reqwest::Client,
+    endpoint: Url,
+    bucket: String,
+    region: String,
+    access_key_id: String,
+    secret_access_key: String,
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+pub fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("hmac takes any key length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+impl S3Storage {
+    fn from_env() -> Self {
+        let var = |name: &str| std::env::var(name).unwrap_or_else(|_| panic!("{name} must be set"));
+        S3Storage {
+            client: reqwest::Client::new(),
+            endpoint: var("S3_ENDPOINT")
+                .parse()
+                .expect("S3_ENDPOINT is not a url"),
+            bucket: var("S3_BUCKET"),
+            region: std::env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".into()),
+            access_key_id: var("S3_ACCESS_KEY_ID"),
+            secret_access_key: var("S3_SECRET_ACCESS_KEY"),
+        }
+    }
+
+    // a signed request for an object, keys only hold characters that need no
+    // escaping (see media::valid_object_key) so the path is already canonical
+    fn request(&self, method: reqwest::Method, key: &str, body: &[u8]) -> reqwest::RequestBuilder {
+        let path = format!("/{}/{}", self.bucket, key);
+        let mut url = self.endpoint.clone();
+        url.set_path(&path);
+        let host = match url.port() {
+            Some(port) => format!("{}:{port}", url.host_str().unwrap_or_default()),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = hex(&Sha256::digest(body));
+        let scope = format!("{date}/{}/s3/aws4_request", self.region);
+
+        let canonical_request = format!(
+            "{method}\n{path}\n\nhost:{host}\nx-amz-content-sha256:{payload_hash}\nx-amz-date:{amz_date}\n\nhost;x-amz-content-sha256;x-amz-date\n{payload_hash}"
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let signing_key = [self.region.as_str(), "s3", "aws4_request"]
+            .into_iter()
+            .fold(
+                hmac(format!("AWS4{}", self.secret_access_key).as_bytes(), &date),
+                |key, part| hmac(&key, part),
+            );
+        let signature = hex(&hmac(&signing_key, &string_to_sign));
+
+        self.client
+            .request(method, url)
+            .header("x-amz-date", amz_date)
+            .header("x-amz-content-sha256", payload_hash)
+            .header(
+                "authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={signature}",
+                    self.access_key_id
+                ),
+            )
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn put(&self, key: &str, bytes: Vec<u8>, content_type: &str) -> Result<(), StorageError> {
+        let response = self
+            .request(reqwest::Method::PUT, key, &bytes)
+            .header("content-type", content_type)
+            .body(bytes)
+            .send()
+            .await?;
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            status => Err(StorageError::Status(status)),
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StorageError> {
+        let response = self.request(reqwest::Method::GET, key, &[]).send().await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Err(StorageError::NotFound),
+            status if status.is_success() => Ok(response.bytes().await?.to_vec()),
+            status => Err(StorageError::Status(status)),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        let response = self
+            .request(reqwest::Method::DELETE, key, &[])
+            .send()
+            .await?;
+        match response.status() {
+            status if status.is_success() || status == StatusCode::NOT_FOUND => Ok(()),
+            status => Err(StorageError::Status(status)),
+        }
+    }
+}
@@ -0,0 +1,277 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::http::header::HeaderMap;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+
+use crate::{
+    carriers::decode_hex,
+    money::{Currency, Money},
+    payments::{
+        Charge, ChargeRequest, ChargeStatus, DisputeEvent, DisputeStatus, PaymentError,
+        PaymentProvider, WebhookEvent,
+    },
+};
+
+// header Stripe signs webhook bodies in
+const SIGNATURE_HEADER: &str = "Stripe-Signature";
+
+// webhooks older than this are rejected as replays
+const SIGNATURE_TOLERANCE_SECS: i64 = 300;
+
+#[derive(Deserialize)]
+struct PaymentIntent {
+    id: String,
+    client_secret: Option<String>,
+    status: String,
+    last_payment_error: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct Refund {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct Dispute {
+    id: String,
+    payment_intent: Option<String>,
+    amount: i64,
+    currency: String,
+    reason: Option<String>,
+    status: String,
+    evidence_details: Option<EvidenceDetails>,
+}
+
+#[derive(Deserialize)]
+struct EvidenceDetails {
+    due_by: Option<i64>,
+}
+
+// the part of a webhook event we act on, data.object is a payment intent or a dispute
+#[derive(Deserialize)]
+struct Event {
+    #[serde(rename = "type")]
+    kind: String,
+    data: EventData,
+}
+
+#[derive(Deserialize)]
+struct EventData {
+    object: serde_json::Value,
+}
+
+impl Dispute {
+    fn into_event(self) -> Result<DisputeEvent, PaymentError> {
+        let currency = Currency::parse(&self.currency)
+            .ok_or_else(|| PaymentError::Malformed(format!("currency {}", self.currency)))?;
+        let status = match self.status.as_str() {
+            "won" => DisputeStatus::Won,
+            "lost" => DisputeStatus::Lost,
+            "under_review" | "warning_under_review" => DisputeStatus::UnderReview,
+            "warning_closed" => DisputeStatus::Closed,
+            _ => DisputeStatus::NeedsResponse,
+        };
+        Ok(DisputeEvent {
+            provider_ref: self.id,
+            charge_ref: self.payment_intent,
+            order_id: None,
+            // in the smallest unit, which isn't cents for every currency
+            amount: Money::from_minor_units(self.amount, currency).amount(),
+            currency: currency.to_string(),
+            reason: self.reason,
+            status,
+            evidence_due_by: self
+                .evidence_details
+                .and_then(|details| details.due_by)
+                .and_then(|due_by| DateTime::<Utc>::from_timestamp(due_by, 0)),
+        })
+    }
+}
+
+// Stripe payment intents, configured with STRIPE_SECRET_KEY and STRIPE_WEBHOOK_SECRET
+pub struct Stripe {
+    client: reqwest::Client,
+    secret_key: String,
+    webhook_secret: String,
+}
+
+impl Stripe {
+    pub fn from_env() -> Option<Self> {
+        let secret_key = std::env::var("STRIPE_SECRET_KEY").ok()?;
+        Some(Stripe {
+            client: reqwest::Client::new(),
+            secret_key,
+            webhook_secret: std::env::var("STRIPE_WEBHOOK_SECRET")
+                .expect("STRIPE_WEBHOOK_SECRET must be set"),
+        })
+    }
+
+    async fn retrieve_intent(&self, intent_id: &str) -> Result<PaymentIntent, reqwest::Error> {
+        self.client
+            .get(format!(
+                "https://api.stripe.com/v1/payment_intents/{intent_id}"
+            ))
+            .basic_auth(&self.secret_key, None::<&str>)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    }
+
+    // check the "t=...,v1=..." signature header against the raw body
+    fn verify_signature(&self, body: &[u8], header: &str) -> bool {
+        let mut timestamp = None;
+        let mut signatures = Vec::new();
+        for part in header.split(',') {
+            match part.trim().split_once('=') {
+                Some(("t", value)) => timestamp = value.parse::<i64>().ok(),
+                Some(("v1", value)) => signatures.push(value),
+                _ => {}
+            }
+        }
+        let Some(timestamp) = timestamp else {
+            return false;
+        };
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_default();
+        if (now - timestamp).abs() > SIGNATURE_TOLERANCE_SECS {
+            return false;
+        }
+
+        signatures.into_iter().any(|signature| {
+            let Some(signature) = decode_hex(signature) else {
+                return false;
+            };
+            let mut mac = Hmac::<Sha256>::new_from_slice(self.webhook_secret.as_bytes())
+                .expect("invalid webhook secret");
+            mac.update(timestamp.to_string().as_bytes());
+            mac.update(b".");
+            mac.update(body);
+            mac.verify_slice(&signature).is_ok()
+        })
+    }
+}
+
+#[async_trait]
+impl PaymentProvider for Stripe {
+    fn name(&self) -> &'static str {
+        "stripe"
+    }
+
+    async fn create_charge(&self, request: &ChargeRequest<'_>) -> Result<Charge, PaymentError> {
+        let amount = request.amount.minor_units().to_string();
+        let order_id = request.order_id.to_string();
+        let intent: PaymentIntent = self
+            .client
+            .post("https://api.stripe.com/v1/payment_intents")
+            .basic_auth(&self.secret_key, None::<&str>)
+            // the order id keeps a retried request from opening a second intent
+            .header("Idempotency-Key", &order_id)
+            .form(&[
+                ("amount", amount.as_str()),
+                ("currency", request.amount.currency().code()),
+                ("metadata[order_id]", order_id.as_str()),
+                ("metadata[billing_address]", request.billing_address),
+                ("metadata[shipping_address]", request.shipping_address),
+                ("automatic_payment_methods[enabled]", "true"),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(Charge {
+            provider_ref: intent.id,
+            client_secret: intent.client_secret,
+            approval_url: None,
+        })
+    }
+
+    async fn confirm_charge(&self, provider_ref: &str) -> Result<ChargeStatus, PaymentError> {
+        let intent = self.retrieve_intent(provider_ref).await?;
+        Ok(match intent.status.as_str() {
+            "succeeded" => ChargeStatus::Succeeded,
+            "canceled" => ChargeStatus::Failed,
+            // a new intent waits for a payment method too, only a declined attempt is a failure
+            "requires_payment_method" if intent.last_payment_error.is_some() => {
+                ChargeStatus::Failed
+            }
+            _ => ChargeStatus::Pending,
+        })
+    }
+
+    async fn cancel_charge(&self, provider_ref: &str) -> Result<(), PaymentError> {
+        self.client
+            .post(format!(
+                "https://api.stripe.com/v1/payment_intents/{provider_ref}/cancel"
+            ))
+            .basic_auth(&self.secret_key, None::<&str>)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    async fn refund(
+        &self,
+        provider_ref: &str,
+        amount: Money,
+        idempotency_key: &str,
+    ) -> Result<String, PaymentError> {
+        let amount = amount.minor_units().to_string();
+        let refund: Refund = self
+            .client
+            .post("https://api.stripe.com/v1/refunds")
+            .basic_auth(&self.secret_key, None::<&str>)
+            .header("Idempotency-Key", idempotency_key)
+            .form(&[
+                ("payment_intent", provider_ref),
+                ("amount", amount.as_str()),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(refund.id)
+    }
+
+    async fn parse_webhook(
+        &self,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> Result<Option<WebhookEvent>, PaymentError> {
+        let signature = headers
+            .get(SIGNATURE_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        if !self.verify_signature(body, signature) {
+            return Err(PaymentError::InvalidSignature);
+        }
+
+        let event: Event =
+            serde_json::from_slice(body).map_err(|err| PaymentError::Malformed(err.to_string()))?;
+        match event.kind.as_str() {
+            "payment_intent.succeeded" | "payment_intent.payment_failed" => {
+                let intent: PaymentIntent = serde_json::from_value(event.data.object)
+                    .map_err(|err| PaymentError::Malformed(err.to_string()))?;
+                Ok(Some(WebhookEvent::Charge(intent.id)))
+            }
+            kind if kind.starts_with("charge.dispute.") => {
+                let dispute: Dispute = serde_json::from_value(event.data.object)
+                    .map_err(|err| PaymentError::Malformed(err.to_string()))?;
+                Ok(Some(WebhookEvent::Dispute(dispute.into_event()?)))
+            }
+            _ => Ok(None),
+        }
+    }
+}
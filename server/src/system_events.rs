@@ -0,0 +1,19 @@
+use serde_json::Value;
+use sqlx::PgExecutor;
+
+// note something that went wrong on its own for the staff activity feed,
+// like a webhook that failed and will be sent again
+pub async fn record<'c>(
+    executor: impl PgExecutor<'c>,
+    event_type: &str,
+    details: Value,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO system_events (event_type, details) VALUES ($1, $2)",
+        event_type,
+        details
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,238 @@
+use std::{
+    fmt,
+    future::Future,
+    time::{Duration, SystemTime},
+};
+
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::HeaderMap,
+    middleware::Next,
+    HttpMessage,
+};
+use opentelemetry::{
+    global,
+    propagation::Extractor,
+    trace::{FutureExt, Span, SpanKind, Status, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator, runtime, trace::TracerProvider, Resource,
+};
+use tracing::{
+    field::{Field, Visit},
+    subscriber::Interest,
+    Event, Metadata, Subscriber,
+};
+use tracing_subscriber::{layer::SubscriberExt, Layer, Registry};
+
+use crate::request_id::RequestId;
+
+const TRACER: &str = "rustacean-market";
+
+// traces go out over OTLP/HTTP when OTEL_EXPORTER_OTLP_ENDPOINT is set, e.g.
+// http://localhost:4318 for a collector, Jaeger or Tempo. OTEL_SERVICE_NAME names
+// the service (default rustacean-market), the other OTEL_EXPORTER_OTLP_* variables
+// work as usual. Every request is a server span continuing the trace of its
+// traceparent header, the queries and payment provider calls made while serving
+// it are its children. Keep the provider to flush the last spans on shutdown
+pub fn init() -> Option<TracerProvider> {
+    std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT")
+        .ok()
+        .filter(|endpoint| !endpoint.is_empty())?;
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .build()
+        .unwrap_or_else(|err| panic!("OTEL_EXPORTER_OTLP_ENDPOINT is not usable: {err}"));
+    let resource = match std::env::var("OTEL_SERVICE_NAME") {
+        Ok(_) => Resource::default(),
+        Err(_) => {
+            Resource::default().merge(&Resource::new([KeyValue::new("service.name", TRACER)]))
+        }
+    };
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::TokioCurrentThread)
+        .with_resource(resource)
+        .build();
+
+    global::set_tracer_provider(provider.clone());
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    // sqlx reports every query it ran as a tracing event, those become the database spans
+    tracing::subscriber::set_global_default(Registry::default().with(QuerySpans))
+        .expect("a tracing subscriber is already installed");
+    Some(provider)
+}
+
+struct RequestHeaders<'a>(&'a HeaderMap);
+
+impl Extractor for RequestHeaders<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+// the server span of a request, named after its route once routing found one
+pub async fn trace(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let parent = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&RequestHeaders(req.headers()))
+    });
+    let method = req.method().to_string();
+    let mut attributes = vec![
+        KeyValue::new("http.request.method", method.clone()),
+        KeyValue::new("url.path", req.path().to_string()),
+    ];
+    if let Some(RequestId(id)) = req.extensions().get::<RequestId>() {
+        attributes.push(KeyValue::new("request_id", id.clone()));
+    }
+
+    let tracer = global::tracer(TRACER);
+    let span = tracer
+        .span_builder(method.clone())
+        .with_kind(SpanKind::Server)
+        .with_attributes(attributes)
+        .start_with_context(&tracer, &parent);
+    let cx = parent.with_span(span);
+
+    let res = next.call(req).with_context(cx.clone()).await;
+    let span = cx.span();
+    match &res {
+        Ok(res) => {
+            if let Some(route) = res.request().match_pattern() {
+                span.update_name(format!("{method} {route}"));
+                span.set_attribute(KeyValue::new("http.route", route));
+            }
+            let status = res.status();
+            span.set_attribute(KeyValue::new(
+                "http.response.status_code",
+                i64::from(status.as_u16()),
+            ));
+            if status.is_server_error() {
+                span.set_status(Status::error(status.to_string()));
+            }
+        }
+        Err(err) => span.set_status(Status::error(err.to_string())),
+    }
+    span.end();
+    res
+}
+
+// a call to another service made while serving a request, as a client span
+pub async fn client_span<T, E: fmt::Display>(
+    name: String,
+    attributes: Vec<KeyValue>,
+    call: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    let tracer = global::tracer(TRACER);
+    let span = tracer
+        .span_builder(name)
+        .with_kind(SpanKind::Client)
+        .with_attributes(attributes)
+        .start(&tracer);
+    let cx = Context::current_with_span(span);
+
+    let result = call.with_context(cx.clone()).await;
+    if let Err(err) = &result {
+        cx.span().set_status(Status::error(err.to_string()));
+    }
+    cx.span().end();
+    result
+}
+
+// turns the event sqlx logs after each query into a span that ended just now.
+// Only queries run on behalf of a traced request are kept, the background jobs
+// would otherwise each start a trace of their own every minute
+struct QuerySpans;
+
+const QUERY_TARGET: &str = "sqlx::query";
+
+impl<S: Subscriber> Layer<S> for QuerySpans {
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        if metadata.target() == QUERY_TARGET {
+            Interest::always()
+        } else {
+            Interest::never()
+        }
+    }
+
+    fn enabled(
+        &self,
+        metadata: &Metadata<'_>,
+        _: tracing_subscriber::layer::Context<'_, S>,
+    ) -> bool {
+        metadata.target() == QUERY_TARGET
+    }
+
+    fn on_event(&self, event: &Event<'_>, _: tracing_subscriber::layer::Context<'_, S>) {
+        let parent = Context::current();
+        if !parent.has_active_span() {
+            return;
+        }
+        let mut query = Query::default();
+        event.record(&mut query);
+
+        let end = SystemTime::now();
+        let start = end - Duration::from_secs_f64(query.elapsed_secs);
+        let summary = query.summary.trim_end_matches(" …").to_string();
+        let statement = match query.statement.trim() {
+            "" => summary.clone(),
+            statement => statement.to_string(),
+        };
+        let tracer = global::tracer(TRACER);
+        let mut span = tracer
+            .span_builder(summary)
+            .with_kind(SpanKind::Client)
+            .with_start_time(start)
+            .with_attributes([
+                KeyValue::new("db.system", "postgresql"),
+                KeyValue::new("db.query.text", statement),
+                KeyValue::new("db.response.returned_rows", query.rows_returned as i64),
+                KeyValue::new("db.response.affected_rows", query.rows_affected as i64),
+            ])
+            .start_with_context(&tracer, &parent);
+        span.end_with_timestamp(end);
+    }
+}
+
+#[derive(Default)]
+struct Query {
+    summary: String,
+    statement: String,
+    rows_returned: u64,
+    rows_affected: u64,
+    elapsed_secs: f64,
+}
+
+impl Visit for Query {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "summary" => self.summary = value.to_string(),
+            "db.statement" => self.statement = value.to_string(),
+            _ => {}
+        }
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        match field.name() {
+            "rows_returned" => self.rows_returned = value,
+            "rows_affected" => self.rows_affected = value,
+            _ => {}
+        }
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        if field.name() == "elapsed_secs" {
+            self.elapsed_secs = value;
+        }
+    }
+
+    fn record_debug(&mut self, _: &Field, _: &dyn fmt::Debug) {}
+}
@@ -0,0 +1,141 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+// VAT number prefixes of the EU member states, Greece uses EL
+const EU_COUNTRIES: [&str; 27] = [
+    "AT", "BE", "BG", "CY", "CZ", "DE", "DK", "EE", "EL", "ES", "FI", "FR", "HR", "HU", "IE", "IT",
+    "LT", "LU", "LV", "MT", "NL", "PL", "PT", "RO", "SE", "SI", "SK",
+];
+
+// what the validator answered, kept on the order as evidence for the zero VAT
+#[derive(Serialize)]
+pub struct VatCheck {
+    pub vat_number: String,
+    pub valid: bool,
+    pub name: Option<String>,
+    pub address: Option<String>,
+    // the validator's reference for this consultation, if it gives one
+    pub consultation_ref: Option<String>,
+    pub checked_at: DateTime<Utc>,
+}
+
+#[async_trait]
+pub trait VatValidator: Send + Sync {
+    async fn validate(&self, country: &str, number: &str) -> Result<VatCheck, reqwest::Error>;
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ViesResponse {
+    valid: bool,
+    name: Option<String>,
+    address: Option<String>,
+    request_identifier: Option<String>,
+}
+
+// the European Commission's VIES service, a requester VAT number makes it
+// return a consultation number that proves the check was made
+pub struct Vies {
+    client: reqwest::Client,
+    requester: Option<(String, String)>,
+}
+
+impl Vies {
+    pub fn new(requester: Option<(String, String)>) -> Self {
+        Vies {
+            client: reqwest::Client::new(),
+            requester,
+        }
+    }
+}
+
+#[async_trait]
+impl VatValidator for Vies {
+    async fn validate(&self, country: &str, number: &str) -> Result<VatCheck, reqwest::Error> {
+        let mut request = json!({ "countryCode": country, "vatNumber": number });
+        if let Some((requester_country, requester_number)) = &self.requester {
+            request["requesterMemberStateCode"] = json!(requester_country);
+            request["requesterNumber"] = json!(requester_number);
+        }
+
+        let response: ViesResponse = self
+            .client
+            .post("https://ec.europa.eu/taxation_customs/vies/rest-api/check-vat-number")
+            .json(&request)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        // VIES answers "---" for details a member state doesn't share
+        let shared = |value: Option<String>| value.filter(|value| value.trim() != "---");
+        Ok(VatCheck {
+            vat_number: format!("{country}{number}"),
+            valid: response.valid,
+            name: shared(response.name),
+            address: shared(response.address),
+            consultation_ref: response.request_identifier.filter(|id| !id.is_empty()),
+            checked_at: Utc::now(),
+        })
+    }
+}
+
+// reverse charge for cross-border B2B orders within the EU, turned on by
+// VAT_SELLER_NUMBER (the shop's own EU VAT number, its prefix is the seller country)
+#[derive(Clone)]
+pub struct ReverseCharge {
+    validator: Arc<dyn VatValidator>,
+    seller_country: String,
+}
+
+impl ReverseCharge {
+    pub fn from_env() -> Option<Self> {
+        let seller = std::env::var("VAT_SELLER_NUMBER").ok()?;
+        let (seller_country, seller_number) =
+            parse_vat_number(&seller).expect("VAT_SELLER_NUMBER must be an EU VAT number");
+        let vies = Vies::new(Some((seller_country.clone(), seller_number)));
+        Some(ReverseCharge::new(seller_country, Arc::new(vies)))
+    }
+
+    // reverse charge for a seller in the member state, buyers' numbers go to
+    // the validator
+    pub fn new(seller_country: String, validator: Arc<dyn VatValidator>) -> Self {
+        ReverseCharge {
+            validator,
+            seller_country,
+        }
+    }
+
+    // buyers registered in another member state pay no VAT
+    pub fn applies(&self, buyer_country: &str) -> bool {
+        buyer_country != self.seller_country && EU_COUNTRIES.contains(&buyer_country)
+    }
+
+    pub async fn validate(&self, vat_number: &str) -> Result<VatCheck, reqwest::Error> {
+        let (country, number) = parse_vat_number(vat_number).unwrap_or_default();
+        self.validator.validate(&country, &number).await
+    }
+}
+
+// split "NL 1234.56.789 B01" into ("NL", "123456789B01"), none when it isn't
+// shaped like an EU VAT number
+pub fn parse_vat_number(input: &str) -> Option<(String, String)> {
+    let cleaned: String = input
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_uppercase();
+    let country = cleaned.get(..2)?;
+    let number = cleaned.get(2..)?;
+    // GR is the ISO code, VIES only knows EL
+    let country = if country == "GR" { "EL" } else { country };
+    if !EU_COUNTRIES.contains(&country) || !(2..=12).contains(&number.len()) {
+        return None;
+    }
+    Some((country.to_string(), number.to_string()))
+}
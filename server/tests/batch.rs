@@ -0,0 +1,122 @@
+mod common;
+
+use actix_web::http::Method;
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+use common::{request, send};
+
+#[sqlx::test(migrations = false)]
+async fn batches_run_in_order_and_atomic_ones_all_or_nothing(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let mug = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+    let plush = common::product(&app, &admin, "Ferris Plush", "24.90", 1).await;
+    let poster = common::product(&app, &admin, "Lifetime Poster", "9.99", 5).await;
+
+    let requests = json!([
+        {"method": "POST", "path": "/api/cart-items", "body": {"product_id": mug, "quantity": 2}},
+        {"method": "POST", "path": "/api/cart-items", "body": {"product_id": plush, "quantity": 3}},
+        {"method": "POST", "path": "/api/cart-items", "body": {"product_id": poster, "quantity": 1}},
+    ]);
+    let statuses = |batch: &Value| -> Vec<u64> {
+        batch["data"]["results"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|result| result["status"].as_u64().unwrap())
+            .collect()
+    };
+
+    // all or nothing, the plush is short of stock so nothing is added
+    let (status, batch): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/batch",
+            Some(&customer),
+            Some(json!({"atomic": true, "requests": requests})),
+        ),
+    )
+    .await;
+    assert_eq!(status, 200, "{batch}");
+    assert_eq!(batch["data"]["committed"], false);
+    assert_eq!(statuses(&batch), vec![201, 409, 424]);
+    assert_eq!(batch["data"]["cart"]["items"].as_array().unwrap().len(), 0);
+
+    // otherwise every request that can succeed does
+    let (status, batch): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/batch",
+            Some(&customer),
+            Some(json!({"requests": requests})),
+        ),
+    )
+    .await;
+    assert_eq!(status, 200, "{batch}");
+    assert_eq!(batch["data"]["committed"], true);
+    assert_eq!(statuses(&batch), vec![201, 409, 201]);
+    assert!(batch["data"]["results"][1]["body"]
+        .as_str()
+        .unwrap()
+        .contains("Not enough stock"));
+    // each answers what its route would, adding a product the cart's items
+    // right after it
+    let added = |index: usize| {
+        batch["data"]["results"][index]["body"]
+            .as_array()
+            .unwrap()
+            .len()
+    };
+    assert_eq!((added(0), added(2)), (1, 2));
+    let items = batch["data"]["cart"]["items"].as_array().unwrap();
+    assert_eq!(items.len(), 2);
+    assert_eq!(batch["data"]["cart"]["subtotal"], "38.99");
+
+    // routes that can't be batched are turned down before anything runs
+    let (status, _): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/batch",
+            Some(&customer),
+            Some(json!({"requests": [
+                {"method": "POST", "path": "/api/cart-items", "body": {"product_id": mug, "quantity": 1}},
+                {"method": "POST", "path": "/api/checkout"},
+            ]})),
+        ),
+    )
+    .await;
+    assert_eq!(status, 400);
+
+    // a bundle quantity that isn't positive is refused like a product's
+    let (status, batch): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/batch",
+            Some(&customer),
+            Some(json!({"requests": [
+                {"method": "POST", "path": "/api/cart-bundles", "body": {"bundle_id": mug, "quantity": 0}},
+                {"method": "POST", "path": "/api/cart-items/00000000-0000-0000-0000-000000000000/save-for-later"},
+            ]})),
+        ),
+    )
+    .await;
+    assert_eq!(status, 200, "{batch}");
+    assert_eq!(statuses(&batch), vec![422, 404]);
+    assert_eq!(
+        batch["data"]["results"][0]["body"][0]["code"],
+        "invalid_quantity"
+    );
+
+    let (_, cart): (u16, Value) = send(
+        &app,
+        request(Method::GET, "/api/carts", Some(&customer), None),
+    )
+    .await;
+    assert_eq!(cart["data"]["subtotal"], "38.99");
+}
@@ -0,0 +1,272 @@
+mod common;
+
+use actix_web::http::Method;
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use common::{request, send};
+
+#[sqlx::test(migrations = false)]
+async fn suggestions_go_by_what_is_in_the_cart_not_what_is_saved(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let buyer = common::customer(&app, "buyer@example.com").await;
+    let shopper = common::customer(&app, "shopper@example.com").await;
+    let mug = common::product(&app, &admin, "Mug", "12.00", 10).await;
+    let tea = common::product(&app, &admin, "Tea", "6.00", 10).await;
+
+    // (cart item id, product id) of what the customer's cart holds
+    let add = |token: String, product_id: Uuid| {
+        let app = &app;
+        async move {
+            let (status, items): (u16, Value) = send(
+                app,
+                request(
+                    Method::POST,
+                    "/api/cart-items",
+                    Some(&token),
+                    Some(json!({ "product_id": product_id, "quantity": "1" })),
+                ),
+            )
+            .await;
+            assert_eq!(status, 201, "{items}");
+            items["data"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|item| {
+                    (
+                        item["cart_item_id"].as_str().unwrap().to_string(),
+                        item["product_id"].as_str().unwrap().to_string(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        }
+    };
+
+    // the mug and the tea were bought together once
+    add(buyer.clone(), mug).await;
+    add(buyer.clone(), tea).await;
+    let (placed, _): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/checkout",
+            Some(&buyer),
+            Some(json!({
+                "shipping_address": "Dam 1, 1012 JS Amsterdam",
+                "shipping_country": "NL",
+                "payment_provider": "cod",
+            })),
+        ),
+    )
+    .await;
+    assert_eq!(placed, 201);
+
+    add(shopper.clone(), mug).await;
+    let items = add(shopper.clone(), tea).await;
+    let save = |product_id: Uuid| {
+        let (item, _) = items
+            .iter()
+            .find(|(_, product)| *product == product_id.to_string())
+            .unwrap();
+        request(
+            Method::POST,
+            &format!("/api/cart-items/{item}/save-for-later"),
+            Some(&shopper),
+            None,
+        )
+    };
+    let suggested = || async {
+        let (status, suggestions): (u16, Value) = send(
+            &app,
+            request(Method::GET, "/api/carts/suggestions", Some(&shopper), None),
+        )
+        .await;
+        assert_eq!(status, 200, "{suggestions}");
+        suggestions["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|suggestion| suggestion["name"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+
+    // the tea saved for later is still worth suggesting next to the mug
+    let (saved, _): (u16, Value) = send(&app, save(tea)).await;
+    assert_eq!(saved, 200);
+    assert_eq!(suggested().await, ["Tea"]);
+
+    // and a mug saved for later doesn't bring suggestions of its own
+    let (saved, _): (u16, Value) = send(&app, save(mug)).await;
+    assert_eq!(saved, 200);
+    assert!(suggested().await.is_empty());
+}
+
+#[sqlx::test(migrations = false)]
+async fn bundle_lines_add_up_to_the_bundle_price(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let mug = common::product(&app, &admin, "Mug", "10.00", 10).await;
+    let tea = common::product(&app, &admin, "Tea", "10.00", 10).await;
+    let spoon = common::product(&app, &admin, "Spoon", "10.00", 10).await;
+
+    // a third of 10.00 each doesn't split into cents
+    let (created, bundle): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/admin/bundles",
+            Some(&admin),
+            Some(json!({
+                "name": "Tea time",
+                "price": "10.00",
+                "items": [
+                    { "product_id": mug, "quantity": 1 },
+                    { "product_id": tea, "quantity": 1 },
+                    { "product_id": spoon, "quantity": 1 },
+                ],
+            })),
+        ),
+    )
+    .await;
+    assert_eq!(created, 201, "{bundle}");
+
+    let (added, cart): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/cart-bundles",
+            Some(&customer),
+            Some(json!({ "bundle_id": bundle["data"]["bundle_id"], "quantity": 2 })),
+        ),
+    )
+    .await;
+    assert_eq!(added, 201, "{cart}");
+    let mut prices: Vec<String> = cart["data"]["items"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|item| item["product_price"].as_str().unwrap().to_string())
+        .collect();
+    prices.sort();
+    assert_eq!(prices, ["3.33", "3.33", "3.34"]);
+    assert_eq!(cart["data"]["subtotal"], "20.00");
+}
+
+#[sqlx::test(migrations = false)]
+async fn quantity_steps_are_explained_in_the_unit(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let mug = common::product(&app, &admin, "Mug", "12.00", 10).await;
+
+    let (added, body): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/cart-items",
+            Some(&customer),
+            Some(json!({ "product_id": mug, "quantity": "1.5" })),
+        ),
+    )
+    .await;
+    assert_eq!(added, 422, "{body}");
+    assert_eq!(
+        body["errors"][0]["message"],
+        "quantity must be a multiple of 1 piece"
+    );
+}
+
+#[sqlx::test(migrations = false)]
+async fn idle_carts_keep_saved_items_and_named_carts(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let saver = common::customer(&app, "saver@example.com").await;
+    let browser = common::customer(&app, "browser@example.com").await;
+    let planner = common::customer(&app, "planner@example.com").await;
+    let mug = common::product(&app, &admin, "Mug", "12.00", 10).await;
+    let tea = common::product(&app, &admin, "Tea", "6.00", 10).await;
+
+    let add = |token: String, product_id: Uuid| {
+        let app = &app;
+        async move {
+            let (status, items): (u16, Value) = send(
+                app,
+                request(
+                    Method::POST,
+                    "/api/cart-items",
+                    Some(&token),
+                    Some(json!({ "product_id": product_id, "quantity": "1" })),
+                ),
+            )
+            .await;
+            assert_eq!(status, 201, "{items}");
+            items["data"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|item| item["product_id"] == product_id.to_string())
+                .unwrap()["cart_item_id"]
+                .as_str()
+                .unwrap()
+                .to_string()
+        }
+    };
+
+    add(saver.clone(), mug).await;
+    let tea_item = add(saver.clone(), tea).await;
+    let (saved, body): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            &format!("/api/cart-items/{tea_item}/save-for-later"),
+            Some(&saver),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(saved, 200, "{body}");
+
+    add(browser.clone(), mug).await;
+
+    let (created, body): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/carts",
+            Some(&planner),
+            Some(json!({ "name": "Birthday", "activate": true })),
+        ),
+    )
+    .await;
+    assert_eq!(created, 201, "{body}");
+    add(planner.clone(), tea).await;
+
+    sqlx::query("UPDATE carts SET updated_at = NOW() - INTERVAL '60 days'")
+        .execute(&pool)
+        .await
+        .unwrap();
+    let (carts, items) = server::jobs::cleanup_carts(&pool, 30).await.unwrap();
+    assert_eq!((carts, items), (1, 2));
+
+    // what is left in each customer's carts, by email
+    let left = sqlx::query_as::<_, (String, i64)>(
+        "SELECT u.email, COUNT(i.cart_item_id) FROM carts c
+        JOIN users u ON u.user_id = c.user_id
+        LEFT JOIN cart_items i ON i.cart_id = c.cart_id
+        GROUP BY u.email ORDER BY u.email",
+    )
+    .fetch_all(&pool)
+    .await
+    .unwrap();
+    assert_eq!(
+        left,
+        vec![
+            ("planner@example.com".to_string(), 1),
+            ("saver@example.com".to_string(), 1),
+        ]
+    );
+}
@@ -0,0 +1,162 @@
+mod common;
+
+use actix_web::{
+    http::{header, Method},
+    test,
+};
+use prost::Message;
+use serde_json::Value;
+use sqlx::PgPool;
+
+use common::{request, send};
+
+// the parts of proto/catalog.proto the tests look at
+#[derive(Clone, PartialEq, Message)]
+struct Product {
+    #[prost(string, tag = "1")]
+    product_id: String,
+    #[prost(string, tag = "2")]
+    name: String,
+    #[prost(string, tag = "5")]
+    price: String,
+    #[prost(string, tag = "6")]
+    stock_quantity: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct CatalogSync {
+    #[prost(int64, tag = "1")]
+    synced_at: i64,
+    #[prost(bool, tag = "2")]
+    full: bool,
+    #[prost(message, repeated, tag = "3")]
+    products: Vec<Product>,
+    #[prost(string, repeated, tag = "4")]
+    deleted_product_ids: Vec<String>,
+}
+
+#[sqlx::test(migrations = false)]
+async fn the_catalog_syncs_in_full_and_by_delta(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let mug = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+    let plush = common::product(&app, &admin, "Ferris Plush", "24.90", 5).await;
+    let poster = common::product(&app, &admin, "Lifetime Poster", "9.99", 3).await;
+
+    let sync = |path: String| {
+        test::TestRequest::get()
+            .uri(&path)
+            .insert_header((header::AUTHORIZATION, format!("Bearer {admin}")))
+            .insert_header((header::ACCEPT, "application/x-protobuf"))
+            .to_request()
+    };
+
+    let response = test::call_service(&app, sync("/api/admin/catalog/sync".into())).await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.headers().get(header::CONTENT_TYPE).unwrap(),
+        "application/x-protobuf"
+    );
+    let full = CatalogSync::decode(test::read_body(response).await).unwrap();
+    assert!(full.full);
+    assert_eq!(full.products.len(), 3);
+    let synced_mug = full
+        .products
+        .iter()
+        .find(|product| product.product_id == mug.to_string())
+        .unwrap();
+    assert_eq!(synced_mug.name, "Borrow Checker Mug");
+    assert_eq!(synced_mug.price, "14.50");
+    assert_eq!(synced_mug.stock_quantity, "10");
+
+    // the last sync was long enough ago that nothing falls in the overlap
+    sqlx::query("ALTER TABLE products DISABLE TRIGGER products_touch")
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query("UPDATE products SET updated_at = NOW() - INTERVAL '1 hour'")
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query("ALTER TABLE products ENABLE TRIGGER products_touch")
+        .execute(&pool)
+        .await
+        .unwrap();
+    let since = full.synced_at - 30 * 60 * 1000;
+
+    sqlx::query("UPDATE products SET price = 12.00 WHERE product_id = $1")
+        .bind(mug)
+        .execute(&pool)
+        .await
+        .unwrap();
+    let (status, _): (u16, Value) = send(
+        &app,
+        request(
+            Method::DELETE,
+            &format!("/api/product/{plush}"),
+            Some(&admin),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(status, 200);
+
+    let response =
+        test::call_service(&app, sync(format!("/api/admin/catalog/sync?since={since}"))).await;
+    assert_eq!(response.status(), 200);
+    let delta = CatalogSync::decode(test::read_body(response).await).unwrap();
+    assert!(!delta.full);
+    assert_eq!(delta.products.len(), 1);
+    assert_eq!(delta.products[0].product_id, mug.to_string());
+    assert_eq!(delta.products[0].price, "12.00");
+    assert_eq!(delta.deleted_product_ids, vec![plush.to_string()]);
+    assert!(!delta
+        .products
+        .iter()
+        .any(|product| product.product_id == poster.to_string()));
+
+    // without the protobuf Accept the same sync is JSON
+    let (status, json): (u16, Value) = send(
+        &app,
+        request(
+            Method::GET,
+            &format!("/api/admin/catalog/sync?since={since}"),
+            Some(&admin),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(status, 200, "{json}");
+    assert_eq!(json["data"]["products"][0]["name"], "Borrow Checker Mug");
+    assert_eq!(json["data"]["deleted_product_ids"][0], plush.to_string());
+
+    let (status, _): (u16, Value) = send(
+        &app,
+        request(
+            Method::GET,
+            "/api/admin/catalog/sync",
+            Some(&customer),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(status, 403);
+}
+
+#[sqlx::test(migrations = false)]
+async fn the_catalog_schema_is_published(pool: PgPool) {
+    let app = common::app(&pool).await;
+
+    let response = test::call_service(
+        &app,
+        test::TestRequest::get()
+            .uri("/api/catalog/sync.proto")
+            .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), 200);
+    let schema = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+    assert!(schema.contains("package rustacean_market.catalog.v1;"));
+    assert!(schema.contains("message CatalogSync"));
+}
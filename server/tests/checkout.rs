@@ -0,0 +1,1137 @@
+mod common;
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use actix_web::http::Method;
+use async_trait::async_trait;
+use chrono::{Datelike, Utc};
+use serde_json::{json, Value};
+use server::vat::{VatCheck, VatValidator};
+use sqlx::{types::Decimal, PgPool};
+use uuid::Uuid;
+
+use common::{request, send, status};
+
+async fn stock(pool: &PgPool, product_id: Uuid) -> Decimal {
+    sqlx::query_scalar("SELECT stock_quantity FROM products WHERE product_id = $1")
+        .bind(product_id)
+        .fetch_one(pool)
+        .await
+        .expect("product exists")
+}
+
+fn cash_on_delivery() -> Value {
+    json!({
+        "shipping_address": "Dam 1, 1012 JS Amsterdam",
+        "shipping_country": "NL",
+        "shipping_postcode": "1012 JS",
+        "payment_provider": "cod",
+    })
+}
+
+// a validator that knows every number, as the company it names
+#[derive(Default)]
+struct KnownNumbers {
+    checks: AtomicUsize,
+}
+
+#[async_trait]
+impl VatValidator for KnownNumbers {
+    async fn validate(&self, country: &str, number: &str) -> Result<VatCheck, reqwest::Error> {
+        self.checks.fetch_add(1, Ordering::SeqCst);
+        Ok(VatCheck {
+            vat_number: format!("{country}{number}"),
+            valid: true,
+            name: Some("Ferris GmbH".into()),
+            address: None,
+            consultation_ref: Some("WAPIAAAAW1234567".into()),
+            checked_at: Utc::now(),
+        })
+    }
+}
+
+#[sqlx::test(migrations = false)]
+async fn cart_checks_out_into_an_order(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let product_id = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+
+    let (status, cart): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/cart-items",
+            Some(&customer),
+            Some(json!({ "product_id": product_id, "quantity": "2" })),
+        ),
+    )
+    .await;
+    assert_eq!(status, 201, "adding to the cart: {cart}");
+
+    let (status, placed): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/checkout",
+            Some(&customer),
+            Some(cash_on_delivery()),
+        ),
+    )
+    .await;
+    assert_eq!(status, 201, "checking out: {placed}");
+    assert_eq!(placed["data"]["payment"]["provider"], "cod");
+
+    let (status, orders): (u16, Value) = send(
+        &app,
+        request(Method::GET, "/api/orders", Some(&customer), None),
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(orders["meta"]["pagination"]["total"], 1);
+    assert_eq!(
+        orders["data"][0]["order_id"],
+        placed["data"]["order"]["order_id"]
+    );
+
+    assert_eq!(stock(&pool, product_id).await, Decimal::from(8));
+}
+
+#[sqlx::test(migrations = false)]
+async fn checkout_is_pre_filled_from_the_last_order(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let product_id = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+
+    let defaults = || async {
+        let (status, defaults): (u16, Value) = send(
+            &app,
+            request(Method::GET, "/api/checkout/defaults", Some(&customer), None),
+        )
+        .await;
+        assert_eq!(status, 200, "{defaults}");
+        defaults["data"].clone()
+    };
+    let first = defaults().await;
+    assert_eq!(first["email"], "customer@example.com");
+    assert_eq!(first["phone"], "+3100000000");
+    assert_eq!(first["shipping_address"], Value::Null);
+
+    let (added, _): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/cart-items",
+            Some(&customer),
+            Some(json!({ "product_id": product_id, "quantity": "1" })),
+        ),
+    )
+    .await;
+    assert_eq!(added, 201);
+    let (placed, _): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/checkout",
+            Some(&customer),
+            Some(cash_on_delivery()),
+        ),
+    )
+    .await;
+    assert_eq!(placed, 201);
+
+    let next = defaults().await;
+    assert_eq!(next["shipping_address"], "Dam 1, 1012 JS Amsterdam");
+    assert_eq!(next["billing_address"], "Dam 1, 1012 JS Amsterdam");
+    assert_eq!(next["shipping_postcode"], "1012 JS");
+    assert_eq!(next["country"], "NL");
+    assert_eq!(next["currency"], "EUR");
+}
+
+#[sqlx::test(migrations = false)]
+async fn empty_carts_cant_check_out(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let checkout = || {
+        request(
+            Method::POST,
+            "/api/checkout",
+            Some(&customer),
+            Some(cash_on_delivery()),
+        )
+    };
+
+    // before a cart was started, and with a cart that holds nothing
+    assert_eq!(status(&app, checkout()).await, 400);
+    let opened = status(
+        &app,
+        request(Method::GET, "/api/carts", Some(&customer), None),
+    )
+    .await;
+    assert_eq!(opened, 200);
+    assert_eq!(status(&app, checkout()).await, 400);
+}
+
+#[sqlx::test(migrations = false)]
+async fn checkout_never_sells_more_than_in_stock(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let first = common::customer(&app, "first@example.com").await;
+    let second = common::customer(&app, "second@example.com").await;
+    let product_id = common::product(&app, &admin, "Mechanical Keyboard", "129.00", 1).await;
+
+    for customer in [&first, &second] {
+        let (status, cart): (u16, Value) = send(
+            &app,
+            request(
+                Method::POST,
+                "/api/cart-items",
+                Some(customer),
+                Some(json!({ "product_id": product_id, "quantity": "1" })),
+            ),
+        )
+        .await;
+        assert_eq!(status, 201, "adding to the cart: {cart}");
+    }
+
+    let (status, _): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/checkout",
+            Some(&first),
+            Some(cash_on_delivery()),
+        ),
+    )
+    .await;
+    assert_eq!(status, 201);
+
+    let (status, _): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/checkout",
+            Some(&second),
+            Some(cash_on_delivery()),
+        ),
+    )
+    .await;
+    assert_eq!(status, 409);
+    assert_eq!(stock(&pool, product_id).await, Decimal::ZERO);
+}
+
+#[sqlx::test(migrations = false)]
+async fn checkout_leaves_its_events_in_the_outbox(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let product_id = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+
+    let status = status(
+        &app,
+        request(
+            Method::POST,
+            "/api/cart-items",
+            Some(&customer),
+            Some(json!({ "product_id": product_id, "quantity": "2" })),
+        ),
+    )
+    .await;
+    assert_eq!(status, 201);
+    let (status, placed): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/checkout",
+            Some(&customer),
+            Some(cash_on_delivery()),
+        ),
+    )
+    .await;
+    assert_eq!(status, 201, "checking out: {placed}");
+
+    let events: Vec<(String, Value)> = sqlx::query_as(
+        "SELECT event_type, payload FROM outbox_events
+        WHERE published_at IS NULL ORDER BY created_at, event_id",
+    )
+    .fetch_all(&pool)
+    .await
+    .unwrap();
+    // the customer signed up through the API, consumers only get the id
+    let (_, registered) = events
+        .iter()
+        .find(|(t, _)| t == "user.registered")
+        .expect("user.registered is recorded");
+    assert_eq!(registered.as_object().unwrap().len(), 1, "{registered}");
+    assert!(registered["user_id"].is_string());
+
+    let (_, order) = events
+        .iter()
+        .find(|(t, _)| t == "order.created")
+        .expect("order.created is recorded");
+    assert_eq!(order["order_id"], placed["data"]["order"]["order_id"]);
+
+    let (_, stock) = events
+        .iter()
+        .find(|(t, _)| t == "stock.changed")
+        .expect("stock.changed is recorded");
+    assert_eq!(stock["product_id"], product_id.to_string());
+    assert_eq!(stock["previous_stock_quantity"], 10.0);
+    assert_eq!(stock["stock_quantity"], 8.0);
+}
+
+#[sqlx::test(migrations = false)]
+async fn totals_round_half_away_from_zero_to_the_cent(pool: PgPool) {
+    let app = common::app_with(&pool, |state| state.with_tax_rate(Decimal::new(21, 2))).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let product_id = common::product(&app, &admin, "Ferris Sticker", "1.25", 10).await;
+
+    let added = status(
+        &app,
+        request(
+            Method::POST,
+            "/api/cart-items",
+            Some(&customer),
+            Some(json!({ "product_id": product_id, "quantity": "2" })),
+        ),
+    )
+    .await;
+    assert_eq!(added, 201);
+
+    // 21% of 2.50 is 0.525
+    let (status, preview): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/checkout/preview",
+            Some(&customer),
+            Some(json!({ "shipping_country": "NL" })),
+        ),
+    )
+    .await;
+    assert_eq!(status, 200, "{preview}");
+    let preview = &preview["data"];
+    assert_eq!(preview["subtotal"], "2.50");
+    assert_eq!(preview["tax"], "0.53");
+    assert_eq!(preview["discount"], "0.00");
+    assert_eq!(preview["total"], "3.03");
+}
+
+#[sqlx::test(migrations = false)]
+async fn postcode_ranges_compare_as_numbers(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+
+    // as text "10000" falls between "1000" and "9999" and would go to the first
+    for (name, from, to, priority, rate) in [
+        ("Four digits", "1000", "9999", 1, "5.00"),
+        ("Five digits", "10000", "99999", 0, "9.00"),
+    ] {
+        let (created, zone): (u16, Value) = send(
+            &app,
+            request(
+                Method::POST,
+                "/api/admin/shipping-zones",
+                Some(&admin),
+                Some(json!({
+                    "name": name,
+                    "country": "US",
+                    "postcode_from": from,
+                    "postcode_to": to,
+                    "priority": priority,
+                    "rates": [{ "method": "standard", "rate": rate }],
+                })),
+            ),
+        )
+        .await;
+        assert_eq!(created, 201, "{zone}");
+    }
+
+    for (postcode, rate) in [
+        ("10000", Some("9.00")),
+        ("9000", Some("5.00")),
+        ("999", None),
+    ] {
+        let (listed, options): (u16, Value) = send(
+            &app,
+            request(
+                Method::GET,
+                &format!("/api/checkout/shipping-options?country=US&postcode={postcode}"),
+                Some(&customer),
+                None,
+            ),
+        )
+        .await;
+        assert_eq!(listed, 200, "{options}");
+        let rates: Vec<&str> = options["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|option| option["rate"].as_str().unwrap())
+            .collect();
+        assert_eq!(rates, Vec::from_iter(rate), "{postcode}");
+    }
+}
+
+#[sqlx::test(migrations = false)]
+async fn pickup_locations_are_offered_nearest_first(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+
+    for (name, coordinates, is_active) in [
+        ("Utrecht", Some((52.0907, 5.1214)), true),
+        ("Amsterdam", Some((52.3731, 4.8922)), true),
+        ("Rotterdam", Some((51.9244, 4.4777)), true),
+        ("Haarlem", Some((52.3874, 4.6462)), false),
+        ("Warehouse", None, true),
+    ] {
+        let mut body = json!({ "name": name, "address": "Main St 1", "is_active": is_active });
+        if let Some((latitude, longitude)) = coordinates {
+            body["latitude"] = json!(latitude);
+            body["longitude"] = json!(longitude);
+        }
+        let (status, location): (u16, Value) = send(
+            &app,
+            request(
+                Method::POST,
+                "/api/admin/pickup-locations",
+                Some(&admin),
+                Some(body),
+            ),
+        )
+        .await;
+        assert_eq!(status, 201, "{location}");
+    }
+
+    // inactive ones, ones too far away and ones without coordinates are left out
+    let (code, nearby): (u16, Value) = send(
+        &app,
+        request(
+            Method::GET,
+            "/api/pickup-locations/nearby?lat=52.37&lng=4.89&radius=50",
+            Some(&customer),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(code, 200, "{nearby}");
+    let nearby = nearby["data"].as_array().unwrap();
+    let names: Vec<&str> = nearby.iter().map(|l| l["name"].as_str().unwrap()).collect();
+    assert_eq!(names, ["Amsterdam", "Utrecht"]);
+    assert!(nearby[0]["distance_km"].as_f64().unwrap() < 1.0);
+    let utrecht = nearby[1]["distance_km"].as_f64().unwrap();
+    assert!((30.0..40.0).contains(&utrecht), "{utrecht}");
+
+    assert_eq!(
+        status(
+            &app,
+            request(
+                Method::GET,
+                "/api/pickup-locations/nearby?lat=95&lng=4.89",
+                Some(&customer),
+                None,
+            ),
+        )
+        .await,
+        400
+    );
+    assert_eq!(
+        status(
+            &app,
+            request(
+                Method::POST,
+                "/api/admin/pickup-locations",
+                Some(&admin),
+                Some(json!({ "name": "Nowhere", "address": "Main St 2", "latitude": 52.0 })),
+            ),
+        )
+        .await,
+        400
+    );
+}
+
+#[sqlx::test(migrations = false)]
+async fn pickup_locations_with_orders_are_kept(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let product_id = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+
+    let (created, location): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/admin/pickup-locations",
+            Some(&admin),
+            Some(json!({ "name": "Amsterdam", "address": "Dam 1" })),
+        ),
+    )
+    .await;
+    assert_eq!(created, 201, "{location}");
+    let location_id = location["data"]["location_id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    let (added, _): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/cart-items",
+            Some(&customer),
+            Some(json!({ "product_id": product_id, "quantity": "1" })),
+        ),
+    )
+    .await;
+    assert_eq!(added, 201);
+    let (placed, order): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/checkout",
+            Some(&customer),
+            Some(json!({
+                "shipping_address": "",
+                "shipping_method": "pickup",
+                "pickup_location_id": location_id,
+                "payment_provider": "cod",
+            })),
+        ),
+    )
+    .await;
+    assert_eq!(placed, 201, "{order}");
+
+    let (deleted, refused): (u16, Value) = send(
+        &app,
+        request(
+            Method::DELETE,
+            &format!("/api/admin/pickup-locations/{location_id}"),
+            Some(&admin),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(deleted, 409, "{refused}");
+}
+
+#[sqlx::test(migrations = false)]
+async fn products_kept_for_a_group_are_hidden_and_unsold_to_the_rest(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let retail = common::customer(&app, "retail@example.com").await;
+    let trader_id = common::register(&app, "trader@example.com").await;
+    let group = |customer_group: &str| {
+        request(
+            Method::PUT,
+            &format!("/api/admin/users/{trader_id}/customer-group"),
+            Some(&admin),
+            Some(json!({ "customer_group": customer_group })),
+        )
+    };
+    assert_eq!(status(&app, group("wholesale")).await, 200);
+    let trader = common::login(&app, "trader@example.com").await;
+    let mug = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+    let pallet = common::product(&app, &admin, "Pallet of Mugs", "400.00", 10).await;
+
+    let (code, visibility): (u16, Value) = send(
+        &app,
+        request(
+            Method::PUT,
+            &format!("/api/admin/products/{pallet}/visibility"),
+            Some(&admin),
+            Some(json!({ "customer_groups": ["wholesale"] })),
+        ),
+    )
+    .await;
+    assert_eq!(code, 200, "{visibility}");
+    assert_eq!(visibility["data"]["customer_groups"], json!(["wholesale"]));
+
+    let listed = |token: &str| {
+        let (app, token) = (&app, token.to_string());
+        async move {
+            let (_, listing): (u16, Value) = send(
+                app,
+                request(Method::GET, "/api/products", Some(&token), None),
+            )
+            .await;
+            listing["meta"]["pagination"]["total"].clone()
+        }
+    };
+    assert_eq!(listed(&retail).await, 1);
+    assert_eq!(listed(&trader).await, 2);
+    assert_eq!(listed(&admin).await, 2);
+    let (_, detail): (u16, Value) = send(
+        &app,
+        request(
+            Method::GET,
+            &format!("/api/product/{pallet}"),
+            Some(&retail),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(detail["data"], "product was not found");
+
+    let add_pallet = || Some(json!({ "product_id": pallet, "quantity": "1" }));
+    assert_eq!(
+        status(
+            &app,
+            request(Method::POST, "/api/cart-items", Some(&retail), add_pallet())
+        )
+        .await,
+        404
+    );
+    // nor can they buy it in a bundle
+    let (code, bundle): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/admin/bundles",
+            Some(&admin),
+            Some(json!({
+                "name": "Shop starter",
+                "price": "410.00",
+                "items": [
+                    { "product_id": mug, "quantity": 1 },
+                    { "product_id": pallet, "quantity": 1 },
+                ],
+            })),
+        ),
+    )
+    .await;
+    assert_eq!(code, 201, "{bundle}");
+    assert_eq!(
+        status(
+            &app,
+            request(
+                Method::POST,
+                "/api/cart-bundles",
+                Some(&retail),
+                Some(json!({ "bundle_id": bundle["data"]["bundle_id"], "quantity": 1 })),
+            )
+        )
+        .await,
+        404
+    );
+    assert_eq!(
+        status(
+            &app,
+            request(Method::POST, "/api/cart-items", Some(&trader), add_pallet())
+        )
+        .await,
+        201
+    );
+
+    // a customer moved out of the group can't check out what they put aside
+    assert_eq!(status(&app, group("retail")).await, 200);
+    let (code, refused): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/checkout",
+            Some(&trader),
+            Some(cash_on_delivery()),
+        ),
+    )
+    .await;
+    assert_eq!(code, 409, "{refused}");
+    assert_eq!(stock(&pool, pallet).await, Decimal::from(10));
+    // or ask for a quote on it
+    sqlx::query("UPDATE users SET vat_number = 'NL123456789B01' WHERE user_id = $1")
+        .bind(trader_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+    let (code, refused): (u16, Value) = send(
+        &app,
+        request(Method::POST, "/api/quotes", Some(&trader), Some(json!({}))),
+    )
+    .await;
+    assert_eq!(code, 409, "{refused}");
+    assert_eq!(
+        refused["detail"],
+        "Pallet of Mugs is not sold to your customer group"
+    );
+
+    // categories are kept the same way
+    sqlx::query("UPDATE products SET category = 'Trade' WHERE product_id = $1")
+        .bind(pallet)
+        .execute(&pool)
+        .await
+        .unwrap();
+    for (uri, body) in [
+        (
+            format!("/api/admin/products/{pallet}/visibility"),
+            json!({ "customer_groups": [] }),
+        ),
+        (
+            "/api/admin/categories/Trade/visibility".to_string(),
+            json!({ "customer_groups": ["wholesale", "vip"] }),
+        ),
+    ] {
+        assert_eq!(
+            status(&app, request(Method::PUT, &uri, Some(&admin), Some(body))).await,
+            200
+        );
+    }
+    assert_eq!(listed(&retail).await, 1);
+    assert_eq!(status(&app, group("vip")).await, 200);
+    assert_eq!(listed(&trader).await, 2);
+}
+
+#[sqlx::test(migrations = false)]
+async fn age_restricted_products_need_a_verified_adult(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer_id = common::register(&app, "customer@example.com").await;
+    let customer = common::login(&app, "customer@example.com").await;
+    let rum = common::product(&app, &admin, "Spiced Rum", "24.00", 10).await;
+    let (code, patched): (u16, Value) = send(
+        &app,
+        request(
+            Method::PATCH,
+            &format!("/api/product/{rum}"),
+            Some(&admin),
+            Some(json!({ "age_restriction": 18 })),
+        ),
+    )
+    .await;
+    assert_eq!(code, 200, "{patched}");
+    assert_eq!(patched["data"]["age_restriction"], 18);
+
+    let add_rum = || {
+        request(
+            Method::POST,
+            "/api/cart-items",
+            Some(&customer),
+            Some(json!({ "product_id": rum, "quantity": "1" })),
+        )
+    };
+    let refused = |code: &'static str| {
+        let app = &app;
+        let add_rum = &add_rum;
+        async move {
+            let (status, violations): (u16, Value) = send(app, add_rum()).await;
+            assert_eq!(status, 422, "{violations}");
+            assert_eq!(violations["errors"][0]["code"], code, "{violations}");
+        }
+    };
+    let date_of_birth = |date: &str| {
+        request(
+            Method::PUT,
+            "/api/users/me/date-of-birth",
+            Some(&customer),
+            Some(json!({ "date_of_birth": date })),
+        )
+    };
+    let verify = || {
+        request(
+            Method::PATCH,
+            &format!("/api/admin/users/{customer_id}"),
+            Some(&admin),
+            Some(json!({ "age_verified": true })),
+        )
+    };
+
+    // a date of birth alone isn't enough, staff have to verify it
+    refused("age_unverified").await;
+    let this_year = Utc::now().year();
+    let minor = format!("{}-01-01", this_year - 16);
+    assert_eq!(status(&app, date_of_birth(&minor)).await, 200);
+    refused("age_unverified").await;
+    assert_eq!(status(&app, verify()).await, 200);
+    refused("age_restricted").await;
+
+    // a new date of birth is verified again
+    let adult = format!("{}-01-01", this_year - 30);
+    let (_, profile): (u16, Value) = send(&app, date_of_birth(&adult)).await;
+    assert_eq!(profile["data"]["age_verified"], false);
+    refused("age_unverified").await;
+    assert_eq!(status(&app, verify()).await, 200);
+    assert_eq!(status(&app, add_rum()).await, 201);
+
+    // and checkout checks again
+    sqlx::query(
+        "UPDATE users SET date_of_birth = CURRENT_DATE - INTERVAL '10 years' WHERE user_id = $1",
+    )
+    .bind(customer_id)
+    .execute(&pool)
+    .await
+    .unwrap();
+    let (code, violations): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/checkout",
+            Some(&customer),
+            Some(cash_on_delivery()),
+        ),
+    )
+    .await;
+    assert_eq!(code, 422, "{violations}");
+    assert_eq!(violations["errors"][0]["code"], "age_restricted");
+}
+
+#[sqlx::test(migrations = false)]
+async fn limited_editions_count_what_the_customer_bought_before(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let sneakers = common::product(&app, &admin, "Ferris Sneakers", "120.00", 10).await;
+    let limit = |limit: u32| {
+        request(
+            Method::PATCH,
+            &format!("/api/product/{sneakers}"),
+            Some(&admin),
+            Some(json!({ "purchase_limit": limit })),
+        )
+    };
+    let (code, patched): (u16, Value) = send(&app, limit(2)).await;
+    assert_eq!(code, 200, "{patched}");
+    assert_eq!(patched["data"]["purchase_limit"], "2.000");
+
+    let add = |quantity: &str| {
+        request(
+            Method::POST,
+            "/api/cart-items",
+            Some(&customer),
+            Some(json!({ "product_id": sneakers, "quantity": quantity })),
+        )
+    };
+    let checkout = || {
+        request(
+            Method::POST,
+            "/api/checkout",
+            Some(&customer),
+            Some(cash_on_delivery()),
+        )
+    };
+    let (code, violations): (u16, Value) = send(&app, add("3")).await;
+    assert_eq!(code, 422, "{violations}");
+    assert_eq!(violations["errors"][0]["code"], "purchase_limit");
+    assert_eq!(status(&app, add("1")).await, 201);
+    assert_eq!(status(&app, checkout()).await, 201);
+
+    // one bought, one more to go
+    let (code, violations): (u16, Value) = send(&app, add("2")).await;
+    assert_eq!(code, 422, "{violations}");
+    assert_eq!(
+        violations["errors"][0]["message"],
+        "Ferris Sneakers is limited to 2 per customer, you can buy 1 more"
+    );
+    assert_eq!(status(&app, add("1")).await, 201);
+
+    // checkout checks again, the limit may have been lowered since
+    assert_eq!(status(&app, limit(1)).await, 200);
+    let (code, violations): (u16, Value) = send(&app, checkout()).await;
+    assert_eq!(code, 422, "{violations}");
+    assert_eq!(violations["errors"][0]["code"], "purchase_limit");
+}
+
+#[sqlx::test(migrations = false)]
+async fn checkouts_at_the_same_time_stay_within_the_limit(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let sneakers = common::product(&app, &admin, "Ferris Sneakers", "120.00", 10).await;
+    let patch = request(
+        Method::PATCH,
+        &format!("/api/product/{sneakers}"),
+        Some(&admin),
+        Some(json!({ "purchase_limit": 1 })),
+    );
+    assert_eq!(status(&app, patch).await, 200);
+    let add = request(
+        Method::POST,
+        "/api/cart-items",
+        Some(&customer),
+        Some(json!({ "product_id": sneakers, "quantity": "1" })),
+    );
+    assert_eq!(status(&app, add).await, 201);
+
+    let checkout = || {
+        request(
+            Method::POST,
+            "/api/checkout",
+            Some(&customer),
+            Some(cash_on_delivery()),
+        )
+    };
+    let (first, second) = tokio::join!(status(&app, checkout()), status(&app, checkout()));
+    let mut codes = [first, second];
+    codes.sort();
+    assert_eq!(codes[0], 201, "{codes:?}");
+    assert_ne!(codes[1], 201, "{codes:?}");
+    let bought: Decimal = sqlx::query_scalar(
+        "SELECT COALESCE(SUM(quantity), 0) FROM order_details WHERE product_id = $1",
+    )
+    .bind(sneakers)
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert_eq!(bought, Decimal::ONE);
+}
+
+#[sqlx::test(migrations = false)]
+async fn reorders_put_back_what_is_still_sold_and_in_stock(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let mug = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+    let kettle = common::product(&app, &admin, "Kettle", "25.00", 10).await;
+    let poster = common::product(&app, &admin, "Lifetime Poster", "9.00", 10).await;
+    for (product_id, quantity) in [(mug, "3"), (kettle, "1"), (poster, "2")] {
+        let add = request(
+            Method::POST,
+            "/api/cart-items",
+            Some(&customer),
+            Some(json!({ "product_id": product_id, "quantity": quantity })),
+        );
+        assert_eq!(status(&app, add).await, 201);
+    }
+    let (code, placed): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/checkout",
+            Some(&customer),
+            Some(cash_on_delivery()),
+        ),
+    )
+    .await;
+    assert_eq!(code, 201, "{placed}");
+    let order_id = placed["data"]["order"]["order_id"].as_str().unwrap();
+
+    // two mugs left and the poster taken off sale
+    let patch = |product_id: Uuid, body: Value| {
+        request(
+            Method::PATCH,
+            &format!("/api/product/{product_id}"),
+            Some(&admin),
+            Some(body),
+        )
+    };
+    assert_eq!(
+        status(&app, patch(mug, json!({ "stock_quantity": 2 }))).await,
+        200
+    );
+    assert_eq!(
+        status(&app, patch(poster, json!({ "is_available": false }))).await,
+        200
+    );
+
+    let reorder = |token: &str| {
+        request(
+            Method::POST,
+            &format!("/api/orders/{order_id}/reorder"),
+            Some(token),
+            None,
+        )
+    };
+    let (code, report): (u16, Value) = send(&app, reorder(&customer)).await;
+    assert_eq!(code, 200, "{report}");
+    let mut in_cart: Vec<(String, f64)> = report["data"]["items"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|item| {
+            (
+                item["product_name"].as_str().unwrap().to_string(),
+                item["quantity"].as_str().unwrap().parse().unwrap(),
+            )
+        })
+        .collect();
+    in_cart.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        in_cart,
+        [
+            ("Borrow Checker Mug".to_string(), 2.0),
+            ("Kettle".to_string(), 1.0)
+        ]
+    );
+    let not_added = &report["data"]["not_added"];
+    assert_eq!(not_added[0]["name"], "Borrow Checker Mug");
+    assert_eq!(not_added[0]["code"], "limited_stock");
+    assert_eq!(not_added[1]["name"], "Lifetime Poster");
+    assert_eq!(not_added[1]["code"], "discontinued");
+
+    // the rest of the stock is already in the cart the second time
+    let (code, report): (u16, Value) = send(&app, reorder(&customer)).await;
+    assert_eq!(code, 200, "{report}");
+    assert_eq!(report["data"]["not_added"][0]["code"], "out_of_stock");
+
+    // nobody else's orders
+    let someone_else = common::customer(&app, "someone@example.com").await;
+    assert_eq!(status(&app, reorder(&someone_else)).await, 404);
+}
+
+#[sqlx::test(migrations = false)]
+async fn reorders_put_back_bundles_as_far_as_the_stock_left_allows(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let mug = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+    let kettle = common::product(&app, &admin, "Kettle", "25.00", 10).await;
+    let (code, bundle): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/admin/bundles",
+            Some(&admin),
+            Some(json!({
+                "name": "Tea time",
+                "price": "35.00",
+                "items": [
+                    { "product_id": mug, "quantity": 1 },
+                    { "product_id": kettle, "quantity": 1 },
+                ],
+            })),
+        ),
+    )
+    .await;
+    assert_eq!(code, 201, "{bundle}");
+    let add_mugs = request(
+        Method::POST,
+        "/api/cart-items",
+        Some(&customer),
+        Some(json!({ "product_id": mug, "quantity": "3" })),
+    );
+    assert_eq!(status(&app, add_mugs).await, 201);
+    let add_bundles = request(
+        Method::POST,
+        "/api/cart-bundles",
+        Some(&customer),
+        Some(json!({ "bundle_id": bundle["data"]["bundle_id"], "quantity": 2 })),
+    );
+    assert_eq!(status(&app, add_bundles).await, 201);
+    let (code, placed): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/checkout",
+            Some(&customer),
+            Some(cash_on_delivery()),
+        ),
+    )
+    .await;
+    assert_eq!(code, 201, "{placed}");
+    let order_id = placed["data"]["order"]["order_id"].as_str().unwrap();
+
+    // four mugs left, the three loose ones go back first
+    let patch = request(
+        Method::PATCH,
+        &format!("/api/product/{mug}"),
+        Some(&admin),
+        Some(json!({ "stock_quantity": 4 })),
+    );
+    assert_eq!(status(&app, patch).await, 200);
+    let reorder = || {
+        request(
+            Method::POST,
+            &format!("/api/orders/{order_id}/reorder"),
+            Some(&customer),
+            None,
+        )
+    };
+    let (code, report): (u16, Value) = send(&app, reorder()).await;
+    assert_eq!(code, 200, "{report}");
+    let not_added = report["data"]["not_added"].as_array().unwrap();
+    assert_eq!(not_added.len(), 1, "{report}");
+    assert_eq!(not_added[0]["name"], "Tea time");
+    assert_eq!(not_added[0]["code"], "limited_stock");
+    assert_eq!(not_added[0]["added"], "1");
+    let quantity = |name: &str| -> f64 {
+        report["data"]["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|item| item["product_name"] == name)
+            .map(|item| item["quantity"].as_str().unwrap().parse::<f64>().unwrap())
+            .sum()
+    };
+    assert_eq!(quantity("Borrow Checker Mug"), 4.0);
+    assert_eq!(quantity("Kettle"), 1.0);
+
+    // a bundle with a product kept from the customer's group isn't sold to them
+    let visibility = request(
+        Method::PUT,
+        &format!("/api/admin/products/{kettle}/visibility"),
+        Some(&admin),
+        Some(json!({ "customer_groups": ["wholesale"] })),
+    );
+    assert_eq!(status(&app, visibility).await, 200);
+    let (code, report): (u16, Value) = send(&app, reorder()).await;
+    assert_eq!(code, 200, "{report}");
+    let not_added = report["data"]["not_added"].as_array().unwrap();
+    assert_eq!(not_added[1]["name"], "Tea time", "{report}");
+    assert_eq!(not_added[1]["code"], "discontinued");
+}
+
+#[sqlx::test(migrations = false)]
+async fn business_buyers_abroad_are_reverse_charged(pool: PgPool) {
+    let validator = Arc::new(KnownNumbers::default());
+    let app = common::app_with(&pool, |state| {
+        state
+            .with_tax_rate(Decimal::new(21, 2))
+            .with_vat_validator("NL", validator.clone())
+    })
+    .await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let product_id = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+
+    let (code, profile): (u16, Value) = send(
+        &app,
+        request(
+            Method::PUT,
+            "/api/users/me/vat",
+            Some(&customer),
+            Some(json!({ "vat_number": "DE 123456789" })),
+        ),
+    )
+    .await;
+    assert_eq!(code, 200, "{profile}");
+    assert_eq!(profile["data"]["vat_company_name"], "Ferris GmbH");
+
+    let added = request(
+        Method::POST,
+        "/api/cart-items",
+        Some(&customer),
+        Some(json!({ "product_id": product_id, "quantity": "1" })),
+    );
+    assert_eq!(status(&app, added).await, 201);
+    let (code, placed): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/checkout",
+            Some(&customer),
+            Some(cash_on_delivery()),
+        ),
+    )
+    .await;
+    assert_eq!(code, 201, "{placed}");
+
+    // checked again at checkout, the answer is kept as evidence
+    assert_eq!(validator.checks.load(Ordering::SeqCst), 2);
+    let (reverse_charge, tax, evidence): (bool, String, Value) = sqlx::query_as(
+        "SELECT reverse_charge, tax_amount::TEXT, vat_evidence FROM orders WHERE order_id = $1::uuid",
+    )
+    .bind(placed["data"]["order"]["order_id"].as_str().unwrap())
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert!(reverse_charge);
+    assert_eq!(tax, "0.00");
+    assert_eq!(
+        evidence["consultation_ref"], "WAPIAAAAW1234567",
+        "{evidence}"
+    );
+}
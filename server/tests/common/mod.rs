@@ -0,0 +1,252 @@
+// shared setup for the integration tests. Each test gets an empty database
+// from #[sqlx::test(migrations = false)] (DATABASE_URL points at the server it
+// is created on), `app` loads the schema and migrations into it and returns the
+// API as it is served, with helpers to register, log in and send requests
+#![allow(dead_code)]
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use actix_http::Request;
+use actix_web::{
+    body::MessageBody,
+    dev::{Service, ServiceResponse},
+    http::{header, Method},
+    test, web, App,
+};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use sqlx::{Executor, PgPool};
+use uuid::Uuid;
+
+use server::{
+    cli, routes,
+    storage::{Storage, StorageError},
+    AppState,
+};
+
+pub const PASSWORD: &str = "correct-horse-42";
+
+// the settings the API can't start without, the same for every test. Cash on
+// delivery is the one payment method that works without a provider account
+fn configure_env() {
+    for (name, value) in [
+        ("HASH_SECRET", "test-hash-secret"),
+        ("JWT_SECRET", "test-jwt-secret"),
+        ("COD_ENABLED", "true"),
+    ] {
+        std::env::set_var(name, value);
+    }
+}
+
+// files kept in memory, so tests leave nothing on disk
+#[derive(Default)]
+pub struct MemoryStorage {
+    pub objects: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn put(
+        &self,
+        key: &str,
+        bytes: Vec<u8>,
+        _content_type: &str,
+    ) -> Result<(), StorageError> {
+        self.objects.lock().unwrap().insert(key.to_string(), bytes);
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StorageError> {
+        self.objects
+            .lock()
+            .unwrap()
+            .get(key)
+            .cloned()
+            .ok_or(StorageError::NotFound)
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.objects.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+pub async fn app(
+    pool: &PgPool,
+) -> impl Service<Request, Response = ServiceResponse<impl MessageBody>, Error = actix_web::Error> {
+    app_with(pool, |state| state).await
+}
+
+// the API with its state adjusted, for settings a test needs of its own
+pub async fn app_with(
+    pool: &PgPool,
+    configure: impl FnOnce(AppState) -> AppState,
+) -> impl Service<Request, Response = ServiceResponse<impl MessageBody>, Error = actix_web::Error> {
+    configure_env();
+    pool.execute(include_str!("schema.sql"))
+        .await
+        .expect("failed to load the base schema");
+    cli::migrate(pool).await;
+
+    test::init_service(
+        App::new()
+            .app_data(web::Data::new(configure(
+                AppState::from_env(pool.clone()).with_storage(Arc::new(MemoryStorage::default())),
+            )))
+            .configure(routes),
+    )
+    .await
+}
+
+// the state with a pool that never connects, for handlers that are given
+// in-memory repositories and don't touch the database
+pub fn state_without_database() -> AppState {
+    configure_env();
+    let pool = PgPool::connect_lazy("postgres://localhost/unused").expect("a valid database url");
+    AppState::from_env(pool).with_storage(Arc::new(MemoryStorage::default()))
+}
+
+// a request with a JSON body, authenticated when given a token
+pub fn request(method: Method, uri: &str, token: Option<&str>, body: Option<Value>) -> Request {
+    let mut request = test::TestRequest::default().method(method).uri(uri);
+    if let Some(token) = token {
+        request = request.insert_header((header::AUTHORIZATION, format!("Bearer {token}")));
+    }
+    if let Some(body) = body {
+        request = request.set_json(body);
+    }
+    request.to_request()
+}
+
+// send a request, the status and the decoded JSON body. Successful responses
+// come in the envelope, their payload is under "data", failures are problem
+// documents
+pub async fn send<S, B, T>(app: &S, request: Request) -> (u16, T)
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+    T: DeserializeOwned,
+{
+    let response = test::call_service(app, request).await;
+    let status = response.status().as_u16();
+    let body = test::read_body(response).await;
+    let body = serde_json::from_slice(&body).unwrap_or_else(|err| {
+        panic!(
+            "{status} response is not the expected JSON ({err}): {}",
+            String::from_utf8_lossy(&body)
+        )
+    });
+    (status, body)
+}
+
+pub async fn register<S, B>(app: &S, email: &str) -> Uuid
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let (status, user): (u16, Value) = send(
+        app,
+        request(
+            Method::POST,
+            "/api/users",
+            None,
+            Some(json!({
+                "first_name": "Test",
+                "last_name": "User",
+                "email": email,
+                "password": PASSWORD,
+                "phone": "+3100000000",
+                "accept_terms": true,
+            })),
+        ),
+    )
+    .await;
+    assert_eq!(status, 200, "registering {email}: {user}");
+    user["data"]["user_id"]
+        .as_str()
+        .and_then(|id| id.parse().ok())
+        .expect("registration returns the user id")
+}
+
+// log in with basic auth, the bearer token for the other requests
+pub async fn login<S, B>(app: &S, email: &str) -> String
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let credentials = STANDARD.encode(format!("{email}:{PASSWORD}"));
+    let request = test::TestRequest::get()
+        .uri("/api/auth")
+        .insert_header((header::AUTHORIZATION, format!("Basic {credentials}")))
+        .to_request();
+    let (status, token): (u16, Value) = send(app, request).await;
+    assert_eq!(status, 200, "logging in {email}");
+    token["data"]
+        .as_str()
+        .expect("logging in returns the token")
+        .to_string()
+}
+
+// a customer account, logged in
+pub async fn customer<S, B>(app: &S, email: &str) -> String
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    register(app, email).await;
+    login(app, email).await
+}
+
+// an admin account made the way operators make one, logged in
+pub async fn admin<S, B>(app: &S, pool: &PgPool, email: &str) -> String
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    cli::create_admin(pool, email, "Test", "Admin", Some(PASSWORD))
+        .await
+        .expect("failed to create admin");
+    login(app, email).await
+}
+
+// a product created through the admin API
+pub async fn product<S, B>(app: &S, admin_token: &str, name: &str, price: &str, stock: u32) -> Uuid
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let (status, product): (u16, Value) = send(
+        app,
+        request(
+            Method::POST,
+            "/api/product",
+            Some(admin_token),
+            Some(json!({
+                "name": name,
+                "description": null,
+                "price": price,
+                "stock_quantity": stock,
+            })),
+        ),
+    )
+    .await;
+    assert!(status < 300, "creating {name}: {status} {product}");
+    product["data"]["product_id"]
+        .as_str()
+        .and_then(|id| id.parse().ok())
+        .expect("product creation returns the product id")
+}
+
+// send a request, only the status matters
+pub async fn status<S, B>(app: &S, request: Request) -> u16
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    test::call_service(app, request).await.status().as_u16()
+}
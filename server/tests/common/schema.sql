@@ -0,0 +1,53 @@
+-- the tables the first migrations build on, they were created by hand before
+-- migrations were kept in the repo. Tests load this into an empty database
+CREATE TYPE user_role AS ENUM ('admin', 'customer');
+CREATE TYPE order_status AS ENUM ('pending', 'confirmed', 'shipped');
+CREATE TABLE users (
+    user_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    first_name VARCHAR(100) NOT NULL,
+    last_name VARCHAR(100) NOT NULL,
+    email VARCHAR(255) NOT NULL,
+    password_hash TEXT NOT NULL,
+    phone VARCHAR(20),
+    role user_role DEFAULT 'customer',
+    created_at TIMESTAMPTZ DEFAULT NOW()
+);
+CREATE TABLE products (
+    product_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    name VARCHAR(255) NOT NULL,
+    description TEXT,
+    price DECIMAL(10, 2) NOT NULL,
+    stock_quantity INTEGER NOT NULL DEFAULT 0,
+    category VARCHAR(100),
+    is_available BOOLEAN DEFAULT TRUE,
+    created_at TIMESTAMPTZ DEFAULT NOW()
+);
+CREATE TABLE carts (
+    cart_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID REFERENCES users(user_id),
+    created_at TIMESTAMPTZ DEFAULT NOW(),
+    updated_at TIMESTAMPTZ DEFAULT NOW()
+);
+CREATE TABLE cart_items (
+    cart_item_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    cart_id UUID REFERENCES carts(cart_id),
+    product_id UUID REFERENCES products(product_id),
+    quantity INTEGER NOT NULL,
+    added_at TIMESTAMPTZ DEFAULT NOW()
+);
+CREATE TABLE orders (
+    order_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(user_id),
+    order_date TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    status order_status NOT NULL DEFAULT 'pending',
+    shipping_address TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    total_amount DECIMAL(10, 2) NOT NULL
+);
+CREATE TABLE order_details (
+    order_detail_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    order_id UUID REFERENCES orders(order_id),
+    product_id UUID REFERENCES products(product_id),
+    quantity INTEGER NOT NULL,
+    price_per_unit DECIMAL(10, 2) NOT NULL
+);
@@ -0,0 +1,194 @@
+mod common;
+
+use actix_web::{http::Method, test};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+use common::{request, send, status};
+
+const WEBHOOK_SECRET: &str = "test-email-webhook-secret";
+
+// an SES notification as SNS posts it, the notification itself is a string
+fn sns_notification(notification: Value) -> test::TestRequest {
+    test::TestRequest::post()
+        .insert_header(("Content-Type", "text/plain; charset=UTF-8"))
+        .set_payload(
+            json!({
+                "Type": "Notification",
+                "MessageId": "5b3c1f2e-0000-4000-8000-000000000000",
+                "Message": notification.to_string(),
+            })
+            .to_string(),
+        )
+}
+
+#[sqlx::test(migrations = false)]
+async fn orders_are_confirmed_by_mail_until_the_address_bounces(pool: PgPool) {
+    std::env::set_var("EMAIL_WEBHOOK_SECRET", WEBHOOK_SECRET);
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let mug = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+
+    let added = status(
+        &app,
+        request(
+            Method::POST,
+            "/api/cart-items",
+            Some(&customer),
+            Some(json!({ "product_id": mug, "quantity": "1" })),
+        ),
+    )
+    .await;
+    assert_eq!(added, 201);
+    let (placed, checkout): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/checkout",
+            Some(&customer),
+            Some(json!({
+                "shipping_address": "Dam 1, 1012 JS Amsterdam",
+                "shipping_country": "NL",
+                "shipping_postcode": "1012 JS",
+                "payment_provider": "cod",
+            })),
+        ),
+    )
+    .await;
+    assert_eq!(placed, 201, "checking out: {checkout}");
+
+    let queued: Vec<(String, Value)> =
+        sqlx::query_as("SELECT kind, data FROM email_messages WHERE status = 'pending'")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+    assert_eq!(queued.len(), 1);
+    assert_eq!(queued[0].0, "order.created");
+    assert_eq!(
+        queued[0].1["order_id"],
+        checkout["data"]["order"]["order_id"]
+    );
+
+    let wrong_token = sns_notification(json!({ "notificationType": "Delivery" }))
+        .uri("/api/webhooks/email/ses?token=guessed")
+        .to_request();
+    assert_eq!(status(&app, wrong_token).await, 401);
+
+    // a mailbox that is full is no reason to stop
+    let soft_bounce = sns_notification(json!({
+        "notificationType": "Bounce",
+        "bounce": {
+            "bounceType": "Transient",
+            "bouncedRecipients": [{ "emailAddress": "customer@example.com" }],
+        },
+    }))
+    .uri(&format!("/api/webhooks/email/ses?token={WEBHOOK_SECRET}"))
+    .to_request();
+    assert_eq!(status(&app, soft_bounce).await, 200);
+
+    let hard_bounce = sns_notification(json!({
+        "notificationType": "Bounce",
+        "bounce": {
+            "bounceType": "Permanent",
+            "bouncedRecipients": [{
+                "emailAddress": "Customer@Example.com",
+                "diagnosticCode": "smtp; 550 5.1.1 user unknown",
+            }],
+        },
+    }))
+    .uri(&format!("/api/webhooks/email/ses?token={WEBHOOK_SECRET}"))
+    .to_request();
+    assert_eq!(status(&app, hard_bounce).await, 200);
+
+    let complaint = test::TestRequest::post()
+        .uri(&format!(
+            "/api/webhooks/email/generic?token={WEBHOOK_SECRET}"
+        ))
+        .set_json(json!({ "type": "complaint", "email": "someone@example.com" }))
+        .to_request();
+    assert_eq!(status(&app, complaint).await, 200);
+
+    let (_, suppressions): (u16, Value) = send(
+        &app,
+        request(
+            Method::GET,
+            "/api/admin/email-suppressions",
+            Some(&admin),
+            None,
+        ),
+    )
+    .await;
+    let mut suppressed: Vec<(String, String)> = suppressions["data"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|s| {
+            (
+                s["email"].as_str().unwrap().to_string(),
+                s["reason"].as_str().unwrap().to_string(),
+            )
+        })
+        .collect();
+    suppressed.sort();
+    assert_eq!(
+        suppressed,
+        vec![
+            ("customer@example.com".to_string(), "bounce".to_string()),
+            ("someone@example.com".to_string(), "complaint".to_string()),
+        ]
+    );
+
+    let forbidden = status(
+        &app,
+        request(
+            Method::DELETE,
+            "/api/admin/email-suppressions/customer@example.com",
+            Some(&customer),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(forbidden, 403);
+    let removed = status(
+        &app,
+        request(
+            Method::DELETE,
+            "/api/admin/email-suppressions/customer@example.com",
+            Some(&admin),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(removed, 200);
+}
+
+// confirmations are only fetched from SNS in the configured region
+#[sqlx::test(migrations = false)]
+async fn subscriptions_are_only_confirmed_with_sns(pool: PgPool) {
+    std::env::set_var("EMAIL_WEBHOOK_SECRET", WEBHOOK_SECRET);
+    std::env::set_var("SNS_REGION", "eu-west-1");
+    let app = common::app(&pool).await;
+
+    for subscribe_url in [
+        "http://sns.eu-west-1.amazonaws.com/?Action=ConfirmSubscription",
+        "https://sns.us-east-1.amazonaws.com/?Action=ConfirmSubscription",
+        "https://sns.eu-west-1.amazonaws.com.example.com/",
+        "https://sns.eu-west-1.amazonaws.com@example.com/",
+        "https://sns.eu-west-1.amazonaws.com:8443/",
+        "https://example.com/?sns.eu-west-1.amazonaws.com",
+    ] {
+        let confirmation = test::TestRequest::post()
+            .uri(&format!("/api/webhooks/email/ses?token={WEBHOOK_SECRET}"))
+            .set_payload(
+                json!({
+                    "Type": "SubscriptionConfirmation",
+                    "Message": "You have chosen to subscribe to the topic",
+                    "SubscribeURL": subscribe_url,
+                })
+                .to_string(),
+            )
+            .to_request();
+        assert_eq!(status(&app, confirmation).await, 400, "{subscribe_url}");
+    }
+}
@@ -0,0 +1,69 @@
+mod common;
+
+use actix_web::{http::Method, test};
+use serde_json::Value;
+use sqlx::PgPool;
+
+use common::{request, send};
+
+async fn store_rates(pool: &PgPool, hours_ago: i32, usd: &str) {
+    sqlx::query(
+        "INSERT INTO exchange_rates (base, currency, rate, provider, fetched_at)
+        SELECT 'EUR', currency, rate::numeric, 'frankfurter', NOW() - make_interval(hours => $1)
+        FROM (VALUES ('USD', $2), ('GBP', '0.8600')) AS rates (currency, rate)",
+    )
+    .bind(hours_ago)
+    .bind(usd)
+    .execute(pool)
+    .await
+    .unwrap();
+}
+
+#[sqlx::test(migrations = false)]
+async fn last_known_rates_are_served_and_flagged_once_stale(pool: PgPool) {
+    std::env::set_var("EXCHANGE_RATES_PROVIDER", "frankfurter");
+    std::env::set_var("EXCHANGE_RATES_BASE", "eur");
+    let app = common::app(&pool).await;
+
+    let rates = || async {
+        let (status, rates): (u16, Value) = send(
+            &app,
+            request(Method::GET, "/api/exchange-rates", None, None),
+        )
+        .await;
+        assert_eq!(status, 200);
+        rates["data"].clone()
+    };
+    let metrics = || async {
+        let response = test::call_service(&app, request(Method::GET, "/metrics", None, None)).await;
+        String::from_utf8(test::read_body(response).await.to_vec()).unwrap()
+    };
+
+    // nothing fetched yet
+    let empty = rates().await;
+    assert_eq!(empty["stale"], true);
+    assert_eq!(empty["rates"], serde_json::json!({}));
+    assert!(metrics()
+        .await
+        .contains("exchange_rates_stale{base=\"EUR\"} 1"));
+
+    // the provider went down after the last fetch two days ago
+    store_rates(&pool, 72, "1.0500").await;
+    store_rates(&pool, 48, "1.0800").await;
+    let old = rates().await;
+    assert_eq!(old["base"], "EUR");
+    assert_eq!(old["stale"], true);
+    assert_eq!(old["rates"]["USD"], "1.08");
+    assert_eq!(old["rates"]["GBP"], "0.86");
+
+    store_rates(&pool, 1, "1.1000").await;
+    let fresh = rates().await;
+    assert_eq!(fresh["stale"], false);
+    assert_eq!(fresh["rates"]["USD"], "1.1");
+    let metrics = metrics().await;
+    assert!(
+        metrics.contains("exchange_rates_stale{base=\"EUR\"} 0"),
+        "{metrics}"
+    );
+    assert!(metrics.contains("exchange_rates_age_seconds{base=\"EUR\"} 36"));
+}
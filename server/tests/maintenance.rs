@@ -0,0 +1,81 @@
+mod common;
+
+use actix_web::{
+    http::{header, Method},
+    test,
+};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+use common::{request, send, status};
+
+#[sqlx::test(migrations = false)]
+async fn maintenance_turns_customers_away_and_lets_admins_work(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "ferris@example.com").await;
+
+    let (code, maintenance): (u16, Value) = send(
+        &app,
+        request(
+            Method::PUT,
+            "/api/admin/maintenance",
+            Some(&admin),
+            Some(json!({"enabled": true, "message": "Back at noon", "retry_after_secs": 120})),
+        ),
+    )
+    .await;
+    assert_eq!(code, 200, "{maintenance}");
+
+    let response = test::call_service(
+        &app,
+        request(Method::GET, "/api/products", Some(&customer), None),
+    )
+    .await;
+    assert_eq!(response.status(), 503);
+    assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "120");
+    let problem: Value = test::read_body_json(response).await;
+    assert_eq!(problem["errors"][0]["code"], "maintenance");
+    assert_eq!(problem["errors"][0]["message"], "Back at noon");
+    assert_eq!(
+        status(&app, request(Method::GET, "/api/products", None, None)).await,
+        503
+    );
+
+    // health checks and admins keep going, logging in included
+    let (code, health): (u16, Value) =
+        send(&app, request(Method::GET, "/health", None, None)).await;
+    assert_eq!(code, 200);
+    assert_eq!(health["maintenance"], true);
+    let admin = common::login(&app, "admin@example.com").await;
+    assert_eq!(
+        status(
+            &app,
+            request(Method::GET, "/api/products", Some(&admin), None)
+        )
+        .await,
+        200
+    );
+
+    assert_eq!(
+        status(
+            &app,
+            request(
+                Method::PUT,
+                "/api/admin/maintenance",
+                Some(&admin),
+                Some(json!({"enabled": false})),
+            )
+        )
+        .await,
+        200
+    );
+    assert_eq!(
+        status(
+            &app,
+            request(Method::GET, "/api/products", Some(&customer), None)
+        )
+        .await,
+        200
+    );
+}
@@ -0,0 +1,95 @@
+mod common;
+
+use actix_web::{
+    http::{header, Method},
+    test,
+};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+use common::{request, send};
+
+#[sqlx::test(migrations = false)]
+async fn consent_is_recorded_and_shown_on_the_profile(pool: PgPool) {
+    let app = common::app(&pool).await;
+
+    let (registered, _): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/users",
+            None,
+            Some(json!({
+                "first_name": "Test",
+                "last_name": "User",
+                "email": "customer@example.com",
+                "password": common::PASSWORD,
+                "phone": "+3100000000",
+                "marketing_consent": true,
+                "accept_terms": true,
+            })),
+        ),
+    )
+    .await;
+    assert_eq!(registered, 200);
+    let customer = common::login(&app, "customer@example.com").await;
+
+    let profile = || async {
+        let (status, profile): (u16, Value) = send(
+            &app,
+            request(Method::GET, "/api/user_info", Some(&customer), None),
+        )
+        .await;
+        assert_eq!(status, 200);
+        profile["data"]["marketing_consent"].clone()
+    };
+    let consent = profile().await;
+    assert_eq!(consent["granted"], true);
+    assert_eq!(consent["source"], "registration");
+    assert_eq!(consent["policy_version"], "1");
+
+    // recorded with the address the connection came from, not one the client claims
+    let (withdrawn, _): (u16, Value) = send(
+        &app,
+        test::TestRequest::put()
+            .uri("/api/users/me/marketing-consent")
+            .peer_addr("192.0.2.10:5000".parse().unwrap())
+            .insert_header(("X-Forwarded-For", "203.0.113.99"))
+            .insert_header((header::AUTHORIZATION, format!("Bearer {customer}")))
+            .set_json(json!({ "granted": false }))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(withdrawn, 200);
+    let consent = profile().await;
+    assert_eq!(consent["granted"], false);
+    assert_eq!(consent["source"], "profile");
+
+    // the history stays, the flag the notifications go by follows the last
+    let history: Vec<(bool, String, Option<String>)> = sqlx::query_as(
+        "SELECT granted, source, ip_address FROM marketing_consents ORDER BY created_at",
+    )
+    .fetch_all(&pool)
+    .await
+    .unwrap();
+    assert_eq!(
+        history,
+        vec![
+            (true, "registration".into(), None),
+            (false, "profile".into(), Some("192.0.2.10".into()))
+        ]
+    );
+    let opted_in: bool = sqlx::query_scalar("SELECT marketing_opt_in FROM users")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert!(!opted_in);
+
+    // without an answer nothing is recorded and nothing is shown
+    common::customer(&app, "silent@example.com").await;
+    let recorded: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM marketing_consents")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(recorded, 2);
+}
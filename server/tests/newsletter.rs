@@ -0,0 +1,121 @@
+mod common;
+
+use actix_web::{http::Method, test};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+use common::{request, status};
+
+#[sqlx::test(migrations = false)]
+async fn subscribers_confirm_by_mail_and_can_leave(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+
+    let subscribe = || {
+        request(
+            Method::POST,
+            "/api/newsletter/subscribe",
+            None,
+            Some(json!({ "email": " Reader@Example.com " })),
+        )
+    };
+    assert_eq!(status(&app, subscribe()).await, 202);
+
+    let mails: Vec<(Option<String>, String, Value)> =
+        sqlx::query_as("SELECT recipient, kind, data FROM email_messages")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+    assert_eq!(mails.len(), 1);
+    assert_eq!(mails[0].0.as_deref(), Some("reader@example.com"));
+    assert_eq!(mails[0].1, "newsletter.confirm");
+    let confirm_url = mails[0].2["confirm_url"].as_str().unwrap();
+    let token = confirm_url
+        .split_once("token=")
+        .map(|(_, token)| token)
+        .unwrap();
+
+    let export = || async {
+        let response = test::call_service(
+            &app,
+            request(
+                Method::GET,
+                "/api/admin/newsletter/subscribers.csv",
+                Some(&admin),
+                None,
+            ),
+        )
+        .await;
+        assert_eq!(response.status(), 200);
+        String::from_utf8(test::read_body(response).await.to_vec()).unwrap()
+    };
+    assert_eq!(
+        export().await.lines().count(),
+        1,
+        "only confirmed subscribers"
+    );
+
+    let guessed = status(
+        &app,
+        request(
+            Method::POST,
+            "/api/newsletter/confirm",
+            None,
+            Some(json!({ "token": "guessed" })),
+        ),
+    )
+    .await;
+    assert_eq!(guessed, 404);
+    // the consent is recorded with the address the connection came from, not
+    // one the client claims
+    let confirmed = status(
+        &app,
+        test::TestRequest::post()
+            .uri("/api/newsletter/confirm")
+            .peer_addr("192.0.2.10:5000".parse().unwrap())
+            .insert_header(("X-Forwarded-For", "203.0.113.99"))
+            .set_json(json!({ "token": token }))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(confirmed, 200);
+
+    // subscribing again answers the same and mails nothing
+    assert_eq!(status(&app, subscribe()).await, 202);
+    let mails: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM email_messages")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(mails, 1);
+
+    let csv = export().await;
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[1].starts_with("reader@example.com,"), "{csv}");
+    assert_eq!(lines[1].split(',').nth(3), Some("192.0.2.10"), "{csv}");
+    let unsubscribe_url = lines[1].rsplit(',').next().unwrap();
+    let unsubscribe_token = unsubscribe_url
+        .split_once("token=")
+        .map(|(_, token)| token)
+        .unwrap();
+
+    let unsubscribe = || {
+        request(
+            Method::POST,
+            "/api/newsletter/unsubscribe",
+            None,
+            Some(json!({ "token": unsubscribe_token })),
+        )
+    };
+    assert_eq!(status(&app, unsubscribe()).await, 200);
+    assert_eq!(status(&app, unsubscribe()).await, 200);
+    assert_eq!(export().await.lines().count(), 1);
+
+    // the consents stay as the record of what was agreed to
+    let consents: Vec<String> =
+        sqlx::query_scalar("SELECT action FROM newsletter_consents ORDER BY created_at")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+    assert_eq!(consents, vec!["subscribe", "confirm", "unsubscribe"]);
+}
@@ -0,0 +1,1036 @@
+mod common;
+
+use actix_http::Request;
+use actix_web::{
+    body::MessageBody,
+    dev::{Service, ServiceResponse},
+    http::{header, Method},
+    test::{self, TestRequest},
+};
+use serde_json::{json, Value};
+use sqlx::{postgres::PgListener, PgPool};
+use std::time::Duration;
+use uuid::Uuid;
+
+use common::{request, send, status};
+
+// check a cart with one product out, cash on delivery, returns the order id
+async fn place_order<S, B>(app: &S, customer: &str, product_id: Uuid, quantity: &str) -> String
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let added = status(
+        app,
+        request(
+            Method::POST,
+            "/api/cart-items",
+            Some(customer),
+            Some(json!({ "product_id": product_id, "quantity": quantity })),
+        ),
+    )
+    .await;
+    assert_eq!(added, 201);
+    let (placed, checkout): (u16, Value) = send(
+        app,
+        request(
+            Method::POST,
+            "/api/checkout",
+            Some(customer),
+            Some(json!({
+                "shipping_address": "Dam 1, 1012 JS Amsterdam",
+                "shipping_country": "NL",
+                "shipping_postcode": "1012 JS",
+                "payment_provider": "cod",
+            })),
+        ),
+    )
+    .await;
+    assert_eq!(placed, 201, "checking out: {checkout}");
+    checkout["data"]["order"]["order_id"]
+        .as_str()
+        .expect("checkout returns the order")
+        .to_string()
+}
+
+// a staff account with the role, logged in
+async fn staff<S, B>(app: &S, admin: &str, email: &str, role: &str) -> String
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let user_id = common::register(app, email).await;
+    let promoted = status(
+        app,
+        request(
+            Method::PATCH,
+            &format!("/api/admin/users/{user_id}"),
+            Some(admin),
+            Some(json!({ "role": role })),
+        ),
+    )
+    .await;
+    assert_eq!(promoted, 200);
+    common::login(app, email).await
+}
+
+#[sqlx::test(migrations = false)]
+async fn order_status_is_projected_from_its_events(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let product_id = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+
+    let order_id = place_order(&app, &customer, product_id, "1").await;
+    let order_id = order_id.as_str();
+
+    let updated = status(
+        &app,
+        request(
+            Method::PUT,
+            "/api/admin/order",
+            Some(&admin),
+            Some(json!({ "order_id": order_id, "order_status": "Shipped" })),
+        ),
+    )
+    .await;
+    assert_eq!(updated, 200);
+
+    let (found, order): (u16, Value) = send(
+        &app,
+        request(
+            Method::GET,
+            &format!("/api/admin/orders/{order_id}"),
+            Some(&admin),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(found, 200, "{order}");
+    let order = &order["data"];
+    assert_eq!(order["status"], "Shipped");
+    let history = order["history"].as_array().unwrap();
+    let steps: Vec<(i64, &str, &Value)> = history
+        .iter()
+        .map(|e| {
+            (
+                e["sequence"].as_i64().unwrap(),
+                e["event"].as_str().unwrap(),
+                &e["status"],
+            )
+        })
+        .collect();
+    assert_eq!(
+        steps,
+        [
+            (1, "order.created", &json!("Confirmed")),
+            (2, "order.status_changed", &json!("Shipped")),
+        ]
+    );
+
+    // the row can't drift from its events, nor the events be rewritten
+    let drifted = sqlx::query("UPDATE orders SET status = 'cancelled' WHERE order_id = $1::uuid")
+        .bind(order_id)
+        .execute(&pool)
+        .await;
+    assert!(drifted.is_err());
+    let rewritten =
+        sqlx::query("UPDATE order_events SET status = 'cancelled' WHERE order_id = $1::uuid")
+            .bind(order_id)
+            .execute(&pool)
+            .await;
+    assert!(rewritten.is_err());
+
+    // a status named as it is sent out is taken, a name we don't know isn't
+    let set_status = |name: &str| {
+        request(
+            Method::PUT,
+            "/api/admin/order",
+            Some(&admin),
+            Some(json!({ "order_id": order_id, "order_status": name })),
+        )
+    };
+    assert_eq!(status(&app, set_status("Refunded")).await, 200);
+    assert_eq!(status(&app, set_status("Returned")).await, 400);
+    let (_, order): (u16, Value) = send(
+        &app,
+        request(
+            Method::GET,
+            &format!("/api/admin/orders/{order_id}"),
+            Some(&admin),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(order["data"]["status"], "Refunded");
+}
+
+#[sqlx::test(migrations = false)]
+async fn admin_order_list_reads_the_summaries(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let mug = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+    let plush = common::product(&app, &admin, "Ferris Plush", "24.90", 5).await;
+
+    let first = place_order(&app, &customer, mug, "1").await;
+    let added = status(
+        &app,
+        request(
+            Method::POST,
+            "/api/cart-items",
+            Some(&customer),
+            Some(json!({ "product_id": plush, "quantity": "2" })),
+        ),
+    )
+    .await;
+    assert_eq!(added, 201);
+    let second = place_order(&app, &customer, mug, "3").await;
+
+    let updated = status(
+        &app,
+        request(
+            Method::PUT,
+            "/api/admin/order",
+            Some(&admin),
+            Some(json!({ "order_id": first, "order_status": "Shipped" })),
+        ),
+    )
+    .await;
+    assert_eq!(updated, 200);
+    sqlx::query("UPDATE users SET last_name = 'Customer' WHERE email = 'customer@example.com'")
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let (found, list): (u16, Value) = send(
+        &app,
+        request(Method::GET, "/api/admin/orders", Some(&admin), None),
+    )
+    .await;
+    assert_eq!(found, 200, "{list}");
+    assert_eq!(list["meta"]["pagination"]["total"], 2);
+    let newest = &list["data"][0];
+    assert_eq!(newest["order_id"], second);
+    assert_eq!(newest["customer_name"], "Test Customer");
+    assert_eq!(newest["customer_email"], "customer@example.com");
+    assert_eq!(newest["item_count"], 2);
+    assert_eq!(newest["total_quantity"], "5.000");
+    let oldest = &list["data"][1];
+    assert_eq!(oldest["order_id"], first);
+    assert_eq!(oldest["status"], "Shipped");
+    assert_eq!(oldest["item_count"], 1);
+}
+
+#[sqlx::test(migrations = false)]
+async fn admin_order_export_has_a_line_per_order_line(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let mug = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+    let plush = common::product(&app, &admin, "Ferris Plush, Large", "24.90", 5).await;
+
+    let first = place_order(&app, &customer, mug, "1").await;
+    let added = status(
+        &app,
+        request(
+            Method::POST,
+            "/api/cart-items",
+            Some(&customer),
+            Some(json!({ "product_id": plush, "quantity": "2" })),
+        ),
+    )
+    .await;
+    assert_eq!(added, 201);
+    let second = place_order(&app, &customer, mug, "3").await;
+    let shipped = status(
+        &app,
+        request(
+            Method::PUT,
+            "/api/admin/order",
+            Some(&admin),
+            Some(json!({ "order_id": second, "order_status": "Shipped" })),
+        ),
+    )
+    .await;
+    assert_eq!(shipped, 200);
+
+    let export = |query: &'static str| {
+        let app = &app;
+        let admin = &admin;
+        async move {
+            let response = test::call_service(
+                app,
+                request(
+                    Method::GET,
+                    &format!("/api/admin/orders/export{query}"),
+                    Some(admin),
+                    None,
+                ),
+            )
+            .await;
+            assert_eq!(response.status(), 200);
+            String::from_utf8(test::read_body(response).await.to_vec()).unwrap()
+        }
+    };
+
+    // oldest first, the order columns repeated on each of its lines
+    let csv = export("").await;
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(lines.len(), 4, "{csv}");
+    assert!(lines[0].starts_with("order_id,created_at,status,"));
+    assert!(lines[1].starts_with(&first));
+    assert!(lines[1].ends_with(",1,14.50,14.50"), "{csv}");
+    assert!(lines[2].starts_with(&second));
+    assert!(lines[3].starts_with(&second));
+    assert!(
+        csv.contains(",\"Ferris Plush, Large\",2,24.90,49.80\n"),
+        "{csv}"
+    );
+
+    // the listing's filters
+    let csv = export("?status=Shipped").await;
+    assert_eq!(csv.lines().count(), 3);
+    assert!(csv.lines().skip(1).all(|line| line.starts_with(&second)));
+    let (found, list): (u16, Value) = send(
+        &app,
+        request(
+            Method::GET,
+            "/api/admin/orders?status=Shipped",
+            Some(&admin),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(found, 200);
+    assert_eq!(list["meta"]["pagination"]["total"], 1);
+    assert_eq!(
+        export("?from=2100-01-01T00:00:00Z").await.lines().count(),
+        1
+    );
+
+    // the listing itself answers with the same file when asked for CSV
+    let response = test::call_service(
+        &app,
+        TestRequest::get()
+            .uri("/api/admin/orders?status=Shipped")
+            .insert_header((header::AUTHORIZATION, format!("Bearer {admin}")))
+            .insert_header((header::ACCEPT, "text/csv, application/json;q=0.5"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.headers().get(header::CONTENT_TYPE).unwrap(),
+        "text/csv; charset=utf-8"
+    );
+    let listed = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+    assert_eq!(listed, export("?status=Shipped").await);
+
+    let forbidden = status(
+        &app,
+        request(
+            Method::GET,
+            "/api/admin/orders/export",
+            Some(&customer),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(forbidden, 401);
+}
+
+#[sqlx::test(migrations = false)]
+async fn new_orders_are_announced_to_admin_dashboards(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let mug = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+
+    // only admins may follow the feed
+    let feed = |token: Option<&str>| {
+        let mut req = TestRequest::get()
+            .uri("/api/admin/feed")
+            .insert_header((header::CONNECTION, "upgrade"))
+            .insert_header((header::UPGRADE, "websocket"))
+            .insert_header((header::SEC_WEBSOCKET_VERSION, "13"))
+            .insert_header((header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ=="));
+        if let Some(token) = token {
+            req = req.insert_header((header::AUTHORIZATION, format!("Bearer {token}")));
+        }
+        req.to_request()
+    };
+    assert_eq!(status(&app, feed(None)).await, 401);
+    assert_eq!(status(&app, feed(Some(&customer))).await, 403);
+    // the server runs the socket on its worker's local task set
+    let upgraded = tokio::task::LocalSet::new()
+        .run_until(status(&app, feed(Some(&admin))))
+        .await;
+    assert_eq!(upgraded, 101);
+
+    let mut listener = PgListener::connect_with(&pool).await.unwrap();
+    listener.listen("admin_events").await.unwrap();
+    let order_id = place_order(&app, &customer, mug, "2").await;
+
+    let notification = tokio::time::timeout(Duration::from_secs(5), listener.recv())
+        .await
+        .expect("the new order is announced")
+        .unwrap();
+    let event: Value = serde_json::from_str(notification.payload()).unwrap();
+    assert_eq!(event["type"], "order.created");
+    assert_eq!(event["order_id"], order_id);
+    assert_eq!(event["customer_name"], "Test User");
+    assert_eq!(event["item_count"], 1);
+    assert_eq!(event["total_amount"], 29.0);
+}
+
+#[sqlx::test(migrations = false)]
+async fn order_lines_keep_the_name_they_were_sold_under(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let mug = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+    let tea = common::product(&app, &admin, "Ferris Tea", "6.00", 10).await;
+    let added = request(
+        Method::POST,
+        "/api/cart-items",
+        Some(&customer),
+        Some(json!({ "product_id": tea, "quantity": "1" })),
+    );
+    assert_eq!(status(&app, added).await, 201);
+    let order_id = place_order(&app, &customer, mug, "1").await;
+
+    sqlx::query("UPDATE products SET name = 'Lifetime Mug' WHERE product_id = $1")
+        .bind(mug)
+        .execute(&pool)
+        .await
+        .unwrap();
+    // a line whose product is gone
+    sqlx::query("UPDATE order_details SET product_id = NULL WHERE product_id = $1")
+        .bind(tea)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let (found, order): (u16, Value) = send(
+        &app,
+        request(
+            Method::GET,
+            &format!("/api/admin/orders/{order_id}"),
+            Some(&admin),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(found, 200, "{order}");
+    let mut names = order["data"]["items"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|item| item["product_name"].as_str().unwrap().to_string())
+        .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, ["Borrow Checker Mug", "Ferris Tea"]);
+}
+
+#[sqlx::test(migrations = false)]
+async fn orders_keep_the_prices_they_were_sold_at(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let product_id = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+
+    let order_id = place_order(&app, &customer, product_id, "2").await;
+    let order_id = order_id.as_str();
+
+    sqlx::query("UPDATE products SET price = 99.99 WHERE product_id = $1")
+        .bind(product_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let (found, order): (u16, Value) = send(
+        &app,
+        request(
+            Method::GET,
+            &format!("/api/admin/orders/{order_id}"),
+            Some(&admin),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(found, 200, "{order}");
+    let order = &order["data"];
+    assert_eq!(order["total_amount"], "29.00");
+    assert_eq!(order["subtotal_amount"], "29.00");
+    assert_eq!(order["items"][0]["price_per_unit"], "14.50");
+    assert_eq!(order["items"][0]["line_total"], "29.00");
+
+    let audit = || async {
+        let (status, audit): (u16, Value) = send(
+            &app,
+            request(
+                Method::GET,
+                "/api/admin/orders/price-audit",
+                Some(&admin),
+                None,
+            ),
+        )
+        .await;
+        assert_eq!(status, 200, "{audit}");
+        audit["data"].clone()
+    };
+    let clean = audit().await;
+    assert_eq!(clean["checked"], 1);
+    assert_eq!(clean["mismatches"], json!([]));
+
+    // the lines can't be repriced, a total changed behind the shop's back shows up
+    let repriced =
+        sqlx::query("UPDATE order_details SET price_per_unit = 99.99 WHERE order_id = $1::uuid")
+            .bind(order_id)
+            .execute(&pool)
+            .await;
+    assert!(repriced.is_err());
+    sqlx::query("UPDATE orders SET total_amount = 199.98 WHERE order_id = $1::uuid")
+        .bind(order_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+    let drifted = audit().await;
+    assert_eq!(drifted["mismatches"][0]["order_id"], order_id);
+    assert_eq!(drifted["mismatches"][0]["expected_total"], "29.00");
+    assert_eq!(drifted["mismatches"][0]["problems"], json!(["total"]));
+}
+
+#[sqlx::test(migrations = false)]
+async fn new_rows_get_time_ordered_ids(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let product_id = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+
+    let first: Uuid = place_order(&app, &customer, product_id, "1")
+        .await
+        .parse()
+        .unwrap();
+    let second: Uuid = place_order(&app, &customer, product_id, "1")
+        .await
+        .parse()
+        .unwrap();
+    assert_eq!(first.get_version_num(), 7);
+    assert!(second > first, "later orders sort after earlier ones");
+
+    let lines: Vec<Uuid> =
+        sqlx::query_scalar("SELECT order_detail_id FROM order_details ORDER BY order_detail_id")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+    assert_eq!(lines.len(), 2);
+    assert!(lines.iter().all(|id| id.get_version_num() == 7));
+}
+
+#[sqlx::test(migrations = false)]
+async fn stale_status_updates_are_refused(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let product_id = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+    let order_id = place_order(&app, &customer, product_id, "1").await;
+
+    let response = test::call_service(
+        &app,
+        request(
+            Method::GET,
+            &format!("/api/admin/orders/{order_id}"),
+            Some(&admin),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(response.status(), 200);
+    let etag = response.headers().get(header::ETAG).unwrap().clone();
+
+    let update = |status: &str| {
+        TestRequest::put()
+            .uri("/api/admin/order")
+            .insert_header((header::AUTHORIZATION, format!("Bearer {admin}")))
+            .insert_header((header::IF_MATCH, etag.clone()))
+            .set_json(json!({ "order_id": order_id, "order_status": status }))
+            .to_request()
+    };
+    assert_eq!(
+        test::call_service(&app, update("Shipped")).await.status(),
+        200
+    );
+    // the first update moved the order on, the tag read before it is stale
+    assert_eq!(
+        test::call_service(&app, update("Cancelled")).await.status(),
+        412
+    );
+
+    let (_, order): (u16, Value) = send(
+        &app,
+        request(
+            Method::GET,
+            &format!("/api/admin/orders/{order_id}"),
+            Some(&admin),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(order["data"]["status"], "Shipped");
+}
+
+#[sqlx::test(migrations = false)]
+async fn staff_do_what_their_role_allows(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let support = staff(&app, &admin, "support@example.com", "Support").await;
+    let warehouse = staff(&app, &admin, "warehouse@example.com", "WarehouseStaff").await;
+    let product_id = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+    let order_id = place_order(&app, &customer, product_id, "1").await;
+    let order = format!("/api/admin/orders/{order_id}");
+    let set_status = |token: &str, order_status: &str| {
+        request(
+            Method::PUT,
+            "/api/admin/order",
+            Some(token),
+            Some(json!({ "order_id": order_id, "order_status": order_status })),
+        )
+    };
+
+    // support reads orders and refunds them, but doesn't change them or the catalogue
+    assert_eq!(
+        status(&app, request(Method::GET, &order, Some(&support), None)).await,
+        200
+    );
+    assert_eq!(
+        status(
+            &app,
+            request(Method::GET, "/api/admin/orders", Some(&support), None)
+        )
+        .await,
+        200
+    );
+    assert_eq!(status(&app, set_status(&support, "Shipped")).await, 403);
+    assert_eq!(
+        status(
+            &app,
+            request(
+                Method::PUT,
+                &format!("/api/product/{product_id}"),
+                Some(&support),
+                Some(json!({"name": "Mug", "description": null, "price": "1.00", "stock_quantity": 1})),
+            )
+        )
+        .await,
+        403
+    );
+    let refund = format!("/api/admin/orders/{order_id}/refund");
+    assert_eq!(
+        status(
+            &app,
+            request(Method::POST, &refund, Some(&warehouse), Some(json!({})))
+        )
+        .await,
+        403
+    );
+    let (refunded, body): (u16, Value) = send(
+        &app,
+        request(Method::POST, &refund, Some(&support), Some(json!({}))),
+    )
+    .await;
+    // cash on delivery isn't collected yet, there is nothing to refund
+    assert_eq!(refunded, 409, "{body}");
+
+    // the warehouse sees what to pack, gift instructions included, and ships
+    // orders and nothing else
+    let (seen, body): (u16, Value) =
+        send(&app, request(Method::GET, &order, Some(&warehouse), None)).await;
+    assert_eq!(seen, 200, "{body}");
+    assert_eq!(status(&app, set_status(&warehouse, "Cancelled")).await, 403);
+    assert_eq!(status(&app, set_status(&warehouse, "Shipped")).await, 200);
+    // only from confirmed or ready for pickup, a shipped order stays shipped
+    assert_eq!(status(&app, set_status(&warehouse, "Shipped")).await, 403);
+    let cancelled = place_order(&app, &customer, product_id, "1").await;
+    let confirmed = place_order(&app, &customer, product_id, "1").await;
+    assert_eq!(
+        status(
+            &app,
+            request(
+                Method::PUT,
+                "/api/admin/order",
+                Some(&admin),
+                Some(json!({ "order_id": cancelled, "order_status": "Cancelled" })),
+            )
+        )
+        .await,
+        200
+    );
+    let (code, results): (u16, Value) = send(
+        &app,
+        request(
+            Method::PUT,
+            "/api/admin/orders/status",
+            Some(&warehouse),
+            Some(json!({ "order_ids": [cancelled, confirmed], "status": "Shipped" })),
+        ),
+    )
+    .await;
+    assert_eq!(code, 200, "{results}");
+    assert_eq!(results["data"][0]["success"], false);
+    assert_eq!(results["data"][1]["success"], true);
+    // not even an admin brings a cancelled order back
+    assert_eq!(
+        status(
+            &app,
+            request(
+                Method::PUT,
+                "/api/admin/order",
+                Some(&admin),
+                Some(json!({ "order_id": cancelled, "order_status": "Confirmed" })),
+            )
+        )
+        .await,
+        403
+    );
+    assert_eq!(
+        status(
+            &app,
+            request(
+                Method::POST,
+                &format!("{order}/shipments"),
+                Some(&support),
+                Some(json!({"carrier": "PostNL", "tracking_number": "3STEST123"})),
+            )
+        )
+        .await,
+        403
+    );
+}
+
+#[sqlx::test(migrations = false)]
+async fn staff_and_system_activity_share_one_feed(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let support = staff(&app, &admin, "support@example.com", "Support").await;
+    let product_id = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+    let order_id = place_order(&app, &customer, product_id, "1").await;
+    let shipped = status(
+        &app,
+        request(
+            Method::PUT,
+            "/api/admin/order",
+            Some(&admin),
+            Some(json!({ "order_id": order_id, "order_status": "Shipped" })),
+        ),
+    )
+    .await;
+    assert_eq!(shipped, 200);
+    sqlx::query(
+        "INSERT INTO system_events (event_type, details)
+        VALUES ('webhook.failed', '{\"source\": \"carriers/postnl\"}')",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+
+    let (code, feed): (u16, Value) = send(
+        &app,
+        request(Method::GET, "/api/admin/activity", Some(&admin), None),
+    )
+    .await;
+    assert_eq!(code, 200, "{feed}");
+    let entries: Vec<(&str, &str)> = feed["data"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| {
+            (
+                entry["kind"].as_str().unwrap(),
+                entry["event_type"].as_str().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        entries,
+        [
+            ("system", "webhook.failed"),
+            ("staff", "order.status_changed"),
+            ("staff", "user.update"),
+        ]
+    );
+    assert_eq!(feed["meta"]["pagination"]["total"], 3);
+
+    let (_, me): (u16, Value) = send(
+        &app,
+        request(Method::GET, "/api/user_info", Some(&admin), None),
+    )
+    .await;
+    let admin_id = me["data"]["user_id"].as_str().unwrap();
+    let (_, feed): (u16, Value) = send(
+        &app,
+        request(
+            Method::GET,
+            &format!("/api/admin/activity?actor={admin_id}&type=order.status_changed"),
+            Some(&admin),
+            None,
+        ),
+    )
+    .await;
+    let entries = feed["data"].as_array().unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0]["entity_id"], order_id.as_str());
+    assert_eq!(entries[0]["actor_name"], "Test Admin");
+
+    let (_, feed): (u16, Value) = send(
+        &app,
+        request(
+            Method::GET,
+            "/api/admin/activity?type=system",
+            Some(&admin),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(feed["data"].as_array().unwrap().len(), 1);
+
+    assert_eq!(
+        status(
+            &app,
+            request(Method::GET, "/api/admin/activity", Some(&support), None)
+        )
+        .await,
+        403
+    );
+}
+
+#[sqlx::test(migrations = false)]
+async fn invoices_carry_the_billing_and_shipping_addresses(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let other = common::customer(&app, "other@example.com").await;
+    let product_id = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+
+    let added = status(
+        &app,
+        request(
+            Method::POST,
+            "/api/cart-items",
+            Some(&customer),
+            Some(json!({ "product_id": product_id, "quantity": "2" })),
+        ),
+    )
+    .await;
+    assert_eq!(added, 201);
+    let (placed, checkout): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/checkout",
+            Some(&customer),
+            Some(json!({
+                "shipping_address": "Dam 1, 1012 JS Amsterdam",
+                "billing_address": "Coolsingel 40, 3011 AD Rotterdam",
+                "shipping_country": "NL",
+                "shipping_postcode": "1012 JS",
+                "payment_provider": "cod",
+            })),
+        ),
+    )
+    .await;
+    assert_eq!(placed, 201);
+    let order_id = checkout["data"]["order"]["order_id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    let (fetched, invoice): (u16, Value) = send(
+        &app,
+        request(
+            Method::GET,
+            &format!("/api/orders/{order_id}/invoice"),
+            Some(&customer),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(fetched, 200);
+    let invoice = &invoice["data"];
+    assert_eq!(
+        invoice["billing_address"],
+        "Coolsingel 40, 3011 AD Rotterdam"
+    );
+    assert_eq!(invoice["shipping_address"], "Dam 1, 1012 JS Amsterdam");
+    assert_eq!(invoice["customer_email"], "customer@example.com");
+    assert_eq!(invoice["lines"].as_array().unwrap().len(), 1);
+    assert_eq!(invoice["lines"][0]["description"], "Borrow Checker Mug");
+
+    let (fetched, admin_invoice): (u16, Value) = send(
+        &app,
+        request(
+            Method::GET,
+            &format!("/api/admin/orders/{order_id}/invoice"),
+            Some(&admin),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(fetched, 200);
+    assert_eq!(
+        admin_invoice["data"]["invoice_number"],
+        invoice["invoice_number"]
+    );
+
+    assert_eq!(
+        status(
+            &app,
+            request(
+                Method::GET,
+                &format!("/api/orders/{order_id}/invoice"),
+                Some(&other),
+                None
+            )
+        )
+        .await,
+        404
+    );
+    assert_eq!(
+        status(
+            &app,
+            request(
+                Method::GET,
+                &format!("/api/admin/orders/{order_id}/invoice"),
+                Some(&other),
+                None
+            )
+        )
+        .await,
+        403
+    );
+}
+
+#[sqlx::test(migrations = false)]
+async fn restocking_gives_back_the_components_a_kit_was_sold_with(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let mug = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+    let tea = common::product(&app, &admin, "Ferris Tea", "6.00", 10).await;
+    let spoon = common::product(&app, &admin, "Lifetime Spoon", "3.00", 10).await;
+    let kit = common::product(&app, &admin, "Tea Set", "20.00", 0).await;
+    let set_components = |components: Value| {
+        request(
+            Method::PUT,
+            &format!("/api/admin/products/{kit}/components"),
+            Some(&admin),
+            Some(components),
+        )
+    };
+    let stock = |product_id: Uuid| {
+        sqlx::query_scalar::<_, String>(
+            "SELECT stock_quantity::INTEGER::TEXT FROM products WHERE product_id = $1",
+        )
+        .bind(product_id)
+        .fetch_one(&pool)
+    };
+
+    let set = status(
+        &app,
+        set_components(json!([
+            { "product_id": mug, "quantity": 1 },
+            { "product_id": tea, "quantity": 2 },
+        ])),
+    )
+    .await;
+    assert_eq!(set, 200);
+    let order_id = place_order(&app, &customer, kit, "1").await;
+    assert_eq!(stock(mug).await.unwrap(), "9");
+    assert_eq!(stock(tea).await.unwrap(), "8");
+
+    // the kit changes after it was sold
+    let set = status(
+        &app,
+        set_components(json!([
+            { "product_id": mug, "quantity": 1 },
+            { "product_id": spoon, "quantity": 1 },
+        ])),
+    )
+    .await;
+    assert_eq!(set, 200);
+
+    let collected = status(
+        &app,
+        request(
+            Method::POST,
+            &format!("/api/admin/orders/{order_id}/cod-collected"),
+            Some(&admin),
+            Some(json!({})),
+        ),
+    )
+    .await;
+    assert_eq!(collected, 200);
+    let (refunded, body): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            &format!("/api/admin/orders/{order_id}/refund"),
+            Some(&admin),
+            Some(json!({ "restock": true })),
+        ),
+    )
+    .await;
+    assert_eq!(refunded, 201, "{body}");
+
+    assert_eq!(stock(mug).await.unwrap(), "10");
+    assert_eq!(stock(tea).await.unwrap(), "10");
+    assert_eq!(stock(spoon).await.unwrap(), "10");
+    // what was given back is off the order, nothing can be released twice
+    let left: i64 =
+        sqlx::query_scalar("SELECT COUNT(*) FROM order_stock WHERE order_id = $1::uuid")
+            .bind(&order_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert_eq!(left, 0);
+}
+
+#[sqlx::test(migrations = false)]
+async fn cash_is_not_collected_for_a_cancelled_order(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let product_id = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+    let order_id = place_order(&app, &customer, product_id, "1").await;
+    sqlx::query(
+        "INSERT INTO order_events (order_id, event_type, status, data)
+        VALUES ($1::uuid, 'order.expired', 'cancelled', '{}')",
+    )
+    .bind(&order_id)
+    .execute(&pool)
+    .await
+    .unwrap();
+
+    let (collected, body): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            &format!("/api/admin/orders/{order_id}/cod-collected"),
+            Some(&admin),
+            Some(json!({})),
+        ),
+    )
+    .await;
+    assert_eq!(collected, 409, "{body}");
+    let recorded: Option<String> = sqlx::query_scalar(
+        "SELECT cod_collected_amount::TEXT FROM orders WHERE order_id = $1::uuid",
+    )
+    .bind(&order_id)
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert_eq!(recorded, None);
+}
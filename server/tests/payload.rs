@@ -0,0 +1,78 @@
+mod common;
+
+use actix_web::{
+    http::{header, Method},
+    test,
+};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+use common::request;
+
+// in its own test binary, the limits are read from the environment
+#[sqlx::test(migrations = false)]
+async fn bodies_over_the_route_limit_are_refused(pool: PgPool) {
+    std::env::set_var("PAYLOAD_LIMITS", "POST /api/newsletter/subscribe=100");
+    std::env::set_var("JSON_MAX_DEPTH", "8");
+    let app = common::app(&pool).await;
+
+    // registration takes a few fields, not a novel
+    let response = test::call_service(
+        &app,
+        request(
+            Method::POST,
+            "/api/users",
+            None,
+            Some(json!({
+                "first_name": "x".repeat(16 * 1024),
+                "last_name": "User",
+                "email": "customer@example.com",
+                "password": common::PASSWORD,
+                "phone": "+3100000000",
+            })),
+        ),
+    )
+    .await;
+    assert_eq!(response.status(), 413);
+    assert_eq!(
+        response.headers().get(header::CONTENT_TYPE).unwrap(),
+        "application/problem+json"
+    );
+    let problem: Value = test::read_body_json(response).await;
+    assert_eq!(problem["status"], 413);
+
+    // the configured limit applies to its route only
+    let subscribe = |email: String| {
+        request(
+            Method::POST,
+            "/api/newsletter/subscribe",
+            None,
+            Some(json!({ "email": email })),
+        )
+    };
+    let response = test::call_service(&app, subscribe("a".repeat(100) + "@example.com")).await;
+    assert_eq!(response.status(), 413);
+    let response = test::call_service(&app, subscribe("reader@example.com".into())).await;
+    assert_eq!(response.status(), 202);
+
+    let mut nested = json!("deep");
+    for _ in 0..10 {
+        nested = json!([nested]);
+    }
+    let response = test::call_service(
+        &app,
+        request(
+            Method::POST,
+            "/api/cart-items",
+            None,
+            Some(json!({ "product_id": nested })),
+        ),
+    )
+    .await;
+    assert_eq!(response.status(), 400);
+    let problem: Value = test::read_body_json(response).await;
+    assert_eq!(
+        problem["detail"],
+        "JSON body is nested deeper than 8 levels"
+    );
+}
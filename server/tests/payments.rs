@@ -0,0 +1,541 @@
+mod common;
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use actix_web::{
+    http::{header::HeaderMap, Method},
+    test,
+};
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+use common::{request, send};
+use server::{
+    jobs::{expire_unpaid_orders, retry_refunds},
+    money::Money,
+    payments::{
+        Charge, ChargeRequest, ChargeStatus, PaymentError, PaymentProvider, Payments, WebhookEvent,
+    },
+};
+
+// a provider whose charges have all gone through, the webhook body is the
+// charge it is about. Opening a charge takes charge_delay and fails with
+// charge_fails, the first failing_refunds refunds fail and every refund's
+// idempotency key is kept
+#[derive(Default)]
+struct PaidProvider {
+    refunds: AtomicUsize,
+    charge_delay: Duration,
+    charge_fails: AtomicBool,
+    failing_refunds: AtomicUsize,
+    refund_keys: Mutex<Vec<String>>,
+}
+
+#[async_trait]
+impl PaymentProvider for PaidProvider {
+    fn name(&self) -> &'static str {
+        "fake"
+    }
+
+    async fn create_charge(&self, request: &ChargeRequest<'_>) -> Result<Charge, PaymentError> {
+        actix_web::rt::time::sleep(self.charge_delay).await;
+        if self.charge_fails.load(Ordering::SeqCst) {
+            return Err(PaymentError::Malformed("card processor is down".into()));
+        }
+        Ok(Charge {
+            provider_ref: format!("charge-{}", request.order_id),
+            client_secret: None,
+            approval_url: None,
+        })
+    }
+
+    async fn confirm_charge(&self, _provider_ref: &str) -> Result<ChargeStatus, PaymentError> {
+        Ok(ChargeStatus::Succeeded)
+    }
+
+    async fn cancel_charge(&self, _provider_ref: &str) -> Result<(), PaymentError> {
+        Ok(())
+    }
+
+    async fn refund(
+        &self,
+        _provider_ref: &str,
+        _amount: Money,
+        idempotency_key: &str,
+    ) -> Result<String, PaymentError> {
+        self.refund_keys
+            .lock()
+            .unwrap()
+            .push(idempotency_key.to_string());
+        if self
+            .failing_refunds
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+                left.checked_sub(1)
+            })
+            .is_ok()
+        {
+            return Err(PaymentError::Malformed("refund timed out".into()));
+        }
+        self.refunds.fetch_add(1, Ordering::SeqCst);
+        Ok("refund".into())
+    }
+
+    async fn parse_webhook(
+        &self,
+        _headers: &HeaderMap,
+        body: &[u8],
+    ) -> Result<Option<WebhookEvent>, PaymentError> {
+        Ok(Some(WebhookEvent::Charge(
+            String::from_utf8_lossy(body).into_owned(),
+        )))
+    }
+}
+
+#[sqlx::test(migrations = false)]
+async fn a_replayed_success_webhook_leaves_the_paid_order_alone(pool: PgPool) {
+    let provider = Arc::new(PaidProvider::default());
+    let app = common::app_with(&pool, |state| state.with_payment_provider(provider.clone())).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let product_id = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+
+    let (added, _): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/cart-items",
+            Some(&customer),
+            Some(json!({ "product_id": product_id, "quantity": "1" })),
+        ),
+    )
+    .await;
+    assert_eq!(added, 201);
+    let (placed, checkout): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/checkout",
+            Some(&customer),
+            Some(json!({
+                "shipping_address": "Dam 1, 1012 JS Amsterdam",
+                "shipping_country": "NL",
+                "payment_provider": "fake",
+            })),
+        ),
+    )
+    .await;
+    assert_eq!(placed, 201, "{checkout}");
+    let order_id = checkout["data"]["order"]["order_id"].as_str().unwrap();
+
+    let (confirmed, result): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            &format!("/api/checkout/{order_id}/confirm"),
+            Some(&customer),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(confirmed, 200, "{result}");
+    assert_eq!(result["data"]["paid"], true);
+
+    // the provider's own success notice comes in after the customer confirmed
+    let webhook = test::TestRequest::post()
+        .uri("/api/webhooks/payments/fake")
+        .set_payload(format!("charge-{order_id}"))
+        .to_request();
+    let (delivered, _): (u16, Value) = send(&app, webhook).await;
+    assert_eq!(delivered, 200);
+
+    assert_eq!(provider.refunds.load(Ordering::SeqCst), 0);
+    let (status, events): (String, Vec<String>) = sqlx::query_as(
+        "SELECT o.status::text, ARRAY(
+            SELECT event_type FROM order_events e WHERE e.order_id = o.order_id ORDER BY sequence
+        )
+        FROM orders o WHERE o.order_id = $1::uuid",
+    )
+    .bind(order_id)
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert_eq!(status, "confirmed");
+    assert_eq!(
+        events
+            .iter()
+            .filter(|event| event.starts_with("payment."))
+            .collect::<Vec<_>>(),
+        ["payment.succeeded"]
+    );
+}
+
+#[sqlx::test(migrations = false)]
+async fn a_slow_provider_is_not_counted_as_a_slow_order_query(pool: PgPool) {
+    let provider = Arc::new(PaidProvider {
+        charge_delay: Duration::from_millis(400),
+        ..Default::default()
+    });
+    let app = common::app_with(&pool, |state| state.with_payment_provider(provider)).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let product_id = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+
+    let (added, _): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/cart-items",
+            Some(&customer),
+            Some(json!({ "product_id": product_id, "quantity": "1" })),
+        ),
+    )
+    .await;
+    assert_eq!(added, 201);
+    let (placed, checkout): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/checkout",
+            Some(&customer),
+            Some(json!({
+                "shipping_address": "Dam 1, 1012 JS Amsterdam",
+                "shipping_country": "NL",
+                "payment_provider": "fake",
+            })),
+        ),
+    )
+    .await;
+    assert_eq!(placed, 201, "{checkout}");
+
+    let response = test::call_service(&app, request(Method::GET, "/metrics", None, None)).await;
+    let metrics = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+    assert!(
+        metrics.contains("db_slow_queries_total{tag=\"orders.create\"} 0"),
+        "{metrics}"
+    );
+    assert!(
+        metrics.contains("db_slow_queries_total{tag=\"payments.create_charge\"} 1"),
+        "{metrics}"
+    );
+}
+
+#[sqlx::test(migrations = false)]
+async fn a_refund_the_provider_failed_is_sent_again_with_the_same_key(pool: PgPool) {
+    let provider = Arc::new(PaidProvider {
+        failing_refunds: AtomicUsize::new(1),
+        ..Default::default()
+    });
+    let app = common::app_with(&pool, |state| state.with_payment_provider(provider.clone())).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let product_id = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+
+    let (added, _): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/cart-items",
+            Some(&customer),
+            Some(json!({ "product_id": product_id, "quantity": "1" })),
+        ),
+    )
+    .await;
+    assert_eq!(added, 201);
+    let (placed, checkout): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/checkout",
+            Some(&customer),
+            Some(json!({
+                "shipping_address": "Dam 1, 1012 JS Amsterdam",
+                "shipping_country": "NL",
+                "payment_provider": "fake",
+            })),
+        ),
+    )
+    .await;
+    assert_eq!(placed, 201, "{checkout}");
+    let order_id = checkout["data"]["order"]["order_id"].as_str().unwrap();
+    let confirm = request(
+        Method::POST,
+        &format!("/api/checkout/{order_id}/confirm"),
+        Some(&customer),
+        None,
+    );
+    assert_eq!(common::status(&app, confirm).await, 200);
+
+    let refund = |body: Value| {
+        request(
+            Method::POST,
+            &format!("/api/admin/orders/{order_id}/refund"),
+            Some(&admin),
+            Some(body),
+        )
+    };
+    // the provider times out, the refund stays pending
+    let (failed, body): (u16, Value) = send(&app, refund(json!({ "amount": "5.00" }))).await;
+    assert_eq!(failed, 502, "{body}");
+
+    // the next refund finishes the pending one instead of taking another
+    let (retried, body): (u16, Value) = send(&app, refund(json!({ "amount": "9.50" }))).await;
+    assert_eq!(retried, 201, "{body}");
+    assert_eq!(body["data"]["refund"]["amount"], "5.00");
+    assert_eq!(body["data"]["refund"]["status"], "succeeded");
+    assert_eq!(body["data"]["status"], "PartiallyRefunded");
+
+    let (rest, body): (u16, Value) = send(&app, refund(json!({}))).await;
+    assert_eq!(rest, 201, "{body}");
+    assert_eq!(body["data"]["refund"]["amount"], "9.50");
+    assert_eq!(body["data"]["status"], "Refunded");
+
+    let keys = provider.refund_keys.lock().unwrap().clone();
+    assert_eq!(keys.len(), 3);
+    assert_eq!(keys[0], keys[1]);
+    assert_ne!(keys[1], keys[2]);
+    assert_eq!(provider.refunds.load(Ordering::SeqCst), 2);
+}
+
+#[sqlx::test(migrations = false)]
+async fn a_slow_charge_holds_no_locks_and_a_failed_one_puts_the_order_back(pool: PgPool) {
+    let provider = Arc::new(PaidProvider {
+        charge_delay: Duration::from_millis(400),
+        ..Default::default()
+    });
+    let app = common::app_with(&pool, |state| state.with_payment_provider(provider.clone())).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let product_id = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+
+    let add = || {
+        request(
+            Method::POST,
+            "/api/cart-items",
+            Some(&customer),
+            Some(json!({ "product_id": product_id, "quantity": "2" })),
+        )
+    };
+    let checkout = || {
+        request(
+            Method::POST,
+            "/api/checkout",
+            Some(&customer),
+            Some(json!({
+                "shipping_address": "Dam 1, 1012 JS Amsterdam",
+                "shipping_country": "NL",
+                "payment_provider": "fake",
+            })),
+        )
+    };
+    assert_eq!(common::status(&app, add()).await, 201);
+
+    // while the provider takes its time the product can be locked by others
+    let (placed, locked) = tokio::join!(common::status(&app, checkout()), async {
+        actix_web::rt::time::sleep(Duration::from_millis(200)).await;
+        sqlx::query("SELECT 1 FROM products WHERE product_id = $1 FOR UPDATE NOWAIT")
+            .bind(product_id)
+            .fetch_one(&pool)
+            .await
+    });
+    assert_eq!(placed, 201);
+    assert!(locked.is_ok(), "{:?}", locked.err());
+
+    // a provider that is down leaves the stock and the cart as they were
+    provider.charge_fails.store(true, Ordering::SeqCst);
+    assert_eq!(common::status(&app, add()).await, 201);
+    assert_eq!(common::status(&app, checkout()).await, 502);
+
+    let stock: i32 =
+        sqlx::query_scalar("SELECT stock_quantity::INTEGER FROM products WHERE product_id = $1")
+            .bind(product_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert_eq!(stock, 8);
+    let in_cart: i32 =
+        sqlx::query_scalar("SELECT SUM(quantity)::INTEGER FROM cart_items WHERE product_id = $1")
+            .bind(product_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert_eq!(in_cart, 2);
+    let statuses: Vec<String> =
+        sqlx::query_scalar("SELECT status::text FROM orders ORDER BY created_at")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+    assert_eq!(statuses, ["pendingpayment", "cancelled"]);
+}
+
+#[sqlx::test(migrations = false)]
+async fn money_for_a_cancelled_order_is_refunded_until_the_provider_takes_it(pool: PgPool) {
+    let provider = Arc::new(PaidProvider {
+        failing_refunds: AtomicUsize::new(2),
+        ..Default::default()
+    });
+    let app = common::app_with(&pool, |state| state.with_payment_provider(provider.clone())).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let product_id = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+
+    let (added, _): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/cart-items",
+            Some(&customer),
+            Some(json!({ "product_id": product_id, "quantity": "1" })),
+        ),
+    )
+    .await;
+    assert_eq!(added, 201);
+    let (placed, checkout): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/checkout",
+            Some(&customer),
+            Some(json!({
+                "shipping_address": "Dam 1, 1012 JS Amsterdam",
+                "shipping_country": "NL",
+                "payment_provider": "fake",
+            })),
+        ),
+    )
+    .await;
+    assert_eq!(placed, 201, "{checkout}");
+    let order_id = checkout["data"]["order"]["order_id"].as_str().unwrap();
+    sqlx::query(
+        "INSERT INTO order_events (order_id, event_type, status, data)
+        VALUES ($1::uuid, 'order.expired', 'cancelled', '{}')",
+    )
+    .bind(order_id)
+    .execute(&pool)
+    .await
+    .unwrap();
+
+    // the payment comes in after the order expired, the provider refuses the
+    // refund and again when the webhook is replayed
+    let webhook = || {
+        test::TestRequest::post()
+            .uri("/api/webhooks/payments/fake")
+            .set_payload(format!("charge-{order_id}"))
+            .to_request()
+    };
+    for _ in 0..2 {
+        let (delivered, _): (u16, Value) = send(&app, webhook()).await;
+        assert_eq!(delivered, 200);
+    }
+    assert_eq!(provider.refunds.load(Ordering::SeqCst), 0);
+
+    // the retry job leaves it a minute, then sends it until it goes through
+    let mut payments = Payments::from_env();
+    payments.add_provider(provider.clone());
+    assert_eq!(retry_refunds(&pool, &payments).await.unwrap(), 0);
+    sqlx::query("UPDATE refunds SET created_at = NOW() - INTERVAL '5 minutes'")
+        .execute(&pool)
+        .await
+        .unwrap();
+    assert_eq!(retry_refunds(&pool, &payments).await.unwrap(), 1);
+    assert_eq!(retry_refunds(&pool, &payments).await.unwrap(), 0);
+    assert_eq!(provider.refunds.load(Ordering::SeqCst), 1);
+    let keys = provider.refund_keys.lock().unwrap().clone();
+    assert_eq!(keys.len(), 3);
+    assert!(keys.iter().all(|key| *key == keys[0]));
+
+    let (status, refunds): (String, Vec<String>) = sqlx::query_as(
+        "SELECT o.status::text, ARRAY(SELECT r.status::text FROM refunds r WHERE r.order_id = o.order_id)
+        FROM orders o WHERE o.order_id = $1::uuid",
+    )
+    .bind(order_id)
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert_eq!(status, "cancelled");
+    assert_eq!(refunds, ["succeeded"]);
+}
+
+#[sqlx::test(migrations = false)]
+async fn a_quote_whose_order_expired_unpaid_can_be_ordered_again(pool: PgPool) {
+    let provider = Arc::new(PaidProvider::default());
+    let app = common::app_with(&pool, |state| state.with_payment_provider(provider.clone())).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let product_id = common::product(&app, &admin, "Pallet of Mugs", "400.00", 10).await;
+    sqlx::query("UPDATE users SET vat_number = 'NL123456789B01' WHERE email = $1")
+        .bind("customer@example.com")
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let added = request(
+        Method::POST,
+        "/api/cart-items",
+        Some(&customer),
+        Some(json!({ "product_id": product_id, "quantity": "1" })),
+    );
+    assert_eq!(common::status(&app, added).await, 201);
+    let (code, quote): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/quotes",
+            Some(&customer),
+            Some(json!({})),
+        ),
+    )
+    .await;
+    assert_eq!(code, 201, "{quote}");
+    let quote_id = quote["data"]["quote_id"].as_str().unwrap().to_string();
+    let respond = request(
+        Method::PUT,
+        &format!("/api/admin/quotes/{quote_id}"),
+        Some(&admin),
+        Some(json!({
+            "prices": [{ "product_id": product_id, "price": "350.00" }],
+            "expires_at": chrono::Utc::now() + chrono::Duration::days(7),
+        })),
+    );
+    assert_eq!(common::status(&app, respond).await, 200);
+    let accept = request(
+        Method::POST,
+        &format!("/api/quotes/{quote_id}/accept"),
+        Some(&customer),
+        None,
+    );
+    assert_eq!(common::status(&app, accept).await, 200);
+
+    let checkout = || {
+        request(
+            Method::POST,
+            "/api/checkout",
+            Some(&customer),
+            Some(json!({
+                "shipping_address": "Dam 1, 1012 JS Amsterdam",
+                "shipping_country": "NL",
+                "payment_provider": "fake",
+                "quote_id": quote_id,
+            })),
+        )
+    };
+    let (code, placed): (u16, Value) = send(&app, checkout()).await;
+    assert_eq!(code, 201, "{placed}");
+
+    // the charge is never paid
+    sqlx::query("UPDATE orders SET payment_expires_at = NOW() - INTERVAL '1 minute'")
+        .execute(&pool)
+        .await
+        .unwrap();
+    let (expired, _) = expire_unpaid_orders(&pool).await.unwrap();
+    assert_eq!(expired, 1);
+
+    let (code, placed): (u16, Value) = send(&app, checkout()).await;
+    assert_eq!(code, 201, "{placed}");
+}
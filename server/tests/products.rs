@@ -0,0 +1,931 @@
+mod common;
+
+use std::sync::Arc;
+
+use actix_web::{
+    http::{header, Method},
+    test::{self, TestRequest},
+};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+use common::{request, send, status};
+
+#[sqlx::test(migrations = false)]
+async fn listings_are_cached_until_a_product_changes(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let mug = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+    sqlx::query("UPDATE products SET category = 'Kitchen' WHERE product_id = $1")
+        .bind(mug)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let list = |path: &'static str| {
+        let app = &app;
+        let customer = &customer;
+        async move {
+            let (status, listing): (u16, Value) =
+                send(app, request(Method::GET, path, Some(customer), None)).await;
+            assert_eq!(status, 200, "listing {path}: {listing}");
+            listing
+        }
+    };
+    assert_eq!(
+        list("/api/products").await["meta"]["pagination"]["total"],
+        1
+    );
+
+    // changes that bypass the API wait for the entry to expire
+    sqlx::query("UPDATE products SET stock_quantity = 3 WHERE product_id = $1")
+        .bind(mug)
+        .execute(&pool)
+        .await
+        .unwrap();
+    assert_eq!(
+        list("/api/products").await["data"][0]["stock_quantity"],
+        "10.000"
+    );
+
+    // writes through the API drop the cached listings
+    common::product(&app, &admin, "Ferris Plush", "24.90", 5).await;
+    let listing = list("/api/products").await;
+    assert_eq!(listing["meta"]["pagination"]["total"], 2);
+    assert_eq!(listing["data"][0]["stock_quantity"], "3.000");
+
+    let kitchen = list("/api/products?category=Kitchen").await;
+    assert_eq!(kitchen["meta"]["pagination"]["total"], 1);
+    assert_eq!(kitchen["data"][0]["product_id"], mug.to_string());
+}
+
+#[sqlx::test(migrations = false)]
+async fn the_search_box_is_offered_names_and_categories(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let mug = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+    let cup = common::product(&app, &admin, "Travel Mug", "9.00", 10).await;
+    let hidden = common::product(&app, &admin, "Mug Tree", "20.00", 10).await;
+    sqlx::query("UPDATE products SET category = 'Mugs & Cups' WHERE product_id IN ($1, $2)")
+        .bind(mug)
+        .bind(cup)
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query("UPDATE products SET is_available = false WHERE product_id = $1")
+        .bind(hidden)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let (status, suggestions): (u16, Value) = send(
+        &app,
+        request(
+            Method::GET,
+            "/api/products/suggest?q=MUG",
+            Some(&customer),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(status, 200, "{suggestions}");
+    let suggestions = suggestions["data"].as_array().unwrap();
+    let texts: Vec<&str> = suggestions
+        .iter()
+        .map(|s| s["text"].as_str().unwrap())
+        .collect();
+    // prefix matches first, unavailable products are left out
+    assert_eq!(texts, ["Mugs & Cups", "Borrow Checker Mug", "Travel Mug"]);
+    assert_eq!(suggestions[0]["kind"], "category");
+    assert_eq!(suggestions[1]["kind"], "product");
+    assert_eq!(suggestions[1]["product_id"], mug.to_string());
+
+    let (_, limited): (u16, Value) = send(
+        &app,
+        request(
+            Method::GET,
+            "/api/products/suggest?q=mug&limit=1",
+            Some(&customer),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(limited["data"].as_array().unwrap().len(), 1);
+
+    // like's wildcards are matched as they are
+    let (_, wildcard): (u16, Value) = send(
+        &app,
+        request(
+            Method::GET,
+            "/api/products/suggest?q=%25",
+            Some(&customer),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(wildcard["data"], serde_json::json!([]));
+
+    let (status, _): (u16, Value) = send(
+        &app,
+        request(
+            Method::GET,
+            "/api/products/suggest?q=%20",
+            Some(&customer),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(status, 400);
+}
+
+#[sqlx::test(migrations = false)]
+async fn search_falls_back_to_the_database_and_changes_queue_for_indexing(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let mug = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+    let shirt = common::product(&app, &admin, "Lifetime Shirt", "25.00", 10).await;
+    let travel = common::product(&app, &admin, "Mug For Travel", "9.00", 10).await;
+
+    // no search engine in the tests, the database answers
+    let (status, results): (u16, Value) = send(
+        &app,
+        request(
+            Method::GET,
+            "/api/products/search?q=mug",
+            Some(&customer),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(status, 200, "{results}");
+    let names: Vec<&str> = results["data"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|product| product["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, ["Mug For Travel", "Borrow Checker Mug"]);
+    assert_eq!(results["meta"]["pagination"]["total"], 2);
+
+    // creates, edits to searched fields and deletes wait for the indexer,
+    // stock moves don't
+    sqlx::query("UPDATE products SET stock_quantity = 4 WHERE product_id = $1")
+        .bind(shirt)
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query("UPDATE products SET name = 'Travel Mug' WHERE product_id = $1")
+        .bind(travel)
+        .execute(&pool)
+        .await
+        .unwrap();
+    let (status, _): (u16, Value) = send(
+        &app,
+        request(
+            Method::DELETE,
+            &format!("/api/product/{mug}"),
+            Some(&admin),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(status, 200);
+    let queued: Vec<(String, uuid::Uuid)> = sqlx::query_as(
+        "SELECT event_type, aggregate_id FROM outbox_events
+        WHERE indexed_at IS NULL AND event_type LIKE 'product.%'
+        ORDER BY created_at, event_type",
+    )
+    .fetch_all(&pool)
+    .await
+    .unwrap();
+    assert_eq!(
+        queued
+            .iter()
+            .filter(|(event_type, _)| event_type != "product.created")
+            .collect::<Vec<_>>(),
+        [
+            &("product.updated".to_string(), travel),
+            &("product.deleted".to_string(), mug),
+        ]
+    );
+    assert_eq!(
+        queued
+            .iter()
+            .filter(|(event_type, _)| event_type == "product.created")
+            .count(),
+        3
+    );
+
+    let (status, _): (u16, Value) = send(
+        &app,
+        request(
+            Method::GET,
+            "/api/products/search?q=",
+            Some(&customer),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(status, 400);
+}
+
+#[sqlx::test(migrations = false)]
+async fn listings_link_to_their_neighbouring_pages(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    for name in ["Borrow Checker Mug", "Ferris Plush", "Lifetime Poster"] {
+        common::product(&app, &admin, name, "9.99", 5).await;
+    }
+
+    let response = test::call_service(
+        &app,
+        request(
+            Method::GET,
+            "/api/products?utm_source=feed&page=2&per_page=1",
+            Some(&admin),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(response.status(), 200);
+    let link = response
+        .headers()
+        .get(header::LINK)
+        .and_then(|link| link.to_str().ok())
+        .unwrap();
+    let rels: Vec<(&str, &str)> = link
+        .split(", ")
+        .map(|link| {
+            let (url, rel) = link.split_once("; ").unwrap();
+            let query = url.trim_end_matches('>').split_once('?').unwrap().1;
+            (rel, query)
+        })
+        .collect();
+    assert_eq!(
+        rels,
+        [
+            ("rel=\"first\"", "utm_source=feed&page=1&per_page=1"),
+            ("rel=\"prev\"", "utm_source=feed&page=1&per_page=1"),
+            ("rel=\"next\"", "utm_source=feed&page=3&per_page=1"),
+            ("rel=\"last\"", "utm_source=feed&page=3&per_page=1"),
+        ]
+    );
+}
+
+#[sqlx::test(migrations = false)]
+async fn listings_come_as_csv_when_asked(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let mug = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+    common::product(&app, &admin, "Ferris Plush, Large", "24.90", 5).await;
+    sqlx::query("UPDATE products SET category = 'Kitchen' WHERE product_id = $1")
+        .bind(mug)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let listing = |path: &'static str| {
+        let app = &app;
+        let admin = &admin;
+        async move {
+            let response = test::call_service(
+                app,
+                test::TestRequest::get()
+                    .uri(path)
+                    .insert_header((header::AUTHORIZATION, format!("Bearer {admin}")))
+                    .insert_header((header::ACCEPT, "text/csv"))
+                    .to_request(),
+            )
+            .await;
+            assert_eq!(response.status(), 200);
+            String::from_utf8(test::read_body(response).await.to_vec()).unwrap()
+        }
+    };
+
+    // every product, not a page of them
+    let csv = listing("/api/products?per_page=1").await;
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(lines.len(), 3, "{csv}");
+    assert!(lines[0].starts_with("product_id,name,description,category,price,"));
+    assert!(lines[1].starts_with(&format!("{mug},Borrow Checker Mug,")));
+    assert!(lines[2].contains(",\"Ferris Plush, Large\","), "{csv}");
+
+    let kitchen = listing("/api/products?category=Kitchen").await;
+    assert_eq!(kitchen.lines().count(), 2);
+    assert!(kitchen.contains(",Kitchen,14.50,"), "{kitchen}");
+}
+
+#[sqlx::test(migrations = false)]
+async fn listings_come_as_msgpack_when_asked(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+
+    let get = |path: &'static str| {
+        test::TestRequest::get()
+            .uri(path)
+            .insert_header((header::AUTHORIZATION, format!("Bearer {admin}")))
+            .insert_header((
+                header::ACCEPT,
+                "application/msgpack, application/json;q=0.5",
+            ))
+            .to_request()
+    };
+    let response = test::call_service(&app, get("/api/products")).await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.headers().get(header::CONTENT_TYPE).unwrap(),
+        "application/msgpack"
+    );
+    let body = test::read_body(response).await;
+    let listing: Value = rmp_serde::from_slice(&body).unwrap();
+    assert_eq!(listing["meta"]["pagination"]["total"], 1);
+    assert_eq!(listing["data"][0]["name"], "Borrow Checker Mug");
+    assert_eq!(listing["data"][0]["price"], "14.50");
+
+    // everything else stays JSON
+    let response = test::call_service(&app, get("/api/user_info")).await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.headers().get(header::CONTENT_TYPE).unwrap(),
+        "application/json"
+    );
+}
+
+#[sqlx::test(migrations = false)]
+async fn patches_change_only_the_fields_sent(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let mug = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+    let path = format!("/api/product/{mug}");
+    let patch = |body: Value| request(Method::PATCH, &path, Some(&admin), Some(body));
+
+    let (status, product): (u16, Value) = send(
+        &app,
+        patch(serde_json::json!({"description": "Holds one coffee", "weight_kg": "0.4"})),
+    )
+    .await;
+    assert_eq!(status, 200, "{product}");
+
+    let (status, product): (u16, Value) =
+        send(&app, patch(serde_json::json!({"is_available": false}))).await;
+    assert_eq!(status, 200, "{product}");
+    assert_eq!(product["data"]["is_available"], false);
+    assert_eq!(product["data"]["name"], "Borrow Checker Mug");
+    assert_eq!(product["data"]["price"], "14.50");
+    assert_eq!(product["data"]["stock_quantity"], "10.000");
+    assert_eq!(product["data"]["description"], "Holds one coffee");
+    assert_eq!(product["data"]["weight_kg"], "0.400");
+
+    // null clears a field, leaving it out keeps it
+    let (status, product): (u16, Value) =
+        send(&app, patch(serde_json::json!({"description": null}))).await;
+    assert_eq!(status, 200, "{product}");
+    assert_eq!(product["data"]["description"], Value::Null);
+    assert_eq!(product["data"]["weight_kg"], "0.400");
+
+    // each field sent is checked
+    for body in [
+        serde_json::json!({"price": "-1"}),
+        serde_json::json!({"name": " "}),
+        serde_json::json!({"quantity_step": 0}),
+        serde_json::json!({"length_cm": 0}),
+    ] {
+        let (status, _): (u16, Value) = send(&app, patch(body.clone())).await;
+        assert_eq!(status, 400, "{body}");
+    }
+
+    let (status, _): (u16, Value) = send(
+        &app,
+        request(
+            Method::PATCH,
+            &format!("/api/product/{}", uuid::Uuid::nil()),
+            Some(&admin),
+            Some(serde_json::json!({"is_available": true})),
+        ),
+    )
+    .await;
+    assert_eq!(status, 404);
+}
+
+#[sqlx::test(migrations = false)]
+async fn writes_can_be_made_conditional_on_the_version_read(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let mug = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+    let path = format!("/api/product/{mug}");
+    let write = |method: Method, precondition: (header::HeaderName, String)| {
+        test::TestRequest::default()
+            .method(method)
+            .uri(&path)
+            .insert_header((header::AUTHORIZATION, format!("Bearer {admin}")))
+            .insert_header(precondition)
+            .set_json(serde_json::json!({"is_available": false}))
+            .to_request()
+    };
+
+    let response = test::call_service(&app, request(Method::GET, &path, Some(&admin), None)).await;
+    assert_eq!(response.status(), 200);
+    let etag = response
+        .headers()
+        .get(header::ETAG)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+    assert!(response.headers().contains_key(header::LAST_MODIFIED));
+
+    // someone else changes the product in between
+    let response =
+        test::call_service(&app, write(Method::PATCH, (header::IF_MATCH, etag.clone()))).await;
+    assert_eq!(response.status(), 200);
+    let newer = response
+        .headers()
+        .get(header::ETAG)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+    assert_ne!(newer, etag);
+
+    // so writes based on what was read before fail
+    let response =
+        test::call_service(&app, write(Method::PATCH, (header::IF_MATCH, etag.clone()))).await;
+    assert_eq!(response.status(), 412);
+    let response = test::call_service(&app, write(Method::DELETE, (header::IF_MATCH, etag))).await;
+    assert_eq!(response.status(), 412);
+    let response = test::call_service(
+        &app,
+        write(
+            Method::PATCH,
+            (
+                header::IF_UNMODIFIED_SINCE,
+                "Sun, 06 Nov 1994 08:49:37 GMT".to_string(),
+            ),
+        ),
+    )
+    .await;
+    assert_eq!(response.status(), 412);
+
+    // and one with the current version goes through
+    let response = test::call_service(&app, write(Method::DELETE, (header::IF_MATCH, newer))).await;
+    assert_eq!(response.status(), 200);
+    let (_, product): (u16, Value) =
+        send(&app, request(Method::GET, &path, Some(&admin), None)).await;
+    assert_eq!(product["data"], "product was not found");
+}
+
+#[sqlx::test(migrations = false)]
+async fn each_write_in_a_transaction_moves_the_version(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let mug = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+
+    let mut tx = pool.begin().await.unwrap();
+    let mut versions = Vec::new();
+    for stock in [9, 8] {
+        let version: chrono::DateTime<chrono::Utc> = sqlx::query_scalar(
+            "UPDATE products SET stock_quantity = $2 WHERE product_id = $1::uuid
+            RETURNING updated_at",
+        )
+        .bind(mug)
+        .bind(stock)
+        .fetch_one(&mut *tx)
+        .await
+        .unwrap();
+        versions.push(version);
+    }
+    tx.commit().await.unwrap();
+    assert!(versions[0] < versions[1], "{versions:?}");
+}
+
+#[sqlx::test(migrations = false)]
+async fn customers_hear_when_the_product_they_wait_for_is_back(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "ferris@example.com").await;
+    let mug = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 0).await;
+    let kettle = common::product(&app, &admin, "Kettle", "25.00", 3).await;
+    let notify_me = |product: uuid::Uuid| {
+        request(
+            Method::POST,
+            &format!("/api/products/{product}/notify-me"),
+            Some(&customer),
+            None,
+        )
+    };
+
+    let (status, _): (u16, Value) = send(&app, notify_me(kettle)).await;
+    assert_eq!(status, 409);
+    let (status, subscription): (u16, Value) = send(&app, notify_me(mug)).await;
+    assert_eq!(status, 201, "{subscription}");
+    let (status, _): (u16, Value) = send(&app, notify_me(mug)).await;
+    assert_eq!(status, 201);
+
+    let demand = || async {
+        let (status, demand): (u16, Value) = send(
+            &app,
+            request(
+                Method::GET,
+                "/api/admin/products/stock-demand",
+                Some(&admin),
+                None,
+            ),
+        )
+        .await;
+        assert_eq!(status, 200, "{demand}");
+        demand["data"].clone()
+    };
+    let waiting = demand().await;
+    assert_eq!(waiting[0]["name"], "Borrow Checker Mug");
+    assert_eq!(waiting[0]["subscribers"], 1);
+
+    // restocking tells them once and ends the subscription
+    let (status, _): (u16, Value) = send(
+        &app,
+        request(
+            Method::PATCH,
+            &format!("/api/product/{mug}"),
+            Some(&admin),
+            Some(serde_json::json!({"stock_quantity": 5})),
+        ),
+    )
+    .await;
+    assert_eq!(status, 200);
+    let (_, notifications): (u16, Value) = send(
+        &app,
+        request(
+            Method::GET,
+            "/api/users/me/notifications",
+            Some(&customer),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(
+        notifications["data"][0]["message"],
+        "Borrow Checker Mug is back in stock"
+    );
+    let mails: i64 =
+        sqlx::query_scalar("SELECT COUNT(*) FROM email_messages WHERE kind = 'stock.restocked'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert_eq!(mails, 1);
+    assert_eq!(demand().await, serde_json::json!([]));
+}
+
+#[sqlx::test(migrations = false)]
+async fn admins_list_every_product_by_stock(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "ferris@example.com").await;
+    common::product(&app, &admin, "Borrow Checker Mug", "14.50", 0).await;
+    common::product(&app, &admin, "Kettle", "25.00", 3).await;
+    let draft = common::product(&app, &admin, "Lifetime Poster", "9.00", 12).await;
+    sqlx::query("UPDATE products SET is_available = false WHERE product_id = $1")
+        .bind(draft)
+        .execute(&pool)
+        .await
+        .unwrap();
+    let list = |uri: &str| {
+        let uri = format!("/api/admin/products{uri}");
+        let admin = admin.clone();
+        let app = &app;
+        async move {
+            let (status, products): (u16, Value) =
+                send(app, request(Method::GET, &uri, Some(&admin), None)).await;
+            assert_eq!(status, 200, "{products}");
+            let names: Vec<String> = products["data"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|product| product["name"].as_str().unwrap().to_string())
+                .collect();
+            (names, products["meta"]["pagination"]["total"].clone())
+        }
+    };
+
+    // the catalogue leaves the unpublished poster out, the admin list doesn't
+    let (names, total) = list("?sort=stock&order=asc").await;
+    assert_eq!(names, ["Borrow Checker Mug", "Kettle", "Lifetime Poster"]);
+    assert_eq!(total, 3);
+    let (names, _) = list("?status=unpublished").await;
+    assert_eq!(names, ["Lifetime Poster"]);
+    let (names, _) = list("?max_stock=0").await;
+    assert_eq!(names, ["Borrow Checker Mug"]);
+    let (names, total) = list("?min_stock=1&sort=stock&per_page=1").await;
+    assert_eq!(names, ["Lifetime Poster"]);
+    assert_eq!(total, 2);
+
+    let (_, catalogue): (u16, Value) = send(
+        &app,
+        request(Method::GET, "/api/products", Some(&customer), None),
+    )
+    .await;
+    let names: Vec<&str> = catalogue["data"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|product| product["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, ["Borrow Checker Mug", "Kettle"]);
+    assert_eq!(catalogue["meta"]["pagination"]["total"], 2);
+    let response = test::call_service(
+        &app,
+        test::TestRequest::get()
+            .uri("/api/products")
+            .insert_header((header::AUTHORIZATION, format!("Bearer {customer}")))
+            .insert_header((header::ACCEPT, "text/csv"))
+            .to_request(),
+    )
+    .await;
+    let csv = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+    assert!(!csv.contains("Lifetime Poster"), "{csv}");
+
+    let status = common::status(
+        &app,
+        request(Method::GET, "/api/admin/products", Some(&customer), None),
+    )
+    .await;
+    assert_eq!(status, 403);
+}
+
+#[sqlx::test(migrations = false)]
+async fn digital_downloads_are_for_buyers(pool: PgPool) {
+    let storage = Arc::new(common::MemoryStorage::default());
+    let app = common::app_with(&pool, |state| state.with_storage(storage.clone())).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let buyer = common::customer(&app, "buyer@example.com").await;
+    let browser = common::customer(&app, "browser@example.com").await;
+    let ebook = common::product(&app, &admin, "The Rust Book", "25.00", 100).await;
+
+    let uploaded = test::call_service(
+        &app,
+        TestRequest::put()
+            .uri(&format!(
+                "/api/admin/products/{ebook}/download?file_name=rust-book.pdf"
+            ))
+            .insert_header((header::AUTHORIZATION, format!("Bearer {admin}")))
+            .insert_header((header::CONTENT_TYPE, "application/pdf"))
+            .set_payload("%PDF-1.7 ownership")
+            .to_request(),
+    )
+    .await;
+    assert_eq!(uploaded.status(), 200);
+    let keys: Vec<String> = storage.objects.lock().unwrap().keys().cloned().collect();
+    assert_eq!(keys.len(), 1);
+    assert!(
+        keys[0].starts_with(&format!("downloads/{ebook}/")),
+        "{keys:?}"
+    );
+
+    let download = format!("/api/products/{ebook}/download");
+    assert_eq!(
+        status(&app, request(Method::GET, &download, Some(&browser), None)).await,
+        403
+    );
+
+    let added = status(
+        &app,
+        request(
+            Method::POST,
+            "/api/cart-items",
+            Some(&buyer),
+            Some(json!({ "product_id": ebook, "quantity": "1" })),
+        ),
+    )
+    .await;
+    assert_eq!(added, 201);
+    let (placed, checkout): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/checkout",
+            Some(&buyer),
+            Some(json!({
+                "shipping_address": "Dam 1, 1012 JS Amsterdam",
+                "shipping_country": "NL",
+                "payment_provider": "cod",
+            })),
+        ),
+    )
+    .await;
+    assert_eq!(placed, 201, "{checkout}");
+
+    let response =
+        test::call_service(&app, request(Method::GET, &download, Some(&buyer), None)).await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.headers().get(header::CONTENT_DISPOSITION).unwrap(),
+        "attachment; filename=\"rust-book.pdf\""
+    );
+    assert_eq!(test::read_body(response).await, "%PDF-1.7 ownership");
+}
+
+#[sqlx::test(migrations = false)]
+async fn duplicates_are_sold_the_way_the_original_is(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let pallet = common::product(&app, &admin, "Pallet of Mugs", "400.00", 10).await;
+    let visibility = request(
+        Method::PUT,
+        &format!("/api/admin/products/{pallet}/visibility"),
+        Some(&admin),
+        Some(json!({ "customer_groups": ["wholesale"] })),
+    );
+    assert_eq!(status(&app, visibility).await, 200);
+
+    let (code, copy): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            &format!("/api/admin/products/{pallet}/duplicate"),
+            Some(&admin),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(code, 201, "{copy}");
+    let copy_id = copy["data"]["product_id"].as_str().unwrap().to_string();
+    let publish = request(
+        Method::PATCH,
+        &format!("/api/product/{copy_id}"),
+        Some(&admin),
+        Some(json!({ "is_available": true })),
+    );
+    assert_eq!(status(&app, publish).await, 200);
+
+    // published, the copy is still kept from retail customers
+    let (_, detail): (u16, Value) = send(
+        &app,
+        request(
+            Method::GET,
+            &format!("/api/product/{copy_id}"),
+            Some(&customer),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(detail["data"], "product was not found");
+}
+
+#[sqlx::test(migrations = false)]
+async fn duplicates_keep_images_price_breaks_and_components(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let mug = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+    let tea = common::product(&app, &admin, "Ferris Tea", "6.00", 10).await;
+    let kit = common::product(&app, &admin, "Tea Set", "20.00", 0).await;
+    let setup = [
+        (
+            Method::PUT,
+            "components",
+            json!([
+                { "product_id": mug, "quantity": 1 },
+                { "product_id": tea, "quantity": 2 },
+            ]),
+        ),
+        (
+            Method::PUT,
+            "price-tiers",
+            json!([{ "min_quantity": 5, "price": "18.00" }]),
+        ),
+        (
+            Method::PUT,
+            "group-prices",
+            json!([{ "customer_group": "wholesale", "price": "15.00" }]),
+        ),
+        (
+            Method::POST,
+            "images",
+            json!({ "object_key": "catalogue/tea-set.jpg", "position": 0 }),
+        ),
+    ];
+    for (method, path, body) in setup {
+        let set = request(
+            method,
+            &format!("/api/admin/products/{kit}/{path}"),
+            Some(&admin),
+            Some(body),
+        );
+        assert!(status(&app, set).await < 300, "{path}");
+    }
+
+    let (code, copy): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            &format!("/api/admin/products/{kit}/duplicate"),
+            Some(&admin),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(code, 201, "{copy}");
+    let copy_id = copy["data"]["product_id"].as_str().unwrap().to_string();
+    let publish = request(
+        Method::PATCH,
+        &format!("/api/product/{copy_id}"),
+        Some(&admin),
+        Some(json!({ "is_available": true })),
+    );
+    assert_eq!(status(&app, publish).await, 200);
+
+    let (_, detail): (u16, Value) = send(
+        &app,
+        request(
+            Method::GET,
+            &format!("/api/product/{copy_id}"),
+            Some(&admin),
+            None,
+        ),
+    )
+    .await;
+    let detail = &detail["data"];
+    assert_eq!(detail["images"].as_array().unwrap().len(), 1, "{detail}");
+    assert_eq!(detail["price_tiers"][0]["min_quantity"], 5, "{detail}");
+    assert_eq!(
+        detail["components"].as_array().unwrap().len(),
+        2,
+        "{detail}"
+    );
+    // the copy is a kit, its stock comes from the components
+    assert_eq!(detail["stock_quantity"], "5", "{detail}");
+
+    let (_, prices): (u16, Value) = send(
+        &app,
+        request(
+            Method::GET,
+            &format!("/api/admin/products/{copy_id}/group-prices"),
+            Some(&admin),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(prices["data"][0]["customer_group"], "wholesale", "{prices}");
+
+    // removing the copy's image leaves the original's
+    let image_id = detail["images"][0]["image_id"].as_str().unwrap();
+    let remove = request(
+        Method::DELETE,
+        &format!("/api/admin/products/{copy_id}/images/{image_id}"),
+        Some(&admin),
+        None,
+    );
+    assert!(status(&app, remove).await < 300);
+    let left: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM product_images WHERE object_key = $1")
+        .bind("catalogue/tea-set.jpg")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(left, 1);
+}
+
+#[sqlx::test(migrations = false)]
+async fn bulk_rows_out_of_range_are_reported_on_their_own(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let mug = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+    let tea = common::product(&app, &admin, "Ferris Tea", "6.00", 10).await;
+
+    let (code, body): (u16, Value) = send(
+        &app,
+        request(
+            Method::PATCH,
+            "/api/admin/products/bulk",
+            Some(&admin),
+            Some(json!([
+                { "product_id": mug, "price": "12.00" },
+                { "product_id": mug, "price": "100000000" },
+                { "product_id": tea, "stock_delta": "999999999" },
+            ])),
+        ),
+    )
+    .await;
+    assert_eq!(code, 422, "{body}");
+    let errors = body["errors"].as_array().expect("row errors");
+    let rows = errors
+        .iter()
+        .map(|error| (error["row"].as_u64().unwrap(), error["error"].clone()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        rows,
+        [
+            (1, json!("price is too large")),
+            (2, json!("stock would be too large")),
+        ]
+    );
+
+    let price: String =
+        sqlx::query_scalar("SELECT price::TEXT FROM products WHERE product_id = $1")
+            .bind(mug)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert_eq!(price, "14.50");
+}
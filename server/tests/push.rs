@@ -0,0 +1,343 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+
+use actix_http::Request;
+use actix_web::{
+    body::MessageBody,
+    dev::{Service, ServiceResponse},
+    http::Method,
+};
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use server::{
+    jobs::dispatch_push_messages,
+    push::{Push, PushError, PushMessage, PushService},
+};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use common::{request, send, status};
+
+// a push service keeping what it was asked to send, every token it gets is
+// unregistered when it forgets devices
+#[derive(Default)]
+struct FakePush {
+    sent: Mutex<Vec<(String, String)>>,
+    forgets_devices: bool,
+}
+
+#[async_trait]
+impl PushService for FakePush {
+    async fn send(&self, token: &str, message: &PushMessage) -> Result<(), PushError> {
+        if self.forgets_devices {
+            return Err(PushError::Unregistered);
+        }
+        self.sent
+            .lock()
+            .unwrap()
+            .push((token.to_string(), message.body.clone()));
+        Ok(())
+    }
+}
+
+async fn register_device<S, B>(app: &S, token: &str, platform: &str, device: &str)
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let registered = status(
+        app,
+        request(
+            Method::POST,
+            "/api/users/me/devices",
+            Some(token),
+            Some(json!({ "platform": platform, "token": device })),
+        ),
+    )
+    .await;
+    assert_eq!(registered, 201);
+}
+
+async fn queued(pool: &PgPool) -> Vec<(String, Value)> {
+    sqlx::query_as("SELECT kind, data FROM push_messages ORDER BY created_at, message_id")
+        .fetch_all(pool)
+        .await
+        .unwrap()
+}
+
+#[sqlx::test(migrations = false)]
+async fn devices_get_order_updates_and_back_in_stock_pushes(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let mug = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+
+    let (registered, device): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/users/me/devices",
+            Some(&customer),
+            Some(json!({ "platform": "android", "token": "fcm-registration-token" })),
+        ),
+    )
+    .await;
+    assert_eq!(registered, 201, "registering: {device}");
+    assert_eq!(device["data"]["platform"], "android");
+
+    let (_, preferences): (u16, Value) = send(
+        &app,
+        request(
+            Method::GET,
+            "/api/users/me/push-preferences",
+            Some(&customer),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(
+        preferences["data"],
+        json!({ "order_updates": true, "back_in_stock": true })
+    );
+
+    // a customer's order moving is pushed
+    let added = status(
+        &app,
+        request(
+            Method::POST,
+            "/api/cart-items",
+            Some(&customer),
+            Some(json!({ "product_id": mug, "quantity": "1" })),
+        ),
+    )
+    .await;
+    assert_eq!(added, 201);
+    let (placed, checkout): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/checkout",
+            Some(&customer),
+            Some(json!({
+                "shipping_address": "Dam 1, 1012 JS Amsterdam",
+                "shipping_country": "NL",
+                "shipping_postcode": "1012 JS",
+                "payment_provider": "cod",
+            })),
+        ),
+    )
+    .await;
+    assert_eq!(placed, 201, "checking out: {checkout}");
+    let order_id = &checkout["data"]["order"]["order_id"];
+    assert!(queued(&pool).await.is_empty(), "placing it is not news");
+
+    let shipped = status(
+        &app,
+        request(
+            Method::PUT,
+            "/api/admin/order",
+            Some(&admin),
+            Some(json!({ "order_id": order_id, "order_status": "Shipped" })),
+        ),
+    )
+    .await;
+    assert_eq!(shipped, 200);
+    assert_eq!(
+        queued(&pool).await,
+        [(
+            "order.status".to_string(),
+            json!({ "order_id": order_id, "status": "shipped" })
+        )]
+    );
+
+    // customers with a sold out product in a cart hear when it is back
+    let added = status(
+        &app,
+        request(
+            Method::POST,
+            "/api/cart-items",
+            Some(&customer),
+            Some(json!({ "product_id": mug, "quantity": "1" })),
+        ),
+    )
+    .await;
+    assert_eq!(added, 201);
+    for stock in [0, 4] {
+        sqlx::query("UPDATE products SET stock_quantity = $1 WHERE product_id = $2")
+            .bind(stock)
+            .bind(mug)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+    let messages = queued(&pool).await;
+    assert_eq!(messages.len(), 2, "{messages:?}");
+    assert_eq!(messages[1].0, "stock.back");
+    assert_eq!(messages[1].1["name"], "Borrow Checker Mug");
+
+    // nothing is queued for customers without a device
+    let device_id = device["data"]["device_id"].as_str().unwrap();
+    let removed = status(
+        &app,
+        request(
+            Method::DELETE,
+            &format!("/api/users/me/devices/{device_id}"),
+            Some(&customer),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(removed, 200);
+    let cancelled = status(
+        &app,
+        request(
+            Method::PUT,
+            "/api/admin/order",
+            Some(&admin),
+            Some(json!({ "order_id": order_id, "order_status": "Cancelled" })),
+        ),
+    )
+    .await;
+    assert_eq!(cancelled, 200);
+    assert_eq!(queued(&pool).await.len(), 2);
+}
+
+#[sqlx::test(migrations = false)]
+async fn customers_choose_which_pushes_they_get(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let customer = common::customer(&app, "customer@example.com").await;
+
+    let (saved, preferences): (u16, Value) = send(
+        &app,
+        request(
+            Method::PUT,
+            "/api/users/me/push-preferences",
+            Some(&customer),
+            Some(json!({ "order_updates": false, "back_in_stock": true })),
+        ),
+    )
+    .await;
+    assert_eq!(saved, 200, "{preferences}");
+
+    let (_, preferences): (u16, Value) = send(
+        &app,
+        request(
+            Method::GET,
+            "/api/users/me/push-preferences",
+            Some(&customer),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(
+        preferences["data"],
+        json!({ "order_updates": false, "back_in_stock": true })
+    );
+
+    let rejected = status(
+        &app,
+        request(
+            Method::POST,
+            "/api/users/me/devices",
+            Some(&customer),
+            Some(json!({ "platform": "ios", "token": "  " })),
+        ),
+    )
+    .await;
+    assert_eq!(rejected, 400);
+}
+
+// queue an order update for the customer, as the order status trigger does
+async fn queue_order_update(pool: &PgPool, email: &str) -> Uuid {
+    sqlx::query_scalar(
+        "INSERT INTO push_messages (user_id, kind, data)
+        SELECT user_id, 'order.status', $2 FROM users WHERE email = $1
+        RETURNING message_id",
+    )
+    .bind(email)
+    .bind(json!({ "order_id": Uuid::new_v4(), "status": "shipped" }))
+    .fetch_one(pool)
+    .await
+    .unwrap()
+}
+
+async fn message_status(pool: &PgPool, message_id: Uuid) -> (String, i32, bool) {
+    sqlx::query_as(
+        "SELECT status, attempts, claimed_until IS NOT NULL FROM push_messages
+        WHERE message_id = $1",
+    )
+    .bind(message_id)
+    .fetch_one(pool)
+    .await
+    .unwrap()
+}
+
+#[sqlx::test(migrations = false)]
+async fn the_dispatcher_sends_to_every_device_and_forgets_unregistered_ones(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    register_device(&app, &customer, "android", "android-token").await;
+    register_device(&app, &customer, "ios", "ios-token").await;
+    let message_id = queue_order_update(&pool, "customer@example.com").await;
+
+    let android = Arc::new(FakePush::default());
+    let ios = Arc::new(FakePush {
+        forgets_devices: true,
+        ..Default::default()
+    });
+    let push = Push::new(Some(android.clone()), Some(ios));
+    assert_eq!(dispatch_push_messages(&pool, &push).await.unwrap(), 1);
+
+    assert_eq!(
+        *android.sent.lock().unwrap(),
+        [(
+            "android-token".to_string(),
+            "Your order is on its way".to_string()
+        )]
+    );
+    assert_eq!(
+        message_status(&pool, message_id).await,
+        ("sent".to_string(), 1, false)
+    );
+    let devices: Vec<String> = sqlx::query_scalar("SELECT token FROM push_devices")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+    assert_eq!(devices, ["android-token"]);
+
+    // handled messages are not sent again
+    assert_eq!(dispatch_push_messages(&pool, &push).await.unwrap(), 0);
+    assert_eq!(android.sent.lock().unwrap().len(), 1);
+}
+
+#[sqlx::test(migrations = false)]
+async fn the_dispatcher_skips_pushes_the_customer_opted_out_of(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    register_device(&app, &customer, "android", "android-token").await;
+    let message_id = queue_order_update(&pool, "customer@example.com").await;
+
+    // opting out after the message was queued still counts
+    let saved = status(
+        &app,
+        request(
+            Method::PUT,
+            "/api/users/me/push-preferences",
+            Some(&customer),
+            Some(json!({ "order_updates": false, "back_in_stock": true })),
+        ),
+    )
+    .await;
+    assert_eq!(saved, 200);
+
+    let android = Arc::new(FakePush::default());
+    let push = Push::new(Some(android.clone()), None);
+    assert_eq!(dispatch_push_messages(&pool, &push).await.unwrap(), 1);
+
+    assert!(android.sent.lock().unwrap().is_empty());
+    assert_eq!(
+        message_status(&pool, message_id).await,
+        ("skipped".to_string(), 1, false)
+    );
+}
@@ -0,0 +1,118 @@
+mod common;
+
+use std::time::Duration;
+
+use actix_web::{
+    http::{
+        header::{self, HeaderMap},
+        Method,
+    },
+    test,
+};
+use serde_json::Value;
+use sqlx::{postgres::PgPoolOptions, PgPool};
+
+use common::request;
+
+fn header_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned)
+}
+
+// in its own test binary, the limits are read from the environment
+#[sqlx::test(migrations = false)]
+async fn clients_over_the_route_limit_are_turned_away(pool: PgPool) {
+    std::env::set_var("RATE_LIMITS", "GET /api/users=2/60");
+    let app = common::app(&pool).await;
+
+    for left in ["1", "0"] {
+        let response =
+            test::call_service(&app, request(Method::GET, "/api/users", None, None)).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            header_value(response.headers(), "x-ratelimit-remaining").as_deref(),
+            Some(left)
+        );
+        assert_eq!(
+            header_value(response.headers(), "x-ratelimit-limit").as_deref(),
+            Some("2")
+        );
+        assert!(response.headers().contains_key("x-ratelimit-reset"));
+    }
+    let response = test::call_service(&app, request(Method::GET, "/api/users", None, None)).await;
+    assert_eq!(response.status(), 429);
+    assert!(response.headers().contains_key(header::RETRY_AFTER));
+    assert_eq!(
+        header_value(response.headers(), "x-ratelimit-remaining").as_deref(),
+        Some("0")
+    );
+    let problem: Value = test::read_body_json(response).await;
+    assert_eq!(problem["status"], 429);
+
+    // other routes have no limit
+    let response =
+        test::call_service(&app, request(Method::GET, "/api/user_info", None, None)).await;
+    assert_eq!(response.status(), 401);
+    assert_eq!(
+        header_value(response.headers(), "x-ratelimit-remaining"),
+        None
+    );
+}
+
+// clients are told apart by the connection, X-Forwarded-For only counts when a
+// trusted proxy sent it
+#[sqlx::test(migrations = false)]
+async fn forwarded_addresses_only_count_from_trusted_proxies(pool: PgPool) {
+    std::env::set_var("RATE_LIMITS", "GET /api/users=2/60");
+    std::env::set_var("TRUSTED_PROXIES", "10.0.0.9");
+    let app = common::app(&pool).await;
+
+    let from = |peer: &str, forwarded_for: &str| {
+        test::TestRequest::get()
+            .uri("/api/users")
+            .peer_addr(peer.parse().unwrap())
+            .insert_header(("X-Forwarded-For", forwarded_for))
+            .to_request()
+    };
+
+    // a new made up address on every request doesn't start a new window
+    for (forwarded_for, status) in [("1.1.1.1", 200), ("2.2.2.2", 200), ("3.3.3.3", 429)] {
+        let response = test::call_service(&app, from("192.0.2.1:4000", forwarded_for)).await;
+        assert_eq!(response.status(), status);
+    }
+
+    // behind the proxy, each client has a window of its own
+    for (forwarded_for, status) in [
+        ("192.0.2.1, 198.51.100.7", 200),
+        ("198.51.100.7", 200),
+        ("198.51.100.7", 429),
+        ("198.51.100.8", 200),
+    ] {
+        let response = test::call_service(&app, from("10.0.0.9:4000", forwarded_for)).await;
+        assert_eq!(response.status(), status);
+    }
+}
+
+// a request the database can't take, with no connection free in the pool,
+// answers 503 and tells the client when to come back
+#[sqlx::test(migrations = false)]
+async fn requests_on_an_exhausted_pool_are_retried_later(pool: PgPool) {
+    let busy = PgPoolOptions::new()
+        .max_connections(1)
+        .acquire_timeout(Duration::from_millis(200))
+        .connect_with(pool.connect_options().as_ref().clone())
+        .await
+        .unwrap();
+    let app = common::app(&busy).await;
+
+    let held = busy.acquire().await.unwrap();
+    let response = test::call_service(&app, request(Method::GET, "/api/users", None, None)).await;
+    assert_eq!(response.status(), 503);
+    assert!(response.headers().contains_key(header::RETRY_AFTER));
+    drop(held);
+
+    let response = test::call_service(&app, request(Method::GET, "/api/users", None, None)).await;
+    assert_eq!(response.status(), 200);
+}
@@ -0,0 +1,77 @@
+mod common;
+
+use actix_web::http::Method;
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+use common::{request, send};
+
+#[sqlx::test(migrations = false)]
+async fn a_reported_question_is_hidden_from_the_product_page(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let asker = common::customer(&app, "asker@example.com").await;
+    let reporter = common::customer(&app, "reporter@example.com").await;
+    let kettle = common::product(&app, &admin, "Kettle", "30.00", 5).await;
+
+    let (asked, question): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            &format!("/api/product/{kettle}/questions"),
+            Some(&asker),
+            Some(json!({ "body": "Does it whistle?" })),
+        ),
+    )
+    .await;
+    assert_eq!(asked, 201, "{question}");
+    let question_id = question["data"]["question_id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    let (reported, report): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/reports",
+            Some(&reporter),
+            Some(json!({
+                "target_type": "question",
+                "target_id": question_id,
+                "reason": "spam",
+            })),
+        ),
+    )
+    .await;
+    assert_eq!(reported, 201, "{report}");
+    assert_eq!(report["data"]["target_type"], "question");
+
+    let (resolved, _): (u16, Value) = send(
+        &app,
+        request(
+            Method::PUT,
+            &format!(
+                "/api/admin/reports/{}",
+                report["data"]["report_id"].as_str().unwrap()
+            ),
+            Some(&admin),
+            Some(json!({ "action": "hide_content" })),
+        ),
+    )
+    .await;
+    assert_eq!(resolved, 200);
+
+    let (listed, questions): (u16, Value) = send(
+        &app,
+        request(
+            Method::GET,
+            &format!("/api/product/{kettle}/questions"),
+            Some(&reporter),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(listed, 200);
+    assert_eq!(questions["data"], json!([]));
+}
@@ -0,0 +1,29 @@
+mod common;
+
+use actix_web::http::Method;
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use common::{request, send};
+
+#[sqlx::test(migrations = false)]
+async fn only_products_in_the_catalogue_are_reviewed(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let mug = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+    let review = |product_id: Uuid| {
+        request(
+            Method::POST,
+            &format!("/api/product/{product_id}/reviews"),
+            Some(&customer),
+            Some(json!({ "rating": 5, "body": "Holds coffee and lifetimes" })),
+        )
+    };
+
+    let (missing, body): (u16, Value) = send(&app, review(Uuid::new_v4())).await;
+    assert_eq!(missing, 404, "{body}");
+    let (created, body): (u16, Value) = send(&app, review(mug)).await;
+    assert_eq!(created, 201, "{body}");
+}
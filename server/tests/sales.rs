@@ -0,0 +1,91 @@
+mod common;
+
+use actix_web::http::Method;
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+use common::{request, send, status};
+
+#[sqlx::test(migrations = false)]
+async fn sales_summary_counts_placed_orders(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let mug = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+    let plush = common::product(&app, &admin, "Ferris Plush", "24.90", 5).await;
+
+    for (product, quantity) in [(mug, "3"), (plush, "1")] {
+        let added = status(
+            &app,
+            request(
+                Method::POST,
+                "/api/cart-items",
+                Some(&customer),
+                Some(json!({ "product_id": product, "quantity": quantity })),
+            ),
+        )
+        .await;
+        assert_eq!(added, 201);
+    }
+    let (placed, checkout): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/checkout",
+            Some(&customer),
+            Some(json!({
+                "shipping_address": "Dam 1, 1012 JS Amsterdam",
+                "shipping_country": "NL",
+                "shipping_postcode": "1012 JS",
+                "payment_provider": "cod",
+            })),
+        ),
+    )
+    .await;
+    assert_eq!(placed, 201, "checking out: {checkout}");
+    let total = checkout["data"]["order"]["total_amount"].clone();
+
+    let (found, summary): (u16, Value) = send(
+        &app,
+        request(Method::GET, "/api/admin/reports/sales", Some(&admin), None),
+    )
+    .await;
+    assert_eq!(found, 200, "{summary}");
+    let summary = &summary["data"];
+    assert_eq!(summary["orders"], 1);
+    assert_eq!(summary["revenue"], total);
+    assert_eq!(summary["average_order"], total);
+    assert_eq!(summary["units"], "4");
+    assert_eq!(summary["refunded"], "0");
+    let top = summary["top_products"].as_array().unwrap();
+    assert_eq!(top.len(), 2);
+    assert_eq!(top[0]["name"], "Borrow Checker Mug");
+    assert_eq!(top[0]["revenue"], "43.50");
+    assert_eq!(top[1]["revenue"], "24.90");
+
+    let (found, summary): (u16, Value) = send(
+        &app,
+        request(
+            Method::GET,
+            "/api/admin/reports/sales?from=2020-01-01T00:00:00Z&to=2020-01-08T00:00:00Z",
+            Some(&admin),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(found, 200);
+    assert_eq!(summary["data"]["orders"], 0);
+    assert_eq!(summary["data"]["average_order"], "0");
+
+    let refused = status(
+        &app,
+        request(
+            Method::GET,
+            "/api/admin/reports/sales",
+            Some(&customer),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(refused, 403);
+}
@@ -0,0 +1,207 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+
+use actix_web::http::Method;
+use async_trait::async_trait;
+use serde_json::Value;
+use server::{
+    jobs::index_products,
+    outbox,
+    search::{SearchDocument, SearchEngine, SearchError, SearchHits},
+};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use common::{request, send};
+
+// a search engine holding the products it was sent in the order they came,
+// every search finds all of them. One that is down rejects everything
+#[derive(Default)]
+struct FakeSearch {
+    indexed: Mutex<Vec<Uuid>>,
+    down: bool,
+}
+
+#[async_trait]
+impl SearchEngine for FakeSearch {
+    fn name(&self) -> &'static str {
+        "fake"
+    }
+
+    async fn upsert(&self, documents: &[SearchDocument]) -> Result<(), SearchError> {
+        if self.down {
+            return Err(SearchError::Rejected("down".into()));
+        }
+        let mut indexed = self.indexed.lock().unwrap();
+        for document in documents {
+            if !indexed.contains(&document.product_id) {
+                indexed.push(document.product_id);
+            }
+        }
+        Ok(())
+    }
+
+    async fn remove(&self, product_ids: &[Uuid]) -> Result<(), SearchError> {
+        if self.down {
+            return Err(SearchError::Rejected("down".into()));
+        }
+        self.indexed
+            .lock()
+            .unwrap()
+            .retain(|product_id| !product_ids.contains(product_id));
+        Ok(())
+    }
+
+    async fn search(
+        &self,
+        _store_id: Uuid,
+        _q: &str,
+        offset: i64,
+        limit: i64,
+    ) -> Result<SearchHits, SearchError> {
+        let indexed = self.indexed.lock().unwrap();
+        Ok(SearchHits {
+            product_ids: indexed
+                .iter()
+                .skip(offset as usize)
+                .take(limit as usize)
+                .copied()
+                .collect(),
+        })
+    }
+}
+
+async fn unindexed(pool: &PgPool) -> i64 {
+    sqlx::query_scalar(
+        "SELECT COUNT(*) FROM outbox_events
+        WHERE event_type LIKE 'product.%' AND indexed_at IS NULL",
+    )
+    .fetch_one(pool)
+    .await
+    .unwrap()
+}
+
+#[sqlx::test(migrations = false)]
+async fn the_indexer_sends_changed_products_and_retries_what_failed(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let mug = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+    let shirt = common::product(&app, &admin, "Lifetime Shirt", "25.00", 10).await;
+
+    // an engine that is down gets the products again on the next run
+    let down = FakeSearch {
+        down: true,
+        ..Default::default()
+    };
+    assert!(index_products(&pool, &down).await.is_err());
+    assert_eq!(unindexed(&pool).await, 2);
+    let claimed: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM outbox_events WHERE index_claimed_until IS NOT NULL",
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert_eq!(claimed, 0);
+
+    let engine = FakeSearch::default();
+    assert_eq!(index_products(&pool, &engine).await.unwrap(), 2);
+    assert_eq!(*engine.indexed.lock().unwrap(), [mug, shirt]);
+    assert_eq!(unindexed(&pool).await, 0);
+    assert_eq!(index_products(&pool, &engine).await.unwrap(), 0);
+
+    sqlx::query("DELETE FROM products WHERE product_id = $1")
+        .bind(mug)
+        .execute(&pool)
+        .await
+        .unwrap();
+    assert_eq!(index_products(&pool, &engine).await.unwrap(), 1);
+    assert_eq!(*engine.indexed.lock().unwrap(), [shirt]);
+}
+
+#[sqlx::test(migrations = false)]
+async fn search_pages_and_counts_only_what_the_customer_may_see(pool: PgPool) {
+    let engine = Arc::new(FakeSearch::default());
+    let app = common::app_with(&pool, {
+        let engine = engine.clone();
+        |state| state.with_search_engine(engine)
+    })
+    .await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let mug = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+    let poster = common::product(&app, &admin, "Wholesale Poster", "3.00", 10).await;
+    let sticker = common::product(&app, &admin, "Retired Sticker", "1.00", 10).await;
+    let shirt = common::product(&app, &admin, "Lifetime Shirt", "25.00", 10).await;
+    sqlx::query(
+        "INSERT INTO product_visibility (product_id, customer_group) VALUES ($1, 'wholesale')",
+    )
+    .bind(poster)
+    .execute(&pool)
+    .await
+    .unwrap();
+    index_products(&pool, engine.as_ref()).await.unwrap();
+    // taken off sale since it was indexed
+    sqlx::query("UPDATE products SET is_available = FALSE WHERE product_id = $1")
+        .bind(sticker)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let page = |number: u32| {
+        request(
+            Method::GET,
+            &format!("/api/products/search?q=a&per_page=1&page={number}"),
+            Some(&customer),
+            None,
+        )
+    };
+    let (status, first): (u16, Value) = send(&app, page(1)).await;
+    assert_eq!(status, 200, "{first}");
+    assert_eq!(first["meta"]["pagination"]["total"], 2);
+    assert_eq!(first["data"][0]["product_id"], mug.to_string());
+    let (_, second): (u16, Value) = send(&app, page(2)).await;
+    assert_eq!(second["data"][0]["product_id"], shirt.to_string());
+}
+
+#[sqlx::test(migrations = false)]
+async fn events_are_pruned_once_every_consumer_has_them(pool: PgPool) {
+    common::app(&pool).await;
+    // a product event nothing handled, one only published, one published
+    // and indexed and an order event that was published, all old enough
+    sqlx::query(
+        "INSERT INTO outbox_events
+            (event_type, aggregate_id, payload, created_at, published_at, indexed_at)
+        VALUES
+            ('product.created', gen_random_uuid(), '{}', NOW() - INTERVAL '9 days', NULL, NULL),
+            ('product.updated', gen_random_uuid(), '{}', NOW() - INTERVAL '9 days',
+                NOW() - INTERVAL '8 days', NULL),
+            ('product.deleted', gen_random_uuid(), '{}', NOW() - INTERVAL '9 days',
+                NOW() - INTERVAL '8 days', NOW() - INTERVAL '8 days'),
+            ('order.placed', gen_random_uuid(), '{}', NOW() - INTERVAL '9 days',
+                NOW() - INTERVAL '8 days', NULL)",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    let left = || async {
+        let mut types: Vec<String> = sqlx::query_scalar("SELECT event_type FROM outbox_events")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        types.sort();
+        types
+    };
+
+    // with a broker and a search engine both have to be done with an event
+    assert_eq!(outbox::prune(&pool, 7, true, true).await.unwrap(), 2);
+    assert_eq!(left().await, ["product.created", "product.updated"]);
+    // with only a search engine nothing waits to be published
+    assert_eq!(outbox::prune(&pool, 7, false, true).await.unwrap(), 0);
+    sqlx::query("UPDATE outbox_events SET indexed_at = NOW() - INTERVAL '8 days'")
+        .execute(&pool)
+        .await
+        .unwrap();
+    assert_eq!(outbox::prune(&pool, 7, false, true).await.unwrap(), 2);
+    assert!(left().await.is_empty());
+}
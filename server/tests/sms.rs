@@ -0,0 +1,325 @@
+mod common;
+
+use actix_web::{http::Method, test};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use sqlx::PgPool;
+
+use common::{request, send, status};
+
+// texts are printed, to the dutch numbers the test accounts have
+fn configure_sms() {
+    for (name, value) in [
+        ("SMS_PROVIDER", "console"),
+        ("SMS_COUNTRIES", "31"),
+        ("SMS_RATE_LIMIT", "3/3600"),
+        ("CARRIER_WEBHOOK_SECRETS", "ups:test-carrier-secret"),
+    ] {
+        std::env::set_var(name, value);
+    }
+}
+
+// the codes only ever go to the phone, the test swaps in one it knows
+async fn set_code(pool: &PgPool, challenge_id: &Value, code: &str) {
+    sqlx::query(
+        "UPDATE sms_challenges
+        SET code_hash = encode(sha256(convert_to(challenge_id::text || ':' || $2, 'UTF8')), 'hex')
+        WHERE challenge_id = $1::uuid",
+    )
+    .bind(challenge_id.as_str().unwrap())
+    .bind(code)
+    .execute(pool)
+    .await
+    .unwrap();
+}
+
+#[sqlx::test(migrations = false)]
+async fn two_factor_login_waits_for_the_texted_code(pool: PgPool) {
+    configure_sms();
+    let app = common::app(&pool).await;
+    let customer = common::customer(&app, "customer@example.com").await;
+
+    let (started, challenge): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/users/me/two-factor",
+            Some(&customer),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(started, 202, "starting two-factor: {challenge}");
+    let challenge_id = &challenge["data"]["challenge_id"];
+    set_code(&pool, challenge_id, "123456").await;
+
+    let wrong = status(
+        &app,
+        request(
+            Method::PUT,
+            "/api/users/me/two-factor",
+            Some(&customer),
+            Some(json!({ "challenge_id": challenge_id, "code": "654321" })),
+        ),
+    )
+    .await;
+    assert_eq!(wrong, 422);
+    let enabled = status(
+        &app,
+        request(
+            Method::PUT,
+            "/api/users/me/two-factor",
+            Some(&customer),
+            Some(json!({ "challenge_id": challenge_id, "code": "123456" })),
+        ),
+    )
+    .await;
+    assert_eq!(enabled, 200);
+
+    // the password alone gets a code texted instead of a token
+    let credentials = STANDARD.encode(format!("customer@example.com:{}", common::PASSWORD));
+    let login = test::TestRequest::get()
+        .uri("/api/auth")
+        .insert_header(("Authorization", format!("Basic {credentials}")))
+        .to_request();
+    let (challenged, challenge): (u16, Value) = send(&app, login).await;
+    assert_eq!(challenged, 202, "logging in: {challenge}");
+    let challenge_id = &challenge["data"]["challenge_id"];
+    set_code(&pool, challenge_id, "246810").await;
+
+    let wrong = status(
+        &app,
+        request(
+            Method::POST,
+            "/api/auth/sms",
+            None,
+            Some(json!({ "challenge_id": challenge_id, "code": "000000" })),
+        ),
+    )
+    .await;
+    assert_eq!(wrong, 401);
+    let (verified, token): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/auth/sms",
+            None,
+            Some(json!({ "challenge_id": challenge_id, "code": "246810" })),
+        ),
+    )
+    .await;
+    assert_eq!(verified, 200, "verifying: {token}");
+    let token = token["data"].as_str().unwrap();
+    let info = status(
+        &app,
+        request(Method::GET, "/api/user_info", Some(token), None),
+    )
+    .await;
+    assert_eq!(info, 200);
+
+    // a code is used once
+    let reused = status(
+        &app,
+        request(
+            Method::POST,
+            "/api/auth/sms",
+            None,
+            Some(json!({ "challenge_id": challenge_id, "code": "246810" })),
+        ),
+    )
+    .await;
+    assert_eq!(reused, 401);
+
+    let texts: Vec<(String, String, Option<String>)> =
+        sqlx::query_as("SELECT phone, kind, body FROM sms_messages WHERE status = 'sent'")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+    assert_eq!(
+        texts,
+        vec![
+            ("+3100000000".to_string(), "code".to_string(), None),
+            ("+3100000000".to_string(), "code".to_string(), None),
+        ]
+    );
+
+    // the third text this hour is the last
+    let third = status(
+        &app,
+        request(
+            Method::POST,
+            "/api/users/me/two-factor",
+            Some(&customer),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(third, 202);
+    let fourth = status(
+        &app,
+        request(
+            Method::POST,
+            "/api/users/me/two-factor",
+            Some(&customer),
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(fourth, 429);
+}
+
+#[sqlx::test(migrations = false)]
+async fn deactivated_accounts_cannot_log_in_with_a_code(pool: PgPool) {
+    configure_sms();
+    let app = common::app(&pool).await;
+    common::customer(&app, "customer@example.com").await;
+    sqlx::query("UPDATE users SET sms_two_factor = TRUE WHERE email = 'customer@example.com'")
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let credentials = STANDARD.encode(format!("customer@example.com:{}", common::PASSWORD));
+    let login = test::TestRequest::get()
+        .uri("/api/auth")
+        .insert_header(("Authorization", format!("Basic {credentials}")))
+        .to_request();
+    let (challenged, challenge): (u16, Value) = send(&app, login).await;
+    assert_eq!(challenged, 202, "logging in: {challenge}");
+    let challenge_id = &challenge["data"]["challenge_id"];
+    set_code(&pool, challenge_id, "246810").await;
+
+    // deactivated after the code was texted
+    sqlx::query("UPDATE users SET is_active = FALSE WHERE email = 'customer@example.com'")
+        .execute(&pool)
+        .await
+        .unwrap();
+    let verified = status(
+        &app,
+        request(
+            Method::POST,
+            "/api/auth/sms",
+            None,
+            Some(json!({ "challenge_id": challenge_id, "code": "246810" })),
+        ),
+    )
+    .await;
+    assert_eq!(verified, 403);
+}
+
+#[sqlx::test(migrations = false)]
+async fn the_last_delivery_steps_are_texted(pool: PgPool) {
+    configure_sms();
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let mug = common::product(&app, &admin, "Borrow Checker Mug", "14.50", 10).await;
+
+    let added = status(
+        &app,
+        request(
+            Method::POST,
+            "/api/cart-items",
+            Some(&customer),
+            Some(json!({ "product_id": mug, "quantity": "1" })),
+        ),
+    )
+    .await;
+    assert_eq!(added, 201);
+    let (placed, checkout): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/checkout",
+            Some(&customer),
+            Some(json!({
+                "shipping_address": "Dam 1, 1012 JS Amsterdam",
+                "shipping_country": "NL",
+                "shipping_postcode": "1012 JS",
+                "payment_provider": "cod",
+            })),
+        ),
+    )
+    .await;
+    assert_eq!(placed, 201, "checking out: {checkout}");
+    let order_id = checkout["data"]["order"]["order_id"].as_str().unwrap();
+    let shipped = status(
+        &app,
+        request(
+            Method::POST,
+            &format!("/api/admin/orders/{order_id}/shipments"),
+            Some(&admin),
+            Some(json!({ "carrier": "ups", "tracking_number": "1Z999" })),
+        ),
+    )
+    .await;
+    assert_eq!(shipped, 201);
+
+    // a late in transit callback doesn't take the delivered shipment back
+    for (carrier_status, expected) in [
+        ("in_transit", 200),
+        ("out_for_delivery", 200),
+        ("delivered", 200),
+        ("in_transit", 409),
+    ] {
+        let body = json!({ "tracking_number": "1Z999", "status": carrier_status }).to_string();
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"test-carrier-secret").unwrap();
+        mac.update(body.as_bytes());
+        let signature: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        let webhook = test::TestRequest::post()
+            .uri("/api/webhooks/carrier/ups")
+            .insert_header(("Content-Type", "application/json"))
+            .insert_header(("X-Webhook-Signature", signature))
+            .set_payload(body)
+            .to_request();
+        assert_eq!(status(&app, webhook).await, expected, "{carrier_status}");
+    }
+    let shipment: String = sqlx::query_scalar("SELECT status::text FROM shipments")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(shipment, "delivered");
+
+    // on its way is only a notification, the last two steps are texted too
+    let texts: Vec<(String, String)> = sqlx::query_as(
+        "SELECT body, status FROM sms_messages WHERE kind = 'delivery' ORDER BY created_at",
+    )
+    .fetch_all(&pool)
+    .await
+    .unwrap();
+    assert_eq!(
+        texts,
+        vec![
+            (
+                "Your order is out for delivery".to_string(),
+                "pending".to_string()
+            ),
+            (
+                "Your order was delivered".to_string(),
+                "pending".to_string()
+            ),
+        ]
+    );
+
+    // and not to customers who turned them off
+    let (_, settings): (u16, Value) = send(
+        &app,
+        request(
+            Method::PUT,
+            "/api/users/me/sms",
+            Some(&customer),
+            Some(json!({ "delivery_updates": false })),
+        ),
+    )
+    .await;
+    assert_eq!(
+        settings["data"],
+        json!({ "delivery_updates": false, "two_factor": false })
+    );
+}
@@ -0,0 +1,417 @@
+mod common;
+
+use actix_http::Request;
+use actix_web::{
+    http::{header, Method},
+    test,
+};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use common::{request, send, status};
+
+// a request to the store with the id, sent as X-Store-Id
+fn in_store(
+    store_id: &str,
+    method: Method,
+    uri: &str,
+    token: &str,
+    body: Option<Value>,
+) -> Request {
+    let mut request = test::TestRequest::default()
+        .method(method)
+        .uri(uri)
+        .insert_header(("X-Store-Id", store_id))
+        .insert_header((header::AUTHORIZATION, format!("Bearer {token}")));
+    if let Some(body) = body {
+        request = request.set_json(body);
+    }
+    request.to_request()
+}
+
+fn names(listing: &Value) -> Vec<&str> {
+    listing["data"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|product| product["name"].as_str().unwrap())
+        .collect()
+}
+
+#[sqlx::test(migrations = false)]
+async fn stores_keep_their_catalogues_and_admins_apart(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "ferris@example.com").await;
+
+    let (code, outlet): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/admin/stores",
+            Some(&admin),
+            Some(json!({"name": "Outlet", "host": "Outlet.Example.com"})),
+        ),
+    )
+    .await;
+    assert_eq!(code, 201, "{outlet}");
+    let outlet_id = outlet["data"]["store_id"].as_str().unwrap().to_string();
+
+    // the host picks the store, unknown hosts get the default one
+    let (_, current): (u16, Value) = send(
+        &app,
+        test::TestRequest::get()
+            .uri("/api/store")
+            .insert_header((header::HOST, "outlet.example.com"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(current["data"]["name"], "Outlet");
+    let (_, current): (u16, Value) = send(
+        &app,
+        test::TestRequest::get()
+            .uri("/api/store")
+            .insert_header((header::HOST, "elsewhere.example.com"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(current["data"]["name"], "Default");
+    assert_eq!(
+        status(
+            &app,
+            in_store(
+                "00000000-0000-0000-0000-0000000000ff",
+                Method::GET,
+                "/api/store",
+                &customer,
+                None
+            )
+        )
+        .await,
+        404
+    );
+
+    common::product(&app, &admin, "Kettle", "25.00", 5).await;
+    let (code, toaster): (u16, Value) = send(
+        &app,
+        in_store(
+            &outlet_id,
+            Method::POST,
+            "/api/product",
+            &admin,
+            Some(json!({"name": "Toaster", "description": null, "price": "30.00", "stock_quantity": 5})),
+        ),
+    )
+    .await;
+    assert_eq!(code, 200, "{toaster}");
+    let toaster_id = toaster["data"]["product_id"].as_str().unwrap().to_string();
+
+    // each store lists its own products
+    let (_, listing): (u16, Value) = send(
+        &app,
+        request(Method::GET, "/api/products", Some(&customer), None),
+    )
+    .await;
+    assert_eq!(names(&listing), ["Kettle"]);
+    let (_, listing): (u16, Value) = send(
+        &app,
+        in_store(&outlet_id, Method::GET, "/api/products", &customer, None),
+    )
+    .await;
+    assert_eq!(names(&listing), ["Toaster"]);
+
+    // and carts only take them in their own store
+    let add_toaster = json!({"product_id": toaster_id, "quantity": 1});
+    assert_eq!(
+        status(
+            &app,
+            request(
+                Method::POST,
+                "/api/cart-items",
+                Some(&customer),
+                Some(add_toaster.clone())
+            )
+        )
+        .await,
+        404
+    );
+    assert_eq!(
+        status(
+            &app,
+            in_store(
+                &outlet_id,
+                Method::POST,
+                "/api/cart-items",
+                &customer,
+                Some(add_toaster)
+            )
+        )
+        .await,
+        201
+    );
+
+    // an admin of the outlet is a customer everywhere else
+    let manager_id = common::register(&app, "manager@example.com").await;
+    assert_eq!(
+        status(
+            &app,
+            request(
+                Method::PATCH,
+                &format!("/api/admin/users/{manager_id}"),
+                Some(&admin),
+                Some(json!({"role": "Admin", "store_id": outlet_id})),
+            )
+        )
+        .await,
+        200
+    );
+    let manager = common::login(&app, "manager@example.com").await;
+    let kettle =
+        json!({"name": "Kettle", "description": null, "price": "20.00", "stock_quantity": 1});
+    assert_eq!(
+        status(
+            &app,
+            request(
+                Method::POST,
+                "/api/product",
+                Some(&manager),
+                Some(kettle.clone())
+            )
+        )
+        .await,
+        403
+    );
+    assert_eq!(
+        status(
+            &app,
+            in_store(
+                &outlet_id,
+                Method::POST,
+                "/api/product",
+                &manager,
+                Some(kettle)
+            )
+        )
+        .await,
+        200
+    );
+    assert_eq!(
+        status(
+            &app,
+            in_store(&outlet_id, Method::GET, "/api/admin/stores", &manager, None)
+        )
+        .await,
+        403
+    );
+}
+
+#[sqlx::test(migrations = false)]
+async fn store_admins_cannot_reach_other_stores_orders_and_settings(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer = common::customer(&app, "ferris@example.com").await;
+    let (_, outlet): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/admin/stores",
+            Some(&admin),
+            Some(json!({"name": "Outlet", "host": "outlet.example.com"})),
+        ),
+    )
+    .await;
+    let outlet_id = outlet["data"]["store_id"].as_str().unwrap().to_string();
+    let manager_id = common::register(&app, "manager@example.com").await;
+    assert_eq!(
+        status(
+            &app,
+            request(
+                Method::PATCH,
+                &format!("/api/admin/users/{manager_id}"),
+                Some(&admin),
+                Some(json!({"role": "Admin", "store_id": outlet_id})),
+            )
+        )
+        .await,
+        200
+    );
+    let manager = common::login(&app, "manager@example.com").await;
+
+    // a cash on delivery order in the default store with a dispute on it, and
+    // a quote the customer asked for there
+    let kettle = common::product(&app, &admin, "Kettle", "25.00", 5).await;
+    assert_eq!(
+        status(
+            &app,
+            request(
+                Method::POST,
+                "/api/cart-items",
+                Some(&customer),
+                Some(json!({"product_id": kettle, "quantity": 1})),
+            )
+        )
+        .await,
+        201
+    );
+    let (placed, checkout): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/checkout",
+            Some(&customer),
+            Some(json!({
+                "shipping_address": "Dam 1, 1012 JS Amsterdam",
+                "shipping_country": "NL",
+                "shipping_postcode": "1012 JS",
+                "payment_provider": "cod",
+            })),
+        ),
+    )
+    .await;
+    assert_eq!(placed, 201, "{checkout}");
+    let order_id = checkout["data"]["order"]["order_id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    sqlx::query(
+        "INSERT INTO disputes (order_id, provider, provider_ref, amount, currency, status)
+        VALUES ($1, 'stripe', 'dp_1', 25.00, 'EUR', 'needs_response')",
+    )
+    .bind(Uuid::parse_str(&order_id).unwrap())
+    .execute(&pool)
+    .await
+    .unwrap();
+    sqlx::query("INSERT INTO quotes (user_id) SELECT user_id FROM orders WHERE order_id = $1")
+        .bind(Uuid::parse_str(&order_id).unwrap())
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    // the outlet's admin can't refund, collect or ship it
+    for (uri, body) in [
+        (format!("/api/admin/orders/{order_id}/refund"), json!({})),
+        (
+            format!("/api/admin/orders/{order_id}/cod-collected"),
+            json!({"amount": "25.00"}),
+        ),
+        (
+            format!("/api/admin/orders/{order_id}/shipments"),
+            json!({"carrier": "dhl", "tracking_number": "JD0001"}),
+        ),
+    ] {
+        assert_eq!(
+            status(
+                &app,
+                in_store(&outlet_id, Method::POST, &uri, &manager, Some(body))
+            )
+            .await,
+            404,
+            "{uri}"
+        );
+    }
+    // nor see it among the outlet's disputes, collections and quotes
+    for uri in [
+        "/api/admin/disputes",
+        "/api/admin/orders/cod",
+        "/api/admin/quotes",
+    ] {
+        let (_, listing): (u16, Value) =
+            send(&app, in_store(&outlet_id, Method::GET, uri, &manager, None)).await;
+        assert_eq!(listing["data"], json!([]), "{uri}");
+        let (_, listing): (u16, Value) =
+            send(&app, request(Method::GET, uri, Some(&admin), None)).await;
+        assert_eq!(listing["data"].as_array().unwrap().len(), 1, "{uri}");
+    }
+
+    // bundles are made of and sold in their own store only
+    let teapot = common::product(&app, &admin, "Teapot", "15.00", 5).await;
+    let bundle = json!({
+        "name": "Tea time",
+        "price": "35.00",
+        "items": [
+            {"product_id": kettle, "quantity": 1},
+            {"product_id": teapot, "quantity": 1},
+        ],
+    });
+    assert_eq!(
+        status(
+            &app,
+            in_store(
+                &outlet_id,
+                Method::POST,
+                "/api/admin/bundles",
+                &manager,
+                Some(bundle.clone())
+            )
+        )
+        .await,
+        404
+    );
+    let (_, bundle): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/admin/bundles",
+            Some(&admin),
+            Some(bundle),
+        ),
+    )
+    .await;
+    let add_bundle = json!({"bundle_id": bundle["data"]["bundle_id"], "quantity": 1});
+    assert_eq!(
+        status(
+            &app,
+            in_store(
+                &outlet_id,
+                Method::POST,
+                "/api/cart-bundles",
+                &customer,
+                Some(add_bundle)
+            )
+        )
+        .await,
+        404
+    );
+
+    // the default store's zones don't price the outlet's shipping
+    let (code, zone): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/admin/shipping-zones",
+            Some(&admin),
+            Some(json!({
+                "name": "Netherlands",
+                "country": "NL",
+                "rates": [{"method": "standard", "rate": "2.50"}],
+            })),
+        ),
+    )
+    .await;
+    assert_eq!(code, 201, "{zone}");
+    let (_, zones): (u16, Value) = send(
+        &app,
+        in_store(
+            &outlet_id,
+            Method::GET,
+            "/api/admin/shipping-zones",
+            &manager,
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(zones["data"], json!([]));
+    let (_, options): (u16, Value) = send(
+        &app,
+        in_store(
+            &outlet_id,
+            Method::GET,
+            "/api/checkout/shipping-options?country=NL",
+            &customer,
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(options["data"].as_array().unwrap().len(), 2, "{options}");
+}
@@ -0,0 +1,603 @@
+mod common;
+
+use std::sync::Arc;
+
+use actix_web::{
+    http::{header, Method},
+    test, web, App,
+};
+use serde_json::{json, Value};
+use server::api::users::{get_user, get_user_by_id, MemoryUserRepo, UserRepo};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use common::{request, send, status};
+
+#[sqlx::test(migrations = false)]
+async fn registered_users_log_in_and_see_their_account(pool: PgPool) {
+    let app = common::app(&pool).await;
+
+    let user_id = common::register(&app, "ferris@example.com").await;
+    let token = common::login(&app, "ferris@example.com").await;
+
+    let (status, user): (u16, Value) = send(
+        &app,
+        request(Method::GET, "/api/user_info", Some(&token), None),
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(user["data"]["email"], "ferris@example.com");
+    assert_eq!(user["data"]["user_id"], user_id.to_string());
+}
+
+#[sqlx::test(migrations = false)]
+async fn protected_routes_need_a_valid_token(pool: PgPool) {
+    let app = common::app(&pool).await;
+
+    assert_eq!(
+        status(&app, request(Method::GET, "/api/user_info", None, None)).await,
+        401
+    );
+    assert_eq!(
+        status(
+            &app,
+            request(Method::GET, "/api/user_info", Some("not-a-token"), None)
+        )
+        .await,
+        401
+    );
+}
+
+#[sqlx::test(migrations = false)]
+async fn sessions_are_marked_used_at_most_once_a_minute(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let token = common::customer(&app, "customer@example.com").await;
+    let last_used = || {
+        sqlx::query_scalar::<_, String>("SELECT last_used_at::TEXT FROM sessions").fetch_one(&pool)
+    };
+    let me = || request(Method::GET, "/api/user_info", Some(&token), None);
+
+    sqlx::query("UPDATE sessions SET last_used_at = NOW() - INTERVAL '30 seconds'")
+        .execute(&pool)
+        .await
+        .unwrap();
+    let before = last_used().await.unwrap();
+    assert_eq!(status(&app, me()).await, 200);
+    assert_eq!(last_used().await.unwrap(), before);
+
+    sqlx::query("UPDATE sessions SET last_used_at = NOW() - INTERVAL '5 minutes'")
+        .execute(&pool)
+        .await
+        .unwrap();
+    let before = last_used().await.unwrap();
+    assert_eq!(status(&app, me()).await, 200);
+    assert_ne!(last_used().await.unwrap(), before);
+
+    // a session that can't be read is a failure, not a logout
+    sqlx::query("ALTER TABLE sessions RENAME TO sessions_moved")
+        .execute(&pool)
+        .await
+        .unwrap();
+    assert_eq!(status(&app, me()).await, 500);
+}
+
+#[sqlx::test(migrations = false)]
+async fn failures_are_problem_documents(pool: PgPool) {
+    let app = common::app(&pool).await;
+
+    let mut unauthorized = request(Method::GET, "/api/user_info", None, None);
+    unauthorized.headers_mut().insert(
+        header::HeaderName::from_static("x-request-id"),
+        header::HeaderValue::from_static("req-42"),
+    );
+    let response = test::call_service(&app, unauthorized).await;
+    assert_eq!(response.status(), 401);
+    assert_eq!(
+        response.headers().get(header::CONTENT_TYPE).unwrap(),
+        "application/problem+json"
+    );
+    let problem: Value = test::read_body_json(response).await;
+    assert_eq!(problem["status"], 401);
+    assert_eq!(problem["title"], "Unauthorized");
+    assert_eq!(problem["instance"], "/api/user_info");
+    assert_eq!(problem["request_id"], "req-42");
+}
+
+#[sqlx::test(migrations = false)]
+async fn responses_carry_a_request_id(pool: PgPool) {
+    let app = common::app(&pool).await;
+
+    let response = test::call_service(&app, request(Method::GET, "/api/users", None, None)).await;
+    assert_eq!(response.status(), 200);
+    let id = response
+        .headers()
+        .get("x-request-id")
+        .expect("a request id");
+    assert!(id.to_str().unwrap().parse::<uuid::Uuid>().is_ok());
+
+    let mut echoed = request(Method::GET, "/api/users", None, None);
+    echoed.headers_mut().insert(
+        header::HeaderName::from_static("x-request-id"),
+        header::HeaderValue::from_static("from-the-proxy"),
+    );
+    let response = test::call_service(&app, echoed).await;
+    assert_eq!(
+        response.headers().get("x-request-id").unwrap(),
+        "from-the-proxy"
+    );
+}
+
+#[sqlx::test(migrations = false)]
+async fn repository_calls_are_timed(pool: PgPool) {
+    let app = common::app(&pool).await;
+
+    assert_eq!(
+        status(&app, request(Method::GET, "/api/users", None, None)).await,
+        200
+    );
+    let response = test::call_service(&app, request(Method::GET, "/metrics", None, None)).await;
+    assert_eq!(response.status(), 200);
+    let body = test::read_body(response).await;
+    let metrics = std::str::from_utf8(&body).unwrap();
+    assert!(metrics.contains("db_query_duration_seconds_count{tag=\"users.get_all\"} 1"));
+    assert!(metrics.contains("db_slow_queries_total{tag=\"users.get_all\"}"));
+}
+
+#[sqlx::test(migrations = false)]
+async fn weak_passwords_are_rejected(pool: PgPool) {
+    let app = common::app(&pool).await;
+
+    let (status, problem): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/users",
+            None,
+            Some(json!({
+                "first_name": "Weak",
+                "last_name": "Password",
+                "email": "weak@example.com",
+                "password": "short",
+                "phone": "+3100000000",
+            })),
+        ),
+    )
+    .await;
+    assert_eq!(status, 422);
+    assert_eq!(problem["status"], 422);
+    assert!(problem["errors"]
+        .as_array()
+        .is_some_and(|violations| !violations.is_empty()));
+}
+
+#[sqlx::test(migrations = false)]
+async fn only_admins_manage_products(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let customer = common::customer(&app, "customer@example.com").await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+
+    let body = json!({ "name": "Ferris Plush", "price": "24.90", "stock_quantity": 5 });
+    assert_eq!(
+        status(
+            &app,
+            request(
+                Method::POST,
+                "/api/product",
+                Some(&customer),
+                Some(body.clone())
+            )
+        )
+        .await,
+        403
+    );
+    assert!(
+        status(
+            &app,
+            request(Method::POST, "/api/product", Some(&admin), Some(body))
+        )
+        .await
+            < 300
+    );
+}
+
+#[sqlx::test(migrations = false)]
+async fn admins_patch_users_and_the_change_is_audited(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let customer_id = common::register(&app, "ferris@example.com").await;
+    let customer = common::login(&app, "ferris@example.com").await;
+    let path = format!("/api/admin/users/{customer_id}");
+
+    // customers can't edit anyone
+    assert_eq!(
+        status(
+            &app,
+            request(
+                Method::PATCH,
+                &path,
+                Some(&customer),
+                Some(json!({"role": "Admin"}))
+            )
+        )
+        .await,
+        403
+    );
+
+    let (code, user): (u16, Value) = send(
+        &app,
+        request(
+            Method::PATCH,
+            &path,
+            Some(&admin),
+            Some(json!({"phone": "+31 6 1234 5678"})),
+        ),
+    )
+    .await;
+    assert_eq!(code, 200, "{user}");
+    assert_eq!(user["data"]["phone"], "+31 6 1234 5678");
+    assert_eq!(user["data"]["first_name"], "Test");
+    assert_eq!(user["data"]["is_active"], true);
+
+    let (code, _): (u16, Value) = send(
+        &app,
+        request(
+            Method::PATCH,
+            &path,
+            Some(&admin),
+            Some(json!({"phone": "call me"})),
+        ),
+    )
+    .await;
+    assert_eq!(code, 400);
+
+    // deactivating ends the sessions and blocks logging in again
+    let (code, user): (u16, Value) = send(
+        &app,
+        request(
+            Method::PATCH,
+            &path,
+            Some(&admin),
+            Some(json!({"is_active": false, "phone": null})),
+        ),
+    )
+    .await;
+    assert_eq!(code, 200, "{user}");
+    assert_eq!(user["data"]["is_active"], false);
+    assert_eq!(user["data"]["phone"], Value::Null);
+    assert_eq!(
+        status(
+            &app,
+            request(Method::GET, "/api/user_info", Some(&customer), None)
+        )
+        .await,
+        401
+    );
+    let credentials = base64::Engine::encode(
+        &base64::engine::general_purpose::STANDARD,
+        format!("ferris@example.com:{}", common::PASSWORD),
+    );
+    let login = test::TestRequest::get()
+        .uri("/api/auth")
+        .insert_header((header::AUTHORIZATION, format!("Basic {credentials}")))
+        .to_request();
+    assert_eq!(status(&app, login).await, 403);
+
+    let entries: Vec<(String, Value)> = sqlx::query_as(
+        "SELECT action, details FROM audit_log WHERE entity_id = $1 ORDER BY created_at",
+    )
+    .bind(customer_id)
+    .fetch_all(&pool)
+    .await
+    .unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].0, "user.update");
+    assert_eq!(
+        entries[1].1,
+        json!({
+            "before": {"is_active": true, "phone": "+31 6 1234 5678"},
+            "after": {"is_active": false, "phone": null},
+        })
+    );
+
+    // admins can't lock themselves out
+    let (_, me): (u16, Value) = send(
+        &app,
+        request(Method::GET, "/api/user_info", Some(&admin), None),
+    )
+    .await;
+    let (code, _): (u16, Value) = send(
+        &app,
+        request(
+            Method::PATCH,
+            &format!(
+                "/api/admin/users/{}",
+                me["data"]["user_id"].as_str().unwrap()
+            ),
+            Some(&admin),
+            Some(json!({"role": "Customer"})),
+        ),
+    )
+    .await;
+    assert_eq!(code, 409);
+}
+
+#[sqlx::test(migrations = false)]
+async fn customers_accept_the_current_policies_before_anything_else(pool: PgPool) {
+    let app = common::app(&pool).await;
+
+    let (code, problem): (u16, Value) = send(
+        &app,
+        request(
+            Method::POST,
+            "/api/users",
+            None,
+            Some(json!({
+                "first_name": "Test",
+                "last_name": "User",
+                "email": "ferris@example.com",
+                "password": common::PASSWORD,
+                "phone": "+3100000000",
+            })),
+        ),
+    )
+    .await;
+    assert_eq!(code, 422, "{problem}");
+
+    // registering accepts both
+    let user_id = common::register(&app, "ferris@example.com").await;
+    let token = common::login(&app, "ferris@example.com").await;
+    let (_, policies): (u16, Value) = send(
+        &app,
+        request(Method::GET, "/api/users/me/policies", Some(&token), None),
+    )
+    .await;
+    assert_eq!(policies["data"][0]["policy"], "terms");
+    assert_eq!(policies["data"][0]["accepted_version"], "1");
+    assert_eq!(policies["data"][1]["accepted_version"], "1");
+    let recorded: i64 =
+        sqlx::query_scalar("SELECT COUNT(*) FROM policy_acceptances WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert_eq!(recorded, 2);
+    assert_eq!(
+        status(
+            &app,
+            request(Method::GET, "/api/users/me/sessions", Some(&token), None)
+        )
+        .await,
+        200
+    );
+
+    // a customer who accepted an older version of the terms is held back
+    // until they accept the current one
+    sqlx::query("UPDATE policy_acceptances SET version = '0' WHERE policy = 'terms'")
+        .execute(&pool)
+        .await
+        .unwrap();
+    let (code, problem): (u16, Value) = send(
+        &app,
+        request(Method::GET, "/api/users/me/sessions", Some(&token), None),
+    )
+    .await;
+    assert_eq!(code, 451);
+    assert_eq!(problem["errors"][0]["code"], "policy_not_accepted");
+    assert_eq!(problem["errors"][0]["policy"], "terms");
+    assert_eq!(
+        status(
+            &app,
+            request(Method::GET, "/api/user_info", Some(&token), None)
+        )
+        .await,
+        200
+    );
+    // recorded with the address the connection came from, not one the client claims
+    let accept = |version: &str| {
+        test::TestRequest::put()
+            .uri("/api/users/me/policies/terms")
+            .peer_addr("192.0.2.10:5000".parse().unwrap())
+            .insert_header(("X-Forwarded-For", "203.0.113.99"))
+            .insert_header((header::AUTHORIZATION, format!("Bearer {token}")))
+            .set_json(json!({ "version": version }))
+            .to_request()
+    };
+    assert_eq!(status(&app, accept("0")).await, 409);
+    assert_eq!(status(&app, accept("1")).await, 200);
+    let ip_address: Option<String> = sqlx::query_scalar(
+        "SELECT ip_address FROM policy_acceptances WHERE policy = 'terms'
+        ORDER BY created_at DESC LIMIT 1",
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert_eq!(ip_address.as_deref(), Some("192.0.2.10"));
+    assert_eq!(
+        status(
+            &app,
+            request(Method::GET, "/api/users/me/sessions", Some(&token), None)
+        )
+        .await,
+        200
+    );
+
+    // staff don't sign up, nothing is asked of them
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    assert_eq!(
+        status(
+            &app,
+            request(Method::GET, "/api/admin/orders", Some(&admin), None)
+        )
+        .await,
+        200
+    );
+}
+
+#[sqlx::test(migrations = false)]
+async fn create_admin_leaves_registered_accounts_alone(pool: PgPool) {
+    let app = common::app(&pool).await;
+    common::register(&app, "squatter@example.com").await;
+
+    let created = server::cli::create_admin(
+        &pool,
+        "squatter@example.com",
+        "Shop",
+        "Owner",
+        Some(common::PASSWORD),
+    )
+    .await;
+    assert!(created.is_err());
+    let role: String =
+        sqlx::query_scalar("SELECT role::TEXT FROM users WHERE email = 'squatter@example.com'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert_eq!(role, "customer");
+
+    // running it again for an admin changes nothing
+    common::admin(&app, &pool, "owner@example.com").await;
+    server::cli::create_admin(&pool, "owner@example.com", "Shop", "Owner", None)
+        .await
+        .unwrap();
+}
+
+#[sqlx::test(migrations = false)]
+async fn the_first_admin_is_created_not_promoted(pool: PgPool) {
+    let app = common::app(&pool).await;
+    // someone registers the bootstrap email before the operator starts the server
+    common::register(&app, "owner@example.com").await;
+
+    assert!(
+        server::cli::bootstrap_first_admin(&pool, "owner@example.com", Some(common::PASSWORD))
+            .await
+            .is_err()
+    );
+    let admins: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users WHERE role = 'admin'")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(admins, 0);
+
+    server::cli::bootstrap_first_admin(&pool, "founder@example.com", Some(common::PASSWORD))
+        .await
+        .unwrap();
+    let admin = common::login(&app, "founder@example.com").await;
+    let (_, me): (u16, Value) = send(
+        &app,
+        request(Method::GET, "/api/user_info", Some(&admin), None),
+    )
+    .await;
+    assert_eq!(me["data"]["role"], "Admin");
+
+    // once there is an admin the setting does nothing
+    server::cli::bootstrap_first_admin(&pool, "late@example.com", Some(common::PASSWORD))
+        .await
+        .unwrap();
+    let admins: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users WHERE role = 'admin'")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(admins, 1);
+}
+
+// the handlers only need the repository the state hands them, no database
+#[actix_web::test]
+async fn user_handlers_run_on_the_in_memory_repository() {
+    let state = common::state_without_database();
+    let users = Arc::new(MemoryUserRepo::default());
+    users
+        .create(
+            serde_json::from_value(json!({
+                "first_name": "Ferris",
+                "last_name": "Crab",
+                "email": "ferris@example.com",
+                "password": common::PASSWORD,
+                "phone": "+31600000000",
+            }))
+            .unwrap(),
+        )
+        .await
+        .unwrap();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(state.with_user_repo(users)))
+            .service(get_user)
+            .service(get_user_by_id),
+    )
+    .await;
+
+    let listed: Value =
+        test::call_and_read_body_json(&app, request(Method::GET, "/api/users", None, None)).await;
+    assert_eq!(listed.as_array().unwrap().len(), 1);
+    let user_id = listed[0]["user_id"].as_str().unwrap();
+
+    let response = test::call_service(
+        &app,
+        request(Method::GET, &format!("/api/users/{user_id}"), None, None),
+    )
+    .await;
+    assert_eq!(response.status(), 200);
+    let user: Value = test::read_body_json(response).await;
+    assert_eq!(user["email"], "ferris@example.com");
+
+    let missing = test::call_service(
+        &app,
+        request(
+            Method::GET,
+            &format!("/api/users/{}", Uuid::new_v4()),
+            None,
+            None,
+        ),
+    )
+    .await;
+    assert_eq!(missing.status(), 404);
+}
+
+#[sqlx::test(migrations = false)]
+async fn blocked_domains_match_whole_labels(pool: PgPool) {
+    let app = common::app(&pool).await;
+    let admin = common::admin(&app, &pool, "admin@example.com").await;
+    let block = |domain: &str| {
+        request(
+            Method::POST,
+            "/api/admin/blocked-domains",
+            Some(&admin),
+            Some(json!({ "domain": domain })),
+        )
+    };
+
+    for invalid in [
+        "mail_inator.com",
+        "%.com",
+        "localhost",
+        "-mail.com",
+        "mail..com",
+    ] {
+        assert_eq!(status(&app, block(invalid)).await, 400, "{invalid}");
+    }
+    assert_eq!(status(&app, block("@Mailinator.com")).await, 201);
+    // stored before domains were checked, an underscore matches only itself
+    sqlx::query("INSERT INTO blocked_email_domains (domain) VALUES ('ex_mple.com')")
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let register = |email: &str| {
+        request(
+            Method::POST,
+            "/api/users",
+            None,
+            Some(json!({
+                "first_name": "Test",
+                "last_name": "User",
+                "email": email,
+                "password": common::PASSWORD,
+                "phone": "+3100000000",
+                "accept_terms": true,
+            })),
+        )
+    };
+    assert_eq!(status(&app, register("a@mx.mailinator.com")).await, 422);
+    assert_eq!(status(&app, register("b@notmailinator.com")).await, 200);
+    assert_eq!(status(&app, register("c@example.com")).await, 200);
+}